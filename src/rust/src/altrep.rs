@@ -0,0 +1,168 @@
+use std::cell::OnceCell;
+use std::fmt;
+use std::sync::Arc;
+
+use extendr_api::prelude::*;
+use extendr_api::SEXP;
+use extendr_ffi::{
+    R_ExternalPtrAddr, R_altrep_data1, R_altrep_data2, R_set_altrep_data2, Rf_allocVector,
+    Rf_protect, Rf_unprotect, DATAPTR, SET_STRING_ELT, SEXPTYPE, TYPEOF,
+};
+
+/// A character vector whose strings stay in Rust memory, each becoming an R
+/// string only when R accesses it (an ALTREP vector). The whole vector is
+/// materialized on the first access to its data pointer, e.g. by `match()`.
+pub(crate) fn alt_strings<I, T>(values: I) -> Robj
+where
+    I: IntoIterator<Item = T>,
+    T: AsRef<[u8]>,
+{
+    let mut strings = PackedStrings::default();
+    for value in values {
+        strings.push(value.as_ref());
+    }
+    thread_local! {
+        static CLASS: OnceCell<Robj> = const { OnceCell::new() };
+    }
+    let class = CLASS.with(|class| {
+        class
+            .get_or_init(|| Altrep::make_altstring_class::<AltStrings>("mire_strings", "mire"))
+            .clone()
+    });
+    Altrep::from_state_and_class(AltStrings(Arc::new(strings)), class, false).into()
+}
+
+/// A double vector of counts kept in Rust memory (an ALTREP vector)
+pub(crate) fn alt_counts(counts: Vec<usize>) -> Robj {
+    thread_local! {
+        static CLASS: OnceCell<Robj> = const { OnceCell::new() };
+    }
+    let class = CLASS.with(|class| {
+        class
+            .get_or_init(|| Altrep::make_altreal_class::<AltCounts>("mire_counts", "mire"))
+            .clone()
+    });
+    Altrep::from_state_and_class(AltCounts(Arc::new(counts)), class, false).into()
+}
+
+/// Strings concatenated into a single buffer
+#[derive(Default)]
+struct PackedStrings {
+    data: Vec<u8>,
+    ends: Vec<usize>,
+}
+
+impl PackedStrings {
+    fn push(&mut self, value: &[u8]) {
+        self.data.extend_from_slice(value);
+        self.ends.push(self.data.len());
+    }
+
+    fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    fn get(&self, index: usize) -> &[u8] {
+        let start = index.checked_sub(1).map_or(0, |x| self.ends[x]);
+        &self.data[start .. self.ends[index]]
+    }
+}
+
+#[derive(Clone)]
+struct AltStrings(Arc<PackedStrings>);
+
+impl fmt::Debug for AltStrings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "mire_strings ({} strings)", self.0.len())
+    }
+}
+
+impl AltrepImpl for AltStrings {
+    fn length(&self) -> usize {
+        self.0.len()
+    }
+
+    // The default of extendr only materializes vectors of numbers
+    fn dataptr(x: SEXP, _writeable: bool) -> *mut u8 {
+        single_threaded(|| unsafe {
+            let mut data2 = R_altrep_data2(x);
+            if TYPEOF(data2) != SEXPTYPE::STRSXP {
+                let strings = &*(R_ExternalPtrAddr(R_altrep_data1(x)) as *const AltStrings);
+                data2 = Rf_protect(Rf_allocVector(
+                    SEXPTYPE::STRSXP,
+                    strings.length() as extendr_ffi::R_xlen_t,
+                ));
+                for i in 0 .. strings.length() {
+                    SET_STRING_ELT(data2, i as extendr_ffi::R_xlen_t, strings.elt(i).get());
+                }
+                R_set_altrep_data2(x, data2);
+                Rf_unprotect(1);
+            }
+            DATAPTR(data2) as *mut u8
+        })
+    }
+}
+
+impl AltStringImpl for AltStrings {
+    fn elt(&self, index: usize) -> Rstr {
+        Rstr::from(String::from_utf8_lossy(self.0.get(index)).as_ref())
+    }
+
+    fn no_na(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone)]
+struct AltCounts(Arc<Vec<usize>>);
+
+impl fmt::Debug for AltCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "mire_counts ({} counts)", self.0.len())
+    }
+}
+
+impl AltrepImpl for AltCounts {
+    fn length(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl AltRealImpl for AltCounts {
+    fn elt(&self, index: usize) -> Rfloat {
+        Rfloat::from(self.0[index] as f64)
+    }
+
+    fn no_na(&self) -> bool {
+        true
+    }
+
+    // Counts are never NA, which the default of extendr miscounts
+    fn tot_min_max_nas(&self) -> (f64, f64, f64, usize, usize) {
+        let counts = self.0.iter().map(|&x| x as f64);
+        (
+            counts.clone().sum(),
+            counts.clone().fold(f64::INFINITY, f64::min),
+            counts.fold(f64::NEG_INFINITY, f64::max),
+            0,
+            self.0.len(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packed_strings() {
+        let mut strings = PackedStrings::default();
+        for value in ["read1", "", "read3"] {
+            strings.push(value.as_bytes());
+        }
+        assert_eq!(strings.len(), 3);
+        assert_eq!(strings.get(0), b"read1");
+        assert_eq!(strings.get(1), b"");
+        assert_eq!(strings.get(2), b"read3");
+    }
+}
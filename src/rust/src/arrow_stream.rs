@@ -0,0 +1,411 @@
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::ptr::{null, null_mut};
+
+use anyhow::{anyhow, Result};
+
+/// Rows of a batch of an Arrow stream, so that R can convert a stream chunk
+/// by chunk
+pub(crate) const ARROW_BATCH_ROWS: usize = 1 << 20;
+
+// Bytes of the strings of a column in a batch, well below the limit of the
+// 32-bit offsets of Arrow strings
+const BATCH_BYTES: usize = 1 << 30;
+
+// The field may hold nulls
+const ARROW_FLAG_NULLABLE: i64 = 2;
+
+/// The buffers of a string column of a batch
+struct ColumnData {
+    offsets: Vec<i32>,
+    data: Vec<u8>,
+}
+
+impl ColumnData {
+    fn new() -> Self {
+        Self {
+            offsets: vec![0],
+            data: Vec::new(),
+        }
+    }
+
+    // No validity buffer: columns hold no nulls
+    fn buffers(&self) -> Vec<*const c_void> {
+        vec![
+            null(),
+            self.offsets.as_ptr() as *const c_void,
+            self.data.as_ptr() as *const c_void,
+        ]
+    }
+}
+
+struct Batch {
+    columns: Vec<ColumnData>,
+    rows: usize,
+}
+
+/// Builds a table of strings row by row, to hand it over to R as an Arrow C
+/// stream (`ArrowArrayStream`) of record batches.
+///
+/// Rust keeps the memory of the table until R releases each batch, so the
+/// table never goes through one R string per value, and R (e.g. nanoarrow or
+/// arrow) converts it in batches of `batch_rows` rows.
+pub(crate) struct ArrowStreamBuilder {
+    fields: Vec<CString>,
+    batch_rows: usize,
+    batches: Vec<Batch>,
+    current: Batch,
+}
+
+impl ArrowStreamBuilder {
+    pub(crate) fn new(fields: &[&str], batch_rows: usize) -> Self {
+        let fields = fields
+            .iter()
+            // Safety: field names are static identifiers without NUL
+            .map(|name| CString::new(*name).unwrap())
+            .collect::<Vec<_>>();
+        let current = Self::new_batch(&fields);
+        Self {
+            fields,
+            batch_rows: batch_rows.max(1),
+            batches: Vec::new(),
+            current,
+        }
+    }
+
+    fn new_batch(fields: &[CString]) -> Batch {
+        Batch {
+            columns: fields.iter().map(|_| ColumnData::new()).collect(),
+            rows: 0,
+        }
+    }
+
+    /// Add a row holding a value of each field, in order
+    pub(crate) fn push_row(&mut self, values: &[&[u8]]) -> Result<()> {
+        if values.len() != self.fields.len() {
+            return Err(anyhow!(
+                "Expected {} values in a row, found {}",
+                self.fields.len(),
+                values.len()
+            ));
+        }
+        for (column, value) in self.current.columns.iter_mut().zip(values) {
+            // Arrow strings must be valid UTF-8
+            column
+                .data
+                .extend_from_slice(String::from_utf8_lossy(value).as_bytes());
+            column.offsets.push(column.data.len() as i32);
+        }
+        self.current.rows += 1;
+        if self.current.rows >= self.batch_rows
+            || self
+                .current
+                .columns
+                .iter()
+                .any(|x| x.data.len() >= BATCH_BYTES)
+        {
+            self.seal();
+        }
+        Ok(())
+    }
+
+    fn seal(&mut self) {
+        if self.current.rows > 0 {
+            let batch = std::mem::replace(&mut self.current, Self::new_batch(&self.fields));
+            self.batches.push(batch);
+        }
+    }
+
+    /// Move the table into the `ArrowArrayStream` at `address`, allocated by
+    /// R (e.g. with `nanoarrow::nanoarrow_allocate_array_stream()`), given as
+    /// a decimal or hexadecimal string
+    pub(crate) fn export(mut self, address: &str) -> Result<()> {
+        let out = parse_address(address)? as *mut FfiArrayStream;
+        if out.is_null() {
+            return Err(anyhow!("Arrow stream address is null"));
+        }
+        self.seal();
+        let private = Box::new(StreamPrivate {
+            fields: self.fields,
+            batches: self.batches.into_iter(),
+        });
+        let stream = FfiArrayStream {
+            get_schema: Some(stream_get_schema),
+            get_next: Some(stream_get_next),
+            get_last_error: Some(stream_get_last_error),
+            release: Some(stream_release),
+            private_data: Box::into_raw(private) as *mut c_void,
+        };
+        // Safety: the address is of a released stream allocated by R, which
+        // takes ownership of the table until it releases the stream
+        unsafe { out.write(stream) };
+        Ok(())
+    }
+}
+
+fn parse_address(address: &str) -> Result<usize> {
+    let parsed = match address.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => address.parse::<usize>(),
+    };
+    parsed.map_err(|_| anyhow!("Invalid Arrow stream address '{}'", address))
+}
+
+// ─── Arrow C Data Interface ───────────────────────────────────
+// The structures of https://arrow.apache.org/docs/format/CDataInterface.html
+// and https://arrow.apache.org/docs/format/CStreamInterface.html
+
+#[repr(C)]
+struct FfiSchema {
+    format: *const c_char,
+    name: *const c_char,
+    metadata: *const c_char,
+    flags: i64,
+    n_children: i64,
+    children: *mut *mut FfiSchema,
+    dictionary: *mut FfiSchema,
+    release: Option<unsafe extern "C" fn(*mut FfiSchema)>,
+    private_data: *mut c_void,
+}
+
+#[repr(C)]
+struct FfiArray {
+    length: i64,
+    null_count: i64,
+    offset: i64,
+    n_buffers: i64,
+    n_children: i64,
+    buffers: *mut *const c_void,
+    children: *mut *mut FfiArray,
+    dictionary: *mut FfiArray,
+    release: Option<unsafe extern "C" fn(*mut FfiArray)>,
+    private_data: *mut c_void,
+}
+
+#[repr(C)]
+struct FfiArrayStream {
+    get_schema: Option<unsafe extern "C" fn(*mut FfiArrayStream, *mut FfiSchema) -> c_int>,
+    get_next: Option<unsafe extern "C" fn(*mut FfiArrayStream, *mut FfiArray) -> c_int>,
+    get_last_error: Option<unsafe extern "C" fn(*mut FfiArrayStream) -> *const c_char>,
+    release: Option<unsafe extern "C" fn(*mut FfiArrayStream)>,
+    private_data: *mut c_void,
+}
+
+struct SchemaPrivate {
+    format: CString,
+    name: CString,
+    children: Vec<*mut FfiSchema>,
+}
+
+fn new_schema(format: &CStr, name: &CStr, flags: i64, children: Vec<FfiSchema>) -> FfiSchema {
+    let mut private = Box::new(SchemaPrivate {
+        format: format.to_owned(),
+        name: name.to_owned(),
+        children: children
+            .into_iter()
+            .map(|child| Box::into_raw(Box::new(child)))
+            .collect(),
+    });
+    FfiSchema {
+        format: private.format.as_ptr(),
+        name: private.name.as_ptr(),
+        metadata: null(),
+        flags,
+        n_children: private.children.len() as i64,
+        children: private.children.as_mut_ptr(),
+        dictionary: null_mut(),
+        release: Some(release_schema),
+        private_data: Box::into_raw(private) as *mut c_void,
+    }
+}
+
+unsafe extern "C" fn release_schema(schema: *mut FfiSchema) {
+    let Some(schema) = schema.as_mut() else {
+        return;
+    };
+    let private = Box::from_raw(schema.private_data as *mut SchemaPrivate);
+    for &child in &private.children {
+        // Children moved out by the consumer are already released
+        if let Some(release) = (*child).release {
+            release(child);
+        }
+        drop(Box::from_raw(child));
+    }
+    schema.release = None;
+}
+
+struct ArrayPrivate {
+    // Owns the memory the buffers point to
+    _data: Option<ColumnData>,
+    buffers: Vec<*const c_void>,
+    children: Vec<*mut FfiArray>,
+}
+
+fn new_array(length: usize, data: Option<ColumnData>, children: Vec<FfiArray>) -> FfiArray {
+    let buffers = data
+        .as_ref()
+        .map_or_else(|| vec![null()], ColumnData::buffers);
+    let mut private = Box::new(ArrayPrivate {
+        _data: data,
+        buffers,
+        children: children
+            .into_iter()
+            .map(|child| Box::into_raw(Box::new(child)))
+            .collect(),
+    });
+    FfiArray {
+        length: length as i64,
+        null_count: 0,
+        offset: 0,
+        n_buffers: private.buffers.len() as i64,
+        n_children: private.children.len() as i64,
+        buffers: private.buffers.as_mut_ptr(),
+        children: private.children.as_mut_ptr(),
+        dictionary: null_mut(),
+        release: Some(release_array),
+        private_data: Box::into_raw(private) as *mut c_void,
+    }
+}
+
+unsafe extern "C" fn release_array(array: *mut FfiArray) {
+    let Some(array) = array.as_mut() else {
+        return;
+    };
+    let private = Box::from_raw(array.private_data as *mut ArrayPrivate);
+    for &child in &private.children {
+        if let Some(release) = (*child).release {
+            release(child);
+        }
+        drop(Box::from_raw(child));
+    }
+    array.release = None;
+}
+
+struct StreamPrivate {
+    fields: Vec<CString>,
+    batches: std::vec::IntoIter<Batch>,
+}
+
+// A record batch is a struct array of the columns
+unsafe extern "C" fn stream_get_schema(stream: *mut FfiArrayStream, out: *mut FfiSchema) -> c_int {
+    let private = &*((*stream).private_data as *const StreamPrivate);
+    let children = private
+        .fields
+        .iter()
+        .map(|name| new_schema(c"u", name, ARROW_FLAG_NULLABLE, Vec::new()))
+        .collect();
+    out.write(new_schema(c"+s", c"", 0, children));
+    0
+}
+
+unsafe extern "C" fn stream_get_next(stream: *mut FfiArrayStream, out: *mut FfiArray) -> c_int {
+    let private = &mut *((*stream).private_data as *mut StreamPrivate);
+    match private.batches.next() {
+        Some(batch) => {
+            let children = batch
+                .columns
+                .into_iter()
+                .map(|column| new_array(batch.rows, Some(column), Vec::new()))
+                .collect();
+            out.write(new_array(batch.rows, None, children));
+        }
+        // The end of the stream is a released array
+        None => out.write(FfiArray {
+            length: 0,
+            null_count: 0,
+            offset: 0,
+            n_buffers: 0,
+            n_children: 0,
+            buffers: null_mut(),
+            children: null_mut(),
+            dictionary: null_mut(),
+            release: None,
+            private_data: null_mut(),
+        }),
+    }
+    0
+}
+
+unsafe extern "C" fn stream_get_last_error(_stream: *mut FfiArrayStream) -> *const c_char {
+    null()
+}
+
+unsafe extern "C" fn stream_release(stream: *mut FfiArrayStream) {
+    let Some(stream) = stream.as_mut() else {
+        return;
+    };
+    drop(Box::from_raw(stream.private_data as *mut StreamPrivate));
+    stream.release = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::MaybeUninit;
+
+    use super::*;
+
+    #[test]
+    fn test_arrow_stream() -> Result<()> {
+        let mut builder = ArrowStreamBuilder::new(&["taxid", "id"], 2);
+        for (taxid, id) in [("562", "r1"), ("9606", "r2"), ("1280", "r3")] {
+            builder.push_row(&[taxid.as_bytes(), id.as_bytes()])?;
+        }
+        assert!(builder.push_row(&[b"562"]).is_err());
+
+        let mut stream = MaybeUninit::<FfiArrayStream>::zeroed();
+        builder.export(&format!("{}", stream.as_mut_ptr() as usize))?;
+        let mut stream = unsafe { stream.assume_init() };
+        unsafe {
+            let mut schema = MaybeUninit::<FfiSchema>::zeroed();
+            assert_eq!(
+                stream.get_schema.unwrap()(&mut stream, schema.as_mut_ptr()),
+                0
+            );
+            let mut schema = schema.assume_init();
+            assert_eq!(CStr::from_ptr(schema.format), c"+s");
+            assert_eq!(schema.n_children, 2);
+            let child = &**schema.children.add(1);
+            assert_eq!(CStr::from_ptr(child.name), c"id");
+            assert_eq!(CStr::from_ptr(child.format), c"u");
+            schema.release.unwrap()(&mut schema);
+            assert!(schema.release.is_none());
+
+            let mut rows = Vec::new();
+            loop {
+                let mut array = MaybeUninit::<FfiArray>::zeroed();
+                assert_eq!(stream.get_next.unwrap()(&mut stream, array.as_mut_ptr()), 0);
+                let mut array = array.assume_init();
+                let Some(release) = array.release else {
+                    break;
+                };
+                let column = |i: usize, row: usize| {
+                    let child = &**array.children.add(i);
+                    let offsets = *child.buffers.add(1) as *const i32;
+                    let data = *child.buffers.add(2) as *const u8;
+                    let start = *offsets.add(row) as usize;
+                    let end = *offsets.add(row + 1) as usize;
+                    String::from_utf8_lossy(std::slice::from_raw_parts(
+                        data.add(start),
+                        end - start,
+                    ))
+                    .into_owned()
+                };
+                for row in 0 .. array.length as usize {
+                    rows.push((column(0, row), column(1, row)));
+                }
+                release(&mut array);
+            }
+            assert_eq!(
+                rows,
+                [
+                    ("562".to_string(), "r1".to_string()),
+                    ("9606".to_string(), "r2".to_string()),
+                    ("1280".to_string(), "r3".to_string())
+                ]
+            );
+            stream.release.unwrap()(&mut stream);
+        }
+        assert!(parse_address("0x10").is_ok_and(|x| x == 16));
+        assert!(parse_address("x").is_err());
+        Ok(())
+    }
+}
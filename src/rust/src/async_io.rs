@@ -0,0 +1,391 @@
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+#[cfg(not(feature = "async"))]
+use anyhow::Context;
+use anyhow::Result;
+use indicatif::ProgressBar;
+
+use crate::io_retry::{append_retry, RetryFile};
+
+// Built with the `async` feature, local files are read and written by the
+// tasks of a small shared tokio runtime rather than by the threads consuming
+// or producing them: a run fanning out to thousands of outputs (e.g. one per
+// cell barcode) keeps a handful of I/O threads, while the reader, parser and
+// writer threads only hand blocks over. Remote inputs are still streamed by
+// the blocking HTTP client of the `remote` feature.
+
+/// Read a local file through the I/O runtime if built with the `async`
+/// feature, directly otherwise, retrying transient errors
+#[cfg(not(feature = "async"))]
+pub(crate) fn file_reader(file: RetryFile) -> Box<dyn Read> {
+    Box::new(file)
+}
+
+/// Write a local file through the I/O runtime if built with the `async`
+/// feature, directly otherwise, retrying transient errors
+#[cfg(not(feature = "async"))]
+pub(crate) fn file_writer(file: RetryFile) -> Box<dyn Write> {
+    Box::new(file)
+}
+
+#[cfg(feature = "async")]
+pub(crate) fn file_reader(file: RetryFile) -> Box<dyn Read> {
+    Box::new(runtime::AsyncReader::new(file.into_file()))
+}
+
+#[cfg(feature = "async")]
+pub(crate) fn file_writer(file: RetryFile) -> Box<dyn Write> {
+    Box::new(runtime::AsyncWriter::new(file.into_file()))
+}
+
+/// Appends chunks to many output files, opening each file only for the time
+/// of an append, so no file handle is kept open per output.
+///
+/// Built with the `async` feature, appends are written by tasks of the I/O
+/// runtime, those of an output in order, and `append()` only waits when too
+/// many bytes are in flight. Errors of an output are then reported by its
+/// next append or by `finish()`.
+pub(crate) struct OutputAppends {
+    #[cfg(feature = "async")]
+    pending: runtime::PendingAppends,
+}
+
+impl OutputAppends {
+    pub(crate) fn new() -> Self {
+        Self {
+            #[cfg(feature = "async")]
+            pending: runtime::PendingAppends::new(),
+        }
+    }
+
+    /// Append `pack` to the file at `path`, replacing the file first if
+    /// `truncate`, like `File::create()`
+    #[cfg(not(feature = "async"))]
+    pub(crate) fn append(
+        &mut self,
+        path: PathBuf,
+        pack: Vec<u8>,
+        truncate: bool,
+        bar: Option<&ProgressBar>,
+    ) -> Result<()> {
+        append_retry(&path, &pack, truncate)
+            .with_context(|| format!("(Writer) Failed to write to {}", path.display()))?;
+        if let Some(bar) = bar {
+            bar.inc(pack.len() as u64);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn append(
+        &mut self,
+        path: PathBuf,
+        pack: Vec<u8>,
+        truncate: bool,
+        bar: Option<&ProgressBar>,
+    ) -> Result<()> {
+        self.pending.append(path, pack, truncate, bar.cloned())
+    }
+
+    /// Wait for all appends to be written
+    pub(crate) fn finish(self) -> Result<()> {
+        #[cfg(feature = "async")]
+        self.pending.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+mod runtime {
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+    use std::sync::{Arc, OnceLock};
+
+    use anyhow::{anyhow, Context, Result};
+    use indicatif::ProgressBar;
+    use rustc_hash::FxHashMap as HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::runtime::Runtime;
+    use tokio::sync::{mpsc, oneshot, Semaphore};
+    use tokio::task::JoinHandle;
+
+    use super::append_retry;
+    use crate::utils::BUFFER_SIZE;
+
+    // Tokio runs file operations on its blocking pool: these threads are
+    // shared by all files of all running calls
+    const IO_WORKERS: usize = 2;
+    const IO_BLOCKING_THREADS: usize = 8;
+    // Blocks a file is read ahead of its consuming thread
+    const READ_AHEAD_BLOCKS: usize = 4;
+    // Bytes of appends in flight over all outputs of an `OutputAppends`
+    const APPEND_BYTES: usize = 256 * 1024 * 1024;
+
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+    fn runtime() -> &'static Runtime {
+        RUNTIME.get_or_init(|| {
+            tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(IO_WORKERS)
+                .max_blocking_threads(IO_BLOCKING_THREADS)
+                .thread_name("mire-io")
+                .build()
+                // Only fails when the OS cannot spawn threads
+                .expect("Failed to start the I/O runtime")
+        })
+    }
+
+    fn join_error(e: tokio::task::JoinError) -> std::io::Error {
+        std::io::Error::other(format!("I/O task failed: {}", e))
+    }
+
+    /// A file read in blocks by a task of the I/O runtime
+    pub(super) struct AsyncReader {
+        block_rx: mpsc::Receiver<std::io::Result<Vec<u8>>>,
+        block: Vec<u8>,
+        pos: usize,
+    }
+
+    impl AsyncReader {
+        pub(super) fn new(file: File) -> Self {
+            let (block_tx, block_rx) = mpsc::channel(READ_AHEAD_BLOCKS);
+            let mut file = tokio::fs::File::from_std(file);
+            runtime().spawn(async move {
+                loop {
+                    let mut block = vec![0; BUFFER_SIZE];
+                    let block = file.read(&mut block).await.map(|nbytes| {
+                        block.truncate(nbytes);
+                        block
+                    });
+                    let end = block.as_ref().map_or(true, |x| x.is_empty());
+                    // The consuming thread stops receiving when dropped
+                    if block_tx.send(block).await.is_err() || end {
+                        return;
+                    }
+                }
+            });
+            Self {
+                block_rx,
+                block: Vec::new(),
+                pos: 0,
+            }
+        }
+    }
+
+    impl Read for AsyncReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.pos == self.block.len() {
+                match self.block_rx.blocking_recv() {
+                    Some(block) => {
+                        self.block = block?;
+                        self.pos = 0;
+                    }
+                    // The end of the file was sent as an empty block
+                    None => return Ok(0),
+                }
+            }
+            let nbytes = buf.len().min(self.block.len() - self.pos);
+            buf[.. nbytes].copy_from_slice(&self.block[self.pos .. self.pos + nbytes]);
+            self.pos += nbytes;
+            Ok(nbytes)
+        }
+    }
+
+    enum WriteRequest {
+        Data(Vec<u8>),
+        Flush(oneshot::Sender<std::io::Result<()>>),
+    }
+
+    /// A file written by a task of the I/O runtime. Writes are buffered into
+    /// blocks handed over to the task, `flush()` waits for all of them to be
+    /// written. A writer dropped without a flush still waits for the task.
+    pub(super) struct AsyncWriter {
+        request_tx: Option<mpsc::Sender<WriteRequest>>,
+        task: Option<JoinHandle<std::io::Result<()>>>,
+        buffer: Vec<u8>,
+    }
+
+    impl AsyncWriter {
+        pub(super) fn new(file: File) -> Self {
+            let (request_tx, mut request_rx) = mpsc::channel(READ_AHEAD_BLOCKS);
+            let mut file = tokio::fs::File::from_std(file);
+            let task = runtime().spawn(async move {
+                while let Some(request) = request_rx.recv().await {
+                    match request {
+                        WriteRequest::Data(block) => file.write_all(&block).await?,
+                        WriteRequest::Flush(done_tx) => {
+                            done_tx.send(file.flush().await).ok();
+                        }
+                    }
+                }
+                file.flush().await
+            });
+            Self {
+                request_tx: Some(request_tx),
+                task: Some(task),
+                buffer: Vec::with_capacity(BUFFER_SIZE),
+            }
+        }
+
+        fn send(&mut self, request: WriteRequest) -> std::io::Result<()> {
+            let sent = self
+                .request_tx
+                .as_ref()
+                .is_some_and(|tx| tx.blocking_send(request).is_ok());
+            if sent {
+                Ok(())
+            } else {
+                // The task only stops early on errors
+                Err(self.join().err().unwrap_or_else(|| {
+                    std::io::Error::other("I/O task stopped before the end of the file")
+                }))
+            }
+        }
+
+        fn join(&mut self) -> std::io::Result<()> {
+            self.request_tx = None;
+            match self.task.take() {
+                Some(task) => runtime().block_on(task).map_err(join_error)?,
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl Write for AsyncWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.buffer.extend_from_slice(buf);
+            if self.buffer.len() >= BUFFER_SIZE {
+                let block = std::mem::replace(&mut self.buffer, Vec::with_capacity(BUFFER_SIZE));
+                self.send(WriteRequest::Data(block))?;
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            if !self.buffer.is_empty() {
+                let block = std::mem::take(&mut self.buffer);
+                self.send(WriteRequest::Data(block))?;
+            }
+            let (done_tx, done_rx) = oneshot::channel();
+            self.send(WriteRequest::Flush(done_tx))?;
+            done_rx
+                .blocking_recv()
+                .map_err(|_| std::io::Error::other("I/O task stopped before the flush"))?
+        }
+    }
+
+    impl Drop for AsyncWriter {
+        fn drop(&mut self) {
+            if !self.buffer.is_empty() {
+                let block = std::mem::take(&mut self.buffer);
+                self.send(WriteRequest::Data(block)).ok();
+            }
+            self.join().ok();
+        }
+    }
+
+    /// The appends of [`super::OutputAppends`] in flight, by output
+    pub(super) struct PendingAppends {
+        tasks: HashMap<PathBuf, JoinHandle<std::io::Result<()>>>,
+        permits: Arc<Semaphore>,
+    }
+
+    impl PendingAppends {
+        pub(super) fn new() -> Self {
+            Self {
+                tasks: HashMap::default(),
+                permits: Arc::new(Semaphore::new(APPEND_BYTES)),
+            }
+        }
+
+        pub(super) fn append(
+            &mut self,
+            path: PathBuf,
+            pack: Vec<u8>,
+            truncate: bool,
+            bar: Option<ProgressBar>,
+        ) -> Result<()> {
+            let permit = runtime()
+                .block_on(
+                    self.permits
+                        .clone()
+                        .acquire_many_owned(pack.len().clamp(1, APPEND_BYTES) as u32),
+                )
+                .map_err(|e| anyhow!("(Writer) Failed to queue output chunk: {}", e))?;
+            // Appends to an output are chained, so they land in order
+            let previous = self.tasks.remove(&path);
+            let output = path.clone();
+            let task = runtime().spawn(async move {
+                let _permit = permit;
+                if let Some(previous) = previous {
+                    previous.await.map_err(join_error)??;
+                }
+                let nbytes = pack.len() as u64;
+                tokio::task::spawn_blocking(move || append_retry(&output, &pack, truncate))
+                    .await
+                    .map_err(join_error)??;
+                if let Some(bar) = bar {
+                    bar.inc(nbytes);
+                }
+                Ok(())
+            });
+            self.tasks.insert(path, task);
+            // Reap the appends already written, reporting their errors early
+            let done = self
+                .tasks
+                .iter()
+                .filter(|(_, task)| task.is_finished())
+                .map(|(path, _)| path.clone())
+                .collect::<Vec<_>>();
+            for path in done {
+                if let Some(task) = self.tasks.remove(&path) {
+                    wait_append(&path, task)?;
+                }
+            }
+            Ok(())
+        }
+
+        pub(super) fn finish(self) -> Result<()> {
+            for (path, task) in self.tasks {
+                wait_append(&path, task)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn wait_append(path: &std::path::Path, task: JoinHandle<std::io::Result<()>>) -> Result<()> {
+        runtime()
+            .block_on(task)
+            .map_err(join_error)
+            .and_then(|x| x)
+            .with_context(|| format!("(Writer) Failed to write to {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io_retry::{create_retry, open_retry};
+
+    #[test]
+    fn test_output_appends() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let path = temp.path().join("reads.fq");
+        std::fs::write(&path, b"stale")?;
+        let mut appends = OutputAppends::new();
+        for (i, chunk) in ["@r1\n", "ACGT\n", "+\n", "IIII\n"].iter().enumerate() {
+            appends.append(path.clone(), chunk.as_bytes().to_vec(), i == 0, None)?;
+        }
+        appends.finish()?;
+        assert_eq!(std::fs::read_to_string(&path)?, "@r1\nACGT\n+\nIIII\n");
+
+        let copy = temp.path().join("copy.fq");
+        let mut writer = file_writer(create_retry(&copy)?);
+        std::io::copy(&mut file_reader(open_retry(&path)?), &mut writer)?;
+        writer.flush()?;
+        assert_eq!(std::fs::read(&copy)?, std::fs::read(&path)?);
+        Ok(())
+    }
+}
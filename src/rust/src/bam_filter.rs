@@ -0,0 +1,970 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use extendr_api::prelude::*;
+use flate2::bufread::MultiGzDecoder;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::bam_index::{alignment_span, BaiIndex, BgzfReader, Region, MAX_PREALLOCATED};
+use crate::bam_writer::*;
+use crate::error::RError;
+use crate::part_writer::ChunkedWriter;
+use crate::utils::*;
+
+#[allow(clippy::too_many_arguments)]
+#[extendr]
+fn bam_filter(
+    ifile: &str,
+    ofile: &str,
+    barcodes: Option<Vec<String>>,
+    barcode_tag: &str,
+    taxids: Option<Vec<String>>,
+    taxid_tag: &str,
+    regions: Option<Vec<String>>,
+    index: Option<&str>,
+    compression_level: Option<i32>,
+) -> std::result::Result<List, RError> {
+    let filter = TagFilter::new(barcodes, barcode_tag, taxids, taxid_tag).map_err(RError::from)?;
+    let counts = match regions {
+        Some(regions) => filter_regions(ifile, index, &regions, ofile, &filter, compression_level),
+        None => filter_alignments(ifile, ofile, &filter, compression_level),
+    }
+    .with_context(|| format!("Failed to filter alignments of {}", ifile))
+    .map_err(RError::from)?;
+    Ok(list!(
+        records = counts.records as f64,
+        kept = counts.kept as f64
+    ))
+}
+
+#[extendr]
+fn bam_unmapped(
+    ifile: &str,
+    ofile1: &str,
+    ofile2: Option<&str>,
+    compression_level: Option<i32>,
+    chunk_bytes: usize,
+) -> std::result::Result<List, RError> {
+    let paths: Vec<&Path> = std::iter::once(ofile1)
+        .chain(ofile2)
+        .map(Path::new)
+        .collect();
+    let compressions = paths
+        .iter()
+        .map(|path| Compression::new(path, compression_level))
+        .collect::<Result<Vec<_>>>()
+        .map_err(RError::from)?;
+    let counts = (|| -> Result<HarvestCounts> {
+        let mut input = open_alignments(ifile)?;
+        let mut outputs: Vec<ChunkedWriter> = paths
+            .iter()
+            .zip(compressions)
+            .map(|(path, compression)| ChunkedWriter::new(path, compression, chunk_bytes))
+            .collect();
+        let counts = harvest_unmapped(&mut input, ofile2.is_some(), |i, fastq| {
+            outputs[i].write(fastq)
+        })?;
+        for output in outputs {
+            output.finish()?;
+        }
+        Ok(counts)
+    })()
+    .with_context(|| format!("Failed to harvest unmapped reads of {}", ifile))
+    .map_err(RError::from)?;
+    Ok(list!(
+        records = counts.records as f64,
+        reads = counts.reads as f64,
+        pairs = counts.pairs as f64,
+        orphans = counts.orphans as f64
+    ))
+}
+
+extendr_module! {
+    mod bam_filter;
+    fn bam_filter;
+    fn bam_unmapped;
+}
+
+const CRAM_MAGIC: &[u8] = b"CRAM";
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+// BAM records hold at least their 32 bytes of fixed fields. Records of the
+// longest reads take a few tens of MiB, larger block sizes are corrupt
+const MIN_BAM_RECORD: usize = 32;
+const MAX_BAM_RECORD: usize = 1 << 28;
+
+// SAM flags
+const FLAG_REVERSE: u16 = 0x10;
+const FLAG_SECONDARY: u16 = 0x100;
+const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+// The quality written for reads stored without qualities
+const MISSING_QUALITY: u8 = b'!';
+
+/// The records kept by a tag value filter, out of all records read
+#[derive(Default)]
+struct FilterCounts {
+    records: usize,
+    kept: usize,
+}
+
+/// Keep the alignment records whose cell barcode is in a whitelist and whose
+/// taxid is in a set, each test only applying when given
+struct TagFilter {
+    barcodes: Option<FxHashSet<Vec<u8>>>,
+    barcode_tag: [u8; 2],
+    taxids: Option<FxHashSet<Vec<u8>>>,
+    taxid_tag: [u8; 2],
+}
+
+impl TagFilter {
+    fn new(
+        barcodes: Option<Vec<String>>,
+        barcode_tag: &str,
+        taxids: Option<Vec<String>>,
+        taxid_tag: &str,
+    ) -> Result<Self> {
+        let tag = |tag: &str| -> Result<[u8; 2]> {
+            tag.as_bytes()
+                .try_into()
+                .ok()
+                .filter(|x: &[u8; 2]| x[0].is_ascii_alphabetic() && x[1].is_ascii_alphanumeric())
+                .ok_or_else(|| anyhow!("Invalid SAM tag: {}", tag))
+        };
+        let set = |values: Option<Vec<String>>| {
+            values.map(|values| {
+                values
+                    .into_iter()
+                    .map(String::into_bytes)
+                    .collect::<FxHashSet<_>>()
+            })
+        };
+        Ok(Self {
+            barcodes: set(barcodes),
+            barcode_tag: tag(barcode_tag)?,
+            taxids: set(taxids),
+            taxid_tag: tag(taxid_tag)?,
+        })
+    }
+
+    /// Whether a record passes, `tag` giving the value of one of its tags
+    fn accept<'a, F>(&self, tag: F) -> bool
+    where
+        F: Fn(&[u8; 2]) -> Option<Cow<'a, [u8]>>,
+    {
+        let pass = |values: &Option<FxHashSet<Vec<u8>>>, name: &[u8; 2]| {
+            values
+                .as_ref()
+                .is_none_or(|values| tag(name).is_some_and(|value| values.contains(&*value)))
+        };
+        pass(&self.barcodes, &self.barcode_tag) && pass(&self.taxids, &self.taxid_tag)
+    }
+}
+
+/// Filter the records of a SAM or BAM file into a BAM file, keeping the
+/// header as is
+fn filter_alignments(
+    ifile: &str,
+    ofile: &str,
+    filter: &TagFilter,
+    compression_level: Option<i32>,
+) -> Result<FilterCounts> {
+    let input = open_alignments(ifile)?;
+    let compression = Compression::with_format(ChunkFormat::Gzip, compression_level)?;
+    let mut writer = BgzfWriter::new(new_writer(ofile, None)?, compression.compressor());
+    let counts = match input {
+        Alignments::Bam(reader) => filter_bam(reader, &mut writer, filter)?,
+        Alignments::Sam(reader) => filter_sam(reader, &mut writer, filter)?,
+    };
+    writer.finish()?;
+    Ok(counts)
+}
+
+/// Filter the records of a BAM file overlapping `regions` into a BAM file,
+/// reading only the parts of the file located by its index. The records are
+/// then filtered by tags as those of the whole file.
+fn filter_regions(
+    ifile: &str,
+    index: Option<&str>,
+    regions: &[String],
+    ofile: &str,
+    filter: &TagFilter,
+    compression_level: Option<i32>,
+) -> Result<FilterCounts> {
+    let file = File::open(ifile).with_context(|| format!("Failed to open file: {}", ifile))?;
+    let mut reader = BgzfReader::new(BufReader::new(file));
+    let compression = Compression::with_format(ChunkFormat::Gzip, compression_level)?;
+    let mut writer = BgzfWriter::new(new_writer(ofile, None)?, compression.compressor());
+    let references = copy_bam_header(&mut reader, &mut writer)?;
+    let regions = regions
+        .iter()
+        .map(|region| Region::parse(region, &references))
+        .collect::<Result<Vec<_>>>()?;
+    let index = match index {
+        Some(index) => BaiIndex::from_path(index)?,
+        None => {
+            let bai = format!("{}.bai", ifile);
+            let sibling = Path::new(ifile).with_extension("bai");
+            if Path::new(&bai).exists() || !sibling.exists() {
+                BaiIndex::from_path(&bai)?
+            } else {
+                BaiIndex::from_path(&sibling)?
+            }
+        }
+    };
+
+    let mut chunks = index.chunks(&regions).into_iter();
+    // The end of the chunk being read, none read yet
+    let mut chunk_end = 0;
+    let counts = filter_records(&mut writer, filter, |record| loop {
+        if reader
+            .virtual_offset()?
+            .is_none_or(|offset| offset >= chunk_end)
+        {
+            let Some((start, end)) = chunks.next() else {
+                return Ok(false);
+            };
+            reader.seek(start)?;
+            chunk_end = end;
+        }
+        if !read_bam_record(&mut reader, record)? {
+            chunk_end = 0;
+            continue;
+        }
+        let Some((reference, start, end)) = alignment_span(record) else {
+            continue;
+        };
+        if regions.iter().any(|x| x.overlaps(reference, start, end)) {
+            return Ok(true);
+        }
+    })?;
+    writer.finish()?;
+    Ok(counts)
+}
+
+/// An alignment input, SAM or BAM by its content
+enum Alignments {
+    Sam(BufReader<Box<dyn Read>>),
+    Bam(MultiGzDecoder<BufReader<Box<dyn Read>>>),
+}
+
+fn open_alignments(ifile: &str) -> Result<Alignments> {
+    let mut reader = BufReader::new(new_reader(ifile, BUFFER_SIZE, None)?);
+    let head = reader.fill_buf().context("(Reader) Failed to read input")?;
+    if head.starts_with(CRAM_MAGIC) {
+        Err(crate::error::ErrorKind::Config.error(
+            "CRAM input is not supported, convert it to BAM first (e.g. `samtools view -b`)",
+        ))
+    } else if head.starts_with(GZIP_MAGIC) {
+        Ok(Alignments::Bam(MultiGzDecoder::new(reader)))
+    } else {
+        Ok(Alignments::Sam(reader))
+    }
+}
+
+impl Alignments {
+    /// The read of the next record, the BAM header having been read
+    fn next_read(&mut self, buffer: &mut Vec<u8>) -> Result<Option<AlignedRead>> {
+        match self {
+            Self::Sam(reader) => loop {
+                buffer.clear();
+                if reader
+                    .read_until(b'\n', buffer)
+                    .context("(Reader) Failed to read SAM line")?
+                    == 0
+                {
+                    return Ok(None);
+                }
+                if !buffer.starts_with(b"@") {
+                    return AlignedRead::from_sam(buffer).map(Some);
+                }
+            },
+            Self::Bam(reader) => {
+                if read_bam_record(reader, buffer)? {
+                    AlignedRead::from_bam(buffer).map(Some)
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
+}
+
+/// The read of an alignment record, back on the strand it was sequenced from
+struct AlignedRead {
+    name: Vec<u8>,
+    flag: u16,
+    seq: Vec<u8>,
+    qual: Vec<u8>,
+}
+
+impl AlignedRead {
+    fn from_sam(line: &[u8]) -> Result<Self> {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let fields: Vec<&[u8]> = line.splitn(12, |x| *x == b'\t').collect();
+        if fields.len() < 11 {
+            return Err(anyhow!(
+                "(Reader) Invalid SAM line: {}",
+                String::from_utf8_lossy(line)
+            ));
+        }
+        let flag = std::str::from_utf8(fields[1])
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .ok_or_else(|| {
+                anyhow!(
+                    "(Reader) Invalid SAM flag: {}",
+                    String::from_utf8_lossy(fields[1])
+                )
+            })?;
+        let seq = if fields[9] == b"*" {
+            &[][..]
+        } else {
+            fields[9]
+        };
+        let qual = if fields[10] == b"*" {
+            vec![MISSING_QUALITY; seq.len()]
+        } else {
+            fields[10].to_vec()
+        };
+        Ok(Self::new(fields[0].to_vec(), flag, seq.to_vec(), qual))
+    }
+
+    fn from_bam(record: &[u8]) -> Result<Self> {
+        let invalid = || anyhow!("(Reader) Invalid BAM record");
+        if record.len() < 32 {
+            return Err(invalid());
+        }
+        let name_len = record[8] as usize;
+        let cigar_len = u16::from_le_bytes([record[12], record[13]]) as usize;
+        let flag = u16::from_le_bytes([record[14], record[15]]);
+        let seq_len = u32::from_le_bytes([record[16], record[17], record[18], record[19]]) as usize;
+        let name = record.get(32 .. 32 + name_len).ok_or_else(invalid)?;
+        let name = name.strip_suffix(b"\0").unwrap_or(name);
+        let start = 32 + name_len + 4 * cigar_len;
+        let packed = record
+            .get(start .. start + seq_len.div_ceil(2))
+            .ok_or_else(invalid)?;
+        let seq = (0 .. seq_len)
+            .map(|i| BAM_BASES[((packed[i / 2] >> (4 * (1 - i % 2))) & 0x0f) as usize])
+            .collect();
+        let start = start + seq_len.div_ceil(2);
+        let qual = record.get(start .. start + seq_len).ok_or_else(invalid)?;
+        let qual = if qual.first() == Some(&0xff) {
+            vec![MISSING_QUALITY; seq_len]
+        } else {
+            qual.iter().map(|q| q.saturating_add(33)).collect()
+        };
+        Ok(Self::new(name.to_vec(), flag, seq, qual))
+    }
+
+    fn new(name: Vec<u8>, flag: u16, mut seq: Vec<u8>, mut qual: Vec<u8>) -> Self {
+        if flag & FLAG_REVERSE != 0 {
+            seq.reverse();
+            seq.iter_mut().for_each(|base| *base = complement(*base));
+            qual.reverse();
+        }
+        Self {
+            name,
+            flag,
+            seq,
+            qual,
+        }
+    }
+
+    fn is_paired(&self) -> bool {
+        self.flag & FLAG_PAIRED != 0
+    }
+
+    /// Primary records of unmapped reads, or of reads whose mate is unmapped
+    fn is_harvested(&self) -> bool {
+        self.flag & (FLAG_SECONDARY | FLAG_SUPPLEMENTARY) == 0
+            && (self.flag & FLAG_UNMAPPED != 0
+                || (self.is_paired() && self.flag & FLAG_MATE_UNMAPPED != 0))
+    }
+
+    /// Write the read as a FASTQ record, with a `/1` or `/2` suffix for mates
+    fn write_fastq(&self, out: &mut Vec<u8>) {
+        out.push(b'@');
+        out.extend_from_slice(&self.name);
+        if self.is_paired() {
+            out.extend_from_slice(if self.flag & FLAG_FIRST != 0 {
+                b"/1"
+            } else {
+                b"/2"
+            });
+        }
+        out.push(b'\n');
+        out.extend_from_slice(&self.seq);
+        out.extend_from_slice(b"\n+\n");
+        out.extend_from_slice(&self.qual);
+        out.push(b'\n');
+    }
+}
+
+fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        b'a' => b't',
+        b'c' => b'g',
+        b'g' => b'c',
+        b't' => b'a',
+        b'M' => b'K',
+        b'K' => b'M',
+        b'R' => b'Y',
+        b'Y' => b'R',
+        b'V' => b'B',
+        b'B' => b'V',
+        b'H' => b'D',
+        b'D' => b'H',
+        x => x,
+    }
+}
+
+/// The reads harvested from an alignment file
+#[derive(Default)]
+struct HarvestCounts {
+    records: usize,
+    // Reads of the harvested records
+    reads: usize,
+    // Pairs written to the two outputs
+    pairs: usize,
+    // Reads left out of the two outputs: single-end reads and mates whose
+    // partner was not harvested
+    orphans: usize,
+}
+
+/// Write the unmapped reads of `input`, and the mates of unmapped reads, as
+/// FASTQ records to `write`. With `paired`, mates are paired up by name and
+/// written to outputs 0 and 1, otherwise all reads are written to output 0.
+fn harvest_unmapped<F>(input: &mut Alignments, paired: bool, mut write: F) -> Result<HarvestCounts>
+where
+    F: FnMut(usize, &[u8]) -> Result<()>,
+{
+    if let Alignments::Bam(reader) = input {
+        copy_bam_header(reader, &mut std::io::sink())?;
+    }
+    let mut counts = HarvestCounts::default();
+    // Mates waiting for their partner, which coordinate-sorted files place
+    // anywhere
+    let mut pending: FxHashMap<Vec<u8>, AlignedRead> = FxHashMap::default();
+    let mut buffer = Vec::new();
+    let mut fastq = Vec::new();
+    while let Some(read) = input.next_read(&mut buffer)? {
+        counts.records += 1;
+        if !read.is_harvested() {
+            continue;
+        }
+        counts.reads += 1;
+        if !paired {
+            fastq.clear();
+            read.write_fastq(&mut fastq);
+            write(0, &fastq)?;
+            continue;
+        }
+        if !read.is_paired() {
+            counts.orphans += 1;
+            continue;
+        }
+        let Some(mate) = pending.remove(&read.name) else {
+            pending.insert(read.name.clone(), read);
+            continue;
+        };
+        let (read1, read2) = if read.flag & FLAG_FIRST != 0 {
+            (read, mate)
+        } else {
+            (mate, read)
+        };
+        for (i, read) in [read1, read2].iter().enumerate() {
+            fastq.clear();
+            read.write_fastq(&mut fastq);
+            write(i, &fastq)?;
+        }
+        counts.pairs += 1;
+    }
+    counts.orphans += pending.len();
+    Ok(counts)
+}
+
+/// Filter the records of a SAM file into BAM records, the header text being
+/// written as is
+fn filter_sam<R: BufRead, W: Write>(
+    mut reader: R,
+    writer: &mut W,
+    filter: &TagFilter,
+) -> Result<FilterCounts> {
+    let mut read_line = |line: &mut Vec<u8>| -> Result<bool> {
+        line.clear();
+        Ok(reader
+            .read_until(b'\n', line)
+            .context("(Reader) Failed to read SAM line")?
+            > 0)
+    };
+    // The header ends at the first record
+    let mut text = Vec::new();
+    let mut line = Vec::new();
+    while read_line(&mut line)? && line.starts_with(b"@") {
+        text.extend_from_slice(&line);
+    }
+    let references = sam_references(&text)?;
+    write_bam_header(writer, &text, &references).context("(Writer) Failed to write BAM header")?;
+    let references: FxHashMap<Vec<u8>, i32> = references
+        .into_iter()
+        .enumerate()
+        .map(|(i, (name, _))| (name, i as i32))
+        .collect();
+
+    let mut counts = FilterCounts::default();
+    while !line.is_empty() {
+        if !line.iter().all(|x| x.is_ascii_whitespace()) {
+            counts.records += 1;
+            if filter.accept(|tag| sam_tag(&line, tag).map(Cow::Borrowed)) {
+                counts.kept += 1;
+                write_bam_record(writer, &sam_bam_record(&line, &references)?)?;
+            }
+        }
+        read_line(&mut line)?;
+    }
+    Ok(counts)
+}
+
+/// The name and length of the reference sequences of a SAM header, from its
+/// `@SQ` lines
+fn sam_references(text: &[u8]) -> Result<Vec<(Vec<u8>, u32)>> {
+    let mut references = Vec::new();
+    for line in text.split(|x| *x == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let Some(fields) = line.strip_prefix(b"@SQ\t") else {
+            continue;
+        };
+        let field = |tag: &[u8]| {
+            fields
+                .split(|x| *x == b'\t')
+                .find_map(|x| x.strip_prefix(tag))
+        };
+        let len = field(b"LN:")
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| x.parse().ok());
+        match (field(b"SN:"), len) {
+            (Some(name), Some(len)) => references.push((name.to_vec(), len)),
+            _ => {
+                return Err(anyhow!(
+                    "(Reader) Invalid SAM header line: {}",
+                    String::from_utf8_lossy(line)
+                ))
+            }
+        }
+    }
+    Ok(references)
+}
+
+fn filter_bam<R: Read, W: Write>(
+    mut reader: R,
+    writer: &mut W,
+    filter: &TagFilter,
+) -> Result<FilterCounts> {
+    copy_bam_header(&mut reader, writer)?;
+    filter_records(writer, filter, |record| {
+        read_bam_record(&mut reader, record)
+    })
+}
+
+/// Write the BAM records passing `filter`, `next` reading each record until
+/// it returns `false`
+fn filter_records<W, F>(writer: &mut W, filter: &TagFilter, mut next: F) -> Result<FilterCounts>
+where
+    W: Write,
+    F: FnMut(&mut Vec<u8>) -> Result<bool>,
+{
+    let mut counts = FilterCounts::default();
+    let mut record = Vec::new();
+    while next(&mut record)? {
+        counts.records += 1;
+        if filter.accept(|tag| bam_tag(&record, tag)) {
+            counts.kept += 1;
+            write_bam_record(writer, &record)?;
+        }
+    }
+    Ok(counts)
+}
+
+/// Copy the BAM header, its text and reference sequences, as is. Returns the
+/// names of the reference sequences.
+fn copy_bam_header<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<Vec<Vec<u8>>> {
+    let mut magic = [0u8; 4];
+    reader
+        .read_exact(&mut magic)
+        .context("(Reader) Failed to read BAM header")?;
+    if magic != BAM_MAGIC {
+        return Err(anyhow!("(Reader) Invalid BAM magic number"));
+    }
+    writer.write_all(&magic)?;
+    let text = read_i32(reader)?;
+    copy_bytes(reader, writer, text)?;
+    let references = read_i32(reader)?;
+    writer.write_all(&references.to_le_bytes())?;
+    let mut names = Vec::with_capacity((references as usize).min(MAX_PREALLOCATED));
+    for _ in 0 .. references {
+        let len = read_i32(reader)?;
+        // Read as it comes, rather than allocated from the length
+        let mut name = Vec::new();
+        reader.by_ref().take(len as u64).read_to_end(&mut name)?;
+        if name.len() < len as usize {
+            return Err(anyhow!("(Reader) Truncated BAM header"));
+        }
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(&name)?;
+        let mut length = [0u8; 4];
+        reader
+            .read_exact(&mut length)
+            .context("(Reader) Truncated BAM header")?;
+        writer.write_all(&length)?;
+        name.pop_if(|x| *x == 0);
+        names.push(name);
+    }
+    Ok(names)
+}
+
+/// Read the next BAM record, without its block size, into `record`. Returns
+/// `false` at the end of the file.
+fn read_bam_record<R: Read>(reader: &mut R, record: &mut Vec<u8>) -> Result<bool> {
+    let mut size = [0u8; 4];
+    match reader.read_exact(&mut size) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+        Err(e) => return Err(e).context("(Reader) Failed to read BAM record"),
+    }
+    let size = i32::from_le_bytes(size);
+    if !(MIN_BAM_RECORD ..= MAX_BAM_RECORD).contains(&(size.max(0) as usize)) {
+        return Err(crate::error::ErrorKind::Parse
+            .error(format!("(Reader) Invalid BAM record size {}", size)));
+    }
+    record.resize(size as usize, 0);
+    reader
+        .read_exact(record)
+        .context("(Reader) Truncated BAM record")?;
+    Ok(true)
+}
+
+/// Read a little-endian length of the BAM header
+fn read_i32<R: Read>(reader: &mut R) -> Result<i32> {
+    let mut bytes = [0u8; 4];
+    reader
+        .read_exact(&mut bytes)
+        .context("(Reader) Truncated BAM header")?;
+    let value = i32::from_le_bytes(bytes);
+    if value < 0 {
+        return Err(anyhow!("(Reader) Invalid BAM header length {}", value));
+    }
+    Ok(value)
+}
+
+/// Copy a length of the BAM header, along with the length itself
+fn copy_bytes<R: Read, W: Write>(reader: &mut R, writer: &mut W, len: i32) -> Result<()> {
+    writer.write_all(&len.to_le_bytes())?;
+    let copied = std::io::copy(&mut reader.take(len as u64), writer)?;
+    if copied < len as u64 {
+        return Err(anyhow!("(Reader) Truncated BAM header"));
+    }
+    Ok(())
+}
+
+/// The value of `tag` in a SAM line, as written
+fn sam_tag<'a>(line: &'a [u8], tag: &[u8; 2]) -> Option<&'a [u8]> {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    line.split(|x| *x == b'\t')
+        .skip(11)
+        .find(|field| field.len() >= 5 && field[.. 2] == tag[..] && field[2] == b':')
+        .map(|field| &field[5 ..])
+}
+
+/// The value of `tag` in a BAM record (without its block size), with
+/// integers in decimal as in SAM. Floats and arrays are never matched.
+fn bam_tag<'a>(record: &'a [u8], tag: &[u8; 2]) -> Option<Cow<'a, [u8]>> {
+    let name_len = *record.get(8)? as usize;
+    let cigar_len = u16::from_le_bytes(record.get(12 .. 14)?.try_into().ok()?) as usize;
+    let seq_len = u32::from_le_bytes(record.get(16 .. 20)?.try_into().ok()?) as usize;
+    let start = 32 + name_len + 4 * cigar_len + seq_len.div_ceil(2) + seq_len;
+    let mut data = record.get(start ..)?;
+    let int = |value: i64| Some(Cow::Owned(value.to_string().into_bytes()));
+    while data.len() >= 3 {
+        let (name, kind, rest) = (&data[.. 2], data[2], &data[3 ..]);
+        let (value, len) = match kind {
+            b'A' => (Some(Cow::Borrowed(rest.get(.. 1)?)), 1),
+            b'c' => (int(*rest.first()? as i8 as i64), 1),
+            b'C' => (int(*rest.first()? as i64), 1),
+            b's' => (
+                int(i16::from_le_bytes(rest.get(.. 2)?.try_into().ok()?) as i64),
+                2,
+            ),
+            b'S' => (
+                int(u16::from_le_bytes(rest.get(.. 2)?.try_into().ok()?) as i64),
+                2,
+            ),
+            b'i' => (
+                int(i32::from_le_bytes(rest.get(.. 4)?.try_into().ok()?) as i64),
+                4,
+            ),
+            b'I' => (
+                int(u32::from_le_bytes(rest.get(.. 4)?.try_into().ok()?) as i64),
+                4,
+            ),
+            b'f' => (None, 4),
+            b'Z' | b'H' => {
+                let end = memchr::memchr(0, rest)?;
+                (Some(Cow::Borrowed(&rest[.. end])), end + 1)
+            }
+            b'B' => {
+                let size = match rest.first()? {
+                    b'c' | b'C' => 1,
+                    b's' | b'S' => 2,
+                    b'i' | b'I' | b'f' => 4,
+                    _ => return None,
+                };
+                let n = u32::from_le_bytes(rest.get(1 .. 5)?.try_into().ok()?) as usize;
+                (None, 5 + n * size)
+            }
+            _ => return None,
+        };
+        if name == tag {
+            return value;
+        }
+        data = rest.get(len ..)?;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use libdeflater::Compressor;
+
+    use super::*;
+
+    /// A BAM record named `r1`, without sequence, with the given tags
+    fn bam_record(tags: &[u8]) -> Vec<u8> {
+        let mut record = Vec::new();
+        record.extend_from_slice(&(-1i32).to_le_bytes()); // refID
+        record.extend_from_slice(&(-1i32).to_le_bytes()); // pos
+        record.extend_from_slice(&[3, 0]); // l_read_name, mapq
+        record.extend_from_slice(&4680u16.to_le_bytes()); // bin
+        record.extend_from_slice(&0u16.to_le_bytes()); // n_cigar_op
+        record.extend_from_slice(&4u16.to_le_bytes()); // flag
+        record.extend_from_slice(&0u32.to_le_bytes()); // l_seq
+        record.extend_from_slice(&(-1i32).to_le_bytes()); // next_refID
+        record.extend_from_slice(&(-1i32).to_le_bytes()); // next_pos
+        record.extend_from_slice(&0i32.to_le_bytes()); // tlen
+        record.extend_from_slice(b"r1\0");
+        record.extend_from_slice(tags);
+        record
+    }
+
+    #[test]
+    fn test_bam_unmapped() -> Result<()> {
+        let sam = "@HD\tVN:1.6\n\
+            r1\t73\tchr1\t100\t60\t4M\t=\t100\t0\tACGG\tABCD\n\
+            r1\t133\tchr1\t100\t0\t*\t=\t100\t0\tTTGA\tEFGH\n\
+            r2\t99\tchr1\t200\t60\t4M\t=\t300\t104\tAAAA\tIIII\n\
+            r2\t147\tchr1\t300\t60\t4M\t=\t200\t-104\tCCCC\tIIII\n\
+            r3\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\t*\n\
+            r4\t89\tchr1\t400\t60\t3M\t=\t400\t0\tAAC\tABC\n\
+            r4\t329\tchr1\t500\t0\t3M\t=\t400\t0\tAAC\tABC\n";
+        let input = |sam: &'static str| {
+            Alignments::Sam(BufReader::new(Box::new(sam.as_bytes()) as Box<dyn Read>))
+        };
+        let mut outputs = [Vec::new(), Vec::new()];
+        let counts = harvest_unmapped(&mut input(sam), true, |i, fastq| {
+            outputs[i].extend_from_slice(fastq);
+            Ok(())
+        })?;
+        assert_eq!(
+            (counts.records, counts.reads, counts.pairs, counts.orphans),
+            (7, 4, 1, 2)
+        );
+        assert_eq!(outputs[0], b"@r1/1\nACGG\n+\nABCD\n");
+        assert_eq!(outputs[1], b"@r1/2\nTTGA\n+\nEFGH\n");
+
+        // Reverse-strand reads are complemented back
+        let mut output = Vec::new();
+        harvest_unmapped(&mut input(sam), false, |_, fastq| {
+            output.extend_from_slice(fastq);
+            Ok(())
+        })?;
+        assert!(output.ends_with(b"@r3\nACGT\n+\n!!!!\n@r4/1\nGTT\n+\nCBA\n"));
+
+        let mut record = bam_record(b"");
+        record[14 .. 16].copy_from_slice(&0x14u16.to_le_bytes());
+        record[16 .. 20].copy_from_slice(&3u32.to_le_bytes());
+        record.extend_from_slice(&[0x12, 0x40, 0, 1, 2]);
+        let read = AlignedRead::from_bam(&record)?;
+        assert_eq!((&read.name[..], &read.seq[..]), (&b"r1"[..], &b"CGT"[..]));
+        assert_eq!(read.qual, b"#\"!");
+        Ok(())
+    }
+
+    #[test]
+    fn test_bam_filter() -> Result<()> {
+        let filter = TagFilter::new(
+            Some(vec!["ACGT".to_string()]),
+            "CB",
+            Some(vec!["562".to_string()]),
+            "kt",
+        )?;
+        assert!(TagFilter::new(None, "C", None, "kt").is_err());
+
+        let kept = bam_record(b"XAf\0\0\0\0CBZACGT\0ktS\x32\x02");
+        let record = bam_record(b"XBB\x43\x02\0\0\0\xab\xcdCBZACGT\0kti\x32\x02\0\0");
+        assert_eq!(bam_tag(&record, b"kt").unwrap(), &b"562"[..]);
+        assert!(bam_tag(&record, b"XB").is_none());
+        let dropped = bam_record(b"CBZAAAA\0ktC\x09");
+
+        let mut bam = Vec::new();
+        bam.extend_from_slice(BAM_MAGIC);
+        bam.extend_from_slice(&10i32.to_le_bytes());
+        bam.extend_from_slice(b"@HD\tVN:1.6");
+        bam.extend_from_slice(&1i32.to_le_bytes());
+        bam.extend_from_slice(&5i32.to_le_bytes());
+        bam.extend_from_slice(b"chr1\0");
+        bam.extend_from_slice(&1000i32.to_le_bytes());
+        for record in [&kept, &record, &dropped] {
+            bam.extend_from_slice(&(record.len() as i32).to_le_bytes());
+            bam.extend_from_slice(record);
+        }
+        let compressor_of = |level| -> Result<Compressor> {
+            Ok(Compression::with_format(ChunkFormat::Gzip, level)?.compressor())
+        };
+        let mut writer = BgzfWriter::new(Vec::new(), compressor_of(None)?);
+        let counts = filter_bam(&bam[..], &mut writer, &filter)?;
+        writer.finish()?;
+        assert_eq!((counts.records, counts.kept), (3, 2));
+        assert!(writer.get_ref().ends_with(BGZF_EOF));
+
+        // Blocks are found again by their virtual offsets
+        let mut bgzf = BgzfWriter::new(Vec::new(), compressor_of(None)?);
+        bgzf.write_all(b"block1")?;
+        bgzf.write_block()?;
+        let second = bgzf.get_ref().len() as u64;
+        bgzf.write_all(b"block2")?;
+        bgzf.finish()?;
+        let mut reader = BgzfReader::new(std::io::Cursor::new(bgzf.get_ref()));
+        reader.seek(second << 16 | 5)?;
+        assert_eq!(reader.virtual_offset()?, Some(second << 16 | 5));
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest)?;
+        assert_eq!(rest, b"2");
+        assert_eq!(reader.virtual_offset()?, None);
+        let mut out = Vec::new();
+        MultiGzDecoder::new(&writer.get_ref()[..]).read_to_end(&mut out)?;
+        assert_eq!(out, bam[.. bam.len() - dropped.len() - 4]);
+
+        // Block sizes out of range are parse errors, before any allocation
+        for size in [-1i32, 16, i32::MAX] {
+            let mut record = Vec::new();
+            let err = read_bam_record(&mut &size.to_le_bytes()[..], &mut record).unwrap_err();
+            assert_eq!(
+                crate::error::ErrorKind::of(&err),
+                Some(crate::error::ErrorKind::Parse)
+            );
+            assert!(record.is_empty());
+        }
+
+        let sam = "@HD\tVN:1.6\n\
+            r1\t4\t*\t0\t0\t*\t*\t0\t0\tA\tI\tCB:Z:ACGT\tkt:i:562\n\
+            r2\t4\t*\t0\t0\t*\t*\t0\t0\tA\tI\tCB:Z:ACGT\tkt:i:5620\n\
+            r3\t4\t*\t0\t0\t*\t*\t0\t0\tA\tI\tkt:i:562\n";
+        let mut out = Vec::new();
+        let counts = filter_sam(sam.as_bytes(), &mut out, &filter)?;
+        assert_eq!((counts.records, counts.kept), (3, 1));
+        // SAM input is written as BAM
+        let mut expected = BAM_MAGIC.to_vec();
+        expected.extend_from_slice(&11i32.to_le_bytes());
+        expected.extend_from_slice(b"@HD\tVN:1.6\n");
+        expected.extend_from_slice(&0i32.to_le_bytes());
+        let record = sam_bam_record(
+            sam.lines().nth(1).unwrap().as_bytes(),
+            &FxHashMap::default(),
+        )?;
+        write_bam_record(&mut expected, &record)?;
+        assert_eq!(out, expected);
+        let read = AlignedRead::from_bam(&record)?;
+        assert_eq!((&read.name[..], &read.qual[..]), (&b"r1"[..], &b"I"[..]));
+        assert_eq!(bam_tag(&record, b"kt").unwrap(), &b"562"[..]);
+
+        let sam = "@SQ\tSN:chr1\tLN:1000\n";
+        assert_eq!(sam_references(sam.as_bytes())?, [(b"chr1".to_vec(), 1000)]);
+        assert!(sam_references(b"@SQ\tSN:chr1\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bam_filter_regions() -> Result<()> {
+        // Records of chr1: two overlapping the region, of different
+        // barcodes, and one past it
+        let references = FxHashMap::from_iter([(b"chr1".to_vec(), 0)]);
+        let records = [
+            "r1\t0\tchr1\t11\t60\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT",
+            "r2\t0\tchr1\t21\t60\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:TTTT",
+            "r3\t0\tchr1\t5001\t60\t4M\t*\t0\t0\tACGT\tIIII\tCB:Z:ACGT",
+        ]
+        .iter()
+        .map(|line| sam_bam_record(line.as_bytes(), &references))
+        .collect::<Result<Vec<_>>>()?;
+        let mut header = Vec::new();
+        write_bam_header(
+            &mut header,
+            b"@SQ\tSN:chr1\tLN:10000\n",
+            &[(b"chr1".to_vec(), 10000)],
+        )?;
+
+        let compressor = || -> Result<Compressor> {
+            Ok(Compression::with_format(ChunkFormat::Gzip, None)?.compressor())
+        };
+        let mut bgzf = BgzfWriter::new(Vec::new(), compressor()?);
+        bgzf.write_all(&header)?;
+        bgzf.write_block()?;
+        let start = (bgzf.get_ref().len() as u64) << 16;
+        for record in &records {
+            write_bam_record(&mut bgzf, record)?;
+        }
+        bgzf.write_block()?;
+        let end = (bgzf.get_ref().len() as u64) << 16;
+        bgzf.finish()?;
+        let dir = tempfile::tempdir()?;
+        let bam = dir.path().join("reads.bam");
+        std::fs::write(&bam, bgzf.get_ref())?;
+        // The index of a single bin, holding all records
+        let mut bai = b"BAI\x01".to_vec();
+        for x in [1u32, 1, 4681, 1] {
+            bai.extend_from_slice(&x.to_le_bytes());
+        }
+        bai.extend_from_slice(&start.to_le_bytes());
+        bai.extend_from_slice(&end.to_le_bytes());
+        bai.extend_from_slice(&0u32.to_le_bytes());
+        std::fs::write(dir.path().join("reads.bam.bai"), bai)?;
+
+        // The barcodes filter the records of the region as those of the file
+        let ofile = dir.path().join("out.bam");
+        let (ifile, ofile) = (bam.to_str().unwrap(), ofile.to_str().unwrap());
+        let regions = ["chr1:1-100".to_string()];
+        let filter = TagFilter::new(Some(vec!["ACGT".to_string()]), "CB", None, "kt")?;
+        let counts = filter_regions(ifile, None, &regions, ofile, &filter, None)?;
+        assert_eq!((counts.records, counts.kept), (2, 1));
+        let mut out = Vec::new();
+        MultiGzDecoder::new(&std::fs::read(ofile)?[..]).read_to_end(&mut out)?;
+        let mut expected = header.clone();
+        write_bam_record(&mut expected, &records[0])?;
+        assert_eq!(out, expected);
+
+        let filter = TagFilter::new(None, "CB", None, "kt")?;
+        let counts = filter_regions(ifile, None, &regions, ofile, &filter, None)?;
+        assert_eq!((counts.records, counts.kept), (2, 2));
+        Ok(())
+    }
+}
@@ -0,0 +1,354 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use flate2::read::GzDecoder;
+
+const BAI_MAGIC: &[u8] = b"BAI\x01";
+// The bin of the metadata of a reference sequence, without alignments
+const BAI_PSEUDO_BIN: u32 = 37450;
+// Positions of the linear index are in windows of 16 kbp
+const BAI_LINEAR_SHIFT: u32 = 14;
+// Bins cover positions below 2^29
+const BAI_MAX_POSITION: i64 = 1 << 29;
+// Counts read from a file preallocate at most this many items, so that a
+// corrupt count fails on reading rather than on allocating
+pub(crate) const MAX_PREALLOCATED: usize = 1 << 16;
+
+/// A region of a reference sequence, 0-based and half-open
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Region {
+    pub(crate) reference: usize,
+    pub(crate) start: i64,
+    pub(crate) end: i64,
+}
+
+impl Region {
+    /// Parse a region as `samtools` does, `chr1`, `chr1:100` or
+    /// `chr1:1,000-2,000` (1-based and inclusive), among the `references`
+    /// of the BAM header. A name that contains colons (e.g. HLA alleles) is
+    /// matched whole first.
+    pub(crate) fn parse(region: &str, references: &[Vec<u8>]) -> Result<Self> {
+        let find = |name: &str| references.iter().position(|x| x == name.as_bytes());
+        if let Some(reference) = find(region) {
+            return Ok(Self {
+                reference,
+                start: 0,
+                end: BAI_MAX_POSITION,
+            });
+        }
+        let invalid = || anyhow!("Invalid region: {}", region);
+        let (name, range) = region.rsplit_once(':').ok_or_else(|| {
+            anyhow!(
+                "Invalid region: {}, no reference sequence of this name",
+                region
+            )
+        })?;
+        let reference = find(name)
+            .ok_or_else(|| anyhow!("Invalid region: {}, unknown reference {}", region, name))?;
+        let position =
+            |x: &str| -> Result<i64> { x.replace(',', "").parse().map_err(|_| invalid()) };
+        let (start, end) = match range.split_once('-') {
+            Some((start, "")) => (position(start)?, BAI_MAX_POSITION),
+            Some((start, end)) => (position(start)?, position(end)?),
+            None => (position(range)?, BAI_MAX_POSITION),
+        };
+        if start < 1 || end < start {
+            return Err(invalid());
+        }
+        Ok(Self {
+            reference,
+            start: start - 1,
+            end: end.min(BAI_MAX_POSITION),
+        })
+    }
+
+    /// Whether an alignment of `reference` spanning `start .. end` overlaps
+    pub(crate) fn overlaps(&self, reference: usize, start: i64, end: i64) -> bool {
+        self.reference == reference && start < self.end && end > self.start
+    }
+}
+
+/// The bins and linear index of a reference sequence
+#[derive(Default)]
+struct ReferenceIndex {
+    bins: Vec<(u32, Vec<(u64, u64)>)>,
+    linear: Vec<u64>,
+}
+
+/// A BAM index (`.bai`), locating the alignments of regions as ranges of
+/// virtual offsets of the BGZF file
+pub(crate) struct BaiIndex {
+    references: Vec<ReferenceIndex>,
+}
+
+impl BaiIndex {
+    pub(crate) fn from_path<P: AsRef<Path> + ?Sized>(path: &P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open BAM index {}", path.display()))?;
+        Self::from_reader(BufReader::new(file))
+            .with_context(|| format!("Failed to read BAM index {}", path.display()))
+    }
+
+    fn from_reader<R: Read>(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != BAI_MAGIC {
+            return Err(anyhow!("Invalid BAM index magic number"));
+        }
+        let n_ref = read_u32(&mut reader)?;
+        let mut references = Vec::with_capacity((n_ref as usize).min(MAX_PREALLOCATED));
+        for _ in 0 .. n_ref {
+            let mut index = ReferenceIndex::default();
+            for _ in 0 .. read_u32(&mut reader)? {
+                let bin = read_u32(&mut reader)?;
+                let chunks = (0 .. read_u32(&mut reader)?)
+                    .map(|_| Ok((read_u64(&mut reader)?, read_u64(&mut reader)?)))
+                    .collect::<Result<Vec<_>>>()?;
+                if bin != BAI_PSEUDO_BIN {
+                    index.bins.push((bin, chunks));
+                }
+            }
+            index.linear = (0 .. read_u32(&mut reader)?)
+                .map(|_| read_u64(&mut reader))
+                .collect::<Result<_>>()?;
+            references.push(index);
+        }
+        Ok(Self { references })
+    }
+
+    /// The merged ranges of virtual offsets holding the alignments that may
+    /// overlap `regions`, in file order
+    pub(crate) fn chunks(&self, regions: &[Region]) -> Vec<(u64, u64)> {
+        let mut chunks = Vec::new();
+        for region in regions {
+            let Some(index) = self.references.get(region.reference) else {
+                continue;
+            };
+            // Sorted, to be searched for each bin of the reference
+            let bins = region_bins(region.start, region.end);
+            // Alignments ending before the window of the start are skipped
+            let window = (region.start >> BAI_LINEAR_SHIFT) as usize;
+            let min_offset = index.linear.get(window).copied().unwrap_or(0);
+            for (bin, bin_chunks) in &index.bins {
+                if bins.binary_search(bin).is_ok() {
+                    chunks.extend(bin_chunks.iter().filter(|(_, end)| *end > min_offset));
+                }
+            }
+        }
+        chunks.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(chunks.len());
+        for (start, end) in chunks {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        merged
+    }
+}
+
+/// The bins that may hold alignments overlapping `start .. end`, in
+/// ascending order
+fn region_bins(start: i64, end: i64) -> Vec<u32> {
+    let start = start.clamp(0, BAI_MAX_POSITION - 1) as u32;
+    let end = (end.clamp(1, BAI_MAX_POSITION) - 1) as u32;
+    let mut bins = vec![0];
+    for (shift, offset) in [(26, 1), (23, 9), (20, 73), (17, 585), (14, 4681)] {
+        bins.extend(offset + (start >> shift) ..= offset + (end >> shift));
+    }
+    bins
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// A BGZF reader that can seek to the virtual offsets of a BAM index: the
+/// offset of a block in the file, shifted by 16 bits, plus an offset in the
+/// decompressed block
+pub(crate) struct BgzfReader<R: Read + Seek> {
+    inner: R,
+    block: Vec<u8>,
+    pos: usize,
+    offset: u64,
+    next_offset: u64,
+}
+
+impl<R: Read + Seek> BgzfReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            block: Vec::new(),
+            pos: 0,
+            offset: 0,
+            next_offset: 0,
+        }
+    }
+
+    pub(crate) fn seek(&mut self, virtual_offset: u64) -> Result<()> {
+        self.inner.seek(SeekFrom::Start(virtual_offset >> 16))?;
+        self.next_offset = virtual_offset >> 16;
+        self.read_block()?;
+        self.pos = (virtual_offset & 0xffff) as usize;
+        if self.pos > self.block.len() {
+            return Err(anyhow!(
+                "(Reader) Invalid virtual offset {}",
+                virtual_offset
+            ));
+        }
+        Ok(())
+    }
+
+    /// The virtual offset of the next byte, or `None` at the end of the file
+    pub(crate) fn virtual_offset(&mut self) -> Result<Option<u64>> {
+        while self.pos == self.block.len() {
+            if !self.read_block()? {
+                return Ok(None);
+            }
+        }
+        Ok(Some(self.offset << 16 | self.pos as u64))
+    }
+
+    /// Read the next block, returning `false` at the end of the file
+    fn read_block(&mut self) -> Result<bool> {
+        self.offset = self.next_offset;
+        self.block.clear();
+        self.pos = 0;
+        let mut header = [0u8; 18];
+        match self.inner.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e).context("(Reader) Failed to read BGZF block"),
+        }
+        if header[.. 4] != [0x1f, 0x8b, 0x08, 0x04] || header[12 .. 14] != *b"BC" {
+            return Err(anyhow!(
+                "(Reader) Invalid BGZF block at offset {}",
+                self.offset
+            ));
+        }
+        let size = u16::from_le_bytes([header[16], header[17]]) as usize + 1;
+        let mut data = header.to_vec();
+        data.resize(size, 0);
+        self.inner
+            .read_exact(&mut data[18 ..])
+            .context("(Reader) Truncated BGZF block")?;
+        GzDecoder::new(&data[..])
+            .read_to_end(&mut self.block)
+            .with_context(|| format!("(Reader) Invalid BGZF block at offset {}", self.offset))?;
+        self.next_offset = self.offset + size as u64;
+        Ok(true)
+    }
+}
+
+impl<R: Read + Seek> Read for BgzfReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.block.len() {
+            if !self.read_block().map_err(std::io::Error::other)? {
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(self.block.len() - self.pos);
+        buf[.. len].copy_from_slice(&self.block[self.pos .. self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// The reference, start and end of the alignment of a BAM record (without
+/// its block size), unmapped reads placed at their mate spanning one base
+pub(crate) fn alignment_span(record: &[u8]) -> Option<(usize, i64, i64)> {
+    let reference = i32::from_le_bytes(record.get(0 .. 4)?.try_into().ok()?);
+    let start = i32::from_le_bytes(record.get(4 .. 8)?.try_into().ok()?) as i64;
+    if reference < 0 || start < 0 {
+        return None;
+    }
+    let name_len = *record.get(8)? as usize;
+    let cigar_len = u16::from_le_bytes(record.get(12 .. 14)?.try_into().ok()?) as usize;
+    let cigar = record.get(32 + name_len .. 32 + name_len + 4 * cigar_len)?;
+    // M, D, N, = and X consume the reference
+    let len: i64 = cigar
+        .chunks_exact(4)
+        .map(|op| u32::from_le_bytes([op[0], op[1], op[2], op[3]]))
+        .filter(|op| matches!(op & 0xf, 0 | 2 | 3 | 7 | 8))
+        .map(|op| (op >> 4) as i64)
+        .sum();
+    Some((reference as usize, start, start + len.max(1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bam_index_regions() -> Result<()> {
+        let references = [b"chr1".to_vec(), b"HLA-A*01:01".to_vec()];
+        let region = |x: &str| Region::parse(x, &references);
+        assert_eq!(
+            region("chr1:1,001-2,000")?,
+            Region {
+                reference: 0,
+                start: 1000,
+                end: 2000
+            }
+        );
+        assert_eq!(region("HLA-A*01:01")?.reference, 1);
+        assert_eq!(region("HLA-A*01:01:5-")?.start, 4);
+        assert!(region("chr2:1-10").is_err());
+        assert!(region("chr1:10-1").is_err());
+        assert!(region("chr1:0").is_err());
+
+        assert_eq!(region_bins(0, 1), [0, 1, 9, 73, 585, 4681]);
+        assert_eq!(region_bins(16384, 16385)[5], 4682);
+
+        // One reference, with a bin of two chunks and the pseudo-bin
+        let mut bai = BAI_MAGIC.to_vec();
+        let mut push = |x: u64, size: usize| bai.extend_from_slice(&x.to_le_bytes()[.. size]);
+        push(1, 4);
+        push(2, 4);
+        push(4681, 4);
+        push(2, 4);
+        push(100 << 16, 8);
+        push(200 << 16, 8);
+        push(150 << 16, 8);
+        push(300 << 16, 8);
+        push(BAI_PSEUDO_BIN as u64, 4);
+        push(2, 4);
+        for _ in 0 .. 4 {
+            push(0, 8);
+        }
+        push(1, 4);
+        push(100 << 16, 8);
+        let index = BaiIndex::from_reader(&bai[..])?;
+        let chunks = index.chunks(&[region("chr1:1-100")?, region("HLA-A*01:01")?]);
+        assert_eq!(chunks, [(100 << 16, 300 << 16)]);
+        assert!(index.chunks(&[region("chr1:20000-30000")?]).is_empty());
+
+        // A corrupt count of references fails on reading the index
+        let mut bai = BAI_MAGIC.to_vec();
+        bai.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(BaiIndex::from_reader(&bai[..]).is_err());
+
+        // A record of chr1 at 10 with a 3M2D1I3M CIGAR
+        let mut record = vec![0u8; 32];
+        record[4 .. 8].copy_from_slice(&10i32.to_le_bytes());
+        record[8] = 3;
+        record[12 .. 14].copy_from_slice(&4u16.to_le_bytes());
+        record.extend_from_slice(b"r1\0");
+        for op in [3 << 4, 2 << 4 | 2, 1 << 4 | 1, 3 << 4] {
+            record.extend_from_slice(&(op as u32).to_le_bytes());
+        }
+        assert_eq!(alignment_span(&record), Some((0, 10, 18)));
+        Ok(())
+    }
+}
@@ -0,0 +1,451 @@
+use std::io::Write;
+
+use anyhow::{anyhow, Result};
+use libdeflater::Compressor;
+use rustc_hash::FxHashMap;
+
+use crate::utils::*;
+
+pub(crate) const BAM_MAGIC: &[u8] = b"BAM\x01";
+
+// BGZF blocks hold at most 64 KiB, the data is cut below it so that
+// incompressible data still fits in a block
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+// The empty block ending BGZF files
+pub(crate) const BGZF_EOF: &[u8] = b"\x1f\x8b\x08\x04\x00\x00\x00\x00\x00\xff\x06\x00\x42\x43\
+    \x02\x00\x1b\x00\x03\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+
+// SAM flags
+pub(crate) const FLAG_PAIRED: u16 = 0x1;
+pub(crate) const FLAG_UNMAPPED: u16 = 0x4;
+pub(crate) const FLAG_MATE_UNMAPPED: u16 = 0x8;
+pub(crate) const FLAG_FIRST: u16 = 0x40;
+pub(crate) const FLAG_LAST: u16 = 0x80;
+
+// The 4-bit bases of BAM sequences
+pub(crate) const BAM_BASES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+// The bin of records without a position
+const UNPLACED_BIN: u16 = 4680;
+// Read names of BAM records hold at most 254 bytes, as in SAM
+const MAX_READ_NAME: usize = 254;
+// The operations of SAM CIGAR strings, by their BAM code
+const CIGAR_OPS: &[u8; 9] = b"MIDNSHP=X";
+
+/// Writer of BGZF, the blocked gzip of BAM files
+pub(crate) struct BgzfWriter<W: Write> {
+    writer: W,
+    compressor: Compressor,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub(crate) fn new(writer: W, compressor: Compressor) -> Self {
+        Self {
+            writer,
+            compressor,
+            buffer: Vec::with_capacity(BGZF_BLOCK_SIZE),
+        }
+    }
+
+    /// The underlying writer, holding the blocks written so far
+    #[cfg(test)]
+    pub(crate) fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub(crate) fn write_block(&mut self) -> std::io::Result<()> {
+        let bound = self.compressor.deflate_compress_bound(self.buffer.len());
+        let mut block = vec![0u8; 18 + bound + 8];
+        let size = self
+            .compressor
+            .deflate_compress(&self.buffer, &mut block[18 .. 18 + bound])
+            .map_err(|e| std::io::Error::other(format!("{:?}", e)))?;
+        block.truncate(18 + size + 8);
+        // Gzip header with the `BC` extra field giving the block size
+        block[.. 12].copy_from_slice(b"\x1f\x8b\x08\x04\x00\x00\x00\x00\x00\xff\x06\x00");
+        block[12 .. 16].copy_from_slice(b"BC\x02\x00");
+        let block_size = (block.len() - 1) as u16;
+        block[16 .. 18].copy_from_slice(&block_size.to_le_bytes());
+        let trailer = 18 + size;
+        block[trailer .. trailer + 4]
+            .copy_from_slice(&libdeflater::crc32(&self.buffer).to_le_bytes());
+        block[trailer + 4 ..].copy_from_slice(&(self.buffer.len() as u32).to_le_bytes());
+        self.writer.write_all(&block)?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl<W: CompleteWrite> BgzfWriter<W> {
+    pub(crate) fn finish(&mut self) -> std::io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.write_block()?;
+        }
+        self.writer.write_all(BGZF_EOF)?;
+        self.writer.complete()
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(BGZF_BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[.. len]);
+        if self.buffer.len() == BGZF_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Write the header of a BAM file: its SAM header text, and the name and
+/// length of each reference sequence
+pub(crate) fn write_bam_header<W: Write>(
+    writer: &mut W,
+    text: &[u8],
+    references: &[(Vec<u8>, u32)],
+) -> std::io::Result<()> {
+    writer.write_all(BAM_MAGIC)?;
+    writer.write_all(&(text.len() as i32).to_le_bytes())?;
+    writer.write_all(text)?;
+    writer.write_all(&(references.len() as i32).to_le_bytes())?;
+    for (name, len) in references {
+        writer.write_all(&(name.len() as i32 + 1).to_le_bytes())?;
+        writer.write_all(name)?;
+        writer.write_all(&[0])?;
+        writer.write_all(&len.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Write a BAM record, preceded by its block size
+pub(crate) fn write_bam_record<W: Write>(writer: &mut W, record: &[u8]) -> std::io::Result<()> {
+    writer.write_all(&(record.len() as i32).to_le_bytes())?;
+    writer.write_all(record)
+}
+
+/// Encode an unaligned read as a BAM record, without its block size. `flag`
+/// tells the mate of paired reads, the read is flagged as unmapped. `qual`
+/// is Phred+33, and `tags` are written as strings.
+pub(crate) fn unaligned_bam_record(
+    name: &[u8],
+    flag: u16,
+    seq: &[u8],
+    qual: &[u8],
+    tags: &[(&[u8; 2], &[u8])],
+) -> Result<Vec<u8>> {
+    if name.is_empty() || name.len() > MAX_READ_NAME {
+        return Err(anyhow!(
+            "(Writer) Invalid BAM read name {}: names hold 1 to {} bytes",
+            String::from_utf8_lossy(name),
+            MAX_READ_NAME
+        ));
+    }
+    let mut flag = flag | FLAG_UNMAPPED;
+    if flag & FLAG_PAIRED != 0 {
+        flag |= FLAG_MATE_UNMAPPED;
+    }
+    let mut record = Vec::with_capacity(32 + name.len() + 1 + seq.len() * 2);
+    record.extend_from_slice(&(-1i32).to_le_bytes()); // refID
+    record.extend_from_slice(&(-1i32).to_le_bytes()); // pos
+    record.push(name.len() as u8 + 1);
+    record.push(0); // mapq
+    record.extend_from_slice(&UNPLACED_BIN.to_le_bytes());
+    record.extend_from_slice(&0u16.to_le_bytes()); // n_cigar_op
+    record.extend_from_slice(&flag.to_le_bytes());
+    record.extend_from_slice(&(seq.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(-1i32).to_le_bytes()); // next_refID
+    record.extend_from_slice(&(-1i32).to_le_bytes()); // next_pos
+    record.extend_from_slice(&0i32.to_le_bytes()); // tlen
+    record.extend_from_slice(name);
+    record.push(0);
+    let code = |base: u8| {
+        BAM_BASES
+            .iter()
+            .position(|x| *x == base.to_ascii_uppercase())
+            .unwrap_or(15) as u8
+    };
+    for pair in seq.chunks(2) {
+        record.push(code(pair[0]) << 4 | pair.get(1).map_or(0, |x| code(*x)));
+    }
+    record.extend(qual.iter().map(|q| q.saturating_sub(33)));
+    for (tag, value) in tags {
+        record.extend_from_slice(&tag[..]);
+        record.push(b'Z');
+        record.extend_from_slice(value);
+        record.push(0);
+    }
+    Ok(record)
+}
+
+/// The smallest bin holding the 0-based `[start, end)`, as computed by
+/// `reg2bin()` of the SAM specification
+fn reg2bin(start: i64, end: i64) -> u16 {
+    let end = end - 1;
+    for (shift, offset) in [(14, 4681), (17, 585), (20, 73), (23, 9), (26, 1)] {
+        if start >> shift == end >> shift {
+            return (offset + (start >> shift)) as u16;
+        }
+    }
+    0
+}
+
+fn sam_number<T: std::str::FromStr>(value: &[u8]) -> Option<T> {
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+/// Encode a SAM line as a BAM record, without its block size. `references`
+/// gives the index of each reference sequence of the header by name.
+pub(crate) fn sam_bam_record(line: &[u8], references: &FxHashMap<Vec<u8>, i32>) -> Result<Vec<u8>> {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let fields: Vec<&[u8]> = line.split(|x| *x == b'\t').collect();
+    if fields.len() < 11 {
+        return Err(anyhow!(
+            "(Reader) Invalid SAM line: {}",
+            String::from_utf8_lossy(line)
+        ));
+    }
+    let invalid = |name: &str, value: &[u8]| {
+        anyhow!(
+            "(Reader) Invalid SAM {} of read {}: {}",
+            name,
+            String::from_utf8_lossy(fields[0]),
+            String::from_utf8_lossy(value)
+        )
+    };
+    let reference = |name: &[u8]| -> Result<i32> {
+        if name == b"*" {
+            return Ok(-1);
+        }
+        references.get(name).copied().ok_or_else(|| {
+            anyhow!(
+                "(Reader) Reference sequence {} of read {} is not in the SAM header",
+                String::from_utf8_lossy(name),
+                String::from_utf8_lossy(fields[0])
+            )
+        })
+    };
+
+    let name = fields[0];
+    if name.is_empty() || name.len() > MAX_READ_NAME {
+        return Err(invalid("read name", name));
+    }
+    let flag: u16 = sam_number(fields[1]).ok_or_else(|| invalid("flag", fields[1]))?;
+    let ref_id = reference(fields[2])?;
+    let pos = sam_number::<i64>(fields[3]).ok_or_else(|| invalid("position", fields[3]))? - 1;
+    let mapq: u8 = sam_number(fields[4]).ok_or_else(|| invalid("mapping quality", fields[4]))?;
+    let mut cigar = Vec::new();
+    if fields[5] != b"*" {
+        let mut len = 0u32;
+        for &x in fields[5] {
+            if x.is_ascii_digit() {
+                len = len
+                    .checked_mul(10)
+                    .and_then(|len| len.checked_add((x - b'0') as u32))
+                    .ok_or_else(|| invalid("CIGAR", fields[5]))?;
+            } else {
+                let op = CIGAR_OPS
+                    .iter()
+                    .position(|op| *op == x)
+                    .ok_or_else(|| invalid("CIGAR", fields[5]))?;
+                cigar.push(len << 4 | op as u32);
+                len = 0;
+            }
+        }
+        if len != 0 || cigar.len() > u16::MAX as usize {
+            return Err(invalid("CIGAR", fields[5]));
+        }
+    }
+    let next_ref_id = match fields[6] {
+        b"=" => ref_id,
+        name => reference(name)?,
+    };
+    let next_pos =
+        sam_number::<i64>(fields[7]).ok_or_else(|| invalid("mate position", fields[7]))? - 1;
+    let tlen: i32 = sam_number(fields[8]).ok_or_else(|| invalid("template length", fields[8]))?;
+    let seq = if fields[9] == b"*" {
+        &[][..]
+    } else {
+        fields[9]
+    };
+    if fields[10] != b"*" && fields[10].len() != seq.len() {
+        return Err(invalid("quality", fields[10]));
+    }
+    // M, D, N, = and X consume the reference
+    let span: i64 = cigar
+        .iter()
+        .filter(|op| matches!(*op & 0xf, 0 | 2 | 3 | 7 | 8))
+        .map(|op| (op >> 4) as i64)
+        .sum();
+
+    let mut record = Vec::with_capacity(32 + name.len() + 1 + seq.len() * 2 + 4 * cigar.len());
+    record.extend_from_slice(&ref_id.to_le_bytes());
+    record.extend_from_slice(&(pos as i32).to_le_bytes());
+    record.push(name.len() as u8 + 1);
+    record.push(mapq);
+    record.extend_from_slice(&reg2bin(pos, pos + span.max(1)).to_le_bytes());
+    record.extend_from_slice(&(cigar.len() as u16).to_le_bytes());
+    record.extend_from_slice(&flag.to_le_bytes());
+    record.extend_from_slice(&(seq.len() as u32).to_le_bytes());
+    record.extend_from_slice(&next_ref_id.to_le_bytes());
+    record.extend_from_slice(&(next_pos as i32).to_le_bytes());
+    record.extend_from_slice(&tlen.to_le_bytes());
+    record.extend_from_slice(name);
+    record.push(0);
+    for op in &cigar {
+        record.extend_from_slice(&op.to_le_bytes());
+    }
+    let code = |base: u8| {
+        BAM_BASES
+            .iter()
+            .position(|x| *x == base.to_ascii_uppercase())
+            .unwrap_or(15) as u8
+    };
+    for pair in seq.chunks(2) {
+        record.push(code(pair[0]) << 4 | pair.get(1).map_or(0, |x| code(*x)));
+    }
+    if fields[10] == b"*" {
+        record.resize(record.len() + seq.len(), 0xff);
+    } else {
+        record.extend(fields[10].iter().map(|q| q.saturating_sub(33)));
+    }
+    for field in &fields[11 ..] {
+        push_sam_tag(&mut record, field).ok_or_else(|| invalid("tag", field))?;
+    }
+    Ok(record)
+}
+
+/// Encode a `TG:TYPE:VALUE` SAM tag into a BAM record, integers taking the
+/// smallest type holding them
+fn push_sam_tag(record: &mut Vec<u8>, field: &[u8]) -> Option<()> {
+    if field.len() < 5 || field[2] != b':' || field[4] != b':' {
+        return None;
+    }
+    let (tag, kind, value) = (&field[.. 2], field[3], &field[5 ..]);
+    record.extend_from_slice(tag);
+    match kind {
+        b'A' if value.len() == 1 => record.extend_from_slice(&[b'A', value[0]]),
+        b'i' => {
+            let value: i64 = sam_number(value)?;
+            if let Ok(x) = u8::try_from(value) {
+                record.extend_from_slice(&[b'C', x]);
+            } else if let Ok(x) = i8::try_from(value) {
+                record.extend_from_slice(&[b'c', x as u8]);
+            } else if let Ok(x) = u16::try_from(value) {
+                record.push(b'S');
+                record.extend_from_slice(&x.to_le_bytes());
+            } else if let Ok(x) = i16::try_from(value) {
+                record.push(b's');
+                record.extend_from_slice(&x.to_le_bytes());
+            } else if let Ok(x) = u32::try_from(value) {
+                record.push(b'I');
+                record.extend_from_slice(&x.to_le_bytes());
+            } else {
+                record.push(b'i');
+                record.extend_from_slice(&i32::try_from(value).ok()?.to_le_bytes());
+            }
+        }
+        b'f' => {
+            record.push(b'f');
+            record.extend_from_slice(&sam_number::<f32>(value)?.to_le_bytes());
+        }
+        b'Z' | b'H' => {
+            record.push(kind);
+            record.extend_from_slice(value);
+            record.push(0);
+        }
+        b'B' => {
+            let mut values = value.split(|x| *x == b',');
+            let subtype = *values.next()?.first()?;
+            let values: Vec<&[u8]> = values.collect();
+            record.push(b'B');
+            record.push(subtype);
+            record.extend_from_slice(&(values.len() as u32).to_le_bytes());
+            for value in values {
+                match subtype {
+                    b'c' => record.push(sam_number::<i8>(value)? as u8),
+                    b'C' => record.push(sam_number::<u8>(value)?),
+                    b's' => record.extend_from_slice(&sam_number::<i16>(value)?.to_le_bytes()),
+                    b'S' => record.extend_from_slice(&sam_number::<u16>(value)?.to_le_bytes()),
+                    b'i' => record.extend_from_slice(&sam_number::<i32>(value)?.to_le_bytes()),
+                    b'I' => record.extend_from_slice(&sam_number::<u32>(value)?.to_le_bytes()),
+                    b'f' => record.extend_from_slice(&sam_number::<f32>(value)?.to_le_bytes()),
+                    _ => return None,
+                }
+            }
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sam_bam_record() -> Result<()> {
+        let references = [(b"chr1".to_vec(), 0)].into_iter().collect();
+        let record = sam_bam_record(
+            b"r1\t99\tchr1\t101\t60\t2S3M1D1M\t=\t201\t150\tACGTNA\tIIIII#\t\
+              NM:i:1\tAS:i:-300\tXA:A:x\tXF:f:0.5\tCB:Z:ACGT\tXB:B:s,-1,2\n",
+            &references,
+        )?;
+        assert_eq!(&record[.. 8], [0, 0, 0, 0, 100, 0, 0, 0]);
+        // The alignment spans 5 bases of the first 16 kb bin
+        assert_eq!(&record[8 .. 16], [3, 60, 0x49, 0x12, 4, 0, 99, 0]);
+        assert_eq!(
+            &record[16 .. 32],
+            [6, 0, 0, 0, 0, 0, 0, 0, 200, 0, 0, 0, 150, 0, 0, 0]
+        );
+        assert_eq!(&record[32 .. 35], b"r1\0");
+        assert_eq!(&record[35 .. 39], (2u32 << 4 | 4).to_le_bytes());
+        assert_eq!(&record[47 .. 51], (1u32 << 4).to_le_bytes());
+        assert_eq!(&record[51 .. 54], [0x12, 0x48, 0xf1]);
+        assert_eq!(&record[54 .. 60], [40, 40, 40, 40, 40, 2]);
+        let mut tags = b"NMC\x01ASs\xd4\xfeXAAxXFf".to_vec();
+        tags.extend_from_slice(&0.5f32.to_le_bytes());
+        tags.extend_from_slice(b"CBZACGT\0XBBs\x02\0\0\0\xff\xff\x02\0");
+        assert_eq!(&record[60 ..], tags);
+
+        // Unmapped reads without sequence
+        let record = sam_bam_record(b"r2\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*", &references)?;
+        assert_eq!(
+            &record[.. 12],
+            [255, 255, 255, 255, 255, 255, 255, 255, 3, 0, 0x48, 0x12]
+        );
+        assert_eq!(record.len(), 35);
+
+        for line in [
+            &b"r3\t0\tchr2\t1\t0\t*\t*\t0\t0\tA\tI"[..],
+            b"r3\t0\tchr1\t1\t0\t1Q\t*\t0\t0\tA\tI",
+            b"r3\t0\tchr1\t1\t0\t*\t*\t0\t0\tAC\tI",
+            b"r3\t0\tchr1\t1\t0\t*\t*\t0\t0\tA\tI\tXB:B:q,1",
+            b"r3\t0\tchr1\t1",
+        ] {
+            assert!(sam_bam_record(line, &references).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_unaligned_bam_record() -> Result<()> {
+        let record = unaligned_bam_record(
+            b"r1",
+            FLAG_PAIRED | FLAG_LAST,
+            b"acgTx",
+            b"IIII#",
+            &[(b"CB", b"AAAC")],
+        )?;
+        assert_eq!(u16::from_le_bytes([record[14], record[15]]), 141);
+        assert_eq!(&record[35 .. 38], [0x12, 0x48, 0xf0]);
+        assert_eq!(&record[38 .. 43], [40, 40, 40, 40, 2]);
+        assert_eq!(&record[43 ..], b"CBZAAAC\0");
+        assert!(unaligned_bam_record(&[b'r'; 255], 0, b"A", b"I", &[]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use extendr_api::prelude::*;
+use libdeflater::Crc;
+
+use crate::error::RError;
+use crate::part_writer::part_path;
+use crate::utils::BUFFER_SIZE;
+
+/// The files written for an output: the output itself, or the numbered part
+/// files it rolled over into (see [`part_path`])
+fn output_files(path: &Path) -> Vec<PathBuf> {
+    if path.is_file() {
+        return vec![path.to_path_buf()];
+    }
+    (0 ..)
+        .map(|part| part_path(path, part))
+        .take_while(|x| x.is_file())
+        .collect()
+}
+
+/// The size and CRC-32 of the content of a file, as `<size>:<crc32>`
+fn file_checksum(path: &Path) -> Result<String> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut crc = Crc::new();
+    let mut size = 0;
+    loop {
+        let nbytes = file
+            .read(&mut buffer)
+            .with_context(|| format!("Failed to read file: {}", path.display()))?;
+        if nbytes == 0 {
+            break;
+        }
+        crc.update(&buffer[.. nbytes]);
+        size += nbytes;
+    }
+    Ok(format!("{}:{:08x}", size, crc.sum()))
+}
+
+/// The files written for each of `outputs` with their checksums, for
+/// `resume` to tell complete outputs of a previous run from missing or
+/// altered ones. Outputs without any file are left out.
+#[extendr]
+fn output_checksums(outputs: Vec<String>) -> std::result::Result<List, RError> {
+    let mut files = Vec::with_capacity(outputs.len());
+    let mut checksums = Vec::with_capacity(outputs.len());
+    for output in &outputs {
+        for file in output_files(Path::new(output)) {
+            checksums.push(file_checksum(&file).map_err(RError::from)?);
+            files.push(file.to_string_lossy().into_owned());
+        }
+    }
+    Ok(list!(file = files, checksum = checksums))
+}
+
+extendr_module! {
+    mod checksum;
+    fn output_checksums;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_checksums() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let path = temp.path().join("reads.fq.gz");
+        assert!(output_files(&path).is_empty());
+        std::fs::write(part_path(&path, 0), b"@r1\nACGT\n+\nIIII\n")?;
+        std::fs::write(part_path(&path, 1), b"")?;
+        assert_eq!(
+            output_files(&path),
+            vec![part_path(&path, 0), part_path(&path, 1)]
+        );
+        assert_eq!(file_checksum(&part_path(&path, 1))?, "0:00000000");
+
+        std::fs::write(&path, b"123456789")?;
+        assert_eq!(output_files(&path), vec![path.clone()]);
+        // The check value of CRC-32
+        assert_eq!(file_checksum(&path)?, "9:cbf43926");
+        Ok(())
+    }
+}
@@ -0,0 +1,581 @@
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use extendr_api::prelude::*;
+use libdeflater::CompressionLvl;
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::error::{ErrorKind, RError};
+use crate::part_writer::ChunkedWriter;
+use crate::table_writer::TableWriter;
+use crate::utils::*;
+
+/// Write the counts of each feature (taxon) in each barcode into matrix
+/// directories in the 10x Genomics format, one per sample when barcodes are
+/// assigned to samples. The features of `spike_ins` are tallied apart from
+/// the matrices to normalize the samples.
+#[extendr]
+fn write_count_matrix(
+    counts: List,
+    ids: Vec<String>,
+    names: Vec<String>,
+    odir: &str,
+    samples: Robj,
+    combined: bool,
+    spike_ins: Robj,
+) -> std::result::Result<List, RError> {
+    write_count_matrix_internal(counts, ids, names, odir, samples, combined, spike_ins)
+        .map_err(RError::from)
+}
+
+/// Write the counts of each feature (taxon) in each barcode into a Zarr
+/// store, laid out as an AnnData object of barcodes by features
+#[extendr]
+fn write_count_zarr(
+    counts: List,
+    ids: Vec<String>,
+    names: Vec<String>,
+    store: &str,
+) -> std::result::Result<f64, RError> {
+    write_count_zarr_internal(counts, ids, names, store)
+        .map(|entries| entries as f64)
+        .map_err(RError::from)
+}
+
+extendr_module! {
+    mod count_matrix;
+    fn write_count_matrix;
+    fn write_count_zarr;
+}
+
+fn write_count_matrix_internal(
+    counts: List,
+    ids: Vec<String>,
+    names: Vec<String>,
+    odir: &str,
+    samples: Robj,
+    combined: bool,
+    spike_ins: Robj,
+) -> Result<List> {
+    let mut columns = list_to_counts(&counts)?;
+    check_counts(&columns, &ids)?;
+    let spike_ins =
+        robj_to_option_str(&spike_ins).with_context(|| format!("Failed to parse 'spike_ins'"))?;
+    let (ids, names, spikes) = match spike_ins {
+        Some(spike_ins) => {
+            let is_spike_in = ids
+                .iter()
+                .map(|id| spike_ins.contains(&id.as_str()))
+                .collect::<Vec<_>>();
+            if !is_spike_in.contains(&true) {
+                return Err(ErrorKind::Config.error(format!(
+                    "None of the spike-in taxids {} was counted",
+                    spike_ins.join(", ")
+                )));
+            }
+            let spikes = columns
+                .iter_mut()
+                .map(|counts| split_spike_ins(counts, &is_spike_in))
+                .collect::<Vec<_>>();
+            let keep = |x: Vec<String>| {
+                x.into_iter()
+                    .zip(&is_spike_in)
+                    .filter_map(|(x, spike_in)| (!spike_in).then_some(x))
+                    .collect::<Vec<_>>()
+            };
+            (keep(ids), keep(names), Some(spikes))
+        }
+        None => (ids, names, None),
+    };
+    let barcodes = counts
+        .names()
+        .map(|x| x.collect::<Vec<_>>())
+        .unwrap_or_default();
+    let samples = if samples.is_null() {
+        None
+    } else {
+        let samples = samples
+            .as_str_iter()
+            .ok_or_else(|| anyhow!("Samples must be a character vector"))?
+            .map(|x| (!x.is_na()).then_some(x))
+            .collect::<Vec<_>>();
+        if samples.len() != barcodes.len() {
+            return Err(anyhow!("Expected a sample for each barcode"));
+        }
+        Some(samples)
+    };
+    let features = Features { ids, names };
+    let cells = barcodes
+        .into_iter()
+        .zip(&columns)
+        .enumerate()
+        .map(|(i, (barcode, counts))| Cell {
+            barcode,
+            sample: samples.as_ref().map_or(Some(""), |x| x[i]),
+            counts,
+            spike_ins: spikes.as_ref().map_or(0, |x| x[i]),
+        })
+        // Barcodes without a sample are dropped
+        .filter(|cell| cell.sample.is_some())
+        .collect::<Vec<_>>();
+    let odir = Path::new(odir);
+    let factors = spikes.is_some().then(|| size_factors(&cells));
+
+    let mut written = Vec::new();
+    if combined || samples.is_none() {
+        let entries = write_matrix(odir, &features, &cells, samples.is_some())?;
+        if let Some(factors) = &factors {
+            write_size_factors(odir, factors)?;
+        }
+        written.push(("".to_string(), cells.len(), entries));
+    } else {
+        let mut groups: HashMap<&str, Vec<&Cell>> = HashMap::default();
+        for cell in &cells {
+            // Safety: barcodes without a sample were dropped
+            groups.entry(cell.sample.unwrap()).or_default().push(cell);
+        }
+        let mut groups = groups.into_iter().collect::<Vec<_>>();
+        groups.sort_unstable_by_key(|(sample, _)| *sample);
+        for (sample, cells) in groups {
+            check_sample(sample)?;
+            let cells = cells.into_iter().cloned().collect::<Vec<_>>();
+            let entries = write_matrix(&odir.join(sample), &features, &cells, false)?;
+            if let Some(factors) = &factors {
+                let factors = factors
+                    .iter()
+                    .filter(|x| x.sample == sample)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                write_size_factors(&odir.join(sample), &factors)?;
+            }
+            written.push((sample.to_string(), cells.len(), entries));
+        }
+    }
+    let mut out = list!(
+        sample = written.iter().map(|x| x.0.as_str()).collect::<Vec<_>>(),
+        barcodes = written.iter().map(|x| x.1 as f64).collect::<Vec<_>>(),
+        entries = written.iter().map(|x| x.2 as f64).collect::<Vec<_>>()
+    );
+    if let Some(factors) = factors {
+        let factors = list!(
+            sample = factors.iter().map(|x| x.sample).collect::<Vec<_>>(),
+            spike_ins = factors
+                .iter()
+                .map(|x| x.spike_ins as f64)
+                .collect::<Vec<_>>(),
+            size_factor = factors
+                .iter()
+                .map(|x| x.size_factor.map_or(Rfloat::na(), Rfloat::from))
+                .collect::<Doubles>()
+        );
+        out = list!(written = out, size_factors = factors);
+    }
+    Ok(out)
+}
+
+/// Take the counts of the spike-in features out of `counts`, returning their
+/// total
+fn split_spike_ins(counts: &mut Vec<usize>, is_spike_in: &[bool]) -> usize {
+    let mut spikes = 0;
+    let mut spike_in = is_spike_in.iter();
+    counts.retain(|count| {
+        // Safety: counts were checked to have one entry per feature
+        if *spike_in.next().unwrap() {
+            spikes += count;
+            false
+        } else {
+            true
+        }
+    });
+    spikes
+}
+
+/// The slots of a `dgCMatrix` holding the counts of each column of rows
+/// named `rownames`, for R to build the matrix from without casting a dense
+/// or long table, which would double the peak memory
+pub(crate) fn dgc_slots(
+    columns: &[Vec<Option<usize>>],
+    rownames: Vec<Rstr>,
+    colnames: Vec<String>,
+) -> List {
+    let (i, p, x) = compress_columns(columns);
+    list!(
+        i = i,
+        p = p,
+        x = x,
+        Dim = [rownames.len() as i32, colnames.len() as i32],
+        Dimnames = list!(rownames, colnames)
+    )
+}
+
+/// Compress `columns` into the 0-based row indices and the values of their
+/// counts, absent and zero counts left out, and the offsets of each column
+/// into them
+fn compress_columns(columns: &[Vec<Option<usize>>]) -> (Vec<i32>, Vec<i32>, Vec<f64>) {
+    let mut i = Vec::new();
+    let mut x = Vec::new();
+    let mut p = Vec::with_capacity(columns.len() + 1);
+    p.push(0);
+    for column in columns {
+        for (row, count) in column.iter().enumerate() {
+            if let Some(count) = count.filter(|x| *x > 0) {
+                i.push(row as i32);
+                x.push(count as f64);
+            }
+        }
+        p.push(i.len() as i32);
+    }
+    (i, p, x)
+}
+
+/// Every barcode must have a count for each feature
+fn check_counts(columns: &[Vec<usize>], ids: &[String]) -> Result<()> {
+    if let Some(column) = columns.iter().position(|x| x.len() != ids.len()) {
+        return Err(anyhow!(
+            "Barcode {} has {} counts for {} features",
+            column + 1,
+            columns[column].len(),
+            ids.len()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "zarr")]
+fn write_count_zarr_internal(
+    counts: List,
+    ids: Vec<String>,
+    names: Vec<String>,
+    store: &str,
+) -> Result<usize> {
+    let columns = list_to_counts(&counts)?;
+    check_counts(&columns, &ids)?;
+    let barcodes = counts
+        .names()
+        .map(|x| x.map(|x| x.to_string()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    write_anndata(Path::new(store), &barcodes, &ids, &names, &columns)
+}
+
+#[cfg(not(feature = "zarr"))]
+fn write_count_zarr_internal(
+    _counts: List,
+    _ids: Vec<String>,
+    _names: Vec<String>,
+    _store: &str,
+) -> Result<usize> {
+    Err(anyhow!(
+        "Zarr export is not available: mire was built without the 'zarr' feature"
+    ))
+}
+
+/// Write the AnnData layout of a Zarr store: the counts of each barcode
+/// (`obs`) in each feature (`var`) as the sparse rows of `X`, and an `uns`
+/// group for other tables. Returns the number of non-zero counts.
+#[cfg(feature = "zarr")]
+fn write_anndata(
+    path: &Path,
+    barcodes: &[String],
+    ids: &[String],
+    names: &[String],
+    columns: &[Vec<usize>],
+) -> Result<usize> {
+    use crate::zarr::{ZarrStore, CHUNK_LEN};
+
+    const STRINGS: &str = r#"{"encoding-type": "string-array", "encoding-version": "0.2.0"}"#;
+    const ARRAY: &str = r#"{"encoding-type": "array", "encoding-version": "0.2.0"}"#;
+    let store = ZarrStore::create(
+        path,
+        r#"{"encoding-type": "anndata", "encoding-version": "0.1.0"}"#,
+    )?;
+    store.group(
+        "obs",
+        r#"{"_index": "_index", "column-order": [], "encoding-type": "dataframe", "encoding-version": "0.2.0"}"#,
+    )?;
+    store.write_array("obs/_index", barcodes.iter().cloned(), STRINGS)?;
+    store.group(
+        "var",
+        r#"{"_index": "_index", "column-order": ["name"], "encoding-type": "dataframe", "encoding-version": "0.2.0"}"#,
+    )?;
+    store.write_array("var/_index", ids.iter().cloned(), STRINGS)?;
+    store.write_array("var/name", names.iter().cloned(), STRINGS)?;
+    store.group(
+        "uns",
+        r#"{"encoding-type": "dict", "encoding-version": "0.1.0"}"#,
+    )?;
+
+    // Barcodes are the rows of a CSR matrix
+    store.group(
+        "X",
+        &format!(
+            r#"{{"encoding-type": "csr_matrix", "encoding-version": "0.1.0", "shape": [{}, {}]}}"#,
+            barcodes.len(),
+            ids.len()
+        ),
+    )?;
+    let mut indptr = vec![0];
+    for counts in columns {
+        indptr.push(indptr[indptr.len() - 1] + counts.iter().filter(|x| **x > 0).count() as i64);
+    }
+    let entries = indptr[indptr.len() - 1] as usize;
+    let mut data = store.array::<i64>("X/data", entries.min(CHUNK_LEN), ARRAY)?;
+    let mut indices = store.array::<i32>("X/indices", entries.min(CHUNK_LEN), ARRAY)?;
+    for counts in columns {
+        for (feature, &count) in counts.iter().enumerate().filter(|(_, x)| **x > 0) {
+            data.push(count as i64)?;
+            indices.push(feature as i32)?;
+        }
+    }
+    data.finish()?;
+    indices.finish()?;
+    store.write_array("X/indptr", indptr.into_iter(), ARRAY)?;
+    Ok(entries)
+}
+
+struct Features {
+    ids: Vec<String>,
+    names: Vec<String>,
+}
+
+#[derive(Clone)]
+struct Cell<'a> {
+    barcode: &'a str,
+    sample: Option<&'a str>,
+    counts: &'a [usize],
+    // Counts of the spike-in features, tallied apart from `counts`
+    spike_ins: usize,
+}
+
+/// Spike-ins counted in a sample, and its normalization factor
+#[derive(Clone)]
+struct SizeFactor<'a> {
+    sample: &'a str,
+    spike_ins: usize,
+    // `None` for a sample without spike-ins
+    size_factor: Option<f64>,
+}
+
+/// The spike-ins of each sample over their mean across samples. As the same
+/// amount of spike-ins is added to each sample, counts divided by the factor
+/// of their sample are comparable in absolute terms.
+fn size_factors<'a>(cells: &[Cell<'a>]) -> Vec<SizeFactor<'a>> {
+    let mut samples: HashMap<&str, usize> = HashMap::default();
+    for cell in cells {
+        // Safety: barcodes without a sample were dropped
+        *samples.entry(cell.sample.unwrap()).or_default() += cell.spike_ins;
+    }
+    let mut samples = samples.into_iter().collect::<Vec<_>>();
+    samples.sort_unstable_by_key(|(sample, _)| *sample);
+    let spiked = samples.iter().filter(|(_, x)| *x > 0).collect::<Vec<_>>();
+    let mean = spiked.iter().map(|(_, x)| *x as f64).sum::<f64>() / spiked.len() as f64;
+    samples
+        .into_iter()
+        .map(|(sample, spike_ins)| SizeFactor {
+            sample,
+            spike_ins,
+            size_factor: (spike_ins > 0).then(|| spike_ins as f64 / mean),
+        })
+        .collect()
+}
+
+/// Write `size_factors.tsv.gz`: the spike-ins and size factor of each sample
+/// of a matrix directory
+fn write_size_factors(dir: &Path, factors: &[SizeFactor]) -> Result<()> {
+    let path = dir.join("size_factors.tsv.gz");
+    let mut writer = TableWriter::new(
+        &path,
+        Compression::Gzip(CompressionLvl::default()),
+        BLOCK_SIZE,
+    );
+    for factor in factors {
+        writer.field(factor.sample.as_bytes());
+        writer.number(factor.spike_ins);
+        match factor.size_factor {
+            Some(size_factor) => writer.number(size_factor),
+            None => writer.field(b"NA"),
+        }
+        writer.end_row()?;
+    }
+    writer.finish()
+}
+
+/// Write a matrix directory: `matrix.mtx.gz` with the non-zero counts of each
+/// feature (rows) in each barcode (columns), `features.tsv.gz` and
+/// `barcodes.tsv.gz`, with the sample of each barcode in a second column if
+/// `with_sample`. Returns the number of non-zero counts.
+fn write_matrix(
+    dir: &Path,
+    features: &Features,
+    cells: &[Cell],
+    with_sample: bool,
+) -> Result<usize> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+    // The files of a matrix are always gzip-compressed
+    let level = Compression::Gzip(CompressionLvl::default());
+
+    let path = dir.join("features.tsv.gz");
+    let mut writer = TableWriter::new(&path, level, BLOCK_SIZE);
+    for (id, name) in features.ids.iter().zip(&features.names) {
+        writer.row([id.as_str(), name, "Taxon"])?;
+    }
+    writer.finish()?;
+
+    let path = dir.join("barcodes.tsv.gz");
+    let mut writer = TableWriter::new(&path, level, BLOCK_SIZE);
+    for cell in cells {
+        writer.field(cell.barcode.as_bytes());
+        if let (true, Some(sample)) = (with_sample, cell.sample) {
+            writer.field(sample.as_bytes());
+        }
+        writer.end_row()?;
+    }
+    writer.finish()?;
+
+    // The header holds the number of entries, counted first
+    let entries = cells
+        .iter()
+        .map(|cell| cell.counts.iter().filter(|x| **x > 0).count())
+        .sum::<usize>();
+    let path = dir.join("matrix.mtx.gz");
+    let mut writer = ChunkedWriter::new(&path, level, BLOCK_SIZE);
+    let mut line = Vec::new();
+    writeln!(
+        line,
+        "%%MatrixMarket matrix coordinate integer general\n{} {} {}",
+        features.ids.len(),
+        cells.len(),
+        entries
+    )?;
+    writer.write(&line)?;
+    for (column, cell) in cells.iter().enumerate() {
+        for (row, count) in cell.counts.iter().enumerate() {
+            if *count > 0 {
+                line.clear();
+                writeln!(line, "{} {} {}", row + 1, column + 1, count)?;
+                writer.write(&line)?;
+            }
+        }
+    }
+    writer.finish()?;
+    Ok(entries)
+}
+
+/// Samples become directory names, reject any which could escape the output
+/// directory
+fn check_sample(sample: &str) -> Result<()> {
+    if sample.is_empty()
+        || sample.starts_with('.')
+        || !sample
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(ErrorKind::Config.error(format!("Invalid sample name '{}'", sample)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::MultiGzDecoder;
+
+    use super::*;
+
+    fn read_gz(path: &Path) -> String {
+        let mut out = String::new();
+        MultiGzDecoder::new(std::fs::File::open(path).unwrap())
+            .read_to_string(&mut out)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_write_matrix() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let features = Features {
+            ids: vec!["562".to_string(), "9606".to_string()],
+            names: vec![
+                "s__Escherichia coli".to_string(),
+                "s__Homo sapiens".to_string(),
+            ],
+        };
+        let cells = vec![
+            Cell {
+                barcode: "AAAC",
+                sample: Some("s1"),
+                counts: &[3, 0],
+                spike_ins: 0,
+            },
+            Cell {
+                barcode: "CCCG",
+                sample: Some("s2"),
+                counts: &[1, 2],
+                spike_ins: 0,
+            },
+        ];
+        assert_eq!(write_matrix(temp.path(), &features, &cells, true)?, 3);
+        assert_eq!(
+            read_gz(&temp.path().join("matrix.mtx.gz")),
+            "%%MatrixMarket matrix coordinate integer general\n2 2 3\n1 1 3\n1 2 1\n2 2 2\n"
+        );
+        assert_eq!(
+            read_gz(&temp.path().join("barcodes.tsv.gz")),
+            "AAAC\ts1\nCCCG\ts2\n"
+        );
+        assert_eq!(
+            read_gz(&temp.path().join("features.tsv.gz")),
+            "562\ts__Escherichia coli\tTaxon\n9606\ts__Homo sapiens\tTaxon\n"
+        );
+        assert!(check_sample("s1").is_ok());
+        assert!(check_sample("../s1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_size_factors() {
+        let mut counts = vec![3, 4, 0, 1];
+        assert_eq!(split_spike_ins(&mut counts, &[false, true, false, true]), 5);
+        assert_eq!(counts, vec![3, 0]);
+
+        let cell = |sample, spike_ins| Cell {
+            barcode: "AAAC",
+            sample: Some(sample),
+            counts: &[],
+            spike_ins,
+        };
+        let cells = [
+            cell("s2", 10),
+            cell("s1", 20),
+            cell("s1", 10),
+            cell("s3", 0),
+        ];
+        let factors = size_factors(&cells);
+        assert_eq!(
+            factors
+                .iter()
+                .map(|x| (x.sample, x.spike_ins, x.size_factor))
+                .collect::<Vec<_>>(),
+            vec![
+                ("s1", 30, Some(1.5)),
+                ("s2", 10, Some(0.5)),
+                ("s3", 0, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compress_columns() {
+        let columns = vec![
+            vec![Some(3), None, Some(0)],
+            vec![None; 3],
+            vec![Some(1), Some(2), None],
+        ];
+        assert_eq!(
+            compress_columns(&columns),
+            (vec![0, 0, 1], vec![0, 1, 1, 3], vec![3.0, 1.0, 2.0])
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use std::fmt;
+
+use extendr_api::prelude::*;
+
+use crate::fastq_record::FastqParseError;
+
+/// The kinds of failure raised in R as conditions of their own class, so R
+/// pipelines can catch them apart with `tryCatch()`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ErrorKind {
+    /// Reading or writing a file failed
+    Io,
+    /// An input record or line is malformed
+    Parse,
+    /// The mates of paired inputs are out of sync
+    Pairing,
+    /// An argument or setting is invalid
+    Config,
+    /// The user interrupted the run
+    Interrupted,
+}
+
+impl ErrorKind {
+    /// The R condition class of the kind
+    fn class(&self) -> &'static str {
+        match self {
+            Self::Io => "mire_error_io",
+            Self::Parse => "mire_error_parse",
+            Self::Pairing => "mire_error_pairing",
+            Self::Config => "mire_error_config",
+            Self::Interrupted => "mire_error_interrupted",
+        }
+    }
+
+    /// An error of this kind, e.g. `Err(ErrorKind::Config.error("..."))`
+    pub(crate) fn error<M: Into<String>>(self, message: M) -> anyhow::Error {
+        anyhow::Error::new(MireError::new(self, message))
+    }
+
+    /// The kind of an error. A kind given explicitly, as the error or as
+    /// context, wins over the kind of the errors it wraps.
+    pub(crate) fn of(error: &anyhow::Error) -> Option<Self> {
+        if let Some(e) = error.downcast_ref::<MireError>() {
+            return Some(e.kind);
+        }
+        if let Some(e) = error.downcast_ref::<FastqParseError>() {
+            return match e {
+                FastqParseError::FastqPairError { .. } => Some(Self::Pairing),
+                _ => Some(Self::Parse),
+            };
+        }
+        // IO errors may also be the source of other errors
+        error
+            .chain()
+            .any(|cause| cause.is::<std::io::Error>())
+            .then_some(Self::Io)
+    }
+}
+
+/// An error of a known kind
+#[derive(Debug)]
+pub(crate) struct MireError {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl MireError {
+    pub(crate) fn new<M: Into<String>>(kind: ErrorKind, message: M) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for MireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for MireError {}
+
+/// The error of an extendr function, raised by `rust_call()` in R as a
+/// condition of class `mire_error`, and of the class of its kind if known
+#[derive(Debug)]
+pub(crate) struct RError {
+    message: String,
+    kind: Option<ErrorKind>,
+}
+
+impl From<anyhow::Error> for RError {
+    fn from(error: anyhow::Error) -> Self {
+        Self {
+            kind: ErrorKind::of(&error),
+            message: format!("{:?}", error),
+        }
+    }
+}
+
+impl From<String> for RError {
+    fn from(message: String) -> Self {
+        Self {
+            message,
+            kind: None,
+        }
+    }
+}
+
+impl From<RError> for Robj {
+    fn from(error: RError) -> Self {
+        let class = match error.kind {
+            Some(kind) => vec![kind.class(), "mire_error"],
+            None => vec!["mire_error"],
+        };
+        list!(message = error.message, class = class).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn test_error_kind() {
+        let io = std::fs::File::open("/nonexistent/reads.fq")
+            .context("Failed to open reads")
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&io), Some(ErrorKind::Io));
+        let config = Err::<(), _>(io)
+            .with_context(|| MireError::new(ErrorKind::Config, "Invalid output"))
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&config), Some(ErrorKind::Config));
+        let pairing = anyhow::Error::new(FastqParseError::FastqPairError {
+            read1_id: "r1".to_string(),
+            read2_id: "r2".to_string(),
+            read1_pos: None,
+            read2_pos: None,
+        });
+        assert_eq!(ErrorKind::of(&pairing), Some(ErrorKind::Pairing));
+        assert_eq!(ErrorKind::of(&anyhow::anyhow!("Unknown")), None);
+    }
+}
@@ -0,0 +1,136 @@
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use extendr_api::prelude::*;
+
+use crate::error::RError;
+use crate::fastq_reader::FastqReader;
+
+#[extendr]
+fn fastq_check_pairs(fq1: &str, fq2: &str) -> std::result::Result<List, RError> {
+    let check = FastqReader::from_path(fq1)
+        .and_then(|reader1| Ok((reader1, FastqReader::from_path(fq2)?)))
+        .and_then(|(reader1, reader2)| check_pairs(reader1, reader2))
+        .map_err(RError::from)?;
+    let id = |id: Option<Bytes>| id.map(|x| String::from_utf8_lossy(&x).into_owned());
+    let (position, id1, id2) = match check.divergence {
+        Some((position, id1, id2)) => (Some(position as f64), id(id1), id(id2)),
+        None => (None, None, None),
+    };
+    Ok(list!(
+        records1 = check.records1 as f64,
+        records2 = check.records2 as f64,
+        position = position,
+        id1 = id1,
+        id2 = id2
+    ))
+}
+
+extendr_module! {
+    mod fastq_check;
+    fn fastq_check_pairs;
+}
+
+/// The result of a pairing check of two FASTQ files
+#[derive(Debug, PartialEq)]
+struct PairCheck {
+    records1: usize,
+    records2: usize,
+    /// The first record (1-based) whose mates differ, with the ID of each
+    /// mate, `None` for a file ending before the other
+    divergence: Option<(usize, Option<Bytes>, Option<Bytes>)>,
+}
+
+/// Stream the mates of two FASTQ files, checking that the records of both
+/// files pair up one to one, in the same order. Both files are read to the
+/// end, so the record counts are reported even after a divergence.
+fn check_pairs<R1: Read, R2: Read>(
+    mut reader1: FastqReader<R1>,
+    mut reader2: FastqReader<R2>,
+) -> Result<PairCheck> {
+    let mut check = PairCheck {
+        records1: 0,
+        records2: 0,
+        divergence: None,
+    };
+    loop {
+        let record1 = reader1.read_record().with_context(|| {
+            format!(
+                "(Reader) Failed to read record {} of read1",
+                check.records1 + 1
+            )
+        })?;
+        let record2 = reader2.read_record().with_context(|| {
+            format!(
+                "(Reader) Failed to read record {} of read2",
+                check.records2 + 1
+            )
+        })?;
+        if record1.is_none() && record2.is_none() {
+            break;
+        }
+        check.records1 += record1.is_some() as usize;
+        check.records2 += record2.is_some() as usize;
+        if check.divergence.is_some() {
+            continue;
+        }
+        let id1 = record1.map(|x| x.id);
+        let id2 = record2.map(|x| x.id);
+        let paired = match (&id1, &id2) {
+            (Some(id1), Some(id2)) => mate_id(id1) == mate_id(id2),
+            _ => false,
+        };
+        if !paired {
+            check.divergence = Some((check.records1.max(check.records2), id1, id2));
+        }
+    }
+    Ok(check)
+}
+
+/// The ID shared by both mates, without the `/1` or `/2` suffix of older
+/// Illumina read names
+pub(crate) fn mate_id(id: &[u8]) -> &[u8] {
+    match id {
+        [id @ .., b'/', b'1' | b'2'] => id,
+        _ => id,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(fq1: &str, fq2: &str) -> Result<PairCheck> {
+        check_pairs(
+            FastqReader::new(fq1.as_bytes()),
+            FastqReader::new(fq2.as_bytes()),
+        )
+    }
+
+    #[test]
+    fn test_check_pairs() -> Result<()> {
+        let fq1 = "@r1/1\nA\n+\nI\n@r2 x\nA\n+\nI\n@r3\nA\n+\nI\n";
+        let ok = check(fq1, "@r1/2\nC\n+\nI\n@r2 y\nC\n+\nI\n@r3\nC\n+\nI\n")?;
+        assert_eq!(ok.divergence, None);
+        assert_eq!((ok.records1, ok.records2), (3, 3));
+
+        // Swapped records, counted to the end
+        let swapped = check(fq1, "@r1\nC\n+\nI\n@r3\nC\n+\nI\n@r2\nC\n+\nI\n")?;
+        assert_eq!(
+            swapped.divergence,
+            Some((2, Some(Bytes::from("r2")), Some(Bytes::from("r3"))))
+        );
+        assert_eq!((swapped.records1, swapped.records2), (3, 3));
+
+        // Missing mates
+        let truncated = check(fq1, "@r1\nC\n+\nI\n")?;
+        assert_eq!(
+            truncated.divergence,
+            Some((2, Some(Bytes::from("r2")), None))
+        );
+        assert_eq!((truncated.records1, truncated.records2), (3, 1));
+        assert!(check(fq1, "@r1\nC\n").is_err());
+        Ok(())
+    }
+}
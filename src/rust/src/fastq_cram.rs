@@ -0,0 +1,1059 @@
+use std::io::{Read, Write};
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use extendr_api::prelude::*;
+use flate2::Crc;
+
+use crate::error::{ErrorKind, RError};
+use crate::fastq_check::mate_id;
+use crate::fastq_reader::FastqReader;
+use crate::fastq_record::FastqRecord;
+use crate::utils::*;
+
+#[extendr]
+fn fastq_cram(
+    fq1: &str,
+    fq2: Option<&str>,
+    ofile: &str,
+    container_records: usize,
+    compression_level: Option<u32>,
+) -> std::result::Result<List, RError> {
+    let level = compression_level
+        .map(|level| {
+            if level > 9 {
+                Err(anyhow!("CRAM blocks are gzip-compressed at levels 0 to 9"))
+            } else {
+                Ok(flate2::Compression::new(level))
+            }
+        })
+        .transpose()
+        .map_err(RError::from)?
+        .unwrap_or_default();
+    let writer = new_writer(ofile, None).map_err(RError::from)?;
+    let mut cram =
+        CramWriter::new(writer, ofile, container_records, level).map_err(RError::from)?;
+    let reader1 = FastqReader::from_path(fq1).map_err(RError::from)?;
+    match fq2 {
+        Some(fq2) => {
+            let reader2 = FastqReader::from_path(fq2).map_err(RError::from)?;
+            write_paired(&mut cram, reader1, reader2)
+        }
+        None => write_single(&mut cram, reader1),
+    }
+    .and_then(|_| cram.finish())
+    .with_context(|| format!("Failed to write CRAM file {}", ofile))
+    .map_err(RError::from)?;
+    Ok(list!(
+        records = cram.records as f64,
+        bases = cram.bases as f64
+    ))
+}
+
+extendr_module! {
+    mod fastq_cram;
+    fn fastq_cram;
+}
+
+fn write_single<R: Read, W: CompleteWrite>(
+    cram: &mut CramWriter<W>,
+    reader: FastqReader<R>,
+) -> Result<()> {
+    for record in reader {
+        cram.push(
+            &record.context("(Reader) Failed to read record")?,
+            Mate::Single,
+        )?;
+    }
+    Ok(())
+}
+
+fn write_paired<R1: Read, R2: Read, W: CompleteWrite>(
+    cram: &mut CramWriter<W>,
+    mut reader1: FastqReader<R1>,
+    mut reader2: FastqReader<R2>,
+) -> Result<()> {
+    loop {
+        let record1 = reader1
+            .read_record()
+            .context("(Reader) Failed to read read1")?;
+        let record2 = reader2
+            .read_record()
+            .context("(Reader) Failed to read read2")?;
+        match (record1, record2) {
+            (Some(record1), Some(record2)) => {
+                if mate_id(&record1.id) != mate_id(&record2.id) {
+                    return Err(ErrorKind::Pairing.error(format!(
+                        "(Reader) FASTQ pairing error: mismatched read IDs {} and {}",
+                        String::from_utf8_lossy(&record1.id),
+                        String::from_utf8_lossy(&record2.id)
+                    )));
+                }
+                // Mates are kept in the same container
+                cram.push(&record1, Mate::First)?;
+                cram.push(&record2, Mate::Second)?;
+            }
+            (None, None) => return Ok(()),
+            _ => {
+                return Err(ErrorKind::Pairing
+                    .error("(Reader) FASTQ pairing error: read1 and read2 differ in length"))
+            }
+        }
+    }
+}
+
+// ─── CRAM 3.0 ───
+//
+// Unaligned reads are written without reference: every data series is stored
+// in its own gzip-compressed external block, one slice per container. The
+// FASTQ description (with its `MIRE{}` annotation) is kept in a `CO:Z` tag.
+
+const CRAM_MAGIC: &[u8] = b"CRAM";
+
+// Block content types
+const FILE_HEADER: u8 = 0;
+const COMPRESSION_HEADER: u8 = 1;
+const SLICE_HEADER: u8 = 2;
+const EXTERNAL_DATA: u8 = 4;
+const CORE_DATA: u8 = 5;
+
+// Codecs of the data series
+const EXTERNAL: i32 = 1;
+const BYTE_ARRAY_LEN: i32 = 4;
+const BYTE_ARRAY_STOP: i32 = 5;
+
+// BAM flags of unaligned reads
+const FLAG_PAIRED: i32 = 0x1;
+const FLAG_UNMAPPED: i32 = 0x4;
+const FLAG_MATE_UNMAPPED: i32 = 0x8;
+const FLAG_FIRST: i32 = 0x40;
+const FLAG_LAST: i32 = 0x80;
+// CRAM flags: qualities are stored, mates are not linked
+const CF_PRESERVE_QUALITY: i32 = 0x1;
+const CF_DETACHED: i32 = 0x2;
+// Mate flag: the mate is unmapped
+const MF_MATE_UNMAPPED: i32 = 0x2;
+
+// Read names end with a tab, which cannot occur in a FASTQ read ID
+const NAME_STOP: u8 = b'\t';
+// Tag of the FASTQ description, `CO` of type `Z`
+const DESCRIPTION_TAG: [u8; 3] = *b"COZ";
+
+/// The data series of unaligned reads, with the content ID of their external
+/// block
+#[derive(Clone, Copy)]
+enum Series {
+    Bf = 1,
+    Cf,
+    Rl,
+    Ap,
+    Rg,
+    Rn,
+    Mf,
+    Ns,
+    Np,
+    Ts,
+    Tl,
+    Ba,
+    Qs,
+    DescriptionLen,
+    Description,
+}
+
+const SERIES: [(&[u8; 2], Series); 13] = [
+    (b"BF", Series::Bf),
+    (b"CF", Series::Cf),
+    (b"RL", Series::Rl),
+    (b"AP", Series::Ap),
+    (b"RG", Series::Rg),
+    (b"RN", Series::Rn),
+    (b"MF", Series::Mf),
+    (b"NS", Series::Ns),
+    (b"NP", Series::Np),
+    (b"TS", Series::Ts),
+    (b"TL", Series::Tl),
+    (b"BA", Series::Ba),
+    (b"QS", Series::Qs),
+];
+const EXTERNAL_BLOCKS: usize = Series::Description as usize;
+
+#[derive(Clone, Copy)]
+enum Mate {
+    Single,
+    First,
+    Second,
+}
+
+/// Writer of unaligned reads to a CRAM 3.0 file
+struct CramWriter<W: CompleteWrite> {
+    writer: W,
+    level: flate2::Compression,
+    container_records: usize,
+    // The external blocks of the container being filled
+    blocks: Vec<Vec<u8>>,
+    container: usize,
+    container_bases: usize,
+    records: usize,
+    bases: usize,
+}
+
+impl<W: CompleteWrite> CramWriter<W> {
+    fn new(
+        mut writer: W,
+        name: &str,
+        container_records: usize,
+        level: flate2::Compression,
+    ) -> Result<Self> {
+        // File definition: magic, version 3.0 and a 20 bytes file ID
+        let mut definition = Vec::with_capacity(26);
+        definition.extend_from_slice(CRAM_MAGIC);
+        definition.extend_from_slice(&[3, 0]);
+        let mut id = [0u8; 20];
+        let name = name.rsplit('/').next().unwrap_or(name).as_bytes();
+        let len = name.len().min(id.len());
+        id[.. len].copy_from_slice(&name[.. len]);
+        definition.extend_from_slice(&id);
+        writer.write_all(&definition)?;
+
+        let text = b"@HD\tVN:1.6\tSO:unsorted\tGO:query\n@PG\tID:mire\tPN:mire\n";
+        let mut header = Vec::with_capacity(text.len() + 4);
+        header.extend_from_slice(&(text.len() as i32).to_le_bytes());
+        header.extend_from_slice(text);
+        let block = block(FILE_HEADER, 0, &header, None)?;
+        writer.write_all(&container(0, 0, 0, 0, &[block], &[]))?;
+        Ok(Self {
+            writer,
+            level,
+            container_records: container_records.max(1),
+            blocks: vec![Vec::new(); EXTERNAL_BLOCKS],
+            container: 0,
+            container_bases: 0,
+            records: 0,
+            bases: 0,
+        })
+    }
+
+    fn push(&mut self, record: &FastqRecord<Bytes>, mate: Mate) -> Result<()> {
+        // Full containers are flushed between pairs, so mates stay together
+        if self.container >= self.container_records && !matches!(mate, Mate::Second) {
+            self.flush()?;
+        }
+        let (flag, mate_flag) = match mate {
+            Mate::Single => (FLAG_UNMAPPED, 0),
+            Mate::First => (
+                FLAG_PAIRED | FLAG_UNMAPPED | FLAG_MATE_UNMAPPED | FLAG_FIRST,
+                MF_MATE_UNMAPPED,
+            ),
+            Mate::Second => (
+                FLAG_PAIRED | FLAG_UNMAPPED | FLAG_MATE_UNMAPPED | FLAG_LAST,
+                MF_MATE_UNMAPPED,
+            ),
+        };
+        let len = record.seq.len();
+        self.int(Series::Bf, flag);
+        self.int(Series::Cf, CF_PRESERVE_QUALITY | CF_DETACHED);
+        self.int(Series::Rl, len as i32);
+        self.int(Series::Ap, 0);
+        self.int(Series::Rg, -1);
+        let name = match mate {
+            Mate::Single => &record.id[..],
+            _ => mate_id(&record.id),
+        };
+        self.bytes(Series::Rn, name);
+        self.bytes(Series::Rn, &[NAME_STOP]);
+        self.int(Series::Mf, mate_flag);
+        self.int(Series::Ns, -1);
+        self.int(Series::Np, 0);
+        self.int(Series::Ts, 0);
+        // Tag line 1 holds the description, tag line 0 is empty
+        match record.desc.as_ref().filter(|desc| !desc.is_empty()) {
+            Some(desc) => {
+                self.int(Series::Tl, 1);
+                self.int(Series::DescriptionLen, desc.len() as i32 + 1);
+                self.bytes(Series::Description, desc);
+                self.bytes(Series::Description, &[0]);
+            }
+            None => self.int(Series::Tl, 0),
+        }
+        self.bytes(Series::Ba, &record.seq);
+        let qual = &mut self.blocks[Series::Qs as usize - 1];
+        qual.extend(record.qual.iter().map(|q| q.saturating_sub(33)));
+        self.container += 1;
+        self.container_bases += len;
+        Ok(())
+    }
+
+    fn int(&mut self, series: Series, value: i32) {
+        itf8(&mut self.blocks[series as usize - 1], value);
+    }
+
+    fn bytes(&mut self, series: Series, value: &[u8]) {
+        self.blocks[series as usize - 1].extend_from_slice(value);
+    }
+
+    /// Write the records pushed so far as a container of a single slice
+    fn flush(&mut self) -> Result<()> {
+        if self.container == 0 {
+            return Ok(());
+        }
+        let compression = block(COMPRESSION_HEADER, 0, &compression_header(), None)?;
+
+        let mut slice = Vec::new();
+        itf8(&mut slice, -1); // unmapped
+        itf8(&mut slice, 0);
+        itf8(&mut slice, 0);
+        itf8(&mut slice, self.container as i32);
+        ltf8(&mut slice, self.records as i64);
+        // The core block and the external blocks
+        itf8(&mut slice, EXTERNAL_BLOCKS as i32 + 1);
+        itf8(&mut slice, EXTERNAL_BLOCKS as i32 + 1);
+        for id in 0 ..= EXTERNAL_BLOCKS {
+            itf8(&mut slice, id as i32);
+        }
+        itf8(&mut slice, -1); // no embedded reference
+        slice.extend_from_slice(&[0; 16]); // no reference MD5
+
+        let mut blocks = vec![
+            compression,
+            block(SLICE_HEADER, 0, &slice, None)?,
+            block(CORE_DATA, 0, &[], None)?,
+        ];
+        for (i, data) in self.blocks.iter_mut().enumerate() {
+            blocks.push(block(EXTERNAL_DATA, i as i32 + 1, data, Some(self.level))?);
+            data.clear();
+        }
+        let landmarks = [blocks[0].len() as i32];
+        self.writer.write_all(&container(
+            -1,
+            self.container,
+            self.records,
+            self.container_bases,
+            &blocks,
+            &landmarks,
+        ))?;
+        self.records += self.container;
+        self.bases += self.container_bases;
+        self.container = 0;
+        self.container_bases = 0;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.flush()?;
+        self.writer.write_all(&eof_container()?)?;
+        self.writer.complete()?;
+        Ok(())
+    }
+}
+
+/// The compression header of all containers: read names are kept, positions
+/// are not delta-coded, and no reference is needed
+fn compression_header() -> Vec<u8> {
+    let mut preservation = Vec::new();
+    itf8(&mut preservation, 5);
+    preservation.extend_from_slice(b"RN\x01");
+    preservation.extend_from_slice(b"AP\x00");
+    preservation.extend_from_slice(b"RR\x00");
+    // Substitutions are never used by unaligned reads
+    preservation.extend_from_slice(b"SM");
+    preservation.extend_from_slice(&[0x1b; 5]);
+    let mut dictionary = Vec::new();
+    dictionary.push(0);
+    dictionary.extend_from_slice(&DESCRIPTION_TAG);
+    dictionary.push(0);
+    preservation.extend_from_slice(b"TD");
+    itf8(&mut preservation, dictionary.len() as i32);
+    preservation.extend_from_slice(&dictionary);
+
+    let mut series = Vec::new();
+    itf8(&mut series, SERIES.len() as i32);
+    for (key, id) in SERIES {
+        series.extend_from_slice(key);
+        match id {
+            Series::Rn => {
+                let mut params = vec![NAME_STOP];
+                itf8(&mut params, id as i32);
+                encoding(&mut series, BYTE_ARRAY_STOP, &params);
+            }
+            _ => external(&mut series, id),
+        }
+    }
+
+    let mut tags = Vec::new();
+    itf8(&mut tags, 1);
+    let [a, b, t] = DESCRIPTION_TAG;
+    itf8(&mut tags, (a as i32) << 16 | (b as i32) << 8 | t as i32);
+    let mut params = Vec::new();
+    external(&mut params, Series::DescriptionLen);
+    external(&mut params, Series::Description);
+    encoding(&mut tags, BYTE_ARRAY_LEN, &params);
+
+    let mut header = Vec::new();
+    for map in [preservation, series, tags] {
+        itf8(&mut header, map.len() as i32);
+        header.extend_from_slice(&map);
+    }
+    header
+}
+
+fn encoding(out: &mut Vec<u8>, codec: i32, params: &[u8]) {
+    itf8(out, codec);
+    itf8(out, params.len() as i32);
+    out.extend_from_slice(params);
+}
+
+fn external(out: &mut Vec<u8>, series: Series) {
+    let mut params = Vec::new();
+    itf8(&mut params, series as i32);
+    encoding(out, EXTERNAL, &params);
+}
+
+/// A block of `data`, gzip-compressed at `level` if given
+fn block(
+    content_type: u8,
+    content_id: i32,
+    data: &[u8],
+    level: Option<flate2::Compression>,
+) -> Result<Vec<u8>> {
+    let compressed;
+    let (method, payload) = match level {
+        Some(level) if !data.is_empty() => {
+            let mut encoder = gzip_encoder(Vec::new(), level);
+            encoder.write_all(data)?;
+            compressed = encoder.finish()?;
+            (1, &compressed[..])
+        }
+        _ => (0, data),
+    };
+    let mut out = Vec::with_capacity(payload.len() + 16);
+    out.push(method);
+    out.push(content_type);
+    itf8(&mut out, content_id);
+    itf8(&mut out, payload.len() as i32);
+    itf8(&mut out, data.len() as i32);
+    out.extend_from_slice(payload);
+    out.extend_from_slice(&crc32(&out).to_le_bytes());
+    Ok(out)
+}
+
+/// A container of `blocks`, whose slices start at `landmarks`
+fn container(
+    reference: i32,
+    records: usize,
+    counter: usize,
+    bases: usize,
+    blocks: &[Vec<u8>],
+    landmarks: &[i32],
+) -> Vec<u8> {
+    let len: usize = blocks.iter().map(|block| block.len()).sum();
+    let mut out = Vec::with_capacity(len + 64);
+    out.extend_from_slice(&(len as i32).to_le_bytes());
+    itf8(&mut out, reference);
+    // The EOF container starts at "EOF"
+    itf8(
+        &mut out,
+        if records == 0 && reference == -1 {
+            0x454f46
+        } else {
+            0
+        },
+    );
+    itf8(&mut out, 0);
+    itf8(&mut out, records as i32);
+    ltf8(&mut out, counter as i64);
+    ltf8(&mut out, bases as i64);
+    itf8(&mut out, blocks.len() as i32);
+    itf8(&mut out, landmarks.len() as i32);
+    for landmark in landmarks {
+        itf8(&mut out, *landmark);
+    }
+    out.extend_from_slice(&crc32(&out).to_le_bytes());
+    for block in blocks {
+        out.extend_from_slice(block);
+    }
+    out
+}
+
+/// The empty container ending CRAM 3.0 files, whose absence tells readers
+/// that a file is truncated
+fn eof_container() -> Result<Vec<u8>> {
+    let block = block(COMPRESSION_HEADER, 0, &[1, 0, 1, 0, 1, 0], None)?;
+    Ok(container(-1, 0, 0, 0, &[block], &[]))
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(bytes);
+    crc.sum()
+}
+
+/// Append a 32 bits integer with the variable length ITF8 encoding
+fn itf8(out: &mut Vec<u8>, value: i32) {
+    let v = value as u32;
+    if v < 0x80 {
+        out.push(v as u8);
+    } else if v < 0x4000 {
+        out.extend_from_slice(&[0x80 | (v >> 8) as u8, v as u8]);
+    } else if v < 0x20_0000 {
+        out.extend_from_slice(&[0xc0 | (v >> 16) as u8, (v >> 8) as u8, v as u8]);
+    } else if v < 0x1000_0000 {
+        out.extend_from_slice(&[
+            0xe0 | (v >> 24) as u8,
+            (v >> 16) as u8,
+            (v >> 8) as u8,
+            v as u8,
+        ]);
+    } else {
+        out.extend_from_slice(&[
+            0xf0 | (v >> 28) as u8,
+            (v >> 20) as u8,
+            (v >> 12) as u8,
+            (v >> 4) as u8,
+            (v & 0x0f) as u8,
+        ]);
+    }
+}
+
+/// Append a 64 bits integer with the variable length LTF8 encoding
+fn ltf8(out: &mut Vec<u8>, value: i64) {
+    let v = value as u64;
+    // The number of bytes following the first one
+    let extra = match v {
+        0 ..= 0x7f => 0,
+        0x80 ..= 0x3fff => 1,
+        0x4000 ..= 0x1f_ffff => 2,
+        0x20_0000 ..= 0x0fff_ffff => 3,
+        0x1000_0000 ..= 0x07_ffff_ffff => 4,
+        0x08_0000_0000 ..= 0x03ff_ffff_ffff => 5,
+        0x0400_0000_0000 ..= 0x01_ffff_ffff_ffff => 6,
+        0x02_0000_0000_0000 ..= 0xff_ffff_ffff_ffff => 7,
+        _ => 8,
+    };
+    // The leading ones of the first byte count the following bytes
+    let prefix = !(0xffu16 >> extra) as u8;
+    if extra == 8 {
+        out.push(prefix);
+    } else {
+        out.push(prefix | (v >> (8 * extra)) as u8);
+    }
+    for i in (0 .. extra).rev() {
+        out.push((v >> (8 * i)) as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::error::ErrorKind;
+
+    #[test]
+    fn test_cram_encoding() -> Result<()> {
+        let encode = |value: i32| {
+            let mut out = Vec::new();
+            itf8(&mut out, value);
+            out
+        };
+        assert_eq!(encode(0x7f), [0x7f]);
+        assert_eq!(encode(0x80), [0x80, 0x80]);
+        assert_eq!(encode(0x4000), [0xc0, 0x40, 0x00]);
+        assert_eq!(encode(-1), [0xff, 0xff, 0xff, 0xff, 0x0f]);
+        let encode = |value: i64| {
+            let mut out = Vec::new();
+            ltf8(&mut out, value);
+            out
+        };
+        assert_eq!(encode(0x7f), [0x7f]);
+        assert_eq!(encode(0x4000), [0xc0, 0x40, 0x00]);
+        assert_eq!(encode(0x1_0000_0000), [0xf1, 0, 0, 0, 0]);
+        assert_eq!(encode(-1), [0xff; 9]);
+
+        // The EOF container of htslib, byte for byte
+        assert_eq!(
+            eof_container()?,
+            b"\x0f\x00\x00\x00\xff\xff\xff\xff\x0f\xe0\x45\x4f\x46\x00\x00\x00\
+              \x00\x01\x00\x05\xbd\xd9\x4f\x00\x01\x00\x06\x06\x01\x00\x01\x00\
+              \x01\x00\xee\x63\x01\x4b"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cram_layout() -> Result<()> {
+        // The bytes of a single read, laid out by hand from the CRAM 3.0
+        // specification rather than read back by the decoder below
+        let mut cram = CramWriter::new(Vec::new(), "dir/reads.cram", 1, Default::default())?;
+        write_single(&mut cram, FastqReader::new(&b"@r1\nAC\n+\nII\n"[..]))?;
+        cram.finish()?;
+        let mut out = &cram.writer[..];
+
+        // File definition: magic, version 3.0 and the file ID padded with zeros
+        assert_eq!(
+            take(&mut out, 26),
+            b"CRAM\x03\x00reads.cram\0\0\0\0\0\0\0\0\0\0"
+        );
+
+        // Header container of a single raw file header block: the container
+        // length, 6 zero fields, 1 block, no landmark and the CRC32
+        assert_eq!(
+            take(&mut out, 16),
+            b"\x41\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\xb1\x0c\x08\xc9"
+        );
+        let text = b"@HD\tVN:1.6\tSO:unsorted\tGO:query\n@PG\tID:mire\tPN:mire\n";
+        assert_eq!(take(&mut out, 5), b"\x00\x00\x00\x38\x38");
+        assert_eq!(take(&mut out, 4), (text.len() as i32).to_le_bytes());
+        assert_eq!(take(&mut out, text.len()), text);
+        assert_eq!(take(&mut out, 4), b"\x9e\x1f\xc1\x68");
+
+        // Data container: unmapped, 1 record, counter 0, 2 bases, 18 blocks
+        // and the compression header block as the landmark of the slice
+        let header = out;
+        let len = i32::from_le_bytes(take(&mut out, 4).try_into()?) as usize;
+        assert_eq!(
+            take(&mut out, 13),
+            b"\xff\xff\xff\xff\x0f\x00\x00\x01\x00\x02\x12\x01\x75"
+        );
+        let crc = u32::from_le_bytes(take(&mut out, 4).try_into()?);
+        assert_eq!(crc, crc32(&header[.. 17]));
+        let blocks = out;
+
+        // Compression header: preservation map, data series map, tag map
+        assert_eq!(
+            take(&mut out, 117),
+            b"\x00\x01\x00\x6c\x6c\
+              \x19\x05RN\x01AP\x00RR\x00SM\x1b\x1b\x1b\x1b\x1bTD\x05\x00COZ\x00\
+              \x43\x0dBF\x01\x01\x01CF\x01\x01\x02RL\x01\x01\x03AP\x01\x01\x04\
+              RG\x01\x01\x05RN\x05\x02\x09\x06MF\x01\x01\x07NS\x01\x01\x08\
+              NP\x01\x01\x09TS\x01\x01\x0aTL\x01\x01\x0bBA\x01\x01\x0c\
+              QS\x01\x01\x0d\
+              \x0d\x01\xe0COZ\x04\x06\x01\x01\x0e\x01\x01\x0f\
+              \x43\xa6\x60\xd1"
+        );
+        // Slice header: unmapped, 1 record, counter 0, the core block and
+        // 15 external blocks, no embedded reference and no MD5
+        assert_eq!(
+            take(&mut out, 57),
+            b"\x00\x02\x00\x30\x30\
+              \xff\xff\xff\xff\x0f\x00\x00\x01\x00\x10\x10\
+              \x00\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a\x0b\x0c\x0d\x0e\x0f\
+              \xff\xff\xff\xff\x0f\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\
+              \x5a\x90\x07\x3f"
+        );
+        // Empty core block
+        assert_eq!(take(&mut out, 9), b"\x00\x05\x00\x00\x00\x2f\x07\xfc\xf1");
+
+        // Gzip-compressed external blocks, one per data series
+        let series: [&[u8]; 13] = [
+            b"\x04",
+            b"\x03",
+            b"\x02",
+            b"\x00",
+            b"\xff\xff\xff\xff\x0f",
+            b"r1\t",
+            b"\x00",
+            b"\xff\xff\xff\xff\x0f",
+            b"\x00",
+            b"\x00",
+            b"\x00",
+            b"AC",
+            b"\x28\x28",
+        ];
+        for (id, expected) in (1 ..).zip(series) {
+            let start = out;
+            assert_eq!(take(&mut out, 3), [1, 4, id]);
+            let size = take(&mut out, 1)[0] as usize;
+            assert!(size < 0x80);
+            assert_eq!(take(&mut out, 1), [expected.len() as u8]);
+            let mut raw = Vec::new();
+            flate2::read::MultiGzDecoder::new(take(&mut out, size)).read_to_end(&mut raw)?;
+            assert_eq!(raw, expected);
+            let crc = u32::from_le_bytes(take(&mut out, 4).try_into()?);
+            assert_eq!(crc, crc32(&start[.. size + 5]));
+        }
+        // The description blocks are empty, hence stored raw
+        assert_eq!(take(&mut out, 9), b"\x00\x04\x0e\x00\x00\x40\x4d\xde\x43");
+        assert_eq!(take(&mut out, 9), b"\x00\x04\x0f\x00\x00\x77\x27\x1c\x42");
+        assert_eq!(blocks.len() - out.len(), len);
+
+        // The EOF container of htslib
+        assert_eq!(
+            out,
+            b"\x0f\x00\x00\x00\xff\xff\xff\xff\x0f\xe0\x45\x4f\x46\x00\x00\x00\
+              \x00\x01\x00\x05\xbd\xd9\x4f\x00\x01\x00\x06\x06\x01\x00\x01\x00\
+              \x01\x00\xee\x63\x01\x4b"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cram_writer() -> Result<()> {
+        let fq1 = "@r1/1 MIRE{CB:ACGT}\nACGT\n+\nIIII\n@r2/1\nGG\n+\n!!\n";
+        let fq2 = "@r1/2 MIRE{CB:ACGT}\nTTTT\n+\nIIII\n@r2/2\nCC\n+\n!!\n";
+        let mut cram = CramWriter::new(Vec::new(), "reads.cram", 1, Default::default())?;
+        write_paired(
+            &mut cram,
+            FastqReader::new(fq1.as_bytes()),
+            FastqReader::new(fq2.as_bytes()),
+        )?;
+        cram.finish()?;
+        assert_eq!((cram.records, cram.bases), (4, 12));
+        let out = &cram.writer;
+        assert_eq!(&out[.. 6], b"CRAM\x03\x00");
+        assert_eq!(&out[6 .. 16], b"reads.cram");
+        assert!(out.ends_with(&eof_container()?));
+
+        // One container per pair, after the header container
+        let mut containers = 0;
+        let mut pos = 26;
+        while pos < out.len() {
+            let len = i32::from_le_bytes(out[pos .. pos + 4].try_into()?) as usize;
+            let header = &out[pos ..];
+            let crc_end = header.len() - out[pos ..].len() + container_header_len(header);
+            let crc = u32::from_le_bytes(header[crc_end .. crc_end + 4].try_into()?);
+            assert_eq!(crc, crc32(&header[.. crc_end]));
+            pos += crc_end + 4 + len;
+            containers += 1;
+        }
+        assert_eq!(pos, out.len());
+        assert_eq!(containers, 4);
+
+        // Mates of different IDs, or a mate missing, are pairing errors
+        let mates = "@r1\nACGT\n+\nIIII\n@r2\nGG\n+\n!!\n";
+        for (fq1, fq2) in [(mates, &mates[.. 16]), (mates, &mates[16 ..])] {
+            let mut cram = CramWriter::new(Vec::new(), "reads.cram", 1, Default::default())?;
+            let err = write_paired(
+                &mut cram,
+                FastqReader::new(fq1.as_bytes()),
+                FastqReader::new(fq2.as_bytes()),
+            )
+            .unwrap_err();
+            assert_eq!(ErrorKind::of(&err), Some(ErrorKind::Pairing));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_cram_decode() -> Result<()> {
+        let fq1 = "@r1/1 MIRE{CB:ACGT}\nACGT\n+\nIIII\n@r2/1\nGG\n+\n!!\n\
+                   @r3/1\nGATTACA\n+\nABCDEFG\n";
+        let fq2 = "@r1/2 MIRE{CB:ACGT}\nTTTT\n+\nIIII\n@r2/2\nCC\n+\n!#\n\
+                   @r3/2\nT\n+\n5\n";
+        // Containers of one pair, and of several pairs per slice
+        for container_records in [1, 4] {
+            let mut cram = CramWriter::new(
+                Vec::new(),
+                "reads.cram",
+                container_records,
+                flate2::Compression::new(6),
+            )?;
+            write_paired(
+                &mut cram,
+                FastqReader::new(fq1.as_bytes()),
+                FastqReader::new(fq2.as_bytes()),
+            )?;
+            cram.finish()?;
+            let reads = decode_cram(&cram.writer)?;
+            let first = FLAG_PAIRED | FLAG_UNMAPPED | FLAG_MATE_UNMAPPED | FLAG_FIRST;
+            let last = FLAG_PAIRED | FLAG_UNMAPPED | FLAG_MATE_UNMAPPED | FLAG_LAST;
+            assert_eq!(
+                reads,
+                vec![
+                    read(first, "r1", Some("MIRE{CB:ACGT}"), "ACGT", "IIII"),
+                    read(last, "r1", Some("MIRE{CB:ACGT}"), "TTTT", "IIII"),
+                    read(first, "r2", None, "GG", "!!"),
+                    read(last, "r2", None, "CC", "!#"),
+                    read(first, "r3", None, "GATTACA", "ABCDEFG"),
+                    read(last, "r3", None, "T", "5"),
+                ]
+            );
+        }
+
+        let fq = "@r1 sample=1\nACGTN\n+\nIIII#\n@r2\nG\n+\n!\n";
+        let mut cram = CramWriter::new(Vec::new(), "reads.cram", 2, Default::default())?;
+        write_single(&mut cram, FastqReader::new(fq.as_bytes()))?;
+        cram.finish()?;
+        assert_eq!(
+            decode_cram(&cram.writer)?,
+            vec![
+                read(FLAG_UNMAPPED, "r1", Some("sample=1"), "ACGTN", "IIII#"),
+                read(FLAG_UNMAPPED, "r2", None, "G", "!"),
+            ]
+        );
+        Ok(())
+    }
+
+    /// A decoded read: its BAM flag, name, description, sequence and
+    /// qualities
+    type DecodedRead = (i32, String, Option<String>, String, String);
+
+    fn read(flag: i32, name: &str, desc: Option<&str>, seq: &str, qual: &str) -> DecodedRead {
+        (
+            flag,
+            name.to_string(),
+            desc.map(str::to_string),
+            seq.to_string(),
+            qual.to_string(),
+        )
+    }
+
+    /// How a data series or tag is decoded
+    #[derive(Debug)]
+    enum Encoding {
+        External(i32),
+        ByteArrayStop(u8, i32),
+        ByteArrayLen(Box<Encoding>, Box<Encoding>),
+    }
+
+    fn read_itf8(data: &mut &[u8]) -> i32 {
+        let extra = data[0].leading_ones().min(4) as usize;
+        // The first byte of 5 holds the 4 highest bits
+        let mut value = (data[0] as u32) & (0xff >> (extra + 1).min(4));
+        for i in 1 ..= extra {
+            value = if i == 4 {
+                value << 4 | (data[i] & 0x0f) as u32
+            } else {
+                value << 8 | data[i] as u32
+            };
+        }
+        *data = &data[extra + 1 ..];
+        value as i32
+    }
+
+    fn read_ltf8(data: &mut &[u8]) -> i64 {
+        let extra = data[0].leading_ones() as usize;
+        let mut value = if extra == 8 {
+            0
+        } else {
+            (data[0] as u64) & (0xff >> (extra + 1))
+        };
+        for i in 1 ..= extra {
+            value = value << 8 | data[i] as u64;
+        }
+        *data = &data[extra + 1 ..];
+        value as i64
+    }
+
+    fn take<'a>(data: &mut &'a [u8], len: usize) -> &'a [u8] {
+        let (head, tail) = data.split_at(len);
+        *data = tail;
+        head
+    }
+
+    /// A map of the compression header, prefixed by its size
+    fn take_map<'a>(data: &mut &'a [u8]) -> &'a [u8] {
+        let len = read_itf8(data) as usize;
+        take(data, len)
+    }
+
+    fn read_encoding(data: &mut &[u8]) -> Encoding {
+        let codec = read_itf8(data);
+        let len = read_itf8(data) as usize;
+        let mut params = take(data, len);
+        match codec {
+            EXTERNAL => Encoding::External(read_itf8(&mut params)),
+            BYTE_ARRAY_STOP => {
+                let stop = take(&mut params, 1)[0];
+                Encoding::ByteArrayStop(stop, read_itf8(&mut params))
+            }
+            BYTE_ARRAY_LEN => Encoding::ByteArrayLen(
+                Box::new(read_encoding(&mut params)),
+                Box::new(read_encoding(&mut params)),
+            ),
+            _ => panic!("Unexpected codec {}", codec),
+        }
+    }
+
+    /// The content type, content ID and uncompressed data of a block
+    fn read_block(data: &mut &[u8]) -> Result<(u8, i32, Vec<u8>)> {
+        let start = *data;
+        let method = take(data, 1)[0];
+        let content_type = take(data, 1)[0];
+        let content_id = read_itf8(data);
+        let size = read_itf8(data) as usize;
+        let raw_size = read_itf8(data) as usize;
+        let payload = take(data, size);
+        let crc_end = start.len() - data.len();
+        let crc = u32::from_le_bytes(take(data, 4).try_into()?);
+        assert_eq!(crc, crc32(&start[.. crc_end]));
+        let raw = match method {
+            0 => payload.to_vec(),
+            1 => {
+                let mut raw = Vec::new();
+                flate2::read::MultiGzDecoder::new(payload).read_to_end(&mut raw)?;
+                raw
+            }
+            _ => panic!("Unexpected block method {}", method),
+        };
+        assert_eq!(raw.len(), raw_size);
+        Ok((content_type, content_id, raw))
+    }
+
+    /// Decode the reads of a CRAM file written by [`CramWriter`], as a reader
+    /// would: from the encodings of the compression header, and the blocks
+    /// listed by the slice header
+    fn decode_cram(mut data: &[u8]) -> Result<Vec<DecodedRead>> {
+        assert_eq!(take(&mut data, 6), b"CRAM\x03\x00");
+        take(&mut data, 20);
+        let mut reads = Vec::new();
+        while !data.is_empty() {
+            // The CRC of the container header is checked by `test_cram_writer`
+            let header_len = container_header_len(data) + 4;
+            let mut header = take(&mut data, header_len);
+            let len = i32::from_le_bytes(take(&mut header, 4).try_into()?) as usize;
+            read_itf8(&mut header);
+            read_itf8(&mut header);
+            read_itf8(&mut header);
+            let records = read_itf8(&mut header);
+            let mut blocks = take(&mut data, len);
+            let (content_type, _, compression) = read_block(&mut blocks)?;
+            if records == 0 {
+                continue;
+            }
+            assert_eq!(content_type, COMPRESSION_HEADER);
+
+            // The compression header: tag dictionary, data series and tags
+            let mut compression = compression.as_slice();
+            let mut map = take_map(&mut compression);
+            let mut dictionary = Vec::new();
+            for _ in 0 .. read_itf8(&mut map) {
+                let key = take(&mut map, 2);
+                match key {
+                    b"RN" => assert_eq!(take(&mut map, 1), [1]),
+                    b"AP" | b"RR" => assert_eq!(take(&mut map, 1), [0]),
+                    b"SM" => drop(take(&mut map, 5)),
+                    b"TD" => {
+                        let len = read_itf8(&mut map) as usize;
+                        dictionary = take(&mut map, len)
+                            .split(|b| *b == 0)
+                            .map(|line| line.chunks(3).map(|tag| tag.to_vec()).collect())
+                            .collect::<Vec<Vec<Vec<u8>>>>();
+                    }
+                    _ => panic!("Unexpected preservation key {:?}", key),
+                }
+            }
+            let mut map = take_map(&mut compression);
+            let mut series = HashMap::new();
+            for _ in 0 .. read_itf8(&mut map) {
+                let key = take(&mut map, 2).to_vec();
+                series.insert(key, read_encoding(&mut map));
+            }
+            let mut map = take_map(&mut compression);
+            let mut tags = HashMap::new();
+            for _ in 0 .. read_itf8(&mut map) {
+                let key = read_itf8(&mut map);
+                let tag = vec![(key >> 16) as u8, (key >> 8) as u8, key as u8];
+                tags.insert(tag, read_encoding(&mut map));
+            }
+
+            // The slice header, and the blocks it lists
+            let (content_type, _, slice) = read_block(&mut blocks)?;
+            assert_eq!(content_type, SLICE_HEADER);
+            let mut slice = slice.as_slice();
+            assert_eq!(read_itf8(&mut slice), -1);
+            read_itf8(&mut slice);
+            read_itf8(&mut slice);
+            assert_eq!(read_itf8(&mut slice), records);
+            read_ltf8(&mut slice);
+            let n_blocks = read_itf8(&mut slice);
+            let mut external = HashMap::new();
+            for _ in 0 .. n_blocks {
+                let (content_type, content_id, data) = read_block(&mut blocks)?;
+                if content_type == EXTERNAL_DATA {
+                    external.insert(content_id, data);
+                }
+            }
+            assert!(blocks.is_empty());
+            let mut cursors = external
+                .iter()
+                .map(|(id, data)| (*id, data.as_slice()))
+                .collect::<HashMap<i32, &[u8]>>();
+
+            fn int(cursors: &mut HashMap<i32, &[u8]>, encoding: &Encoding) -> i32 {
+                match encoding {
+                    Encoding::External(id) => read_itf8(cursors.get_mut(id).unwrap()),
+                    _ => panic!("Unexpected integer encoding {:?}", encoding),
+                }
+            }
+            fn bytes(cursors: &mut HashMap<i32, &[u8]>, encoding: &Encoding) -> Vec<u8> {
+                match encoding {
+                    Encoding::ByteArrayStop(stop, id) => {
+                        let data = cursors.get_mut(id).unwrap();
+                        let len = data.iter().position(|b| b == stop).unwrap();
+                        let value = take(data, len).to_vec();
+                        take(data, 1);
+                        value
+                    }
+                    Encoding::ByteArrayLen(len, value) => {
+                        let len = int(cursors, len) as usize;
+                        match value.as_ref() {
+                            Encoding::External(id) => {
+                                take(cursors.get_mut(id).unwrap(), len).to_vec()
+                            }
+                            _ => panic!("Unexpected value encoding {:?}", value),
+                        }
+                    }
+                    _ => panic!("Unexpected byte array encoding {:?}", encoding),
+                }
+            }
+            let get = |key: &[u8]| &series[key];
+            for _ in 0 .. records {
+                let flag = int(&mut cursors, get(b"BF"));
+                let cram_flag = int(&mut cursors, get(b"CF"));
+                assert_eq!(cram_flag, CF_PRESERVE_QUALITY | CF_DETACHED);
+                let len = int(&mut cursors, get(b"RL")) as usize;
+                int(&mut cursors, get(b"AP"));
+                assert_eq!(int(&mut cursors, get(b"RG")), -1);
+                let name = bytes(&mut cursors, get(b"RN"));
+                int(&mut cursors, get(b"MF"));
+                int(&mut cursors, get(b"NS"));
+                int(&mut cursors, get(b"NP"));
+                int(&mut cursors, get(b"TS"));
+                let line = int(&mut cursors, get(b"TL")) as usize;
+                let mut desc = None;
+                for tag in &dictionary[line] {
+                    let value = bytes(&mut cursors, &tags[tag]);
+                    assert_eq!(tag, &DESCRIPTION_TAG);
+                    let value = value.strip_suffix(&[0]).unwrap();
+                    desc = Some(String::from_utf8(value.to_vec())?);
+                }
+                let bases = (0 .. len)
+                    .map(|_| take(cursors.get_mut(&(Series::Ba as i32)).unwrap(), 1)[0])
+                    .collect::<Vec<_>>();
+                let quals = take(cursors.get_mut(&(Series::Qs as i32)).unwrap(), len)
+                    .iter()
+                    .map(|q| q + 33)
+                    .collect::<Vec<_>>();
+                reads.push((
+                    flag,
+                    String::from_utf8(name)?,
+                    desc,
+                    String::from_utf8(bases)?,
+                    String::from_utf8(quals)?,
+                ));
+            }
+            // Every byte of the external blocks is decoded
+            assert!(cursors.values().all(|data| data.is_empty()));
+        }
+        Ok(reads)
+    }
+
+    /// The length of a container header up to its CRC
+    fn container_header_len(header: &[u8]) -> usize {
+        let itf8_len = |byte: u8| byte.leading_ones().min(4) as usize + 1;
+        let ltf8_len = |byte: u8| byte.leading_ones() as usize + 1;
+        let mut pos = 4;
+        for _ in 0 .. 4 {
+            pos += itf8_len(header[pos]);
+        }
+        pos += ltf8_len(header[pos]);
+        pos += ltf8_len(header[pos]);
+        pos += itf8_len(header[pos]);
+        let landmarks = header[pos] as usize;
+        pos += 1;
+        for _ in 0 .. landmarks {
+            pos += itf8_len(header[pos]);
+        }
+        pos
+    }
+}
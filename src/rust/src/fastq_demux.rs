@@ -0,0 +1,371 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use extendr_api::prelude::*;
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::error::RError;
+use crate::fastq_record::FastqRecord;
+use crate::multi_writer::{route_reads, MultiWriter};
+use crate::utils::*;
+
+/// Output of the reads matching no sample, or several of them
+const UNDETERMINED: &str = "Undetermined";
+
+#[extendr]
+fn fastq_demux(
+    fq1: &str,
+    fq2: Option<&str>,
+    index1: &str,
+    index2: Option<&str>,
+    samples: Vec<String>,
+    index1_seqs: Vec<String>,
+    index2_seqs: Option<Vec<String>>,
+    mismatches: usize,
+    odir: &str,
+    compression_level: Option<i32>,
+    batch_size: usize,
+    chunk_bytes: usize,
+    writers: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> std::result::Result<List, RError> {
+    let sheet =
+        SampleSheet::new(samples, index1_seqs, index2_seqs, mismatches).map_err(RError::from)?;
+    demultiplex(
+        fq1,
+        fq2,
+        index1,
+        index2,
+        &sheet,
+        odir,
+        compression_level,
+        batch_size,
+        chunk_bytes,
+        writers.max(1),
+        nqueue,
+        threads.max(1),
+    )
+    .map(|counts| {
+        let (samples, reads): (Vec<_>, Vec<_>) = counts.into_iter().unzip();
+        list!(
+            sample = u8_to_list_rstr(samples.into_iter().map(|x| x.to_vec()).collect()),
+            reads = reads.into_iter().map(|x| x as f64).collect::<Vec<_>>()
+        )
+    })
+    .map_err(RError::from)
+}
+
+/// Index sequences of the samples, with every sequence within `mismatches`
+/// of an index mapped to the samples carrying it
+struct SampleSheet {
+    samples: Vec<Bytes>,
+    index1: IndexTable,
+    index2: Option<IndexTable>,
+}
+
+struct IndexTable {
+    length: usize,
+    variants: HashMap<Vec<u8>, Vec<usize>>,
+}
+
+impl SampleSheet {
+    fn new(
+        samples: Vec<String>,
+        index1_seqs: Vec<String>,
+        index2_seqs: Option<Vec<String>>,
+        mismatches: usize,
+    ) -> Result<Self> {
+        if mismatches > 1 {
+            return Err(anyhow!("Only up to 1 index mismatch is supported"));
+        }
+        let index1 = IndexTable::new(&index1_seqs, mismatches)?;
+        let index2 = index2_seqs
+            .as_ref()
+            .map(|seqs| IndexTable::new(seqs, mismatches))
+            .transpose()?;
+        // Two samples whose indexes are all within twice the tolerance could
+        // both match a read: refuse such sheets rather than guessing
+        for i in 0 .. samples.len() {
+            for j in (i + 1) .. samples.len() {
+                let collide = hamming(&index1_seqs[i], &index1_seqs[j]) <= 2 * mismatches
+                    && index2_seqs
+                        .as_ref()
+                        .is_none_or(|seqs| hamming(&seqs[i], &seqs[j]) <= 2 * mismatches);
+                if collide {
+                    return Err(anyhow!(
+                        "Indexes of samples '{}' and '{}' cannot be told apart with {} mismatch(es)",
+                        samples[i],
+                        samples[j],
+                        mismatches
+                    ));
+                }
+            }
+        }
+        Ok(Self {
+            samples: samples.into_iter().map(Bytes::from).collect(),
+            index1,
+            index2,
+        })
+    }
+
+    /// Find the sample of a read from its index reads
+    fn assign(&self, index1: &[u8], index2: Option<&[u8]>) -> Option<&Bytes> {
+        let candidates1 = self.index1.lookup(index1)?;
+        let sample = match (&self.index2, index2) {
+            (Some(table), Some(index2)) => {
+                let candidates2 = table.lookup(index2)?;
+                let mut shared = candidates1
+                    .iter()
+                    .filter(|sample| candidates2.contains(sample));
+                let sample = shared.next()?;
+                if shared.next().is_some() {
+                    return None;
+                }
+                *sample
+            }
+            _ => match candidates1 {
+                [sample] => *sample,
+                _ => return None,
+            },
+        };
+        Some(&self.samples[sample])
+    }
+}
+
+impl IndexTable {
+    fn new(seqs: &[String], mismatches: usize) -> Result<Self> {
+        let length = seqs.first().map_or(0, |seq| seq.len());
+        let mut variants: HashMap<Vec<u8>, Vec<usize>> = HashMap::default();
+        for (sample, seq) in seqs.iter().enumerate() {
+            let seq = seq.to_ascii_uppercase().into_bytes();
+            if seq.len() != length {
+                return Err(anyhow!("Index sequences must all have the same length"));
+            }
+            if let Some(b) = seq.iter().find(|b| !b"ACGT".contains(b)) {
+                return Err(anyhow!(
+                    "Invalid base '{}' in index sequence {}",
+                    *b as char,
+                    String::from_utf8_lossy(&seq)
+                ));
+            }
+            if mismatches > 0 {
+                for pos in 0 .. seq.len() {
+                    for base in b"ACGTN" {
+                        if *base != seq[pos] {
+                            let mut variant = seq.clone();
+                            variant[pos] = *base;
+                            variants.entry(variant).or_default().push(sample);
+                        }
+                    }
+                }
+            }
+            variants.entry(seq).or_default().push(sample);
+        }
+        Ok(Self { length, variants })
+    }
+
+    /// Index reads longer than the sample indexes are trimmed
+    fn lookup(&self, index: &[u8]) -> Option<&[usize]> {
+        let index = index.get(.. self.length)?;
+        self.variants
+            .get(&index.to_ascii_uppercase())
+            .map(|samples| samples.as_slice())
+    }
+}
+
+fn hamming(a: &str, b: &str) -> usize {
+    a.bytes()
+        .zip(b.bytes())
+        .filter(|(a, b)| !a.eq_ignore_ascii_case(b))
+        .count()
+}
+
+/// Route reads (or read pairs) into per-sample FASTQs in `odir`, based on
+/// their index reads. Returns the number of reads of each sample, sorted by
+/// sample, with unassigned reads counted under "Undetermined".
+fn demultiplex(
+    fq1: &str,
+    fq2: Option<&str>,
+    index1: &str,
+    index2: Option<&str>,
+    sheet: &SampleSheet,
+    odir: &str,
+    compression_level: Option<i32>,
+    batch_size: usize,
+    chunk_bytes: usize,
+    writers: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> Result<Vec<(Bytes, usize)>> {
+    if sheet.index2.is_some() != index2.is_some() {
+        return Err(anyhow!(
+            "Both index reads and index sequences must be given for the second index"
+        ));
+    }
+    let progress = new_progress();
+    let pb1 = progress.add(input_progress_bar(fq1)?);
+    pb1.set_prefix("Reading fastq");
+    pb1.set_style(progress_reader_style()?);
+    let pb2 = progress.add(output_progress_bar());
+    pb2.set_prefix("Writing fastq");
+    pb2.set_style(progress_writer_style()?);
+
+    // Index reads follow the mates, they are only used for routing
+    let mut inputs = vec![fq1];
+    inputs.extend(fq2);
+    let mates = inputs.len();
+    inputs.push(index1);
+    inputs.extend(index2);
+
+    let undetermined = Bytes::from(UNDETERMINED);
+    let route = |records: &[FastqRecord<Bytes>]| -> Result<Option<Bytes>> {
+        let sample = sheet.assign(
+            &records[mates].seq,
+            records.get(mates + 1).map(|record| record.seq.as_ref()),
+        );
+        Ok(Some(sample.unwrap_or(&undetermined).clone()))
+    };
+    let output_name = |sample: &[u8], mate: usize| -> String {
+        let sample = String::from_utf8_lossy(sample);
+        if mates == 2 {
+            format!("{}_R{}.fastq.gz", sample, mate)
+        } else {
+            format!("{}.fastq.gz", sample)
+        }
+    };
+    let (counts, _) = route_reads(
+        &inputs,
+        mates,
+        || MultiWriter::directory(odir, compression_level, chunk_bytes, Some(pb2.clone())),
+        route,
+        output_name,
+        Some(pb1),
+        batch_size,
+        chunk_bytes,
+        writers,
+        nqueue,
+        threads,
+    )?;
+    Ok(counts)
+}
+
+extendr_module! {
+    mod fastq_demux;
+    fn fastq_demux;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn sheet(index2: bool) -> Result<SampleSheet> {
+        SampleSheet::new(
+            vec!["S1".to_string(), "S2".to_string()],
+            vec!["ACGTAC".to_string(), "TTGCAA".to_string()],
+            index2.then(|| vec!["GGGG".to_string(), "GGGG".to_string()]),
+            1,
+        )
+    }
+
+    #[test]
+    fn test_demultiplex() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let fq1 = temp.path().join("reads_R1.fq");
+        let fq2 = temp.path().join("reads_R2.fq");
+        let i1 = temp.path().join("reads_I1.fq");
+        let i2 = temp.path().join("reads_I2.fq");
+        std::fs::write(
+            &fq1,
+            "@r1\nAAAA\n+\nIIII\n@r2\nCCCC\n+\nIIII\n@r3\nGGGG\n+\nIIII\n@r4\nTTTT\n+\nIIII\n",
+        )?;
+        std::fs::write(
+            &fq2,
+            "@r1\nTTTT\n+\nIIII\n@r2\nGGGG\n+\nIIII\n@r3\nCCCC\n+\nIIII\n@r4\nAAAA\n+\nIIII\n",
+        )?;
+        // exact match, one mismatch (and longer index read), two mismatches,
+        // and one N
+        std::fs::write(
+            &i1,
+            "@r1\nACGTAC\n+\nIIIIII\n@r2\nTTGCTAGG\n+\nIIIIIIII\n\
+             @r3\nACGAAA\n+\nIIIIII\n@r4\nACNTAC\n+\nIIIIII\n",
+        )?;
+        std::fs::write(
+            &i2,
+            "@r1\nGGGG\n+\nIIII\n@r2\nGGGC\n+\nIIII\n@r3\nGGGG\n+\nIIII\n@r4\nCCCC\n+\nIIII\n",
+        )?;
+        let odir = temp.path().to_str().unwrap();
+        let counts = demultiplex(
+            fq1.to_str().unwrap(),
+            Some(fq2.to_str().unwrap()),
+            i1.to_str().unwrap(),
+            Some(i2.to_str().unwrap()),
+            &sheet(true)?,
+            odir,
+            Some(4),
+            2,
+            64,
+            2,
+            None,
+            1,
+        )?;
+        assert_eq!(
+            counts,
+            vec![
+                (Bytes::from("S1"), 1),
+                (Bytes::from("S2"), 1),
+                (Bytes::from(UNDETERMINED), 2)
+            ]
+        );
+        let mut out = String::new();
+        new_reader(&temp.path().join("S2_R2.fastq.gz"), BUFFER_SIZE, None)?
+            .read_to_string(&mut out)?;
+        assert_eq!(out, "@r2\nGGGG\n+\nIIII\n");
+
+        // Without the second index, r4 matches S1 with one mismatch
+        let counts = demultiplex(
+            fq1.to_str().unwrap(),
+            None,
+            i1.to_str().unwrap(),
+            None,
+            &sheet(false)?,
+            odir,
+            Some(4),
+            2,
+            64,
+            1,
+            None,
+            1,
+        )?;
+        assert_eq!(
+            counts,
+            vec![
+                (Bytes::from("S1"), 2),
+                (Bytes::from("S2"), 1),
+                (Bytes::from(UNDETERMINED), 1)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample_sheet_collision() {
+        // Two mismatches apart: a read could match both samples
+        assert!(SampleSheet::new(
+            vec!["S1".to_string(), "S2".to_string()],
+            vec!["ACGTAC".to_string(), "ACGTTT".to_string()],
+            None,
+            1,
+        )
+        .is_err());
+        // ... unless the second index tells them apart
+        assert!(SampleSheet::new(
+            vec!["S1".to_string(), "S2".to_string()],
+            vec!["ACGTAC".to_string(), "ACGTTT".to_string()],
+            Some(vec!["AAAAAA".to_string(), "CCCCCC".to_string()]),
+            1,
+        )
+        .is_ok());
+    }
+}
@@ -1,4 +1,5 @@
 use std::io::Read;
+use std::path::Path;
 
 use anyhow::Result;
 use bytes::{Bytes, BytesMut};
@@ -7,24 +8,59 @@ use memchr::memchr2;
 use crate::fastq_record::FastqParseError;
 use crate::fastq_record::FastqRecord;
 use crate::reader::*;
-
-pub(crate) struct FastqReader<R> {
+use crate::utils::{new_reader, BUFFER_SIZE};
+
+/// A reader of FASTQ records.
+///
+/// Records are read with [`FastqReader::read_record()`], or by iterating over
+/// the reader, which yields `Result<FastqRecord<Bytes>>`. Each field of the
+/// returned records shares the buffer it was read into, no bytes are copied;
+/// use [`FastqRecord::as_ref()`] to borrow the fields as slices, or
+/// [`FastqRecord::to_vec_record()`] to copy them out of the buffer.
+///
+/// ```
+/// use mire::fastq_reader::FastqReader;
+///
+/// // Or `FastqReader::from_path("reads.fastq.gz")?`
+/// let reader = FastqReader::new(&b"@r1\nACGTACGT\n+\nIIIIIIII\n@r2\nACG\n+\nIII\n"[..]);
+/// let mut ids = Vec::new();
+/// for record in reader {
+///     let record = record?;
+///     if record.seq.len() >= 5 {
+///         ids.push(String::from_utf8_lossy(&record.id).into_owned());
+///     }
+/// }
+/// assert_eq!(ids, ["r1"]);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct FastqReader<R> {
     reader: LineReader<R>,
 }
 
+impl FastqReader<Box<dyn Read>> {
+    /// Open a FASTQ file, which may be compressed (e.g., gzip) or stored
+    /// remotely, just like the inputs of the R functions.
+    pub fn from_path<P: AsRef<Path> + ?Sized>(path: &P) -> Result<Self> {
+        Ok(Self::with_capacity(
+            BUFFER_SIZE,
+            new_reader(path, BUFFER_SIZE, None)?,
+        ))
+    }
+}
+
 impl<R: Read> FastqReader<R> {
-    #[allow(dead_code)]
-    pub(crate) fn new(reader: R) -> Self {
+    pub fn new(reader: R) -> Self {
         Self::with_capacity(8 * 1024, reader)
     }
 
-    pub(crate) fn with_capacity(capacity: usize, reader: R) -> Self {
+    pub fn with_capacity(capacity: usize, reader: R) -> Self {
         Self {
             reader: LineReader::with_capacity(capacity, reader),
         }
     }
 
-    pub(crate) fn offset(&self) -> usize {
+    /// Number of lines read so far
+    pub fn offset(&self) -> usize {
         self.reader.offset()
     }
 
@@ -33,8 +69,10 @@ impl<R: Read> FastqReader<R> {
         self.reader.read_line()
     }
 
+    /// Read the next record, `None` at the end of the input. Blank lines
+    /// between records are skipped.
     #[inline]
-    pub(crate) fn read_record(&mut self) -> Result<Option<FastqRecord<Bytes>>> {
+    pub fn read_record(&mut self) -> Result<Option<FastqRecord<Bytes>>> {
         let mut header;
         loop {
             if let Some(line) = self.read_line()? {
@@ -165,6 +203,15 @@ impl<R: Read> FastqReader<R> {
     }
 }
 
+impl<R: Read> Iterator for FastqReader<R> {
+    type Item = Result<FastqRecord<Bytes>>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
@@ -242,4 +289,20 @@ mod tests {
         assert!(result.unwrap().is_none());
         Ok(())
     }
+
+    #[test]
+    fn test_iterate_records() -> Result<()> {
+        let fastq_data = "@seq1 description\nATGC\n+\n!!!!\n\n@seq2\nGCG\n+\n$$$\n";
+
+        let records = create_reader(fastq_data).collect::<Result<Vec<_>>>()?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].as_ref().id, b"seq2");
+        assert_eq!(records[1].to_vec_record().seq, b"GCG".to_vec());
+
+        // Iteration stops at the first invalid record
+        let mut reader = create_reader("@seq1\nATGC\n+\n!!!!\nseq2\n");
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_err());
+        Ok(())
+    }
 }
@@ -1,17 +1,22 @@
 use std::io::Write;
 
-#[derive(Debug)]
-pub(crate) struct FastqRecord<T> {
-    pub(crate) id: T,
-    pub(crate) desc: Option<T>,
-    pub(crate) seq: T,
-    pub(crate) sep: T,
-    pub(crate) qual: T,
+/// A FASTQ record, generic over the storage of its fields: `Bytes` when read
+/// by [`FastqReader`](crate::fastq_reader::FastqReader), `&[u8]` when
+/// borrowed and `Vec<u8>` when owned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FastqRecord<T> {
+    /// Sequence ID, without the leading '@'
+    pub id: T,
+    /// Description following the sequence ID, if any
+    pub desc: Option<T>,
+    pub seq: T,
+    /// Separator line, starting with '+'
+    pub sep: T,
+    pub qual: T,
 }
 
 impl<T> FastqRecord<T> {
-    #[allow(dead_code)]
-    pub(crate) fn new(id: T, desc: Option<T>, seq: T, sep: T, qual: T) -> Self {
+    pub fn new(id: T, desc: Option<T>, seq: T, sep: T, qual: T) -> Self {
         Self {
             id,
             desc,
@@ -23,12 +28,11 @@ impl<T> FastqRecord<T> {
 }
 
 impl<T: AsRef<[u8]>> FastqRecord<T> {
-    #[allow(dead_code)]
-    pub(crate) fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+    pub fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
         writer.write_all(&self.as_vec())
     }
 
-    pub(crate) fn bytes_size(&self) -> usize {
+    pub fn bytes_size(&self) -> usize {
         self.id.as_ref().len()
             // extra one for space between id and description
             + self.desc.as_ref().map(|d| d.as_ref().len() + 1).unwrap_or(0) // ' '
@@ -39,7 +43,7 @@ impl<T: AsRef<[u8]>> FastqRecord<T> {
     }
 
     /// Efficiently appends the FASTQ record to the provided Vec<u8>
-    pub(crate) fn extend(&self, buf: &mut Vec<u8>) {
+    pub fn extend(&self, buf: &mut Vec<u8>) {
         buf.push(b'@');
         buf.extend_from_slice(self.id.as_ref());
 
@@ -57,7 +61,7 @@ impl<T: AsRef<[u8]>> FastqRecord<T> {
         buf.push(b'\n');
     }
 
-    pub(crate) fn as_vec(&self) -> Vec<u8> {
+    pub fn as_vec(&self) -> Vec<u8> {
         let id = self.id.as_ref();
         let desc = self.desc.as_ref().map(|d| d.as_ref());
         let seq = self.seq.as_ref();
@@ -82,8 +86,7 @@ impl<T: AsRef<[u8]>> FastqRecord<T> {
         buffer
     }
 
-    #[allow(dead_code)]
-    pub(crate) fn write_buf(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+    pub fn write_buf(&self, buf: &mut [u8]) -> std::io::Result<usize> {
         let mut pos = 0;
 
         // Write '@' and ID
@@ -153,8 +156,8 @@ impl<T: AsRef<[u8]>> FastqRecord<T> {
         Ok(pos)
     }
 
-    #[allow(dead_code)]
-    pub(crate) fn as_ref(&self) -> FastqRecord<&[u8]> {
+    /// Borrow the fields of the record
+    pub fn as_ref(&self) -> FastqRecord<&[u8]> {
         FastqRecord {
             id: self.id.as_ref(),
             desc: self.desc.as_ref().map(|d| d.as_ref()),
@@ -163,6 +166,18 @@ impl<T: AsRef<[u8]>> FastqRecord<T> {
             qual: self.qual.as_ref(),
         }
     }
+
+    /// Copy the fields of the record, which no longer hold on to the buffer
+    /// they were read into
+    pub fn to_vec_record(&self) -> FastqRecord<Vec<u8>> {
+        FastqRecord {
+            id: self.id.as_ref().to_vec(),
+            desc: self.desc.as_ref().map(|d| d.as_ref().to_vec()),
+            seq: self.seq.as_ref().to_vec(),
+            sep: self.sep.as_ref().to_vec(),
+            qual: self.qual.as_ref().to_vec(),
+        }
+    }
 }
 use std::error::Error;
 use std::fmt;
@@ -0,0 +1,695 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use extendr_api::prelude::*;
+use rayon::slice::ParallelSliceMut;
+
+use crate::altrep::{alt_counts, alt_strings};
+use crate::bam_writer::*;
+use crate::error::RError;
+use crate::fastq_reader::FastqReader;
+use crate::fastq_record::FastqRecord;
+use crate::kractor::reads::barcode::BarcodeSource;
+use crate::part_writer::ChunkedWriter;
+use crate::utils::*;
+
+// Bookkeeping of a buffered read, beyond its key and records
+const ENTRY_OVERHEAD: usize = 64;
+// Runs merged at once at most, each holding an open file and a read buffer
+const MAX_MERGE_RUNS: usize = 64;
+// Smallest read buffer of a merged run
+const MIN_MERGE_BUFFER: usize = 64 << 10;
+// The headers of BAM outputs, grouped by barcode or sorted by read ID
+const BAM_HEADER_UNSORTED: &[u8] = b"@HD\tVN:1.6\tSO:unsorted\n";
+const BAM_HEADER_QUERYNAME: &[u8] = b"@HD\tVN:1.6\tSO:queryname\tSS:queryname:lexicographical\n";
+
+#[extendr]
+fn fastq_sort(
+    fq1: &str,
+    ofile1: &str,
+    fq2: Option<&str>,
+    ofile2: Option<&str>,
+    by: &str,
+    barcode: Robj,
+    max_memory: usize,
+    tmpdir: &str,
+    compression_level: Option<i32>,
+    chunk_bytes: usize,
+) -> std::result::Result<List, RError> {
+    let barcode = BarcodeSource::try_from(&barcode).map_err(RError::from)?;
+    let inputs = match fq2 {
+        Some(fq2) => vec![fq1, fq2],
+        None => vec![fq1],
+    };
+    let bam = Path::new(ofile1)
+        .extension()
+        .is_some_and(|x| x.eq_ignore_ascii_case("bam"));
+    let output = match (ofile2, bam) {
+        (Some(_), true) => {
+            return Err(RError::from(crate::error::ErrorKind::Config.error(
+                "'ofile2' must be NULL with a BAM output, which holds both mates",
+            )));
+        }
+        (_, true) if by == "barcode" => SortOutput::Bam {
+            path: Path::new(ofile1),
+            header: BAM_HEADER_UNSORTED,
+            key_tag: Some(*b"CB"),
+        },
+        (_, true) => SortOutput::Bam {
+            path: Path::new(ofile1),
+            header: BAM_HEADER_QUERYNAME,
+            key_tag: None,
+        },
+        (Some(ofile2), false) if fq2.is_some() => {
+            SortOutput::Fastq(vec![Path::new(ofile1), Path::new(ofile2)])
+        }
+        _ => SortOutput::Fastq(vec![Path::new(ofile1)]),
+    };
+    let by_barcode = |records: &[FastqRecord<Bytes>]| -> Result<Vec<u8>> {
+        barcode.barcode(&records[0], records.get(1)).ok_or_else(|| {
+            anyhow!(
+                "(Reader) No barcode in read {}",
+                String::from_utf8_lossy(&records[0].id)
+            )
+        })
+    };
+    let by_id = |records: &[FastqRecord<Bytes>]| -> Result<Vec<u8>> { Ok(records[0].id.to_vec()) };
+    let tmpdir = Path::new(tmpdir);
+    let counts = match by {
+        "barcode" => sort_reads(
+            &inputs,
+            &output,
+            by_barcode,
+            true,
+            max_memory,
+            tmpdir,
+            compression_level,
+            chunk_bytes,
+        ),
+        "id" => sort_reads(
+            &inputs,
+            &output,
+            by_id,
+            false,
+            max_memory,
+            tmpdir,
+            compression_level,
+            chunk_bytes,
+        ),
+        _ => Err(crate::error::ErrorKind::Config.error(format!("Invalid 'by': {}", by))),
+    }
+    .map_err(RError::from)?;
+    let (keys, reads): (Vec<_>, Vec<_>) = counts.into_iter().unzip();
+    Ok(list!(key = alt_strings(keys), reads = alt_counts(reads)))
+}
+
+extendr_module! {
+    mod fastq_sort;
+    fn fastq_sort;
+}
+
+/// The outputs of sorted reads
+pub(crate) enum SortOutput<'a> {
+    // A FASTQ file per mate
+    Fastq(Vec<&'a Path>),
+    // A single unaligned BAM file holding all mates
+    Bam {
+        path: &'a Path,
+        // The header text, giving the sort order
+        header: &'static [u8],
+        // The tag carrying the sort key of each record, e.g. `CB` for
+        // barcodes
+        key_tag: Option<[u8; 2]>,
+    },
+}
+
+impl SortOutput<'_> {
+    /// The records of a read (or read pair) as written to the output: each
+    /// mate as FASTQ, or as a length-prefixed BAM record
+    fn encode(&self, key: &[u8], records: &[FastqRecord<Bytes>]) -> Result<Vec<Vec<u8>>> {
+        let Self::Bam { key_tag, .. } = self else {
+            return Ok(records.iter().map(|x| x.as_vec()).collect());
+        };
+        let mut encoded = Vec::with_capacity(records.len());
+        for (mate, record) in records.iter().enumerate() {
+            let flag = match (records.len(), mate) {
+                (1, _) => 0,
+                (_, 0) => FLAG_PAIRED | FLAG_FIRST,
+                _ => FLAG_PAIRED | FLAG_LAST,
+            };
+            // The two-letter tags of the `MIRE{}` annotation are SAM tags
+            let mut tags: Vec<(&[u8; 2], &[u8])> = record
+                .desc
+                .as_ref()
+                .map(|desc| mire_tags(desc))
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|(tag, value)| Some((<&[u8; 2]>::try_from(tag).ok()?, value)))
+                .filter(|(tag, _)| Some(**tag) != *key_tag)
+                .collect();
+            if let Some(key_tag) = key_tag {
+                tags.push((key_tag, key));
+            }
+            let bam = unaligned_bam_record(&record.id, flag, &record.seq, &record.qual, &tags)?;
+            let mut entry = Vec::with_capacity(bam.len() + 4);
+            write_bam_record(&mut entry, &bam)?;
+            encoded.push(entry);
+        }
+        Ok(encoded)
+    }
+
+    fn writer(&self, compression_level: Option<i32>, chunk_bytes: usize) -> Result<SortWriter<'_>> {
+        match self {
+            Self::Fastq(paths) => Ok(SortWriter::Fastq(
+                paths
+                    .iter()
+                    .map(|path| {
+                        Ok(ChunkedWriter::new(
+                            path,
+                            Compression::new(path, compression_level)?,
+                            chunk_bytes,
+                        ))
+                    })
+                    .collect::<Result<_>>()?,
+            )),
+            Self::Bam { path, header, .. } => {
+                let compression = Compression::with_format(ChunkFormat::Gzip, compression_level)?;
+                let mut writer = BgzfWriter::new(new_writer(path, None)?, compression.compressor());
+                write_bam_header(&mut writer, header, &[])
+                    .context("(Writer) Failed to write BAM header")?;
+                Ok(SortWriter::Bam(writer))
+            }
+        }
+    }
+}
+
+/// Writes the sorted reads into their outputs
+enum SortWriter<'a> {
+    Fastq(Vec<ChunkedWriter<'a>>),
+    Bam(BgzfWriter<Box<dyn CompleteWrite>>),
+}
+
+impl SortWriter<'_> {
+    fn write(&mut self, records: &[Vec<u8>]) -> Result<()> {
+        match self {
+            Self::Fastq(outputs) => {
+                for (output, record) in outputs.iter_mut().zip(records) {
+                    output.write(record)?;
+                }
+                Ok(())
+            }
+            Self::Bam(writer) => records
+                .iter()
+                .try_for_each(|record| writer.write_all(record))
+                .context("(Writer) Failed to write BAM records"),
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Self::Fastq(outputs) => outputs.into_iter().try_for_each(|x| x.finish()),
+            Self::Bam(mut writer) => writer.finish().context("(Writer) Failed to flush writer"),
+        }
+    }
+}
+
+/// A read (or read pair) being sorted: its sort key, and its records as
+/// written to the outputs
+struct SortEntry {
+    key: Vec<u8>,
+    records: Vec<Vec<u8>>,
+}
+
+impl SortEntry {
+    fn size(&self) -> usize {
+        self.key.len() + self.records.iter().map(|x| x.len()).sum::<usize>() + ENTRY_OVERHEAD
+    }
+}
+
+type SortSource<'a> = Box<dyn Iterator<Item = Result<SortEntry>> + 'a>;
+
+/// Sort reads by a key with an external merge sort, so inputs larger than
+/// memory can be sorted: reads are buffered up to `max_memory` bytes, sorted
+/// and spilled as runs into temporary files of `tmpdir`, then the runs are
+/// merged into the output, at most [`MAX_MERGE_RUNS`] at once, in as many
+/// passes as needed. Reads with the same key keep their input order. Returns
+/// the number of reads (or read pairs) of each key, in sorted order, or
+/// without `group_counts` (e.g. for unique keys) the total number of reads,
+/// under an empty key.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn sort_reads<K>(
+    inputs: &[&str],
+    output: &SortOutput,
+    key: K,
+    group_counts: bool,
+    max_memory: usize,
+    tmpdir: &Path,
+    compression_level: Option<i32>,
+    chunk_bytes: usize,
+) -> Result<Vec<(Vec<u8>, usize)>>
+where
+    K: Fn(&[FastqRecord<Bytes>]) -> Result<Vec<u8>>,
+{
+    // Each output is checked against its own format before any read is sorted
+    match output {
+        SortOutput::Fastq(paths) => {
+            for path in paths {
+                Compression::new(path, compression_level)?;
+            }
+        }
+        SortOutput::Bam { .. } => {
+            Compression::with_format(ChunkFormat::Gzip, compression_level)?;
+        }
+    }
+    let mut readers = inputs
+        .iter()
+        .map(FastqReader::from_path)
+        .collect::<Result<Vec<_>>>()?;
+    let mates = inputs.len();
+    let mut runs = SpillRuns::new(tmpdir);
+    let mut entries: Vec<SortEntry> = Vec::new();
+    let mut buffered = 0;
+    while let Some(records) = read_next(&mut readers)? {
+        let key = key(&records)?;
+        let records = output.encode(&key, &records)?;
+        let entry = SortEntry { key, records };
+        buffered += entry.size();
+        entries.push(entry);
+        if buffered >= max_memory {
+            runs.spill(&mut entries)?;
+            buffered = 0;
+        }
+    }
+    // Without spilled runs, the reads are sorted in memory only
+    let sources: Vec<SortSource> = if runs.runs.is_empty() {
+        entries.par_sort_by(|a, b| a.key.cmp(&b.key));
+        vec![Box::new(entries.into_iter().map(Ok))]
+    } else {
+        if !entries.is_empty() {
+            runs.spill(&mut entries)?;
+        }
+        drop(entries);
+        // The read buffers of the merged runs share `max_memory`
+        let fan_in = (max_memory / MIN_MERGE_BUFFER).clamp(2, MAX_MERGE_RUNS);
+        let buffer_size = (max_memory / fan_in).clamp(MIN_MERGE_BUFFER, BUFFER_SIZE);
+        while runs.runs.len() > fan_in {
+            runs.merge_pass(fan_in, mates, buffer_size)?;
+        }
+        runs.runs
+            .iter()
+            .map(|path| -> Result<SortSource> {
+                Ok(Box::new(RunReader::open(path, mates, buffer_size)?))
+            })
+            .collect::<Result<_>>()?
+    };
+
+    let mut writer = output.writer(compression_level, chunk_bytes)?;
+    let mut counts: Vec<(Vec<u8>, usize)> = Vec::new();
+    merge_runs(sources, |entry| {
+        writer.write(&entry.records)?;
+        match counts.last_mut() {
+            Some((last, count)) if !group_counts || *last == entry.key => *count += 1,
+            _ if !group_counts => counts.push((Vec::new(), 1)),
+            _ => counts.push((entry.key, 1)),
+        }
+        Ok(())
+    })?;
+    writer.finish()?;
+    Ok(counts)
+}
+
+/// Read the next record of each input, `None` once all inputs are exhausted
+fn read_next<R: Read>(readers: &mut [FastqReader<R>]) -> Result<Option<Vec<FastqRecord<Bytes>>>> {
+    let records = readers
+        .iter_mut()
+        .map(|reader| reader.read_record())
+        .collect::<Result<Vec<_>>>()
+        .with_context(|| format!("(Reader) Failed to read FASTQ records"))?;
+    if records.iter().all(|x| x.is_none()) {
+        return Ok(None);
+    }
+    let records = records
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| {
+            crate::error::ErrorKind::Pairing
+                .error("(Reader) FASTQ pairing error: inputs have different numbers of reads")
+        })?;
+    if records.iter().any(|x| x.id != records[0].id) {
+        return Err(crate::error::ErrorKind::Pairing.error(format!(
+            "(Reader) FASTQ pairing error: mismatched read IDs {}",
+            String::from_utf8_lossy(&records[0].id)
+        )));
+    }
+    Ok(Some(records))
+}
+
+/// Merge sorted runs, handing each read over to `emit` in order. Reads with
+/// the same key are taken from the earliest run first, which keeps the sort
+/// stable.
+fn merge_runs<F>(mut sources: Vec<SortSource>, mut emit: F) -> Result<()>
+where
+    F: FnMut(SortEntry) -> Result<()>,
+{
+    let mut heads: Vec<Option<SortEntry>> = Vec::with_capacity(sources.len());
+    let mut heap = BinaryHeap::with_capacity(sources.len());
+    for (run, source) in sources.iter_mut().enumerate() {
+        let head = source.next().transpose()?;
+        if let Some(entry) = &head {
+            heap.push(Reverse((entry.key.clone(), run)));
+        }
+        heads.push(head);
+    }
+    while let Some(Reverse((_, run))) = heap.pop() {
+        // Safety: every run in the heap has a head
+        let entry = heads[run].take().unwrap();
+        emit(entry)?;
+        if let Some(next) = sources[run].next().transpose()? {
+            heap.push(Reverse((next.key.clone(), run)));
+            heads[run] = Some(next);
+        }
+    }
+    Ok(())
+}
+
+/// Sorted runs spilled into temporary files, removed once dropped
+struct SpillRuns<'a> {
+    tmpdir: &'a Path,
+    // The runs left to merge, in input order
+    runs: Vec<PathBuf>,
+    // Every file created, including the runs already merged
+    created: Vec<PathBuf>,
+}
+
+impl<'a> SpillRuns<'a> {
+    fn new(tmpdir: &'a Path) -> Self {
+        Self {
+            tmpdir,
+            runs: Vec::new(),
+            created: Vec::new(),
+        }
+    }
+
+    /// Create the file of a new run
+    fn create(&mut self) -> Result<RunWriter> {
+        let path = self.tmpdir.join(format!(
+            "mire-sort-{}-{}.run",
+            std::process::id(),
+            self.created.len()
+        ));
+        let file = File::create(&path)
+            .with_context(|| format!("Failed to create spill file {}", path.display()))?;
+        self.created.push(path.clone());
+        Ok(RunWriter {
+            writer: BufWriter::with_capacity(BUFFER_SIZE, file),
+            path,
+        })
+    }
+
+    /// Sort the buffered reads and write them into a new run
+    fn spill(&mut self, entries: &mut Vec<SortEntry>) -> Result<()> {
+        entries.par_sort_by(|a, b| a.key.cmp(&b.key));
+        let mut writer = self.create()?;
+        for entry in entries.drain(..) {
+            writer.write_entry(&entry)?;
+        }
+        self.runs.push(writer.finish()?);
+        Ok(())
+    }
+
+    /// Merge each group of `fan_in` consecutive runs into a new run, which
+    /// keeps the runs in input order
+    fn merge_pass(&mut self, fan_in: usize, mates: usize, buffer_size: usize) -> Result<()> {
+        let runs = std::mem::take(&mut self.runs);
+        for group in runs.chunks(fan_in) {
+            if let [run] = group {
+                self.runs.push(run.clone());
+                continue;
+            }
+            let sources = group
+                .iter()
+                .map(|path| -> Result<SortSource> {
+                    Ok(Box::new(RunReader::open(path, mates, buffer_size)?))
+                })
+                .collect::<Result<_>>()?;
+            let mut writer = self.create()?;
+            merge_runs(sources, |entry| writer.write_entry(&entry))?;
+            self.runs.push(writer.finish()?);
+            for path in group {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SpillRuns<'_> {
+    fn drop(&mut self) {
+        for path in &self.created {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Writes the reads of a run, each read as its length-prefixed key and
+/// records
+struct RunWriter {
+    writer: BufWriter<File>,
+    path: PathBuf,
+}
+
+impl RunWriter {
+    fn write_entry(&mut self, entry: &SortEntry) -> Result<()> {
+        std::iter::once(&entry.key)
+            .chain(&entry.records)
+            .try_for_each(|field| {
+                self.writer.write_all(&(field.len() as u64).to_le_bytes())?;
+                self.writer.write_all(field)
+            })
+            .with_context(|| format!("Failed to write spill file {}", self.path.display()))
+    }
+
+    /// Flush the run, returning its path
+    fn finish(mut self) -> Result<PathBuf> {
+        self.writer
+            .flush()
+            .with_context(|| format!("Failed to flush spill file {}", self.path.display()))?;
+        Ok(self.path)
+    }
+}
+
+/// Reads back the reads of a spilled run
+struct RunReader {
+    path: PathBuf,
+    reader: BufReader<File>,
+    mates: usize,
+}
+
+impl RunReader {
+    fn open(path: &Path, mates: usize, buffer_size: usize) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open spill file {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            reader: BufReader::with_capacity(buffer_size, file),
+            mates,
+        })
+    }
+
+    fn read_field(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        let mut len = [0; 8];
+        match self.reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut field = vec![0; u64::from_le_bytes(len) as usize];
+        self.reader.read_exact(&mut field)?;
+        Ok(Some(field))
+    }
+
+    fn read_entry(&mut self) -> std::io::Result<Option<SortEntry>> {
+        let Some(key) = self.read_field()? else {
+            return Ok(None);
+        };
+        let mut records = Vec::with_capacity(self.mates);
+        for _ in 0 .. self.mates {
+            let record = self
+                .read_field()?
+                .ok_or_else(|| std::io::Error::from(ErrorKind::UnexpectedEof))?;
+            records.push(record);
+        }
+        Ok(Some(SortEntry { key, records }))
+    }
+}
+
+impl Iterator for RunReader {
+    type Item = Result<SortEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_entry()
+            .with_context(|| format!("Failed to read spill file {}", self.path.display()))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sort_reads() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let fq1 = temp.path().join("reads_1.fq");
+        let fq2 = temp.path().join("reads_2.fq");
+        std::fs::write(
+            &fq1,
+            "@r1 MIRE{CB:CCCA}\nACGT\n+\nIIII\n\
+             @r2 MIRE{CB:AAAC}\nGGGG\n+\nIIII\n\
+             @r3 MIRE{CB:CCCA}\nTTTT\n+\nIIII\n\
+             @r4 MIRE{CB:AAAC}\nCCCC\n+\nIIII\n",
+        )?;
+        std::fs::write(
+            &fq2,
+            "@r1\nAA\n+\nII\n@r2\nCC\n+\nII\n@r3\nGG\n+\nII\n@r4\nTT\n+\nII\n",
+        )?;
+        let (out1, out2) = (temp.path().join("out_1.fq"), temp.path().join("out_2.fq"));
+        let barcode = BarcodeSource::Tag(b"CB".to_vec());
+        let key = |records: &[FastqRecord<Bytes>]| -> Result<Vec<u8>> {
+            barcode
+                .barcode(&records[0], records.get(1))
+                .ok_or_else(|| anyhow!("No barcode"))
+        };
+        // Spill every read into its own run, merged two at a time, or keep them
+        // all in memory
+        for max_memory in [1, usize::MAX] {
+            let counts = sort_reads(
+                &[fq1.to_str().unwrap(), fq2.to_str().unwrap()],
+                &SortOutput::Fastq(vec![&out1, &out2]),
+                key,
+                true,
+                max_memory,
+                temp.path(),
+                Some(4),
+                16,
+            )?;
+            assert_eq!(counts, vec![(b"AAAC".to_vec(), 2), (b"CCCA".to_vec(), 2)]);
+            assert_eq!(
+                std::fs::read_to_string(&out2)?,
+                "@r2\nCC\n+\nII\n@r4\nTT\n+\nII\n@r1\nAA\n+\nII\n@r3\nGG\n+\nII\n"
+            );
+            assert!(std::fs::read_to_string(&out1)?.starts_with("@r2 MIRE{CB:AAAC}\n"));
+        }
+        // Spill files are removed
+        let runs = std::fs::read_dir(temp.path())?
+            .filter(|x| {
+                x.as_ref()
+                    .is_ok_and(|x| x.path().extension() == Some("run".as_ref()))
+            })
+            .count();
+        assert_eq!(runs, 0);
+
+        // IDs are sorted by bytes, and only the total is counted
+        std::fs::write(&fq1, "@r2\nA\n+\nI\n@r10\nC\n+\nI\n@r1\nG\n+\nI\n")?;
+        let by_id = |records: &[FastqRecord<Bytes>]| Ok(records[0].id.to_vec());
+        let counts = sort_reads(
+            &[fq1.to_str().unwrap()],
+            &SortOutput::Fastq(vec![&out1]),
+            by_id,
+            false,
+            1,
+            temp.path(),
+            Some(4),
+            16,
+        )?;
+        assert_eq!(counts, vec![(Vec::new(), 3)]);
+        assert_eq!(
+            std::fs::read_to_string(&out1)?,
+            "@r1\nG\n+\nI\n@r10\nC\n+\nI\n@r2\nA\n+\nI\n"
+        );
+
+        std::fs::write(&fq2, "@r1\nAA\n+\nII\n")?;
+        assert!(sort_reads(
+            &[fq1.to_str().unwrap(), fq2.to_str().unwrap()],
+            &SortOutput::Fastq(vec![&out1, &out2]),
+            key,
+            true,
+            1,
+            temp.path(),
+            Some(4),
+            16
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sort_reads_bam() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let fq1 = temp.path().join("reads_1.fq");
+        let fq2 = temp.path().join("reads_2.fq");
+        std::fs::write(
+            &fq1,
+            "@r1 MIRE{CB:CCCA:UB:TTTT}\nACGN\n+\nIIII\n@r2 MIRE{CB:AAAC}\nGGG\n+\nIII\n",
+        )?;
+        std::fs::write(&fq2, "@r1\nAA\n+\nII\n@r2\nCC\n+\nII\n")?;
+        let out = temp.path().join("sorted.bam");
+        let barcode = BarcodeSource::Tag(b"CB".to_vec());
+        let key = |records: &[FastqRecord<Bytes>]| -> Result<Vec<u8>> {
+            barcode
+                .barcode(&records[0], records.get(1))
+                .ok_or_else(|| anyhow!("No barcode"))
+        };
+        let output = SortOutput::Bam {
+            path: &out,
+            header: BAM_HEADER_UNSORTED,
+            key_tag: Some(*b"CB"),
+        };
+        for max_memory in [1, usize::MAX] {
+            let counts = sort_reads(
+                &[fq1.to_str().unwrap(), fq2.to_str().unwrap()],
+                &output,
+                key,
+                true,
+                max_memory,
+                temp.path(),
+                None,
+                16,
+            )?;
+            assert_eq!(counts, vec![(b"AAAC".to_vec(), 1), (b"CCCA".to_vec(), 1)]);
+
+            let mut bam = Vec::new();
+            flate2::read::MultiGzDecoder::new(File::open(&out)?).read_to_end(&mut bam)?;
+            assert!(bam.starts_with(BAM_MAGIC));
+            let text_len = i32::from_le_bytes(bam[4 .. 8].try_into()?) as usize;
+            assert_eq!(&bam[8 .. 8 + text_len], BAM_HEADER_UNSORTED);
+            let mut records = Vec::new();
+            // Without references, the records follow the reference count
+            let mut rest = &bam[12 + text_len ..];
+            while !rest.is_empty() {
+                let size = i32::from_le_bytes(rest[.. 4].try_into()?) as usize;
+                records.push(&rest[4 .. 4 + size]);
+                rest = &rest[4 + size ..];
+            }
+            assert_eq!(records.len(), 4);
+            let flag = |record: &[u8]| u16::from_le_bytes([record[14], record[15]]);
+            assert_eq!(
+                records.iter().map(|x| flag(x)).collect::<Vec<_>>(),
+                [77, 141, 77, 141]
+            );
+            // r1, mate 1: name, 4-bit bases, qualities and tags
+            let record = records[2];
+            assert_eq!(&record[32 .. 35], b"r1\0");
+            assert_eq!(&record[35 .. 37], [0x12, 0x4f]);
+            assert_eq!(&record[37 .. 41], [40; 4]);
+            assert_eq!(&record[41 ..], b"UBZTTTT\0CBZCCCA\0");
+            assert_eq!(&records[0][32 .. 35], b"r2\0");
+        }
+        Ok(())
+    }
+}
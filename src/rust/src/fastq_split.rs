@@ -0,0 +1,224 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use extendr_api::prelude::*;
+
+use crate::error::RError;
+use crate::fastq_record::FastqRecord;
+use crate::multi_writer::{route_reads, MultiWriter};
+use crate::utils::*;
+
+#[extendr]
+fn fastq_split(
+    fq1: &str,
+    fq2: Option<&str>,
+    barcode_tag: &str,
+    odir: &str,
+    archive: Option<&str>,
+    compression_level: Option<i32>,
+    batch_size: usize,
+    chunk_bytes: usize,
+    writers: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> std::result::Result<List, RError> {
+    split_by_barcode(
+        fq1,
+        fq2,
+        barcode_tag,
+        odir,
+        archive,
+        compression_level,
+        batch_size,
+        chunk_bytes,
+        writers.max(1),
+        nqueue,
+        threads.max(1),
+    )
+    .map(|counts| {
+        let (barcodes, reads): (Vec<_>, Vec<_>) = counts.into_iter().unzip();
+        list!(
+            barcode = u8_to_list_rstr(barcodes.into_iter().map(|x| x.to_vec()).collect()),
+            reads = reads.into_iter().map(|x| x as f64).collect::<Vec<_>>()
+        )
+    })
+    .map_err(RError::from)
+}
+
+/// Split reads into one FASTQ per cell barcode, taken from the `MIRE{}`
+/// annotation embedded by `seq_refine()`. Outputs are written into `odir`, or
+/// as members of a single tar archive. Returns the number of reads (or read
+/// pairs) of each barcode, sorted by barcode.
+#[allow(clippy::too_many_arguments)]
+fn split_by_barcode(
+    fq1: &str,
+    fq2: Option<&str>,
+    barcode_tag: &str,
+    odir: &str,
+    archive: Option<&str>,
+    compression_level: Option<i32>,
+    batch_size: usize,
+    chunk_bytes: usize,
+    writers: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> Result<Vec<(Bytes, usize)>> {
+    let progress = new_progress();
+    let pb1 = progress.add(input_progress_bar(fq1)?);
+    pb1.set_prefix("Reading fastq");
+    pb1.set_style(progress_reader_style()?);
+    let pb2 = progress.add(output_progress_bar());
+    pb2.set_prefix("Writing fastq");
+    pb2.set_style(progress_writer_style()?);
+
+    // A compressed archive is compressed as a whole, its members are not
+    let archive = archive.map(|archive| Path::new(odir).join(archive));
+    let extension = match &archive {
+        Some(path) if gz_compressed(path) || path.extension().is_some_and(|x| x == "tgz") => {
+            "fastq"
+        }
+        _ => "fastq.gz",
+    };
+    let paired = fq2.is_some();
+    let output_name = |barcode: &[u8], mate: usize| -> String {
+        let barcode = String::from_utf8_lossy(barcode);
+        if paired {
+            format!("{}_R{}.{}", barcode, mate, extension)
+        } else {
+            format!("{}.{}", barcode, extension)
+        }
+    };
+    let route = |records: &[FastqRecord<Bytes>]| -> Result<Option<Bytes>> {
+        // The barcode is taken from the first mate carrying it
+        let barcode = records
+            .iter()
+            .find_map(|record| record_barcode(record, barcode_tag))
+            .ok_or_else(|| {
+                anyhow!(
+                    "(Parser) No '{}' tag in read {}",
+                    barcode_tag,
+                    String::from_utf8_lossy(&records[0].id)
+                )
+            })?;
+        check_barcode(&barcode)?;
+        Ok(Some(barcode))
+    };
+    let inputs = match fq2 {
+        Some(fq2) => vec![fq1, fq2],
+        None => vec![fq1],
+    };
+    let (counts, _) = route_reads(
+        &inputs,
+        inputs.len(),
+        || match &archive {
+            Some(path) => MultiWriter::tar(path, compression_level, chunk_bytes, Some(pb2.clone())),
+            None => MultiWriter::directory(odir, compression_level, chunk_bytes, Some(pb2.clone())),
+        },
+        route,
+        output_name,
+        Some(pb1),
+        batch_size,
+        chunk_bytes,
+        // The members of an archive are all written by one thread
+        if archive.is_some() { 1 } else { writers },
+        nqueue,
+        threads,
+    )?;
+    Ok(counts)
+}
+
+fn record_barcode(record: &FastqRecord<Bytes>, barcode_tag: &str) -> Option<Bytes> {
+    let desc = record.desc.as_ref()?;
+    mire_tag(desc, barcode_tag.as_bytes()).map(|barcode| desc.slice_ref(barcode))
+}
+
+/// Barcodes become file names, reject any which could escape the output
+/// directory or archive
+fn check_barcode(barcode: &[u8]) -> Result<()> {
+    if barcode.is_empty()
+        || barcode[0] == b'.'
+        || !barcode
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+    {
+        return Err(anyhow!(
+            "(Parser) Invalid barcode '{}'",
+            String::from_utf8_lossy(barcode)
+        ));
+    }
+    Ok(())
+}
+
+extendr_module! {
+    mod fastq_split;
+    fn fastq_split;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn test_split_by_barcode() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let fq1 = temp.path().join("reads_1.fq");
+        let fq2 = temp.path().join("reads_2.fq");
+        std::fs::write(
+            &fq1,
+            "@r1 MIRE{CB:AAAC:UB:TTTT}\nACGT\n+\nIIII\n\
+             @r2 MIRE{CB:CCCA:UB:GGGG}\nGGGG\n+\nIIII\n\
+             @r3 MIRE{CB:AAAC:UB:CCCC}\nTTTT\n+\nIIII\n",
+        )?;
+        std::fs::write(
+            &fq2,
+            "@r1\nAAAA\n+\nIIII\n@r2\nCCCC\n+\nIIII\n@r3\nGGGG\n+\nIIII\n",
+        )?;
+        let odir = temp.path().to_str().unwrap();
+        let counts = split_by_barcode(
+            fq1.to_str().unwrap(),
+            Some(fq2.to_str().unwrap()),
+            "CB",
+            odir,
+            Some("cells.tar"),
+            Some(4),
+            2,
+            64,
+            2,
+            None,
+            1,
+        )?;
+        assert_eq!(
+            counts,
+            vec![(Bytes::from("AAAC"), 2), (Bytes::from("CCCA"), 1)]
+        );
+
+        let mut out = String::new();
+        new_reader(
+            &temp.path().join("cells.tar#AAAC_R2.fastq.gz"),
+            BUFFER_SIZE,
+            None,
+        )?
+        .read_to_string(&mut out)?;
+        assert_eq!(out, "@r1\nAAAA\n+\nIIII\n@r3\nGGGG\n+\nIIII\n");
+
+        // Missing barcodes are reported
+        assert!(split_by_barcode(
+            fq2.to_str().unwrap(),
+            None,
+            "CB",
+            odir,
+            None,
+            Some(4),
+            2,
+            64,
+            1,
+            None,
+            1
+        )
+        .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,433 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use extendr_api::prelude::*;
+use rustc_hash::FxHashMap as HashMap;
+use rustc_hash::FxHashSet as HashSet;
+
+use crate::batchsender::BatchSender;
+use crate::error::RError;
+use crate::fastq_reader::FastqReader;
+use crate::fastq_record::FastqRecord;
+use crate::kractor::reads::barcode::BarcodeSource;
+use crate::seq_range::SeqRanges;
+use crate::utils::*;
+
+#[extendr]
+fn feature_count(
+    fq: &str,
+    ids: Vec<String>,
+    sequences: Vec<String>,
+    feature_range: Robj,
+    barcode: Robj,
+    umi: Robj,
+    mismatches: usize,
+    batch_size: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> std::result::Result<List, RError> {
+    feature_count_internal(
+        fq,
+        ids,
+        sequences,
+        feature_range,
+        barcode,
+        umi,
+        mismatches,
+        batch_size,
+        nqueue,
+        threads.max(1),
+    )
+    .map_err(RError::from)
+}
+
+extendr_module! {
+    mod feature_count;
+    fn feature_count;
+}
+
+fn feature_count_internal(
+    fq: &str,
+    ids: Vec<String>,
+    sequences: Vec<String>,
+    feature_range: Robj,
+    barcode: Robj,
+    umi: Robj,
+    mismatches: usize,
+    batch_size: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> Result<List> {
+    let reference = FeatureReference::new(&sequences, mismatches)?;
+    let feature_range = if feature_range.is_null() {
+        None
+    } else {
+        Some(SeqRanges::try_from(&feature_range)?)
+    };
+    let barcode = BarcodeSource::try_from(&barcode)?;
+    let umi = if umi.is_null() {
+        None
+    } else {
+        Some(BarcodeSource::try_from(&umi)?)
+    };
+    let matcher = FeatureMatcher {
+        reference,
+        feature_range,
+        barcode,
+        umi,
+    };
+    let counts = count_features(fq, &matcher, batch_size, nqueue, threads)?;
+
+    // ─── Build count table: feature x barcode ────────────
+    let mut barcodes = counts.cells.keys().collect::<Vec<_>>();
+    barcodes.sort();
+    let counts_vec = barcodes
+        .iter()
+        .map(|barcode| {
+            let features = &counts.cells[*barcode];
+            (0 .. ids.len())
+                .map(|feature| {
+                    features
+                        .get(&feature)
+                        .map(|count| count.count(matcher.umi.is_some()))
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let barcode_cols = barcodes
+        .iter()
+        .map(|barcode| String::from_utf8_lossy(barcode).into_owned())
+        .collect::<Vec<_>>();
+    Ok(list!(
+        features = ids,
+        counts = List::from_names_and_values(barcode_cols, counts_vec)
+            .map_err(|e| anyhow!("Failed to create list for counts: {}", e))?,
+        stats = list!(
+            reads = counts.reads as f64,
+            matched = counts.matched as f64,
+            ambiguous = counts.ambiguous as f64,
+            unmatched = counts.unmatched as f64,
+            no_barcode = counts.no_barcode as f64
+        )
+    ))
+}
+
+/// The feature barcodes of a feature reference (antibody or hashtag
+/// oligos), with all sequences within the mismatch tolerance of each
+struct FeatureReference {
+    /// Sequence → feature index, `None` for sequences within the tolerance
+    /// of several features
+    variants: HashMap<Vec<u8>, Option<usize>>,
+    /// Distinct lengths of the feature barcodes, longest first
+    lengths: Vec<usize>,
+}
+
+impl FeatureReference {
+    fn new(sequences: &[String], mismatches: usize) -> Result<Self> {
+        let mut exact = HashMap::default();
+        for (feature, sequence) in sequences.iter().enumerate() {
+            let sequence = sequence.to_ascii_uppercase().into_bytes();
+            if sequence.is_empty() {
+                return Err(anyhow!("Feature {} has an empty sequence", feature + 1));
+            }
+            if let Some(other) = exact.insert(sequence.clone(), feature) {
+                return Err(anyhow!(
+                    "Features {} and {} have the same sequence {}",
+                    other + 1,
+                    feature + 1,
+                    String::from_utf8_lossy(&sequence)
+                ));
+            }
+        }
+        let mut variants: HashMap<Vec<u8>, Option<usize>> = HashMap::default();
+        for (sequence, &feature) in &exact {
+            let mut variant = sequence.clone();
+            add_variants(&mut variants, &mut variant, 0, mismatches, feature);
+        }
+        // A read matching a feature exactly is never ambiguous
+        for (sequence, feature) in exact {
+            variants.insert(sequence, Some(feature));
+        }
+        let mut lengths = sequences
+            .iter()
+            .map(|x| x.len())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        lengths.sort_unstable_by(|a, b| b.cmp(a));
+        Ok(Self { variants, lengths })
+    }
+
+    /// The feature whose barcode starts `window`, `Err(())` if the barcode is
+    /// ambiguous, `Ok(None)` if it matches no feature
+    fn find(&self, window: &[u8]) -> std::result::Result<Option<usize>, ()> {
+        for &length in &self.lengths {
+            if window.len() < length {
+                continue;
+            }
+            match self.variants.get(&window[.. length]) {
+                Some(Some(feature)) => return Ok(Some(*feature)),
+                Some(None) => return Err(()),
+                None => {}
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Insert all substitutions of `sequence` from `start`, with up to
+/// `mismatches` substitutions, as variants of `feature`. `N` is included as
+/// a substitution, sequencers calling it for a base they can't resolve.
+fn add_variants(
+    variants: &mut HashMap<Vec<u8>, Option<usize>>,
+    sequence: &mut Vec<u8>,
+    start: usize,
+    mismatches: usize,
+    feature: usize,
+) {
+    variants
+        .entry(sequence.clone())
+        .and_modify(|x| {
+            if *x != Some(feature) {
+                *x = None
+            }
+        })
+        .or_insert(Some(feature));
+    if mismatches == 0 {
+        return;
+    }
+    for pos in start .. sequence.len() {
+        let original = sequence[pos];
+        for base in b"ACGTN" {
+            if *base == original {
+                continue;
+            }
+            sequence[pos] = *base;
+            add_variants(variants, sequence, pos + 1, mismatches - 1, feature);
+        }
+        sequence[pos] = original;
+    }
+}
+
+struct FeatureMatcher {
+    reference: FeatureReference,
+    /// Ranges of the read holding the feature barcode, the whole read if
+    /// `None`
+    feature_range: Option<SeqRanges>,
+    barcode: BarcodeSource,
+    umi: Option<BarcodeSource>,
+}
+
+/// The reads and distinct UMIs of a feature in a cell
+#[derive(Default)]
+struct FeatureCount {
+    reads: usize,
+    umis: HashSet<Vec<u8>>,
+}
+
+impl FeatureCount {
+    fn count(&self, umi: bool) -> usize {
+        if umi {
+            self.umis.len()
+        } else {
+            self.reads
+        }
+    }
+}
+
+#[derive(Default)]
+struct FeatureCounts {
+    reads: usize,
+    matched: usize,
+    ambiguous: usize,
+    unmatched: usize,
+    no_barcode: usize,
+    // barcode → feature → count
+    cells: HashMap<Vec<u8>, HashMap<usize, FeatureCount>>,
+}
+
+impl FeatureCounts {
+    fn add_record(&mut self, matcher: &FeatureMatcher, record: &FastqRecord<Bytes>) {
+        self.reads += 1;
+        let mut window = match &matcher.feature_range {
+            Some(ranges) => {
+                let mut window = Vec::new();
+                for range in ranges {
+                    match range.try_extract(&record.seq) {
+                        Ok(x) => window.extend_from_slice(x),
+                        Err(_) => {
+                            // Reads too short for the ranges hold no feature
+                            self.unmatched += 1;
+                            return;
+                        }
+                    }
+                }
+                window
+            }
+            None => record.seq.to_vec(),
+        };
+        window.make_ascii_uppercase();
+        let feature = match matcher.reference.find(&window) {
+            Ok(Some(feature)) => feature,
+            Ok(None) => {
+                self.unmatched += 1;
+                return;
+            }
+            Err(()) => {
+                self.ambiguous += 1;
+                return;
+            }
+        };
+        self.matched += 1;
+        let Some(barcode) = matcher.barcode.barcode(record, None) else {
+            self.no_barcode += 1;
+            return;
+        };
+        let count = self
+            .cells
+            .entry(barcode)
+            .or_default()
+            .entry(feature)
+            .or_default();
+        count.reads += 1;
+        if let Some(umi) = matcher.umi.as_ref().and_then(|x| x.barcode(record, None)) {
+            count.umis.insert(umi);
+        }
+    }
+
+    fn merge(&mut self, other: FeatureCounts) {
+        self.reads += other.reads;
+        self.matched += other.matched;
+        self.ambiguous += other.ambiguous;
+        self.unmatched += other.unmatched;
+        self.no_barcode += other.no_barcode;
+        for (barcode, features) in other.cells {
+            let cell = self.cells.entry(barcode).or_default();
+            for (feature, count) in features {
+                let total = cell.entry(feature).or_default();
+                total.reads += count.reads;
+                total.umis.extend(count.umis);
+            }
+        }
+    }
+}
+
+fn count_features<P: AsRef<Path> + ?Sized>(
+    input_path: &P,
+    matcher: &FeatureMatcher,
+    batch_size: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> Result<FeatureCounts> {
+    let input: &Path = input_path.as_ref();
+    std::thread::scope(|scope| -> Result<FeatureCounts> {
+        let (reader_tx, reader_rx) = new_channel(nqueue);
+
+        // ─── Parser Thread ─────────────────────────────────────
+        let mut parser_handles = Vec::with_capacity(threads);
+        for _ in 0 .. threads {
+            let rx = reader_rx.clone();
+            let handle = scope.spawn(move || -> Result<FeatureCounts> {
+                let mut counts = FeatureCounts::default();
+                while let Ok(records) = rx.recv() {
+                    for record in records {
+                        counts.add_record(matcher, &record);
+                    }
+                }
+                Ok(counts)
+            });
+            parser_handles.push(handle);
+        }
+        drop(reader_rx);
+
+        // ─── reader Thread ─────────────────────────────────────
+        let reader_handle = scope.spawn(move || -> Result<()> {
+            let mut reader =
+                FastqReader::with_capacity(BUFFER_SIZE, new_reader(input, BUFFER_SIZE, None)?);
+            let mut reader_tx = BatchSender::with_capacity(batch_size, reader_tx);
+            while let Some(record) = reader
+                .read_record()
+                .context("(Reader) Failed to read FASTQ record")?
+            {
+                reader_tx
+                    .send(record)
+                    .context("(Reader) Failed to send FASTQ records to Parser thread")?;
+            }
+            reader_tx
+                .flush()
+                .context("(Reader) Failed to flush FASTQ records to Parser thread")?;
+            Ok(())
+        });
+
+        // ─── Join Threads and Propagate Errors ────────────────
+        let mut counts = FeatureCounts::default();
+        for handler in parser_handles {
+            counts.merge(
+                handler
+                    .join()
+                    .map_err(|e| anyhow!("(Parser) thread panicked: {:?}", e))??,
+            );
+        }
+        reader_handle
+            .join()
+            .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))??;
+        Ok(counts)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seq_range::SeqRange;
+
+    #[test]
+    fn test_feature_reference() -> Result<()> {
+        let reference = FeatureReference::new(&["AAAA".to_string(), "AACC".to_string()], 1)?;
+        assert_eq!(reference.find(b"AAAAGG"), Ok(Some(0)));
+        assert_eq!(reference.find(b"ATAA"), Ok(Some(0)));
+        assert_eq!(reference.find(b"AACN"), Ok(Some(1)));
+        // One mismatch from both features
+        assert_eq!(reference.find(b"AACA"), Err(()));
+        assert_eq!(reference.find(b"TTTT"), Ok(None));
+        assert_eq!(reference.find(b"AAA"), Ok(None));
+        assert!(FeatureReference::new(&["AAAA".to_string(), "aaaa".to_string()], 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_features() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let fq = temp.path().join("features.fq");
+        std::fs::write(
+            &fq,
+            "@r1 MIRE{BARCODE:C1:UMI:U1}\nGGAAAA\n+\nIIIIII\n\
+             @r2 MIRE{BARCODE:C1:UMI:U1}\nGGAAAT\n+\nIIIIII\n\
+             @r3 MIRE{BARCODE:C1:UMI:U2}\nGGCCCC\n+\nIIIIII\n\
+             @r4 MIRE{BARCODE:C2:UMI:U1}\nGGCCCC\n+\nIIIIII\n\
+             @r5 MIRE{UMI:U3}\nGGCCCC\n+\nIIIIII\n\
+             @r6 MIRE{BARCODE:C2:UMI:U4}\nGGTTGG\n+\nIIIIII\n",
+        )?;
+        let matcher = FeatureMatcher {
+            reference: FeatureReference::new(&["AAAA".to_string(), "CCCC".to_string()], 1)?,
+            feature_range: Some(vec![SeqRange::From(2)].into()),
+            barcode: BarcodeSource::Tag(b"BARCODE".to_vec()),
+            umi: Some(BarcodeSource::Tag(b"UMI".to_vec())),
+        };
+        let counts = count_features(&fq, &matcher, 2, None, 2)?;
+        assert_eq!(
+            (
+                counts.reads,
+                counts.matched,
+                counts.unmatched,
+                counts.no_barcode
+            ),
+            (6, 5, 1, 1)
+        );
+        let c1 = &counts.cells[&b"C1".to_vec()];
+        assert_eq!((c1[&0].reads, c1[&0].count(true)), (2, 1));
+        assert_eq!(c1[&1].count(true), 1);
+        assert_eq!(counts.cells[&b"C2".to_vec()][&1].count(false), 1);
+        Ok(())
+    }
+}
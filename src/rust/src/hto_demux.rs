@@ -0,0 +1,167 @@
+use anyhow::{anyhow, Result};
+use extendr_api::prelude::*;
+
+use crate::error::RError;
+use crate::utils::*;
+
+/// Assign cells to the samples of their hashtag, from the counts of each
+/// hashtag in each cell returned by `feature_count()`
+#[extendr]
+fn hto_demux(
+    counts: List,
+    hashtags: Vec<String>,
+    thresholds: Robj,
+    min_count: usize,
+) -> std::result::Result<List, RError> {
+    hto_demux_internal(counts, hashtags, thresholds, min_count).map_err(RError::from)
+}
+
+extendr_module! {
+    mod hto_demux;
+    fn hto_demux;
+}
+
+fn hto_demux_internal(
+    counts: List,
+    hashtags: Vec<String>,
+    thresholds: Robj,
+    min_count: usize,
+) -> Result<List> {
+    let cells = list_to_counts(&counts)?;
+    if let Some(cell) = cells.iter().position(|x| x.len() != hashtags.len()) {
+        return Err(anyhow!(
+            "Cell {} has {} counts for {} hashtags",
+            cell + 1,
+            cells[cell].len(),
+            hashtags.len()
+        ));
+    }
+    let thresholds = if thresholds.is_null() {
+        (0 .. hashtags.len())
+            .map(|hashtag| {
+                let counts = cells.iter().map(|x| x[hashtag]).collect::<Vec<_>>();
+                hashtag_threshold(counts, min_count)
+            })
+            .collect::<Vec<_>>()
+    } else {
+        let thresholds = thresholds
+            .as_real_vector()
+            .ok_or_else(|| anyhow!("Thresholds must be numeric"))?;
+        if thresholds.len() != hashtags.len() {
+            return Err(anyhow!("Expected a threshold for each hashtag"));
+        }
+        thresholds
+    };
+
+    let mut class = Vec::with_capacity(cells.len());
+    let mut sample = Vec::with_capacity(cells.len());
+    let mut count = Vec::with_capacity(cells.len());
+    for cell in &cells {
+        let assignment = assign_cell(cell, &thresholds);
+        class.push(assignment.class());
+        sample.push(match assignment {
+            Assignment::Singlet(hashtag) => hashtags[hashtag].as_str(),
+            Assignment::Doublet => "Doublet",
+            Assignment::Negative => "Negative",
+        });
+        count.push(cell.iter().max().copied().unwrap_or(0) as f64);
+    }
+    Ok(list!(
+        barcode = counts
+            .names()
+            .map(|x| x.collect::<Vec<_>>())
+            .unwrap_or_default(),
+        class = class,
+        sample = sample,
+        count = count,
+        thresholds = thresholds
+    ))
+}
+
+/// The sample assignment of a cell
+#[derive(Debug, PartialEq)]
+enum Assignment {
+    /// Only one hashtag is above its threshold
+    Singlet(usize),
+    /// Several hashtags are above their threshold: a droplet of cells of
+    /// several samples
+    Doublet,
+    /// No hashtag is above its threshold
+    Negative,
+}
+
+impl Assignment {
+    fn class(&self) -> &'static str {
+        match self {
+            Assignment::Singlet(_) => "singlet",
+            Assignment::Doublet => "doublet",
+            Assignment::Negative => "negative",
+        }
+    }
+}
+
+fn assign_cell(counts: &[usize], thresholds: &[f64]) -> Assignment {
+    let mut positive = counts
+        .iter()
+        .zip(thresholds)
+        .enumerate()
+        .filter(|(_, (count, threshold))| **count as f64 >= **threshold)
+        .map(|(hashtag, _)| hashtag);
+    match (positive.next(), positive.next()) {
+        (Some(hashtag), None) => Assignment::Singlet(hashtag),
+        (Some(_), Some(_)) => Assignment::Doublet,
+        (None, _) => Assignment::Negative,
+    }
+}
+
+/// The count separating the cells tagged by a hashtag from the background of
+/// the other cells: the split of the log counts maximizing the variance
+/// between both groups (Otsu's method), at least `min_count`. Infinite if
+/// all cells have the same count.
+fn hashtag_threshold(mut counts: Vec<usize>, min_count: usize) -> f64 {
+    counts.sort_unstable();
+    let logs = counts
+        .iter()
+        .map(|&x| (x as f64).ln_1p())
+        .collect::<Vec<_>>();
+    let n = logs.len() as f64;
+    let total = logs.iter().sum::<f64>();
+    let mut below = 0.0;
+    let mut best: Option<(f64, usize)> = None;
+    for k in 1 .. logs.len() {
+        below += logs[k - 1];
+        // Only split between distinct counts
+        if counts[k] == counts[k - 1] {
+            continue;
+        }
+        let (n0, n1) = (k as f64, n - k as f64);
+        let (m0, m1) = (below / n0, (total - below) / n1);
+        let variance = n0 * n1 * (m0 - m1).powi(2);
+        if best.is_none_or(|(best, _)| variance > best) {
+            best = Some((variance, k));
+        }
+    }
+    match best {
+        Some((_, k)) => counts[k].max(min_count) as f64,
+        None => f64::INFINITY,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hto_demux() {
+        let threshold = hashtag_threshold(vec![0, 2, 1, 3, 150, 200, 180, 1], 10);
+        assert_eq!(threshold, 150.0);
+        assert_eq!(hashtag_threshold(vec![0, 1, 2, 3], 10), 10.0);
+        assert_eq!(hashtag_threshold(vec![5, 5], 10), f64::INFINITY);
+
+        let thresholds = [100.0, 50.0];
+        assert_eq!(assign_cell(&[150, 3], &thresholds), Assignment::Singlet(0));
+        assert_eq!(assign_cell(&[3, 60], &thresholds), Assignment::Singlet(1));
+        assert_eq!(assign_cell(&[150, 60], &thresholds), Assignment::Doublet);
+        assert_eq!(assign_cell(&[99, 49], &thresholds), Assignment::Negative);
+    }
+}
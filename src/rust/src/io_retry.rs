@@ -0,0 +1,321 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use extendr_api::prelude::*;
+
+use crate::error::{ErrorKind, RError};
+use crate::warnings::warn;
+
+// Network filesystems of clusters (NFS, Lustre) fail reads and writes now and
+// then with errors gone a moment later: EIO when a server is briefly
+// unreachable, ESTALE when a file handle goes stale after a failover. Local
+// files are read and written through `RetryFile`, which retries these errors
+// with exponential backoff, reopening the file at the same offset, and warns
+// of each retry, rather than failing a run of hours on a single hiccup. Built
+// with the `async` feature, the blocks the I/O runtime reads and writes are
+// not retried, only the appends to outputs.
+
+/// Retries of an operation failing with a transient error
+const IO_RETRIES: u32 = 3;
+/// Wait before the first retry, doubled at each retry
+const IO_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest wait before a retry, however many retries came before
+const IO_MAX_WAIT: Duration = Duration::from_secs(3600);
+
+#[derive(Clone, Copy)]
+struct RetrySettings {
+    retries: u32,
+    backoff: Duration,
+}
+
+/// Set in R by `rust_call()` from the options of `mire_io_retry()` before
+/// each call
+static SETTINGS: Mutex<RetrySettings> = Mutex::new(RetrySettings {
+    retries: IO_RETRIES,
+    backoff: IO_BACKOFF,
+});
+
+#[extendr]
+fn io_retry_settings(retries: f64, backoff: f64) -> std::result::Result<(), RError> {
+    if !(0.0 ..= 100.0).contains(&retries) || retries.fract() != 0.0 {
+        return Err(RError::from(ErrorKind::Config.error(format!(
+            "Invalid number of I/O retries {}: retries range from 0 to 100",
+            retries
+        ))));
+    }
+    if !(0.0 ..= 3600.0).contains(&backoff) {
+        return Err(RError::from(ErrorKind::Config.error(format!(
+            "Invalid I/O retry backoff {}: backoffs range from 0 to 3600 seconds",
+            backoff
+        ))));
+    }
+    *SETTINGS.lock().unwrap_or_else(|e| e.into_inner()) = RetrySettings {
+        retries: retries as u32,
+        backoff: Duration::from_secs_f64(backoff),
+    };
+    Ok(())
+}
+
+extendr_module! {
+    mod io_retry;
+    fn io_retry_settings;
+}
+
+/// Whether `error` may be gone by retrying the operation
+fn transient(error: &std::io::Error) -> bool {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        matches!(error.raw_os_error(), Some(libc::EIO) | Some(libc::ESTALE))
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    {
+        let _ = error;
+        false
+    }
+}
+
+/// The wait before retrying after attempt `attempt` (from 0) failed
+fn retry_wait(backoff: Duration, attempt: u32) -> Duration {
+    backoff
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(IO_MAX_WAIT)
+}
+
+/// Run `op` until it succeeds, fails with an error other than a transient
+/// one, or runs out of retries. `op` is given the number of the attempt,
+/// from 0.
+pub(crate) fn with_io_retries<T>(
+    path: &Path,
+    action: &str,
+    op: impl FnMut(u32) -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let settings = *SETTINGS.lock().unwrap_or_else(|e| e.into_inner());
+    retry_with(&settings, path, action, op)
+}
+
+/// `with_io_retries()` with the given settings, rather than those set in R
+fn retry_with<T>(
+    settings: &RetrySettings,
+    path: &Path,
+    action: &str,
+    mut op: impl FnMut(u32) -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op(attempt) {
+            Err(e) if attempt < settings.retries && transient(&e) => {
+                let wait = retry_wait(settings.backoff, attempt);
+                warn(format!(
+                    "Retried {} {} in {:.1}s after: {}",
+                    action,
+                    path.display(),
+                    wait.as_secs_f64(),
+                    e
+                ));
+                std::thread::sleep(wait);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Open a local file for reading, retrying transient errors
+pub(crate) fn open_retry(path: &Path) -> std::io::Result<RetryFile> {
+    let file = with_io_retries(path, "opening", |_| File::open(path))?;
+    Ok(RetryFile::new(file, path, false))
+}
+
+/// Create a local file for writing, retrying transient errors
+pub(crate) fn create_retry(path: &Path) -> std::io::Result<RetryFile> {
+    let file = with_io_retries(path, "creating", |_| File::create(path))?;
+    Ok(RetryFile::new(file, path, true))
+}
+
+/// A local file whose reads and writes retry transient errors, reopening the
+/// file at the offset reached, e.g. once its handle went stale
+pub(crate) struct RetryFile {
+    file: File,
+    path: PathBuf,
+    write: bool,
+    offset: u64,
+    // Taken when opened, so reads and writes need not lock the settings
+    settings: RetrySettings,
+}
+
+impl RetryFile {
+    fn new(file: File, path: &Path, write: bool) -> Self {
+        Self {
+            file,
+            path: path.to_path_buf(),
+            write,
+            offset: 0,
+            settings: *SETTINGS.lock().unwrap_or_else(|e| e.into_inner()),
+        }
+    }
+
+    pub(crate) fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// The file, retrying no more
+    #[cfg(feature = "async")]
+    pub(crate) fn into_file(self) -> File {
+        self.file
+    }
+
+    fn reopen(&mut self) -> std::io::Result<()> {
+        let mut file = if self.write {
+            std::fs::OpenOptions::new().write(true).open(&self.path)?
+        } else {
+            File::open(&self.path)?
+        };
+        file.seek(SeekFrom::Start(self.offset))?;
+        self.file = file;
+        Ok(())
+    }
+
+    /// Retry `op` on the reopened file after it failed with `error`
+    fn retry<T>(
+        &mut self,
+        action: &str,
+        error: std::io::Error,
+        mut op: impl FnMut(&mut File) -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        let settings = self.settings;
+        let path = self.path.clone();
+        let mut error = Some(error);
+        retry_with(&settings, &path, action, |_| match error.take() {
+            Some(error) => Err(error),
+            None => {
+                self.reopen()?;
+                op(&mut self.file)
+            }
+        })
+    }
+}
+
+impl Read for RetryFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let nbytes = match self.file.read(buf) {
+            Err(e) if transient(&e) => self.retry("reading", e, |file| file.read(buf))?,
+            result => result?,
+        };
+        self.offset += nbytes as u64;
+        Ok(nbytes)
+    }
+}
+
+impl Write for RetryFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let nbytes = match self.file.write(buf) {
+            Err(e) if transient(&e) => self.retry("writing", e, |file| file.write(buf))?,
+            result => result?,
+        };
+        self.offset += nbytes as u64;
+        Ok(nbytes)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Append `data` to the file at `path`, replacing the file first if
+/// `truncate`. A failed attempt is cut off before the next one, so the data
+/// is only ever appended once.
+pub(crate) fn append_retry(path: &Path, data: &[u8], truncate: bool) -> std::io::Result<()> {
+    let start = if truncate {
+        0
+    } else {
+        std::fs::metadata(path).map_or(0, |x| x.len())
+    };
+    with_io_retries(path, "appending to", |_| {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(path)?;
+        file.set_len(start)?;
+        file.seek(SeekFrom::Start(start))?;
+        file.write_all(data)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_retries() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("reads.fq");
+        let mut file = create_retry(&path)?;
+        file.write_all(b"@r1\nACGT\n+\nIIII\n")?;
+        drop(file);
+        append_retry(&path, b"@r2\nTT\n", false)?;
+        append_retry(&path, b"+\nII\n", false)?;
+        let mut text = String::new();
+        open_retry(&path)?.read_to_string(&mut text)?;
+        assert_eq!(text, "@r1\nACGT\n+\nIIII\n@r2\nTT\n+\nII\n");
+
+        // Transient errors are retried, others returned at once
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        {
+            let settings = RetrySettings {
+                retries: 3,
+                backoff: Duration::ZERO,
+            };
+            let mut calls = 0;
+            let out = retry_with(&settings, &path, "reading", |attempt| {
+                calls += 1;
+                if attempt < 2 {
+                    Err(std::io::Error::from_raw_os_error(libc::ESTALE))
+                } else {
+                    Ok(attempt)
+                }
+            });
+            assert_eq!((out?, calls), (2, 3));
+        }
+        // Waits double up to the longest one, even after many retries
+        assert_eq!(retry_wait(IO_BACKOFF, 3), IO_BACKOFF * 8);
+        assert_eq!(retry_wait(IO_BACKOFF, 40), IO_MAX_WAIT);
+        assert_eq!(retry_wait(IO_MAX_WAIT, 100), IO_MAX_WAIT);
+        let mut calls = 0;
+        let out: std::io::Result<()> = with_io_retries(&path, "reading", |_| {
+            calls += 1;
+            Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+        });
+        assert!(out.is_err() && calls == 1);
+
+        // A reopened file carries on from the offset reached
+        let mut file = open_retry(&path)?;
+        let mut head = [0; 4];
+        file.read_exact(&mut head)?;
+        file.reopen()?;
+        text.clear();
+        file.read_to_string(&mut text)?;
+        assert_eq!(text, "ACGT\n+\nIIII\n@r2\nTT\n+\nII\n");
+
+        // A failed read is retried on the reopened file, with the settings
+        // taken when the file was opened
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        {
+            let mut file = open_retry(&path)?;
+            file.read_exact(&mut head)?;
+            file.settings.backoff = Duration::ZERO;
+            let error = std::io::Error::from_raw_os_error(libc::EIO);
+            let nbytes = file.retry("reading", error, |file| file.read(&mut head))?;
+            assert_eq!(&head[.. nbytes], b"ACGT");
+            file.settings.retries = 0;
+            let error = std::io::Error::from_raw_os_error(libc::EIO);
+            assert!(file
+                .retry("reading", error, |file| file.read(&mut head))
+                .is_err());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::VecDeque;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use crossbeam_channel::{Receiver, Sender};
+use extendr_api::prelude::*;
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::batchsender::BatchSender;
+use crate::error::RError;
+use crate::fastq_check::mate_id;
+use crate::fastq_reader::FastqReader;
+use crate::kractor::reads::barcode::BarcodeSource;
+use crate::reader::LineReader;
+use crate::table_writer::TableWriter;
+use crate::utils::*;
+
+#[extendr]
+fn koutput_join(
+    koutput: &str,
+    fq: &str,
+    ofile: &str,
+    barcode: Robj,
+    umi: Robj,
+    buffer: usize,
+    batch_size: usize,
+    compression_level: Option<i32>,
+    chunk_bytes: usize,
+    nqueue: Option<usize>,
+) -> std::result::Result<List, RError> {
+    let barcode = BarcodeSource::try_from(&barcode).map_err(RError::from)?;
+    let umi = if umi.is_null() {
+        None
+    } else {
+        Some(BarcodeSource::try_from(&umi).map_err(RError::from)?)
+    };
+    let counts = join_files(
+        koutput,
+        fq,
+        Path::new(ofile),
+        &barcode,
+        umi.as_ref(),
+        buffer,
+        batch_size,
+        compression_level,
+        chunk_bytes,
+        nqueue,
+    )
+    .map_err(RError::from)?;
+    Ok(list!(
+        lines = counts.lines as f64,
+        reads = counts.reads as f64,
+        joined = counts.joined as f64,
+        no_barcode = counts.no_barcode as f64,
+        unmatched_lines = counts.unmatched_lines as f64,
+        unmatched_reads = counts.unmatched_reads as f64
+    ))
+}
+
+extendr_module! {
+    mod koutput_join;
+    fn koutput_join;
+}
+
+/// A read of the FASTQ side of the join, with its cell barcode and UMI
+struct JoinRead {
+    id: Bytes,
+    barcode: Option<Vec<u8>>,
+    umi: Option<Vec<u8>>,
+}
+
+/// Counts of a join of Kraken2 output lines with their reads
+#[derive(Default, Debug, PartialEq)]
+struct JoinCounts {
+    lines: usize,
+    reads: usize,
+    // Lines joined with their read, whether or not the read has a barcode
+    joined: usize,
+    no_barcode: usize,
+    unmatched_lines: usize,
+    unmatched_reads: usize,
+}
+
+/// Join a Kraken2 output with the barcode-tagged reads it classified, writing
+/// the barcode, UMI and taxid of each read as a row of the table `output`.
+/// Both files are streamed by their own reader thread.
+#[allow(clippy::too_many_arguments)]
+fn join_files(
+    koutput: &str,
+    fq: &str,
+    output: &Path,
+    barcode: &BarcodeSource,
+    umi: Option<&BarcodeSource>,
+    buffer: usize,
+    batch_size: usize,
+    compression_level: Option<i32>,
+    chunk_bytes: usize,
+    nqueue: Option<usize>,
+) -> Result<JoinCounts> {
+    let compression = Compression::new(output, compression_level)?;
+    std::thread::scope(|scope| -> Result<JoinCounts> {
+        let (line_tx, line_rx) = new_channel(nqueue);
+        let (read_tx, read_rx): (Sender<Vec<JoinRead>>, Receiver<Vec<JoinRead>>) =
+            new_channel(nqueue);
+
+        // ─── Koutput Reader Thread ─────────────────────────────
+        let line_handle = scope.spawn(move || -> Result<()> {
+            let mut reader =
+                LineReader::with_capacity(BUFFER_SIZE, new_reader(koutput, BUFFER_SIZE, None)?);
+            let mut line_tx = BatchSender::with_capacity(batch_size, line_tx);
+            while let Some(line) = reader
+                .read_line()
+                .with_context(|| format!("(Reader) Failed to read line"))?
+            {
+                let line = line.freeze();
+                let mut fields = line.split(|x| *x == b'\t');
+                let (Some(_), Some(id), Some(taxid)) =
+                    (fields.next(), fields.next(), fields.next())
+                else {
+                    return Err(anyhow!(
+                        "(Reader) Invalid Kraken2 output line: {}",
+                        String::from_utf8_lossy(&line)
+                    ));
+                };
+                let taxid = koutput_taxid(taxid).unwrap_or(taxid);
+                if line_tx
+                    .send((line.slice_ref(id), line.slice_ref(taxid)))
+                    .is_err()
+                {
+                    // The join stopped early on an error, reported by its thread
+                    return Ok(());
+                }
+            }
+            line_tx.flush().ok();
+            Ok(())
+        });
+
+        // ─── FASTQ Reader Thread ───────────────────────────────
+        let read_handle = scope.spawn(move || -> Result<()> {
+            let mut reader = FastqReader::from_path(fq)?;
+            let mut read_tx = BatchSender::with_capacity(batch_size, read_tx);
+            while let Some(record) = reader
+                .read_record()
+                .with_context(|| format!("(Reader) Failed to read FASTQ record"))?
+            {
+                let read = JoinRead {
+                    id: record.id.slice_ref(mate_id(&record.id)),
+                    barcode: barcode.barcode(&record, None),
+                    umi: umi.and_then(|umi| umi.barcode(&record, None)),
+                };
+                if read_tx.send(read).is_err() {
+                    return Ok(());
+                }
+            }
+            read_tx.flush().ok();
+            Ok(())
+        });
+
+        // ─── Join and Write ────────────────────────────────────
+        let (mut writer, writer_handle) =
+            TableWriter::spawn(scope, output, compression, chunk_bytes, nqueue);
+        writer.row(["barcode", "umi", "taxid"])?;
+        let counts = join_koutput(
+            line_rx.into_iter().flatten().map(Ok),
+            read_rx.into_iter().flatten().map(Ok),
+            buffer,
+            |read, taxid| {
+                writer.row([
+                    read.barcode.as_deref().unwrap_or_default(),
+                    read.umi.as_deref().unwrap_or_default(),
+                    taxid,
+                ])
+            },
+        );
+
+        // ─── Join Threads and Propagate Errors ────────────────
+        line_handle
+            .join()
+            .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))??;
+        read_handle
+            .join()
+            .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))??;
+        // The writer is dropped on error, ending its thread, which holds the
+        // cause of a failed write
+        let counts = counts.and_then(|counts| writer.finish().map(|_| counts));
+        writer_handle
+            .join()
+            .map_err(|e| anyhow!("(Writer) thread panicked: {:?}", e))??;
+        counts
+    })
+}
+
+/// Reads waiting for their Kraken2 output line, in input order. Reads
+/// falling out of the window are counted as unmatched.
+struct ReadWindow {
+    order: VecDeque<Bytes>,
+    reads: HashMap<Bytes, (usize, JoinRead)>,
+    capacity: usize,
+}
+
+impl ReadWindow {
+    fn new(capacity: usize) -> Self {
+        Self {
+            order: VecDeque::new(),
+            reads: HashMap::default(),
+            capacity,
+        }
+    }
+
+    fn take(&mut self, id: &[u8]) -> Option<(usize, JoinRead)> {
+        self.reads.remove(id)
+    }
+
+    /// Add the read at `position` of the FASTQ, returning the number of reads
+    /// evicted to make room for it
+    fn push(&mut self, position: usize, read: JoinRead) -> usize {
+        self.order.push_back(read.id.clone());
+        self.reads.insert(read.id.clone(), (position, read));
+        let mut evicted = 0;
+        while self.reads.len() > self.capacity {
+            // Reads already taken are skipped
+            if let Some(id) = self.order.pop_front() {
+                evicted += self.reads.remove(&id).is_some() as usize;
+            }
+        }
+        if self.order.len() > 2 * self.capacity.max(1) {
+            let reads = &self.reads;
+            self.order.retain(|id| reads.contains_key(id));
+        }
+        evicted
+    }
+}
+
+/// Join Kraken2 output lines (read ID and taxid) with their reads, both in
+/// the input order of Kraken2. Either side may lack some entries of the other,
+/// e.g. a Kraken2 output filtered by [`kractor_koutput()`], and entries may
+/// be slightly out of order: reads are looked ahead, up to `buffer` reads past
+/// the last joined read, and kept in a window of `buffer` reads. Each joined
+/// read with a barcode is passed to `emit` with its taxid.
+fn join_koutput<K, R, E>(koutput: K, reads: R, buffer: usize, mut emit: E) -> Result<JoinCounts>
+where
+    K: Iterator<Item = Result<(Bytes, Bytes)>>,
+    R: Iterator<Item = Result<JoinRead>>,
+    E: FnMut(&JoinRead, &[u8]) -> Result<()>,
+{
+    let mut counts = JoinCounts::default();
+    let mut window = ReadWindow::new(buffer);
+    let mut reads = reads;
+    // Number of reads pulled so far, and the position of the last joined read
+    let mut position = 0;
+    let mut last_joined = 0;
+    let mut join = |counts: &mut JoinCounts, read: &JoinRead, taxid: &[u8]| -> Result<()> {
+        counts.joined += 1;
+        if read.barcode.is_none() {
+            counts.no_barcode += 1;
+            return Ok(());
+        }
+        emit(read, taxid)
+    };
+    'lines: for line in koutput {
+        let (id, taxid) = line?;
+        counts.lines += 1;
+        if let Some((read_position, read)) = window.take(&id) {
+            last_joined = last_joined.max(read_position);
+            join(&mut counts, &read, &taxid)?;
+            continue;
+        }
+        while position < last_joined + buffer {
+            let Some(read) = reads.next().transpose()? else {
+                break;
+            };
+            position += 1;
+            counts.reads += 1;
+            if read.id == id {
+                last_joined = position;
+                join(&mut counts, &read, &taxid)?;
+                continue 'lines;
+            }
+            counts.unmatched_reads += window.push(position, read);
+        }
+        counts.unmatched_lines += 1;
+    }
+    for read in reads {
+        read?;
+        counts.reads += 1;
+        counts.unmatched_reads += 1;
+    }
+    counts.unmatched_reads += window.reads.len();
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn join(lines: &[&str], reads: &[&str], buffer: usize) -> Result<(JoinCounts, Vec<String>)> {
+        let lines = lines
+            .iter()
+            .map(|id| Ok((Bytes::from(id.to_string()), Bytes::from(format!("t{}", id)))));
+        let reads = reads.iter().map(|id| {
+            Ok(JoinRead {
+                id: Bytes::from(id.to_string()),
+                barcode: (!id.starts_with('x')).then(|| format!("B{}", id).into_bytes()),
+                umi: None,
+            })
+        });
+        let mut joined = Vec::new();
+        let counts = join_koutput(lines, reads, buffer, |read, taxid| {
+            joined.push(format!(
+                "{}={}",
+                String::from_utf8_lossy(read.barcode.as_ref().unwrap()),
+                String::from_utf8_lossy(taxid)
+            ));
+            Ok(())
+        })?;
+        Ok((counts, joined))
+    }
+
+    #[test]
+    fn test_join_koutput() -> Result<()> {
+        // Same reads, slightly out of order
+        let (counts, joined) = join(&["r1", "r3", "r2", "r4"], &["r1", "r2", "r3", "r4"], 2)?;
+        assert_eq!(joined, ["Br1=tr1", "Br3=tr3", "Br2=tr2", "Br4=tr4"]);
+        assert_eq!((counts.joined, counts.unmatched_reads), (4, 0));
+
+        // A filtered Kraken2 output, a read without barcode and a line
+        // without read
+        let reads = ["r1", "r2", "r3", "x4", "r5", "r6", "r7", "r8"];
+        let (counts, joined) = join(&["r2", "x4", "r0", "r7"], &reads, 3)?;
+        assert_eq!(joined, ["Br2=tr2", "Br7=tr7"]);
+        assert_eq!(
+            counts,
+            JoinCounts {
+                lines: 4,
+                reads: 8,
+                joined: 3,
+                no_barcode: 1,
+                unmatched_lines: 1,
+                unmatched_reads: 5,
+            }
+        );
+
+        // Lines too far from their read are not joined
+        let (counts, _) = join(&["r4", "r1"], &["r1", "r2", "r3", "r4"], 2)?;
+        assert_eq!((counts.joined, counts.unmatched_lines), (1, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_join_files() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let koutput = temp.path().join("koutput.txt");
+        let fq = temp.path().join("reads.fq");
+        let output = temp.path().join("joined.tsv");
+        std::fs::write(
+            &koutput,
+            "C\tr1\tEscherichia coli (taxid 562)\t100\t562:66\nU\tr3\t0\t100\t0:66\n",
+        )?;
+        std::fs::write(
+            &fq,
+            "@r1/1 MIRE{BARCODE:AAAC:UMI:TT}\nA\n+\nI\n\
+             @r2 MIRE{BARCODE:CCCA:UMI:GG}\nA\n+\nI\n\
+             @r3 MIRE{BARCODE:CCCA}\nA\n+\nI\n",
+        )?;
+        let barcode = BarcodeSource::Tag(b"BARCODE".to_vec());
+        let umi = BarcodeSource::Tag(b"UMI".to_vec());
+        let counts = join_files(
+            koutput.to_str().unwrap(),
+            fq.to_str().unwrap(),
+            &output,
+            &barcode,
+            Some(&umi),
+            10,
+            1,
+            Some(4),
+            1024,
+            Some(1),
+        )?;
+        assert_eq!((counts.joined, counts.unmatched_reads), (2, 1));
+        assert_eq!(
+            std::fs::read_to_string(&output)?,
+            "barcode\tumi\ttaxid\nAAAC\tTT\t562\nCCCA\t\t0\n"
+        );
+        Ok(())
+    }
+}
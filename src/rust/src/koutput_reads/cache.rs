@@ -0,0 +1,166 @@
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use rustc_hash::FxHashMap as HashMap;
+
+use super::koutput::{collect_records, ExtraRecords, KoutRecord, KoutRecords};
+use crate::utils::*;
+
+// ─── Binary Layout ─────────────────────────────────────────────────────
+// magic (8 bytes) | version (u32) | records (u64)
+// then for each record: id, length, taxid, LCA
+// an ID classified on several lines has one record per classification
+// each field stored as a length (u32) followed by its raw bytes
+// all integers are little-endian
+const CACHE_MAGIC: &[u8; 8] = b"MIREKOUT";
+const CACHE_VERSION: u32 = 1;
+
+/// Save the parsed koutput map into a compact binary cache file
+pub(super) fn save_koutmap<P: AsRef<Path> + ?Sized>(
+    koutmap: &HashMap<Bytes, KoutRecord>,
+    extra: &ExtraRecords,
+    path: &P,
+) -> Result<()> {
+    let path: &Path = path.as_ref();
+    let mut writer = std::io::BufWriter::with_capacity(BUFFER_SIZE, new_writer(path, None)?);
+    write_koutmap(koutmap, extra, &mut writer)
+        .and_then(|_| writer.complete().map_err(anyhow::Error::from))
+        .with_context(|| format!("Failed to write koutput cache {}", path.display()))
+}
+
+/// Load a koutput map previously written by [`save_koutmap`]
+pub(super) fn load_koutmap<P: AsRef<Path> + ?Sized>(path: &P) -> Result<KoutRecords> {
+    let path: &Path = path.as_ref();
+    let data =
+        std::fs::read(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
+    read_koutmap(Bytes::from(data))
+        .with_context(|| format!("Invalid koutput cache {}", path.display()))
+}
+
+fn write_koutmap<W: Write>(
+    koutmap: &HashMap<Bytes, KoutRecord>,
+    extra: &ExtraRecords,
+    writer: &mut W,
+) -> Result<()> {
+    let records = koutmap.len() + extra.values().map(Vec::len).sum::<usize>();
+    writer.write_all(CACHE_MAGIC)?;
+    writer.write_all(&CACHE_VERSION.to_le_bytes())?;
+    writer.write_all(&(records as u64).to_le_bytes())?;
+    let extra = extra
+        .iter()
+        .flat_map(|(id, records)| records.iter().map(move |record| (id, record)));
+    for (id, (length, taxid, lca)) in koutmap.iter().chain(extra) {
+        for field in [id, length, taxid, lca] {
+            let size = u32::try_from(field.len())
+                .map_err(|_| anyhow!("Field too large: {} bytes", field.len()))?;
+            writer.write_all(&size.to_le_bytes())?;
+            writer.write_all(field)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_koutmap(mut data: Bytes) -> Result<KoutRecords> {
+    if take(&mut data, CACHE_MAGIC.len())? != CACHE_MAGIC.as_slice() {
+        return Err(anyhow!("Not a koutput cache file"));
+    }
+    let version = u32::from_le_bytes(take(&mut data, 4)?[..].try_into()?);
+    if version != CACHE_VERSION {
+        return Err(anyhow!(
+            "Unsupported cache version {} (expected {})",
+            version,
+            CACHE_VERSION
+        ));
+    }
+    let records = u64::from_le_bytes(take(&mut data, 8)?[..].try_into()?) as usize;
+    // Each record takes at least 16 bytes, don't trust a corrupted count
+    let capacity = records.min(data.len() / 16);
+    let mut koutmap = Vec::with_capacity(capacity);
+    for _ in 0 .. records {
+        // Fields are zero-copy slices into the cache buffer
+        let id = take_field(&mut data)?;
+        let length = take_field(&mut data)?;
+        let taxid = take_field(&mut data)?;
+        let lca = take_field(&mut data)?;
+        koutmap.push((id, (length, taxid, lca)));
+    }
+    if !data.is_empty() {
+        return Err(anyhow!(
+            "Trailing {} bytes after the last record",
+            data.len()
+        ));
+    }
+    // Caches of maps keeping all classifications repeat their IDs
+    Ok(collect_records(koutmap, true))
+}
+
+fn take(data: &mut Bytes, size: usize) -> Result<Bytes> {
+    if data.len() < size {
+        return Err(anyhow!("Unexpected end of file"));
+    }
+    Ok(data.split_to(size))
+}
+
+fn take_field(data: &mut Bytes) -> Result<Bytes> {
+    let size = u32::from_le_bytes(take(data, 4)?[..].try_into()?) as usize;
+    take(data, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_koutmap_cache_roundtrip() -> Result<()> {
+        let mut koutmap = HashMap::default();
+        koutmap.insert(
+            Bytes::from_static(b"read1"),
+            (
+                Bytes::from_static(b"151"),
+                Bytes::from_static(b"562"),
+                Bytes::from_static(b"562:13 561:4 A:31 0:1"),
+            ),
+        );
+        koutmap.insert(
+            Bytes::from_static(b"read2"),
+            (
+                Bytes::from_static(b"98|98"),
+                Bytes::from_static(b"10239"),
+                Bytes::new(),
+            ),
+        );
+        let mut buffer = Vec::new();
+        write_koutmap(&koutmap, &ExtraRecords::default(), &mut buffer)?;
+        let (map, extra) = read_koutmap(Bytes::from(buffer.clone()))?;
+        assert_eq!(map, koutmap);
+        assert!(extra.is_empty());
+
+        // All classifications of duplicate IDs are kept
+        let mut extra = ExtraRecords::default();
+        extra.insert(
+            Bytes::from_static(b"read1"),
+            vec![(
+                Bytes::from_static(b"151"),
+                Bytes::from_static(b"9606"),
+                Bytes::from_static(b"9606:49"),
+            )],
+        );
+        let mut all = Vec::new();
+        write_koutmap(&koutmap, &extra, &mut all)?;
+        assert_eq!(read_koutmap(Bytes::from(all))?, (koutmap, extra));
+
+        // Truncated or foreign files are rejected
+        buffer.pop();
+        assert!(read_koutmap(Bytes::from(buffer)).is_err());
+        assert!(read_koutmap(Bytes::from_static(b"C\tread1\t562\t151\t562:5\n")).is_err());
+
+        // Future versions are rejected
+        let mut header = CACHE_MAGIC.to_vec();
+        header.extend_from_slice(&(CACHE_VERSION + 1).to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes());
+        assert!(read_koutmap(Bytes::from(header)).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+use std::path::Path;
+
+use bytes::Bytes;
+use extendr_api::prelude::*;
+use rustc_hash::FxHashMap as HashMap;
+use rustc_hash::FxHashSet as HashSet;
+
+use super::koutput::{collect_records, ExtraRecords, KoutRecord, KoutRecords};
+use crate::error::RError;
+use crate::seq_tag::robj_to_tag_ranges;
+use crate::utils::*;
+
+/// Parsed koutput records kept on the Rust side.
+///
+/// R only holds an external pointer to this map, so the (possibly
+/// multi-gigabyte) records are never copied into R data structures unless
+/// explicitly requested by `lookup()` or `as_list()`.
+#[extendr]
+pub struct KoutputMap {
+    // sequence ID → (sequence length, taxid, LCA)
+    map: HashMap<Bytes, KoutRecord>,
+    // other classifications of the sequence IDs on several lines, only kept
+    // when asked for
+    extra: ExtraRecords,
+}
+
+impl KoutputMap {
+    pub(crate) fn from_map((map, extra): KoutRecords) -> Self {
+        Self { map, extra }
+    }
+
+    /// All records, those of the same sequence ID sorted and adjacent
+    fn records<'a>(&'a self, id: &[u8]) -> impl Iterator<Item = &'a KoutRecord> + 'a {
+        let main = self.map.get(id);
+        let extra = main.and_then(|_| self.extra.get(id)).into_iter().flatten();
+        main.into_iter().chain(extra)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&Bytes, &KoutRecord)> {
+        self.map.iter().chain(
+            self.extra
+                .iter()
+                .flat_map(|(id, records)| records.iter().map(move |record| (id, record))),
+        )
+    }
+}
+
+#[extendr]
+impl KoutputMap {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        kreport: &str,
+        koutput: &str,
+        taxonomy: Robj,
+        exclude: Robj,
+        batch_size: usize,
+        nqueue: Option<usize>,
+        threads: usize,
+        keep_all: bool,
+    ) -> std::result::Result<Self, RError> {
+        super::koutput_map(
+            kreport,
+            koutput,
+            taxonomy,
+            exclude,
+            batch_size,
+            nqueue,
+            threads.max(1),
+            keep_all,
+        )
+        .map(Self::from_map)
+        .map_err(RError::from)
+    }
+
+    /// Load a handle from a binary cache written by `save()`
+    fn load(path: &str) -> std::result::Result<Self, RError> {
+        super::cache::load_koutmap(path)
+            .map(Self::from_map)
+            .map_err(RError::from)
+    }
+
+    /// Save all records into a binary cache file
+    fn save(&self, path: &str) -> std::result::Result<(), RError> {
+        super::cache::save_koutmap(&self.map, &self.extra, path).map_err(RError::from)
+    }
+
+    /// Extract the reads of all records, as `koutput_reads()` does after
+    /// parsing the koutput file
+    fn reads(
+        &self,
+        fq1: &str,
+        fq2: Option<&str>,
+        ofile: &str,
+        ranges1: Robj,
+        ranges2: Robj,
+        fastq_batch: usize,
+        chunk_bytes: usize,
+        compression_level: Option<i32>,
+        nqueue: Option<usize>,
+        threads: usize,
+    ) -> std::result::Result<(), RError> {
+        let tag_ranges1 = robj_to_tag_ranges(&ranges1).map_err(RError::from)?;
+        let tag_ranges2 = robj_to_tag_ranges(&ranges2).map_err(RError::from)?;
+        let compression =
+            Compression::new(Path::new(ofile), compression_level).map_err(RError::from)?;
+        super::koutmap_reads(
+            &self.map,
+            fq1,
+            fq2,
+            ofile,
+            tag_ranges1,
+            tag_ranges2,
+            fastq_batch,
+            chunk_bytes,
+            compression,
+            nqueue,
+            threads.max(1),
+        )
+        .map_err(RError::from)
+    }
+
+    /// Number of records, counting each classification of the sequence IDs
+    /// on several lines
+    fn len(&self) -> usize {
+        self.map.len() + self.extra.values().map(Vec::len).sum::<usize>()
+    }
+
+    /// Unique taxids of all records, sorted
+    fn taxids(&self) -> Strings {
+        let mut taxids = self
+            .iter()
+            .map(|(_, (_, taxid, _))| taxid.as_ref())
+            .collect::<HashSet<&[u8]>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        taxids.sort_unstable();
+        let mut out = RStrings::with_capacity(taxids.len());
+        for taxid in taxids {
+            out.push(taxid);
+        }
+        out.finish()
+    }
+
+    /// Look up records by sequence ID, missing IDs give `NA`. Only the
+    /// `columns` asked for (`taxid`, `length` and `lca`) are returned with
+    /// the IDs, so millions of IDs can be looked up at once for joins in R.
+    /// IDs with several classifications give a row for each of them.
+    fn lookup(&self, ids: Strings, columns: Vec<String>) -> std::result::Result<List, RError> {
+        let mut rows = Vec::with_capacity(ids.len());
+        let mut records = Vec::with_capacity(ids.len());
+        for (i, id) in ids.iter().enumerate() {
+            let mut found = false;
+            if !id.is_na() {
+                for record in self.records(id.as_str().as_bytes()) {
+                    rows.push(i);
+                    records.push(Some(record));
+                    found = true;
+                }
+            }
+            if !found {
+                rows.push(i);
+                records.push(None);
+            }
+        }
+        let id_column = if records.len() == ids.len() {
+            Robj::from(ids)
+        } else {
+            let mut out = RStrings::with_capacity(rows.len());
+            for &i in &rows {
+                let id = ids.elt(i);
+                if id.is_na() {
+                    out.push_na();
+                } else {
+                    out.push(id.as_str().as_bytes());
+                }
+            }
+            Robj::from(out.finish())
+        };
+        let mut names = vec!["id".to_string()];
+        let mut values = vec![id_column];
+        for column in columns {
+            let field: fn(&(Bytes, Bytes, Bytes)) -> &[u8] = match column.as_str() {
+                "length" => |x| &x.0,
+                "taxid" => |x| &x.1,
+                "lca" => |x| &x.2,
+                _ => {
+                    return Err(RError::from(anyhow::anyhow!(
+                        "Unknown column of koutput records: {}",
+                        column
+                    )))
+                }
+            };
+            // Taxids and lengths repeat over many records, each value is
+            // created once and shared by its rows. LCA mappings are mostly
+            // unique to their record.
+            let cache = column != "lca";
+            let mut seen: HashMap<&[u8], usize> = HashMap::default();
+            let mut out = RStrings::with_capacity(records.len());
+            for (i, record) in records.iter().enumerate() {
+                let Some(record) = record else {
+                    out.push_na();
+                    continue;
+                };
+                let value = field(record);
+                if !cache {
+                    out.push(value);
+                } else if let Some(&first) = seen.get(value) {
+                    out.push_from(first);
+                } else {
+                    out.push(value);
+                    seen.insert(value, i);
+                }
+            }
+            names.push(column);
+            values.push(Robj::from(out.finish()));
+        }
+        List::from_names_and_values(names, values)
+            .map_err(|e| anyhow::anyhow!("Failed to create list of koutput records: {}", e))
+            .map_err(RError::from)
+    }
+
+    /// Keep only the records assigned to the given taxids, as a new handle
+    fn subset(&self, taxids: Vec<String>) -> Self {
+        let taxids = taxids
+            .iter()
+            .map(|x| x.as_bytes())
+            .collect::<HashSet<&[u8]>>();
+        let records = self
+            .iter()
+            .filter(|(_, (_, taxid, _))| taxids.contains(taxid.as_ref()))
+            .map(|(id, record)| (id.clone(), record.clone()));
+        Self::from_map(collect_records(records, !self.extra.is_empty()))
+    }
+
+    /// Copy all records into R
+    fn as_list(&self) -> List {
+        let n = self.len();
+        let mut id = RStrings::with_capacity(n);
+        let mut length = RStrings::with_capacity(n);
+        let mut taxid = RStrings::with_capacity(n);
+        let mut lca = RStrings::with_capacity(n);
+        for (i, (l, t, c)) in self.iter() {
+            id.push(i);
+            length.push(l);
+            taxid.push(t);
+            lca.push(c);
+        }
+        list!(
+            id = id.finish(),
+            taxid = taxid.finish(),
+            length = length.finish(),
+            lca = lca.finish()
+        )
+    }
+}
+
+extendr_module! {
+    mod koutmap;
+    impl KoutputMap;
+}
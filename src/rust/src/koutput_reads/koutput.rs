@@ -1,29 +1,67 @@
 use std::path::Path;
 
-use aho_corasick::AhoCorasick;
 use anyhow::{anyhow, Context, Result};
 use bytes::{Bytes, BytesMut};
 use crossbeam_channel::{Receiver, Sender};
-use indicatif::{ProgressBar, ProgressFinish};
 use memchr::memchr;
 use rustc_hash::FxHashMap as HashMap;
 use rustc_hash::FxHashSet as HashSet;
 
 use crate::batchsender::BatchSender;
+use crate::lca_exclude::ExcludeMatcher;
 use crate::reader::LineReader;
 use crate::utils::*;
 
+/// A koutput record: sequence length, taxid and LCA mapping
+pub(super) type KoutRecord = (Bytes, Bytes, Bytes);
+
+/// The other records of the sequence IDs classified on several lines, e.g.
+/// by paired and unpaired runs merged together
+pub(super) type ExtraRecords = HashMap<Bytes, Vec<KoutRecord>>;
+
+/// Records keyed by sequence ID, with the extra records of duplicate IDs
+pub(super) type KoutRecords = (HashMap<Bytes, KoutRecord>, ExtraRecords);
+
+/// Key records by sequence ID. The last record of a sequence ID met on several
+/// lines wins, unless `keep_all`: its distinct records are then all kept, the
+/// smallest as the main one and the others as extra records, whatever the
+/// order in which they were parsed.
+pub(super) fn collect_records<I: IntoIterator<Item = (Bytes, KoutRecord)>>(
+    records: I,
+    keep_all: bool,
+) -> KoutRecords {
+    let mut map: HashMap<Bytes, KoutRecord> = HashMap::default();
+    let mut extra = ExtraRecords::default();
+    for (id, record) in records {
+        if let Some(previous) = map.insert(id.clone(), record) {
+            if keep_all {
+                extra.entry(id).or_default().push(previous);
+            }
+        }
+    }
+    extra.retain(|id, records| {
+        let main = map.get_mut(id).unwrap();
+        records.push(main.clone());
+        records.sort_unstable();
+        records.dedup();
+        *main = records.remove(0);
+        !records.is_empty()
+    });
+    (map, extra)
+}
+
 pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
     input_path: &P,
     include_sets: HashSet<&[u8]>,
-    exclude_aho: Option<AhoCorasick>,
+    exclude_aho: Option<ExcludeMatcher>,
     batch_size: usize,
     nqueue: Option<usize>,
     threads: usize,
-) -> Result<HashMap<Bytes, (Bytes, Bytes, Bytes)>> {
+    keep_all: bool,
+) -> Result<KoutRecords> {
     let input: &Path = input_path.as_ref();
     let style = progress_reader_style()?;
-    let pb = ProgressBar::new(input.metadata()?.len() as u64).with_finish(ProgressFinish::Abandon);
+    let pb = input_progress_bar(input)?;
     pb.set_prefix("Parsing koutput");
     pb.set_style(style);
 
@@ -97,15 +135,10 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
                                     continue 'chunk_loop;
                                 }
                             } else if field_index == 3 {
-                                // Field 4 (LCA): remainder after the last tab
-                                field_start += tab_pos + 1;
-                                if let Some(pos) = memchr(b'\t', &line[field_start ..]) {
-                                    lca = &line[field_start .. (field_start + pos)]
-                                } else {
-                                    lca = &line[field_start ..]
-                                };
+                                // Field 4 (LCA): the rest of the line, tabs included
+                                lca = &line[field_start + tab_pos + 1 ..];
                                 if let Some(ref exclude_matcher) = exclude_aho {
-                                    if exclude_matcher.find(lca).is_some() {
+                                    if exclude_matcher.is_match(lca) {
                                         continue 'chunk_loop;
                                     }
                                 }
@@ -145,13 +178,18 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
 
         // ─── reader Thread ─────────────────────────────────────
         let reader_handle = scope.spawn(move || -> Result<()> {
+            // Inputs of unknown size count the lines read instead of bytes
+            let (input_bar, lines_bar) = split_input_bar(Some(pb))?;
             let mut reader =
-                LineReader::with_capacity(BUFFER_SIZE, new_reader(input, BUFFER_SIZE, Some(pb))?);
+                LineReader::with_capacity(BUFFER_SIZE, new_reader(input, BUFFER_SIZE, input_bar)?);
             let mut reader_tx = BatchSender::with_capacity(batch_size, reader_tx);
             while let Some(record) = reader
                 .read_line()
                 .with_context(|| format!("(Reader) Failed to read line"))?
             {
+                if let Some(bar) = &lines_bar {
+                    bar.inc(1);
+                }
                 reader_tx
                     .send(record)
                     .with_context(|| format!("(Reader) Failed to send lines to Parser thread"))?;
@@ -171,9 +209,6 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
         reader_handle
             .join()
             .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))??;
-        Ok(koutput_rx
-            .into_iter()
-            .flatten()
-            .collect::<HashMap<Bytes, (Bytes, Bytes, Bytes)>>())
+        Ok(collect_records(koutput_rx.into_iter().flatten(), keep_all))
     })
 }
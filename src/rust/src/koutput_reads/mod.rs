@@ -1,16 +1,22 @@
-use aho_corasick::{AhoCorasick, AhoCorasickKind};
-use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
 use extendr_api::prelude::*;
-use libdeflater::CompressionLvl;
 use rustc_hash::FxHashMap as HashMap;
 use rustc_hash::FxHashSet as HashSet;
 
+mod cache;
+mod koutmap;
 mod koutput;
 mod reads;
 
+use crate::error::RError;
 use crate::kreport::taxonomy_kreport;
-use crate::seq_tag::robj_to_tag_ranges;
+use crate::lca_exclude::{ExcludeMatcher, ExcludeOptions};
+use crate::seq_tag::{robj_to_tag_ranges, TagRanges};
 use crate::utils::*;
+use crate::warnings::warn;
 
 #[extendr]
 fn koutput_reads(
@@ -29,10 +35,10 @@ fn koutput_reads(
     koutput_batch: usize,
     fastq_batch: usize,
     chunk_bytes: usize,
-    compression_level: i32,
+    compression_level: Option<i32>,
     nqueue: Option<usize>,
     threads: usize,
-) -> std::result::Result<(), String> {
+) -> std::result::Result<(), RError> {
     koutput_reads_internal(
         kreport,
         koutput,
@@ -50,7 +56,7 @@ fn koutput_reads(
         nqueue,
         threads,
     )
-    .map_err(|e| format!("{:?}", e))
+    .map_err(RError::from)
 }
 
 #[extendr]
@@ -68,16 +74,16 @@ fn pprof_koutput_reads(
     koutput_batch: usize,
     fastq_batch: usize,
     chunk_bytes: usize,
-    compression_level: i32,
+    compression_level: Option<i32>,
     nqueue: Option<usize>,
     threads: usize,
     pprof_file: &str,
-) -> std::result::Result<(), String> {
+) -> std::result::Result<(), RError> {
     let guard = pprof::ProfilerGuardBuilder::default()
         .frequency(2000)
         .build()
         .with_context(|| format!("cannot create profile guard"))
-        .map_err(|e| format!("{:?}", e))?;
+        .map_err(RError::from)?;
     let out = koutput_reads(
         kreport,
         koutput,
@@ -98,13 +104,13 @@ fn pprof_koutput_reads(
     if let Ok(report) = guard.report().build() {
         let file = std::fs::File::create(pprof_file)
             .with_context(|| format!("Failed to create file {}", pprof_file))
-            .map_err(|e| format!("{:?}", e))?;
+            .map_err(RError::from)?;
         let mut options = pprof::flamegraph::Options::default();
         options.image_width = Some(2500);
         report
             .flamegraph_with_options(file, &mut options)
             .with_context(|| format!("Failed to write flamegraph to {}", pprof_file))
-            .map_err(|e| format!("{:?}", e))?;
+            .map_err(RError::from)?;
     };
     out
 }
@@ -122,14 +128,112 @@ fn koutput_reads_internal(
     koutput_batch: usize,
     fastq_batch: usize,
     chunk_bytes: usize,
-    compression_level: i32,
+    compression_level: Option<i32>,
     nqueue: Option<usize>,
     threads: usize,
 ) -> Result<()> {
     let tag_ranges1 = robj_to_tag_ranges(&ranges1)?;
     let tag_ranges2 = robj_to_tag_ranges(&ranges2)?;
-    let compression_level = CompressionLvl::new(compression_level)
-        .map_err(|e| anyhow!("Invalid 'compression_level': {:?}", e))?;
+    let compression = Compression::new(Path::new(ofile), compression_level)?;
+    // Read Kraken2 output and extract matched records
+    let (koutmap, _) = koutput_map(
+        kreport,
+        koutput,
+        taxonomy,
+        exclude,
+        koutput_batch,
+        nqueue,
+        threads,
+        false,
+    )?;
+    koutmap_reads(
+        &koutmap,
+        fq1,
+        fq2,
+        ofile,
+        tag_ranges1,
+        tag_ranges2,
+        fastq_batch,
+        chunk_bytes,
+        compression,
+        nqueue,
+        threads,
+    )
+}
+
+/// Extract the reads of all records in an already parsed koutput map
+fn koutmap_reads(
+    koutmap: &HashMap<Bytes, (Bytes, Bytes, Bytes)>,
+    fq1: &str,
+    fq2: Option<&str>,
+    ofile: &str,
+    tag_ranges1: Option<TagRanges>,
+    tag_ranges2: Option<TagRanges>,
+    fastq_batch: usize,
+    chunk_bytes: usize,
+    compression: Compression,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> Result<()> {
+    if koutmap.is_empty() {
+        warn("No taxonomic matches found in the koutput file");
+        return Ok(());
+    }
+
+    // For each koutput row, we calculate kmer information
+    reads::parse_reads(
+        koutmap,
+        fq1,
+        fq2,
+        ofile,
+        tag_ranges1,
+        tag_ranges2,
+        fastq_batch,
+        chunk_bytes,
+        compression,
+        nqueue,
+        threads,
+    )?;
+    Ok(())
+}
+
+/// Parse the koutput records passing the taxonomy filter, keyed by sequence
+/// ID, with all the records of IDs on several lines if `keep_all`
+#[allow(clippy::too_many_arguments)]
+fn koutput_map(
+    kreport: &str,
+    koutput: &str,
+    taxonomy: Robj,
+    exclude: Robj,
+    batch_size: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+    keep_all: bool,
+) -> Result<koutput::KoutRecords> {
+    let (include_taxids, exclude_aho) = koutput_filter(kreport, taxonomy, exclude)?;
+    let include_sets = include_taxids
+        .iter()
+        .map(|x| x.as_slice())
+        .collect::<HashSet<&[u8]>>();
+    koutput::parse_koutput(
+        koutput,
+        include_sets,
+        exclude_aho,
+        batch_size,
+        nqueue,
+        threads,
+        keep_all,
+    )
+}
+
+/// Resolve the taxonomy filter into the set of included taxids (always with
+/// their descendants) and an optional matcher for excluded taxids in the LCA
+/// mapping field.
+fn koutput_filter(
+    kreport: &str,
+    taxonomy: Robj,
+    exclude: Robj,
+) -> Result<(Vec<Vec<u8>>, Option<ExcludeMatcher>)> {
     let exclude =
         robj_to_option_str(&exclude).with_context(|| format!("Failed to parse 'exclude'"))?;
     let kreports = taxonomy_kreport(kreport, taxonomy)?;
@@ -172,80 +276,34 @@ fn koutput_reads_internal(
         })
         .collect::<HashMap<&[u8], HashSet<&[u8]>>>();
 
-    let include_sets = kreports
+    let include_taxids = kreports
         .iter()
         // Always include the descendants
         .filter_map(|kr| taxid_to_descendants.get(kr.taxid.as_slice()))
         .flatten()
         .copied()
-        .collect::<HashSet<&[u8]>>();
+        .collect::<HashSet<&[u8]>>()
+        .into_iter()
+        .map(|x| x.to_vec())
+        .collect();
 
-    // A space-delimited list indicating the LCA mapping of each
-    // k-mer in the sequence(s). For example, "562:13 561:4 A:31 0:1 562:3" would indicate that:
-    //
-    // the first 13 k-mers mapped to taxonomy ID #562
-    // the next 4 k-mers mapped to taxonomy ID #561
-    // the next 31 k-mers contained an ambiguous nucleotide
-    // the next k-mer was not in the database
-    // the last 3 k-mers mapped to taxonomy ID #562
     let exclude_aho = exclude
-        .map(|v| {
-            let patterns: Vec<Vec<u8>> = v
-                .iter()
-                .map(|taxid| {
-                    let mut pattern = Vec::with_capacity(taxid.len() + 1); // estimated capacity
-                    pattern.extend_from_slice(taxid.as_bytes());
-                    pattern.push(b':');
-                    pattern
-                })
-                .collect();
-            AhoCorasick::builder()
-                .kind(Some(AhoCorasickKind::DFA))
-                .build(patterns)
-        })
+        .map(|taxids| ExcludeMatcher::new(&taxids, ExcludeOptions::default()))
         .transpose()?;
-
-    // Read Kraken2 output and extract matched records
-    let koutmap = koutput::parse_koutput(
-        koutput,
-        include_sets,
-        exclude_aho,
-        koutput_batch,
-        nqueue,
-        threads,
-    )?;
-
-    if koutmap.is_empty() {
-        println!("No taxonomic matches found in the koutput file.");
-        return Ok(());
-    }
-
-    // For each koutput row, we calculate kmer information
-    reads::parse_reads(
-        &koutmap,
-        fq1,
-        fq2,
-        ofile,
-        tag_ranges1,
-        tag_ranges2,
-        fastq_batch,
-        chunk_bytes,
-        compression_level,
-        nqueue,
-        threads,
-    )?;
-    Ok(())
+    Ok((include_taxids, exclude_aho))
 }
 
 #[cfg(not(feature = "bench"))]
 extendr_module! {
     mod koutput_reads;
+    use koutmap;
     fn koutput_reads;
 }
 
 #[cfg(feature = "bench")]
 extendr_module! {
     mod koutput_reads;
+    use koutmap;
     fn koutput_reads;
     fn pprof_koutput_reads;
 }
@@ -1,7 +1,6 @@
 use anyhow::Result;
 use bytes::Bytes;
-use indicatif::{MultiProgress, ProgressBar, ProgressFinish, ProgressStyle};
-use libdeflater::CompressionLvl;
+use indicatif::ProgressStyle;
 use rustc_hash::FxHashMap as HashMap;
 
 use crate::seq_tag::*;
@@ -20,29 +19,24 @@ pub(super) fn parse_reads(
     tag_ranges2: Option<TagRanges>,
     batch_size: usize,
     chunk_bytes: usize,
-    compression_level: CompressionLvl,
+    compression: Compression,
     nqueue: Option<usize>,
     threads: usize,
 ) -> Result<()> {
     let reader_style = progress_reader_style()?;
-    let progress = MultiProgress::new();
-    let reader_pb1 = progress.add(
-        ProgressBar::new(std::fs::metadata(fq1)?.len() as u64).with_finish(ProgressFinish::Abandon),
-    );
+    let progress = new_progress();
+    let reader_pb1 = progress.add(input_progress_bar(fq1)?);
     reader_pb1.set_prefix("Reading fq1");
     reader_pb1.set_style(reader_style.clone());
 
-    let matching_pb = ProgressBar::no_length().with_finish(ProgressFinish::Abandon);
+    let matching_pb = output_progress_bar();
     matching_pb.set_style(ProgressStyle::with_template(
         "Matching {human_len:.bold.cyan/blue} reads {spinner:.green}",
     )?);
 
     let threads = threads.max(1); // always use at least one thread
     if let Some(fq2) = fq2 {
-        let reader_pb2 = progress.add(
-            ProgressBar::new(std::fs::metadata(fq2)?.len() as u64)
-                .with_finish(ProgressFinish::Abandon),
-        );
+        let reader_pb2 = progress.add(input_progress_bar(fq2)?);
         reader_pb2.set_prefix("Reading fq2");
         reader_pb2.set_style(reader_style);
         let matching_pb = progress.add(matching_pb);
@@ -58,7 +52,7 @@ pub(super) fn parse_reads(
             &tag_ranges2,
             batch_size,
             chunk_bytes,
-            compression_level,
+            compression,
             nqueue,
             threads,
         )
@@ -73,7 +67,7 @@ pub(super) fn parse_reads(
             &tag_ranges1,
             batch_size,
             chunk_bytes,
-            compression_level,
+            compression,
             nqueue,
             threads,
         )
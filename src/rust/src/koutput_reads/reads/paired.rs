@@ -7,12 +7,12 @@ use bytes::BufMut;
 use bytes::{Bytes, BytesMut};
 use crossbeam_channel::{Receiver, Sender};
 use indicatif::ProgressBar;
-use libdeflater::{CompressionLvl, Compressor};
 use rustc_hash::FxHashMap as HashMap;
 
 use super::stream::extract_tags_from_desc;
 use super::stream::RecordHandler;
 use crate::batchsender::BatchSender;
+use crate::error::ErrorKind;
 use crate::fastq_reader::*;
 use crate::fastq_record::{FastqParseError, FastqRecord};
 use crate::koutput_reads::reads::stream::KoutreadStream;
@@ -31,7 +31,7 @@ pub(crate) fn parse_paired_read<P: AsRef<Path> + ?Sized>(
     tag_ranges2: &Option<TagRanges>,
     batch_size: usize,
     chunk_bytes: usize,
-    compression_level: CompressionLvl,
+    compression: Compression,
     nqueue: Option<usize>,
     threads: usize,
 ) -> Result<()> {
@@ -67,7 +67,7 @@ pub(crate) fn parse_paired_read<P: AsRef<Path> + ?Sized>(
                     .map_err(|e| anyhow!("(Writer) Failed to write to output: {}", e))?;
             }
             writer
-                .flush()
+                .complete()
                 .map_err(|e| anyhow!("(Writer) Failed to flush writer: {}", e))?;
             Ok(())
         });
@@ -82,18 +82,18 @@ pub(crate) fn parse_paired_read<P: AsRef<Path> + ?Sized>(
                 let record_handler = PairedRecordHandle::new(tag_ranges1, tag_ranges2);
                 let mut stream = KoutreadStream::with_capacity(chunk_bytes, tx, record_handler);
                 if gzip {
-                    let compressor = Compressor::new(compression_level);
+                    let compressor = compression.compressor();
                     stream.set_compressor(Some(compressor));
                 }
                 while let Ok((records1, records2)) = rx.recv() {
                     // Initialize a thread-local batch sender for matching records
                     for (record1, record2) in zip(records1, records2) {
                         if record1.id != record2.id {
-                            return Err(anyhow!("{}", FastqParseError::FastqPairError {
+                            return Err(anyhow::Error::new(FastqParseError::FastqPairError {
                                 read1_id: String::from_utf8_lossy(&record1.id).to_string(),
                                 read2_id: String::from_utf8_lossy(&record2.id).to_string(),
                                 read1_pos: None,
-                                read2_pos: None
+                                read2_pos: None,
                             }));
                         }
                         if let Some((length, taxid, lca)) = koutmap.get(&record1.id) {
@@ -123,13 +123,13 @@ pub(crate) fn parse_paired_read<P: AsRef<Path> + ?Sized>(
                 let (records1, records2) = match (reader1_rx.recv(), reader2_rx.recv()) {
                     (Ok(rec1), Ok(rec2)) => (rec1, rec2),
                     (Err(_), Ok(_)) => {
-                        return Err(anyhow!(
-                            "(Reader collect) FASTQ pairing error: read1 channel closed before read2"
+                        return Err(ErrorKind::Pairing.error(
+                            "(Reader collect) FASTQ pairing error: read1 channel closed before read2",
                         ));
                     }
                     (Ok(_), Err(_)) => {
-                        return Err(anyhow!(
-                            "(Reader collect) FASTQ pairing error: read2 channel closed before read1"
+                        return Err(ErrorKind::Pairing.error(
+                            "(Reader collect) FASTQ pairing error: read2 channel closed before read1",
                         ));
                     }
                     (Err(_), Err(_)) => {
@@ -137,7 +137,11 @@ pub(crate) fn parse_paired_read<P: AsRef<Path> + ?Sized>(
                     }
                 };
                 if records1.len() != records2.len() {
-                    return Err(anyhow!("(Reader collect) FASTQ pairing error: record count mismatch (read1: {}, read2: {})", records1.len(), records2.len()));
+                    return Err(ErrorKind::Pairing.error(format!(
+                        "(Reader collect) FASTQ pairing error: record count mismatch (read1: {}, read2: {})",
+                        records1.len(),
+                        records2.len()
+                    )));
                 }
                 reader_tx.send((records1, records2)).map_err(|e| {
                     anyhow!(
@@ -251,21 +255,19 @@ impl<'a> RecordHandler for PairedRecordHandle<'a> {
                 .trim()
                 .parse::<usize>()
                 .map_err(|e| {
-                    anyhow!(
+                    ErrorKind::Parse.error(format!(
                         "(Paired read1) Invalid length in koutput for ID {:?}: {}",
-                        record.0.id,
-                        e
-                    )
+                        record.0.id, e
+                    ))
                 })?;
             let len2 = std::str::from_utf8(l2)?
                 .trim()
                 .parse::<usize>()
                 .map_err(|e| {
-                    anyhow!(
+                    ErrorKind::Parse.error(format!(
                         "(Paired Read2) Invalid length in koutput for ID {:?}: {}",
-                        record.1.id,
-                        e
-                    )
+                        record.1.id, e
+                    ))
                 })?;
             if record.0.seq.len() != len1 || record.1.seq.len() != len2 {
                 return Err(anyhow!(
@@ -282,11 +284,10 @@ impl<'a> RecordHandler for PairedRecordHandle<'a> {
                 .trim()
                 .parse::<usize>()
                 .map_err(|e| {
-                    anyhow!(
+                    ErrorKind::Parse.error(format!(
                         "(Read2) Invalid length in koutput for ID {:?}: {}",
-                        record.1.id,
-                        e
-                    )
+                        record.1.id, e
+                    ))
                 })?;
             if expected_len != record.1.seq.len() {
                 return Err(anyhow!(
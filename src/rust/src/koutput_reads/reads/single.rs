@@ -5,12 +5,12 @@ use anyhow::{anyhow, Result};
 use bytes::{Bytes, BytesMut};
 use crossbeam_channel::{Receiver, Sender};
 use indicatif::ProgressBar;
-use libdeflater::{CompressionLvl, Compressor};
 use rustc_hash::FxHashMap as HashMap;
 
 use super::stream::extract_tags_from_desc;
 use super::stream::RecordHandler;
 use crate::batchsender::BatchSender;
+use crate::error::ErrorKind;
 use crate::fastq_reader::*;
 use crate::fastq_record::FastqRecord;
 use crate::seq_tag::*;
@@ -25,7 +25,7 @@ pub(crate) fn parse_single_read<P: AsRef<Path> + ?Sized>(
     tag_ranges: &Option<TagRanges>,
     batch_size: usize,
     chunk_bytes: usize,
-    compression_level: CompressionLvl,
+    compression: Compression,
     nqueue: Option<usize>,
     threads: usize,
 ) -> Result<()> {
@@ -54,7 +54,7 @@ pub(crate) fn parse_single_read<P: AsRef<Path> + ?Sized>(
                     .map_err(|e| anyhow!("(Writer) Failed to write to output: {}", e))?;
             }
             writer
-                .flush()
+                .complete()
                 .map_err(|e| anyhow!("(Writer) Failed to flush writer: {}", e))?;
             Ok(())
         });
@@ -73,7 +73,7 @@ pub(crate) fn parse_single_read<P: AsRef<Path> + ?Sized>(
                     record_handler,
                 );
                 if gzip {
-                    let compressor = Compressor::new(compression_level);
+                    let compressor = compression.compressor();
                     stream.set_compressor(Some(compressor));
                 }
                 while let Ok(records) = rx.recv() {
@@ -159,7 +159,12 @@ impl<'a> RecordHandler for SinlgeRecordHandle<'a> {
         let expected_len = std::str::from_utf8(length)?
             .trim()
             .parse::<usize>()
-            .map_err(|e| anyhow!("Invalid length in koutput for ID {:?}: {}", record.id, e))?;
+            .map_err(|e| {
+                ErrorKind::Parse.error(format!(
+                    "Invalid length in koutput for ID {:?}: {}",
+                    record.id, e
+                ))
+            })?;
 
         if expected_len != record.seq.len() {
             return Err(anyhow!(
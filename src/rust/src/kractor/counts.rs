@@ -0,0 +1,434 @@
+use bytes::Bytes;
+use extendr_api::prelude::*;
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::fastq_record::FastqRecord;
+use crate::lca_exclude::ExcludeMatcher;
+use crate::memory::MemoryUsage;
+use crate::occupancy::QueueOccupancy;
+use crate::read_stats::{ReadStats, ReadStatsTables};
+use crate::utils::*;
+
+/// What became of a read in a kractor pass
+#[derive(Clone, Copy)]
+pub(crate) enum ReadFate {
+    Matched,
+    // Matching the taxa, but dropped by a read filter
+    Dropped,
+    Unmatched,
+}
+
+/// The filter stage at which a Kraken2 output line was dropped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum DropStage {
+    Unclassified,
+    // Classified to a taxid outside of the selected taxa
+    Taxa,
+    // Excluded by a taxid of the LCA mapping
+    Exclude,
+    // Not passing the regex filters
+    Regex,
+    // Missing fields or an unterminated "(taxid N)" field
+    Malformed,
+}
+
+/// Number of Kraken2 output lines dropped at each filter stage
+#[derive(Default, Clone, Copy)]
+pub(crate) struct Attrition {
+    unclassified: usize,
+    taxa: usize,
+    exclude: usize,
+    regex: usize,
+    malformed: usize,
+}
+
+impl Attrition {
+    fn add(&mut self, stage: DropStage) {
+        match stage {
+            DropStage::Unclassified => self.unclassified += 1,
+            DropStage::Taxa => self.taxa += 1,
+            DropStage::Exclude => self.exclude += 1,
+            DropStage::Regex => self.regex += 1,
+            DropStage::Malformed => self.malformed += 1,
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.unclassified += other.unclassified;
+        self.taxa += other.taxa;
+        self.exclude += other.exclude;
+        self.regex += other.regex;
+        self.malformed += other.malformed;
+    }
+
+    fn into_list(self) -> List {
+        list!(
+            stage = ["unclassified", "taxa", "exclude", "regex", "malformed"],
+            dropped = [
+                self.unclassified,
+                self.taxa,
+                self.exclude,
+                self.regex,
+                self.malformed
+            ]
+            .map(|x| x as f64)
+        )
+    }
+}
+
+/// Number of lines matched by each included taxid and excluded by each
+/// `exclude` taxid, to check that the taxa lists are spelled right
+#[derive(Default, Clone)]
+pub(crate) struct FilterHits {
+    include: HashMap<Vec<u8>, usize>,
+    // In the order of the patterns of the exclude matcher
+    exclude: Vec<(Vec<u8>, usize)>,
+}
+
+impl FilterHits {
+    pub(crate) fn new<T: AsRef<[u8]>>(include: &[Vec<u8>], exclude: &[T]) -> Self {
+        Self {
+            include: include.iter().map(|taxid| (taxid.clone(), 0)).collect(),
+            exclude: exclude
+                .iter()
+                .map(|taxid| (taxid.as_ref().to_vec(), 0))
+                .collect(),
+        }
+    }
+
+    fn add_include(&mut self, taxid: &[u8]) {
+        if let Some(count) = self.include.get_mut(taxid) {
+            *count += 1;
+        }
+    }
+
+    /// Count a line excluded by `exclude_aho` once for each taxid found in its
+    /// LCA mapping
+    fn add_exclude(&mut self, exclude_aho: &ExcludeMatcher, lca: &[u8]) {
+        let mut patterns = exclude_aho.patterns(lca).collect::<Vec<_>>();
+        patterns.sort_unstable();
+        patterns.dedup();
+        for pattern in patterns {
+            if let Some((_, count)) = self.exclude.get_mut(pattern) {
+                *count += 1;
+            }
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        for (taxid, count) in other.include {
+            *self.include.entry(taxid).or_insert(0) += count;
+        }
+        if self.exclude.is_empty() {
+            self.exclude = other.exclude;
+        } else {
+            for ((_, count), (_, other)) in self.exclude.iter_mut().zip(other.exclude) {
+                *count += other;
+            }
+        }
+    }
+
+    fn into_list(self) -> List {
+        let mut include = self.include.into_iter().collect::<Vec<_>>();
+        include.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let (taxids, matched): (Vec<_>, Vec<_>) = include.into_iter().unzip();
+        let (patterns, excluded): (Vec<_>, Vec<_>) = self.exclude.into_iter().unzip();
+        list!(
+            include = list!(
+                taxid = u8_to_list_rstr(taxids),
+                matched = matched.into_iter().map(|x| x as f64).collect::<Vec<_>>()
+            ),
+            exclude = list!(
+                taxid = u8_to_list_rstr(patterns),
+                excluded = excluded.into_iter().map(|x| x as f64).collect::<Vec<_>>()
+            )
+        )
+    }
+}
+
+/// Statistics of the reads of one mate, by fate
+#[derive(Default, Clone)]
+pub(crate) struct FateStats {
+    matched: ReadStats,
+    dropped: ReadStats,
+    unmatched: ReadStats,
+}
+
+impl FateStats {
+    fn merge(&mut self, other: Self) {
+        self.matched.merge(other.matched);
+        self.dropped.merge(other.dropped);
+        self.unmatched.merge(other.unmatched);
+    }
+}
+
+/// Match statistics collected by a single kractor pass.
+///
+/// Each parser thread keeps its own `KractorCounts` and the results are merged
+/// once all threads have joined, so no synchronization is needed while
+/// counting.
+#[derive(Default)]
+pub(crate) struct KractorCounts {
+    pub(crate) records: usize,
+    pub(crate) matched: usize,
+    // Number of matched records per taxid, only collected on request
+    pub(crate) taxa: Option<HashMap<Vec<u8>, usize>>,
+    // Number of matched records dropped by read filters (barcode allow-list,
+    // GC content), if any
+    pub(crate) dropped: Option<usize>,
+    // Number of unmatched Kraken2 output lines by filter stage, if tracked
+    pub(crate) attrition: Option<Attrition>,
+    // Hits of each included and excluded taxid, if tracked
+    pub(crate) filters: Option<FilterHits>,
+    // Statistics of the reads of each mate, only collected on request
+    pub(crate) stats: Option<Vec<FateStats>>,
+    // Occupancy of the queues between the threads of the pass, if sampled
+    pub(crate) queues: Option<Vec<QueueOccupancy>>,
+    // Resident memory of the process over the run, if sampled
+    pub(crate) memory: Option<MemoryUsage>,
+}
+
+impl KractorCounts {
+    pub(crate) fn new(by_taxon: bool) -> Self {
+        Self {
+            records: 0,
+            matched: 0,
+            taxa: if by_taxon {
+                Some(HashMap::default())
+            } else {
+                None
+            },
+            dropped: None,
+            attrition: None,
+            filters: None,
+            stats: None,
+            queues: None,
+            memory: None,
+        }
+    }
+
+    /// Collect statistics of the reads of each mate
+    pub(crate) fn with_stats(mut self, mates: usize) -> Self {
+        self.stats = Some(vec![Default::default(); mates]);
+        self
+    }
+
+    /// Add the records of a read (one per mate) to the statistics, if
+    /// collected
+    pub(crate) fn add_stats(&mut self, fate: ReadFate, records: &[&FastqRecord<Bytes>]) {
+        if let Some(stats) = self.stats.as_mut() {
+            for (stats, record) in stats.iter_mut().zip(records) {
+                let stats = match fate {
+                    ReadFate::Matched => &mut stats.matched,
+                    ReadFate::Dropped => &mut stats.dropped,
+                    ReadFate::Unmatched => &mut stats.unmatched,
+                };
+                stats.add(&record.seq, &record.qual);
+            }
+        }
+    }
+
+    /// Count the matched records dropped by read filters
+    pub(crate) fn with_dropped(mut self) -> Self {
+        self.dropped = Some(0);
+        self
+    }
+
+    pub(crate) fn add_dropped(&mut self) {
+        if let Some(dropped) = self.dropped.as_mut() {
+            *dropped += 1;
+        }
+    }
+
+    /// Count the unmatched Kraken2 output lines by filter stage
+    pub(crate) fn with_attrition(mut self) -> Self {
+        self.attrition = Some(Attrition::default());
+        self
+    }
+
+    pub(crate) fn add_attrition(&mut self, stage: DropStage) {
+        if let Some(attrition) = self.attrition.as_mut() {
+            attrition.add(stage);
+        }
+    }
+
+    /// Count the hits of each included and excluded taxid, starting from
+    /// `filters`
+    pub(crate) fn with_filters(mut self, filters: FilterHits) -> Self {
+        self.filters = Some(filters);
+        self
+    }
+
+    /// Count a line excluded by `exclude_aho` for the taxids found in its LCA
+    /// mapping, if tracked
+    pub(crate) fn add_excluded(&mut self, exclude_aho: &ExcludeMatcher, lca: &[u8]) {
+        if let Some(filters) = self.filters.as_mut() {
+            filters.add_exclude(exclude_aho, lca);
+        }
+    }
+
+    pub(crate) fn add_match(&mut self, taxid: Option<&[u8]>) {
+        self.matched += 1;
+        if let (Some(filters), Some(taxid)) = (self.filters.as_mut(), taxid) {
+            filters.add_include(taxid);
+        }
+        if let (Some(taxa), Some(taxid)) = (self.taxa.as_mut(), taxid) {
+            if let Some(count) = taxa.get_mut(taxid) {
+                *count += 1;
+            } else {
+                taxa.insert(taxid.to_vec(), 1);
+            }
+        }
+    }
+
+    pub(crate) fn merge(&mut self, other: Self) {
+        self.records += other.records;
+        self.matched += other.matched;
+        if let Some(dropped) = other.dropped {
+            *self.dropped.get_or_insert(0) += dropped;
+        }
+        if let Some(attrition) = other.attrition {
+            self.attrition
+                .get_or_insert_with(Default::default)
+                .merge(attrition);
+        }
+        if let Some(filters) = other.filters {
+            self.filters
+                .get_or_insert_with(Default::default)
+                .merge(filters);
+        }
+        if let Some(other) = other.stats {
+            let stats = self
+                .stats
+                .get_or_insert_with(|| vec![Default::default(); other.len()]);
+            for (stats, other) in stats.iter_mut().zip(other) {
+                stats.merge(other);
+            }
+        }
+        if let (Some(taxa), Some(other)) = (self.taxa.as_mut(), other.taxa) {
+            for (taxid, count) in other {
+                *taxa.entry(taxid).or_insert(0) += count;
+            }
+        }
+    }
+
+    /// Convert to an R list. `inputs` names the files the counts refer to; for
+    /// paired reads, both mates share the same counts.
+    pub(crate) fn into_list(self, inputs: &[&str]) -> List {
+        let taxa = self.taxa.map(|taxa| {
+            let mut taxa = taxa.into_iter().collect::<Vec<_>>();
+            taxa.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            let (taxids, counts): (Vec<_>, Vec<_>) = taxa.into_iter().unzip();
+            list!(
+                taxid = u8_to_list_rstr(taxids),
+                matched = counts.into_iter().map(|x| x as f64).collect::<Vec<_>>()
+            )
+        });
+        let with_dropped = self.dropped.is_some();
+        let stats = self.stats.map(|stats| {
+            let mut tables = ReadStatsTables::default();
+            for (input, stats) in inputs.iter().zip(stats) {
+                // Reads remaining after each stage of filtering
+                let mut stage = stats.matched.clone();
+                if with_dropped {
+                    tables.push_stage(input, "filters", stage.clone());
+                    stage.merge(stats.dropped.clone());
+                }
+                tables.push_stage(input, "taxa", stage.clone());
+                stage.merge(stats.unmatched.clone());
+                tables.push_stage(input, "input", stage);
+
+                tables.push(input, "matched", stats.matched);
+                if with_dropped {
+                    tables.push(input, "dropped", stats.dropped);
+                }
+                tables.push(input, "unmatched", stats.unmatched);
+            }
+            tables.into_list()
+        });
+        list!(
+            input = inputs.to_vec(),
+            records = vec![self.records as f64; inputs.len()],
+            matched = vec![self.matched as f64; inputs.len()],
+            dropped = self
+                .dropped
+                .map_or_else(|| r!(NULL), |x| Robj::from(vec![x as f64; inputs.len()])),
+            taxa = taxa.map_or_else(|| r!(NULL), Robj::from),
+            attrition = self
+                .attrition
+                .map_or_else(|| r!(NULL), |x| Robj::from(x.into_list())),
+            filters = self
+                .filters
+                .map_or_else(|| r!(NULL), |x| Robj::from(x.into_list())),
+            stats = stats.map_or_else(|| r!(NULL), Robj::from),
+            queues = self
+                .queues
+                .map_or_else(|| r!(NULL), |x| Robj::from(QueueOccupancy::into_list(x))),
+            memory = self
+                .memory
+                .map_or_else(|| r!(NULL), |x| Robj::from(x.into_list()))
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_counts() {
+        let mut left = KractorCounts::new(true);
+        left.records = 3;
+        left.add_match(Some(b"562"));
+        left.add_match(Some(b"562"));
+        let mut right = KractorCounts::new(true);
+        right.records = 2;
+        right.add_match(Some(b"561"));
+        right.add_match(Some(b"562"));
+        left.merge(right);
+        assert_eq!(left.records, 5);
+        assert_eq!(left.matched, 4);
+        let taxa = left.taxa.unwrap();
+        assert_eq!(taxa.get(b"562".as_slice()), Some(&3));
+        assert_eq!(taxa.get(b"561".as_slice()), Some(&1));
+
+        let mut counts = KractorCounts::new(false);
+        counts.add_match(Some(b"562"));
+        assert_eq!(counts.matched, 1);
+        assert!(counts.taxa.is_none());
+        assert!(counts.dropped.is_none());
+
+        let mut counts = KractorCounts::new(false).with_dropped();
+        counts.add_dropped();
+        counts.merge(KractorCounts::new(false).with_dropped());
+        assert_eq!(counts.dropped, Some(1));
+
+        let mut counts = KractorCounts::new(false).with_attrition();
+        counts.add_attrition(DropStage::Taxa);
+        let mut other = KractorCounts::new(false).with_attrition();
+        other.add_attrition(DropStage::Taxa);
+        other.add_attrition(DropStage::Malformed);
+        counts.merge(other);
+        let attrition = counts.attrition.unwrap();
+        assert_eq!((attrition.taxa, attrition.malformed), (2, 1));
+        assert_eq!((attrition.unclassified, attrition.exclude), (0, 0));
+
+        let include = [b"562".to_vec(), b"1280".to_vec()];
+        let exclude_aho = ExcludeMatcher::new(&["9606", "606"], Default::default()).unwrap();
+        let filters = FilterHits::new(&include, &["9606", "606"]);
+        let mut counts = KractorCounts::new(false).with_filters(filters.clone());
+        counts.add_match(Some(b"562"));
+        counts.add_excluded(&exclude_aho, b"562:3 9606:2 9606:4");
+        let mut other = KractorCounts::new(false).with_filters(filters);
+        other.add_match(Some(b"562"));
+        other.add_excluded(&exclude_aho, b"1606:2");
+        counts.merge(other);
+        let filters = counts.filters.unwrap();
+        assert_eq!(filters.include.get(b"562".as_slice()), Some(&2));
+        assert_eq!(filters.include.get(b"1280".as_slice()), Some(&0));
+        assert_eq!(
+            filters.exclude,
+            [(b"9606".to_vec(), 1), (b"606".to_vec(), 2)]
+        );
+    }
+}
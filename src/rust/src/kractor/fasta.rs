@@ -0,0 +1,328 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use bytes::{BufMut, BytesMut};
+use crossbeam_channel::{Receiver, Sender};
+use extendr_api::prelude::*;
+use indicatif::ProgressBar;
+use rustc_hash::FxHashSet as HashSet;
+
+use crate::batchsender::BatchSender;
+use crate::kractor::counts::KractorCounts;
+use crate::kractor::koutput::kractor_filter;
+use crate::part_writer::{PartCounter, PartWriter};
+use crate::reader::LineReader;
+use crate::utils::*;
+
+/// Extract the sequences of the selected taxa from FASTA reference libraries,
+/// such as the `library/*.fna` files of a Kraken2 database, whose headers
+/// carry the taxid as `kraken:taxid|N`. Taxa are selected from the Kraken2
+/// report just like [`kractor_koutput()`](super::koutput::kractor_koutput).
+pub(crate) fn kractor_fasta(
+    kreport: &str,
+    fasta: &str,
+    ofile: Option<&str>,
+    dry_run: bool,
+    taxonomy: Robj,
+    ranks: Robj,
+    taxa: Robj,
+    taxids: Robj,
+    descendants: bool,
+    by_taxon: bool,
+    compression_level: Option<i32>,
+    max_file_bytes: Option<u64>,
+    batch_size: usize,
+    chunk_bytes: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> Result<KractorCounts> {
+    let ofile = if dry_run {
+        None
+    } else {
+        Some(ofile.ok_or_else(|| anyhow!("No output file specified."))?)
+    };
+    // Sequences carry no LCA mapping, no taxid can be excluded by it
+    let (include_taxids, _) = kractor_filter(
+        kreport,
+        taxonomy,
+        ranks,
+        taxa,
+        taxids,
+        Robj::from(()),
+        Default::default(),
+        descendants,
+        None,
+    )?;
+    let include_sets = include_taxids
+        .iter()
+        .map(|x| x.as_slice())
+        .collect::<HashSet<&[u8]>>();
+    let progress = new_progress();
+    let pb1 = progress.add(input_progress_bar(fasta)?);
+    pb1.set_prefix("Reading fasta");
+    pb1.set_style(progress_reader_style()?);
+    let pb2 = ofile
+        .map(|_| -> Result<ProgressBar> {
+            let pb2 = progress.add(output_progress_bar());
+            pb2.set_prefix("Writing fasta");
+            pb2.set_style(progress_writer_style()?);
+            Ok(pb2)
+        })
+        .transpose()?;
+
+    parse_fasta(
+        fasta,
+        Some(pb1),
+        ofile,
+        pb2,
+        include_sets,
+        by_taxon,
+        compression_level,
+        max_file_bytes,
+        batch_size,
+        chunk_bytes,
+        nqueue,
+        threads,
+    )
+}
+
+fn parse_fasta<P: AsRef<Path> + ?Sized>(
+    input_path: &P,
+    input_bar: Option<ProgressBar>,
+    output_path: Option<&P>,
+    output_bar: Option<ProgressBar>,
+    include_sets: HashSet<&[u8]>,
+    by_taxon: bool,
+    compression_level: Option<i32>,
+    max_file_bytes: Option<u64>,
+    batch_size: usize,
+    chunk_bytes: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> Result<KractorCounts> {
+    let input: &Path = input_path.as_ref();
+    // Without an output file, sequences are only counted (dry run)
+    let output: Option<&Path> = output_path.map(|x| x.as_ref());
+    let compression = Compression::of(output, compression_level)?;
+
+    std::thread::scope(|scope| -> Result<KractorCounts> {
+        let (writer_tx, writer_rx): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = new_channel(nqueue);
+        let (reader_tx, reader_rx): (Sender<Vec<BytesMut>>, Receiver<Vec<BytesMut>>) =
+            new_channel(nqueue);
+
+        // ─── Writer Thread ─────────────────────────────────────
+        let writer_handle = output.map(|output| {
+            scope.spawn(move || -> Result<()> {
+                let mut counter = PartCounter::new(max_file_bytes, 1);
+                let mut writer = PartWriter::new(output, max_file_bytes, chunk_bytes, output_bar);
+                for chunk in writer_rx {
+                    writer
+                        .write_part(counter.assign(&[chunk.len()]), &chunk)
+                        .with_context(|| {
+                            format!("(Writer) Failed to write Fasta records to output")
+                        })?;
+                }
+                writer
+                    .finish()
+                    .with_context(|| format!("(Writer) Failed to flush writer"))?;
+                Ok(())
+            })
+        });
+
+        // ─── Parser Thread ─────────────────────────────────────
+        // Filters whole records (header and sequence lines) by the taxid of
+        // their header
+        let mut parser_handles = Vec::with_capacity(threads);
+        let gzip = output.map_or(false, gz_compressed);
+        let dry_run = output.is_none();
+        for _ in 0 .. threads {
+            let rx = reader_rx.clone();
+            let tx = writer_tx.clone();
+            let include_sets = &include_sets;
+            let handle = scope.spawn(move || -> Result<KractorCounts> {
+                let mut counts = KractorCounts::new(by_taxon);
+                let mut pool: Vec<u8> = Vec::with_capacity(if dry_run { 0 } else { chunk_bytes });
+                let mut compressor = compression.compressor();
+                while let Ok(records) = rx.recv() {
+                    counts.records += records.len();
+                    for record in records {
+                        let header =
+                            &record[.. memchr::memchr(b'\n', &record).unwrap_or(record.len())];
+                        let Some(taxid) = kraken_header_taxid(header) else {
+                            continue;
+                        };
+                        if !include_sets.contains(taxid) {
+                            continue;
+                        }
+                        if by_taxon {
+                            counts.add_match(Some(taxid));
+                        } else {
+                            counts.matched += 1;
+                        }
+                        if dry_run {
+                            continue;
+                        }
+                        if !pool.is_empty() && pool.capacity() - pool.len() < record.len() {
+                            let mut pack = Vec::with_capacity(chunk_bytes);
+                            std::mem::swap(&mut pool, &mut pack);
+                            if gzip {
+                                pack = gzip_pack(&pack, &mut compressor)?
+                            }
+                            tx.send(pack).with_context(|| {
+                                format!("(Parser) Failed to send parsed records to Writer thread")
+                            })?;
+                        }
+                        pool.extend_from_slice(&record);
+                    }
+                }
+                if !pool.is_empty() {
+                    let pack = if gzip {
+                        gzip_pack(&pool, &mut compressor)?
+                    } else {
+                        pool
+                    };
+                    tx.send(pack).with_context(|| {
+                        format!("(Parser) Failed to send parsed records to Writer thread")
+                    })?;
+                };
+                Ok(counts)
+            });
+            parser_handles.push(handle);
+        }
+        drop(reader_rx);
+        drop(writer_tx);
+
+        // ─── reader Thread ─────────────────────────────────────
+        // Gathers the lines of each record, sequences may span many lines
+        let reader_handle = scope.spawn(move || -> Result<()> {
+            let mut reader =
+                LineReader::with_capacity(BUFFER_SIZE, new_reader(input, BUFFER_SIZE, input_bar)?);
+            let mut reader_tx = BatchSender::with_capacity(batch_size, reader_tx);
+            let mut record = BytesMut::new();
+            while let Some(line) = reader
+                .read_line()
+                .with_context(|| format!("(Reader) Failed to read line"))?
+            {
+                if line.first() == Some(&b'>') {
+                    if !record.is_empty() {
+                        reader_tx.send(record.split()).with_context(|| {
+                            format!("(Reader) Failed to send records to Parser thread")
+                        })?;
+                    }
+                } else if record.is_empty() {
+                    if line.iter().all(|b| b.is_ascii_whitespace()) {
+                        continue;
+                    }
+                    return Err(anyhow!(
+                        "(Reader) Invalid Fasta file: sequence found before any header"
+                    ));
+                }
+                record.extend_from_slice(&line);
+                record.put_u8(b'\n');
+            }
+            if !record.is_empty() {
+                reader_tx
+                    .send(record)
+                    .with_context(|| format!("(Reader) Failed to send records to Parser thread"))?;
+            }
+            reader_tx
+                .flush()
+                .with_context(|| format!("(Reader) Failed to flush records to Parser thread"))?;
+            Ok(())
+        });
+
+        // ─── Join Threads and Propagate Errors ────────────────
+        if let Some(writer_handle) = writer_handle {
+            writer_handle
+                .join()
+                .map_err(|e| anyhow!("(Writer) thread panicked: {:?}", e))??;
+        }
+        let mut counts = KractorCounts::new(by_taxon);
+        for handler in parser_handles {
+            counts.merge(
+                handler
+                    .join()
+                    .map_err(|e| anyhow!("(Parser) thread panicked: {:?}", e))??,
+            );
+        }
+        reader_handle
+            .join()
+            .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))??;
+        Ok(counts)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_kraken_header_taxid() {
+        assert_eq!(
+            kraken_header_taxid(b">kraken:taxid|562|NC_000913.3 Escherichia coli"),
+            Some(b"562".as_slice())
+        );
+        assert_eq!(
+            kraken_header_taxid(b"@read1 kraken:taxid|9606"),
+            Some(b"9606".as_slice())
+        );
+        assert_eq!(kraken_header_taxid(b">NC_000913.3"), None);
+        assert_eq!(kraken_header_taxid(b">kraken:taxid|"), None);
+    }
+
+    #[test]
+    fn test_parse_fasta() -> Result<()> {
+        let temp = tempdir()?;
+        let input_path = temp.path().join("library.fna");
+        let output_path = temp.path().join("out.fna");
+        fs::write(
+            &input_path,
+            ">kraken:taxid|562|NC_1 E. coli\nACGT\nACGT\n\
+             >kraken:taxid|9606|NC_2 Human\nTTTT\n\
+             >kraken:taxid|562|NC_3 E. coli plasmid\nGG\n",
+        )?;
+        let counts = parse_fasta(
+            &input_path,
+            None,
+            Some(&output_path),
+            None,
+            HashSet::from_iter([b"562".as_slice()]),
+            true,
+            Some(4),
+            None,
+            1,
+            64,
+            None,
+            1,
+        )?;
+        assert_eq!(counts.records, 3);
+        assert_eq!(counts.matched, 2);
+        assert_eq!(
+            fs::read_to_string(&output_path)?,
+            ">kraken:taxid|562|NC_1 E. coli\nACGT\nACGT\n\
+             >kraken:taxid|562|NC_3 E. coli plasmid\nGG\n"
+        );
+
+        fs::write(&input_path, "ACGT\n>kraken:taxid|562\nACGT\n")?;
+        assert!(parse_fasta(
+            &input_path,
+            None,
+            None,
+            None,
+            HashSet::default(),
+            false,
+            Some(4),
+            None,
+            1,
+            64,
+            None,
+            1
+        )
+        .is_err());
+        Ok(())
+    }
+}
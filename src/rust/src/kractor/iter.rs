@@ -0,0 +1,207 @@
+use anyhow::{anyhow, Context, Result};
+use bytes::BytesMut;
+use crossbeam_channel::{Receiver, Sender};
+use extendr_api::prelude::*;
+use rustc_hash::FxHashSet as HashSet;
+
+use super::koutput::kractor_filter;
+use super::koutput::parse::kractor_match_aho;
+use crate::error::RError;
+use crate::lca_exclude::ExcludeMatcher;
+use crate::reader::LineReader;
+use crate::utils::*;
+
+/// Iterator handle streaming matched koutput records back to R in chunks.
+///
+/// The koutput file is filtered in a background thread which pushes chunks of
+/// matched lines into a bounded channel, so at most `nqueue` chunks are held in
+/// memory at any time. R pulls the next chunk with `next_chunk()`; dropping the
+/// handle closes the channel and stops the background thread.
+#[extendr]
+pub struct KractorIter {
+    rx: Receiver<Result<Vec<BytesMut>>>,
+    records: bool,
+}
+
+#[extendr]
+impl KractorIter {
+    fn new(
+        kreport: &str,
+        koutput: &str,
+        taxonomy: Robj,
+        ranks: Robj,
+        taxa: Robj,
+        taxids: Robj,
+        exclude: Robj,
+        descendants: bool,
+        records: bool,
+        chunk_size: usize,
+        nqueue: Option<usize>,
+    ) -> std::result::Result<Self, RError> {
+        kractor_iter(
+            kreport,
+            koutput,
+            taxonomy,
+            ranks,
+            taxa,
+            taxids,
+            exclude,
+            descendants,
+            records,
+            chunk_size,
+            nqueue,
+        )
+        .map_err(RError::from)
+    }
+
+    /// Return the next chunk, or `NULL` once all records have been consumed.
+    /// A chunk is a character vector of sequence IDs, or a list of all
+    /// koutput fields when `records = TRUE`.
+    fn next_chunk(&mut self) -> std::result::Result<Robj, RError> {
+        match self.rx.recv() {
+            Ok(Ok(lines)) => Ok(lines_to_robj(lines, self.records)),
+            Ok(Err(e)) => Err(RError::from(e)),
+            // The reader thread has finished
+            Err(_) => Ok(r!(NULL)),
+        }
+    }
+}
+
+fn kractor_iter(
+    kreport: &str,
+    koutput: &str,
+    taxonomy: Robj,
+    ranks: Robj,
+    taxa: Robj,
+    taxids: Robj,
+    exclude: Robj,
+    descendants: bool,
+    records: bool,
+    chunk_size: usize,
+    nqueue: Option<usize>,
+) -> Result<KractorIter> {
+    let (include_taxids, exclude_aho) = kractor_filter(
+        kreport,
+        taxonomy,
+        ranks,
+        taxa,
+        taxids,
+        exclude,
+        Default::default(),
+        descendants,
+        None,
+    )?;
+    let chunk_size = chunk_size.max(1);
+    // Check the input before spawning, so a missing file is reported immediately
+    input_size(koutput)?;
+    let koutput = koutput.to_string();
+    let (tx, rx) = new_channel(nqueue);
+    std::thread::spawn(move || {
+        let include_sets = include_taxids
+            .iter()
+            .map(|x| x.as_slice())
+            .collect::<HashSet<&[u8]>>();
+        let result = new_reader(&koutput, BUFFER_SIZE, None).and_then(|reader| {
+            let reader = LineReader::with_capacity(BUFFER_SIZE, reader);
+            stream_koutput(reader, &include_sets, &exclude_aho, chunk_size, &tx)
+        });
+        if let Err(e) = result {
+            // The receiver may already be gone, nothing left to report then
+            let _ = tx.send(Err(e));
+        }
+    });
+    Ok(KractorIter { rx, records })
+}
+
+fn stream_koutput<R: std::io::Read>(
+    mut reader: LineReader<R>,
+    include_sets: &HashSet<&[u8]>,
+    exclude_aho: &Option<ExcludeMatcher>,
+    chunk_size: usize,
+    tx: &Sender<Result<Vec<BytesMut>>>,
+) -> Result<()> {
+    let mut chunk = Vec::with_capacity(chunk_size);
+    while let Some(line) = reader
+        .read_line()
+        .with_context(|| format!("(Reader) Failed to read line"))?
+    {
+        if kractor_match_aho(include_sets, exclude_aho, &line) {
+            chunk.push(line);
+            if chunk.len() == chunk_size {
+                let full = std::mem::replace(&mut chunk, Vec::with_capacity(chunk_size));
+                tx.send(Ok(full))
+                    .map_err(|_| anyhow!("(Reader) Iterator has been released"))?;
+            }
+        }
+    }
+    if !chunk.is_empty() {
+        tx.send(Ok(chunk))
+            .map_err(|_| anyhow!("(Reader) Iterator has been released"))?;
+    }
+    Ok(())
+}
+
+fn lines_to_robj(lines: Vec<BytesMut>, records: bool) -> Robj {
+    if records {
+        let mut fields: [RStrings; 5] =
+            std::array::from_fn(|_| RStrings::with_capacity(lines.len()));
+        for line in &lines {
+            let mut iter = line[..].splitn(5, |x| *x == b'\t');
+            for field in fields.iter_mut() {
+                field.push(iter.next().unwrap_or_default());
+            }
+        }
+        let [status, id, taxid, length, lca] = fields.map(RStrings::finish);
+        list!(
+            status = status,
+            id = id,
+            taxid = taxid,
+            length = length,
+            lca = lca
+        )
+        .into()
+    } else {
+        let mut ids = RStrings::with_capacity(lines.len());
+        for line in &lines {
+            ids.push(line[..].split(|x| *x == b'\t').nth(1).unwrap_or_default());
+        }
+        ids.finish().into()
+    }
+}
+
+extendr_module! {
+    mod iter;
+    impl KractorIter;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_stream_koutput_chunks() -> Result<()> {
+        let sample = "\
+C\tread1\t123\t100\t123:5
+C\tread2\t456\t100\t456:5
+C\tread3\t123\t100\t123:5
+C\tread4\t123\t100\t123:2 9:1
+C\tread5\t123\t100\t123:5
+";
+        let reader = LineReader::new(Cursor::new(sample.as_bytes()));
+        let mut include = HashSet::default();
+        include.insert(b"123".as_ref());
+        let exclude = Some(ExcludeMatcher::new(&["9"], Default::default())?);
+        let (tx, rx) = crossbeam_channel::unbounded();
+        stream_koutput(reader, &include, &exclude, 2, &tx)?;
+        drop(tx);
+        let chunks = rx
+            .iter()
+            .map(|chunk| chunk.map(|lines| lines.len()))
+            .collect::<Result<Vec<_>>>()?;
+        // read1, read3, read5 match: one full chunk and a remainder
+        assert_eq!(chunks, vec![2, 1]);
+        Ok(())
+    }
+}
@@ -1,31 +1,156 @@
-use aho_corasick::{AhoCorasick, AhoCorasickKind};
 use anyhow::{anyhow, Context, Result};
 use extendr_api::prelude::*;
-use indicatif::{MultiProgress, ProgressBar, ProgressFinish};
 use rustc_hash::FxHashMap as HashMap;
 use rustc_hash::FxHashSet as HashSet;
 
-use crate::kreport::taxonomy_kreport;
+use crate::kractor::counts::{FilterHits, KractorCounts};
+use crate::kreport::{taxonomy_kreport, Kreport};
+use crate::lca_exclude::{ExcludeMatcher, ExcludeOptions};
 use crate::utils::*;
+use regex_filter::RegexFilter;
 
-mod parse;
+pub(super) mod parse;
+pub(super) mod regex_filter;
 
 pub(crate) fn kractor_koutput(
     kreport: &str,
     koutput: &str,
-    ofile: &str,
+    ofile: Option<&str>,
+    dry_run: bool,
     taxonomy: Robj,
     ranks: Robj,
     taxa: Robj,
     taxids: Robj,
     exclude: Robj,
+    exclude_options: ExcludeOptions,
+    regex_filter: Option<RegexFilter>,
     descendants: bool,
-    compression_level: i32,
+    top_n: Option<usize>,
+    by_taxon: bool,
+    filter_report: bool,
+    compression_level: Option<i32>,
+    max_file_bytes: Option<u64>,
     batch_size: usize,
     chunk_bytes: usize,
     nqueue: Option<usize>,
     threads: usize,
-) -> Result<()> {
+) -> Result<KractorCounts> {
+    // In dry-run mode, lines are only matched and counted
+    let ofile = if dry_run {
+        None
+    } else {
+        Some(ofile.ok_or_else(|| anyhow!("No output file specified."))?)
+    };
+    let (include_taxids, exclude_aho) = kractor_filter(
+        kreport,
+        taxonomy,
+        ranks,
+        taxa,
+        taxids,
+        exclude.clone(),
+        exclude_options,
+        descendants,
+        top_n,
+    )?;
+    // Exclude patterns are built in the order of `exclude`
+    let filters = if filter_report {
+        let exclude = robj_to_option_str(&exclude).context("Failed to parse 'exclude'")?;
+        Some(FilterHits::new(
+            &include_taxids,
+            &exclude.unwrap_or_default(),
+        ))
+    } else {
+        None
+    };
+    let include_sets = include_taxids
+        .iter()
+        .map(|x| x.as_slice())
+        .collect::<HashSet<&[u8]>>();
+    let reader_style = progress_reader_style()?;
+    let writer_style = progress_writer_style()?;
+    let progress = new_progress();
+    let pb1 = progress.add(input_progress_bar(koutput)?);
+    pb1.set_prefix("Reading koutput");
+    pb1.set_style(reader_style);
+
+    let pb2 = ofile.map(|_| {
+        let pb2 = progress.add(output_progress_bar());
+        pb2.set_prefix("Writing koutput");
+        pb2.set_style(writer_style);
+        pb2
+    });
+
+    parse::parse_koutput(
+        koutput,
+        Some(pb1),
+        ofile,
+        pb2,
+        include_sets,
+        exclude_aho,
+        regex_filter,
+        by_taxon,
+        filters.as_ref(),
+        compression_level,
+        max_file_bytes,
+        batch_size,
+        chunk_bytes,
+        nqueue,
+        threads,
+    )
+}
+
+/// Count the classified reads of each taxid of a Kraken2 output, without
+/// building any filter or writing any output.
+pub(crate) fn kractor_koutput_taxa(
+    koutput: &str,
+    batch_size: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> Result<KractorCounts> {
+    let pb = input_progress_bar(koutput)?;
+    pb.set_prefix("Reading koutput");
+    pb.set_style(progress_reader_style()?);
+    parse::count_koutput(koutput, Some(pb), batch_size, nqueue, threads)
+}
+
+/// Keep the `n` taxa of each rank with the most reads in their clade, in the
+/// order of `reports`
+fn top_taxa(reports: Vec<&Kreport>, n: usize) -> Vec<&Kreport> {
+    let mut by_rank: HashMap<&[u8], Vec<&Kreport>> = HashMap::default();
+    for report in reports.iter() {
+        by_rank
+            .entry(report.rank.as_slice())
+            .or_default()
+            .push(report);
+    }
+    let mut top = HashSet::default();
+    for (_, mut taxa) in by_rank {
+        taxa.sort_by(|a, b| {
+            b.total_reads
+                .cmp(&a.total_reads)
+                .then_with(|| a.taxid.cmp(&b.taxid))
+        });
+        top.extend(taxa.into_iter().take(n).map(|kr| kr.taxid.as_slice()));
+    }
+    reports
+        .into_iter()
+        .filter(|kr| top.contains(kr.taxid.as_slice()))
+        .collect()
+}
+
+/// Resolve the taxid filters into the set of included taxids and an optional
+/// matcher for excluded taxids in the LCA mapping field.
+pub(in crate::kractor) fn kractor_filter(
+    kreport: &str,
+    taxonomy: Robj,
+    ranks: Robj,
+    taxa: Robj,
+    taxids: Robj,
+    exclude: Robj,
+    exclude_options: ExcludeOptions,
+    descendants: bool,
+    top_n: Option<usize>,
+) -> Result<(Vec<Vec<u8>>, Option<ExcludeMatcher>)> {
     let ranks = robj_to_option_str(&ranks).with_context(|| format!("Failed to parse 'ranks'"))?;
     let taxa = robj_to_option_str(&taxa).with_context(|| format!("Failed to parse 'taxa'"))?;
     let taxids =
@@ -46,7 +171,7 @@ pub(crate) fn kractor_koutput(
 
     let kreports = taxonomy_kreport(kreport, taxonomy)?;
     let mut targeted_taxids: Vec<&[u8]>;
-    if ranks.is_some() || taxa.is_some() || taxids.is_some() {
+    if ranks.is_some() || taxa.is_some() || taxids.is_some() || top_n.is_some() {
         // Parse set of desired taxonomic ranks
         let mut reports = kreports.iter().collect::<Vec<_>>();
         if let Some(ranks) = ranks {
@@ -79,6 +204,9 @@ pub(crate) fn kractor_koutput(
                 .filter(|kr| taxids_sets.contains(kr.taxid.as_slice()))
                 .collect();
         }
+        if let Some(n) = top_n {
+            reports = top_taxa(reports, n);
+        }
         targeted_taxids = reports.into_iter().map(|kr| kr.taxid.as_slice()).collect();
     } else {
         targeted_taxids = kreports.iter().map(|kr| kr.taxid.as_slice()).collect();
@@ -130,57 +258,40 @@ pub(crate) fn kractor_koutput(
             .collect()
     }
 
-    let include_sets = targeted_taxids.into_iter().collect::<HashSet<&[u8]>>();
+    let include_taxids = targeted_taxids.into_iter().map(|x| x.to_vec()).collect();
 
-    // A space-delimited list indicating the LCA mapping of each
-    // k-mer in the sequence(s). For example, "562:13 561:4 A:31 0:1 562:3" would indicate that:
-    //
-    // the first 13 k-mers mapped to taxonomy ID #562
-    // the next 4 k-mers mapped to taxonomy ID #561
-    // the next 31 k-mers contained an ambiguous nucleotide
-    // the next k-mer was not in the database
-    // the last 3 k-mers mapped to taxonomy ID #562
     let exclude_aho = exclude
-        .map(|v| {
-            let patterns: Vec<Vec<u8>> = v
-                .iter()
-                .map(|taxid| {
-                    let mut pattern = Vec::with_capacity(taxid.len() + 1); // estimated capacity
-                    pattern.extend_from_slice(taxid.as_bytes());
-                    pattern.push(b':');
-                    pattern
-                })
-                .collect();
-            AhoCorasick::builder()
-                .kind(Some(AhoCorasickKind::DFA))
-                .build(patterns)
-        })
+        .map(|taxids| ExcludeMatcher::new(&taxids, exclude_options))
         .transpose()?;
-    let reader_style = progress_reader_style()?;
-    let writer_style = progress_writer_style()?;
-    let progress = MultiProgress::new();
-    let pb1 = progress.add(
-        ProgressBar::new(std::fs::metadata(koutput)?.len() as u64)
-            .with_finish(ProgressFinish::Abandon),
-    );
-    pb1.set_prefix("Reading koutput");
-    pb1.set_style(reader_style);
+    Ok((include_taxids, exclude_aho))
+}
 
-    let pb2 = progress.add(ProgressBar::no_length().with_finish(ProgressFinish::Abandon));
-    pb2.set_prefix("Writing koutput");
-    pb2.set_style(writer_style);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kreport::parse_kreport;
 
-    parse::parse_koutput(
-        koutput,
-        Some(pb1),
-        ofile,
-        Some(pb2),
-        include_sets,
-        exclude_aho,
-        compression_level,
-        batch_size,
-        chunk_bytes,
-        nqueue,
-        threads,
-    )
+    #[test]
+    fn test_top_taxa() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let path = temp.path().join("kreport.txt");
+        std::fs::write(
+            &path,
+            "\
+100.00\t100\t0\tR\t1\troot
+100.00\t100\t0\tD\t2\t  Bacteria
+60.00\t60\t0\tG\t561\t    Escherichia
+55.00\t55\t55\tS\t562\t      Escherichia coli
+5.00\t5\t5\tS\t564\t      Escherichia fergusonii
+30.00\t30\t0\tG\t1279\t    Staphylococcus
+30.00\t30\t30\tS\t1280\t      Staphylococcus aureus
+10.00\t10\t10\tG\t1386\t    Bacillus
+",
+        )?;
+        let kreports = parse_kreport(&path)?;
+        let top = top_taxa(kreports.iter().collect(), 2);
+        let taxids = top.iter().map(|kr| kr.taxid.as_slice()).collect::<Vec<_>>();
+        assert_eq!(taxids, [&b"1"[..], b"2", b"561", b"562", b"1279", b"1280"]);
+        Ok(())
+    }
 }
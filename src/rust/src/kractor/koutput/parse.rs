@@ -1,41 +1,45 @@
-use std::io::{BufWriter, Write};
 use std::path::Path;
 
-use aho_corasick::AhoCorasick;
 use anyhow::{anyhow, Context, Result};
 use bytes::{BufMut, BytesMut};
 use crossbeam_channel::{Receiver, Sender};
 use indicatif::ProgressBar;
-use libdeflater::{CompressionLvl, Compressor};
 use memchr::memchr;
 use rustc_hash::FxHashSet as HashSet;
 
 use crate::batchsender::BatchSender;
+use crate::kractor::counts::{DropStage, FilterHits, KractorCounts};
+use crate::lca_exclude::ExcludeMatcher;
+
+use super::regex_filter::RegexFilter;
+use crate::part_writer::{PartCounter, PartWriter};
 use crate::reader::LineReader;
 use crate::utils::*;
 
 pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
     input_path: &P,
     input_bar: Option<ProgressBar>,
-    output_path: &P,
+    output_path: Option<&P>,
     output_bar: Option<ProgressBar>,
     include_sets: HashSet<&[u8]>,
-    exclude_aho: Option<AhoCorasick>,
-    compression_level: i32,
+    exclude_aho: Option<ExcludeMatcher>,
+    regex_filter: Option<RegexFilter>,
+    by_taxon: bool,
+    filters: Option<&FilterHits>,
+    compression_level: Option<i32>,
+    max_file_bytes: Option<u64>,
     batch_size: usize,
     chunk_bytes: usize,
     nqueue: Option<usize>,
     threads: usize,
-) -> Result<()> {
+) -> Result<KractorCounts> {
     let input: &Path = input_path.as_ref();
-    let output: &Path = output_path.as_ref();
+    // Without an output file, lines are only counted (dry run)
+    let output: Option<&Path> = output_path.map(|x| x.as_ref());
 
-    // Ensure compression level is validated and converted before entering thread scope.
-    // Doing this outside avoids redundant validation across parser threads.
-    let compression_level = CompressionLvl::new(compression_level)
-        .map_err(|e| anyhow!("Invalid 'compression_level': {:?}", e))?;
+    let compression = Compression::of(output, compression_level)?;
 
-    std::thread::scope(|scope| -> Result<()> {
+    std::thread::scope(|scope| -> Result<KractorCounts> {
         // Two communication pipelines are set up to decouple IO and CPU-intensive work:
         // - reader_tx: transfers raw FASTQ records to parser threads
         // - writer_tx: receives compressed byte chunks from parser threads
@@ -46,36 +50,67 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
         // ─── Writer Thread ─────────────────────────────────────
         // A single thread handles file output to ensure atomic write order and leverage buffered IO.
         // This thread consumes compressed chunks, not raw records, for performance.
-        let writer_handle = scope.spawn(move || -> Result<()> {
-            let mut writer = BufWriter::with_capacity(chunk_bytes, new_writer(output, output_bar)?);
+        // Chunks are self-contained, so the output may roll over into a new part file
+        // between any two of them.
+        let writer_handle = output.map(|output| {
+            scope.spawn(move || -> Result<()> {
+                let mut counter = PartCounter::new(max_file_bytes, 1);
+                let mut writer = PartWriter::new(output, max_file_bytes, chunk_bytes, output_bar);
 
-            // Iterate over each received batch of records
-            for chunk in writer_rx {
+                // Iterate over each received batch of records
+                for chunk in writer_rx {
+                    writer
+                        .write_part(counter.assign(&[chunk.len()]), &chunk)
+                        .with_context(|| {
+                            format!("(Writer) Failed to write Fastq records to output")
+                        })?;
+                }
                 writer
-                    .write_all(&chunk)
-                    .with_context(|| format!("(Writer) Failed to write Fastq records to output"))?;
-            }
-            writer
-                .flush()
-                .with_context(|| format!("(Writer) Failed to flush writer"))?;
-            Ok(())
+                    .finish()
+                    .with_context(|| format!("(Writer) Failed to flush writer"))?;
+                Ok(())
+            })
         });
 
         // ─── Parser Thread ─────────────────────────────────────
         // Streams Kraken2 output data, filters by ID set
         let mut parser_handles = Vec::with_capacity(threads);
-        let gzip = gz_compressed(output);
+        let gzip = output.map_or(false, gz_compressed);
+        let dry_run = output.is_none();
         for _ in 0 .. threads {
             let rx = reader_rx.clone();
             let tx = writer_tx.clone();
             let include_sets = &include_sets;
             let exclude_aho = &exclude_aho;
-            let handle = scope.spawn(move || -> Result<()> {
-                let mut pool: Vec<u8> = Vec::with_capacity(chunk_bytes);
-                let mut compressor = Compressor::new(compression_level);
+            let regex_filter = &regex_filter;
+            let handle = scope.spawn(move || -> Result<KractorCounts> {
+                let mut counts = KractorCounts::new(by_taxon).with_attrition();
+                if let Some(filters) = filters {
+                    counts = counts.with_filters(filters.clone());
+                }
+                let track_taxa = by_taxon || filters.is_some();
+                let mut pool: Vec<u8> = Vec::with_capacity(if dry_run { 0 } else { chunk_bytes });
+                let mut compressor = compression.compressor();
                 while let Ok(lines) = rx.recv() {
+                    counts.records += lines.len();
                     for line in lines {
                         if kractor_match_aho(&include_sets, &exclude_aho, &line) {
+                            // Regexes are only searched in the lines passing the taxids
+                            if regex_filter.as_ref().is_some_and(|x| !x.accept(&line)) {
+                                counts.add_attrition(DropStage::Regex);
+                                continue;
+                            }
+                            if track_taxa {
+                                counts.add_match(
+                                    koutput_fields(&line)
+                                        .and_then(|[_, _, taxid, _, _]| koutput_taxid(taxid)),
+                                );
+                            } else {
+                                counts.matched += 1;
+                            }
+                            if dry_run {
+                                continue;
+                            }
                             // Flush when pool is too full to accept the next record.
                             // This ensures output chunks remain near the target block size.
                             if pool.capacity() - pool.len() < (line.len() + 1) {
@@ -94,7 +129,17 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
                             // Append encoded lines to buffer
                             pool.extend_from_slice(&line);
                             pool.put_u8(b'\n');
-                        };
+                        } else {
+                            let stage = koutput_drop_stage(include_sets, exclude_aho, &line);
+                            if let (DropStage::Exclude, Some(exclude_aho), true) =
+                                (stage, exclude_aho, filters.is_some())
+                            {
+                                if let Some([_, _, _, _, lca]) = koutput_fields(&line) {
+                                    counts.add_excluded(exclude_aho, lca);
+                                }
+                            }
+                            counts.add_attrition(stage);
+                        }
                     }
                 }
                 // Flush remaining lines if any
@@ -108,7 +153,7 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
                         format!("(Parser) Failed to send parsed lines to Writer thread")
                     })?;
                 };
-                Ok(())
+                Ok(counts)
             });
             parser_handles.push(handle);
         }
@@ -117,6 +162,8 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
 
         // ─── reader Thread ─────────────────────────────────────
         let reader_handle = scope.spawn(move || -> Result<()> {
+            // Inputs of unknown size count the lines read instead of bytes
+            let (input_bar, lines_bar) = split_input_bar(input_bar)?;
             let mut reader =
                 LineReader::with_capacity(BUFFER_SIZE, new_reader(input, BUFFER_SIZE, input_bar)?);
             let mut reader_tx = BatchSender::with_capacity(batch_size, reader_tx);
@@ -124,6 +171,9 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
                 .read_line()
                 .with_context(|| format!("(Reader) Failed to read line"))?
             {
+                if let Some(bar) = &lines_bar {
+                    bar.inc(1);
+                }
                 reader_tx
                     .send(record)
                     .with_context(|| format!("(Reader) Failed to send lines to Parser thread"))?;
@@ -135,24 +185,107 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
         });
 
         // ─── Join Threads and Propagate Errors ────────────────
-        writer_handle
+        if let Some(writer_handle) = writer_handle {
+            writer_handle
+                .join()
+                .map_err(|e| anyhow!("(Writer) thread panicked: {:?}", e))??;
+        }
+        let mut counts = KractorCounts::new(by_taxon).with_attrition();
+        for handler in parser_handles {
+            counts.merge(
+                handler
+                    .join()
+                    .map_err(|e| anyhow!("(Parser) thread panicked: {:?}", e))??,
+            );
+        }
+        reader_handle
             .join()
-            .map_err(|e| anyhow!("(Writer) thread panicked: {:?}", e))??;
+            .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))??;
+        Ok(counts)
+    })
+}
+
+/// Count the reads of each taxid of a Kraken2 output, without any filter or
+/// output: a lightweight scan for a quick look at the content of a sample.
+/// `records` counts the lines of the output, `matched` the classified reads,
+/// and `taxa` the reads of each taxid.
+pub(super) fn count_koutput<P: AsRef<Path> + ?Sized>(
+    input_path: &P,
+    input_bar: Option<ProgressBar>,
+    batch_size: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> Result<KractorCounts> {
+    let input: &Path = input_path.as_ref();
+    std::thread::scope(|scope| -> Result<KractorCounts> {
+        let (reader_tx, reader_rx): (Sender<Vec<BytesMut>>, Receiver<Vec<BytesMut>>) =
+            new_channel(nqueue);
+
+        // ─── Parser Thread ─────────────────────────────────────
+        let mut parser_handles = Vec::with_capacity(threads);
+        for _ in 0 .. threads {
+            let rx = reader_rx.clone();
+            let handle = scope.spawn(move || -> Result<KractorCounts> {
+                let mut counts = KractorCounts::new(true);
+                while let Ok(lines) = rx.recv() {
+                    counts.records += lines.len();
+                    for line in lines {
+                        let mut fields = line[..].split(|x| *x == b'\t');
+                        if fields.next() != Some(b"C") {
+                            continue;
+                        }
+                        counts.add_match(fields.nth(1).and_then(koutput_taxid));
+                    }
+                }
+                Ok(counts)
+            });
+            parser_handles.push(handle);
+        }
+        drop(reader_rx);
+
+        // ─── reader Thread ─────────────────────────────────────
+        let reader_handle = scope.spawn(move || -> Result<()> {
+            // Inputs of unknown size count the lines read instead of bytes
+            let (input_bar, lines_bar) = split_input_bar(input_bar)?;
+            let mut reader =
+                LineReader::with_capacity(BUFFER_SIZE, new_reader(input, BUFFER_SIZE, input_bar)?);
+            let mut reader_tx = BatchSender::with_capacity(batch_size, reader_tx);
+            while let Some(record) = reader
+                .read_line()
+                .with_context(|| format!("(Reader) Failed to read line"))?
+            {
+                if let Some(bar) = &lines_bar {
+                    bar.inc(1);
+                }
+                reader_tx
+                    .send(record)
+                    .with_context(|| format!("(Reader) Failed to send lines to Parser thread"))?;
+            }
+            reader_tx
+                .flush()
+                .with_context(|| format!("(Reader) Failed to flush lines to Parser thread"))?;
+            Ok(())
+        });
+
+        // ─── Join Threads and Propagate Errors ────────────────
+        let mut counts = KractorCounts::new(true);
         for handler in parser_handles {
-            handler
-                .join()
-                .map_err(|e| anyhow!("(Parser) thread panicked: {:?}", e))??;
+            counts.merge(
+                handler
+                    .join()
+                    .map_err(|e| anyhow!("(Parser) thread panicked: {:?}", e))??,
+            );
         }
         reader_handle
             .join()
             .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))??;
-        Ok(())
+        Ok(counts)
     })
 }
 
-fn kractor_match_aho(
+pub(in crate::kractor) fn kractor_match_aho(
     include_sets: &HashSet<&[u8]>,
-    exclude_aho: &Option<AhoCorasick>,
+    exclude_aho: &Option<ExcludeMatcher>,
     line: &[u8],
 ) -> bool {
     let mut field_start = 0usize;
@@ -180,16 +313,10 @@ fn kractor_match_aho(
                 return false;
             }
         } else if field_index == 3 {
-            // Field 4 (LCA): remainder after the last tab
-            field_start += tab_pos + 1;
-            let lca;
-            if let Some(pos) = memchr(b'\t', &line[field_start ..]) {
-                lca = &line[field_start .. (field_start + pos)]
-            } else {
-                lca = &line[field_start ..]
-            };
+            // Field 4 (LCA): the rest of the line, tabs included
+            let lca = &line[field_start + tab_pos + 1 ..];
             if let Some(ref exclude_matcher) = exclude_aho {
-                return exclude_matcher.find(lca).is_none();
+                return !exclude_matcher.is_match(lca);
             }
         }
         field_index += 1;
@@ -197,12 +324,37 @@ fn kractor_match_aho(
     }
     false
 }
+
+/// The filter stage dropping a line not matched by [`kractor_match_aho`]
+pub(in crate::kractor) fn koutput_drop_stage(
+    include_sets: &HashSet<&[u8]>,
+    exclude_aho: &Option<ExcludeMatcher>,
+    line: &[u8],
+) -> DropStage {
+    let Some([status, _, taxid, _, _]) = koutput_fields(line) else {
+        return DropStage::Malformed;
+    };
+    if status == b"U" {
+        return DropStage::Unclassified;
+    }
+    match koutput_taxid(taxid) {
+        None => DropStage::Malformed,
+        Some(taxid) if include_sets.contains(taxid) => {
+            if exclude_aho.is_some() {
+                DropStage::Exclude
+            } else {
+                DropStage::Malformed
+            }
+        }
+        Some(_) => DropStage::Taxa,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::HashSet;
     use std::fs;
 
-    use aho_corasick::AhoCorasick;
     use tempfile::tempdir;
 
     use super::*;
@@ -225,14 +377,18 @@ C\tread2\t456\t456\tFungi
 
         let exclude = None; // No exclusion
 
-        parse_koutput(
+        let counts = parse_koutput(
             &input_path,
             None,
-            &output_path,
+            Some(&output_path),
             None,
             include,
             exclude,
-            3,          // compression level
+            None,
+            false,
+            None,
+            Some(3),    // compression level
+            None,       // max_file_bytes
             10,         // batch size
             512 * 1024, // chunk_bytes
             Some(2),    // nqueue
@@ -242,16 +398,61 @@ C\tread2\t456\t456\tFungi
         // Verify output file exists and is non-empty
         let out_content = fs::read(&output_path)?;
         assert!(!out_content.is_empty(), "Output gzip is empty");
+        assert_eq!(counts.records, 2);
+        assert_eq!(counts.matched, 1);
 
         Ok(())
     }
 
+    #[test]
+    fn test_parse_koutput_dry_run() -> Result<()> {
+        let temp = tempdir()?;
+        let input_path = temp.path().join("kout.txt");
+        let sample = "\
+C\tread1\tBacteria (taxid 123)\t123\t123:5
+C\tread2\t456\t456\t456:5
+C\tread3\t123\t123\t123:5
+U\tread4\t0\t123\t0:5
+";
+        fs::write(&input_path, sample)?;
+
+        let mut include = HashSet::default();
+        include.insert(b"123".as_ref());
+        include.insert(b"456".as_ref());
+
+        let counts = parse_koutput(
+            &input_path,
+            None,
+            None,
+            None,
+            include,
+            None,
+            None,
+            true,
+            None,
+            Some(3),
+            None,
+            2,
+            512 * 1024,
+            Some(2),
+            2,
+        )?;
+        assert_eq!(counts.records, 4);
+        assert_eq!(counts.matched, 3);
+        let taxa = counts.taxa.unwrap();
+        assert_eq!(taxa.get(b"123".as_slice()), Some(&2));
+        assert_eq!(taxa.get(b"456".as_slice()), Some(&1));
+        assert_eq!(fs::read_dir(temp.path())?.count(), 1);
+        Ok(())
+    }
+
     #[test]
     fn test_kractor_match_aho() {
         let mut include = HashSet::default();
         include.insert(b"999".as_ref());
 
-        let exclude = Some(AhoCorasick::new(["Fungi", "Viruses"]).unwrap());
+        let exclude =
+            Some(ExcludeMatcher::from_patterns(["Fungi", "Viruses"], Default::default()).unwrap());
 
         // Simulate a Kraken output line matching taxid 999 and LCA "Bacteria"
         let line = b"C\tid\tkraken:(taxid 999)\t999\tBacteria";
@@ -267,10 +468,50 @@ C\tread2\t456\t456\tFungi
         let mut include = HashSet::default();
         include.insert(b"456".as_ref());
 
-        let exclude = Some(AhoCorasick::new(["Fungi"]).unwrap());
+        let exclude = Some(ExcludeMatcher::from_patterns(["Fungi"], Default::default()).unwrap());
 
         // LCA is "Fungi", should be excluded
         let line = b"C\tid\tkraken:taxid|456\t456\tFungi";
         assert!(!kractor_match_aho(&include, &exclude, line));
+        // Past a stray tab, the LCA mapping is still searched
+        let line = b"C\t@id 1:N:0\t456\t456\t456:2\tFungi";
+        assert!(!kractor_match_aho(&include, &exclude, line));
+    }
+
+    #[test]
+    fn test_koutput_drop_stage() {
+        let mut include = HashSet::default();
+        include.insert(b"456".as_ref());
+        let exclude = Some(ExcludeMatcher::from_patterns(["Fungi"], Default::default()).unwrap());
+
+        let stage = |line: &[u8]| koutput_drop_stage(&include, &exclude, line);
+        assert_eq!(stage(b"U\tid\t0\t100\t0:66"), DropStage::Unclassified);
+        assert_eq!(stage(b"C\tid\t123\t100\t123:66"), DropStage::Taxa);
+        assert_eq!(stage(b"C\tid\tx (taxid 123)\t100\t123:66"), DropStage::Taxa);
+        assert_eq!(stage(b"C\tid\t456\t100\tFungi"), DropStage::Exclude);
+        assert_eq!(stage(b"C\tid\t456\t100"), DropStage::Malformed);
+        assert_eq!(stage(b"C\tid\tx (taxid 456\t100\t1"), DropStage::Malformed);
+        assert_eq!(stage(b"C\tid"), DropStage::Malformed);
+    }
+
+    #[test]
+    fn test_count_koutput() -> Result<()> {
+        let temp = tempdir()?;
+        let input_path = temp.path().join("kout.txt");
+        let sample = "\
+C\tread1\tBacteria (taxid 123)\t123\t123:5
+C\tread2\t456\t456\t456:5
+U\tread3\t0\t123\t0:5
+C\tread4\t123\t123\t123:5
+";
+        fs::write(&input_path, sample)?;
+        let counts = count_koutput(&input_path, None, 2, Some(2), 2)?;
+        assert_eq!(counts.records, 4);
+        assert_eq!(counts.matched, 3);
+        let taxa = counts.taxa.unwrap();
+        assert_eq!(taxa.get(b"123".as_slice()), Some(&2));
+        assert_eq!(taxa.get(b"456".as_slice()), Some(&1));
+        assert_eq!(taxa.len(), 2);
+        Ok(())
     }
 }
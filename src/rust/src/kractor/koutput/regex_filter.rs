@@ -0,0 +1,103 @@
+#[cfg(not(feature = "regex"))]
+use anyhow::anyhow;
+#[cfg(feature = "regex")]
+use anyhow::Context;
+use anyhow::Result;
+#[cfg(feature = "regex")]
+use regex::bytes::Regex;
+
+#[cfg(feature = "regex")]
+use crate::utils::koutput_fields;
+
+/// Regular expressions searched in the sequence ID and LCA mapping of Kraken2
+/// output lines, for selections that the taxid sets cannot express. Only the
+/// lines passing the taxid filters are searched, so the cheap literal
+/// matching still does most of the work.
+pub(crate) struct RegexFilter {
+    // Keep the lines whose sequence ID matches
+    #[cfg(feature = "regex")]
+    id: Option<Regex>,
+    // Keep the lines whose LCA mapping matches
+    #[cfg(feature = "regex")]
+    lca: Option<Regex>,
+    // Drop the lines whose LCA mapping matches
+    #[cfg(feature = "regex")]
+    exclude: Option<Regex>,
+}
+
+impl RegexFilter {
+    /// A filter of the given patterns, or `None` if there are none
+    #[cfg(feature = "regex")]
+    pub(crate) fn new(
+        id: Option<&str>,
+        lca: Option<&str>,
+        exclude: Option<&str>,
+    ) -> Result<Option<Self>> {
+        if id.is_none() && lca.is_none() && exclude.is_none() {
+            return Ok(None);
+        }
+        let regex = |pattern: Option<&str>, name: &str| {
+            pattern
+                .map(|x| Regex::new(x).with_context(|| format!("Invalid '{}': {}", name, x)))
+                .transpose()
+        };
+        Ok(Some(Self {
+            id: regex(id, "id_regex")?,
+            lca: regex(lca, "lca_regex")?,
+            exclude: regex(exclude, "exclude_regex")?,
+        }))
+    }
+
+    #[cfg(not(feature = "regex"))]
+    pub(crate) fn new(
+        id: Option<&str>,
+        lca: Option<&str>,
+        exclude: Option<&str>,
+    ) -> Result<Option<Self>> {
+        if id.is_none() && lca.is_none() && exclude.is_none() {
+            Ok(None)
+        } else {
+            Err(anyhow!(
+                "Regex filters are not available: mire was built without the 'regex' feature"
+            ))
+        }
+    }
+
+    #[cfg(feature = "regex")]
+    pub(crate) fn accept(&self, line: &[u8]) -> bool {
+        let Some([_, id, _, _, lca]) = koutput_fields(line) else {
+            return false;
+        };
+        self.id.as_ref().is_none_or(|x| x.is_match(id))
+            && self.lca.as_ref().is_none_or(|x| x.is_match(lca))
+            && !self.exclude.as_ref().is_some_and(|x| x.is_match(lca))
+    }
+
+    #[cfg(not(feature = "regex"))]
+    pub(crate) fn accept(&self, _line: &[u8]) -> bool {
+        true
+    }
+}
+
+#[cfg(all(test, feature = "regex"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regex_filter() -> Result<()> {
+        assert!(RegexFilter::new(None, None, None)?.is_none());
+        assert!(RegexFilter::new(Some("("), None, None).is_err());
+
+        let filter = RegexFilter::new(Some(r"^A00\d+:"), None, Some(r"(^| )960[56]:"))?.unwrap();
+        assert!(filter.accept(b"C\tA001:1\t562\t150\t562:13 A:31"));
+        assert!(!filter.accept(b"C\tB001:1\t562\t150\t562:13 A:31"));
+        assert!(!filter.accept(b"C\tA001:1\t562\t150\t562:13 9606:2"));
+        assert!(filter.accept(b"C\tA001:1\t562\t150\t562:13 19606:2"));
+
+        let filter = RegexFilter::new(None, Some(r"(^| )562:([5-9]|\d{2,})( |$)"), None)?.unwrap();
+        assert!(filter.accept(b"C\tr1\t562\t150\t562:13 0:1"));
+        assert!(!filter.accept(b"C\tr1\t562\t150\t562:3 0:1"));
+        assert!(!filter.accept(b"C\tr1"));
+        Ok(())
+    }
+}
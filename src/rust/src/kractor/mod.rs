@@ -1,9 +1,66 @@
-#[cfg(feature = "bench")]
 use anyhow::Context;
 use extendr_api::prelude::*;
 
+use crate::altrep::alt_strings;
+use crate::arrow_stream::{ArrowStreamBuilder, ARROW_BATCH_ROWS};
+use crate::error::{ErrorKind, RError};
+use crate::lca_exclude::ExcludeOptions;
+use crate::memory::MemorySampler;
+use crate::seq_refine::seq_action::robj_to_seq_actions;
+use crate::threads::StageThreads;
+use crate::utils::{u8_to_list_rstr, KoutputColumns};
+
+mod counts;
+mod fasta;
+mod iter;
 mod koutput;
 pub(crate) mod reads;
+mod route;
+mod taxid_index;
+mod validate;
+
+/// The koutput columns given from R, 1-based, or the standard layout
+fn koutput_columns(columns: Option<Vec<i32>>) -> anyhow::Result<KoutputColumns> {
+    let Some(columns) = columns else {
+        return Ok(KoutputColumns::default());
+    };
+    let columns: [i32; 5] = columns
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("koutput columns must be 5 column numbers"))?;
+    if columns.iter().any(|x| *x < 1) {
+        return Err(anyhow::anyhow!("koutput columns must be positive"));
+    }
+    KoutputColumns::new(columns.map(|x| x as usize - 1))
+}
+
+/// Convert the named list of the options of a function into their struct
+fn options_from_robj<T>(options: &Robj) -> anyhow::Result<T>
+where
+    T: for<'a> TryFrom<&'a Robj, Error = extendr_api::Error>,
+{
+    T::try_from(options).map_err(|e| ErrorKind::Config.error(format!("Invalid options: {}", e)))
+}
+
+/// The options of `kractor_koutput()`, given from R as a named list
+#[derive(extendr_api::TryFromRobj)]
+struct KoutputArgs {
+    exclude_ignore_case: bool,
+    exclude_whole_word: bool,
+    id_regex: Option<String>,
+    lca_regex: Option<String>,
+    exclude_regex: Option<String>,
+    descendants: bool,
+    top_n: Option<usize>,
+    dry_run: bool,
+    by_taxon: bool,
+    filter_report: bool,
+    compression_level: Option<i32>,
+    max_file_bytes: Option<usize>,
+    batch_size: usize,
+    chunk_bytes: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+}
 
 #[extendr]
 fn kractor_koutput(
@@ -14,59 +71,355 @@ fn kractor_koutput(
     taxa: Robj,
     taxids: Robj,
     exclude: Robj,
+    ofile: Option<&str>,
+    options: Robj,
+) -> std::result::Result<List, RError> {
+    let options: KoutputArgs = options_from_robj(&options).map_err(RError::from)?;
+    let regex_filter = koutput::regex_filter::RegexFilter::new(
+        options.id_regex.as_deref(),
+        options.lca_regex.as_deref(),
+        options.exclude_regex.as_deref(),
+    )
+    .map_err(RError::from)?;
+    koutput::kractor_koutput(
+        kreport,
+        koutput,
+        ofile,
+        options.dry_run,
+        taxonomy,
+        ranks,
+        taxa,
+        taxids,
+        exclude,
+        ExcludeOptions {
+            ignore_case: options.exclude_ignore_case,
+            whole_word: options.exclude_whole_word,
+        },
+        regex_filter,
+        options.descendants,
+        options.top_n,
+        options.by_taxon,
+        options.filter_report,
+        options.compression_level,
+        options.max_file_bytes.map(|x| x as u64),
+        options.batch_size,
+        options.chunk_bytes,
+        options.nqueue,
+        options.threads,
+    )
+    .map(|counts| counts.into_list(&[koutput]))
+    .map_err(RError::from)
+}
+
+/// Count the reads of each taxid of a Kraken2 output
+#[extendr]
+fn kractor_koutput_taxa(
+    koutput: &str,
+    batch_size: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> std::result::Result<List, RError> {
+    koutput::kractor_koutput_taxa(koutput, batch_size, nqueue, threads)
+        .map(|counts| counts.into_list(&[koutput]))
+        .map_err(RError::from)
+}
+
+#[extendr]
+fn kractor_fasta(
+    kreport: &str,
+    fasta: &str,
+    taxonomy: Robj,
+    ranks: Robj,
+    taxa: Robj,
+    taxids: Robj,
     descendants: bool,
-    ofile: &str,
-    compression_level: i32,
+    ofile: Option<&str>,
+    dry_run: bool,
+    by_taxon: bool,
+    compression_level: Option<i32>,
+    max_file_bytes: Option<usize>,
     batch_size: usize,
     chunk_bytes: usize,
     nqueue: Option<usize>,
     threads: usize,
-) -> std::result::Result<(), String> {
-    koutput::kractor_koutput(
+) -> std::result::Result<List, RError> {
+    fasta::kractor_fasta(
         kreport,
-        koutput,
+        fasta,
         ofile,
+        dry_run,
         taxonomy,
         ranks,
         taxa,
         taxids,
-        exclude,
         descendants,
+        by_taxon,
         compression_level,
+        max_file_bytes.map(|x| x as u64),
         batch_size,
         chunk_bytes,
         nqueue,
         threads,
     )
-    .map_err(|e| format!("{:?}", e))
+    .map(|counts| counts.into_list(&[fasta]))
+    .map_err(RError::from)
+}
+
+/// The options of `kractor_reads()`, given from R as a named list
+#[derive(extendr_api::TryFromRobj)]
+struct ReadsArgs {
+    dry_run: bool,
+    by_taxon: bool,
+    barcodes: Option<Vec<String>>,
+    barcode: Robj,
+    correct_barcodes: bool,
+    read_group: Option<String>,
+    transfer_tags: bool,
+    actions1: Robj,
+    actions2: Robj,
+    strip_tags: bool,
+    min_gc: Option<f64>,
+    max_gc: Option<f64>,
+    stats: bool,
+    index: bool,
+    compression_level: Option<i32>,
+    compression_level2: Option<i32>,
+    max_file_bytes: Option<usize>,
+    batch_size: usize,
+    chunk_bytes: usize,
+    nqueue: Option<usize>,
+    threads: Robj,
+    in_memory: usize,
+    min_taxon_reads: Option<usize>,
+    spill_dir: Option<String>,
+    koutput_columns: Option<Vec<i32>>,
+    check_duplicates: bool,
 }
 
 #[extendr]
 fn kractor_reads(
-    koutput: &str,
+    koutput: Option<&str>,
+    classified: Option<Vec<String>>,
     fq1: &str,
     ofile1: Option<&str>,
     fq2: Option<&str>,
     ofile2: Option<&str>,
-    compression_level: i32,
+    callback: Robj,
+    options: Robj,
+) -> std::result::Result<List, RError> {
+    let args: ReadsArgs = options_from_robj(&options).map_err(RError::from)?;
+    let columns = koutput_columns(args.koutput_columns).map_err(RError::from)?;
+    let threads = StageThreads::from_robj(&args.threads).map_err(RError::from)?;
+    let actions1 = robj_to_seq_actions(&args.actions1)
+        .context("Failed to parse actions1")
+        .map_err(RError::from)?;
+    let actions2 = robj_to_seq_actions(&args.actions2)
+        .context("Failed to parse actions2")
+        .map_err(RError::from)?;
+    let memory = MemorySampler::start();
+    // The R function returns the message of any error it raised, `FALSE` if
+    // the user interrupted it, or NULL
+    let mut callback = callback.as_function().map(|callback| {
+        move |reads| -> anyhow::Result<()> {
+            let out = callback
+                .call(pairlist!(reads::matched_reads_list(reads)))
+                .map_err(|e| anyhow::anyhow!("(Callback) Failed to call R function: {:?}", e))?;
+            if out.as_bool() == Some(false) {
+                return Err(ErrorKind::Interrupted.error("(Callback) Interrupted by the user"));
+            }
+            match out.as_str() {
+                Some(message) => Err(anyhow::anyhow!("(Callback) {}", message)),
+                None => Ok(()),
+            }
+        }
+    });
+    reads::kractor_reads(
+        koutput,
+        classified,
+        fq1,
+        ofile1,
+        fq2,
+        ofile2,
+        callback.as_mut().map(|x| x as reads::ReadCallback),
+        reads::ReadsOptions {
+            barcodes: args.barcodes,
+            barcode: &args.barcode,
+            correct_barcodes: args.correct_barcodes,
+            read_group: args.read_group.as_deref(),
+            transfer_tags: args.transfer_tags,
+            actions: (actions1, actions2),
+            strip_tags: args.strip_tags,
+            gc_range: (args.min_gc.is_some() || args.max_gc.is_some())
+                .then(|| (args.min_gc.unwrap_or(0.0), args.max_gc.unwrap_or(1.0))),
+            in_memory: args.in_memory as u64,
+            min_taxon_reads: args.min_taxon_reads,
+            spill_dir: args.spill_dir.as_deref(),
+            columns,
+            check_duplicates: args.check_duplicates,
+            extract: reads::ExtractOptions {
+                dry_run: args.dry_run,
+                by_taxon: args.by_taxon,
+                stats: args.stats,
+                index: args.index,
+                compression_level: (args.compression_level, args.compression_level2),
+                max_file_bytes: args.max_file_bytes.map(|x| x as u64),
+                batch_size: args.batch_size,
+                chunk_bytes: args.chunk_bytes,
+                nqueue: args.nqueue,
+                threads,
+            },
+        },
+    )
+    .map(|mut counts| {
+        counts.memory = Some(memory.finish());
+        if let Some(fq2) = fq2 {
+            counts.into_list(&[fq1, fq2])
+        } else {
+            counts.into_list(&[fq1])
+        }
+    })
+    .map_err(RError::from)
+}
+
+/// Resolve the taxid filters of a Kraken2 report into the selected taxids
+#[extendr]
+fn kractor_taxids(
+    kreport: &str,
+    taxonomy: Robj,
+    ranks: Robj,
+    taxa: Robj,
+    taxids: Robj,
+    descendants: bool,
+    top_n: Option<usize>,
+) -> std::result::Result<Vec<String>, RError> {
+    koutput::kractor_filter(
+        kreport,
+        taxonomy,
+        ranks,
+        taxa,
+        taxids,
+        Robj::from(()),
+        Default::default(),
+        descendants,
+        top_n,
+    )
+    .map(|(taxids, _)| {
+        taxids
+            .into_iter()
+            .map(|x| String::from_utf8_lossy(&x).into_owned())
+            .collect()
+    })
+    .map_err(RError::from)
+}
+
+#[extendr]
+fn kractor_route(
+    koutput: &str,
+    columns: Option<Vec<i32>>,
+    fq1: &str,
+    fq2: Option<&str>,
+    outputs: Vec<String>,
+    taxids: Vec<String>,
+    barcodes: Vec<String>,
+    barcode: Robj,
+    odir: &str,
+    compression_level: Option<i32>,
     batch_size: usize,
     chunk_bytes: usize,
+    writers: usize,
     nqueue: Option<usize>,
     threads: usize,
-) -> std::result::Result<(), String> {
-    reads::kractor_reads(
+) -> std::result::Result<List, RError> {
+    let columns = koutput_columns(columns).map_err(RError::from)?;
+    let rules = route::RouteRules::new(outputs, taxids, barcodes).map_err(RError::from)?;
+    let barcode = reads::barcode::BarcodeSource::try_from(&barcode).map_err(RError::from)?;
+    route::kractor_route(
         koutput,
+        &columns,
         fq1,
-        ofile1,
         fq2,
-        ofile2,
+        &rules,
+        &barcode,
+        odir,
         compression_level,
         batch_size,
         chunk_bytes,
+        writers.max(1),
         nqueue,
-        threads,
+        threads.max(1),
     )
-    .map_err(|e| format!("{}", e))
+    .map(|(counts, taxa)| {
+        let (outputs, reads): (Vec<_>, Vec<_>) = counts.into_iter().unzip();
+        list!(
+            output = u8_to_list_rstr(outputs.into_iter().map(|x| x.to_vec()).collect()),
+            reads = reads.into_iter().map(|x| x as f64).collect::<Vec<_>>(),
+            taxa = list!(
+                output = u8_to_list_rstr(taxa.iter().map(|x| x.0.to_vec()).collect()),
+                taxid = u8_to_list_rstr(taxa.iter().map(|x| x.1.clone()).collect()),
+                reads = taxa.iter().map(|x| x.2 as f64).collect::<Vec<_>>()
+            )
+        )
+    })
+    .map_err(RError::from)
+}
+
+#[extendr]
+fn kractor_validate(
+    koutput: &str,
+    columns: Option<Vec<i32>>,
+    sam: &str,
+) -> std::result::Result<List, RError> {
+    let columns = koutput_columns(columns).map_err(RError::from)?;
+    validate::kractor_validate(koutput, &columns, sam)
+        .map(|counts| {
+            let mut taxid = Vec::with_capacity(counts.len());
+            let mut reads = Vec::with_capacity(counts.len());
+            let mut mapped = Vec::with_capacity(counts.len());
+            let mut concordant = Vec::with_capacity(counts.len());
+            for (id, (n, m, c)) in counts {
+                taxid.push(id);
+                reads.push(n as f64);
+                mapped.push(m as f64);
+                concordant.push(c as f64);
+            }
+            list!(
+                taxid = u8_to_list_rstr(taxid),
+                reads = reads,
+                mapped = mapped,
+                concordant = concordant
+            )
+        })
+        .map_err(RError::from)
+}
+
+/// Build the taxid postings index of a Kraken2 output
+#[extendr]
+fn kractor_taxid_index(koutput: &str, index: &str) -> std::result::Result<List, RError> {
+    taxid_index::build_taxid_index(koutput, index)
+        .map(|(taxids, reads)| list!(taxids = taxids as f64, reads = reads as f64))
+        .map_err(RError::from)
+}
+
+/// Retrieve the reads classified to some taxids from a taxid postings index,
+/// moved into the Arrow stream at the address `stream` if any
+#[extendr]
+fn kractor_taxid_reads(
+    index: &str,
+    taxids: Vec<String>,
+    stream: Option<&str>,
+) -> std::result::Result<List, RError> {
+    let taxids = taxids.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    let reads = taxid_index::query_taxid_index(index, &taxids).map_err(RError::from)?;
+    if let Some(stream) = stream {
+        let mut builder = ArrowStreamBuilder::new(&["taxid", "id"], ARROW_BATCH_ROWS);
+        for (taxid, id) in reads {
+            builder.push_row(&[&taxid, &id]).map_err(RError::from)?;
+        }
+        builder.export(stream).map_err(RError::from)?;
+        return Ok(list!());
+    }
+    let (taxid, id): (Vec<_>, Vec<_>) = reads.into_iter().unzip();
+    Ok(list!(taxid = alt_strings(taxid), id = alt_strings(id)))
 }
 
 #[extendr]
@@ -79,93 +432,69 @@ fn pprof_kractor_koutput(
     taxa: Robj,
     taxids: Robj,
     exclude: Robj,
-    descendants: bool,
-    ofile: &str,
-    compression_level: i32,
-    batch_size: usize,
-    chunk_bytes: usize,
-    nqueue: Option<usize>,
-    threads: usize,
+    ofile: Option<&str>,
+    options: Robj,
     pprof_file: &str,
-) -> std::result::Result<(), String> {
+) -> std::result::Result<List, RError> {
     let guard = pprof::ProfilerGuardBuilder::default()
         .frequency(2000)
         .build()
         .with_context(|| format!("cannot create profile guard"))
-        .map_err(|e| format!("{:?}", e))?;
+        .map_err(RError::from)?;
     let out = kractor_koutput(
-        kreport,
-        koutput,
-        taxonomy,
-        ranks,
-        taxa,
-        taxids,
-        exclude,
-        descendants,
-        ofile,
-        compression_level,
-        batch_size,
-        chunk_bytes,
-        nqueue,
-        threads,
+        kreport, koutput, taxonomy, ranks, taxa, taxids, exclude, ofile, options,
     );
     if let Ok(report) = guard.report().build() {
         let file = std::fs::File::create(pprof_file)
             .with_context(|| format!("Failed to create file {}", pprof_file))
-            .map_err(|e| format!("{:?}", e))?;
+            .map_err(RError::from)?;
         let mut options = pprof::flamegraph::Options::default();
         options.image_width = Some(2500);
         report
             .flamegraph_with_options(file, &mut options)
             .with_context(|| format!("Failed to write flamegraph to {}", pprof_file))
-            .map_err(|e| format!("{:?}", e))?;
+            .map_err(RError::from)?;
     };
     out
 }
 
 #[extendr]
-#[allow(clippy::too_many_arguments)]
 #[cfg(feature = "bench")]
 fn pprof_kractor_reads(
-    koutput: &str,
+    koutput: Option<&str>,
+    classified: Option<Vec<String>>,
     fq1: &str,
     ofile1: Option<&str>,
     fq2: Option<&str>,
     ofile2: Option<&str>,
-    compression_level: i32,
-    batch_size: usize,
-    chunk_bytes: usize,
-    nqueue: Option<usize>,
-    threads: usize,
+    options: Robj,
     pprof_file: &str,
-) -> std::result::Result<(), String> {
+) -> std::result::Result<List, RError> {
     let guard = pprof::ProfilerGuardBuilder::default()
         .frequency(2000)
         .build()
         .with_context(|| format!("cannot create profile guard"))
-        .map_err(|e| format!("{:?}", e))?;
+        .map_err(RError::from)?;
     let out = kractor_reads(
         koutput,
+        classified,
         fq1,
         ofile1,
         fq2,
         ofile2,
-        compression_level,
-        batch_size,
-        chunk_bytes,
-        nqueue,
-        threads,
+        Robj::from(()),
+        options,
     );
     if let Ok(report) = guard.report().build() {
         let file = std::fs::File::create(pprof_file)
             .with_context(|| format!("Failed to create file {}", pprof_file))
-            .map_err(|e| format!("{:?}", e))?;
+            .map_err(RError::from)?;
         let mut options = pprof::flamegraph::Options::default();
         options.image_width = Some(2500);
         report
             .flamegraph_with_options(file, &mut options)
             .with_context(|| format!("Failed to write flamegraph to {}", pprof_file))
-            .map_err(|e| format!("{:?}", e))?;
+            .map_err(RError::from)?;
     };
     out
 }
@@ -173,15 +502,31 @@ fn pprof_kractor_reads(
 #[cfg(not(feature = "bench"))]
 extendr_module! {
     mod kractor;
+    use iter;
     fn kractor_koutput;
+    fn kractor_koutput_taxa;
+    fn kractor_fasta;
     fn kractor_reads;
+    fn kractor_route;
+    fn kractor_taxids;
+    fn kractor_validate;
+    fn kractor_taxid_index;
+    fn kractor_taxid_reads;
 }
 
 #[cfg(feature = "bench")]
 extendr_module! {
     mod kractor;
+    use iter;
     fn kractor_koutput;
+    fn kractor_koutput_taxa;
+    fn kractor_fasta;
     fn kractor_reads;
+    fn kractor_route;
+    fn kractor_taxids;
+    fn kractor_validate;
+    fn kractor_taxid_index;
+    fn kractor_taxid_reads;
     fn pprof_kractor_koutput;
     fn pprof_kractor_reads;
 }
@@ -0,0 +1,290 @@
+use anyhow::Result;
+use bytes::Bytes;
+use extendr_api::prelude::*;
+use rustc_hash::FxHashSet as HashSet;
+
+use crate::error::ErrorKind;
+use crate::fastq_record::FastqRecord;
+use crate::record_filter::RecordFilter;
+use crate::seq_range::{SeqRange, SeqRanges};
+use crate::utils::*;
+use crate::whitelist::Whitelist;
+
+/// Where the cell barcode of a read is found
+pub(crate) enum BarcodeSource {
+    /// A tag of the `MIRE{}` annotation embedded by `seq_refine()`, looked up
+    /// in the first mate carrying it
+    Tag(Vec<u8>),
+    /// Ranges of the first mate sequence, concatenated
+    Ranges(SeqRanges),
+}
+
+impl BarcodeSource {
+    /// The barcode of a read, `None` if the tag is missing or the sequence is
+    /// too short for the ranges
+    pub(crate) fn barcode(
+        &self,
+        record1: &FastqRecord<Bytes>,
+        record2: Option<&FastqRecord<Bytes>>,
+    ) -> Option<Vec<u8>> {
+        match self {
+            BarcodeSource::Tag(tag) => std::iter::once(record1)
+                .chain(record2)
+                .find_map(|record| mire_tag(record.desc.as_ref()?, tag))
+                .map(|barcode| barcode.to_vec()),
+            BarcodeSource::Ranges(ranges) => extract_ranges(ranges, &record1.seq),
+        }
+    }
+
+    /// The base qualities of the barcode of a read, only known for ranges of
+    /// the first mate
+    pub(crate) fn quality(&self, record1: &FastqRecord<Bytes>) -> Option<Vec<u8>> {
+        match self {
+            BarcodeSource::Tag(_) => None,
+            BarcodeSource::Ranges(ranges) => extract_ranges(ranges, &record1.qual),
+        }
+    }
+}
+
+fn extract_ranges(ranges: &SeqRanges, bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    for range in ranges {
+        out.extend_from_slice(range.try_extract(bytes).ok()?);
+    }
+    Some(out)
+}
+
+/// Write `barcode` over the ranges of `seq` it was extracted from
+fn replace_ranges(ranges: &SeqRanges, seq: &[u8], barcode: &[u8]) -> Vec<u8> {
+    let mut out = seq.to_vec();
+    let mut barcode = barcode;
+    for range in ranges {
+        let (start, end) = match *range {
+            SeqRange::From(start) => (start, seq.len()),
+            SeqRange::To(end) => (0, end),
+            SeqRange::Span(start, end) => (start, end),
+        };
+        let (part, rest) = barcode.split_at(end - start);
+        out[start .. end].copy_from_slice(part);
+        barcode = rest;
+    }
+    out
+}
+
+impl TryFrom<&Robj> for BarcodeSource {
+    type Error = anyhow::Error;
+    fn try_from(value: &Robj) -> Result<Self> {
+        if let Some(tag) = value.as_str() {
+            return Ok(BarcodeSource::Tag(tag.as_bytes().to_vec()));
+        }
+        SeqRanges::try_from(value)
+            .map(BarcodeSource::Ranges)
+            .map_err(|e| ErrorKind::Config.error(format!("Invalid barcode specification: {}", e)))
+    }
+}
+
+/// Keep only reads whose cell barcode is in an allow-list, so background
+/// droplets can be dropped in the same pass as taxid filtering
+pub(crate) struct BarcodeFilter {
+    source: BarcodeSource,
+    allowed: HashSet<Vec<u8>>,
+    // Barcodes one substitution away from an allowed barcode are corrected to
+    // it, as `seq_refine()` does with a whitelist
+    correction: Option<Whitelist>,
+}
+
+impl BarcodeFilter {
+    pub(crate) fn new(source: BarcodeSource, barcodes: Vec<String>) -> Self {
+        Self {
+            source,
+            allowed: barcodes.into_iter().map(String::into_bytes).collect(),
+            correction: None,
+        }
+    }
+
+    /// Also keep the reads whose barcode can be corrected to an allowed
+    /// barcode, weighting the candidates by the base qualities of the barcode
+    /// when it is read from ranges
+    pub(crate) fn with_correction(mut self) -> Self {
+        self.correction = Some(Whitelist::from_barcodes(self.allowed.iter().cloned()));
+        self
+    }
+
+    /// The allowed barcode a barcode is corrected to, `None` if it is allowed
+    /// as it is, if it cannot be corrected or without correction
+    fn corrected(&self, barcode: &[u8], qual: Option<&[u8]>) -> Option<Vec<u8>> {
+        if self.allowed.contains(barcode) {
+            return None;
+        }
+        let whitelist = self.correction.as_ref()?;
+        whitelist.correct(barcode, qual).map(|x| x.into_owned())
+    }
+
+    /// Write the corrected barcode of a record: into its tag, and into its
+    /// sequence when it is the first mate
+    fn correct_record(&self, mut record: FastqRecord<Bytes>, first: bool) -> FastqRecord<Bytes> {
+        match &self.source {
+            BarcodeSource::Tag(tag) => {
+                let Some(desc) = &record.desc else {
+                    return record;
+                };
+                let corrected = mire_tag(desc, tag).and_then(|x| self.corrected(x, None));
+                if let Some(corrected) = corrected {
+                    record.desc = Some(set_mire_tags(desc, &[(tag, corrected)]));
+                }
+            }
+            BarcodeSource::Ranges(ranges) if first => {
+                let corrected = extract_ranges(ranges, &record.seq).and_then(|barcode| {
+                    let qual = extract_ranges(ranges, &record.qual);
+                    self.corrected(&barcode, qual.as_deref())
+                });
+                if let Some(corrected) = corrected {
+                    record.seq = Bytes::from(replace_ranges(ranges, &record.seq, &corrected));
+                }
+            }
+            BarcodeSource::Ranges(_) => {}
+        }
+        record
+    }
+
+    pub(crate) fn allows(
+        &self,
+        record1: &FastqRecord<Bytes>,
+        record2: Option<&FastqRecord<Bytes>>,
+    ) -> bool {
+        let Some(barcode) = self.source.barcode(record1, record2) else {
+            return false;
+        };
+        if self.allowed.contains(&barcode) {
+            return true;
+        }
+        self.correction.as_ref().is_some_and(|whitelist| {
+            let qual = self.source.quality(record1);
+            whitelist.correct(&barcode, qual.as_deref()).is_some()
+        })
+    }
+}
+
+impl RecordFilter for BarcodeFilter {
+    fn accept(&self, records: &[&FastqRecord<Bytes>]) -> bool {
+        self.allows(records[0], records.get(1).copied())
+    }
+
+    fn transform(&self, record: FastqRecord<Bytes>) -> FastqRecord<Bytes> {
+        if self.correction.is_none() {
+            return record;
+        }
+        self.correct_record(record, true)
+    }
+
+    fn transform_pair(
+        &self,
+        record1: FastqRecord<Bytes>,
+        record2: FastqRecord<Bytes>,
+    ) -> (FastqRecord<Bytes>, FastqRecord<Bytes>) {
+        if self.correction.is_none() {
+            return (record1, record2);
+        }
+        (
+            self.correct_record(record1, true),
+            self.correct_record(record2, false),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seq_range::SeqRange;
+
+    fn record(desc: Option<&'static str>, seq: &'static str) -> FastqRecord<Bytes> {
+        FastqRecord::new(
+            Bytes::from("r1"),
+            desc.map(Bytes::from),
+            Bytes::from(seq),
+            Bytes::from("+"),
+            Bytes::from("I".repeat(seq.len())),
+        )
+    }
+
+    #[test]
+    fn test_barcode_filter() {
+        let barcodes = vec!["AAAC".to_string(), "CCCA".to_string()];
+        let filter = BarcodeFilter::new(BarcodeSource::Tag(b"CB".to_vec()), barcodes.clone());
+        assert!(filter.allows(&record(Some("MIRE{CB:AAAC:UB:TTTT}"), "ACGT"), None));
+        assert!(!filter.allows(&record(Some("MIRE{CB:GGGG}"), "ACGT"), None));
+        assert!(!filter.allows(&record(None, "ACGT"), None));
+        // The tag may only be found in the second mate
+        assert!(filter.allows(
+            &record(None, "ACGT"),
+            Some(&record(Some("MIRE{CB:CCCA}"), "ACGT"))
+        ));
+
+        let ranges = SeqRanges::from(vec![
+            SeqRange::new(None, Some(2)),
+            SeqRange::new(Some(4), None),
+        ]);
+        let filter = BarcodeFilter::new(BarcodeSource::Ranges(ranges), barcodes);
+        assert!(filter.allows(&record(None, "AAGGAC"), None));
+        assert!(!filter.allows(&record(None, "AAGGAG"), None));
+        // Too short for the ranges
+        assert!(!filter.allows(&record(None, "AAG"), None));
+    }
+
+    #[test]
+    fn test_barcode_filter_correction() {
+        let barcodes = vec!["AAAA".to_string(), "TAAC".to_string()];
+        let ranges = SeqRanges::from(vec![SeqRange::new(None, Some(4))]);
+        let filter = BarcodeFilter::new(BarcodeSource::Ranges(ranges), barcodes.clone());
+        assert!(!filter.allows(&record(None, "AAAGGT"), None));
+        let filter = filter.with_correction();
+        assert!(filter.allows(&record(None, "AAAGGT"), None));
+        assert!(!filter.allows(&record(None, "GGGGGT"), None));
+
+        // TAAA is one substitution away from both barcodes, the low quality
+        // base tells which one is wrong
+        let mut read = record(None, "TAAAGT");
+        assert!(!filter.allows(&read, None));
+        read.qual = Bytes::from("!IIIII");
+        assert!(filter.allows(&read, None));
+
+        // Tags carry no qualities, only unambiguous barcodes are corrected
+        let filter =
+            BarcodeFilter::new(BarcodeSource::Tag(b"CB".to_vec()), barcodes).with_correction();
+        assert!(filter.allows(&record(Some("MIRE{CB:CAAA}"), "ACGT"), None));
+        assert!(!filter.allows(&record(Some("MIRE{CB:TAAA}"), "ACGT"), None));
+    }
+
+    #[test]
+    fn test_barcode_filter_writes_corrections() {
+        // Lowercase allowed barcodes match too
+        let barcodes = vec!["aaaa".to_string(), "TAAC".to_string()];
+        let ranges = SeqRanges::from(vec![
+            SeqRange::new(None, Some(2)),
+            SeqRange::new(Some(4), Some(6)),
+        ]);
+        let filter =
+            BarcodeFilter::new(BarcodeSource::Ranges(ranges), barcodes.clone()).with_correction();
+        // The barcode bases of the first mate sequence are corrected, those of
+        // the second mate and the qualities are kept
+        let (record1, record2) =
+            filter.transform_pair(record(None, "AAGGAGTT"), record(None, "AAGGAGTT"));
+        assert_eq!(record1.seq, "AAGGAATT");
+        assert_eq!(record1.qual, "IIIIIIII");
+        assert_eq!(record2.seq, "AAGGAGTT");
+        assert_eq!(filter.transform(record(None, "TAGGACTT")).seq, "TAGGACTT");
+        assert_eq!(filter.transform(record(None, "GGGGGGTT")).seq, "GGGGGGTT");
+
+        // The tag of every mate carrying it is corrected
+        let filter =
+            BarcodeFilter::new(BarcodeSource::Tag(b"CB".to_vec()), barcodes).with_correction();
+        let (record1, record2) = filter.transform_pair(
+            record(Some("1:N:0 MIRE{CB:CAAA:UB:TTTT}"), "ACGT"),
+            record(Some("2:N:0 MIRE{CB:CAAA}"), "ACGT"),
+        );
+        assert_eq!(record1.desc.unwrap(), "1:N:0 MIRE{UB:TTTT:CB:AAAA}");
+        assert_eq!(record2.desc.unwrap(), "2:N:0 MIRE{CB:AAAA}");
+        let record1 = filter.transform(record(Some("MIRE{CB:TAAC}"), "ACGT"));
+        assert_eq!(record1.desc.unwrap(), "MIRE{CB:TAAC}");
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::hash_map::Entry;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use rustc_hash::FxHashMap as HashMap;
+use rustc_hash::FxHashSet as HashSet;
+
+use crate::fastq_record::FastqRecord;
+use crate::record_filter::RecordFilter;
+use crate::warnings::warn;
+
+/// Number of duplicate sequence IDs quoted in a warning
+const EXAMPLES: usize = 3;
+
+/// Duplicate sequence IDs met in an input, usually from files concatenated
+/// by mistake: their number and the first few of them
+#[derive(Debug, Default)]
+pub(super) struct Duplicates {
+    count: usize,
+    examples: Vec<Vec<u8>>,
+}
+
+impl Duplicates {
+    fn add(&mut self, id: &[u8]) {
+        self.count += 1;
+        if self.examples.len() < EXAMPLES {
+            self.examples.push(id.to_vec());
+        }
+    }
+
+    /// Warn of the duplicates met in `input`, if any
+    pub(super) fn warn(&self, input: &str) {
+        if self.count == 0 {
+            return;
+        }
+        warn(format!(
+            "{} duplicate sequence ID{} in {}, e.g. {}: only the first was kept",
+            self.count,
+            if self.count == 1 { "" } else { "s" },
+            input,
+            self.examples
+                .iter()
+                .map(|id| String::from_utf8_lossy(id))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+}
+
+/// Map the sequence IDs of a Kraken2 output to their taxid. The first line of
+/// a duplicate ID wins, whatever the order in which the map is filled.
+pub(super) fn koutput_taxids(ids: &[(Vec<u8>, Vec<u8>)]) -> (HashMap<&[u8], &[u8]>, Duplicates) {
+    let mut map = HashMap::with_capacity_and_hasher(ids.len(), Default::default());
+    let mut duplicates = Duplicates::default();
+    for (id, taxid) in ids {
+        match map.entry(id.as_slice()) {
+            Entry::Occupied(_) => duplicates.add(id),
+            Entry::Vacant(entry) => {
+                entry.insert(taxid.as_slice());
+            }
+        }
+    }
+    (map, duplicates)
+}
+
+/// Drop the matched reads whose sequence ID was already extracted, tracking
+/// them as duplicates. Only the IDs of matched reads are held.
+#[derive(Default)]
+pub(super) struct DuplicateFilter {
+    seen: Mutex<(HashSet<Vec<u8>>, Duplicates)>,
+}
+
+impl DuplicateFilter {
+    pub(super) fn duplicates(self) -> Duplicates {
+        self.seen.into_inner().unwrap_or_else(|e| e.into_inner()).1
+    }
+}
+
+impl RecordFilter for &DuplicateFilter {
+    fn accept(&self, records: &[&FastqRecord<Bytes>]) -> bool {
+        let id = records[0].id.as_ref();
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        if seen.0.contains(id) {
+            seen.1.add(id);
+            false
+        } else {
+            seen.0.insert(id.to_vec());
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicates() {
+        let ids = vec![
+            (b"r1".to_vec(), b"562".to_vec()),
+            (b"r2".to_vec(), b"1280".to_vec()),
+            (b"r1".to_vec(), b"9606".to_vec()),
+        ];
+        let (map, duplicates) = koutput_taxids(&ids);
+        assert_eq!(map.get(b"r1".as_slice()), Some(&b"562".as_slice()));
+        assert_eq!(duplicates.count, 1);
+        assert_eq!(duplicates.examples, [b"r1".to_vec()]);
+
+        let filter = DuplicateFilter::default();
+        let record = |id: &'static str| {
+            FastqRecord::new(
+                Bytes::from(id),
+                None,
+                Bytes::from("ACGT"),
+                Bytes::from("+"),
+                Bytes::from("IIII"),
+            )
+        };
+        let accepted = ["r1", "r2", "r1", "r1"]
+            .map(|id| (&filter).accept(&[&record(id)]))
+            .to_vec();
+        assert_eq!(accepted, [true, true, false, false]);
+        assert_eq!(filter.duplicates().count, 2);
+    }
+}
@@ -0,0 +1,288 @@
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::error::ErrorKind;
+use crate::fastq_reader::*;
+use crate::fastq_record::FastqParseError;
+use crate::kractor::counts::{KractorCounts, ReadFate};
+use crate::kractor::reads::{ExtractOptions, MatchedRead, ReadCallback, ReadTags, ReadTaxids};
+use crate::part_writer::{PartCounter, PartWriter};
+use crate::record_filter::{FilterChain, RecordFilter};
+use crate::record_index::ChunkRecords;
+use crate::remote::is_remote;
+use crate::utils::*;
+
+// Inputs of a few megabytes, e.g. the reads of a single cell or of a unit
+// test, are extracted in the calling thread from their content in memory:
+// for them, setting up the threads, channels and progress bars of the
+// pipeline takes longer than the extraction itself.
+
+/// Whether `inputs` are local files of `max_bytes` in total at most
+pub(super) fn fits_in_memory(inputs: &[&str], max_bytes: u64) -> Result<bool> {
+    let mut total = 0;
+    for input in inputs {
+        if is_remote(Path::new(input)) {
+            return Ok(false);
+        }
+        match input_size(input)? {
+            Some(size) => total += size,
+            // Named pipes can't be sized
+            None => return Ok(false),
+        }
+    }
+    Ok(total <= max_bytes)
+}
+
+/// The output of a mate: its writer and the chunk being filled
+struct MateOutput<'a> {
+    writer: PartWriter<'a>,
+    packer: ChunkPacker,
+    pool: Vec<u8>,
+    // IDs and offsets of the records in the pool, for the index
+    records: ChunkRecords,
+}
+
+/// Pack the chunks of all mates and write them into the same part
+fn flush(
+    outputs: &mut [Option<MateOutput>],
+    counter: &mut PartCounter,
+    chunk_bytes: usize,
+) -> Result<()> {
+    let mut chunks = Vec::with_capacity(outputs.len());
+    for output in outputs.iter_mut() {
+        chunks.push(
+            output
+                .as_mut()
+                .map(|output| -> Result<_> {
+                    let pool = std::mem::replace(&mut output.pool, Vec::with_capacity(chunk_bytes));
+                    Ok((
+                        output.packer.pack(pool)?,
+                        std::mem::take(&mut output.records),
+                    ))
+                })
+                .transpose()?,
+        );
+    }
+    let sizes: Vec<usize> = chunks
+        .iter()
+        .map(|chunk| chunk.as_ref().map_or(0, |x| x.0.len()))
+        .collect();
+    let part = counter.assign(&sizes);
+    for (output, chunk) in outputs.iter_mut().zip(chunks) {
+        if let (Some(output), Some(chunk)) = (output, chunk) {
+            output
+                .writer
+                .write_records(part, &chunk)
+                .context("(Writer) Failed to write FASTQ records to output")?;
+        }
+    }
+    Ok(())
+}
+
+/// Extract the reads of `inputs`, one per mate, held in memory, in the
+/// calling thread. Reads are written to the output of each mate if any, or
+/// handed over to `callback`; without either, they are only counted.
+pub(super) fn parse_in_memory(
+    taxids: &ReadTaxids,
+    inputs: &[&str],
+    outputs: &[Option<&str>],
+    callback: Option<ReadCallback>,
+    filters: &FilterChain,
+    tags: &ReadTags,
+    options: &ExtractOptions,
+) -> Result<KractorCounts> {
+    let ExtractOptions {
+        by_taxon,
+        stats,
+        index,
+        compression_level,
+        max_file_bytes,
+        batch_size,
+        chunk_bytes,
+        ..
+    } = *options;
+    let compression_levels = &[compression_level.0, compression_level.1][.. inputs.len()];
+    let mut readers = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        let mut data = Vec::new();
+        new_reader(input, BUFFER_SIZE, None)?
+            .read_to_end(&mut data)
+            .with_context(|| format!("(Reader) Failed to read {}", input))?;
+        readers.push(FastqReader::new(std::io::Cursor::new(data)));
+    }
+    let mut outputs = outputs
+        .iter()
+        .zip(compression_levels)
+        .map(|(output, &level)| {
+            output
+                .map(|output| -> Result<_> {
+                    let path = Path::new(output);
+                    Ok(MateOutput {
+                        writer: PartWriter::new(path, max_file_bytes, chunk_bytes, None)
+                            .with_index(index)?,
+                        packer: ChunkPacker::new(Compression::of(Some(path), level)?),
+                        pool: Vec::with_capacity(chunk_bytes),
+                        records: Vec::new(),
+                    })
+                })
+                .transpose()
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut counter = PartCounter::new(max_file_bytes, inputs.len());
+    let dry_run = outputs.iter().all(|x| x.is_none()) && callback.is_none();
+
+    let mut counts = KractorCounts::new(by_taxon);
+    if !filters.is_empty() {
+        counts = counts.with_dropped();
+    }
+    if stats {
+        counts = counts.with_stats(inputs.len());
+    }
+    let mut matched: Vec<MatchedRead> = Vec::new();
+    let mut callback = callback;
+    loop {
+        let mut records = Vec::with_capacity(readers.len());
+        for reader in readers.iter_mut() {
+            records.push(
+                reader
+                    .read_record()
+                    .context("(Reader) Failed to read FASTQ record")?,
+            );
+        }
+        let records: Vec<_> = match records.iter().filter(|x| x.is_some()).count() {
+            0 => break,
+            n if n < records.len() => {
+                return Err(ErrorKind::Pairing.error(format!(
+                    "(Reader) FASTQ pairing error: read{} ended before read{}",
+                    records.iter().position(|x| x.is_none()).unwrap_or(0) + 1,
+                    records.iter().position(|x| x.is_some()).unwrap_or(0) + 1
+                )));
+            }
+            _ => records.into_iter().flatten().collect(),
+        };
+        if let [record1, record2] = records.as_slice() {
+            if record1.id != record2.id {
+                return Err(anyhow::Error::new(FastqParseError::FastqPairError {
+                    read1_id: String::from_utf8_lossy(&record1.id).to_string(),
+                    read2_id: String::from_utf8_lossy(&record2.id).to_string(),
+                    read1_pos: None,
+                    read2_pos: None,
+                }));
+            }
+        }
+        counts.records += 1;
+        let mates: Vec<_> = records.iter().collect();
+        let Some(taxid) = taxids.taxid(&records[0]) else {
+            counts.add_stats(ReadFate::Unmatched, &mates);
+            continue;
+        };
+        if !filters.accept(&mates) {
+            counts.add_dropped();
+            counts.add_stats(ReadFate::Dropped, &mates);
+            continue;
+        }
+        counts.add_match(Some(taxid));
+        counts.add_stats(ReadFate::Matched, &mates);
+        if dry_run {
+            continue;
+        }
+        let taxid = taxid.to_vec();
+        let mut records = match <[_; 2]>::try_from(records) {
+            Ok([record1, record2]) => {
+                let (record1, record2) = filters.transform_pair(record1, record2);
+                let (record1, record2) = tags.tag_pair(record1, record2)?;
+                vec![record1, record2]
+            }
+            Err(records) => records
+                .into_iter()
+                .map(|record| tags.tag(filters.transform(record)))
+                .collect::<Result<_>>()?,
+        }
+        .into_iter();
+        if let Some(callback) = callback.as_mut() {
+            let record1 = records
+                .next()
+                .ok_or_else(|| anyhow!("No record to extract"))?;
+            matched.push((taxid, record1, records.next()));
+            if matched.len() >= batch_size {
+                callback(std::mem::take(&mut matched))?;
+            }
+            continue;
+        }
+        let records: Vec<_> = records.collect();
+        // Flush when a pool is too full to accept the record of its mate
+        if outputs.iter().zip(&records).any(|(output, record)| {
+            output
+                .as_ref()
+                .is_some_and(|x| x.pool.capacity() - x.pool.len() < record.bytes_size())
+        }) {
+            flush(&mut outputs, &mut counter, chunk_bytes)?;
+        }
+        for (output, record) in outputs.iter_mut().zip(records) {
+            if let Some(output) = output {
+                if index {
+                    output.records.push((record.id.clone(), output.pool.len()));
+                }
+                record.extend(&mut output.pool);
+            }
+        }
+    }
+
+    // Flush remaining records if any
+    if let Some(callback) = callback.as_mut().filter(|_| !matched.is_empty()) {
+        callback(matched)?;
+    }
+    if outputs.iter().flatten().any(|x| !x.pool.is_empty()) {
+        flush(&mut outputs, &mut counter, chunk_bytes)?;
+    }
+    for output in outputs.iter_mut().flatten() {
+        output
+            .writer
+            .finish()
+            .context("(Writer) Failed to flush writer")?;
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_in_memory() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let input = temp.path().join("reads.fq");
+        std::fs::write(
+            &input,
+            "@r1\nACGT\n+\nIIII\n@r2\nGGCC\n+\nIIII\n@r3\nTTTT\n+\nIIII\n",
+        )?;
+        let input = input.to_str().unwrap();
+        assert!(fits_in_memory(&[input], 1024)?);
+        assert!(!fits_in_memory(&[input], 8)?);
+
+        let output = temp.path().join("microbe.fq");
+        let output = output.to_str().unwrap();
+        let taxids = ReadTaxids::Koutput(
+            [(&b"r1"[..], &b"562"[..]), (&b"r3"[..], &b"562"[..])]
+                .into_iter()
+                .collect(),
+        );
+        let counts = parse_in_memory(
+            &taxids,
+            &[input],
+            &[Some(output)],
+            None,
+            &FilterChain::new(),
+            &ReadTags::default(),
+            &ExtractOptions::new(10, 1024),
+        )?;
+        assert_eq!((counts.records, counts.matched), (3, 2));
+        assert_eq!(
+            std::fs::read_to_string(output)?,
+            "@r1\nACGT\n+\nIIII\n@r3\nTTTT\n+\nIIII\n"
+        );
+        Ok(())
+    }
+}
@@ -1,128 +1,335 @@
 use std::fmt::Display;
-use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::Path;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use rustc_hash::FxHashMap as HashMap;
 use rustc_hash::FxHashSet as HashSet;
 
+pub(crate) mod barcode;
+mod duplicates;
+mod memory;
 mod paired;
 mod single;
+mod spill;
+mod tags;
 
-use indicatif::{MultiProgress, ProgressBar, ProgressFinish};
+use extendr_api::prelude::*;
 
+use crate::error::ErrorKind;
+use crate::fastq_record::FastqRecord;
+use crate::kractor::counts::KractorCounts;
+use crate::record_filter::{FilterChain, GcFilter};
+use crate::seq_refine::seq_action::SubseqActions;
+use crate::threads::StageThreads;
 use crate::utils::*;
+use barcode::{BarcodeFilter, BarcodeSource};
+use tags::ReadTags;
+
+/// Where the taxid of a read comes from
+pub(super) enum ReadTaxids<'a> {
+    /// Sequence ID → taxid, from the Kraken2 output
+    Koutput(HashMap<&'a [u8], &'a [u8]>),
+    /// The `kraken:taxid|N` token Kraken2 appends to the headers of its
+    /// `--classified-out` reads, only the given taxids are matched
+    Headers(HashSet<&'a [u8]>),
+    /// Sequence ID hash → taxid, spilled from the Kraken2 output
+    Hashed(spill::HashedIds),
+}
+
+impl ReadTaxids<'_> {
+    /// The taxid of a read, or `None` if the read is not matched
+    pub(super) fn taxid<'r>(&'r self, record: &'r FastqRecord<Bytes>) -> Option<&'r [u8]> {
+        match self {
+            Self::Koutput(id_sets) => id_sets.get(record.id.as_ref()).copied(),
+            Self::Headers(taxids) => record
+                .desc
+                .as_deref()
+                .and_then(kraken_header_taxid)
+                .or_else(|| kraken_header_taxid(&record.id))
+                .filter(|taxid| taxids.contains(taxid)),
+            Self::Hashed(ids) => ids.taxid(&record.id),
+        }
+    }
+}
+
+/// Options of `kractor_reads()`: how the matched reads are filtered and
+/// annotated, and how they are extracted
+pub(super) struct ReadsOptions<'a> {
+    // Allow-list of cell barcodes, read from `barcode`
+    pub(super) barcodes: Option<Vec<String>>,
+    pub(super) barcode: &'a Robj,
+    pub(super) correct_barcodes: bool,
+    pub(super) read_group: Option<&'a str>,
+    pub(super) transfer_tags: bool,
+    pub(super) actions: (Option<SubseqActions>, Option<SubseqActions>),
+    pub(super) strip_tags: bool,
+    pub(super) gc_range: Option<(f64, f64)>,
+    // Inputs of up to this many bytes are extracted in memory
+    pub(super) in_memory: u64,
+    pub(super) min_taxon_reads: Option<usize>,
+    pub(super) spill_dir: Option<&'a str>,
+    pub(super) columns: KoutputColumns,
+    pub(super) check_duplicates: bool,
+    pub(super) extract: ExtractOptions,
+}
+
+/// Options of the extraction of the matched reads, the same for the single-end,
+/// paired-end and in-memory paths
+#[derive(Clone, Copy)]
+pub(super) struct ExtractOptions {
+    // Only match and count the records
+    pub(super) dry_run: bool,
+    pub(super) by_taxon: bool,
+    pub(super) stats: bool,
+    pub(super) index: bool,
+    // Compression level of each mate
+    pub(super) compression_level: (Option<i32>, Option<i32>),
+    pub(super) max_file_bytes: Option<u64>,
+    pub(super) batch_size: usize,
+    pub(super) chunk_bytes: usize,
+    pub(super) nqueue: Option<usize>,
+    pub(super) threads: StageThreads,
+}
+
+#[cfg(test)]
+impl ExtractOptions {
+    /// Options writing batches of `batch_size` records in chunks of
+    /// `chunk_bytes`, with a single parser thread
+    pub(super) fn new(batch_size: usize, chunk_bytes: usize) -> Self {
+        Self {
+            dry_run: false,
+            by_taxon: false,
+            stats: false,
+            index: false,
+            compression_level: (None, None),
+            max_file_bytes: None,
+            batch_size,
+            chunk_bytes,
+            nqueue: None,
+            threads: StageThreads::new(1),
+        }
+    }
+}
+
+/// A matched read handed over to a callback instead of written to disk: its
+/// taxid, its first mate and the second mate of paired reads
+pub(super) type MatchedRead = (Vec<u8>, FastqRecord<Bytes>, Option<FastqRecord<Bytes>>);
+
+/// Called on the calling thread with batches of matched reads
+pub(super) type ReadCallback<'a> = &'a mut dyn FnMut(Vec<MatchedRead>) -> Result<()>;
+
+/// Convert a batch of matched reads into an R list of columns `id`, `taxid`,
+/// and `seq` and `qual`, or `seq1`, `qual1`, `seq2` and `qual2` for paired
+/// reads
+pub(super) fn matched_reads_list(reads: Vec<MatchedRead>) -> List {
+    let text = |x: &[u8]| String::from_utf8_lossy(x).into_owned();
+    let mut id = Vec::with_capacity(reads.len());
+    let mut taxid = Vec::with_capacity(reads.len());
+    let mut seq1 = Vec::with_capacity(reads.len());
+    let mut qual1 = Vec::with_capacity(reads.len());
+    let mut seq2 = Vec::new();
+    let mut qual2 = Vec::new();
+    let paired = reads.first().is_some_and(|read| read.2.is_some());
+    for (read_taxid, record1, record2) in reads {
+        id.push(text(&record1.id));
+        taxid.push(text(&read_taxid));
+        seq1.push(text(&record1.seq));
+        qual1.push(text(&record1.qual));
+        if let Some(record2) = record2 {
+            seq2.push(text(&record2.seq));
+            qual2.push(text(&record2.qual));
+        }
+    }
+    if paired {
+        list!(
+            id = id,
+            taxid = taxid,
+            seq1 = seq1,
+            qual1 = qual1,
+            seq2 = seq2,
+            qual2 = qual2
+        )
+    } else {
+        list!(id = id, taxid = taxid, seq = seq1, qual = qual1)
+    }
+}
 
 pub(super) fn kractor_reads(
-    koutput: &str,
+    koutput: Option<&str>,
+    classified: Option<Vec<String>>,
     fq1: &str,
     ofile1: Option<&str>,
     fq2: Option<&str>,
     ofile2: Option<&str>,
-    compression_level: i32,
-    batch_size: usize,
-    chunk_bytes: usize,
-    nqueue: Option<usize>,
-    threads: usize,
-) -> Result<()> {
-    let ids = read_sequence_id_from_koutput(koutput, 126 * 1024)
-        .map_err(|e| anyhow!("Failed to read sequence IDs: {}", e))?;
-    let id_sets = ids
-        .iter()
-        .map(|id| id.as_slice())
-        .collect::<HashSet<&[u8]>>();
-    let threads = threads.max(1); // always use at least one thread
-    if let Some(fq2) = fq2 {
+    callback: Option<ReadCallback>,
+    options: ReadsOptions,
+) -> Result<KractorCounts> {
+    let ReadsOptions {
+        barcodes,
+        barcode,
+        correct_barcodes,
+        read_group,
+        transfer_tags,
+        actions,
+        strip_tags,
+        gc_range,
+        in_memory,
+        min_taxon_reads,
+        spill_dir,
+        columns,
+        check_duplicates,
+        extract,
+    } = options;
+    let columns = &columns;
+    let duplicate_filter = check_duplicates.then(duplicates::DuplicateFilter::default);
+    // Matched reads must also pass the GC content range and carry an allowed
+    // cell barcode when an allow-list is given, and be extracted once when
+    // duplicates are checked
+    let mut filters = FilterChain::new();
+    if let Some((min, max)) = gc_range {
+        filters.push(GcFilter::new(min, max));
+    }
+    if let Some(barcodes) = barcodes {
+        let filter = BarcodeFilter::new(BarcodeSource::try_from(barcode)?, barcodes);
+        if correct_barcodes {
+            filters.push(filter.with_correction());
+        } else {
+            filters.push(filter);
+        }
+    }
+    if let Some(filter) = &duplicate_filter {
+        filters.push(filter);
+    }
+    let mut tags = ReadTags::new(read_group.map(|x| x.as_bytes()));
+    if transfer_tags {
+        tags = tags.with_transfer(BarcodeSource::try_from(barcode).ok());
+    }
+    let tags = tags
+        .with_actions(actions.0, actions.1, fq2.is_some())
+        .with_strip(strip_tags);
+    let mut ids;
+    let taxids = match (koutput, &classified, spill_dir) {
+        // The IDs are spilled to disk rather than held as strings
+        (Some(koutput), None, Some(dir)) => ReadTaxids::Hashed(
+            spill::HashedIds::spill_koutput(koutput, Path::new(dir), min_taxon_reads, columns)
+                .context("Failed to spill sequence IDs")?,
+        ),
+        (Some(koutput), None, None) => {
+            ids = read_sequence_id_from_koutput(koutput, 126 * 1024, columns)
+                .context("Failed to read sequence IDs")?;
+            if let Some(min_reads) = min_taxon_reads {
+                drop_rare_taxa(&mut ids, min_reads);
+            }
+            // Map sequence ID → taxid, the taxid is used for per-taxon counting
+            let (id_sets, duplicates) = duplicates::koutput_taxids(&ids);
+            if check_duplicates {
+                duplicates.warn(koutput);
+            }
+            ReadTaxids::Koutput(id_sets)
+        }
+        // Kraken2 classified reads carry their taxid, no koutput is needed
+        (None, Some(classified), _) => {
+            ReadTaxids::Headers(classified.iter().map(|x| x.as_bytes()).collect())
+        }
+        _ => {
+            return Err(anyhow!(
+                "Exactly one of 'koutput' and the taxids of classified reads must be provided"
+            ))
+        }
+    };
+    // In dry-run mode, records are only matched and counted
+    let (ofile1, ofile2, callback) = if extract.dry_run {
+        (None, None, None)
+    } else {
+        (ofile1, ofile2, callback)
+    };
+    let inputs: Vec<&str> = std::iter::once(fq1).chain(fq2).collect();
+    let counts = if memory::fits_in_memory(&inputs, in_memory)? {
+        let outputs = &[ofile1, ofile2][.. inputs.len()];
+        if outputs.iter().all(|x| x.is_none()) && callback.is_none() && !extract.dry_run {
+            return Err(anyhow!("No output file specified."));
+        }
+        memory::parse_in_memory(
+            &taxids, &inputs, outputs, callback, &filters, &tags, &extract,
+        )
+    } else if let Some(fq2) = fq2 {
         kractor_reads_paired(
-            &id_sets,
-            fq1,
-            ofile1,
-            fq2,
-            ofile2,
-            batch_size,
-            chunk_bytes,
-            compression_level,
-            nqueue,
-            threads,
+            &taxids, fq1, ofile1, fq2, ofile2, callback, &filters, &tags, &extract,
         )
     } else {
-        kractor_reads_single(
-            &id_sets,
-            fq1,
-            ofile1,
-            batch_size,
-            chunk_bytes,
-            compression_level,
-            nqueue,
-            threads,
-        )
+        kractor_reads_single(&taxids, fq1, ofile1, callback, &filters, &tags, &extract)
+    }?;
+    drop(filters);
+    if let Some(filter) = duplicate_filter {
+        filter.duplicates().warn(fq1);
     }
+    Ok(counts)
 }
 
 fn kractor_reads_single(
-    id_sets: &HashSet<&[u8]>,
+    taxids: &ReadTaxids,
     fq1: &str,
     ofile1: Option<&str>,
-    batch_size: usize,
-    chunk_bytes: usize,
-    compression_level: i32,
-    nqueue: Option<usize>,
-    threads: usize,
-) -> Result<()> {
-    let ofile1 = ofile1.ok_or_else(|| anyhow!("No output file specified."))?;
+    callback: Option<ReadCallback>,
+    filters: &FilterChain,
+    tags: &ReadTags,
+    options: &ExtractOptions,
+) -> Result<KractorCounts> {
+    if ofile1.is_none() && callback.is_none() && !options.dry_run {
+        return Err(anyhow!("No output file specified."));
+    }
     let reader_style = progress_reader_style()?;
     let writer_style = progress_writer_style()?;
-    let progress = MultiProgress::new();
-    let pb1 = progress.add(
-        ProgressBar::new(std::fs::metadata(fq1)?.len() as u64).with_finish(ProgressFinish::Abandon),
-    );
+    let progress = new_progress();
+    let pb1 = progress.add(input_progress_bar(fq1)?);
     pb1.set_prefix("Reading fastq");
     pb1.set_style(reader_style);
 
-    let pb2 = progress.add(ProgressBar::no_length().with_finish(ProgressFinish::Abandon));
-    pb2.set_prefix("Writing fastq");
-    pb2.set_style(writer_style);
+    let pb2 = ofile1.map(|_| {
+        let pb2 = progress.add(output_progress_bar());
+        pb2.set_prefix("Writing fastq");
+        pb2.set_style(writer_style);
+        pb2
+    });
 
     single::parse_single(
-        id_sets,
-        &fq1,
+        taxids,
+        fq1,
         Some(pb1),
-        &ofile1,
-        Some(pb2),
-        compression_level,
-        batch_size,
-        chunk_bytes,
-        nqueue,
-        threads,
+        ofile1,
+        pb2,
+        callback,
+        filters,
+        tags,
+        options,
     )
 }
 
 fn kractor_reads_paired(
-    id_sets: &HashSet<&[u8]>,
+    taxids: &ReadTaxids,
     fq1: &str,
     ofile1: Option<&str>,
     fq2: &str,
     ofile2: Option<&str>,
-    batch_size: usize,
-    chunk_bytes: usize,
-    compression_level: i32,
-    nqueue: Option<usize>,
-    threads: usize,
-) -> Result<()> {
-    if ofile1.is_none() && ofile2.is_none() {
+    callback: Option<ReadCallback>,
+    filters: &FilterChain,
+    tags: &ReadTags,
+    options: &ExtractOptions,
+) -> Result<KractorCounts> {
+    if ofile1.is_none() && ofile2.is_none() && callback.is_none() && !options.dry_run {
         return Err(anyhow!("No output file specified."));
     }
 
     let reader_style = progress_reader_style()?;
     let writer_style = progress_writer_style()?;
-    let progress = MultiProgress::new();
-    let pb1 = progress.add(
-        ProgressBar::new(std::fs::metadata(fq1)?.len() as u64).with_finish(ProgressFinish::Abandon),
-    );
+    let progress = new_progress();
+    let pb1 = progress.add(input_progress_bar(fq1)?);
     pb1.set_prefix("Reading fq1");
     pb1.set_style(reader_style.clone());
     let pb2 = if let Some(_) = ofile1 {
-        let pb2 = progress.add(ProgressBar::no_length().with_finish(ProgressFinish::Abandon));
+        let pb2 = progress.add(output_progress_bar());
         pb2.set_prefix("Writing fq1");
         pb2.set_style(writer_style.clone());
         Some(pb2)
@@ -130,13 +337,11 @@ fn kractor_reads_paired(
         None
     };
 
-    let pb3 = progress.add(
-        ProgressBar::new(std::fs::metadata(fq2)?.len() as u64).with_finish(ProgressFinish::Abandon),
-    );
+    let pb3 = progress.add(input_progress_bar(fq2)?);
     pb3.set_prefix("Reading fq2");
     pb3.set_style(reader_style);
     let pb4 = if let Some(_) = ofile2 {
-        let pb4 = progress.add(ProgressBar::no_length().with_finish(ProgressFinish::Abandon));
+        let pb4 = progress.add(output_progress_bar());
         pb4.set_prefix("Writing fq2");
         pb4.set_style(writer_style);
         Some(pb4)
@@ -144,7 +349,7 @@ fn kractor_reads_paired(
         None
     };
     paired::parse_paired(
-        id_sets,
+        taxids,
         fq1,
         Some(pb1),
         fq2,
@@ -153,38 +358,351 @@ fn kractor_reads_paired(
         pb2,
         ofile2,
         pb4,
-        compression_level,
-        batch_size,
-        chunk_bytes,
-        nqueue,
-        threads,
+        callback,
+        filters,
+        tags,
+        options,
     )
 }
 
-fn read_sequence_id_from_koutput<P>(
+/// Label a matched record with its read group in the `MIRE{}` annotation, so
+/// merged downstream files keep the sample of each read
+pub(super) fn with_read_group(
+    mut record: FastqRecord<Bytes>,
+    read_group: Option<&[u8]>,
+) -> FastqRecord<Bytes> {
+    if let Some(read_group) = read_group {
+        record.desc = Some(add_mire_tag(record.desc.as_deref(), b"RG", read_group));
+    }
+    record
+}
+
+pub(in crate::kractor) fn read_sequence_id_from_koutput<P>(
     file: P,
     buffersize: usize,
-) -> std::result::Result<Vec<Vec<u8>>, String>
+    columns: &KoutputColumns,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
 where
     P: AsRef<Path> + Display,
 {
-    let opened = File::open(file).map_err(|e| format!("Open file failed: {}", e))?;
+    let opened = new_reader(&file, buffersize, None)?;
     let buffer = BufReader::with_capacity(buffersize, opened);
-    let id_sets = buffer
-        .lines()
-        .filter_map(|line| {
-            line.ok().and_then(|str| {
-                // we selected the second column
-                str.split("\t").nth(1).and_then(|second| {
-                    // we remove empty sequence IDs
-                    if second.is_empty() {
-                        None
-                    } else {
-                        Some(second.as_bytes().to_vec())
-                    }
-                })
-            })
-        })
-        .collect::<Vec<Vec<u8>>>();
+    let mut id_sets = Vec::new();
+    // Split lines as bytes: sequence IDs need not be valid UTF-8
+    for (i, line) in buffer.split(b'\n').enumerate() {
+        let line = line.with_context(|| format!("Failed to read {}", file))?;
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
+        if line.is_empty() {
+            continue;
+        }
+        let Some([_, id, taxid, _, _]) = columns.fields(line) else {
+            return Err(koutput_line_error(&file, i + 1));
+        };
+        // we remove empty sequence IDs
+        if id.is_empty() {
+            continue;
+        }
+        let taxid = koutput_taxid(taxid).unwrap_or_default();
+        id_sets.push((id.to_vec(), taxid.to_vec()));
+    }
     Ok(id_sets)
 }
+
+/// The error of a line of `koutput` whose 5 fields cannot be split
+pub(super) fn koutput_line_error(koutput: impl Display, line: usize) -> anyhow::Error {
+    ErrorKind::Parse.error(format!(
+        "Invalid file {}: line {} must have 5 fields",
+        koutput, line
+    ))
+}
+
+/// Drop the sequence IDs of the taxa with fewer than `min_reads` reads, as
+/// taxa hit by a read or two are mostly noise
+fn drop_rare_taxa(ids: &mut Vec<(Vec<u8>, Vec<u8>)>, min_reads: usize) {
+    let mut reads: HashMap<&[u8], usize> = HashMap::default();
+    for (_, taxid) in ids.iter() {
+        *reads.entry(taxid.as_slice()).or_insert(0) += 1;
+    }
+    let rare = reads
+        .into_iter()
+        .filter(|&(_, n)| n < min_reads)
+        .map(|(taxid, _)| taxid.to_vec())
+        .collect::<HashSet<Vec<u8>>>();
+    if !rare.is_empty() {
+        ids.retain(|(_, taxid)| !rare.contains(taxid));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_read_group() {
+        let record = |desc: Option<&'static str>| {
+            FastqRecord::new(
+                Bytes::from("r1"),
+                desc.map(Bytes::from),
+                Bytes::from("ACGT"),
+                Bytes::from("+"),
+                Bytes::from("IIII"),
+            )
+        };
+        let desc =
+            |desc: Option<&'static str>| with_read_group(record(desc), Some(b"S1")).desc.unwrap();
+        assert_eq!(desc(None), "MIRE{RG:S1}");
+        assert_eq!(desc(Some("1:N:0")), "1:N:0 MIRE{RG:S1}");
+        assert_eq!(
+            desc(Some("1:N:0 MIRE{BARCODE:ACGT} x")),
+            "1:N:0 MIRE{BARCODE:ACGT:RG:S1} x"
+        );
+        assert!(with_read_group(record(None), None).desc.is_none());
+    }
+
+    #[test]
+    fn test_read_taxids() {
+        let record = |id: &'static str, desc: Option<&'static str>| {
+            FastqRecord::new(
+                Bytes::from(id),
+                desc.map(Bytes::from),
+                Bytes::from("ACGT"),
+                Bytes::from("+"),
+                Bytes::from("IIII"),
+            )
+        };
+        let classified = ReadTaxids::Headers(HashSet::from_iter([b"562".as_slice()]));
+        let taxid = |id, desc| classified.taxid(&record(id, desc)).map(|x| x.to_vec());
+        assert_eq!(taxid("r1", Some("kraken:taxid|562")), Some(b"562".to_vec()));
+        assert_eq!(
+            taxid("r1", Some("1:N:0 kraken:taxid|562")),
+            Some(b"562".to_vec())
+        );
+        assert_eq!(taxid("r1|kraken:taxid|562", None), Some(b"562".to_vec()));
+        assert_eq!(taxid("r1", Some("kraken:taxid|9606")), None);
+        assert_eq!(taxid("r1", None), None);
+
+        let koutput =
+            ReadTaxids::Koutput(HashMap::from_iter([(b"r1".as_slice(), b"9606".as_slice())]));
+        assert_eq!(koutput.taxid(&record("r1", None)), Some(b"9606".as_slice()));
+        assert_eq!(koutput.taxid(&record("r2", None)), None);
+    }
+
+    #[test]
+    fn test_drop_rare_taxa() {
+        let mut ids = [("r1", "562"), ("r2", "1280"), ("r3", "562"), ("r4", "9")]
+            .map(|(id, taxid)| (id.as_bytes().to_vec(), taxid.as_bytes().to_vec()))
+            .to_vec();
+        drop_rare_taxa(&mut ids, 2);
+        let ids = ids.iter().map(|(id, _)| id.as_slice()).collect::<Vec<_>>();
+        assert_eq!(ids, [b"r1", b"r3"]);
+    }
+
+    #[test]
+    fn test_read_sequence_id_from_koutput() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let koutput = temp.path().join("koutput.txt");
+        std::fs::write(&koutput, "C\tr1\t562\t4\t562:1\n\nU\tr2\t0\t4\t0:1\n")?;
+        let koutput = koutput.to_str().unwrap();
+        let ids = read_sequence_id_from_koutput(koutput, 1024, &KoutputColumns::default())?;
+        assert_eq!(
+            ids,
+            vec![
+                (b"r1".to_vec(), b"562".to_vec()),
+                (b"r2".to_vec(), b"0".to_vec())
+            ]
+        );
+
+        // Lines without 5 fields are an error, as when spilling the IDs
+        std::fs::write(koutput, "C\tr1\t562\t4\t562:1\nC\tr2\n")?;
+        let err =
+            read_sequence_id_from_koutput(koutput, 1024, &KoutputColumns::default()).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), Some(ErrorKind::Parse));
+        assert!(err.to_string().contains("line 2"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_single_callback() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let input = temp.path().join("reads.fq");
+        std::fs::write(
+            &input,
+            "@r1\nACGT\n+\nIIII\n@r2\nGG\n+\nII\n@r3\nTTA\n+\nIII\n",
+        )?;
+        let taxids = ReadTaxids::Koutput(HashMap::from_iter([
+            (b"r1".as_slice(), b"562".as_slice()),
+            (b"r3", b"9606"),
+        ]));
+        let mut batches = Vec::new();
+        let mut callback = |reads: Vec<MatchedRead>| -> Result<()> {
+            batches.push(reads);
+            Ok(())
+        };
+        let options = ExtractOptions {
+            compression_level: (Some(4), None),
+            ..ExtractOptions::new(1, 64)
+        };
+        let counts = single::parse_single(
+            &taxids,
+            &input,
+            None,
+            None,
+            None,
+            Some(&mut callback),
+            &FilterChain::new(),
+            &ReadTags::new(Some(b"S1")),
+            &options,
+        )?;
+        assert_eq!(counts.matched, 2);
+        let reads = batches.into_iter().flatten().collect::<Vec<_>>();
+        assert_eq!(reads.len(), 2);
+        assert_eq!(reads[0].0, b"562");
+        assert_eq!(reads[1].1.seq.as_ref(), b"TTA");
+        assert_eq!(reads[1].1.desc.as_deref(), Some(b"MIRE{RG:S1}".as_slice()));
+        assert!(reads[0].2.is_none());
+
+        let mut callback = |_: Vec<MatchedRead>| -> Result<()> { Err(anyhow!("stop")) };
+        assert!(single::parse_single(
+            &taxids,
+            &input,
+            None,
+            None,
+            None,
+            Some(&mut callback),
+            &FilterChain::new(),
+            &ReadTags::default(),
+            &options,
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_paired_compression_levels() -> Result<()> {
+        use std::io::Read;
+
+        let temp = tempfile::tempdir()?;
+        let (input1, input2) = (temp.path().join("r1.fq"), temp.path().join("r2.fq"));
+        let (output1, output2) = (temp.path().join("o1.fq.gz"), temp.path().join("o2.fq.gz"));
+        std::fs::write(&input1, "@r1\nACGT\n+\nIIII\n@r2\nGGCC\n+\nIIII\n")?;
+        std::fs::write(&input2, "@r1\nTTTTTTTT\n+\nIIIIIIII\n@r2\nAA\n+\nII\n")?;
+        let taxids =
+            ReadTaxids::Koutput(HashMap::from_iter([(b"r1".as_slice(), b"562".as_slice())]));
+        let parse = |compression_level, threads| {
+            paired::parse_paired(
+                &taxids,
+                &input1,
+                None,
+                &input2,
+                None,
+                Some(&output1),
+                None,
+                Some(&output2),
+                None,
+                None,
+                &FilterChain::new(),
+                &ReadTags::default(),
+                &ExtractOptions {
+                    compression_level,
+                    threads,
+                    ..ExtractOptions::new(1, 64)
+                },
+            )
+        };
+        let decompress = |path| -> Result<String> {
+            let mut out = String::new();
+            flate2::read::MultiGzDecoder::new(std::fs::File::open(path)?)
+                .read_to_string(&mut out)?;
+            Ok(out)
+        };
+        // Mates compressed by the parsers and written by a writer each, or
+        // compressed by compressor threads and written by a single writer
+        let pooled = StageThreads {
+            compressors: Some(2),
+            writers: Some(1),
+            ..StageThreads::new(2)
+        };
+        for threads in [StageThreads::new(1), pooled] {
+            std::fs::remove_file(&output1).ok();
+            std::fs::remove_file(&output2).ok();
+            assert_eq!(parse((Some(1), Some(12)), threads)?.matched, 1);
+            assert_eq!(decompress(&output1)?, "@r1\nACGT\n+\nIIII\n");
+            assert_eq!(decompress(&output2)?, "@r1\nTTTTTTTT\n+\nIIIIIIII\n");
+        }
+        assert!(parse((Some(4), Some(13)), StageThreads::new(1)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_paired_count_mismatch() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let (input1, input2) = (temp.path().join("r1.fq"), temp.path().join("r2.fq"));
+        std::fs::write(&input1, "@r1\nACGT\n+\nIIII\n@r2\nGGCC\n+\nIIII\n")?;
+        std::fs::write(&input2, "@r1\nTTTT\n+\nIIII\n")?;
+        let taxids =
+            ReadTaxids::Koutput(HashMap::from_iter([(b"r1".as_slice(), b"562".as_slice())]));
+        let err = paired::parse_paired(
+            &taxids,
+            &input1,
+            None,
+            &input2,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            &FilterChain::new(),
+            &ReadTags::default(),
+            &ExtractOptions::new(64, 64),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(ErrorKind::of(&err), Some(ErrorKind::Pairing));
+        assert!(format!("{:#}", err).contains("record count mismatch"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_single_index() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let input = temp.path().join("reads.fq");
+        let output = temp.path().join("out.fq.gz");
+        let reads = (0 .. 20)
+            .map(|i| format!("@r{}\nACGTACGT\n+\nIIIIIIII\n", i))
+            .collect::<String>();
+        std::fs::write(&input, reads)?;
+        let ids = (0 .. 20).map(|i| format!("r{}", i)).collect::<Vec<_>>();
+        let taxids = ReadTaxids::Koutput(
+            ids.iter()
+                .map(|id| (id.as_bytes(), b"562".as_slice()))
+                .collect(),
+        );
+        // Small chunks spread the records over many gzip members
+        single::parse_single(
+            &taxids,
+            &input,
+            None,
+            Some(&output),
+            None,
+            None,
+            &FilterChain::new(),
+            &ReadTags::default(),
+            &ExtractOptions {
+                index: true,
+                compression_level: (Some(4), None),
+                threads: StageThreads::new(2),
+                ..ExtractOptions::new(4, 64)
+            },
+        )?;
+        let found = crate::record_index::lookup_records(
+            &crate::record_index::index_path(&output),
+            &["r17", "r3", "x"],
+        )?;
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].id.as_ref(), b"r17");
+        assert_eq!(found[1].id.as_ref(), b"r3");
+        assert_eq!(found[1].seq.as_ref(), b"ACGTACGT");
+        Ok(())
+    }
+}
@@ -1,5 +1,3 @@
-use std::io::BufWriter;
-use std::io::Write;
 use std::iter::zip;
 use std::path::Path;
 
@@ -7,16 +5,35 @@ use anyhow::{anyhow, Context, Result};
 use bytes::Bytes;
 use crossbeam_channel::{Receiver, Sender};
 use indicatif::ProgressBar;
-use libdeflater::{CompressionLvl, Compressor};
-use rustc_hash::FxHashSet as HashSet;
 
 use crate::batchsender::BatchSender;
+use crate::error::ErrorKind;
 use crate::fastq_reader::*;
 use crate::fastq_record::{FastqParseError, FastqRecord};
+use crate::kractor::counts::{KractorCounts, ReadFate};
+use crate::kractor::reads::{ExtractOptions, MatchedRead, ReadCallback, ReadTags, ReadTaxids};
+use crate::occupancy::QueueSampler;
+use crate::part_writer::{PartCounter, PartWriter};
+use crate::record_index::{ChunkRecords, IndexedChunk};
+use crate::record_filter::{FilterChain, RecordFilter};
+use crate::threads::*;
 use crate::utils::*;
 
+/// The record pairs buffered by a parser thread and its counts
+struct ParserState {
+    counts: KractorCounts,
+    records1_pool: Vec<u8>,
+    records2_pool: Vec<u8>,
+    matched: Vec<MatchedRead>,
+    // IDs and offsets of the records in the pools, for the indexes
+    chunk1_records: ChunkRecords,
+    chunk2_records: ChunkRecords,
+    packer1: ChunkPacker,
+    packer2: ChunkPacker,
+}
+
 pub(super) fn parse_paired<P: AsRef<Path> + ?Sized>(
-    id_sets: &HashSet<&[u8]>,
+    taxids: &ReadTaxids,
     input1_path: &P,
     input1_bar: Option<ProgressBar>,
     input2_path: &P,
@@ -25,23 +42,43 @@ pub(super) fn parse_paired<P: AsRef<Path> + ?Sized>(
     output1_bar: Option<ProgressBar>,
     output2_path: Option<&P>,
     output2_bar: Option<ProgressBar>,
-    compression_level: i32,
-    batch_size: usize,
-    chunk_bytes: usize,
-    nqueue: Option<usize>,
-    threads: usize,
-) -> Result<()> {
-    let compression_level = CompressionLvl::new(compression_level)
-        .map_err(|e| anyhow!("Invalid 'compression_level': {:?}", e))?;
-    std::thread::scope(|scope| -> Result<()> {
+    callback: Option<ReadCallback>,
+    filters: &FilterChain,
+    tags: &ReadTags,
+    options: &ExtractOptions,
+) -> Result<KractorCounts> {
+    let ExtractOptions {
+        by_taxon,
+        stats,
+        index,
+        compression_level,
+        max_file_bytes,
+        batch_size,
+        chunk_bytes,
+        nqueue,
+        threads,
+        ..
+    } = *options;
+    // Each mate is compressed at its own level: barcode reads are small and
+    // cheap to write, while the biological reads benefit from higher levels
+    let compression1 = Compression::of(output1_path.map(|x| x.as_ref()), compression_level.0)?;
+    let compression2 = Compression::of(output2_path.map(|x| x.as_ref()), compression_level.1)?;
+    std::thread::scope(|scope| -> Result<KractorCounts> {
         // Create a channel between the parser and writer threads
         // The channel transmits batches (Vec<FastqRecord>)
         let (writer_tx, writer_rx): (
-            Sender<(Option<Vec<u8>>, Option<Vec<u8>>)>,
-            Receiver<(Option<Vec<u8>>, Option<Vec<u8>>)>,
+            Sender<(Option<IndexedChunk>, Option<IndexedChunk>)>,
+            Receiver<(Option<IndexedChunk>, Option<IndexedChunk>)>,
+        ) = new_channel(nqueue);
+        // Chunks for each mate are tagged with the part file they belong to
+        let (writer1_tx, writer1_rx): (
+            Sender<(usize, IndexedChunk)>,
+            Receiver<(usize, IndexedChunk)>,
+        ) = new_channel(nqueue);
+        let (writer2_tx, writer2_rx): (
+            Sender<(usize, IndexedChunk)>,
+            Receiver<(usize, IndexedChunk)>,
         ) = new_channel(nqueue);
-        let (writer1_tx, writer1_rx): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = new_channel(nqueue);
-        let (writer2_tx, writer2_rx): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = new_channel(nqueue);
 
         let (reader_tx, reader_rx): (
             Sender<(Vec<FastqRecord<Bytes>>, Vec<FastqRecord<Bytes>>)>,
@@ -55,154 +92,274 @@ pub(super) fn parse_paired<P: AsRef<Path> + ?Sized>(
             Sender<Vec<FastqRecord<Bytes>>>,
             Receiver<Vec<FastqRecord<Bytes>>>,
         ) = new_channel(nqueue);
+        // Matched reads are handed over to the callback instead of written
+        let (callback_tx, callback_rx): (
+            Option<Sender<Vec<MatchedRead>>>,
+            Option<Receiver<Vec<MatchedRead>>>,
+        ) = callback.is_some().then(|| new_channel(nqueue)).unzip();
+        // Queues are named after the stage consuming them
+        let mut queues = QueueSampler::new();
+        let reader1_rx = queues.watch("pairing1", reader1_rx);
+        let reader2_rx = queues.watch("pairing2", reader2_rx);
+        let reader_rx = queues.watch("parser", reader_rx);
+        let writer_rx = queues.watch("writer", writer_rx);
+        let callback_rx = callback_rx.map(|rx| queues.watch("callback", rx));
 
         // ─── Writer Thread ─────────────────────────────────────
-        let (writer1_handle, gzip1) = if let Some(output_path) = output1_path {
+        // Each mate is written by a writer thread of its own, or with a single
+        // writer, by the dispatch thread
+        let outputs = output1_path.is_some() as usize + output2_path.is_some() as usize;
+        let own_writers = threads.writers(outputs) > 1;
+        let (output1_bar, direct1_bar) = if own_writers { (output1_bar, None) } else { (None, output1_bar) };
+        let (output2_bar, direct2_bar) = if own_writers { (output2_bar, None) } else { (None, output2_bar) };
+        let writer1_handle = output1_path.filter(|_| own_writers).map(|output_path| {
             let output: &Path = output_path.as_ref();
-            let handle = Some(scope.spawn(move || -> Result<()> {
-                let mut writer =
-                    BufWriter::with_capacity(chunk_bytes, new_writer(output, output1_bar)?);
-                for chunk in writer1_rx {
-                    writer.write_all(&chunk).with_context(|| {
+            let writer1_rx = queues.watch("writer1", writer1_rx);
+            scope.spawn(move || -> Result<()> {
+                let mut writer = PartWriter::new(output, max_file_bytes, chunk_bytes, output1_bar)
+                    .with_index(index)?;
+                for (part, chunk) in writer1_rx {
+                    writer.write_records(part, &chunk).with_context(|| {
                         format!("(Writer1) Failed to write Fastq records to output")
                     })?;
                 }
                 writer
-                    .flush()
+                    .finish()
                     .with_context(|| format!("(Writer1) Failed to flush writer"))?;
                 Ok(())
-            }));
-            let gzip = gz_compressed(output);
-            (handle, gzip)
-        } else {
-            (None, false)
-        };
+            })
+        });
 
-        let (writer2_handle, gzip2) = if let Some(output_path) = output2_path {
+        let writer2_handle = output2_path.filter(|_| own_writers).map(|output_path| {
             let output: &Path = output_path.as_ref();
-            let handle = Some(scope.spawn(move || -> Result<()> {
-                let mut writer =
-                    BufWriter::with_capacity(chunk_bytes, new_writer(output, output2_bar)?);
-                for chunk in writer2_rx {
-                    writer.write_all(&chunk).with_context(|| {
+            let writer2_rx = queues.watch("writer2", writer2_rx);
+            scope.spawn(move || -> Result<()> {
+                let mut writer = PartWriter::new(output, max_file_bytes, chunk_bytes, output2_bar)
+                    .with_index(index)?;
+                for (part, chunk) in writer2_rx {
+                    writer.write_records(part, &chunk).with_context(|| {
                         format!("(Writer2) Failed to write Fastq records to output")
                     })?;
                 }
                 writer
-                    .flush()
+                    .finish()
                     .with_context(|| format!("(Writer2) Failed to flush writer"))?;
                 Ok(())
-            }));
-            let gzip = gz_compressed(output);
-            (handle, gzip)
-        } else {
-            (None, false)
-        };
+            })
+        });
 
         // Consumes batches of records and writes them to file
+        let direct1: Option<&Path> = output1_path.filter(|_| !own_writers).map(|x| x.as_ref());
+        let direct2: Option<&Path> = output2_path.filter(|_| !own_writers).map(|x| x.as_ref());
         let writer_handle = scope.spawn(move || -> Result<()> {
+            // Both mates share one counter so read1 and read2 always rotate together
+            let mut counter = PartCounter::new(max_file_bytes, 2);
+            let mut direct1 = direct1
+                .map(|output| PartWriter::new(output, max_file_bytes, chunk_bytes, direct1_bar).with_index(index))
+                .transpose()?;
+            let mut direct2 = direct2
+                .map(|output| PartWriter::new(output, max_file_bytes, chunk_bytes, direct2_bar).with_index(index))
+                .transpose()?;
             // Iterate over each received batch of records
             for (records1, records2) in writer_rx {
+                let part = counter.assign(&[
+                    records1.as_ref().map_or(0, |x| x.0.len()),
+                    records2.as_ref().map_or(0, |x| x.0.len()),
+                ]);
                 if let Some(records1) = records1 {
-                    writer1_tx.send(records1).with_context(|| {
-                        format!("(Writer dispatch) Failed to send read1 batch to Writer1 thread")
-                    })?;
+                    if let Some(writer) = &mut direct1 {
+                        writer.write_records(part, &records1).with_context(|| {
+                            format!("(Writer) Failed to write read1 records to output")
+                        })?;
+                    } else {
+                        writer1_tx.send((part, records1)).with_context(|| {
+                            format!("(Writer dispatch) Failed to send read1 batch to Writer1 thread")
+                        })?;
+                    }
                 }
                 if let Some(records2) = records2 {
-                    writer2_tx.send(records2).with_context(|| {
-                        format!("(Writer dispatch) Failed to send read2 batch to Writer2 thread")
-                    })?;
+                    if let Some(writer) = &mut direct2 {
+                        writer.write_records(part, &records2).with_context(|| {
+                            format!("(Writer) Failed to write read2 records to output")
+                        })?;
+                    } else {
+                        writer2_tx.send((part, records2)).with_context(|| {
+                            format!("(Writer dispatch) Failed to send read2 batch to Writer2 thread")
+                        })?;
+                    }
                 }
             }
+            for writer in [&mut direct1, &mut direct2].into_iter().flatten() {
+                writer
+                    .finish()
+                    .with_context(|| format!("(Writer) Failed to flush writer"))?;
+            }
             Ok(())
         });
 
+        // ─── Compressor Thread ─────────────────────────────────
+        // Chunks are compressed by compressor threads between the parsers
+        // and the writers if any, by the parsers themselves otherwise
+        let compressors = threads.compressors();
+        let (chunk_tx, compressor_handles) = if compressors > 0 {
+            let (chunk_tx, chunk_rx) = new_channel(nqueue);
+            let chunk_rx = queues.watch("compressor", chunk_rx);
+            let handles = spawn_compressors(scope, compressors, chunk_rx, writer_tx, || {
+                let mut packer1 = ChunkPacker::new(compression1);
+                let mut packer2 = ChunkPacker::new(compression2);
+                move |(chunk1, chunk2): (Option<IndexedChunk>, Option<IndexedChunk>)| {
+                    let chunk1 = chunk1.map(|(pack, records)| Ok::<_, anyhow::Error>((packer1.pack(pack)?, records))).transpose()?;
+                    let chunk2 = chunk2.map(|(pack, records)| Ok::<_, anyhow::Error>((packer2.pack(pack)?, records))).transpose()?;
+                    Ok((chunk1, chunk2))
+                }
+            });
+            (chunk_tx, handles)
+        } else {
+            (writer_tx, Vec::new())
+        };
+        let (parser_compression1, parser_compression2) = if compressors > 0 {
+            (Compression::Plain, Compression::Plain)
+        } else {
+            (compression1, compression2)
+        };
+        let sampler = queues.spawn(scope);
+
         // ─── Parser Thread ─────────────────────────────────────
-        let has_writer1 = writer1_handle.is_some();
-        let has_writer2 = writer2_handle.is_some();
-        let mut parser_handles = Vec::with_capacity(threads);
-        for _ in 0 .. threads {
-            let rx = reader_rx.clone();
-            let tx = writer_tx.clone();
-            let handle = scope.spawn(move || -> Result<()> {
-                let mut records1_pool: Vec<u8> = Vec::with_capacity(chunk_bytes);
-                let mut records2_pool: Vec<u8> = Vec::with_capacity(chunk_bytes);
-                let mut compressor = Compressor::new(compression_level);
-                while let Ok((records1, records2)) = rx.recv() {
-                    // Initialize a thread-local batch sender for matching records
-                    for (record1, record2) in zip(records1, records2) {
-                        if record1.id != record2.id {
-                            return Err(
-                                anyhow!("{}", FastqParseError::FastqPairError { read1_id: String::from_utf8_lossy(&record1.id).to_string(), read2_id: String::from_utf8_lossy(&record2.id).to_string(), read1_pos: None, read2_pos: None }
-                            ));
+        let has_writer1 = output1_path.is_some();
+        let has_writer2 = output2_path.is_some();
+        // Without any output file, record pairs are only counted (dry run)
+        let dry_run = !has_writer1 && !has_writer2 && callback_tx.is_none();
+        let finish_tx = chunk_tx.clone();
+        let finish_callback_tx = callback_tx.clone();
+        let parser_handle = spawn_parsers(
+            scope,
+            threads,
+            reader_rx,
+            move || {
+                let mut counts = KractorCounts::new(by_taxon);
+                if !filters.is_empty() {
+                    counts = counts.with_dropped();
+                }
+                if stats {
+                    counts = counts.with_stats(2);
+                }
+                let pool_size = if has_writer1 || has_writer2 { chunk_bytes } else { 0 };
+                ParserState {
+                    counts,
+                    records1_pool: Vec::with_capacity(pool_size),
+                    records2_pool: Vec::with_capacity(pool_size),
+                    matched: Vec::new(),
+                    chunk1_records: Vec::new(),
+                    chunk2_records: Vec::new(),
+                    packer1: ChunkPacker::new(parser_compression1),
+                    packer2: ChunkPacker::new(parser_compression2),
+                }
+            },
+            move |state, (records1, records2)| -> Result<()> {
+                let ParserState {
+                    counts,
+                    records1_pool,
+                    records2_pool,
+                    matched,
+                    chunk1_records,
+                    chunk2_records,
+                    packer1,
+                    packer2,
+                } = state;
+                counts.records += records1.len();
+                for (record1, record2) in zip(records1, records2) {
+                    if record1.id != record2.id {
+                        return Err(
+                            anyhow::Error::new(FastqParseError::FastqPairError { read1_id: String::from_utf8_lossy(&record1.id).to_string(), read2_id: String::from_utf8_lossy(&record2.id).to_string(), read1_pos: None, read2_pos: None }
+                        ));
+                    }
+                    if let Some(taxid) = taxids.taxid(&record1) {
+                        if !filters.accept(&[&record1, &record2]) {
+                            counts.add_dropped();
+                            counts.add_stats(ReadFate::Dropped, &[&record1, &record2]);
+                            continue;
                         }
-                        if id_sets.contains(record1.id.as_ref()) {
+                        counts.add_match(Some(taxid));
+                        counts.add_stats(ReadFate::Matched, &[&record1, &record2]);
+                        if dry_run {
+                            continue;
+                        }
+                        if let Some(callback_tx) = &callback_tx {
+                            let taxid = taxid.to_vec();
+                            let (record1, record2) = filters.transform_pair(record1, record2);
+                            let (record1, record2) = tags.tag_pair(record1, record2)?;
+                            matched.push((taxid, record1, Some(record2)));
+                            if matched.len() >= batch_size {
+                                callback_tx.send(std::mem::take(matched)).with_context(|| {
+                                    format!("(Parser) Failed to send matched reads to R")
+                                })?;
+                            }
+                            continue;
+                        }
+                        let (record1, record2) = filters.transform_pair(record1, record2);
+                        let (record1, record2) = tags.tag_pair(record1, record2)?;
                         if records1_pool.capacity() - records1_pool.len() < record1.bytes_size() ||
                             records2_pool.capacity() - records2_pool.len() < record2.bytes_size() {
                             let pack1 = if has_writer1 {
                                 let mut pack = Vec::with_capacity(chunk_bytes);
-                                std::mem::swap(&mut records1_pool, &mut pack);
-                                if gzip1 {
-                                    pack = gzip_pack(&pack, &mut compressor)?
-                                }
-                                Some(pack)
+                                std::mem::swap(records1_pool, &mut pack);
+                                pack = packer1.pack(pack)?;
+                                Some((pack, std::mem::take(chunk1_records)))
                             } else {
                                 None
                             };
                             let pack2 = if has_writer2 {
                                 let mut pack = Vec::with_capacity(chunk_bytes);
-                                std::mem::swap(&mut records2_pool, &mut pack);
-                                if gzip2 {
-                                    pack = gzip_pack(&pack, &mut compressor)?
-                                }
-                                Some(pack)
+                                std::mem::swap(records2_pool, &mut pack);
+                                pack = packer2.pack(pack)?;
+                                Some((pack, std::mem::take(chunk2_records)))
                             } else {
                                 None
                             };
-                            tx.send((pack1, pack2)).with_context(|| {
+                            chunk_tx.send((pack1, pack2)).with_context(|| {
                                 format!(
                                     "(Parser) Failed to send send parsed record pair to Writer thread"
                                 )
                             })?;
                         }
-                        record1.extend(&mut records1_pool);
-                        record2.extend(&mut records2_pool);
-                    }
+                        if index {
+                            chunk1_records.push((record1.id.clone(), records1_pool.len()));
+                            chunk2_records.push((record2.id.clone(), records2_pool.len()));
+                        }
+                        record1.extend(records1_pool);
+                        record2.extend(records2_pool);
+                    } else {
+                        counts.add_stats(ReadFate::Unmatched, &[&record1, &record2]);
                     }
                 }
-                if !records1_pool.is_empty() {
+                Ok(())
+            },
+            move |mut state: ParserState| -> Result<KractorCounts> {
+                if let Some(callback_tx) = finish_callback_tx.as_ref().filter(|_| !state.matched.is_empty()) {
+                    callback_tx
+                        .send(state.matched)
+                        .with_context(|| format!("(Parser) Failed to send matched reads to R"))?;
+                }
+                if !state.records1_pool.is_empty() {
                     let pack1 = if has_writer1 {
-                        let pack = if gzip1 {
-                            gzip_pack(&records1_pool, &mut compressor)?
-                        } else {
-                            records1_pool
-                        };
-                        Some(pack)
+                        Some((state.packer1.pack(state.records1_pool)?, state.chunk1_records))
                     } else {
                         None
                     };
                     let pack2 = if has_writer2 {
-                        let pack = if gzip2 {
-                            gzip_pack(&records2_pool, &mut compressor)?
-                        } else {
-                            records2_pool
-                        };
-                        Some(pack)
+                        Some((state.packer2.pack(state.records2_pool)?, state.chunk2_records))
                     } else {
                         None
                     };
-                    tx.send((pack1, pack2)).with_context(|| {
+                    finish_tx.send((pack1, pack2)).with_context(|| {
                         format!(
                             "(Parser) Failed to send send parsed record pair to Writer thread"
                         )
                     })?;
                 }
-                Ok(())
-            });
-            parser_handles.push(handle);
-        }
-        drop(reader_rx);
-        drop(writer_tx);
+                Ok(state.counts)
+            },
+        )?;
 
         // ─── reader Thread ─────────────────────────────────────
         let reader_handle = scope.spawn(move || -> Result<()> {
@@ -210,13 +367,13 @@ pub(super) fn parse_paired<P: AsRef<Path> + ?Sized>(
                 let (records1, records2) = match (reader1_rx.recv(), reader2_rx.recv()) {
                     (Ok(rec1), Ok(rec2)) => (rec1, rec2),
                     (Err(_), Ok(_)) => {
-                        return Err(anyhow!(
-                            "(Reader collect) FASTQ pairing error: read1 channel closed before read2"
+                        return Err(ErrorKind::Pairing.error(
+                            "(Reader collect) FASTQ pairing error: read1 channel closed before read2",
                         ));
                     }
                     (Ok(_), Err(_)) => {
-                        return Err(anyhow!(
-                            "(Reader collect) FASTQ pairing error: read2 channel closed before read1"
+                        return Err(ErrorKind::Pairing.error(
+                            "(Reader collect) FASTQ pairing error: read2 channel closed before read1",
                         ));
                     }
                     (Err(_), Err(_)) => {
@@ -224,7 +381,11 @@ pub(super) fn parse_paired<P: AsRef<Path> + ?Sized>(
                     }
                 };
                 if records1.len() != records2.len() {
-                    return Err(anyhow!("(Reader collect) FASTQ pairing error: record count mismatch (read1: {}, read2: {})", records1.len(), records2.len()));
+                    return Err(ErrorKind::Pairing.error(format!(
+                        "(Reader collect) FASTQ pairing error: record count mismatch (read1: {}, read2: {})",
+                        records1.len(),
+                        records2.len()
+                    )));
                 }
                 reader_tx.send((records1, records2)).with_context(|| {
                     format!(
@@ -239,7 +400,7 @@ pub(super) fn parse_paired<P: AsRef<Path> + ?Sized>(
         let reader1_handle = scope.spawn(move || -> Result<()> {
             let mut reader = FastqReader::with_capacity(
                 BUFFER_SIZE,
-                new_reader(input1, BUFFER_SIZE, input1_bar)?,
+                spawn_reader(scope, input1, input1_bar, threads)?,
             );
             let mut thread_tx = BatchSender::with_capacity(batch_size, reader1_tx);
             while let Some(record) = reader
@@ -260,7 +421,7 @@ pub(super) fn parse_paired<P: AsRef<Path> + ?Sized>(
         let reader2_handle = scope.spawn(move || -> Result<()> {
             let mut reader = FastqReader::with_capacity(
                 BUFFER_SIZE,
-                new_reader(input2, BUFFER_SIZE, input2_bar)?,
+                spawn_reader(scope, input2, input2_bar, threads)?,
             );
             let mut thread_tx = BatchSender::with_capacity(batch_size, reader2_tx);
             while let Some(record) = reader
@@ -277,6 +438,14 @@ pub(super) fn parse_paired<P: AsRef<Path> + ?Sized>(
             Ok(())
         });
 
+        // ─── Callback ──────────────────────────────────────────
+        // R is single-threaded, the callback runs on the calling thread
+        if let (Some(callback), Some(callback_rx)) = (callback, callback_rx) {
+            for reads in callback_rx {
+                callback(reads)?;
+            }
+        }
+
         // ─── Join Threads and Propagate Errors ────────────────
         if let Some(writer_handle) = writer1_handle {
             writer_handle
@@ -291,11 +460,18 @@ pub(super) fn parse_paired<P: AsRef<Path> + ?Sized>(
         writer_handle
             .join()
             .map_err(|e| anyhow!("(Writer dispatch) thread panicked: {:?}", e))??;
-
-        for handler in parser_handles {
+        for handler in compressor_handles {
             handler
                 .join()
-                .map_err(|e| anyhow!("(Parser) thread panicked: {:?}", e))??;
+                .map_err(|e| anyhow!("(Compressor) thread panicked: {:?}", e))??;
+        }
+
+        let mut counts = KractorCounts::new(by_taxon);
+        for parser_counts in parser_handle
+            .join()
+            .map_err(|e| anyhow!("(Parser) thread panicked: {:?}", e))??
+        {
+            counts.merge(parser_counts);
         }
         reader_handle
             .join()
@@ -306,6 +482,7 @@ pub(super) fn parse_paired<P: AsRef<Path> + ?Sized>(
         reader2_handle
             .join()
             .map_err(|e| anyhow!("(Reader2) thread panicked: {:?}", e))??;
-        Ok(())
+        counts.queues = Some(sampler.finish()?);
+        Ok(counts)
     })
 }
@@ -1,126 +1,245 @@
-use std::io::{BufWriter, Write};
 use std::path::Path;
 
 use anyhow::{anyhow, Context, Result};
 use bytes::Bytes;
 use crossbeam_channel::{Receiver, Sender};
 use indicatif::ProgressBar;
-use libdeflater::{CompressionLvl, Compressor};
-use rustc_hash::FxHashSet as HashSet;
 
 use crate::batchsender::BatchSender;
 use crate::fastq_reader::*;
 use crate::fastq_record::FastqRecord;
+use crate::kractor::counts::{KractorCounts, ReadFate};
+use crate::kractor::reads::{ExtractOptions, MatchedRead, ReadCallback, ReadTags, ReadTaxids};
+use crate::occupancy::QueueSampler;
+use crate::part_writer::{PartCounter, PartWriter};
+use crate::record_filter::{FilterChain, RecordFilter};
+use crate::record_index::{ChunkRecords, IndexedChunk};
+use crate::threads::*;
 use crate::utils::*;
 
+/// The records buffered by a parser thread and its counts
+struct ParserState {
+    counts: KractorCounts,
+    records_pool: Vec<u8>,
+    matched: Vec<MatchedRead>,
+    // IDs and offsets of the records in the pool, for the index
+    chunk_records: ChunkRecords,
+    packer: ChunkPacker,
+}
+
 pub(super) fn parse_single<P: AsRef<Path> + ?Sized>(
-    id_sets: &HashSet<&[u8]>,
+    taxids: &ReadTaxids,
     input_path: &P,
     input_bar: Option<ProgressBar>,
-    output_path: &P,
+    output_path: Option<&P>,
     output_bar: Option<ProgressBar>,
-    compression_level: i32,
-    batch_size: usize,
-    chunk_bytes: usize,
-    nqueue: Option<usize>,
-    threads: usize,
-) -> Result<()> {
+    callback: Option<ReadCallback>,
+    filters: &FilterChain,
+    tags: &ReadTags,
+    options: &ExtractOptions,
+) -> Result<KractorCounts> {
+    let ExtractOptions {
+        by_taxon,
+        stats,
+        index,
+        compression_level: (compression_level, _),
+        max_file_bytes,
+        batch_size,
+        chunk_bytes,
+        nqueue,
+        threads,
+        ..
+    } = *options;
     let input: &Path = input_path.as_ref();
-    let output: &Path = output_path.as_ref();
+    // Without an output file, records are only counted (dry run)
+    let output: Option<&Path> = output_path.map(|x| x.as_ref());
 
-    // Ensure compression level is validated and converted before entering thread scope.
-    // Doing this outside avoids redundant validation across parser threads.
-    let compression_level = CompressionLvl::new(compression_level)
-        .map_err(|e| anyhow!("Invalid 'compression_level': {:?}", e))?;
-    std::thread::scope(|scope| -> Result<()> {
+    let compression = Compression::of(output, compression_level)?;
+    std::thread::scope(|scope| -> Result<KractorCounts> {
         // Two communication pipelines are set up to decouple IO and CPU-intensive work:
         // - reader_tx: transfers raw FASTQ records to parser threads
         // - writer_tx: receives compressed byte chunks from parser threads
-        let (writer_tx, writer_rx): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = new_channel(nqueue);
+        let (writer_tx, writer_rx): (Sender<IndexedChunk>, Receiver<IndexedChunk>) =
+            new_channel(nqueue);
         let (reader_tx, reader_rx): (
             Sender<Vec<FastqRecord<Bytes>>>,
             Receiver<Vec<FastqRecord<Bytes>>>,
         ) = new_channel(nqueue);
+        // Matched reads are handed over to the callback instead of written
+        let (callback_tx, callback_rx): (
+            Option<Sender<Vec<MatchedRead>>>,
+            Option<Receiver<Vec<MatchedRead>>>,
+        ) = callback.is_some().then(|| new_channel(nqueue)).unzip();
+        // Queues are named after the stage consuming them
+        let mut queues = QueueSampler::new();
+        let reader_rx = queues.watch("parser", reader_rx);
+        let callback_rx = callback_rx.map(|rx| queues.watch("callback", rx));
 
         // ─── Writer Thread ─────────────────────────────────────
         // A single thread handles file output to ensure atomic write order and leverage buffered IO.
         // This thread consumes compressed chunks, not raw records, for performance.
-        let writer_handle = scope.spawn(move || -> Result<()> {
-            let mut writer = BufWriter::with_capacity(chunk_bytes, new_writer(output, output_bar)?);
+        let writer_handle = output.map(|output| {
+            let writer_rx = queues.watch("writer", writer_rx);
+            scope.spawn(move || -> Result<()> {
+                let mut counter = PartCounter::new(max_file_bytes, 1);
+                let mut writer = PartWriter::new(output, max_file_bytes, chunk_bytes, output_bar)
+                    .with_index(index)?;
 
-            // Iterate over each received batch of records
-            for chunk in writer_rx {
+                // Iterate over each received batch of records
+                for chunk in writer_rx {
+                    writer
+                        .write_records(counter.assign(&[chunk.0.len()]), &chunk)
+                        .with_context(|| {
+                            format!("(Writer) Failed to write FastqRecord to output")
+                        })?;
+                }
                 writer
-                    .write_all(&chunk)
-                    .with_context(|| format!("(Writer) Failed to write FastqRecord to output"))?;
-            }
-            writer
-                .flush()
-                .with_context(|| format!("(Writer) Failed to flush writer"))?;
-            Ok(())
+                    .finish()
+                    .with_context(|| format!("(Writer) Failed to flush writer"))?;
+                Ok(())
+            })
         });
 
+        // ─── Compressor Thread ─────────────────────────────────
+        // Chunks are compressed by compressor threads between the parsers
+        // and the writer if any, by the parsers themselves otherwise
+        let compressors = threads.compressors();
+        let (chunk_tx, compressor_handles) = if compressors > 0 {
+            let (chunk_tx, chunk_rx) = new_channel(nqueue);
+            let chunk_rx = queues.watch("compressor", chunk_rx);
+            let handles = spawn_compressors(scope, compressors, chunk_rx, writer_tx, || {
+                let mut packer = ChunkPacker::new(compression);
+                move |(pack, records): IndexedChunk| Ok((packer.pack(pack)?, records))
+            });
+            (chunk_tx, handles)
+        } else {
+            (writer_tx, Vec::new())
+        };
+        let parser_compression = if compressors > 0 {
+            Compression::Plain
+        } else {
+            compression
+        };
+        let sampler = queues.spawn(scope);
+
         // ─── Parser Thread ─────────────────────────────────────
-        // Multiple parser threads are used to exploit CPU parallelism for `transform_fastq` and gzip compression.
-        // Each thread transforms records and buffers them into a local pool,
+        // A pool of parser threads is used to exploit CPU parallelism for `transform_fastq` and gzip compression.
+        // Each parser transforms records and buffers them into a local pool,
         // which is periodically flushed into the writer pipeline.
-        let mut parser_handles = Vec::with_capacity(threads);
-        let gzip = gz_compressed(output);
-        for _ in 0 .. threads {
-            let rx = reader_rx.clone();
-            let tx = writer_tx.clone();
-            let handle = scope.spawn(move || -> Result<()> {
-                // Temporary buffer for current output chunk
-                let mut records_pool: Vec<u8> = Vec::with_capacity(chunk_bytes);
-                let mut compressor = Compressor::new(compression_level);
-                while let Ok(records) = rx.recv() {
-                    for record in records {
-                        if id_sets.contains(record.id.as_ref()) {
-                            // Flush when pool is too full to accept the next record.
-                            // This ensures output chunks remain near the target block size.
-                            if records_pool.capacity() - records_pool.len() < record.bytes_size() {
-                                let mut pack = Vec::with_capacity(chunk_bytes);
-                                std::mem::swap(&mut records_pool, &mut pack);
-                                // Compress if gzip file
-                                if gzip {
-                                    pack = gzip_pack(&pack, &mut compressor)?
-                                }
-
-                                // Send compressed or raw bytes to writer
-                                tx.send(pack).with_context(|| {
-                                    format!(
-                                        "(Parser) Failed to send parsed record to Writer thread"
-                                    )
+        let dry_run = output.is_none() && callback_tx.is_none();
+        let finish_tx = chunk_tx.clone();
+        let finish_callback_tx = callback_tx.clone();
+        let parser_handle = spawn_parsers(
+            scope,
+            threads,
+            reader_rx,
+            move || {
+                let mut counts = KractorCounts::new(by_taxon);
+                if !filters.is_empty() {
+                    counts = counts.with_dropped();
+                }
+                if stats {
+                    counts = counts.with_stats(1);
+                }
+                ParserState {
+                    counts,
+                    // Temporary buffer for current output chunk
+                    records_pool: Vec::with_capacity(if output.is_none() {
+                        0
+                    } else {
+                        chunk_bytes
+                    }),
+                    matched: Vec::new(),
+                    chunk_records: Vec::new(),
+                    packer: ChunkPacker::new(parser_compression),
+                }
+            },
+            move |state, records| -> Result<()> {
+                let ParserState {
+                    counts,
+                    records_pool,
+                    matched,
+                    chunk_records,
+                    packer,
+                } = state;
+                counts.records += records.len();
+                for record in records {
+                    if let Some(taxid) = taxids.taxid(&record) {
+                        if !filters.accept(&[&record]) {
+                            counts.add_dropped();
+                            counts.add_stats(ReadFate::Dropped, &[&record]);
+                            continue;
+                        }
+                        counts.add_match(Some(taxid));
+                        counts.add_stats(ReadFate::Matched, &[&record]);
+                        if dry_run {
+                            continue;
+                        }
+                        if let Some(callback_tx) = &callback_tx {
+                            let taxid = taxid.to_vec();
+                            let record = tags.tag(filters.transform(record))?;
+                            matched.push((taxid, record, None));
+                            if matched.len() >= batch_size {
+                                callback_tx.send(std::mem::take(matched)).with_context(|| {
+                                    format!("(Parser) Failed to send matched reads to R")
                                 })?;
                             }
+                            continue;
+                        }
+                        let record = tags.tag(filters.transform(record))?;
+                        // Flush when pool is too full to accept the next record.
+                        // This ensures output chunks remain near the target block size.
+                        if records_pool.capacity() - records_pool.len() < record.bytes_size() {
+                            let mut pack = Vec::with_capacity(chunk_bytes);
+                            std::mem::swap(records_pool, &mut pack);
+                            // Compress if gzip or zstd file
+                            pack = packer.pack(pack)?;
+
+                            // Send compressed or raw bytes to writer
+                            let pack = (pack, std::mem::take(chunk_records));
+                            chunk_tx.send(pack).with_context(|| {
+                                format!("(Parser) Failed to send parsed record to Writer thread")
+                            })?;
                         }
                         // Append encoded record to buffer
-                        record.extend(&mut records_pool);
+                        if index {
+                            chunk_records.push((record.id.clone(), records_pool.len()));
+                        }
+                        record.extend(records_pool);
+                    } else {
+                        counts.add_stats(ReadFate::Unmatched, &[&record]);
                     }
                 }
-
+                Ok(())
+            },
+            move |mut state: ParserState| -> Result<KractorCounts> {
                 // Flush remaining records if any
-                if !records_pool.is_empty() {
-                    let pack = if gzip {
-                        gzip_pack(&records_pool, &mut compressor)?
-                    } else {
-                        records_pool
-                    };
-                    tx.send(pack).with_context(|| {
-                        format!("(Parser) Failed to send parsed record to Writer thread")
-                    })?;
+                if let Some(callback_tx) = finish_callback_tx
+                    .as_ref()
+                    .filter(|_| !state.matched.is_empty())
+                {
+                    callback_tx
+                        .send(state.matched)
+                        .with_context(|| format!("(Parser) Failed to send matched reads to R"))?;
                 }
-                Ok(())
-            });
-            parser_handles.push(handle);
-        }
-        drop(reader_rx);
-        drop(writer_tx);
+                if !state.records_pool.is_empty() {
+                    let pack = state.packer.pack(state.records_pool)?;
+                    finish_tx
+                        .send((pack, state.chunk_records))
+                        .with_context(|| {
+                            format!("(Parser) Failed to send parsed record to Writer thread")
+                        })?;
+                }
+                Ok(state.counts)
+            },
+        )?;
 
         // ─── reader Thread ─────────────────────────────────────
         let reader_handle = scope.spawn(move || -> Result<()> {
-            let mut reader =
-                FastqReader::with_capacity(BUFFER_SIZE, new_reader(input, BUFFER_SIZE, input_bar)?);
+            let mut reader = FastqReader::with_capacity(
+                BUFFER_SIZE,
+                spawn_reader(scope, input, input_bar, threads)?,
+            );
             let mut reader_tx = BatchSender::with_capacity(batch_size, reader_tx);
             while let Some(record) = reader
                 .read_record()
@@ -136,18 +255,75 @@ pub(super) fn parse_single<P: AsRef<Path> + ?Sized>(
             Ok(())
         });
 
+        // ─── Callback ──────────────────────────────────────────
+        // R is single-threaded, the callback runs on the calling thread
+        if let (Some(callback), Some(callback_rx)) = (callback, callback_rx) {
+            for reads in callback_rx {
+                callback(reads)?;
+            }
+        }
+
         // ─── Join Threads and Propagate Errors ────────────────
-        writer_handle
-            .join()
-            .map_err(|e| anyhow!("(Writer) thread panicked: {:?}", e))??;
-        for handler in parser_handles {
+        if let Some(writer_handle) = writer_handle {
+            writer_handle
+                .join()
+                .map_err(|e| anyhow!("(Writer) thread panicked: {:?}", e))??;
+        }
+        for handler in compressor_handles {
             handler
                 .join()
-                .map_err(|e| anyhow!("(Parser) thread panicked: {:?}", e))??;
+                .map_err(|e| anyhow!("(Compressor) thread panicked: {:?}", e))??;
+        }
+        let mut counts = KractorCounts::new(by_taxon);
+        for parser_counts in parser_handle
+            .join()
+            .map_err(|e| anyhow!("(Parser) thread panicked: {:?}", e))??
+        {
+            counts.merge(parser_counts);
         }
         reader_handle
             .join()
             .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))??;
-        Ok(())
+        counts.queues = Some(sampler.finish()?);
+        Ok(counts)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_single_matched_only() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let input = dir.path().join("reads.fq");
+        std::fs::write(
+            &input,
+            b"@r1\nACGT\n+\nIIII\n@r2\nTTTT\n+\nIIII\n@r3\nGGGG\n+\nIIII\n",
+        )?;
+        let output = dir.path().join("matched.fq");
+        let taxids = ReadTaxids::Koutput(
+            [(b"r2".as_slice(), b"562".as_slice())]
+                .into_iter()
+                .collect(),
+        );
+        let counts = parse_single(
+            &taxids,
+            &input,
+            None,
+            Some(&output),
+            None,
+            None,
+            &FilterChain::new(),
+            &ReadTags::default(),
+            &ExtractOptions {
+                compression_level: (Some(4), None),
+                ..ExtractOptions::new(2, 1024)
+            },
+        )?;
+        assert_eq!((counts.records, counts.matched), (3, 1));
+        // Records not matched are left out of the output
+        assert_eq!(std::fs::read_to_string(&output)?, "@r2\nTTTT\n+\nIIII\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::hash::{DefaultHasher, Hasher};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rustc_hash::FxHashMap as HashMap;
+
+use super::koutput_line_error;
+use crate::reader::LineReader;
+use crate::utils::*;
+
+// The sequence IDs of a Kraken2 output, held as strings in a map, take over
+// a hundred bytes per read, on top of the FASTQ batches in flight during
+// extraction. Instead, the IDs are streamed from the Kraken2 output, hashed,
+// and spilled as sorted runs of compact entries (the 128-bit hash of the ID
+// and the index of its taxid) into temporary files, which are merged back into
+// a table of 24 bytes per read: neither the strings of the IDs nor a map of
+// them are ever held.
+
+/// Number of entries of a run, sorted in memory before being spilled
+const RUN_LEN: usize = 1 << 22;
+
+/// Bytes of an entry in a spilled run
+const ENTRY_BYTES: usize = 20;
+
+/// The 128-bit hash of a sequence ID, as two SipHash digests of the ID with
+/// distinct prefixes. A read whose ID hash collides with one of the matched
+/// reads is not expected in practice, even for billions of reads.
+pub(super) fn id_hash(id: &[u8]) -> IdHash {
+    let digest = |prefix: u8| {
+        let mut hasher = DefaultHasher::new();
+        hasher.write_u8(prefix);
+        hasher.write(id);
+        hasher.finish()
+    };
+    (digest(0), digest(1))
+}
+
+/// The hash of a sequence ID
+pub(super) type IdHash = (u64, u64);
+
+/// Sequence ID hashes mapped to their taxid, sorted by hash
+#[derive(Debug)]
+pub(in crate::kractor) struct HashedIds {
+    ids: Vec<(IdHash, u32)>,
+    taxids: Vec<Vec<u8>>,
+}
+
+impl HashedIds {
+    /// Spill the sequence IDs of `koutput` into `tmpdir` and merge them back
+    /// into a table. Taxa with fewer than `min_reads` reads are dropped.
+    pub(super) fn spill_koutput(
+        koutput: &str,
+        tmpdir: &Path,
+        min_reads: Option<usize>,
+        columns: &KoutputColumns,
+    ) -> Result<Self> {
+        let mut reader =
+            LineReader::with_capacity(BUFFER_SIZE, new_reader(koutput, BUFFER_SIZE, None)?);
+        let mut taxid_index: HashMap<Vec<u8>, u32> = HashMap::default();
+        let mut taxids: Vec<Vec<u8>> = Vec::new();
+        let mut reads: Vec<usize> = Vec::new();
+        let mut run: Vec<(IdHash, u32)> = Vec::with_capacity(RUN_LEN);
+        let mut runs = IdRuns::new(tmpdir);
+        let mut line_number = 0;
+        while let Some(line) = reader
+            .read_line()
+            .with_context(|| format!("Failed to read {}", koutput))?
+        {
+            line_number += 1;
+            if line.is_empty() {
+                continue;
+            }
+            let Some([_, id, taxid, _, _]) = columns.fields(&line) else {
+                return Err(koutput_line_error(koutput, line_number));
+            };
+            if id.is_empty() {
+                continue;
+            }
+            let taxid = koutput_taxid(taxid).unwrap_or_default();
+            let index = match taxid_index.get(taxid) {
+                Some(index) => *index,
+                None => {
+                    let index = taxids.len() as u32;
+                    taxid_index.insert(taxid.to_vec(), index);
+                    taxids.push(taxid.to_vec());
+                    reads.push(0);
+                    index
+                }
+            };
+            reads[index as usize] += 1;
+            run.push((id_hash(id), index));
+            if run.len() == RUN_LEN {
+                runs.spill(&mut run)?;
+            }
+        }
+        if !run.is_empty() {
+            runs.spill(&mut run)?;
+        }
+        drop(run);
+
+        let mut ids = Vec::with_capacity(runs.len);
+        runs.merge(|entry| {
+            if min_reads.is_none_or(|min| reads[entry.1 as usize] >= min) {
+                ids.push(entry);
+            }
+        })?;
+        Ok(Self { ids, taxids })
+    }
+
+    /// The taxid of the read of sequence ID `id`, if any
+    pub(super) fn taxid(&self, id: &[u8]) -> Option<&[u8]> {
+        let hash = id_hash(id);
+        self.ids
+            .binary_search_by_key(&hash, |(hash, _)| *hash)
+            .ok()
+            .map(|i| self.taxids[self.ids[i].1 as usize].as_slice())
+    }
+}
+
+/// Sorted runs of entries spilled into temporary files, removed once dropped
+struct IdRuns<'a> {
+    tmpdir: &'a Path,
+    paths: Vec<PathBuf>,
+    // Total number of entries
+    len: usize,
+}
+
+impl<'a> IdRuns<'a> {
+    fn new(tmpdir: &'a Path) -> Self {
+        Self {
+            tmpdir,
+            paths: Vec::new(),
+            len: 0,
+        }
+    }
+
+    fn spill(&mut self, run: &mut Vec<(IdHash, u32)>) -> Result<()> {
+        run.sort_unstable();
+        let path = self.tmpdir.join(format!(
+            "mire-ids-{}-{}.run",
+            std::process::id(),
+            self.paths.len()
+        ));
+        let file = File::create(&path)
+            .with_context(|| format!("Failed to create spill file {}", path.display()))?;
+        self.paths.push(path.clone());
+        self.len += run.len();
+        let mut writer = BufWriter::with_capacity(BUFFER_SIZE, file);
+        for ((high, low), taxid) in run.drain(..) {
+            writer
+                .write_all(&high.to_le_bytes())
+                .and_then(|_| writer.write_all(&low.to_le_bytes()))
+                .and_then(|_| writer.write_all(&taxid.to_le_bytes()))
+                .with_context(|| format!("Failed to write spill file {}", path.display()))?;
+        }
+        writer
+            .flush()
+            .with_context(|| format!("Failed to flush spill file {}", path.display()))
+    }
+
+    /// Stream the entries of all runs back in sorted order
+    fn merge(&self, mut f: impl FnMut((IdHash, u32))) -> Result<()> {
+        let mut readers = self
+            .paths
+            .iter()
+            .map(|path| {
+                File::open(path)
+                    .map(|file| BufReader::with_capacity(BUFFER_SIZE, file))
+                    .with_context(|| format!("Failed to open spill file {}", path.display()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut heap = BinaryHeap::with_capacity(readers.len());
+        for (i, reader) in readers.iter_mut().enumerate() {
+            if let Some(entry) = self.read_entry(i, reader)? {
+                heap.push(Reverse((entry, i)));
+            }
+        }
+        while let Some(Reverse((entry, i))) = heap.pop() {
+            f(entry);
+            if let Some(entry) = self.read_entry(i, &mut readers[i])? {
+                heap.push(Reverse((entry, i)));
+            }
+        }
+        Ok(())
+    }
+
+    fn read_entry(&self, i: usize, reader: &mut BufReader<File>) -> Result<Option<(IdHash, u32)>> {
+        let mut buf = [0; ENTRY_BYTES];
+        match reader.read_exact(&mut buf) {
+            Ok(()) => Ok(Some((
+                (
+                    u64::from_le_bytes(buf[.. 8].try_into()?),
+                    u64::from_le_bytes(buf[8 .. 16].try_into()?),
+                ),
+                u32::from_le_bytes(buf[16 ..].try_into()?),
+            ))),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e)
+                .with_context(|| format!("Failed to read spill file {}", self.paths[i].display())),
+        }
+    }
+}
+
+impl Drop for IdRuns<'_> {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+
+    #[test]
+    fn test_hashed_ids() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let koutput = temp.path().join("koutput.txt");
+        std::fs::write(
+            &koutput,
+            "C\tr1\tEscherichia coli (taxid 562)\t4\t562:1\n\
+             C\tr2\t1280\t4\t1280:1\nC\tr3\t562\t4\t562:1\n",
+        )?;
+        let koutput = koutput.to_str().unwrap();
+        let ids = HashedIds::spill_koutput(koutput, temp.path(), None, &KoutputColumns::default())?;
+        assert_eq!(ids.taxid(b"r1"), Some(&b"562"[..]));
+        assert_eq!(ids.taxid(b"r2"), Some(&b"1280"[..]));
+        assert_eq!(ids.taxid(b"r4"), None);
+        // Spill files are removed
+        assert_eq!(std::fs::read_dir(temp.path())?.count(), 1);
+
+        // Taxa with a single read are dropped
+        let ids =
+            HashedIds::spill_koutput(koutput, temp.path(), Some(2), &KoutputColumns::default())?;
+        assert_eq!(ids.taxid(b"r2"), None);
+        assert_eq!(ids.taxid(b"r3"), Some(&b"562"[..]));
+
+        // Blank lines are skipped, lines without 5 fields are an error
+        let koutput = temp.path().join("invalid.txt");
+        std::fs::write(&koutput, "C\tr1\t562\t4\t562:1\n\nC\tr2\t1280\n")?;
+        let err = HashedIds::spill_koutput(
+            koutput.to_str().unwrap(),
+            temp.path(),
+            None,
+            &KoutputColumns::default(),
+        )
+        .unwrap_err();
+        assert_eq!(ErrorKind::of(&err), Some(ErrorKind::Parse));
+        assert!(err.to_string().contains("line 3"));
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+use anyhow::{Context, Result};
+use bytes::Bytes;
+
+use super::barcode::BarcodeSource;
+use super::with_read_group;
+use crate::fastq_record::FastqRecord;
+use crate::seq_refine::seq_action::{SubseqActions, SubseqPairedActions};
+use crate::utils::*;
+
+/// The `seq_refine()` actions applied to extracted reads, to move the barcode
+/// and UMI bases of raw reads into their headers
+enum MateActions {
+    Single(SubseqActions),
+    Paired(Box<SubseqPairedActions>),
+}
+
+/// The tags added to the `MIRE{}` annotation of extracted reads
+#[derive(Default)]
+pub(super) struct ReadTags<'a> {
+    // Label each read with its sample
+    read_group: Option<&'a [u8]>,
+    // Copy the tags of each mate into the other, so a single extracted mate
+    // keeps the annotations of the mate left out
+    transfer: bool,
+    // The cell barcode read from the first mate sequence, added as a
+    // `BARCODE` tag to both mates on transfer
+    barcode: Option<BarcodeSource>,
+    // Embed and trim subsequences of the reads, before any transfer
+    actions: Option<MateActions>,
+    // Remove the whole annotation from the written reads, last
+    strip: bool,
+}
+
+impl<'a> ReadTags<'a> {
+    pub(super) fn new(read_group: Option<&'a [u8]>) -> Self {
+        Self {
+            read_group,
+            ..Default::default()
+        }
+    }
+
+    /// Transfer the tags between mates, `barcode` is only used if it gives
+    /// ranges of the first mate, as tags are transferred anyway
+    pub(super) fn with_transfer(mut self, barcode: Option<BarcodeSource>) -> Self {
+        self.transfer = true;
+        self.barcode = barcode.filter(|x| matches!(x, BarcodeSource::Ranges(_)));
+        self
+    }
+
+    pub(super) fn with_strip(mut self, strip: bool) -> Self {
+        self.strip = strip;
+        self
+    }
+
+    /// Apply the actions of the first and second mates, `actions2` is ignored
+    /// for single-end reads
+    pub(super) fn with_actions(
+        mut self,
+        actions1: Option<SubseqActions>,
+        actions2: Option<SubseqActions>,
+        paired: bool,
+    ) -> Self {
+        self.actions = match (actions1, actions2) {
+            (None, None) => None,
+            (actions1, actions2) if paired => Some(MateActions::Paired(Box::new(
+                SubseqPairedActions::new(actions1, actions2),
+            ))),
+            (actions1, _) => actions1.map(MateActions::Single),
+        };
+        self
+    }
+
+    pub(super) fn tag(&self, mut record: FastqRecord<Bytes>) -> Result<FastqRecord<Bytes>> {
+        if let Some(MateActions::Single(actions)) = &self.actions {
+            actions.transform_fastq(&mut record).with_context(|| {
+                format!(
+                    "Failed to apply sequence actions to read {}",
+                    String::from_utf8_lossy(&record.id)
+                )
+            })?;
+        }
+        Ok(self.finish(record))
+    }
+
+    pub(super) fn tag_pair(
+        &self,
+        mut record1: FastqRecord<Bytes>,
+        mut record2: FastqRecord<Bytes>,
+    ) -> Result<(FastqRecord<Bytes>, FastqRecord<Bytes>)> {
+        if let Some(MateActions::Paired(actions)) = &self.actions {
+            actions
+                .transform_fastq(&mut record1, &mut record2)
+                .with_context(|| {
+                    format!(
+                        "Failed to apply sequence actions to read {}",
+                        String::from_utf8_lossy(&record1.id)
+                    )
+                })?;
+        }
+        let (record1, record2) = if self.transfer {
+            let mut tags1 = mire_fields(record1.desc.as_ref());
+            let mut tags2 = mire_fields(record2.desc.as_ref());
+            if let Some(barcode) = self
+                .barcode
+                .as_ref()
+                .and_then(|x| x.barcode(&record1, Some(&record2)))
+            {
+                let barcode = (Bytes::from_static(b"BARCODE"), Bytes::from(barcode));
+                tags1.push(barcode.clone());
+                tags2.push(barcode);
+            }
+            (with_tags(record1, &tags2), with_tags(record2, &tags1))
+        } else {
+            (record1, record2)
+        };
+        Ok((self.finish(record1), self.finish(record2)))
+    }
+
+    fn finish(&self, record: FastqRecord<Bytes>) -> FastqRecord<Bytes> {
+        let mut record = with_read_group(record, self.read_group);
+        if self.strip {
+            record.desc = record.desc.as_ref().and_then(strip_mire_tags);
+        }
+        record
+    }
+}
+
+/// The `tag:value` pairs of the `MIRE{}` annotation of a read description
+fn mire_fields(desc: Option<&Bytes>) -> Vec<(Bytes, Bytes)> {
+    desc.map_or_else(Vec::new, |desc| {
+        mire_tags(desc)
+            .into_iter()
+            .map(|(tag, value)| (desc.slice_ref(tag), desc.slice_ref(value)))
+            .collect()
+    })
+}
+
+/// Add the tags missing from the `MIRE{}` annotation of a record
+fn with_tags(mut record: FastqRecord<Bytes>, tags: &[(Bytes, Bytes)]) -> FastqRecord<Bytes> {
+    for (tag, value) in tags {
+        let desc = record.desc.as_deref();
+        if desc.and_then(|desc| mire_tag(desc, tag)).is_none() {
+            record.desc = Some(add_mire_tag(desc, tag, value));
+        }
+    }
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seq_range::{SeqRange, SeqRanges};
+    use crate::seq_refine::seq_action::SeqAction;
+
+    #[test]
+    fn test_read_tags_transfer() -> Result<()> {
+        let record = |desc: Option<&'static str>, seq: &'static str| {
+            FastqRecord::new(
+                Bytes::from("r1"),
+                desc.map(Bytes::from),
+                Bytes::from(seq),
+                Bytes::from("+"),
+                Bytes::from("I".repeat(seq.len())),
+            )
+        };
+        let desc = |records: Result<(FastqRecord<Bytes>, FastqRecord<Bytes>)>| {
+            let (record1, record2) = records.unwrap();
+            (record1.desc.unwrap(), record2.desc.unwrap())
+        };
+
+        let tags = ReadTags::new(Some(b"S1")).with_transfer(None);
+        let (desc1, desc2) = desc(tags.tag_pair(
+            record(Some("1:N:0 MIRE{BARCODE:ACGT:UMI:TTTT}"), "ACGTTTTT"),
+            record(Some("2:N:0"), "GGGG"),
+        ));
+        assert_eq!(desc1, "1:N:0 MIRE{BARCODE:ACGT:UMI:TTTT:RG:S1}");
+        assert_eq!(desc2, "2:N:0 MIRE{BARCODE:ACGT:UMI:TTTT:RG:S1}");
+
+        // Tags present in a mate are kept
+        let tags = ReadTags::new(None).with_transfer(None);
+        let (_, desc2) = desc(tags.tag_pair(
+            record(Some("MIRE{UMI:TTTT}"), "ACGT"),
+            record(Some("MIRE{UMI:AAAA}"), "GGGG"),
+        ));
+        assert_eq!(desc2, "MIRE{UMI:AAAA}");
+
+        let ranges = SeqRanges::from_iter([SeqRange::To(4)]);
+        let tags = ReadTags::new(None).with_transfer(Some(BarcodeSource::Ranges(ranges)));
+        let (desc1, desc2) = desc(tags.tag_pair(record(None, "ACGTTTTT"), record(None, "GGGG")));
+        assert_eq!(desc1, "MIRE{BARCODE:ACGT}");
+        assert_eq!(desc2, "MIRE{BARCODE:ACGT}");
+
+        // Without transfer, mates are only labelled with their read group
+        let (record1, record2) =
+            ReadTags::new(None).tag_pair(record(None, "ACGT"), record(None, "GGGG"))?;
+        assert!(record1.desc.is_none() && record2.desc.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_tags_actions() -> Result<()> {
+        let record = |seq: &'static str| {
+            FastqRecord::new(
+                Bytes::from("r1"),
+                None,
+                Bytes::from(seq),
+                Bytes::from("+"),
+                Bytes::from("ABCDEFGHIJ"[.. seq.len()].to_string()),
+            )
+        };
+        let mut builder = SubseqActions::builder();
+        builder.add_action(
+            SeqAction::EmbedTrim(Bytes::from("BARCODE")),
+            SeqRanges::from_iter([SeqRange::To(4)]),
+        )?;
+        let actions = builder.build()?;
+
+        // The barcode bases and their qualities are moved into the header
+        let tags = ReadTags::new(None).with_actions(Some(actions), None, false);
+        let trimmed = tags.tag(record("ACGTTTGG"))?;
+        assert_eq!(trimmed.seq, "TTGG");
+        assert_eq!(trimmed.qual, "EFGH");
+        assert_eq!(trimmed.desc.unwrap(), "MIRE{BARCODE:ACGT}");
+        assert!(tags.tag(record("ACG")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_tags_preserved() -> Result<()> {
+        let record = |desc: &'static str| {
+            FastqRecord::new(
+                Bytes::from("r1"),
+                Some(Bytes::from(desc)),
+                Bytes::from("ACGTTTGG"),
+                Bytes::from("+"),
+                Bytes::from("IIIIIIII"),
+            )
+        };
+        let desc = "1:N:0 MIRE{CB:AAAC:UB:TTTT}";
+
+        // Trimming only adds the embedded tags to the annotation
+        let mut builder = SubseqActions::builder();
+        builder.add_action(
+            SeqAction::EmbedTrim(Bytes::from("UMI")),
+            SeqRanges::from_iter([SeqRange::To(2)]),
+        )?;
+        let tags = ReadTags::new(None).with_actions(Some(builder.build()?), None, false);
+        assert_eq!(
+            tags.tag(record(desc))?.desc.unwrap(),
+            "1:N:0 MIRE{CB:AAAC:UB:TTTT:UMI:AC}"
+        );
+        let (record1, record2) = ReadTags::default().tag_pair(record(desc), record(desc))?;
+        assert_eq!(record1.desc.unwrap(), desc);
+        assert_eq!(record2.desc.unwrap(), desc);
+
+        let tags = ReadTags::default().with_strip(true);
+        assert_eq!(tags.tag(record(desc))?.desc.unwrap(), "1:N:0");
+        assert!(tags.tag(record("MIRE{CB:AAAC}"))?.desc.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,307 @@
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::kractor::reads::barcode::BarcodeSource;
+use crate::kractor::reads::read_sequence_id_from_koutput;
+use crate::multi_writer::{route_reads_with, MultiWriter};
+use crate::utils::*;
+
+/// Rules routing reads to outputs by their taxid and cell barcode. An empty
+/// taxid or barcode in a rule matches any read.
+pub(super) struct RouteRules {
+    outputs: Vec<Bytes>,
+    // (taxid, barcode) → index of the first rule matching both
+    rules: HashMap<(Vec<u8>, Vec<u8>), usize>,
+    by_barcode: bool,
+}
+
+impl RouteRules {
+    pub(super) fn new(
+        outputs: Vec<String>,
+        taxids: Vec<String>,
+        barcodes: Vec<String>,
+    ) -> Result<Self> {
+        if outputs.len() != taxids.len() || outputs.len() != barcodes.len() {
+            return Err(anyhow!(
+                "'outputs', 'taxids' and 'barcodes' must have the same length"
+            ));
+        }
+        let mut rules = HashMap::default();
+        for (i, (taxid, barcode)) in taxids.into_iter().zip(barcodes).enumerate() {
+            if taxid.is_empty() && barcode.is_empty() {
+                return Err(anyhow!(
+                    "Rule {} of output '{}' requires neither a taxid nor a barcode",
+                    i + 1,
+                    outputs[i]
+                ));
+            }
+            // Earlier rules take precedence
+            rules
+                .entry((taxid.into_bytes(), barcode.into_bytes()))
+                .or_insert(i);
+        }
+        let by_barcode = rules.keys().any(|(_, barcode)| !barcode.is_empty());
+        Ok(Self {
+            outputs: outputs.into_iter().map(Bytes::from).collect(),
+            rules,
+            by_barcode,
+        })
+    }
+
+    /// The output of a read, from the first rule it matches
+    #[cfg(test)]
+    fn output(&self, taxid: &[u8], barcode: Option<&[u8]>) -> Option<&Bytes> {
+        self.rule(taxid, barcode).map(|i| &self.outputs[i])
+    }
+
+    /// The index of the first rule a read matches
+    fn rule(&self, taxid: &[u8], barcode: Option<&[u8]>) -> Option<usize> {
+        let mut keys = vec![(taxid, b"".as_slice())];
+        if let Some(barcode) = barcode {
+            keys.push((taxid, barcode));
+            keys.push((b"".as_slice(), barcode));
+        }
+        keys.into_iter()
+            .filter_map(|(taxid, barcode)| self.rules.get(&(taxid.to_vec(), barcode.to_vec())))
+            .min()
+            .copied()
+    }
+}
+
+/// Reads of each (rule, taxid), counted by each parser thread apart
+type RuleTaxa<'a> = HashMap<(usize, &'a [u8]), usize>;
+
+/// Reads of each (output, taxid)
+pub(super) type RoutedTaxa = Vec<(Bytes, Vec<u8>, usize)>;
+
+/// Extract the reads of each output in a single pass, routing every read by
+/// the taxid it was classified to (in `koutput`) and its cell barcode.
+/// Returns the number of reads (or read pairs) of each output, sorted by
+/// output, and of each taxid within each output, sorted by output and taxid.
+pub(super) fn kractor_route(
+    koutput: &str,
+    columns: &KoutputColumns,
+    fq1: &str,
+    fq2: Option<&str>,
+    rules: &RouteRules,
+    barcode: &BarcodeSource,
+    odir: &str,
+    compression_level: Option<i32>,
+    batch_size: usize,
+    chunk_bytes: usize,
+    writers: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> Result<(Vec<(Bytes, usize)>, RoutedTaxa)> {
+    let ids = read_sequence_id_from_koutput(koutput, 126 * 1024, columns)
+        .context("Failed to read sequence IDs")?;
+    let id_sets = ids
+        .iter()
+        .map(|(id, taxid)| (id.as_slice(), taxid.as_slice()))
+        .collect::<HashMap<&[u8], &[u8]>>();
+
+    let progress = new_progress();
+    let pb1 = progress.add(input_progress_bar(fq1)?);
+    pb1.set_prefix("Reading fastq");
+    pb1.set_style(progress_reader_style()?);
+    let pb2 = progress.add(output_progress_bar());
+    pb2.set_prefix("Writing fastq");
+    pb2.set_style(progress_writer_style()?);
+
+    let inputs = match fq2 {
+        Some(fq2) => vec![fq1, fq2],
+        None => vec![fq1],
+    };
+    let paired = fq2.is_some();
+    let output_name = |output: &[u8], mate: usize| -> String {
+        let output = String::from_utf8_lossy(output);
+        if paired {
+            format!("{}_R{}.fastq.gz", output, mate)
+        } else {
+            format!("{}.fastq.gz", output)
+        }
+    };
+    let ((counts, _), parser_taxa) = route_reads_with::<RuleTaxa, _, _, _>(
+        &inputs,
+        inputs.len(),
+        || MultiWriter::directory(odir, compression_level, chunk_bytes, Some(pb2.clone())),
+        // Count the reads of each taxid in the state of the parser thread
+        |taxa, records| {
+            let Some(taxid) = id_sets.get(records[0].id.as_ref()) else {
+                return Ok(None);
+            };
+            let barcode = if rules.by_barcode {
+                barcode.barcode(&records[0], records.get(1))
+            } else {
+                None
+            };
+            let Some(rule) = rules.rule(taxid, barcode.as_deref()) else {
+                return Ok(None);
+            };
+            *taxa.entry((rule, *taxid)).or_insert(0) += 1;
+            Ok(Some(rules.outputs[rule].clone()))
+        },
+        output_name,
+        Some(pb1),
+        batch_size,
+        chunk_bytes,
+        writers,
+        nqueue,
+        threads,
+    )?;
+
+    // Counts of the parser threads, and rules of the same output, are merged
+    let mut by_output: HashMap<(&Bytes, &[u8]), usize> = HashMap::default();
+    for ((rule, taxid), reads) in parser_taxa.into_iter().flatten() {
+        *by_output.entry((&rules.outputs[rule], taxid)).or_insert(0) += reads;
+    }
+    let mut taxa = by_output
+        .into_iter()
+        .map(|((output, taxid), reads)| (output.clone(), taxid.to_vec(), reads))
+        .collect::<Vec<_>>();
+    taxa.sort_unstable();
+    Ok((counts, taxa))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn test_kractor_route() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let koutput = temp.path().join("koutput.txt");
+        let fq = temp.path().join("reads.fq");
+        std::fs::write(
+            &koutput,
+            "C\tr1\t562\t4\t562:1\nC\tr2\t562\t4\t562:1\nC\tr3\t1280\t4\t1280:1\n\
+             C\tr4\t1280\t4\t1280:1\nU\tr5\t0\t4\t0:1\n",
+        )?;
+        std::fs::write(
+            &fq,
+            "@r1 MIRE{CB:AAAC}\nACGT\n+\nIIII\n@r2 MIRE{CB:CCCA}\nACGT\n+\nIIII\n\
+             @r3 MIRE{CB:CCCA}\nACGT\n+\nIIII\n@r4 MIRE{CB:AAAC}\nACGT\n+\nIIII\n\
+             @r5 MIRE{CB:AAAC}\nACGT\n+\nIIII\n",
+        )?;
+        // E. coli reads of cell AAAC, and S. aureus reads of cell CCCA
+        let rules = RouteRules::new(
+            vec!["cluster1".to_string(), "cluster2".to_string()],
+            vec!["562".to_string(), "1280".to_string()],
+            vec!["AAAC".to_string(), "CCCA".to_string()],
+        )?;
+        let (counts, taxa) = kractor_route(
+            koutput.to_str().unwrap(),
+            &KoutputColumns::default(),
+            fq.to_str().unwrap(),
+            None,
+            &rules,
+            &BarcodeSource::Tag(b"CB".to_vec()),
+            temp.path().to_str().unwrap(),
+            Some(4),
+            2,
+            64,
+            2,
+            None,
+            1,
+        )?;
+        assert_eq!(
+            counts,
+            vec![(Bytes::from("cluster1"), 1), (Bytes::from("cluster2"), 1)]
+        );
+        assert_eq!(
+            taxa,
+            vec![
+                (Bytes::from("cluster1"), b"562".to_vec(), 1),
+                (Bytes::from("cluster2"), b"1280".to_vec(), 1)
+            ]
+        );
+        let mut out = String::new();
+        new_reader(&temp.path().join("cluster2.fastq.gz"), BUFFER_SIZE, None)?
+            .read_to_string(&mut out)?;
+        assert_eq!(out, "@r3 MIRE{CB:CCCA}\nACGT\n+\nIIII\n");
+
+        // Rules without a barcode match reads of any cell, the first matching
+        // rule wins
+        let rules = RouteRules::new(
+            vec!["cell".to_string(), "ecoli".to_string()],
+            vec!["562".to_string(), "562".to_string()],
+            vec!["AAAC".to_string(), "".to_string()],
+        )?;
+        assert_eq!(
+            rules.output(b"562", Some(b"AAAC")),
+            Some(&Bytes::from("cell"))
+        );
+        assert_eq!(
+            rules.output(b"562", Some(b"CCCA")),
+            Some(&Bytes::from("ecoli"))
+        );
+        assert_eq!(rules.output(b"1280", Some(b"AAAC")), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_kractor_route_groups() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let koutput = temp.path().join("koutput.txt");
+        let fq = temp.path().join("reads.fq");
+        let taxids = ["562", "83333", "562", "1280", "0", "83333", "1280", "9606"];
+        let mut lines = String::new();
+        let mut reads = String::new();
+        for (i, taxid) in taxids.iter().enumerate() {
+            lines.push_str(&format!("C\tr{}\t{}\t4\t{}:1\n", i, taxid, taxid));
+            reads.push_str(&format!("@r{}\nACGT\n+\nIIII\n", i));
+        }
+        std::fs::write(&koutput, lines)?;
+        std::fs::write(&fq, reads)?;
+        // Groups of taxids, as built by `kractor_groups()`: E. coli with its
+        // K-12 strain, and S. aureus
+        let rules = RouteRules::new(
+            vec![
+                "ecoli".to_string(),
+                "ecoli".to_string(),
+                "staph".to_string(),
+            ],
+            vec!["562".to_string(), "83333".to_string(), "1280".to_string()],
+            vec![String::new(); 3],
+        )?;
+        // Reads are spread over several parser threads, whose counts of each
+        // taxid are merged
+        let (counts, taxa) = kractor_route(
+            koutput.to_str().unwrap(),
+            &KoutputColumns::default(),
+            fq.to_str().unwrap(),
+            None,
+            &rules,
+            &BarcodeSource::Tag(b"CB".to_vec()),
+            temp.path().to_str().unwrap(),
+            Some(4),
+            1,
+            64,
+            1,
+            None,
+            3,
+        )?;
+        assert_eq!(
+            counts,
+            vec![(Bytes::from("ecoli"), 4), (Bytes::from("staph"), 2)]
+        );
+        assert_eq!(
+            taxa,
+            vec![
+                (Bytes::from("ecoli"), b"562".to_vec(), 2),
+                (Bytes::from("ecoli"), b"83333".to_vec(), 2),
+                (Bytes::from("staph"), b"1280".to_vec(), 2)
+            ]
+        );
+        let mut out = String::new();
+        new_reader(&temp.path().join("staph.fastq.gz"), BUFFER_SIZE, None)?
+            .read_to_string(&mut out)?;
+        let mut ids = out.lines().step_by(4).collect::<Vec<_>>();
+        ids.sort_unstable();
+        assert_eq!(ids, vec!["@r3", "@r6"]);
+        Ok(())
+    }
+}
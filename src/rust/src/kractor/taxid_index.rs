@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::reader::LineReader;
+use crate::utils::*;
+
+const INDEX_MAGIC: &[u8] = b"#mire taxid index\n";
+const TABLE_HEADER: &[u8] = b"taxid\treads\toffset\n";
+
+/// Build the postings index of a Kraken2 output: the IDs of the classified
+/// reads of each taxid, grouped by taxid. The index is a text file starting
+/// with a table of the number of reads of each taxid and the offset of its
+/// IDs, ended by an empty line, followed by the IDs themselves, one per line.
+/// Returns the number of taxids and of reads indexed.
+pub(super) fn build_taxid_index(koutput: &str, index: &str) -> Result<(usize, usize)> {
+    let mut reader =
+        LineReader::with_capacity(BUFFER_SIZE, new_reader(koutput, BUFFER_SIZE, None)?);
+    let mut postings: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+    let mut reads = 0;
+    while let Some(line) = reader
+        .read_line()
+        .with_context(|| format!("Failed to read {}", koutput))?
+    {
+        let mut fields = line[..].split(|b| *b == b'\t');
+        let (Some(status), Some(id), Some(taxid)) = (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        if status != b"C" || id.is_empty() {
+            continue;
+        }
+        let Some(taxid) = koutput_taxid(taxid) else {
+            continue;
+        };
+        let ids = postings.entry(taxid.to_vec()).or_default();
+        ids.extend_from_slice(id);
+        ids.push(b'\n');
+        reads += 1;
+    }
+
+    let mut writer = BufWriter::with_capacity(BUFFER_SIZE, new_writer(index, None)?);
+    write_taxid_index(&mut writer, &postings)
+        .and_then(|_| writer.complete())
+        .with_context(|| format!("Failed to write index {}", index))?;
+    Ok((postings.len(), reads))
+}
+
+fn write_taxid_index<W: Write>(
+    writer: &mut W,
+    postings: &BTreeMap<Vec<u8>, Vec<u8>>,
+) -> std::io::Result<()> {
+    writer.write_all(INDEX_MAGIC)?;
+    writer.write_all(TABLE_HEADER)?;
+    let mut offset = 0;
+    for (taxid, ids) in postings {
+        writer.write_all(taxid)?;
+        let reads = memchr::memchr_iter(b'\n', ids).count();
+        writeln!(writer, "\t{}\t{}", reads, offset)?;
+        offset += ids.len();
+    }
+    writer.write_all(b"\n")?;
+    for ids in postings.values() {
+        writer.write_all(ids)?;
+    }
+    Ok(())
+}
+
+/// Retrieve the IDs of the reads classified to `taxids` from a postings
+/// index, reading only the IDs of these taxids. Returns the taxid and the ID
+/// of each read, taxids missing from the index have no read.
+pub(super) fn query_taxid_index(index: &str, taxids: &[&str]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut reader = BufReader::with_capacity(
+        BUFFER_SIZE,
+        File::open(index).with_context(|| format!("Failed to open file: {}", index))?,
+    );
+    let (table, start) =
+        read_table(&mut reader).with_context(|| format!("Invalid taxid index {}", index))?;
+    let mut reads = Vec::new();
+    for taxid in taxids {
+        let Some((count, offset)) = table.get(taxid.as_bytes()) else {
+            continue;
+        };
+        reader
+            .seek(SeekFrom::Start(start + offset))
+            .with_context(|| format!("Failed to seek in file: {}", index))?;
+        for line in (&mut reader).split(b'\n').take(*count) {
+            let id = line.with_context(|| format!("Failed to read index {}", index))?;
+            reads.push((taxid.as_bytes().to_vec(), id));
+        }
+    }
+    Ok(reads)
+}
+
+/// The number of reads and the offset of the IDs of each taxid
+type TaxidTable = BTreeMap<Vec<u8>, (usize, u64)>;
+
+/// Read the table of an index, returning it with where the IDs start in the
+/// index
+fn read_table<R: BufRead + Seek>(reader: &mut R) -> Result<(TaxidTable, u64)> {
+    let mut magic = vec![0; INDEX_MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != INDEX_MAGIC {
+        return Err(anyhow!("Not a taxid index"));
+    }
+    let mut table = BTreeMap::new();
+    let mut line = Vec::new();
+    let mut header = true;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Err(anyhow!("Truncated table"));
+        }
+        if header {
+            header = false;
+            continue;
+        }
+        if line == b"\n" {
+            break;
+        }
+        let fields = line
+            .trim_ascii_end()
+            .split(|b| *b == b'\t')
+            .collect::<Vec<_>>();
+        let [taxid, reads, offset] = fields[..] else {
+            return Err(anyhow!(
+                "Invalid table line: {}",
+                String::from_utf8_lossy(&line)
+            ));
+        };
+        table.insert(
+            taxid.to_vec(),
+            (parse_usize(reads)?, parse_usize(offset)? as u64),
+        );
+    }
+    Ok((table, reader.stream_position()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_taxid_index() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let koutput = temp.path().join("koutput.txt");
+        let index = temp.path().join("koutput.taxidx");
+        std::fs::write(
+            &koutput,
+            "C\tr1\tEscherichia coli (taxid 562)\t100\t562:66\n\
+             U\tr2\t0\t100\t0:66\n\
+             C\tr3\t9606\t100\t9606:66\n\
+             C\tr4\tEscherichia coli (taxid 562)\t100\t562:66\n",
+        )?;
+        let (koutput, index) = (koutput.to_str().unwrap(), index.to_str().unwrap());
+        assert_eq!(build_taxid_index(koutput, index)?, (2, 3));
+        let reads = query_taxid_index(index, &["9606", "1", "562"])?;
+        assert_eq!(
+            reads,
+            vec![
+                (b"9606".to_vec(), b"r3".to_vec()),
+                (b"562".to_vec(), b"r1".to_vec()),
+                (b"562".to_vec(), b"r4".to_vec()),
+            ]
+        );
+        assert!(query_taxid_index(koutput, &["562"]).is_err());
+        Ok(())
+    }
+}
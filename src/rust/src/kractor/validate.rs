@@ -0,0 +1,109 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader};
+
+use anyhow::{anyhow, Context, Result};
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::kractor::reads::read_sequence_id_from_koutput;
+use crate::utils::*;
+
+/// Number of reads (or read pairs), of aligned reads and of reads aligned to
+/// a reference of the same taxid, per taxid assigned by Kraken2
+pub(super) type AlignmentCounts = BTreeMap<Vec<u8>, (usize, usize, usize)>;
+
+/// Summarize the alignments of extracted reads against reference genomes
+/// (e.g., extracted by `kractor_fasta()`) by the taxid each read was assigned
+/// to in `koutput`. The taxid of a reference is taken from the
+/// `kraken:taxid|N` token of its name.
+pub(super) fn kractor_validate(
+    koutput: &str,
+    columns: &KoutputColumns,
+    sam: &str,
+) -> Result<AlignmentCounts> {
+    let ids = read_sequence_id_from_koutput(koutput, 126 * 1024, columns)
+        .context("Failed to read sequence IDs")?;
+    let taxids = ids
+        .iter()
+        .map(|(id, taxid)| (id.as_slice(), taxid.as_slice()))
+        .collect::<HashMap<&[u8], &[u8]>>();
+    let reader = BufReader::with_capacity(BUFFER_SIZE, new_reader(sam, BUFFER_SIZE, None)?);
+    summarize_alignments(reader, &taxids)
+        .with_context(|| format!("Failed to summarize alignments of {}", sam))
+}
+
+/// Count the primary alignments of a SAM stream. Paired reads are counted
+/// once, by their first mate.
+fn summarize_alignments<R: BufRead>(
+    sam: R,
+    taxids: &HashMap<&[u8], &[u8]>,
+) -> Result<AlignmentCounts> {
+    let mut counts = AlignmentCounts::new();
+    for line in sam.split(b'\n') {
+        let line = line.with_context(|| format!("(Parser) Failed to read alignments"))?;
+        if line.is_empty() || line[0] == b'@' {
+            continue;
+        }
+        let mut fields = line.split(|b| *b == b'\t');
+        let (Some(qname), Some(flag), Some(rname)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(anyhow!(
+                "(Parser) Invalid SAM record: {}",
+                String::from_utf8_lossy(&line)
+            ));
+        };
+        let flag = parse_usize(flag).with_context(|| {
+            format!(
+                "(Parser) Invalid SAM flag in read {}",
+                String::from_utf8_lossy(qname)
+            )
+        })?;
+        // Skip secondary and supplementary alignments, and second mates
+        if flag & 0x900 != 0 || (flag & 0x1 != 0 && flag & 0x40 == 0) {
+            continue;
+        }
+        let Some(taxid) = taxids.get(qname) else {
+            continue;
+        };
+        let entry = counts.entry(taxid.to_vec()).or_default();
+        entry.0 += 1;
+        if flag & 0x4 == 0 {
+            entry.1 += 1;
+            if kraken_header_taxid(rname) == Some(taxid) {
+                entry.2 += 1;
+            }
+        }
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize_alignments() -> Result<()> {
+        let sam = "\
+@SQ\tSN:kraken:taxid|562|NC_1\tLN:100
+r1\t0\tkraken:taxid|562|NC_1\t1\t60\t4M\t*\t0\t0\tACGT\tIIII
+r1\t256\tkraken:taxid|561|NC_2\t1\t0\t4M\t*\t0\t0\tACGT\tIIII
+r2\t16\tkraken:taxid|561|NC_2\t1\t60\t4M\t*\t0\t0\tACGT\tIIII
+r3\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tIIII
+r4\t65\tkraken:taxid|9606|NC_3\t1\t60\t4M\t=\t1\t0\tACGT\tIIII
+r4\t129\tkraken:taxid|9606|NC_3\t1\t60\t4M\t=\t1\t0\tACGT\tIIII
+r5\t0\tkraken:taxid|562|NC_1\t1\t60\t4M\t*\t0\t0\tACGT\tIIII
+";
+        let taxids = HashMap::from_iter([
+            (b"r1".as_slice(), b"562".as_slice()),
+            (b"r2", b"562"),
+            (b"r3", b"562"),
+            (b"r4", b"9606"),
+        ]);
+        let counts = summarize_alignments(sam.as_bytes(), &taxids)?;
+        assert_eq!(
+            counts.into_iter().collect::<Vec<_>>(),
+            vec![(b"562".to_vec(), (3, 2, 1)), (b"9606".to_vec(), (1, 1, 1))]
+        );
+        assert!(summarize_alignments("r1\t0\n".as_bytes(), &taxids).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,64 @@
+use anyhow::{anyhow, Result};
+
+/// The ambient profile of barcodes with at most `empty_max` UMIs (or reads),
+/// taken as empty droplets: the fraction of their pooled counts in each taxon,
+/// as SoupX estimates the soup. Barcodes without any count are left out, they
+/// hold no information on the ambient pool. Returns the profile and whether
+/// each barcode is empty.
+pub(super) fn ambient_profile(
+    barcodes: &[Vec<usize>],
+    empty_max: usize,
+) -> Result<(Vec<f64>, Vec<bool>)> {
+    let ntaxa = barcodes
+        .iter()
+        .map(|counts| counts.len())
+        .max()
+        .unwrap_or(0);
+    let mut pooled = vec![0; ntaxa];
+    let mut empty = Vec::with_capacity(barcodes.len());
+    for counts in barcodes {
+        let total = counts.iter().sum::<usize>();
+        let is_empty = total > 0 && total <= empty_max;
+        if is_empty {
+            for (n, count) in pooled.iter_mut().zip(counts) {
+                *n += count;
+            }
+        }
+        empty.push(is_empty);
+    }
+    let total = pooled.iter().sum::<usize>();
+    if total == 0 {
+        return Err(anyhow!(
+            "No barcode has between 1 and {} counts to estimate the ambient profile",
+            empty_max
+        ));
+    }
+    let profile = pooled
+        .into_iter()
+        .map(|n| n as f64 / total as f64)
+        .collect();
+    Ok((profile, empty))
+}
+
+/// Counts of each taxon expected from the ambient pool in a barcode of
+/// `counts`, if a fraction `contamination` of them comes from it
+pub(super) fn expected_ambient(counts: &[usize], profile: &[f64], contamination: f64) -> Vec<f64> {
+    let ambient = counts.iter().sum::<usize>() as f64 * contamination;
+    profile.iter().map(|p| ambient * p).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ambient_profile() -> Result<()> {
+        let barcodes = vec![vec![3, 1], vec![0, 0], vec![100, 300], vec![0, 4]];
+        let (profile, empty) = ambient_profile(&barcodes, 5)?;
+        assert_eq!(empty, [true, false, false, true]);
+        assert_eq!(profile, [3.0 / 8.0, 5.0 / 8.0]);
+        assert_eq!(expected_ambient(&barcodes[2], &profile, 0.1), [15.0, 25.0]);
+        assert!(ambient_profile(&barcodes[1 .. 3], 5).is_err());
+        Ok(())
+    }
+}
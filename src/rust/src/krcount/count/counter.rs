@@ -1,5 +1,6 @@
 use std::hash::Hash;
 
+use rustc_hash::FxHashMap as HashMap;
 use rustc_hash::FxHashSet as HashSet;
 
 /// Generic trait for counting (either total or unique)
@@ -48,6 +49,10 @@ impl<T> CountUnique<T> {
             n: 0,
         }
     }
+
+    pub(super) fn iter(&self) -> impl Iterator<Item = &T> {
+        self.set.iter()
+    }
 }
 
 impl<T: Eq + Hash> Countable for CountUnique<T> {
@@ -63,3 +68,32 @@ impl<T: Eq + Hash> Countable for CountUnique<T> {
         self.n
     }
 }
+
+/// Counts the occurrences of each item, counting distinct items
+pub(super) struct CountMultiset<T> {
+    map: HashMap<T, usize>,
+}
+
+impl<T> CountMultiset<T> {
+    pub(super) fn new() -> Self {
+        Self {
+            map: HashMap::with_capacity_and_hasher(0, rustc_hash::FxBuildHasher),
+        }
+    }
+
+    pub(super) fn iter(&self) -> impl Iterator<Item = (&T, &usize)> {
+        self.map.iter()
+    }
+}
+
+impl<T: Eq + Hash> Countable for CountMultiset<T> {
+    type Item = T;
+
+    fn insert(&mut self, item: T) {
+        *self.map.entry(item).or_insert(0) += 1;
+    }
+
+    fn count(&self) -> usize {
+        self.map.len()
+    }
+}
@@ -3,7 +3,6 @@ use std::path::Path;
 use anyhow::{anyhow, Context, Result};
 use bytes::{Bytes, BytesMut};
 use crossbeam_channel::{Receiver, Sender};
-use indicatif::{ProgressBar, ProgressFinish};
 use memchr::memchr;
 use memchr::memmem::Finder;
 use rustc_hash::FxHashMap as HashMap;
@@ -11,26 +10,16 @@ use rustc_hash::FxHashSet as HashSet;
 
 mod counter;
 
-use counter::{CountTotal, CountUnique, Countable};
+use counter::{CountMultiset, CountTotal, CountUnique, Countable};
 
 use crate::batchsender::BatchSender;
+use crate::error::ErrorKind;
+use crate::kreport::Kreport;
 use crate::reader::LineReader;
+use crate::record_filter::pass_complexity_filter;
+use crate::table_writer::TableWriter;
 use crate::utils::*;
-
-/// Return `true` if all base counts are ≤ `threshold`, otherwise `false`.
-fn pass_complexity_filter(seq: &[u8], threshold: usize) -> bool {
-    // remove low complexity reads (<20 non-sequentially repeated nucleotides)
-    let threshold = seq.len() - threshold;
-    let mut counts = HashMap::with_capacity_and_hasher(4, rustc_hash::FxBuildHasher); // ATGC
-    for &b in seq {
-        let count = counts.entry(b).or_insert(0);
-        *count += 1;
-        if *count > threshold {
-            return false; // Early exit: too many repeats
-        }
-    }
-    true
-}
+use crate::warnings::UNKNOWN_TAXID_RECORDS;
 
 /// Returns `true` if all quality scores are ≥ `min_phred`.
 fn pass_quality_filter(qual: &[u8], threshold: u8) -> bool {
@@ -40,21 +29,25 @@ fn pass_quality_filter(qual: &[u8], threshold: u8) -> bool {
 }
 
 /// ReadsAndKmer holds per-(barcode, taxon) statistics:
-/// number of reads, total k-mers, and unique k-mers.
+/// number of reads, the reads of each UMI, total k-mers, and unique k-mers.
+/// The occurrences of each k-mer are kept as well if a k-mer profile was
+/// requested.
 pub(super) struct ReadsAndKmer {
     reads: CountTotal,
-    umi: CountUnique<Bytes>,
+    umi: CountMultiset<Bytes>,
     kmer_total: CountTotal,
     kmer_unique: CountUnique<Bytes>,
+    kmer_profile: Option<CountMultiset<Bytes>>,
 }
 
 impl ReadsAndKmer {
-    fn new() -> Self {
+    fn new(kmer_profile: bool) -> Self {
         Self {
             reads: CountTotal::new(),
-            umi: CountUnique::new(),
+            umi: CountMultiset::new(),
             kmer_total: CountTotal::new(),
             kmer_unique: CountUnique::new(),
+            kmer_profile: kmer_profile.then(CountMultiset::new),
         }
     }
 
@@ -62,9 +55,10 @@ impl ReadsAndKmer {
     fn with_capacity(capacity: usize) -> Self {
         Self {
             reads: CountTotal::new(),
-            umi: CountUnique::with_capacity(capacity),
+            umi: CountMultiset::new(),
             kmer_total: CountTotal::new(),
             kmer_unique: CountUnique::with_capacity(capacity),
+            kmer_profile: None,
         }
     }
 
@@ -103,24 +97,32 @@ impl ReadsAndKmer {
         for kmer in kmers {
             self.kmer_total.insert(());
             self.kmer_unique.insert(kmer.clone());
+            if let Some(profile) = &mut self.kmer_profile {
+                profile.insert(kmer.clone());
+            }
         }
     }
 }
 
 /// Parses a Koutreads-format file and counts reads and k-mers per (barcode, taxon).
 /// Each taxon aggregates k-mers from its descendant taxa. Optionally groups reads
-/// by barcode and/or UMI if tags are provided.
+/// by barcode and/or UMI if tags are provided. With `kmer_profile`, the
+/// occurrences of each k-mer are kept for [`write_kmer_profiles()`]. With
+/// `provenance`, each counted read is written to a table, see
+/// [`ProvenanceWriter`].
 pub(super) fn count_kmers_and_reads<'taxid, P: AsRef<Path> + ?Sized>(
     koutreads: &P,
     ancestor_map: HashMap<&[u8], HashSet<&'taxid [u8]>>,
     umi_tag: Option<&str>,
     barcode_tag: Option<&str>,
+    kmer_profile: bool,
+    provenance: Option<&Path>,
     batch_size: usize,
     nqueue: Option<usize>,
 ) -> Result<HashMap<Bytes, HashMap<&'taxid [u8], ReadsAndKmer>>> {
     let input: &Path = koutreads.as_ref();
     let style = progress_reader_style()?;
-    let pb = ProgressBar::new(input.metadata()?.len() as u64).with_finish(ProgressFinish::Abandon);
+    let pb = input_progress_bar(input)?;
     pb.set_prefix("Parsing Koutreads");
     pb.set_style(style);
 
@@ -142,13 +144,24 @@ pub(super) fn count_kmers_and_reads<'taxid, P: AsRef<Path> + ?Sized>(
                         HashMap::with_capacity_and_hasher(1, rustc_hash::FxBuildHasher);
                     let umi_finder = umi_tag.as_ref().map(|tag| Finder::new(tag));
                     let barcode_finder = barcode_tag.as_ref().map(|tag| Finder::new(tag));
+                    let mut unknown_taxid = 0;
+                    let mut provenance = provenance
+                        .map(|path| {
+                            ProvenanceWriter::new(path, umi_tag.is_some(), barcode_tag.is_some())
+                        })
+                        .transpose()?;
+                    // 1-based index of the line among the non-blank lines
+                    let mut read_id: usize = 0;
 
                     while let Ok(lines) = reader_rx.recv() {
                         for line in lines {
+                            read_id += 1;
                             let line = line.freeze();
                             let fields: Vec<&[u8]> = line.split(|b| *b == b'\t').collect();
                             if fields.len() != 5 {
-                                return Err(anyhow!("Invalid file: must have 5 fields"));
+                                return Err(
+                                    ErrorKind::Parse.error("Invalid file: must have 5 fields")
+                                );
                             }
 
                             // ─── Extract and validate fields ───────────────
@@ -182,6 +195,17 @@ pub(super) fn count_kmers_and_reads<'taxid, P: AsRef<Path> + ?Sized>(
                                         )
                                     },
                                 )?;
+                                let lca = unsafe { fields.get_unchecked(2) };
+                                if let Some(provenance) = provenance.as_mut() {
+                                    provenance.write_read(
+                                        read_id,
+                                        barcode,
+                                        umi,
+                                        taxid,
+                                        clade_confidence(taxid, lca, &ancestor_map),
+                                        seq,
+                                    )?;
+                                }
 
                                 let barcode = barcode
                                     .map(Bytes::copy_from_slice)
@@ -203,7 +227,6 @@ pub(super) fn count_kmers_and_reads<'taxid, P: AsRef<Path> + ?Sized>(
                                 // the next 31 k-mers contained an ambiguous nucleotide
                                 // the next k-mer was not in the database
                                 // the last 3 k-mers mapped to taxonomy ID #562
-                                let lca = unsafe { fields.get_unchecked(2) };
                                 let kmers =
                                     match (LCA_SEPARATOR_FINDER.find(lca), memchr(b' ', seq)) {
                                         (Some(lca_pos), Some(seq_pos)) => {
@@ -230,13 +253,19 @@ pub(super) fn count_kmers_and_reads<'taxid, P: AsRef<Path> + ?Sized>(
                                 for ancestor in ancestors {
                                     let entry = barcode_map
                                         .entry(*ancestor)
-                                        .or_insert_with(|| ReadsAndKmer::new());
+                                        .or_insert_with(|| ReadsAndKmer::new(kmer_profile));
                                     entry.add_read(umi);
                                     entry.add_kmers(&kmers);
                                 }
+                            } else {
+                                unknown_taxid += 1;
                             }
                         }
                     }
+                    UNKNOWN_TAXID_RECORDS.add(unknown_taxid);
+                    if let Some(provenance) = provenance {
+                        provenance.finish()?;
+                    }
                     Ok(barcode_taxon_map)
                 },
             );
@@ -279,11 +308,407 @@ pub(super) fn count_kmers_and_reads<'taxid, P: AsRef<Path> + ?Sized>(
     )
 }
 
+/// Writes the reads counted by [`count_kmers_and_reads()`], one row each, so
+/// the reads backing a taxon can be audited: the read index (1-based line
+/// number among the non-blank lines of the Koutreads file, as in the `reads`
+/// table of the database), barcode and UMI if their tag is given, taxid,
+/// confidence of the assignment (`NA` without informative k-mers) and length
+/// in bases, summed over mates. The table is comma-separated if its path
+/// ends with `.csv`, and compressed by its extension.
+pub(super) struct ProvenanceWriter<'a> {
+    path: &'a Path,
+    writer: TableWriter<'a>,
+    umi: bool,
+    barcode: bool,
+}
+
+impl<'a> ProvenanceWriter<'a> {
+    pub(super) fn new(path: &'a Path, umi: bool, barcode: bool) -> Result<Self> {
+        let mut out = Self {
+            path,
+            writer: TableWriter::new(path, Compression::new(path, None)?, BLOCK_SIZE),
+            umi,
+            barcode,
+        };
+        let mut header = vec!["read_id"];
+        header.extend(barcode.then_some("barcode"));
+        header.extend(umi.then_some("umi"));
+        header.extend(["taxid", "confidence", "length"]);
+        for column in header {
+            out.writer.field(column.as_bytes());
+        }
+        out.end_row()?;
+        Ok(out)
+    }
+
+    pub(super) fn write_read(
+        &mut self,
+        read_id: usize,
+        barcode: Option<&[u8]>,
+        umi: Option<&[u8]>,
+        taxid: &[u8],
+        confidence: Option<f64>,
+        seq: &[u8],
+    ) -> Result<()> {
+        self.writer.number(read_id);
+        if self.barcode {
+            self.writer.field(barcode.unwrap_or_default());
+        }
+        if self.umi {
+            self.writer.field(umi.unwrap_or_default());
+        }
+        self.writer.field(taxid);
+        match confidence {
+            Some(confidence) => self.writer.number(confidence),
+            None => self.writer.field(b"NA"),
+        }
+        // Mates are separated by spaces
+        self.writer
+            .number(seq.iter().filter(|b| **b != b' ').count());
+        self.end_row()
+    }
+
+    fn end_row(&mut self) -> Result<()> {
+        let path = self.path;
+        self.writer
+            .end_row()
+            .with_context(|| format!("(Parser) Failed to write provenance to {}", path.display()))
+    }
+
+    pub(super) fn finish(self) -> Result<()> {
+        let path = self.path;
+        self.writer
+            .finish()
+            .with_context(|| format!("(Parser) Failed to write provenance to {}", path.display()))
+    }
+}
+
+/// Write the k-mer multiset of each taxon, merged across barcodes, as a
+/// table of taxid, k-mer and number of occurrences. Taxa follow the order of
+/// `kreports` and k-mers are sorted within each taxon. The table is
+/// comma-separated if `file` ends with `.csv`, and compressed by its
+/// extension.
+pub(super) fn write_kmer_profiles<P: AsRef<Path> + ?Sized>(
+    file: &P,
+    kreports: &[Kreport],
+    counts_map: &HashMap<Bytes, HashMap<&[u8], ReadsAndKmer>>,
+) -> Result<()> {
+    let path: &Path = file.as_ref();
+    let mut writer = TableWriter::new(path, Compression::new(path, None)?, BLOCK_SIZE);
+    write_kmer_rows(&mut writer, kreports, counts_map)
+        .and_then(|_| writer.finish())
+        .with_context(|| format!("Failed to write k-mer profiles to {}", path.display()))
+}
+
+fn write_kmer_rows(
+    writer: &mut TableWriter,
+    kreports: &[Kreport],
+    counts_map: &HashMap<Bytes, HashMap<&[u8], ReadsAndKmer>>,
+) -> Result<()> {
+    let mut profile: HashMap<&Bytes, usize> =
+        HashMap::with_capacity_and_hasher(0, rustc_hash::FxBuildHasher);
+    for report in kreports {
+        profile.clear();
+        for barcode_map in counts_map.values() {
+            if let Some(kmer_profile) = barcode_map
+                .get(report.taxid.as_slice())
+                .and_then(|reads_and_kmer| reads_and_kmer.kmer_profile.as_ref())
+            {
+                for (kmer, count) in kmer_profile.iter() {
+                    *profile.entry(kmer).or_insert(0) += count;
+                }
+            }
+        }
+        let mut kmers = profile.iter().collect::<Vec<_>>();
+        kmers.sort_unstable();
+        for (kmer, count) in kmers {
+            writer.field(&report.taxid);
+            writer.field(kmer);
+            writer.number(count);
+            writer.end_row()?;
+        }
+    }
+    Ok(())
+}
+
+/// Number of reads, total and distinct k-mers of each taxon, merged across
+/// barcodes, in the order of `kreports`. Reads of a taxon truly present cover
+/// its genome and share few k-mers, while contamination or false positives
+/// tend to hit the same few k-mers over and over, so the ratio of total to
+/// distinct k-mers discriminates the two.
+pub(super) fn taxon_kmer_qc(
+    kreports: &[Kreport],
+    counts_map: &HashMap<Bytes, HashMap<&[u8], ReadsAndKmer>>,
+) -> Vec<(usize, usize, usize)> {
+    let mut kmers: HashSet<&Bytes> =
+        HashSet::with_capacity_and_hasher(0, rustc_hash::FxBuildHasher);
+    kreports
+        .iter()
+        .map(|report| {
+            kmers.clear();
+            let mut reads = 0;
+            let mut kmer_total = 0;
+            for reads_and_kmer in counts_map
+                .values()
+                .filter_map(|barcode_map| barcode_map.get(report.taxid.as_slice()))
+            {
+                reads += reads_and_kmer.reads();
+                kmer_total += reads_and_kmer.kmer_total();
+                kmers.extend(reads_and_kmer.kmer_unique.iter());
+            }
+            (reads, kmer_total, kmers.len())
+        })
+        .collect()
+}
+
+/// Number of UMIs of each taxon, distinct within each barcode and summed
+/// across barcodes, in the order of `kreports`
+pub(super) fn taxon_umis(
+    kreports: &[Kreport],
+    counts_map: &HashMap<Bytes, HashMap<&[u8], ReadsAndKmer>>,
+) -> Vec<usize> {
+    kreports
+        .iter()
+        .map(|report| {
+            counts_map
+                .values()
+                .filter_map(|barcode_map| barcode_map.get(report.taxid.as_slice()))
+                .map(|reads_and_kmer| reads_and_kmer.umi())
+                .sum()
+        })
+        .collect()
+}
+
+/// Whether each taxon of `kreports` has no ancestor among `kreports`. Reads
+/// count toward each of their ancestors: the root taxa hold every read once.
+fn root_taxa(kreports: &[Kreport]) -> Vec<bool> {
+    let taxids = kreports
+        .iter()
+        .map(|report| report.taxid.as_slice())
+        .collect::<HashSet<_>>();
+    kreports
+        .iter()
+        .map(|report| {
+            report
+                .taxids
+                .iter()
+                .all(|x| x == &report.taxid || !taxids.contains(x.as_slice()))
+        })
+        .collect()
+}
+
+/// The molecules of all barcodes, laid out like the `molecule_info.h5` of
+/// Cell Ranger: a molecule is a UMI of a barcode assigned to a feature (the
+/// taxa of `kreports`, by index), with the number of its reads.
+#[derive(Default)]
+pub(super) struct Molecules {
+    pub(super) barcodes: Vec<Bytes>,
+    pub(super) barcode_idx: Vec<usize>,
+    pub(super) umi: Vec<u32>,
+    pub(super) feature_idx: Vec<usize>,
+    pub(super) count: Vec<usize>,
+    // Reads of the molecules, each counted once across taxa
+    pub(super) reads: usize,
+    // Molecules dropped as their UMI can't be encoded
+    pub(super) invalid_umi: usize,
+}
+
+/// Collect the molecules of each (barcode, taxon) kept by
+/// [`count_kmers_and_reads()`], sorted by barcode, feature and UMI
+pub(super) fn molecule_table(
+    kreports: &[Kreport],
+    counts_map: &HashMap<Bytes, HashMap<&[u8], ReadsAndKmer>>,
+) -> Molecules {
+    let is_root = root_taxa(kreports);
+    let mut out = Molecules::default();
+    let mut barcodes = counts_map.keys().collect::<Vec<_>>();
+    barcodes.sort_unstable();
+    let mut umis = Vec::new();
+    for (barcode_idx, barcode) in barcodes.into_iter().enumerate() {
+        out.barcodes.push(barcode.clone());
+        let barcode_map = &counts_map[barcode];
+        for (feature_idx, report) in kreports.iter().enumerate() {
+            let Some(reads_and_kmer) = barcode_map.get(report.taxid.as_slice()) else {
+                continue;
+            };
+            umis.clear();
+            for (umi, count) in reads_and_kmer.umi.iter() {
+                match encode_umi(umi) {
+                    Some(umi) => umis.push((umi, *count)),
+                    None => out.invalid_umi += 1,
+                }
+            }
+            umis.sort_unstable();
+            for &(umi, count) in &umis {
+                out.barcode_idx.push(barcode_idx);
+                out.umi.push(umi);
+                out.feature_idx.push(feature_idx);
+                out.count.push(count);
+                if is_root[feature_idx] {
+                    out.reads += count;
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Encode a UMI in 2 bits per base, the first base in the highest bits, as
+/// Cell Ranger does. UMIs longer than 16 bases, or with bases other than
+/// `ACGT`, can't be encoded.
+fn encode_umi(umi: &[u8]) -> Option<u32> {
+    if umi.len() > 16 {
+        return None;
+    }
+    umi.iter().try_fold(0u32, |code, base| {
+        let bits = match base {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' => 3,
+            _ => return None,
+        };
+        Some((code << 2) | bits)
+    })
+}
+
+/// Barcodes with their number of molecules over all taxa (reads without
+/// `umi`), in decreasing order, ties by barcode: the ranks of a knee plot.
+pub(super) fn barcode_ranks<'a>(
+    kreports: &[Kreport],
+    counts_map: &'a HashMap<Bytes, HashMap<&[u8], ReadsAndKmer>>,
+    umi: bool,
+) -> Vec<(&'a Bytes, usize)> {
+    let is_root = root_taxa(kreports);
+    let mut ranks = counts_map
+        .iter()
+        .map(|(barcode, barcode_map)| {
+            let total: usize = kreports
+                .iter()
+                .zip(&is_root)
+                .filter(|(_, is_root)| **is_root)
+                .filter_map(|(report, _)| barcode_map.get(report.taxid.as_slice()))
+                .map(|x| if umi { x.umi() } else { x.reads() })
+                .sum();
+            (barcode, total)
+        })
+        .collect::<Vec<_>>();
+    ranks.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    ranks
+}
+
+/// Fractions of the reads at which the saturation curves are computed
+pub(super) const SATURATION_FRACTIONS: [f64; 10] =
+    [0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0];
+
+/// Sequencing saturation, as Cell Ranger computes it: the fraction of reads
+/// duplicating a molecule already sequenced, `NaN` without reads. Close to
+/// 1, sequencing deeper would mostly find the same molecules again.
+pub(super) fn saturation(reads: f64, umis: f64) -> f64 {
+    if reads == 0.0 {
+        f64::NAN
+    } else {
+        1.0 - umis / reads
+    }
+}
+
+/// Reads and molecules of a barcode, summed over the root taxa, and the
+/// molecules expected in each of [`SATURATION_FRACTIONS`] of its reads
+pub(super) struct SampleSaturation {
+    pub(super) barcode: Bytes,
+    pub(super) reads: usize,
+    pub(super) umis: usize,
+    pub(super) curve: Vec<f64>,
+}
+
+/// Saturation of each barcode, sorted by barcode. Drawing a fraction `p` of
+/// the reads at random, a molecule of `c` reads is missed with probability
+/// `(1 - p)^c`, which gives the expected molecules without resampling.
+pub(super) fn sample_saturation(
+    kreports: &[Kreport],
+    counts_map: &HashMap<Bytes, HashMap<&[u8], ReadsAndKmer>>,
+) -> Vec<SampleSaturation> {
+    let is_root = root_taxa(kreports);
+    let mut barcodes = counts_map.keys().collect::<Vec<_>>();
+    barcodes.sort_unstable();
+    // Molecules by their number of reads
+    let mut histogram: HashMap<usize, usize> =
+        HashMap::with_capacity_and_hasher(0, rustc_hash::FxBuildHasher);
+    barcodes
+        .into_iter()
+        .map(|barcode| {
+            histogram.clear();
+            let barcode_map = &counts_map[barcode];
+            for reads_and_kmer in kreports
+                .iter()
+                .zip(&is_root)
+                .filter(|(_, is_root)| **is_root)
+                .filter_map(|(report, _)| barcode_map.get(report.taxid.as_slice()))
+            {
+                for (_, count) in reads_and_kmer.umi.iter() {
+                    *histogram.entry(*count).or_insert(0) += 1;
+                }
+            }
+            SampleSaturation {
+                barcode: barcode.clone(),
+                reads: histogram.iter().map(|(c, n)| c * n).sum(),
+                umis: histogram.values().sum(),
+                curve: SATURATION_FRACTIONS
+                    .iter()
+                    .map(|p| {
+                        histogram
+                            .iter()
+                            .map(|(c, n)| *n as f64 * (1.0 - (1.0 - p).powi(*c as i32)))
+                            .sum()
+                    })
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+/// Fraction of the non-ambiguous k-mers which map into the clade rooted at
+/// `taxid`, as Kraken2 computes its confidence score. Returns `None` if the
+/// read has no informative k-mers.
+pub(super) fn clade_confidence(
+    taxid: &[u8],
+    lca: &[u8],
+    ancestor_map: &HashMap<&[u8], HashSet<&[u8]>>,
+) -> Option<f64> {
+    let mut total = 0;
+    let mut clade = 0;
+    for pair in lca.trim_ascii().split(|b| *b == b' ') {
+        let Some(pos) = memchr(b':', pair) else {
+            continue;
+        };
+        let kmer_taxid = &pair[.. pos];
+        // "A" marks ambiguous k-mers, "|" the paired-end separator "|:|"
+        if kmer_taxid == b"A" || kmer_taxid == b"|" {
+            continue;
+        }
+        let Ok(n) = parse_usize(&pair[pos + 1 ..]) else {
+            continue;
+        };
+        total += n;
+        if ancestor_map
+            .get(kmer_taxid)
+            .is_some_and(|ancestors| ancestors.contains(taxid))
+        {
+            clade += n;
+        }
+    }
+    if total == 0 {
+        None
+    } else {
+        Some(clade as f64 / total as f64)
+    }
+}
+
 const LCA_SEPARATOR: &'static [u8] = b"|:|";
 static LCA_SEPARATOR_FINDER: std::sync::LazyLock<Finder> =
     std::sync::LazyLock::new(|| Finder::new(TAG_PREFIX));
 
-fn extract_tag<'t>(
+pub(super) fn extract_tag<'t>(
     tags: &'t [u8],
     finder: &Option<Finder>,
     label: &Option<&str>,
@@ -321,16 +746,16 @@ fn extract_kmers(lca: &[u8], sequence: &[u8]) -> Result<Vec<Bytes>> {
             if let Some(pos) = memchr(b':', pair) {
                 // SAFETY: checked pos + 1 < pair.len()
                 if pos + 1 >= pair.len() {
-                    return Err(anyhow!(
+                    return Err(ErrorKind::Parse.error(format!(
                         "Invalid lca pair, missing number after ':' in {:?}",
                         lca
-                    ));
+                    )));
                 }
                 let n = std::str::from_utf8(unsafe { pair.get_unchecked(pos + 1 ..) })?
                     .parse::<usize>()?;
                 Ok(n)
             } else {
-                Err(anyhow!("Invalid lca pair, missing ':' in {:?}", lca))
+                Err(ErrorKind::Parse.error(format!("Invalid lca pair, missing ':' in {:?}", lca)))
             }
         })
         .collect::<Result<Vec<usize>>>()?;
@@ -353,3 +778,68 @@ fn extract_kmers(lca: &[u8], sequence: &[u8]) -> Result<Vec<Bytes>> {
 
     Ok(kmers)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_umi() {
+        assert_eq!(encode_umi(b""), Some(0));
+        assert_eq!(encode_umi(b"ACGT"), Some(0b00_01_10_11));
+        assert_eq!(encode_umi(b"TTTTTTTTTTTTTTTT"), Some(u32::MAX));
+        assert_eq!(encode_umi(b"ACNT"), None);
+        assert_eq!(encode_umi(&[b'A'; 17]), None);
+    }
+
+    #[test]
+    fn test_sample_saturation() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let path = temp.path().join("kreport.txt");
+        std::fs::write(
+            &path,
+            "\
+100.00\t3\t0\tG\t561\t  Escherichia
+100.00\t3\t3\tS\t562\t    Escherichia coli
+",
+        )?;
+        let kreports = crate::kreport::parse_kreport(&path)?;
+        let mut counts_map = HashMap::default();
+        for taxid in [&b"561"[..], &b"562"[..]] {
+            let mut reads_and_kmer = ReadsAndKmer::new(false);
+            for umi in [&b"AAAA"[..], b"AAAA", b"CCCC"] {
+                reads_and_kmer.add_read(Some(umi));
+            }
+            counts_map
+                .entry(Bytes::from_static(b"AC"))
+                .or_insert_with(HashMap::default)
+                .insert(taxid, reads_and_kmer);
+        }
+        let samples = sample_saturation(&kreports, &counts_map);
+        // Only the root taxon counts the reads of its descendants
+        assert_eq!((samples[0].reads, samples[0].umis), (3, 2));
+        assert!((saturation(3.0, 2.0) - 1.0 / 3.0).abs() < 1e-12);
+        let ranks = barcode_ranks(&kreports, &counts_map, true);
+        assert_eq!(ranks, [(&Bytes::from_static(b"AC"), 2)]);
+        // All molecules are found with all reads
+        assert!((samples[0].curve[9] - 2.0).abs() < 1e-12);
+        // A molecule of 2 reads is missed with 1% of the draws of 10%
+        assert!((samples[0].curve[0] - (0.19 + 0.1)).abs() < 1e-12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_provenance_writer() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let path = temp.path().join("provenance.csv");
+        let mut writer = ProvenanceWriter::new(&path, false, true)?;
+        writer.write_read(1, Some(b"AAAC"), None, b"562", Some(0.75), b"ACGT")?;
+        writer.write_read(3, None, None, b"561", None, b"ACG  ACGT")?;
+        writer.finish()?;
+        assert_eq!(
+            std::fs::read_to_string(&path)?,
+            "read_id,barcode,taxid,confidence,length\n1,AAAC,562,0.75,4\n3,,561,NA,7\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use bytes::BytesMut;
+use crossbeam_channel::{Receiver, Sender};
+use indicatif::ProgressBar;
+use memchr::memmem::Finder;
+use rusqlite::{params, Connection};
+use rustc_hash::FxHashMap as HashMap;
+use rustc_hash::FxHashSet as HashSet;
+
+use super::count::{clade_confidence, extract_tag};
+use crate::batchsender::BatchSender;
+use crate::error::ErrorKind;
+use crate::reader::LineReader;
+use crate::utils::*;
+
+/// Export one row per read of a Koutreads-format file into a SQLite database.
+///
+/// The `reads` table holds the read index (1-based line number in the
+/// Koutreads file), taxid, barcode, UMI, and the Kraken2-style confidence of
+/// the assignment. Indexes on `taxid` and `barcode` are built once all rows
+/// have been inserted. Returns the number of exported reads.
+pub(super) fn export_reads<P: AsRef<Path> + ?Sized>(
+    koutreads: &P,
+    db: &P,
+    ancestor_map: HashMap<&[u8], HashSet<&[u8]>>,
+    umi_tag: Option<&str>,
+    barcode_tag: Option<&str>,
+    batch_size: usize,
+    nqueue: Option<usize>,
+) -> Result<usize> {
+    let input: &Path = koutreads.as_ref();
+    let db: &Path = db.as_ref();
+    let style = progress_reader_style()?;
+    let pb = input_progress_bar(input)?;
+    pb.set_prefix("Exporting Koutreads");
+    pb.set_style(style);
+
+    // Like `File::create()`, an existing database is replaced
+    if db.exists() {
+        std::fs::remove_file(db)
+            .with_context(|| format!("Failed to remove existing database {}", db.display()))?;
+    }
+    let mut conn = Connection::open(db)
+        .with_context(|| format!("Failed to create database {}", db.display()))?;
+    // The database is written from scratch, durability is not needed until
+    // all rows have been inserted
+    conn.execute_batch(
+        "PRAGMA journal_mode = OFF;
+         PRAGMA synchronous = OFF;
+         CREATE TABLE reads (
+             read_id INTEGER PRIMARY KEY,
+             taxid TEXT NOT NULL,
+             barcode TEXT,
+             umi TEXT,
+             confidence REAL
+         );",
+    )
+    .with_context(|| format!("Failed to initialize database {}", db.display()))?;
+
+    let records = std::thread::scope(|scope| -> Result<usize> {
+        let (reader_tx, reader_rx): (Sender<Vec<BytesMut>>, Receiver<Vec<BytesMut>>) =
+            new_channel(nqueue);
+
+        // ─── reader Thread ─────────────────────────────────────
+        // Reads lines from input file and sends them in batches to writer thread
+        let reader_handle = scope.spawn(move || -> Result<()> {
+            let mut reader =
+                LineReader::with_capacity(BUFFER_SIZE, new_reader(input, BUFFER_SIZE, Some(pb))?);
+            let mut reader_tx: BatchSender<BytesMut> =
+                BatchSender::with_capacity(batch_size, reader_tx);
+            while let Some(line) = reader
+                .read_line()
+                .with_context(|| format!("(Reader) Failed to read line"))?
+            {
+                if line.iter().all(|b| b.is_ascii_whitespace()) {
+                    continue;
+                }
+                reader_tx
+                    .send(line)
+                    .with_context(|| format!("(Reader) Failed to send lines to Writer thread"))?;
+            }
+            reader_tx
+                .flush()
+                .with_context(|| format!("(Reader) Failed to flush lines to Writer thread"))?;
+            Ok(())
+        });
+
+        // ─── Writer Thread ─────────────────────────────────────
+        // SQLite connections are single-writer: insert each batch in the
+        // current thread within its own transaction
+        let umi_finder = umi_tag.as_ref().map(|tag| Finder::new(tag));
+        let barcode_finder = barcode_tag.as_ref().map(|tag| Finder::new(tag));
+        let mut read_id: usize = 0;
+        let result = (|| -> Result<()> {
+            while let Ok(lines) = reader_rx.recv() {
+                let tx = conn
+                    .transaction()
+                    .with_context(|| format!("(Writer) Failed to start transaction"))?;
+                {
+                    let mut insert = tx.prepare_cached(
+                        "INSERT INTO reads (read_id, taxid, barcode, umi, confidence)
+                         VALUES (?1, ?2, ?3, ?4, ?5)",
+                    )?;
+                    for line in lines {
+                        read_id += 1;
+                        let fields: Vec<&[u8]> = line[..].split(|b| *b == b'\t').collect();
+                        if fields.len() != 5 {
+                            return Err(ErrorKind::Parse.error("Invalid file: must have 5 fields"));
+                        }
+                        // taxid + tags + lca + seq + qual
+                        let taxid = fields[0];
+                        let tags = fields[1];
+                        let barcode = extract_tag(tags, &barcode_finder, &barcode_tag)
+                            .with_context(|| {
+                                format!("Failed to extract barcode in line {}", read_id)
+                            })?;
+                        let umi = extract_tag(tags, &umi_finder, &umi_tag).with_context(|| {
+                            format!("Failed to extract umi in line {}", read_id)
+                        })?;
+                        let confidence = clade_confidence(taxid, fields[2], &ancestor_map);
+                        insert
+                            .execute(params![
+                                read_id as i64,
+                                String::from_utf8_lossy(taxid),
+                                barcode.map(String::from_utf8_lossy),
+                                umi.map(String::from_utf8_lossy),
+                                confidence
+                            ])
+                            .with_context(|| {
+                                format!("(Writer) Failed to insert line {}", read_id)
+                            })?;
+                    }
+                }
+                tx.commit()
+                    .with_context(|| format!("(Writer) Failed to commit transaction"))?;
+            }
+            Ok(())
+        })();
+        // Unblock the reader thread if the writer stopped early
+        drop(reader_rx);
+
+        // ─── Join Threads and Propagate Errors ────────────────
+        let reader_result = reader_handle
+            .join()
+            .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))?;
+        // A writer failure also breaks the reader's channel, report the cause
+        result?;
+        reader_result?;
+        Ok(read_id)
+    })?;
+
+    // Building indexes once is much faster than maintaining them on insert
+    conn.execute_batch(
+        "CREATE INDEX reads_taxid ON reads (taxid);
+         CREATE INDEX reads_barcode ON reads (barcode);",
+    )
+    .with_context(|| format!("Failed to create indexes in {}", db.display()))?;
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_reads() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let input = temp.path().join("koutreads.txt");
+        std::fs::write(
+            &input,
+            "\
+562\tCB:AAAC UB:TTTT\t562:2 561:1 A:1 0:1\tACGTACGT\tIIIIIIII
+561\tCB:AAAC UB:GGGG\t561:3\tACGTACGT\tIIIIIIII
+561\tCB:CCCA UB:GGGG\t562:2 |:| 561:2\tACGT  ACGT\tIIII  IIII
+",
+        )?;
+        let db = temp.path().join("reads.sqlite");
+
+        // 562 descends from 561
+        let mut ancestor_map = HashMap::default();
+        ancestor_map.insert(b"561".as_slice(), HashSet::from_iter([b"561".as_slice()]));
+        ancestor_map.insert(
+            b"562".as_slice(),
+            HashSet::from_iter([b"561".as_slice(), b"562".as_slice()]),
+        );
+
+        let n = export_reads(&input, &db, ancestor_map, Some("UB"), Some("CB"), 2, None)?;
+        assert_eq!(n, 3);
+
+        let conn = Connection::open(&db)?;
+        let rows = conn
+            .prepare("SELECT read_id, taxid, barcode, umi, confidence FROM reads ORDER BY read_id")?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, f64>(4)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        assert_eq!(
+            rows,
+            vec![
+                (1, "562".into(), "AAAC".into(), "TTTT".into(), 0.5),
+                (2, "561".into(), "AAAC".into(), "GGGG".into(), 1.0),
+                (3, "561".into(), "CCCA".into(), "GGGG".into(), 1.0),
+            ]
+        );
+        let n_barcode: i64 = conn.query_row(
+            "SELECT count(*) FROM reads WHERE barcode = 'AAAC'",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(n_barcode, 2);
+        Ok(())
+    }
+}
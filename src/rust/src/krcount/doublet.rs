@@ -0,0 +1,114 @@
+/// Fraction of the counts of a barcode its top species must hold for the
+/// barcode to be a pure profile of that species
+pub(super) const PURE_FRACTION: f64 = 0.9;
+
+/// Score from which a barcode is flagged as a potential doublet: its lesser
+/// species holds at least a quarter of its counts
+pub(super) const DOUBLET_SCORE: f64 = 0.5;
+
+/// The two top species of a barcode, by index, and its score as their mixture
+#[derive(Debug, PartialEq)]
+pub(super) struct Doublet {
+    pub(super) first: usize,
+    pub(super) second: usize,
+    pub(super) score: f64,
+}
+
+/// The two species with the most counts, ties by index, and the total
+fn top_two(counts: &[usize]) -> Option<(usize, Option<usize>, usize)> {
+    let total = counts.iter().sum::<usize>();
+    if total == 0 {
+        return None;
+    }
+    let mut first = 0;
+    let mut second: Option<usize> = None;
+    for i in 1 .. counts.len() {
+        if counts[i] > counts[first] {
+            second = Some(first);
+            first = i;
+        } else if second.is_none_or(|j| counts[i] > counts[j]) {
+            second = Some(i);
+        }
+    }
+    Some((first, second.filter(|j| counts[*j] > 0), total))
+}
+
+/// Score each barcode, given the counts of each species in it, as a mixture
+/// of two distinct high-confidence profiles: species dominating at least one
+/// barcode on their own (holding [`PURE_FRACTION`] of its counts). A barcode
+/// whose two top species are both such profiles scores twice the fraction of
+/// the lesser one, from 0 (a single species) to 1 (an even mixture of both);
+/// others score 0. Barcodes without any count are `None`.
+pub(super) fn doublet_scores(barcodes: &[Vec<usize>]) -> Vec<Option<Doublet>> {
+    let tops = barcodes
+        .iter()
+        .map(|counts| top_two(counts))
+        .collect::<Vec<_>>();
+    let nspecies = barcodes
+        .iter()
+        .map(|counts| counts.len())
+        .max()
+        .unwrap_or(0);
+    let mut pure = vec![false; nspecies];
+    for (counts, top) in barcodes.iter().zip(&tops) {
+        if let Some((first, _, total)) = top {
+            if counts[*first] as f64 >= PURE_FRACTION * *total as f64 {
+                pure[*first] = true;
+            }
+        }
+    }
+    barcodes
+        .iter()
+        .zip(tops)
+        .map(|(counts, top)| {
+            let (first, second, total) = top?;
+            let Some(second) = second else {
+                return Some(Doublet {
+                    first,
+                    second: first,
+                    score: 0.0,
+                });
+            };
+            let score = if pure[first] && pure[second] {
+                2.0 * counts[second] as f64 / total as f64
+            } else {
+                0.0
+            };
+            Some(Doublet {
+                first,
+                second,
+                score,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_doublet_scores() {
+        let barcodes = vec![
+            vec![10, 0, 0],
+            vec![0, 9, 1],
+            vec![5, 5, 0],
+            vec![1, 0, 3],
+            vec![0, 0, 0],
+        ];
+        let scores = doublet_scores(&barcodes);
+        assert_eq!(scores[0].as_ref().map(|x| x.score), Some(0.0));
+        // An even mixture of the pure profiles of species 0 and 1
+        assert_eq!(
+            scores[2],
+            Some(Doublet {
+                first: 0,
+                second: 1,
+                score: 1.0
+            })
+        );
+        // Species 2 never dominates a barcode on its own
+        assert_eq!(scores[3].as_ref().map(|x| x.score), Some(0.0));
+        assert_eq!(scores[4], None);
+    }
+}
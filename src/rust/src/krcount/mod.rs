@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use anyhow::anyhow;
 use anyhow::Result;
 use bytes::Bytes;
@@ -5,9 +7,18 @@ use extendr_api::prelude::*;
 use rustc_hash::FxHashMap as HashMap;
 use rustc_hash::FxHashSet as HashSet;
 
+mod ambient;
 mod count;
+#[cfg(feature = "sqlite")]
+mod db;
+mod doublet;
+mod rarefy;
+#[cfg(feature = "zarr")]
+mod zarr;
 
-use crate::kreport::taxonomy_kreport;
+use crate::count_matrix::dgc_slots;
+use crate::error::RError;
+use crate::kreport::{taxonomy_kreport, Kreport};
 use crate::utils::*;
 
 #[extendr]
@@ -17,19 +28,241 @@ fn krcount(
     umi_tag: Option<&str>,
     barcode_tag: Option<&str>,
     taxonomy: Robj,
+    kmer_profile: Option<&str>,
+    molecules: bool,
+    provenance: Option<&str>,
+    sparse: bool,
     batch_size: usize,
     nqueue: Option<usize>,
-) -> std::result::Result<List, String> {
+) -> std::result::Result<List, RError> {
     krcount_internal(
         koutreads,
         kreport,
         umi_tag,
         barcode_tag,
         taxonomy,
+        kmer_profile,
+        molecules,
+        provenance,
+        sparse,
+        batch_size,
+        nqueue,
+    )
+    .map_err(RError::from)
+}
+
+/// Export a read-level table of a Koutreads-format file into a SQLite database
+#[extendr]
+fn krcount_db(
+    koutreads: &str,
+    kreport: &str,
+    db: &str,
+    umi_tag: Option<&str>,
+    barcode_tag: Option<&str>,
+    taxonomy: Robj,
+    batch_size: usize,
+    nqueue: Option<usize>,
+) -> std::result::Result<f64, RError> {
+    krcount_db_internal(
+        koutreads,
+        kreport,
+        db,
+        umi_tag,
+        barcode_tag,
+        taxonomy,
+        batch_size,
+        nqueue,
+    )
+    .map(|records| records as f64)
+    .map_err(RError::from)
+}
+
+/// Export a read-level table of a Koutreads-format file into a Zarr store
+/// written by `write_count_zarr()`
+#[extendr]
+fn krcount_zarr_reads(
+    koutreads: &str,
+    kreport: &str,
+    store: &str,
+    umi_tag: Option<&str>,
+    barcode_tag: Option<&str>,
+    taxonomy: Robj,
+    batch_size: usize,
+    nqueue: Option<usize>,
+) -> std::result::Result<f64, RError> {
+    krcount_zarr_reads_internal(
+        koutreads,
+        kreport,
+        store,
+        umi_tag,
+        barcode_tag,
+        taxonomy,
+        batch_size,
+        nqueue,
+    )
+    .map(|records| records as f64)
+    .map_err(RError::from)
+}
+
+/// Rarefy the `counts` of [`krcount()`] to a common depth per barcode
+#[extendr]
+fn krcount_rarefy(
+    counts: List,
+    depth: Option<usize>,
+    seed: usize,
+) -> std::result::Result<List, RError> {
+    krcount_rarefy_internal(counts, depth, seed).map_err(RError::from)
+}
+
+fn krcount_rarefy_internal(counts: List, depth: Option<usize>, seed: usize) -> Result<List> {
+    let barcodes = list_to_counts(&counts)?;
+    let depth = match depth {
+        Some(depth) => depth,
+        None => rarefy::min_depth(&barcodes)?,
+    };
+    let rarefied = rarefy::rarefy_barcodes(&barcodes, depth, seed as u64);
+    let (names, values): (Vec<_>, Vec<_>) = counts
+        .names()
+        .into_iter()
+        .flatten()
+        .zip(rarefied)
+        .filter_map(|(barcode, counts)| {
+            let counts = counts?
+                .into_iter()
+                .map(|n| if n == 0 { None } else { Some(n) })
+                .collect::<Vec<_>>();
+            Some((barcode, counts))
+        })
+        .unzip();
+    List::from_names_and_values(names, values)
+        .map_err(|e| anyhow!("Failed to create list for rarefied counts: {}", e))
+}
+
+/// Estimate the ambient profile of the `counts` of [`krcount()`] and the
+/// counts it contributes to each cell
+#[extendr]
+fn krcount_ambient(
+    counts: List,
+    empty_max: usize,
+    contamination: f64,
+) -> std::result::Result<List, RError> {
+    krcount_ambient_internal(counts, empty_max, contamination).map_err(RError::from)
+}
+
+fn krcount_ambient_internal(counts: List, empty_max: usize, contamination: f64) -> Result<List> {
+    let barcodes = list_to_counts(&counts)?;
+    let (profile, empty) = ambient::ambient_profile(&barcodes, empty_max)?;
+    let names = counts.names().into_iter().flatten().collect::<Vec<_>>();
+    // Barcodes above the empty droplets are the cells
+    let (cells, ambient): (Vec<_>, Vec<_>) = names
+        .iter()
+        .zip(&barcodes)
+        .zip(&empty)
+        .filter(|((_, counts), empty)| !**empty && counts.iter().any(|n| *n > 0))
+        .map(|((barcode, counts), _)| {
+            let expected = ambient::expected_ambient(counts, &profile, contamination)
+                .into_iter()
+                .zip(&profile)
+                .map(|(x, p)| {
+                    if *p == 0.0 {
+                        Rfloat::na()
+                    } else {
+                        Rfloat::from(x)
+                    }
+                })
+                .collect::<Doubles>();
+            (*barcode, expected)
+        })
+        .unzip();
+    Ok(list!(
+        profile = profile,
+        empty = names
+            .iter()
+            .zip(&empty)
+            .filter(|(_, empty)| **empty)
+            .map(|(barcode, _)| *barcode)
+            .collect::<Vec<_>>(),
+        ambient = List::from_names_and_values(cells, ambient)
+            .map_err(|e| anyhow!("Failed to create list for ambient counts: {}", e))?
+    ))
+}
+
+#[cfg(feature = "sqlite")]
+fn krcount_db_internal(
+    koutreads: &str,
+    kreport: &str,
+    db: &str,
+    umi_tag: Option<&str>,
+    barcode_tag: Option<&str>,
+    taxonomy: Robj,
+    batch_size: usize,
+    nqueue: Option<usize>,
+) -> Result<usize> {
+    let kreports = taxonomy_kreport(kreport, taxonomy)?;
+    db::export_reads(
+        koutreads,
+        db,
+        taxid_ancestors(&kreports),
+        umi_tag,
+        barcode_tag,
         batch_size,
         nqueue,
     )
-    .map_err(|e| format!("{}", e))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn krcount_db_internal(
+    _koutreads: &str,
+    _kreport: &str,
+    _db: &str,
+    _umi_tag: Option<&str>,
+    _barcode_tag: Option<&str>,
+    _taxonomy: Robj,
+    _batch_size: usize,
+    _nqueue: Option<usize>,
+) -> Result<usize> {
+    Err(anyhow!(
+        "SQLite export is not available: mire was built without the 'sqlite' feature"
+    ))
+}
+
+#[cfg(feature = "zarr")]
+fn krcount_zarr_reads_internal(
+    koutreads: &str,
+    kreport: &str,
+    store: &str,
+    umi_tag: Option<&str>,
+    barcode_tag: Option<&str>,
+    taxonomy: Robj,
+    batch_size: usize,
+    nqueue: Option<usize>,
+) -> Result<usize> {
+    let kreports = taxonomy_kreport(kreport, taxonomy)?;
+    zarr::export_reads(
+        koutreads,
+        store,
+        taxid_ancestors(&kreports),
+        umi_tag,
+        barcode_tag,
+        batch_size,
+        nqueue,
+    )
+}
+
+#[cfg(not(feature = "zarr"))]
+fn krcount_zarr_reads_internal(
+    _koutreads: &str,
+    _kreport: &str,
+    _store: &str,
+    _umi_tag: Option<&str>,
+    _barcode_tag: Option<&str>,
+    _taxonomy: Robj,
+    _batch_size: usize,
+    _nqueue: Option<usize>,
+) -> Result<usize> {
+    Err(anyhow!(
+        "Zarr export is not available: mire was built without the 'zarr' feature"
+    ))
 }
 
 fn krcount_internal(
@@ -38,26 +271,17 @@ fn krcount_internal(
     umi_tag: Option<&str>,
     barcode_tag: Option<&str>,
     taxonomy: Robj,
+    kmer_profile: Option<&str>,
+    molecules: bool,
+    provenance: Option<&str>,
+    sparse: bool,
     batch_size: usize,
     nqueue: Option<usize>,
 ) -> Result<List> {
     let kreports = taxonomy_kreport(kreport, taxonomy)?;
 
     // ─── Build taxonomic ancestry map ───────────────────
-    // Each taxid maps to a set of its ancestor taxids (inclusive)
-    let taxid_to_ancestors = kreports
-        .iter()
-        .map(|report| {
-            // Each report's `taxids` field holds the lineage (ancestors) of this taxon
-            let ancestors = report
-                .taxids
-                .iter()
-                .map(|x| x.as_slice())
-                .collect::<HashSet<&[u8]>>();
-            // Map: current taxid → set of its ancestors
-            (report.taxid.as_slice(), ancestors)
-        })
-        .collect::<HashMap<&[u8], HashSet<&[u8]>>>();
+    let taxid_to_ancestors = taxid_ancestors(&kreports);
 
     // ─── Count reads and kmers per (barcode, taxon) ─────
     let counts_map = count::count_kmers_and_reads(
@@ -65,9 +289,140 @@ fn krcount_internal(
         taxid_to_ancestors,
         umi_tag,
         barcode_tag,
+        kmer_profile.is_some(),
+        provenance.map(Path::new),
         batch_size,
         nqueue,
     )?;
+    if let Some(file) = kmer_profile {
+        count::write_kmer_profiles(file, &kreports, &counts_map)?;
+    }
+
+    // ─── Molecules of each (barcode, taxon) ──────────────
+    let molecules: Robj = if molecules {
+        let molecules = count::molecule_table(&kreports, &counts_map);
+        let column = |x: &[usize]| x.iter().map(|x| *x as f64).collect::<Vec<_>>();
+        list!(
+            barcodes = molecules
+                .barcodes
+                .iter()
+                .map(|x| u8_to_rstr(x.to_vec()))
+                .collect::<Vec<_>>(),
+            barcode_idx = column(&molecules.barcode_idx),
+            umi = molecules.umi.iter().map(|x| *x as f64).collect::<Vec<_>>(),
+            feature_idx = column(&molecules.feature_idx),
+            count = column(&molecules.count),
+            reads = molecules.reads as f64,
+            invalid_umi = molecules.invalid_umi as f64
+        )
+        .into()
+    } else {
+        ().into()
+    };
+
+    // ─── Per-taxon QC: k-mer breadth, duplication and saturation ──
+    let qc = count::taxon_kmer_qc(&kreports, &counts_map);
+    let qc_column = |f: fn(&(usize, usize, usize)) -> f64| qc.iter().map(f).collect::<Vec<_>>();
+    let umis = umi_tag.map(|_| count::taxon_umis(&kreports, &counts_map));
+    // Without UMIs, reads are not told apart by molecule
+    let umi_column = |f: &dyn Fn(usize, usize) -> f64| match &umis {
+        Some(umis) => qc
+            .iter()
+            .zip(umis)
+            .map(|(x, umis)| Rfloat::from(f(x.0, *umis)))
+            .collect::<Doubles>(),
+        None => kreports.iter().map(|_| Rfloat::na()).collect::<Doubles>(),
+    };
+    let qc = list!(
+        taxid = kreports
+            .iter()
+            .map(|report| u8_to_rstr(report.taxid.clone()))
+            .collect::<Vec<_>>(),
+        reads = qc_column(|x| x.0 as f64),
+        kmer_total = qc_column(|x| x.1 as f64),
+        kmer_unique = qc_column(|x| x.2 as f64),
+        duplication = qc_column(|x| if x.2 == 0 {
+            f64::NAN
+        } else {
+            x.1 as f64 / x.2 as f64
+        }),
+        umis = umi_column(&|_, umis| umis as f64),
+        saturation = umi_column(&|reads, umis| count::saturation(reads as f64, umis as f64))
+    );
+
+    // ─── Per-barcode saturation curves ────────────────────
+    let saturation: Robj = if umi_tag.is_some() {
+        let samples = count::sample_saturation(&kreports, &counts_map);
+        let mut barcodes = Vec::new();
+        let mut fractions = Vec::new();
+        let mut reads = Vec::new();
+        let mut umis = Vec::new();
+        for sample in &samples {
+            for (p, expected) in count::SATURATION_FRACTIONS.iter().zip(&sample.curve) {
+                barcodes.push(u8_to_rstr(sample.barcode.to_vec()));
+                fractions.push(*p);
+                reads.push(p * sample.reads as f64);
+                umis.push(*expected);
+            }
+        }
+        let curve_saturation = reads
+            .iter()
+            .zip(&umis)
+            .map(|(reads, umis)| count::saturation(*reads, *umis))
+            .collect::<Vec<_>>();
+        list!(
+            samples = list!(
+                barcode = samples
+                    .iter()
+                    .map(|x| u8_to_rstr(x.barcode.to_vec()))
+                    .collect::<Vec<_>>(),
+                reads = samples.iter().map(|x| x.reads as f64).collect::<Vec<_>>(),
+                umis = samples.iter().map(|x| x.umis as f64).collect::<Vec<_>>(),
+                saturation = samples
+                    .iter()
+                    .map(|x| count::saturation(x.reads as f64, x.umis as f64))
+                    .collect::<Vec<_>>()
+            ),
+            curve = list!(
+                barcode = barcodes,
+                fraction = fractions,
+                reads = reads,
+                umis = umis,
+                saturation = curve_saturation
+            )
+        )
+        .into()
+    } else {
+        ().into()
+    };
+
+    // ─── Barcode ranks, for a knee plot ──────────────────
+    let ranks = count::barcode_ranks(&kreports, &counts_map, umi_tag.is_some());
+    let barcode_ranks = list!(
+        barcode = ranks
+            .iter()
+            .map(|x| u8_to_rstr(x.0.to_vec()))
+            .collect::<Vec<_>>(),
+        rank = (1 ..= ranks.len()).map(|x| x as f64).collect::<Vec<_>>(),
+        count = ranks.iter().map(|x| x.1 as f64).collect::<Vec<_>>()
+    );
+
+    // ─── Feature metadata, aligned to the rows of the tables ──
+    let kreport_column = |f: fn(&Kreport) -> Vec<u8>| {
+        kreports
+            .iter()
+            .map(|report| u8_to_rstr(f(report)))
+            .collect::<Vec<_>>()
+    };
+    let features = list!(
+        taxid = kreport_column(|x| x.taxid.clone()),
+        name = kreport_column(|x| x.taxon.clone()),
+        rank = kreport_column(|x| x.rank.clone()),
+        lineage = kreport_column(|x| x.lineage()),
+        reads = qc_column(|x| x.0 as f64),
+        umis = umi_column(&|_, umis| umis as f64),
+        kmer_unique = qc_column(|x| x.2 as f64)
+    );
 
     // ─── Determine all observed rank codes ───────────────
     // Examples: U, R, D, K, P, C, O, F, G, S, G2, S1, etc.
@@ -86,7 +441,7 @@ fn krcount_internal(
         .collect::<HashSet<_>>();
     let mut ordered_ranks: Vec<_> = rank_sets.into_iter().collect();
     ordered_ranks.sort_by_key(|r| rank_order_key(r));
-    let taxa_cols = ordered_ranks
+    let mut taxa_cols = ordered_ranks
         .iter()
         .map(|bytes| unsafe { String::from_utf8_unchecked(bytes.to_vec()) })
         .collect::<Vec<_>>();
@@ -109,10 +464,25 @@ fn krcount_internal(
         }
         taxa_table.insert(rank, taxa_vec);
     }
-    let taxa_vec = ordered_ranks
+    let mut taxa_vec = ordered_ranks
         .iter()
         .filter_map(|rank| taxa_table.remove(rank.as_slice()))
         .collect::<Vec<_>>();
+    // Identify each taxon by taxid and its full lineage as well
+    taxa_cols.push("taxid".to_string());
+    taxa_vec.push(
+        kreports
+            .iter()
+            .map(|report| u8_to_rstr(report.taxid.clone()))
+            .collect(),
+    );
+    taxa_cols.push("lineage".to_string());
+    taxa_vec.push(
+        kreports
+            .iter()
+            .map(|report| u8_to_rstr(report.lineage()))
+            .collect(),
+    );
 
     // ─── Build data tables: taxon x barcode stats ────────
     // Each table holds rows for barcodes, columns for taxa
@@ -159,18 +529,93 @@ fn krcount_internal(
         .map(|bytes| unsafe { String::from_utf8_unchecked(bytes.to_vec()) })
         .collect::<Vec<_>>();
 
+    // ─── Doublet scores, from the species of each barcode ──
+    let species = kreports
+        .iter()
+        .enumerate()
+        .filter(|(_, report)| report.rank == b"S")
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    let species_counts = counts_vec
+        .iter()
+        .map(|column| species.iter().map(|&i| column[i].unwrap_or(0)).collect())
+        .collect::<Vec<Vec<usize>>>();
+    let scored = barcode_cols
+        .iter()
+        .zip(doublet::doublet_scores(&species_counts))
+        .filter_map(|(barcode, doublet)| Some((barcode.as_str(), doublet?)))
+        .collect::<Vec<_>>();
+    let species_taxid = |i: usize| u8_to_rstr(kreports[species[i]].taxid.clone());
+    let doublets = list!(
+        barcode = scored.iter().map(|x| x.0).collect::<Vec<_>>(),
+        first = scored
+            .iter()
+            .map(|x| species_taxid(x.1.first))
+            .collect::<Vec<_>>(),
+        second = scored
+            .iter()
+            .map(|x| {
+                if x.1.second == x.1.first {
+                    Rstr::na()
+                } else {
+                    species_taxid(x.1.second)
+                }
+            })
+            .collect::<Vec<_>>(),
+        score = scored.iter().map(|x| x.1.score).collect::<Vec<_>>(),
+        doublet = scored
+            .iter()
+            .map(|x| x.1.score >= doublet::DOUBLET_SCORE)
+            .collect::<Vec<_>>()
+    );
+    // With `sparse`, each table is returned as the slots of a `dgCMatrix` of
+    // taxa (rows) by barcodes (columns), otherwise as a list of columns
+    let taxids = kreports
+        .iter()
+        .map(|report| u8_to_rstr(report.taxid.clone()))
+        .collect::<Vec<_>>();
+    let table = |name: &str, columns: Vec<Vec<Option<usize>>>| -> Result<Robj> {
+        if sparse {
+            Ok(dgc_slots(&columns, taxids.clone(), barcode_cols.clone()).into())
+        } else {
+            List::from_names_and_values(barcode_cols.clone(), columns)
+                .map(Robj::from)
+                .map_err(|e| anyhow!("Failed to create list for {}: {}", name, e))
+        }
+    };
+
     Ok(list![
         taxa = List::from_names_and_values(taxa_cols, taxa_vec)
             .map_err(|e| anyhow!("Failed to create list for taxa: {}", e))?,
-        counts = List::from_names_and_values(barcode_cols.clone(), counts_vec)
-            .map_err(|e| anyhow!("Failed to create list for counts: {}", e))?,
-        kmer_total = List::from_names_and_values(barcode_cols.clone(), kmer_total_vec)
-            .map_err(|e| anyhow!("Failed to create list for kmer_total: {}", e))?,
-        kmer_unique = List::from_names_and_values(barcode_cols, kmer_unique_vec)
-            .map_err(|e| anyhow!("Failed to create list for kmer_unique: {}", e))?,
+        counts = table("counts", counts_vec)?,
+        kmer_total = table("kmer_total", kmer_total_vec)?,
+        kmer_unique = table("kmer_unique", kmer_unique_vec)?,
+        qc = qc,
+        saturation = saturation,
+        barcode_ranks = barcode_ranks,
+        doublets = doublets,
+        features = features,
+        molecules = molecules,
     ])
 }
 
+/// Each taxid maps to a set of its ancestor taxids (inclusive)
+fn taxid_ancestors(kreports: &[Kreport]) -> HashMap<&[u8], HashSet<&[u8]>> {
+    kreports
+        .iter()
+        .map(|report| {
+            // Each report's `taxids` field holds the lineage (ancestors) of this taxon
+            let ancestors = report
+                .taxids
+                .iter()
+                .map(|x| x.as_slice())
+                .collect::<HashSet<&[u8]>>();
+            // Map: current taxid → set of its ancestors
+            (report.taxid.as_slice(), ancestors)
+        })
+        .collect()
+}
+
 fn rank_order_key(rank: &[u8]) -> (usize, usize) {
     match rank {
         b"U" => (0, 0),
@@ -206,4 +651,8 @@ fn rank_order_key(rank: &[u8]) -> (usize, usize) {
 extendr_module! {
     mod krcount;
     fn krcount;
+    fn krcount_db;
+    fn krcount_zarr_reads;
+    fn krcount_rarefy;
+    fn krcount_ambient;
 }
@@ -0,0 +1,88 @@
+use anyhow::{anyhow, Result};
+use rand::seq::index;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
+
+/// Rarefy the counts of each barcode to a common `depth`, by sampling `depth`
+/// UMIs (or reads) without replacement. Barcodes with fewer than `depth` UMIs
+/// are dropped, their position in the returned vector is `None`.
+///
+/// Each barcode draws from its own stream of a generator seeded with `seed`,
+/// the result is reproducible whatever the number of threads.
+pub(super) fn rarefy_barcodes(
+    barcodes: &[Vec<usize>],
+    depth: usize,
+    seed: u64,
+) -> Vec<Option<Vec<usize>>> {
+    barcodes
+        .par_iter()
+        .enumerate()
+        .map(|(i, counts)| {
+            let mut rng = ChaCha8Rng::seed_from_u64(seed);
+            rng.set_stream(i as u64);
+            rarefy(counts, depth, &mut rng)
+        })
+        .collect()
+}
+
+/// Sample `depth` units out of the sum of `counts`, returning the number of
+/// sampled units of each entry, or `None` if there are not enough units.
+fn rarefy(counts: &[usize], depth: usize, rng: &mut ChaCha8Rng) -> Option<Vec<usize>> {
+    let total = counts.iter().sum::<usize>();
+    if total < depth {
+        return None;
+    }
+    // Only the (sorted) positions of the sampled units are kept, memory does
+    // not depend on the total number of units
+    let mut sampled = index::sample(rng, total, depth).into_vec();
+    sampled.sort_unstable();
+    let mut out = vec![0; counts.len()];
+    let mut sampled = sampled.into_iter().peekable();
+    let mut end = 0;
+    for (count, n) in counts.iter().zip(out.iter_mut()) {
+        end += count;
+        while sampled.next_if(|&pos| pos < end).is_some() {
+            *n += 1;
+        }
+    }
+    Some(out)
+}
+
+/// The default depth: the smallest total of the barcodes with any UMI
+pub(super) fn min_depth(barcodes: &[Vec<usize>]) -> Result<usize> {
+    barcodes
+        .iter()
+        .map(|counts| counts.iter().sum::<usize>())
+        .filter(|&total| total > 0)
+        .min()
+        .ok_or_else(|| anyhow!("No barcode has any count to rarefy"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rarefy_barcodes() {
+        let barcodes = vec![vec![10, 0, 5], vec![1, 1], vec![100, 200, 300, 0]];
+        assert_eq!(min_depth(&barcodes).unwrap(), 2);
+        assert!(min_depth(&[vec![0, 0]]).is_err());
+
+        let rarefied = rarefy_barcodes(&barcodes, 6, 42);
+        assert_eq!(rarefied, rarefy_barcodes(&barcodes, 6, 42));
+        assert!(rarefied[1].is_none());
+        for counts in [&rarefied[0], &rarefied[2]] {
+            assert_eq!(counts.as_ref().unwrap().iter().sum::<usize>(), 6);
+        }
+        // Entries without any count are never sampled
+        assert_eq!(rarefied[0].as_ref().unwrap()[1], 0);
+        assert_eq!(rarefied[2].as_ref().unwrap()[3], 0);
+
+        // Rarefying to the total keeps all counts
+        assert_eq!(
+            rarefy_barcodes(&barcodes[.. 1], 15, 1),
+            vec![Some(vec![10, 0, 5])]
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use bytes::BytesMut;
+use crossbeam_channel::{Receiver, Sender};
+use memchr::memmem::Finder;
+use rustc_hash::FxHashMap as HashMap;
+use rustc_hash::FxHashSet as HashSet;
+
+use super::count::{clade_confidence, extract_tag};
+use crate::batchsender::BatchSender;
+use crate::error::ErrorKind;
+use crate::reader::LineReader;
+use crate::utils::*;
+use crate::zarr::{ZarrStore, CHUNK_LEN};
+
+const STRINGS: &str = r#"{"encoding-type": "string-array", "encoding-version": "0.2.0"}"#;
+const ARRAY: &str = r#"{"encoding-type": "array", "encoding-version": "0.2.0"}"#;
+
+/// Export one row per read of a Koutreads-format file into the `uns/reads`
+/// data frame of the AnnData layout of a Zarr store.
+///
+/// Like the `reads` table of [`super::db::export_reads()`], it holds the read
+/// index (1-based line number in the Koutreads file), taxid, barcode and UMI
+/// if their tag is given, and the confidence of the assignment (`NaN` without
+/// informative k-mers), each column an array of its own. Returns the number
+/// of exported reads.
+pub(super) fn export_reads<P: AsRef<Path> + ?Sized>(
+    koutreads: &P,
+    store: &P,
+    ancestor_map: HashMap<&[u8], HashSet<&[u8]>>,
+    umi_tag: Option<&str>,
+    barcode_tag: Option<&str>,
+    batch_size: usize,
+    nqueue: Option<usize>,
+) -> Result<usize> {
+    let input: &Path = koutreads.as_ref();
+    let store = ZarrStore::open(store.as_ref())?;
+    let style = progress_reader_style()?;
+    let pb = input_progress_bar(input)?;
+    pb.set_prefix("Exporting Koutreads");
+    pb.set_style(style);
+
+    let mut columns = vec!["taxid"];
+    columns.extend(barcode_tag.map(|_| "barcode"));
+    columns.extend(umi_tag.map(|_| "umi"));
+    columns.push("confidence");
+    store.group(
+        "uns/reads",
+        &format!(
+            r#"{{"_index": "read_id", "column-order": [{}], "encoding-type": "dataframe", "encoding-version": "0.2.0"}}"#,
+            columns
+                .iter()
+                .map(|x| format!("\"{}\"", x))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    )?;
+    let mut read_ids = store.array::<i64>("uns/reads/read_id", CHUNK_LEN, ARRAY)?;
+    let mut taxids = store.array::<String>("uns/reads/taxid", CHUNK_LEN, STRINGS)?;
+    let mut barcodes = barcode_tag
+        .map(|_| store.array::<String>("uns/reads/barcode", CHUNK_LEN, STRINGS))
+        .transpose()?;
+    let mut umis = umi_tag
+        .map(|_| store.array::<String>("uns/reads/umi", CHUNK_LEN, STRINGS))
+        .transpose()?;
+    let mut confidences = store.array::<f64>("uns/reads/confidence", CHUNK_LEN, ARRAY)?;
+
+    let records = std::thread::scope(|scope| -> Result<usize> {
+        let (reader_tx, reader_rx): (Sender<Vec<BytesMut>>, Receiver<Vec<BytesMut>>) =
+            new_channel(nqueue);
+
+        // ─── reader Thread ─────────────────────────────────────
+        // Reads lines from input file and sends them in batches to writer thread
+        let reader_handle = scope.spawn(move || -> Result<()> {
+            let mut reader =
+                LineReader::with_capacity(BUFFER_SIZE, new_reader(input, BUFFER_SIZE, Some(pb))?);
+            let mut reader_tx: BatchSender<BytesMut> =
+                BatchSender::with_capacity(batch_size, reader_tx);
+            while let Some(line) = reader.read_line().context("(Reader) Failed to read line")? {
+                if line.iter().all(|b| b.is_ascii_whitespace()) {
+                    continue;
+                }
+                reader_tx
+                    .send(line)
+                    .context("(Reader) Failed to send lines to Writer thread")?;
+            }
+            reader_tx
+                .flush()
+                .context("(Reader) Failed to flush lines to Writer thread")?;
+            Ok(())
+        });
+
+        // ─── Writer Thread ─────────────────────────────────────
+        // Columns are chunked and compressed as they fill, in the current
+        // thread
+        let umi_finder = umi_tag.as_ref().map(Finder::new);
+        let barcode_finder = barcode_tag.as_ref().map(Finder::new);
+        let mut read_id: usize = 0;
+        let result = (|| -> Result<()> {
+            while let Ok(lines) = reader_rx.recv() {
+                for line in lines {
+                    read_id += 1;
+                    let fields: Vec<&[u8]> = line[..].split(|b| *b == b'\t').collect();
+                    if fields.len() != 5 {
+                        return Err(ErrorKind::Parse.error("Invalid file: must have 5 fields"));
+                    }
+                    // taxid + tags + lca + seq + qual
+                    let taxid = fields[0];
+                    let tags = fields[1];
+                    let barcode =
+                        extract_tag(tags, &barcode_finder, &barcode_tag).with_context(|| {
+                            format!("Failed to extract barcode in line {}", read_id)
+                        })?;
+                    let umi = extract_tag(tags, &umi_finder, &umi_tag)
+                        .with_context(|| format!("Failed to extract umi in line {}", read_id))?;
+                    let confidence = clade_confidence(taxid, fields[2], &ancestor_map);
+                    read_ids.push(read_id as i64)?;
+                    taxids.push(String::from_utf8_lossy(taxid).into_owned())?;
+                    if let (Some(barcodes), Some(barcode)) = (barcodes.as_mut(), barcode) {
+                        barcodes.push(String::from_utf8_lossy(barcode).into_owned())?;
+                    }
+                    if let (Some(umis), Some(umi)) = (umis.as_mut(), umi) {
+                        umis.push(String::from_utf8_lossy(umi).into_owned())?;
+                    }
+                    confidences.push(confidence.unwrap_or(f64::NAN))?;
+                }
+            }
+            Ok(())
+        })();
+        // Unblock the reader thread if the writer stopped early
+        drop(reader_rx);
+
+        // ─── Join Threads and Propagate Errors ────────────────
+        let reader_result = reader_handle
+            .join()
+            .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))?;
+        // A writer failure also breaks the reader's channel, report the cause
+        result?;
+        reader_result?;
+        Ok(read_id)
+    })?;
+
+    // Array metadata hold their length, written once all rows are known
+    read_ids.finish()?;
+    taxids.finish()?;
+    if let Some(barcodes) = barcodes {
+        barcodes.finish()?;
+    }
+    if let Some(umis) = umis {
+        umis.finish()?;
+    }
+    confidences.finish()?;
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_reads() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let input = temp.path().join("koutreads.txt");
+        std::fs::write(
+            &input,
+            "\
+562\tCB:AAAC UB:TTTT\t562:2 561:1 A:1 0:1\tACGTACGT\tIIIIIIII
+561\tCB:CCCA UB:GGGG\t562:2 |:| 561:2\tACGT  ACGT\tIIII  IIII
+",
+        )?;
+        let path = temp.path().join("counts.zarr");
+        ZarrStore::create(&path, "")?;
+
+        // 562 descends from 561
+        let mut ancestor_map = HashMap::default();
+        ancestor_map.insert(b"561".as_slice(), HashSet::from_iter([b"561".as_slice()]));
+        ancestor_map.insert(
+            b"562".as_slice(),
+            HashSet::from_iter([b"561".as_slice(), b"562".as_slice()]),
+        );
+
+        let n = export_reads(&input, &path, ancestor_map, None, Some("CB"), 2, None)?;
+        assert_eq!(n, 2);
+        let reads = path.join("uns/reads");
+        assert!(std::fs::read_to_string(reads.join(".zattrs"))?
+            .contains(r#""column-order": ["taxid", "barcode", "confidence"]"#));
+        assert!(reads.join("barcode/0").is_file() && !reads.join("umi").exists());
+        assert!(std::fs::read_to_string(reads.join("read_id/.zarray"))?.contains(r#""shape": [2]"#));
+        Ok(())
+    }
+}
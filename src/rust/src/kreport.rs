@@ -4,6 +4,7 @@ use anyhow::{anyhow, Context, Result};
 use extendr_api::prelude::*;
 use rustc_hash::FxHashSet as HashSet;
 
+use crate::error::RError;
 use crate::utils::*;
 use crate::{reader::LineReader, utils::BUFFER_SIZE};
 
@@ -222,9 +223,30 @@ pub(crate) struct Kreport {
     pub(crate) level: usize,
 }
 
+impl Kreport {
+    /// Lineage string over the standard ranks, from the domain down to this
+    /// taxon, e.g. `d__Bacteria;p__Pseudomonadota;...;s__Escherichia coli`.
+    /// Root and intermediate ranks (e.g. `G2`) are omitted.
+    pub(crate) fn lineage(&self) -> Vec<u8> {
+        let mut lineage = Vec::new();
+        for (rank, taxon) in self.ranks.iter().zip(self.taxa.iter()) {
+            if rank.len() != 1 || matches!(rank[0], b'R' | b'U') {
+                continue;
+            }
+            if !lineage.is_empty() {
+                lineage.push(b';');
+            }
+            lineage.push(rank[0].to_ascii_lowercase());
+            lineage.extend_from_slice(b"__");
+            lineage.extend_from_slice(taxon);
+        }
+        lineage
+    }
+}
+
 #[extendr]
-fn read_kreport(kreport: &str, taxonomy: Robj) -> std::result::Result<List, String> {
-    let kreports = taxonomy_kreport(kreport, taxonomy).map_err(|e| format!("{:?}", e))?;
+fn read_kreport(kreport: &str, taxonomy: Robj) -> std::result::Result<List, RError> {
+    let kreports = taxonomy_kreport(kreport, taxonomy).map_err(RError::from)?;
 
     let mut percents = Vec::with_capacity(kreports.len());
     let mut total_reads = Vec::with_capacity(kreports.len());
@@ -237,6 +259,7 @@ fn read_kreport(kreport: &str, taxonomy: Robj) -> std::result::Result<List, Stri
     let mut ranks = Vec::with_capacity(kreports.len());
     let mut taxids = Vec::with_capacity(kreports.len());
     let mut taxon = Vec::with_capacity(kreports.len());
+    let mut lineage = Vec::with_capacity(kreports.len());
 
     // Optional columns
     let mut minimizer_len = Vec::with_capacity(kreports.len());
@@ -246,6 +269,7 @@ fn read_kreport(kreport: &str, taxonomy: Robj) -> std::result::Result<List, Stri
         total_reads.push(report.total_reads as f64);
         reads.push(report.reads as f64);
 
+        lineage.push(u8_to_rstr(report.lineage()));
         rank.push(u8_to_rstr(report.rank));
         taxid.push(u8_to_rstr(report.taxid));
         taxon.push(u8_to_rstr(report.taxon));
@@ -276,6 +300,7 @@ fn read_kreport(kreport: &str, taxonomy: Robj) -> std::result::Result<List, Stri
             rank = rank,
             taxid = taxid,
             taxon = taxon,
+            lineage = lineage,
             ranks = ranks,
             taxids = taxids,
             taxa = taxa
@@ -290,6 +315,7 @@ fn read_kreport(kreport: &str, taxonomy: Robj) -> std::result::Result<List, Stri
             rank = rank,
             taxid = taxid,
             taxon = taxon,
+            lineage = lineage,
             ranks = ranks,
             taxids = taxids,
             taxa = taxa
@@ -302,3 +328,43 @@ extendr_module! {
     mod kreport;
     fn read_kreport;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kreport_lineage() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let path = temp.path().join("kreport.txt");
+        std::fs::write(
+            &path,
+            "\
+10.00\t10\t10\tU\t0\tunclassified
+90.00\t90\t0\tR\t1\troot
+90.00\t90\t0\tD\t2\t  Bacteria
+90.00\t90\t0\tP\t1224\t    Pseudomonadota
+90.00\t90\t0\tG\t561\t      Escherichia
+90.00\t90\t0\tG1\t2608867\t        Escherichia group
+90.00\t90\t90\tS\t562\t          Escherichia coli
+",
+        )?;
+        let kreports = parse_kreport(&path)?;
+        let lineages = kreports
+            .iter()
+            .map(|kr| String::from_utf8(kr.lineage()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        assert_eq!(
+            lineages,
+            vec![
+                "",
+                "d__Bacteria",
+                "d__Bacteria;p__Pseudomonadota",
+                "d__Bacteria;p__Pseudomonadota;g__Escherichia",
+                "d__Bacteria;p__Pseudomonadota;g__Escherichia",
+                "d__Bacteria;p__Pseudomonadota;g__Escherichia;s__Escherichia coli",
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use aho_corasick::{AhoCorasick, AhoCorasickKind};
+use anyhow::Result;
+
+/// How the `exclude` patterns match the LCA mapping of Kraken2 output lines
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct ExcludeOptions {
+    // ASCII case-insensitive matching
+    pub(crate) ignore_case: bool,
+    // Only match whole words, so "606" does not match "9606:13", nor "Homo"
+    // match "Homoserine"
+    pub(crate) whole_word: bool,
+}
+
+/// Matcher of the `exclude` patterns in the LCA mapping field of Kraken2
+/// output lines.
+///
+/// A space-delimited list indicating the LCA mapping of each k-mer in the
+/// sequence(s). For example, "562:13 561:4 A:31 0:1 562:3" would indicate
+/// that:
+///
+/// - the first 13 k-mers mapped to taxonomy ID #562
+/// - the next 4 k-mers mapped to taxonomy ID #561
+/// - the next 31 k-mers contained an ambiguous nucleotide
+/// - the next k-mer was not in the database
+/// - the last 3 k-mers mapped to taxonomy ID #562
+///
+/// Each taxid is searched with its `:` suffix.
+pub(crate) struct ExcludeMatcher {
+    aho: AhoCorasick,
+    whole_word: bool,
+}
+
+impl ExcludeMatcher {
+    pub(crate) fn new<T: AsRef<str>>(taxids: &[T], options: ExcludeOptions) -> Result<Self> {
+        let patterns = taxids.iter().map(|taxid| {
+            let taxid = taxid.as_ref().as_bytes();
+            let mut pattern = Vec::with_capacity(taxid.len() + 1);
+            pattern.extend_from_slice(taxid);
+            pattern.push(b':');
+            pattern
+        });
+        Self::from_patterns(patterns, options)
+    }
+
+    /// Search the raw `patterns`, without the `:` suffix of taxids
+    pub(crate) fn from_patterns<I, P>(patterns: I, options: ExcludeOptions) -> Result<Self>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<[u8]>,
+    {
+        let aho = AhoCorasick::builder()
+            .kind(Some(AhoCorasickKind::DFA))
+            .ascii_case_insensitive(options.ignore_case)
+            .build(patterns)?;
+        Ok(Self {
+            aho,
+            whole_word: options.whole_word,
+        })
+    }
+
+    pub(crate) fn is_match(&self, lca: &[u8]) -> bool {
+        if self.whole_word {
+            self.patterns(lca).next().is_some()
+        } else {
+            self.aho.is_match(lca)
+        }
+    }
+
+    /// Indices of the patterns found in `lca`, once per occurrence
+    pub(crate) fn patterns<'a>(&'a self, lca: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        self.aho
+            .find_overlapping_iter(lca)
+            .filter(move |m| !self.whole_word || whole_word(lca, m.start(), m.end()))
+            .map(|m| m.pattern().as_usize())
+    }
+}
+
+fn is_word(byte: Option<&u8>) -> bool {
+    byte.is_some_and(|x| x.is_ascii_alphanumeric() || *x == b'_')
+}
+
+/// Whether `haystack[start .. end]` is delimited by word boundaries
+fn whole_word(haystack: &[u8], start: usize, end: usize) -> bool {
+    let before = start.checked_sub(1).and_then(|i| haystack.get(i));
+    is_word(before) != is_word(haystack.get(start))
+        && is_word(haystack.get(end - 1)) != is_word(haystack.get(end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exclude_matcher() -> Result<()> {
+        let lca = b"562:13 19606:4 A:31";
+        let matcher = ExcludeMatcher::new(&["9606"], ExcludeOptions::default())?;
+        assert!(matcher.is_match(lca));
+        let options = ExcludeOptions {
+            whole_word: true,
+            ..Default::default()
+        };
+        let matcher = ExcludeMatcher::new(&["9606", "562"], options)?;
+        assert!(!matcher.is_match(b"19606:4 A:31"));
+        assert_eq!(matcher.patterns(lca).collect::<Vec<_>>(), [1]);
+        assert!(matcher.is_match(b"A:31 9606:4"));
+
+        let matcher = ExcludeMatcher::from_patterns(["homo"], options)?;
+        assert!(!matcher.is_match(b"Homo sapiens"));
+        let options = ExcludeOptions {
+            ignore_case: true,
+            whole_word: true,
+        };
+        let matcher = ExcludeMatcher::from_patterns(["homo"], options)?;
+        assert!(matcher.is_match(b"Homo sapiens"));
+        assert!(!matcher.is_match(b"Homoserine"));
+        Ok(())
+    }
+}
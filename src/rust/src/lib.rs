@@ -1,17 +1,55 @@
 use extendr_api::prelude::*;
 
+mod altrep;
+mod arrow_stream;
+mod async_io;
+mod bam_filter;
+mod bam_index;
+mod bam_writer;
 mod batchsender;
-mod fastq_reader;
-mod fastq_record;
+mod checksum;
+mod count_matrix;
+mod error;
+mod fastq_check;
+mod fastq_cram;
+mod fastq_demux;
+pub mod fastq_reader;
+pub mod fastq_record;
+mod fastq_sort;
+mod fastq_split;
+mod feature_count;
+mod hto_demux;
+mod io_retry;
+mod koutput_join;
 mod koutput_reads;
 mod kractor;
 mod krcount;
 mod kreport;
+mod lca_exclude;
+mod memory;
+mod multi_writer;
+mod numa;
+mod occupancy;
+mod part_writer;
+mod progress;
+mod read_stats;
 mod reader;
+pub mod record_filter;
+mod record_index;
+mod remote;
+mod s3;
+mod seekable;
 mod seq_range;
 mod seq_refine;
 mod seq_tag;
+mod table_writer;
+mod tar;
+mod threads;
 pub(crate) mod utils;
+mod warnings;
+mod whitelist;
+#[cfg(feature = "zarr")]
+mod zarr;
 
 // https://extendr.github.io/extendr/extendr_api/#returning-resultt-e-to-r
 // https://github.com/extendr/extendr/blob/master/extendr-api/src/robj/into_robj.rs#L100
@@ -33,6 +71,22 @@ extendr_module! {
     use kreport;
     use seq_refine;
     use koutput_reads;
+    use koutput_join;
     use krcount;
+    use count_matrix;
+    use checksum;
     use kractor;
+    use fastq_split;
+    use fastq_sort;
+    use fastq_demux;
+    use fastq_check;
+    use fastq_cram;
+    use bam_filter;
+    use progress;
+    use io_retry;
+    use feature_count;
+    use hto_demux;
+    use record_index;
+    use warnings;
+    use whitelist;
 }
@@ -0,0 +1,168 @@
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+use extendr_api::prelude::*;
+
+// Resident memory of the process, sampled at a fixed interval while a run
+// goes on, so its peak tells the memory to request for the next runs of the
+// same size on a cluster. Read from /proc on Linux and from the Mach task
+// info on macOS; elsewhere the usage is unknown.
+
+/// Interval between two samples of the resident memory
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The resident set size of the process, in bytes
+#[cfg(target_os = "linux")]
+pub(crate) fn resident_bytes() -> Option<u64> {
+    // `statm` holds the sizes in pages: total, then resident
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    (page_size > 0).then(|| pages * page_size as u64)
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn resident_bytes() -> Option<u64> {
+    let mut info: libc::mach_task_basic_info = unsafe { std::mem::zeroed() };
+    let mut count = libc::MACH_TASK_BASIC_INFO_COUNT;
+    #[allow(deprecated)]
+    let status = unsafe {
+        libc::task_info(
+            libc::mach_task_self(),
+            libc::MACH_TASK_BASIC_INFO,
+            &mut info as *mut _ as libc::task_info_t,
+            &mut count,
+        )
+    };
+    (status == libc::KERN_SUCCESS).then_some(info.resident_size)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn resident_bytes() -> Option<u64> {
+    None
+}
+
+/// The resident memory of the process over a run, in bytes
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct MemoryUsage {
+    start: Option<u64>,
+    peak: Option<u64>,
+    end: Option<u64>,
+}
+
+impl MemoryUsage {
+    fn add(&mut self, rss: Option<u64>) {
+        if let Some(rss) = rss {
+            self.start.get_or_insert(rss);
+            self.peak = Some(self.peak.map_or(rss, |x| x.max(rss)));
+            self.end = Some(rss);
+        }
+    }
+
+    /// The usage as the columns of a data frame of a single row, missing
+    /// where unknown
+    pub(crate) fn into_list(self) -> List {
+        let bytes = |x: Option<u64>| x.map_or(Rfloat::na(), |x| Rfloat::from(x as f64));
+        list!(
+            start = bytes(self.start),
+            peak = bytes(self.peak),
+            end = bytes(self.end)
+        )
+    }
+}
+
+/// The thread sampling the resident memory of the process until finished
+pub(crate) struct MemorySampler {
+    stop_tx: Sender<()>,
+    handle: JoinHandle<MemoryUsage>,
+}
+
+impl MemorySampler {
+    pub(crate) fn start() -> Self {
+        Self::start_with(resident_bytes)
+    }
+
+    /// Sample the memory with `sample`, first before returning, then at each
+    /// interval and once more when finished
+    fn start_with<F>(mut sample: F) -> Self
+    where
+        F: FnMut() -> Option<u64> + Send + 'static,
+    {
+        let mut usage = MemoryUsage::default();
+        usage.add(sample());
+        let (stop_tx, stop_rx) = bounded::<()>(0);
+        let handle = std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(SAMPLE_INTERVAL) {
+                usage.add(sample());
+            }
+            usage.add(sample());
+            usage
+        });
+        Self { stop_tx, handle }
+    }
+
+    /// Stop sampling and return the usage over the run
+    pub(crate) fn finish(self) -> MemoryUsage {
+        drop(self.stop_tx);
+        // A sampler that panicked has nothing to report
+        self.handle.join().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn test_memory_usage() {
+        let mut usage = MemoryUsage::default();
+        for rss in [None, Some(10), Some(30), None, Some(20), None] {
+            usage.add(rss);
+        }
+        assert_eq!(
+            (usage.start, usage.peak, usage.end),
+            (Some(10), Some(30), Some(20))
+        );
+        // Unknown samples leave the usage unknown
+        let mut usage = MemoryUsage::default();
+        usage.add(None);
+        assert_eq!((usage.start, usage.peak, usage.end), (None, None, None));
+    }
+
+    #[test]
+    fn test_memory_sampler() {
+        // Synthetic samples, counted so the test waits for the sampler
+        // rather than for a fixed time
+        let rss = Arc::new(AtomicU64::new(5));
+        let samples = Arc::new(AtomicUsize::new(0));
+        let sampler = MemorySampler::start_with({
+            let (rss, samples) = (rss.clone(), samples.clone());
+            move || {
+                samples.fetch_add(1, Ordering::SeqCst);
+                Some(rss.load(Ordering::SeqCst))
+            }
+        });
+        assert_eq!(samples.load(Ordering::SeqCst), 1);
+        rss.store(50, Ordering::SeqCst);
+        let taken = samples.load(Ordering::SeqCst);
+        while samples.load(Ordering::SeqCst) <= taken {
+            std::thread::sleep(SAMPLE_INTERVAL / 5);
+        }
+        rss.store(20, Ordering::SeqCst);
+        let usage = sampler.finish();
+        assert_eq!(
+            (usage.start, usage.peak, usage.end),
+            (Some(5), Some(50), Some(20))
+        );
+
+        // The resident memory itself is only known on Linux and macOS
+        assert_eq!(
+            resident_bytes().is_some(),
+            cfg!(any(target_os = "linux", target_os = "macos"))
+        );
+    }
+}
@@ -0,0 +1,548 @@
+use std::hash::{Hash, Hasher};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use crossbeam_channel::{Receiver, Sender};
+use flate2::write::GzEncoder;
+use indicatif::ProgressBar;
+use libdeflater::Compressor;
+use rustc_hash::FxHashMap as HashMap;
+use rustc_hash::FxHashSet as HashSet;
+use rustc_hash::FxHasher;
+
+use crate::async_io::OutputAppends;
+use crate::batchsender::BatchSender;
+use crate::error::ErrorKind;
+use crate::fastq_reader::FastqReader;
+use crate::fastq_record::FastqRecord;
+use crate::tar::TarWriter;
+use crate::utils::*;
+
+/// Where the outputs of a [`MultiWriter`] are stored
+enum MultiSink {
+    // One file per output in a directory
+    Directory(PathBuf),
+    // One member per output in a tar (or tar.gz) archive
+    Tar(TarWriter<TarOutput>),
+}
+
+/// The stream of a tar archive, which may be compressed as a whole
+enum TarOutput {
+    Plain(BufWriter<Box<dyn CompleteWrite>>),
+    Gzip(GzEncoder<BufWriter<Box<dyn CompleteWrite>>>),
+}
+
+impl TarOutput {
+    /// Write the gzip trailer (if any) and complete the output. This must
+    /// happen in this order: completing finishes remote uploads (see
+    /// `CompleteWrite`).
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(mut writer) => writer.complete(),
+            Self::Gzip(encoder) => encoder.finish()?.complete(),
+        }
+    }
+}
+
+impl Write for TarOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(writer) => writer.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(writer) => writer.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// A writer fanning records out to many named outputs, such as one FASTQ per
+/// cell barcode or per sample.
+///
+/// Records are buffered per output, and each buffer is gzip-compressed (for
+/// outputs named `*.gz`) once it reaches `chunk_bytes`. In a directory, the
+/// compressed chunks are appended to the output file right away, without
+/// keeping a file handle open for each of possibly hundreds of thousands of
+/// outputs. A tar member must be written in one piece, so for archives the
+/// compressed chunks are kept in memory and each output becomes one member
+/// when the writer is finished: this suits extracted reads, which are a small
+/// fraction of the input.
+pub(crate) struct MultiWriter {
+    sink: MultiSink,
+    chunk_bytes: usize,
+    compressor: Compressor,
+    // Records not compressed yet, per output
+    buffers: HashMap<String, Vec<u8>>,
+    // Compressed chunks held for the tar archive, per output
+    packs: HashMap<String, Vec<u8>>,
+    // Outputs already created in the directory
+    created: HashSet<String>,
+    appends: OutputAppends,
+    bar: Option<ProgressBar>,
+}
+
+impl MultiWriter {
+    /// Write each output as a file in `odir`, which must exist
+    pub(crate) fn directory<P: AsRef<Path> + ?Sized>(
+        odir: &P,
+        compression_level: Option<i32>,
+        chunk_bytes: usize,
+        progress_bar: Option<ProgressBar>,
+    ) -> Result<Self> {
+        Self::new(
+            MultiSink::Directory(odir.as_ref().to_path_buf()),
+            compression_level,
+            chunk_bytes,
+            progress_bar,
+        )
+    }
+
+    /// Write each output as a member of the tar `archive`. Archives named
+    /// `*.tar.gz` or `*.tgz` are compressed as a whole.
+    pub(crate) fn tar<P: AsRef<Path> + ?Sized>(
+        archive: &P,
+        compression_level: Option<i32>,
+        chunk_bytes: usize,
+        progress_bar: Option<ProgressBar>,
+    ) -> Result<Self> {
+        let path: &Path = archive.as_ref();
+        let writer = BufWriter::with_capacity(BUFFER_SIZE, new_writer(path, progress_bar)?);
+        let name = path.to_string_lossy().to_ascii_lowercase();
+        let writer = if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            let level = Compression::with_format(ChunkFormat::Gzip, compression_level)?
+                .level()
+                .unwrap_or_default();
+            let level = flate2::Compression::new(level.clamp(0, 9) as u32);
+            TarOutput::Gzip(gzip_encoder(writer, level))
+        } else {
+            TarOutput::Plain(writer)
+        };
+        Self::new(
+            MultiSink::Tar(TarWriter::new(writer)),
+            compression_level,
+            chunk_bytes,
+            None,
+        )
+    }
+
+    fn new(
+        sink: MultiSink,
+        compression_level: Option<i32>,
+        chunk_bytes: usize,
+        bar: Option<ProgressBar>,
+    ) -> Result<Self> {
+        // Outputs are gzip-compressed by their name, whatever the name
+        let compression = Compression::with_format(ChunkFormat::Gzip, compression_level)?;
+        Ok(Self {
+            sink,
+            chunk_bytes,
+            compressor: compression.compressor(),
+            buffers: HashMap::default(),
+            packs: HashMap::default(),
+            created: HashSet::default(),
+            appends: OutputAppends::new(),
+            bar,
+        })
+    }
+
+    /// Append whole records to the output `name`
+    pub(crate) fn write(&mut self, name: &str, records: &[u8]) -> Result<()> {
+        let buffer = match self.buffers.get_mut(name) {
+            Some(buffer) => buffer,
+            None => self.buffers.entry(name.to_string()).or_default(),
+        };
+        buffer.extend_from_slice(records);
+        if buffer.len() >= self.chunk_bytes {
+            let buffer = std::mem::take(buffer);
+            self.pack(name, buffer)?;
+        }
+        Ok(())
+    }
+
+    /// Flush all outputs and close the archive. Returns the output names, in
+    /// the order they were written.
+    pub(crate) fn finish(mut self) -> Result<Vec<String>> {
+        let mut names = self
+            .buffers
+            .keys()
+            .chain(self.packs.keys())
+            .chain(self.created.iter())
+            .cloned()
+            .collect::<HashSet<String>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        for name in &names {
+            if let Some(buffer) = self.buffers.remove(name) {
+                self.pack(name, buffer)?;
+            }
+        }
+        self.appends.finish()?;
+        if let MultiSink::Tar(mut writer) = self.sink {
+            for name in &names {
+                let pack = self.packs.remove(name).unwrap_or_default();
+                writer
+                    .append(name, &pack)
+                    .with_context(|| format!("(Writer) Failed to add {} to archive", name))?;
+            }
+            writer
+                .finish()
+                .and_then(TarOutput::finish)
+                .context("(Writer) Failed to finish archive")?;
+        }
+        Ok(names)
+    }
+
+    fn pack(&mut self, name: &str, buffer: Vec<u8>) -> Result<()> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        let pack = if name.ends_with(".gz") {
+            gzip_pack(&buffer, &mut self.compressor)?
+        } else {
+            buffer
+        };
+        match &self.sink {
+            MultiSink::Directory(odir) => {
+                // The first chunk replaces any existing file, like `File::create()`
+                let truncate = !self.created.contains(name);
+                self.appends
+                    .append(odir.join(name), pack, truncate, self.bar.as_ref())?;
+                self.created.insert(name.to_string());
+            }
+            MultiSink::Tar(_) => {
+                self.packs
+                    .entry(name.to_string())
+                    .or_default()
+                    .extend_from_slice(&pack);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Records grouped by routing key: number of reads and the records of each mate
+type RoutedGroups = HashMap<Bytes, (usize, Vec<Vec<u8>>)>;
+
+/// Number of reads routed to each key (sorted by key), and of dropped reads
+pub(crate) type RoutedCounts = (Vec<(Bytes, usize)>, usize);
+
+/// The writer thread of a routing key, all outputs of a key are written by
+/// the same thread
+fn writer_shard(key: &[u8], writers: usize) -> usize {
+    let mut hasher = FxHasher::default();
+    key.hash(&mut hasher);
+    (hasher.finish() % writers as u64) as usize
+}
+
+/// Send grouped records to the writer thread of each key
+fn send_groups(groups: RoutedGroups, txs: &[Sender<RoutedGroups>]) -> Result<()> {
+    let send = |tx: &Sender<RoutedGroups>, groups: RoutedGroups| {
+        tx.send(groups)
+            .context("(Parser) Failed to send records to Writer thread")
+    };
+    if let [tx] = txs {
+        return if groups.is_empty() {
+            Ok(())
+        } else {
+            send(tx, groups)
+        };
+    }
+    let mut shards = vec![RoutedGroups::default(); txs.len()];
+    for (key, group) in groups {
+        shards[writer_shard(&key, txs.len())].insert(key, group);
+    }
+    for (groups, tx) in shards.into_iter().zip(txs) {
+        if !groups.is_empty() {
+            send(tx, groups)?;
+        }
+    }
+    Ok(())
+}
+
+/// Route reads into the outputs of a [`MultiWriter`] in a single pass.
+///
+/// All `inputs` are read in lockstep, one record of each making up a read.
+/// `route` maps the records of a read to a routing key, or to `None` to drop
+/// the read. The records of the first `mates` inputs are then written to the
+/// outputs named by `output_name(key, mate)` (with `mate` starting from 1);
+/// any further inputs, such as index reads, are only used for routing. Reads
+/// of a key are not necessarily written in input order.
+///
+/// Outputs are written, and compressed, by `writers` threads, each with its
+/// own [`MultiWriter`] built by `new_multi_writer`. The keys are spread over
+/// the writer threads, so each output is still written by a single thread.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn route_reads<W, R, N>(
+    inputs: &[&str],
+    mates: usize,
+    new_multi_writer: W,
+    route: R,
+    output_name: N,
+    input_bar: Option<ProgressBar>,
+    batch_size: usize,
+    chunk_bytes: usize,
+    writers: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> Result<RoutedCounts>
+where
+    W: Fn() -> Result<MultiWriter> + Sync,
+    R: Fn(&[FastqRecord<Bytes>]) -> Result<Option<Bytes>> + Sync,
+    N: Fn(&[u8], usize) -> String + Sync,
+{
+    route_reads_with(
+        inputs,
+        mates,
+        new_multi_writer,
+        |_: &mut (), records: &[FastqRecord<Bytes>]| route(records),
+        output_name,
+        input_bar,
+        batch_size,
+        chunk_bytes,
+        writers,
+        nqueue,
+        threads,
+    )
+    .map(|(counts, _)| counts)
+}
+
+/// [`route_reads()`] keeping a state in each parser thread, such as counts
+/// gathered while routing without locking: `route` is given the state of its
+/// thread, and the states of all threads are returned with the counts.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn route_reads_with<S, W, R, N>(
+    inputs: &[&str],
+    mates: usize,
+    new_multi_writer: W,
+    route: R,
+    output_name: N,
+    input_bar: Option<ProgressBar>,
+    batch_size: usize,
+    chunk_bytes: usize,
+    writers: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> Result<(RoutedCounts, Vec<S>)>
+where
+    S: Default + Send,
+    W: Fn() -> Result<MultiWriter> + Sync,
+    R: Fn(&mut S, &[FastqRecord<Bytes>]) -> Result<Option<Bytes>> + Sync,
+    N: Fn(&[u8], usize) -> String + Sync,
+{
+    let route = &route;
+    let new_multi_writer = &new_multi_writer;
+    let output_name = &output_name;
+    std::thread::scope(|scope| -> Result<(RoutedCounts, Vec<S>)> {
+        let (writer_txs, writer_rxs): (Vec<Sender<RoutedGroups>>, Vec<Receiver<RoutedGroups>>) =
+            (0 .. writers.max(1)).map(|_| new_channel(nqueue)).unzip();
+        let (reader_tx, reader_rx): (
+            Sender<Vec<Vec<FastqRecord<Bytes>>>>,
+            Receiver<Vec<Vec<FastqRecord<Bytes>>>>,
+        ) = new_channel(nqueue);
+
+        // ─── Writer Thread ─────────────────────────────────────
+        let writer_handles = writer_rxs
+            .into_iter()
+            .map(|writer_rx| {
+                scope.spawn(move || -> Result<Vec<(Bytes, usize)>> {
+                    let mut writer = new_multi_writer()?;
+                    let mut counts: HashMap<Bytes, usize> = HashMap::default();
+                    for groups in writer_rx {
+                        for (key, (reads, records)) in groups {
+                            for (mate, records) in records.iter().enumerate() {
+                                writer.write(&output_name(&key, mate + 1), records)?;
+                            }
+                            *counts.entry(key).or_insert(0) += reads;
+                        }
+                    }
+                    writer.finish()?;
+                    Ok(counts.into_iter().collect())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // ─── Parser Thread ─────────────────────────────────────
+        // Group records by routing key, sending the groups to the writer
+        // thread once they hold about `chunk_bytes`
+        let mut parser_handles = Vec::with_capacity(threads);
+        for _ in 0 .. threads {
+            let rx = reader_rx.clone();
+            let txs = writer_txs.clone();
+            let handle = scope.spawn(move || -> Result<(usize, S)> {
+                let mut state = S::default();
+                let mut groups = RoutedGroups::default();
+                let mut pool_bytes = 0;
+                let mut dropped = 0;
+                while let Ok(reads) = rx.recv() {
+                    for records in reads {
+                        let Some(key) = route(&mut state, &records)? else {
+                            dropped += 1;
+                            continue;
+                        };
+                        let group = groups
+                            .entry(key)
+                            .or_insert_with(|| (0, vec![Vec::new(); mates]));
+                        group.0 += 1;
+                        for (record, pool) in records.iter().zip(group.1.iter_mut()) {
+                            pool_bytes += record.bytes_size();
+                            record.extend(pool);
+                        }
+                        if pool_bytes >= chunk_bytes {
+                            send_groups(std::mem::take(&mut groups), &txs)?;
+                            pool_bytes = 0;
+                        }
+                    }
+                }
+                send_groups(groups, &txs)?;
+                Ok((dropped, state))
+            });
+            parser_handles.push(handle);
+        }
+        drop(reader_rx);
+        drop(writer_txs);
+
+        // ─── Reader Thread ─────────────────────────────────────
+        // Inputs are read in lockstep so the records of a read stay together
+        let reader_handle = scope.spawn(move || -> Result<()> {
+            let mut input_bar = input_bar;
+            let mut readers = inputs
+                .iter()
+                .map(|input| -> Result<_> {
+                    Ok(FastqReader::with_capacity(
+                        BUFFER_SIZE,
+                        new_reader(input, BUFFER_SIZE, input_bar.take())?,
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let mut reader_tx = BatchSender::with_capacity(batch_size, reader_tx);
+            loop {
+                let mut records = Vec::with_capacity(readers.len());
+                for (reader, input) in readers.iter_mut().zip(inputs) {
+                    let record = reader.read_record().with_context(|| {
+                        format!("(Reader) Failed to read FASTQ record from {}", input)
+                    })?;
+                    records.extend(record);
+                }
+                if records.is_empty() {
+                    break;
+                }
+                if records.len() < inputs.len() {
+                    return Err(ErrorKind::Pairing.error(format!(
+                        "(Reader) Inputs have different numbers of reads: {}",
+                        inputs.join(", ")
+                    )));
+                }
+                reader_tx
+                    .send(records)
+                    .context("(Reader) Failed to send FASTQ records to Parser thread")?;
+            }
+            reader_tx
+                .flush()
+                .context("(Reader) Failed to flush FASTQ records to Parser thread")?;
+            Ok(())
+        });
+
+        // ─── Join Threads and Propagate Errors ────────────────
+        let mut writer_results = Vec::with_capacity(writer_handles.len());
+        for handler in writer_handles {
+            writer_results.push(
+                handler
+                    .join()
+                    .map_err(|e| anyhow!("(Writer) thread panicked: {:?}", e))?,
+            );
+        }
+        let mut parser_results = Vec::with_capacity(parser_handles.len());
+        for handler in parser_handles {
+            parser_results.push(
+                handler
+                    .join()
+                    .map_err(|e| anyhow!("(Parser) thread panicked: {:?}", e))?,
+            );
+        }
+        let reader_result = reader_handle
+            .join()
+            .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))?;
+        // A writer failure also breaks the parsers' channel, report the cause
+        let mut counts = Vec::new();
+        for result in writer_results {
+            counts.extend(result?);
+        }
+        counts.sort_unstable();
+        let (mut dropped, mut states) = (0, Vec::with_capacity(parser_results.len()));
+        for result in parser_results {
+            let (parser_dropped, state) = result?;
+            dropped += parser_dropped;
+            states.push(state);
+        }
+        reader_result?;
+        Ok(((counts, dropped), states))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::tar::TarMembers;
+
+    #[test]
+    fn test_multi_writer() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let records = [
+            ("AAAC.fastq", "@r1\nACGT\n+\nIIII\n"),
+            ("CCCA.fastq", "@r2\nGGGG\n+\nIIII\n"),
+            ("AAAC.fastq", "@r3\nTTTT\n+\nIIII\n"),
+        ];
+
+        // Directory: the second AAAC record is appended after the first flush
+        let mut writer = MultiWriter::directory(temp.path(), Some(4), 16, None)?;
+        for (name, record) in records {
+            writer.write(name, record.as_bytes())?;
+        }
+        assert_eq!(writer.finish()?, vec!["AAAC.fastq", "CCCA.fastq"]);
+        assert_eq!(
+            std::fs::read_to_string(temp.path().join("AAAC.fastq"))?,
+            "@r1\nACGT\n+\nIIII\n@r3\nTTTT\n+\nIIII\n"
+        );
+
+        // Archive: each output becomes a single gzip-compressed member
+        let archive = temp.path().join("cells.tar");
+        let mut writer = MultiWriter::tar(&archive, Some(4), 16, None)?;
+        for (name, record) in records {
+            writer.write(&format!("{}.gz", name), record.as_bytes())?;
+        }
+        writer.finish()?;
+        let mut out = String::new();
+        flate2::read::MultiGzDecoder::new(TarMembers::new(
+            std::fs::File::open(&archive)?,
+            "AAAC.fastq.gz",
+        ))
+        .read_to_string(&mut out)?;
+        assert_eq!(out, "@r1\nACGT\n+\nIIII\n@r3\nTTTT\n+\nIIII\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_writer_tgz() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        // A compressed archive is read back through its members
+        let archive = temp.path().join("cells.tgz");
+        let mut writer = MultiWriter::tar(&archive, Some(4), 16, None)?;
+        writer.write("AAAC.fastq", b"@r1\nACGT\n+\nIIII\n")?;
+        writer.write("CCCA.fastq", b"@r2\nGGGG\n+\nIIII\n")?;
+        writer.finish()?;
+        let mut out = String::new();
+        new_reader(&temp.path().join("cells.tgz#CCCA.fastq"), BUFFER_SIZE, None)?
+            .read_to_string(&mut out)?;
+        assert_eq!(out, "@r2\nGGGG\n+\nIIII\n");
+        Ok(())
+    }
+}
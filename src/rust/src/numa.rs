@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::OnceLock;
+
+// Thread placement on machines of several NUMA nodes (e.g. dual-socket
+// servers), read from sysfs: threads reading an input run on the node its
+// storage device is attached to, so the data lands in local memory, while
+// parsers are spread over all nodes. On a single node, or off Linux, threads
+// are left where the OS places them.
+
+/// The CPUs of each NUMA node of the machine by node id, empty on a single
+/// node
+pub(crate) fn numa_nodes() -> &'static BTreeMap<usize, Vec<usize>> {
+    static NODES: OnceLock<BTreeMap<usize, Vec<usize>>> = OnceLock::new();
+    NODES.get_or_init(|| {
+        let nodes = read_nodes();
+        if nodes.len() < 2 {
+            BTreeMap::new()
+        } else {
+            nodes
+        }
+    })
+}
+
+/// Parse a sysfs CPU list, e.g. `0-15,32-47`
+fn parse_cpulist(cpulist: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in cpulist.trim().split(',').filter(|x| !x.is_empty()) {
+        match range.split_once('-') {
+            Some((start, end)) => cpus.extend(start.parse::<usize>().ok()? ..= end.parse().ok()?),
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+#[cfg(target_os = "linux")]
+fn read_nodes() -> BTreeMap<usize, Vec<usize>> {
+    read_nodes_in(Path::new("/sys/devices/system/node"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_nodes() -> BTreeMap<usize, Vec<usize>> {
+    BTreeMap::new()
+}
+
+/// The CPUs of the `node<id>` directories of `dir`. Node ids may have gaps
+/// (e.g. offline nodes), and nodes without CPUs (e.g. memory-only nodes) are
+/// left out.
+fn read_nodes_in(dir: &Path) -> BTreeMap<usize, Vec<usize>> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return BTreeMap::new();
+    };
+    entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let id = entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("node")?
+                .parse()
+                .ok()?;
+            let cpus = std::fs::read_to_string(entry.path().join("cpulist")).ok()?;
+            Some((id, parse_cpulist(&cpus)?))
+        })
+        .filter(|(_, cpus)| !cpus.is_empty())
+        .collect()
+}
+
+/// The NUMA node of the storage device holding `path`, if known: the closest
+/// device of the block device (e.g. the PCI device of an NVMe drive) telling
+/// its node. Files of virtual or network file systems have none.
+#[cfg(target_os = "linux")]
+pub(crate) fn storage_node(path: &Path) -> Option<usize> {
+    use std::os::unix::fs::MetadataExt;
+
+    let dev = std::fs::metadata(path).ok()?.dev();
+    // The major and minor numbers of the device, as `major()` and `minor()`
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    let device = std::fs::canonicalize(format!("/sys/dev/block/{}:{}", major, minor)).ok()?;
+    device.ancestors().find_map(|dir| {
+        ["numa_node", "device/numa_node"].iter().find_map(|file| {
+            std::fs::read_to_string(dir.join(file))
+                .ok()
+                .and_then(|x| x.trim().parse::<i64>().ok())
+                // `-1` when the device has no node of its own
+                .filter(|&x| x >= 0)
+                .map(|x| x as usize)
+        })
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn storage_node(_path: &Path) -> Option<usize> {
+    None
+}
+
+/// Pin the current thread to `cpus`. Placement is only a hint: failures,
+/// e.g. CPUs outside of the cgroup of the process, leave the thread as is.
+#[cfg(target_os = "linux")]
+pub(crate) fn pin_thread(cpus: &[usize]) {
+    if cpus.is_empty() {
+        return;
+    }
+    // SAFETY: `set` is a plain bit mask, initialized to no CPU and only read by
+    // `sched_setaffinity()` within its size
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus.iter().filter(|&&x| x < libc::CPU_SETSIZE as usize) {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn pin_thread(_cpus: &[usize]) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpulist() {
+        assert_eq!(
+            parse_cpulist("0-3,8,10-11\n"),
+            Some(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+        assert_eq!(parse_cpulist(""), Some(vec![]));
+        assert_eq!(parse_cpulist("0-x"), None);
+
+        // Nodes are keyed by id, across gaps and nodes without CPUs
+        let sysfs = tempfile::tempdir().unwrap();
+        for (node, cpulist) in [
+            ("node0", "0-1"),
+            ("node1", ""),
+            ("node3", "4,5"),
+            ("nodex", "6"),
+        ] {
+            std::fs::create_dir(sysfs.path().join(node)).unwrap();
+            std::fs::write(sysfs.path().join(node).join("cpulist"), cpulist).unwrap();
+        }
+        std::fs::write(sysfs.path().join("online"), "0-1,3").unwrap();
+        assert_eq!(
+            read_nodes_in(sysfs.path()),
+            BTreeMap::from([(0, vec![0, 1]), (3, vec![4, 5])])
+        );
+
+        // Placement must never fail, whatever the machine
+        let temp = tempfile::tempdir().unwrap();
+        storage_node(temp.path());
+        assert!(numa_nodes().is_empty() || numa_nodes().len() >= 2);
+    }
+}
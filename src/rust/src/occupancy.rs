@@ -0,0 +1,227 @@
+use std::sync::{Arc, Weak};
+use std::thread::{Scope, ScopedJoinHandle};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
+use extendr_api::prelude::*;
+
+// Occupancy of the channels between the stages of a pipeline, sampled at a
+// fixed interval while it runs: a queue mostly full waits on its consumer, a
+// queue mostly empty on its producer, telling which stage holds the pipeline
+// back.
+
+/// Interval between two samples of the queues
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The receiving side of a watched channel, used like a `Receiver`. The
+/// sampler only holds a weak reference to it, so watching a channel never
+/// keeps it open once its consumers are gone.
+pub(crate) struct QueueReceiver<T>(Arc<Receiver<T>>);
+
+impl<T> Clone for QueueReceiver<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> std::ops::Deref for QueueReceiver<T> {
+    type Target = Receiver<T>;
+
+    fn deref(&self) -> &Receiver<T> {
+        &self.0
+    }
+}
+
+impl<T> Iterator for QueueReceiver<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.0.recv().ok()
+    }
+}
+
+/// A queue of the pipeline, its length read while its receiver is alive
+struct Queue<'a> {
+    name: &'static str,
+    capacity: Option<usize>,
+    len: Box<dyn Fn() -> Option<usize> + Send + 'a>,
+}
+
+/// The queues of a pipeline, sampled by a thread of their own
+#[derive(Default)]
+pub(crate) struct QueueSampler<'a> {
+    queues: Vec<Queue<'a>>,
+}
+
+impl<'a> QueueSampler<'a> {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sample the occupancy of the channel of `rx` under `name`
+    pub(crate) fn watch<T: Send + 'a>(
+        &mut self,
+        name: &'static str,
+        rx: Receiver<T>,
+    ) -> QueueReceiver<T> {
+        let rx = Arc::new(rx);
+        let weak: Weak<Receiver<T>> = Arc::downgrade(&rx);
+        self.queues.push(Queue {
+            name,
+            capacity: rx.capacity(),
+            len: Box::new(move || weak.upgrade().map(|rx| rx.len())),
+        });
+        QueueReceiver(rx)
+    }
+
+    /// Sample the queues in a thread of `scope` until the returned handle is
+    /// finished or dropped, e.g. when the pipeline returns early on an error
+    pub(crate) fn spawn<'scope, 'env>(
+        self,
+        scope: &'scope Scope<'scope, 'env>,
+    ) -> SamplerHandle<'scope>
+    where
+        'a: 'scope,
+    {
+        let (stop_tx, stop_rx) = bounded::<()>(0);
+        let handle = scope.spawn(move || {
+            let mut stats: Vec<QueueOccupancy> = self
+                .queues
+                .iter()
+                .map(|queue| QueueOccupancy::new(queue.name, queue.capacity))
+                .collect();
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(SAMPLE_INTERVAL) {
+                for (queue, stats) in self.queues.iter().zip(stats.iter_mut()) {
+                    // Closed queues are no longer sampled
+                    if let Some(len) = (queue.len)() {
+                        stats.add(len);
+                    }
+                }
+            }
+            stats
+        });
+        SamplerHandle { stop_tx, handle }
+    }
+}
+
+/// The thread sampling the queues of a pipeline
+pub(crate) struct SamplerHandle<'scope> {
+    stop_tx: Sender<()>,
+    handle: ScopedJoinHandle<'scope, Vec<QueueOccupancy>>,
+}
+
+impl SamplerHandle<'_> {
+    /// Stop sampling and return the occupancy of each queue
+    pub(crate) fn finish(self) -> Result<Vec<QueueOccupancy>> {
+        drop(self.stop_tx);
+        self.handle
+            .join()
+            .map_err(|e| anyhow!("(Sampler) thread panicked: {:?}", e))
+    }
+}
+
+/// Occupancy of a queue over the samples taken while it was open
+#[derive(Debug, Clone)]
+pub(crate) struct QueueOccupancy {
+    queue: &'static str,
+    // `None` for an unbounded queue
+    capacity: Option<usize>,
+    samples: usize,
+    total: usize,
+    max: usize,
+    // Number of samples finding the queue full, or empty
+    full: usize,
+    empty: usize,
+}
+
+impl QueueOccupancy {
+    fn new(queue: &'static str, capacity: Option<usize>) -> Self {
+        Self {
+            queue,
+            capacity,
+            samples: 0,
+            total: 0,
+            max: 0,
+            full: 0,
+            empty: 0,
+        }
+    }
+
+    fn add(&mut self, len: usize) {
+        self.samples += 1;
+        self.total += len;
+        self.max = self.max.max(len);
+        self.full += self.capacity.is_some_and(|x| len >= x) as usize;
+        self.empty += (len == 0) as usize;
+    }
+
+    /// The occupancy of each queue as the columns of a data frame
+    pub(crate) fn into_list(queues: Vec<Self>) -> List {
+        // Per sample, missing for a run too short to be sampled
+        let per_sample = |x: &Self, n: usize| {
+            if x.samples == 0 {
+                Rfloat::na()
+            } else {
+                Rfloat::from(n as f64 / x.samples as f64)
+            }
+        };
+        list!(
+            queue = queues.iter().map(|x| x.queue).collect::<Vec<_>>(),
+            capacity = queues
+                .iter()
+                .map(|x| x.capacity.map_or(Rfloat::na(), |x| Rfloat::from(x as f64)))
+                .collect::<Doubles>(),
+            mean = queues
+                .iter()
+                .map(|x| per_sample(x, x.total))
+                .collect::<Doubles>(),
+            max = queues.iter().map(|x| x.max as f64).collect::<Vec<_>>(),
+            full = queues
+                .iter()
+                .map(|x| {
+                    if x.capacity.is_some() {
+                        per_sample(x, x.full)
+                    } else {
+                        Rfloat::na()
+                    }
+                })
+                .collect::<Doubles>(),
+            empty = queues
+                .iter()
+                .map(|x| per_sample(x, x.empty))
+                .collect::<Doubles>()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_sampler() -> Result<()> {
+        let (tx, rx) = bounded(2);
+        let queues = std::thread::scope(|scope| -> Result<Vec<QueueOccupancy>> {
+            let mut sampler = QueueSampler::new();
+            let mut rx = sampler.watch("parser", rx);
+            let sampler = sampler.spawn(scope);
+            // A full queue, until the consumer catches up
+            tx.send(1)?;
+            tx.send(2)?;
+            std::thread::sleep(SAMPLE_INTERVAL * 5);
+            drop(tx);
+            assert_eq!(rx.by_ref().sum::<i32>(), 3);
+            drop(rx);
+            sampler.finish()
+        })?;
+        let queue = &queues[0];
+        assert_eq!(
+            (queue.queue, queue.capacity, queue.max),
+            ("parser", Some(2), 2)
+        );
+        assert!(queue.samples > 0 && queue.full > 0);
+        assert!(queue.full <= queue.samples);
+        Ok(())
+    }
+}
@@ -0,0 +1,340 @@
+use std::ffi::OsString;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+
+use crate::record_index::{IndexWriter, IndexedChunk};
+use crate::seekable::{zstd_compressed, SeekTable};
+use crate::utils::*;
+
+/// Decide which part file each output chunk belongs to.
+///
+/// Every chunk sent to a writer thread is a self-contained unit (whole records,
+/// and a complete gzip member for compressed output), so it is always safe to
+/// start a new file between two chunks. `PartCounter` tracks the running size
+/// of one or more parallel outputs (e.g. read1 and read2) and advances all of
+/// them together, so mates always land in corresponding part files.
+pub(crate) struct PartCounter {
+    max_bytes: Option<u64>,
+    part: usize,
+    written: Vec<u64>,
+}
+
+impl PartCounter {
+    pub(crate) fn new(max_bytes: Option<u64>, outputs: usize) -> Self {
+        Self {
+            max_bytes,
+            part: 0,
+            written: vec![0; outputs],
+        }
+    }
+
+    /// Return the part index for the next chunk of each output, given their
+    /// sizes in bytes. A new part is started once any output would exceed the
+    /// size limit; an empty part always accepts the chunk, so a single chunk
+    /// larger than the limit never produces empty files.
+    pub(crate) fn assign(&mut self, sizes: &[usize]) -> usize {
+        if let Some(max_bytes) = self.max_bytes {
+            let overflow = self
+                .written
+                .iter()
+                .zip(sizes)
+                .any(|(&written, &size)| written > 0 && written + size as u64 > max_bytes);
+            if overflow {
+                self.part += 1;
+                self.written.iter_mut().for_each(|w| *w = 0);
+            }
+        }
+        self.written
+            .iter_mut()
+            .zip(sizes)
+            .for_each(|(written, &size)| *written += size as u64);
+        self.part
+    }
+}
+
+/// Build the file path for a given part, inserting `.partNNN` before the file
+/// extension (e.g. `reads.fastq.gz` becomes `reads.part001.fastq.gz`).
+pub(crate) fn part_path(path: &Path, part: usize) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let (mut stem, mut ext) = (name.as_str(), String::new());
+    if gz_compressed(path) || zstd_compressed(path) {
+        if let Some(pos) = stem.rfind('.') {
+            ext = stem[pos ..].to_string();
+            stem = &stem[.. pos];
+        }
+    }
+    if let Some(pos) = stem.rfind('.').filter(|&pos| pos > 0) {
+        ext = format!("{}{}", &stem[pos ..], ext);
+        stem = &stem[.. pos];
+    }
+    let mut file_name = OsString::from(stem);
+    file_name.push(format!(".part{:03}{}", part + 1, ext));
+    path.with_file_name(file_name)
+}
+
+/// A writer which rolls over into numbered part files.
+///
+/// When no size limit is configured, data is written to `path` unchanged.
+/// Otherwise, parts are named by [`part_path`] and a new file is opened every
+/// time the requested part index advances. Chunks of `.zst` outputs are zstd
+/// frames, each part file ends with the seek table of its frames.
+///
+/// An indexed writer also records where each record is written in the sidecar
+/// index of the output (see [`IndexWriter`]).
+pub(crate) struct PartWriter<'a> {
+    path: &'a Path,
+    rotate: bool,
+    buffer_size: usize,
+    bar: Option<ProgressBar>,
+    part: usize,
+    part_path: PathBuf,
+    // Bytes written to the current part file
+    written: u64,
+    // Whether any part file was opened, an output without records is still
+    // created as an empty file
+    opened: bool,
+    writer: Option<BufWriter<Box<dyn Write>>>,
+    seek_table: Option<SeekTable>,
+    index: Option<IndexWriter>,
+}
+
+impl<'a> PartWriter<'a> {
+    pub(crate) fn new(
+        path: &'a Path,
+        max_bytes: Option<u64>,
+        buffer_size: usize,
+        bar: Option<ProgressBar>,
+    ) -> Self {
+        Self {
+            path,
+            rotate: max_bytes.is_some(),
+            buffer_size,
+            bar,
+            part: 0,
+            part_path: path.to_path_buf(),
+            written: 0,
+            opened: false,
+            writer: None,
+            seek_table: None,
+            index: None,
+        }
+    }
+
+    /// Write the sidecar index of the output as well
+    pub(crate) fn with_index(mut self, index: bool) -> Result<Self> {
+        if index {
+            self.index = Some(IndexWriter::new(self.path)?);
+        }
+        Ok(self)
+    }
+
+    /// Write a complete chunk into the given part file.
+    pub(crate) fn write_part(&mut self, part: usize, chunk: &[u8]) -> Result<()> {
+        if self.writer.is_some() && part != self.part {
+            self.close_part()?;
+        }
+        if self.writer.is_none() {
+            self.open_part(part)?;
+        }
+        if let Some(seek_table) = &mut self.seek_table {
+            seek_table.push_frame(chunk)?;
+        }
+        // Safety: writer was initialized above
+        let writer = self.writer.as_mut().unwrap();
+        writer
+            .write_all(chunk)
+            .with_context(|| format!("Failed to write to {}", self.part_path.display()))?;
+        self.written += chunk.len() as u64;
+        Ok(())
+    }
+
+    fn open_part(&mut self, part: usize) -> Result<()> {
+        self.part = part;
+        self.part_path = if self.rotate {
+            part_path(self.path, part)
+        } else {
+            self.path.to_path_buf()
+        };
+        self.writer = Some(BufWriter::with_capacity(
+            self.buffer_size,
+            new_writer(&self.part_path, self.bar.clone())?,
+        ));
+        self.opened = true;
+        self.written = 0;
+        self.seek_table = zstd_compressed(&self.part_path).then(SeekTable::default);
+        Ok(())
+    }
+
+    /// Write a complete chunk into the given part file, and its records into
+    /// the index if any
+    pub(crate) fn write_records(&mut self, part: usize, chunk: &IndexedChunk) -> Result<()> {
+        self.write_part(part, &chunk.0)?;
+        if let Some(index) = &mut self.index {
+            let offset = self.written - chunk.0.len() as u64;
+            index.add(&self.part_path, offset, &chunk.1)?;
+        }
+        Ok(())
+    }
+
+    /// Flush and close the current part file, and the index if any. Without
+    /// any chunk written, the first part is created empty.
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        if !self.opened {
+            self.open_part(0)?;
+        }
+        self.close_part()?;
+        if let Some(index) = self.index.take() {
+            index.finish()?;
+        }
+        Ok(())
+    }
+
+    fn close_part(&mut self) -> Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            if let Some(seek_table) = self.seek_table.take() {
+                seek_table.write_to(&mut writer).with_context(|| {
+                    format!("Failed to write seek table to {}", self.part_path.display())
+                })?;
+            }
+            writer.complete().with_context(|| {
+                format!("Failed to flush writer for {}", self.part_path.display())
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the records of a single output in chunks, compressed by the
+/// extension of the output
+pub(crate) struct ChunkedWriter<'a> {
+    writer: PartWriter<'a>,
+    packer: ChunkPacker,
+    pool: Vec<u8>,
+    chunk_bytes: usize,
+}
+
+impl<'a> ChunkedWriter<'a> {
+    pub(crate) fn new(path: &'a Path, compression: Compression, chunk_bytes: usize) -> Self {
+        Self {
+            writer: PartWriter::new(path, None, chunk_bytes, None),
+            packer: ChunkPacker::new(compression),
+            pool: Vec::with_capacity(chunk_bytes),
+            chunk_bytes,
+        }
+    }
+
+    pub(crate) fn write(&mut self, record: &[u8]) -> Result<()> {
+        if !self.pool.is_empty() && self.pool.len() + record.len() > self.chunk_bytes {
+            self.flush()?;
+        }
+        self.pool.extend_from_slice(record);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let pool = std::mem::replace(&mut self.pool, Vec::with_capacity(self.chunk_bytes));
+        let pack = self.packer.pack(pool)?;
+        self.writer
+            .write_part(0, &pack)
+            .context("(Writer) Failed to write records")
+    }
+
+    pub(crate) fn finish(mut self) -> Result<()> {
+        if !self.pool.is_empty() {
+            self.flush()?;
+        }
+        self.writer
+            .finish()
+            .context("(Writer) Failed to flush writer")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_part_path() {
+        assert_eq!(
+            part_path(Path::new("out/reads.fastq.gz"), 0),
+            PathBuf::from("out/reads.part001.fastq.gz")
+        );
+        assert_eq!(
+            part_path(Path::new("out/reads.fastq.zst"), 0),
+            PathBuf::from("out/reads.part001.fastq.zst")
+        );
+        assert_eq!(
+            part_path(Path::new("sample.R1.fq"), 11),
+            PathBuf::from("sample.R1.part012.fq")
+        );
+        assert_eq!(
+            part_path(Path::new("koutput"), 1),
+            PathBuf::from("koutput.part002")
+        );
+    }
+
+    #[test]
+    fn test_part_counter_keeps_outputs_aligned() {
+        let mut counter = PartCounter::new(Some(10), 2);
+        assert_eq!(counter.assign(&[6, 4]), 0);
+        // read2 still fits, but read1 would overflow: both rotate
+        assert_eq!(counter.assign(&[6, 4]), 1);
+        // a chunk larger than the limit is accepted into an empty part
+        assert_eq!(counter.assign(&[20, 2]), 2);
+        assert_eq!(counter.assign(&[1, 1]), 3);
+
+        let mut counter = PartCounter::new(None, 1);
+        assert_eq!(counter.assign(&[usize::MAX / 2]), 0);
+        assert_eq!(counter.assign(&[usize::MAX / 2]), 0);
+    }
+
+    #[test]
+    fn test_part_writer_rotates() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let path = temp.path().join("out.txt");
+        let mut counter = PartCounter::new(Some(8), 1);
+        let mut writer = PartWriter::new(&path, Some(8), 1024, None);
+        for chunk in [b"aaaa\n".as_slice(), b"bbbb\n", b"cccc\n"] {
+            writer.write_part(counter.assign(&[chunk.len()]), chunk)?;
+        }
+        writer.finish()?;
+        assert_eq!(
+            std::fs::read(temp.path().join("out.part001.txt"))?,
+            b"aaaa\n"
+        );
+        assert_eq!(
+            std::fs::read(temp.path().join("out.part002.txt"))?,
+            b"bbbb\n"
+        );
+        assert_eq!(
+            std::fs::read(temp.path().join("out.part003.txt"))?,
+            b"cccc\n"
+        );
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_part_writer_without_records() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        // Outputs without any matching record are still created, empty
+        for (name, max_bytes, created) in [
+            ("out.fastq.gz", None, "out.fastq.gz"),
+            ("out.txt", Some(8), "out.part001.txt"),
+        ] {
+            let path = temp.path().join(name);
+            PartWriter::new(&path, max_bytes, 1024, None).finish()?;
+            assert_eq!(std::fs::read(temp.path().join(created))?, b"");
+        }
+        let path = temp.path().join("table.tsv");
+        ChunkedWriter::new(&path, Compression::of(Some(&path), None)?, 1024).finish()?;
+        assert_eq!(std::fs::read(&path)?, b"");
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+use std::sync::Mutex;
+
+use extendr_api::prelude::*;
+use indicatif::style::TemplateError;
+use indicatif::{
+    DecimalBytes, HumanCount, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget,
+    ProgressStyle,
+};
+
+use crate::error::{ErrorKind, RError};
+
+const READER_TEMPLATE: &str = "{prefix:.bold.cyan/blue} {decimal_bytes}/{decimal_total_bytes} {spinner:.green} [{elapsed_precise}] {decimal_bytes_per_sec} (ETA {eta})";
+const RECORDS_TEMPLATE: &str =
+    "{prefix:.bold.cyan/blue} {human_pos} records {spinner:.green} [{elapsed_precise}] {per_sec}";
+const WRITER_TEMPLATE: &str =
+    "{prefix:.bold.cyan/blue} {decimal_bytes} {spinner:.green} {decimal_bytes_per_sec}";
+// Redraws per second, the default of indicatif
+const REFRESH_RATE: u8 = 20;
+
+/// How progress bars are drawn, set in R by `rust_call()` from the options
+/// of `mire_progress()` before each call
+struct ProgressSettings {
+    quiet: bool,
+    reader: Option<String>,
+    records: Option<String>,
+    writer: Option<String>,
+    refresh_rate: u8,
+    // Whether the bars are kept for the summaries of the run log
+    record: bool,
+}
+
+static SETTINGS: Mutex<ProgressSettings> = Mutex::new(ProgressSettings {
+    quiet: false,
+    reader: None,
+    records: None,
+    writer: None,
+    refresh_rate: REFRESH_RATE,
+    record: false,
+});
+
+/// What the position of a bar counts
+#[derive(Clone, Copy)]
+pub(crate) enum ProgressUnit {
+    Bytes,
+    Records,
+}
+
+/// The bars drawn since the last `progress_summaries()`, while a run log is
+/// open in R
+static RECORDED: Mutex<Vec<(ProgressBar, ProgressUnit)>> = Mutex::new(Vec::new());
+
+#[extendr]
+fn progress_settings(
+    quiet: bool,
+    reader: Option<String>,
+    records: Option<String>,
+    writer: Option<String>,
+    refresh_rate: f64,
+    record: bool,
+) -> std::result::Result<(), RError> {
+    for (name, template) in [
+        ("reader", &reader),
+        ("records", &records),
+        ("writer", &writer),
+    ] {
+        if let Some(template) = template {
+            ProgressStyle::with_template(template).map_err(|e| {
+                RError::from(
+                    ErrorKind::Config
+                        .error(format!("Invalid progress template of the {}: {}", name, e)),
+                )
+            })?;
+        }
+    }
+    if !(1.0 ..= 255.0).contains(&refresh_rate) {
+        return Err(RError::from(ErrorKind::Config.error(format!(
+            "Invalid progress refresh rate {}: rates range from 1 to 255 per second",
+            refresh_rate
+        ))));
+    }
+    *SETTINGS.lock().unwrap_or_else(|e| e.into_inner()) = ProgressSettings {
+        quiet,
+        reader,
+        records,
+        writer,
+        refresh_rate: refresh_rate as u8,
+        record,
+    };
+    if !record {
+        RECORDED.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+    Ok(())
+}
+
+/// The final state of the bars drawn since the last call, to be written to
+/// the run log
+#[extendr]
+fn progress_summaries() -> List {
+    let bars = std::mem::take(&mut *RECORDED.lock().unwrap_or_else(|e| e.into_inner()));
+    let mut prefix = Vec::with_capacity(bars.len());
+    let mut unit = Vec::with_capacity(bars.len());
+    let mut position = Vec::with_capacity(bars.len());
+    let mut length = Vec::with_capacity(bars.len());
+    let mut seconds = Vec::with_capacity(bars.len());
+    let mut summary = Vec::with_capacity(bars.len());
+    for (bar, bar_unit) in bars {
+        summary.push(bar_summary(&bar, bar_unit));
+        prefix.push(bar.prefix());
+        unit.push(match bar_unit {
+            ProgressUnit::Bytes => "bytes",
+            ProgressUnit::Records => "records",
+        });
+        position.push(bar.position() as f64);
+        length.push(bar.length().map(|x| x as f64));
+        seconds.push(bar.elapsed().as_secs_f64());
+    }
+    list!(
+        prefix = prefix,
+        unit = unit,
+        position = position,
+        length = length,
+        seconds = seconds,
+        summary = summary
+    )
+}
+
+fn bar_summary(bar: &ProgressBar, unit: ProgressUnit) -> String {
+    let elapsed = bar.elapsed();
+    let pos = bar.position();
+    let per_sec = (pos as f64 / elapsed.as_secs_f64().max(1e-3)) as u64;
+    let (done, rate) = match unit {
+        ProgressUnit::Bytes => (
+            match bar.length() {
+                Some(len) => format!("{}/{}", DecimalBytes(pos), DecimalBytes(len)),
+                None => DecimalBytes(pos).to_string(),
+            },
+            format!("{}/s", DecimalBytes(per_sec)),
+        ),
+        ProgressUnit::Records => (
+            format!("{} records", HumanCount(pos)),
+            format!("{} records/s", HumanCount(per_sec)),
+        ),
+    };
+    format!(
+        "{}: {} in {} ({})",
+        bar.prefix(),
+        done,
+        HumanDuration(elapsed),
+        rate
+    )
+}
+
+extendr_module! {
+    mod progress;
+    fn progress_settings;
+    fn progress_summaries;
+}
+
+fn style(
+    template: impl Fn(&ProgressSettings) -> Option<&str>,
+    default: &str,
+) -> std::result::Result<ProgressStyle, TemplateError> {
+    let settings = SETTINGS.lock().unwrap_or_else(|e| e.into_inner());
+    ProgressStyle::with_template(template(&settings).unwrap_or(default))
+}
+
+/// The style of the bars of the bytes read from inputs
+pub(crate) fn progress_reader_style() -> std::result::Result<ProgressStyle, TemplateError> {
+    style(|x| x.reader.as_deref(), READER_TEMPLATE)
+}
+
+/// The style of the spinners of the records parsed from inputs of unknown
+/// size
+pub(crate) fn progress_records_style() -> std::result::Result<ProgressStyle, TemplateError> {
+    style(|x| x.records.as_deref(), RECORDS_TEMPLATE)
+}
+
+/// The style of the spinners of the bytes written to outputs
+pub(crate) fn progress_writer_style() -> std::result::Result<ProgressStyle, TemplateError> {
+    style(|x| x.writer.as_deref(), WRITER_TEMPLATE)
+}
+
+/// Where progress bars are drawn: nowhere when quiet
+pub(crate) fn progress_target() -> ProgressDrawTarget {
+    let settings = SETTINGS.lock().unwrap_or_else(|e| e.into_inner());
+    if settings.quiet {
+        ProgressDrawTarget::hidden()
+    } else {
+        ProgressDrawTarget::stderr_with_hz(settings.refresh_rate)
+    }
+}
+
+/// Keep `bar` for the summaries of the run log, if one is open
+pub(crate) fn record_bar(bar: &ProgressBar, unit: ProgressUnit) {
+    let settings = SETTINGS.lock().unwrap_or_else(|e| e.into_inner());
+    if settings.record {
+        RECORDED
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((bar.clone(), unit));
+    }
+}
+
+/// The bars of a run, drawn together
+pub(crate) fn new_progress() -> MultiProgress {
+    MultiProgress::with_draw_target(progress_target())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_settings() {
+        assert!(
+            progress_settings(false, Some("{pos:x}".to_string()), None, None, 20.0, false).is_err()
+        );
+        assert!(progress_settings(false, None, None, None, 0.0, false).is_err());
+        progress_settings(
+            true,
+            None,
+            Some("{pos} reads".to_string()),
+            None,
+            5.0,
+            false,
+        )
+        .unwrap();
+        assert!(progress_target().is_hidden());
+        assert!(progress_records_style().is_ok());
+        progress_settings(false, None, None, None, REFRESH_RATE as f64, false).unwrap();
+
+        let bar = ProgressBar::hidden();
+        bar.set_prefix("Reading fastq");
+        bar.set_length(2_000_000);
+        bar.inc(1_500_000);
+        let summary = bar_summary(&bar, ProgressUnit::Bytes);
+        assert!(
+            summary.starts_with("Reading fastq: 1.50 MB/2.00 MB in "),
+            "{}",
+            summary
+        );
+        bar.set_length(0);
+        assert!(bar_summary(&bar, ProgressUnit::Records).contains("1,500,000 records in "));
+    }
+}
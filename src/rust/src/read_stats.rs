@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+
+use extendr_api::prelude::*;
+
+// Phred quality scores are encoded with an offset of 33
+const QUALITY_OFFSET: u8 = 33;
+
+/// Base composition and quality statistics of a set of reads, in the spirit
+/// of FastQC but collected during a pass which reads the records anyway.
+///
+/// Each parser thread keeps its own `ReadStats` and the results are merged
+/// once all threads have joined.
+#[derive(Default, Clone)]
+pub(crate) struct ReadStats {
+    pub(crate) reads: usize,
+    pub(crate) bases: usize,
+    quality: u64,
+    gc: usize,
+    n: usize,
+    // Per cycle: sum of qualities, number of bases and of N bases
+    cycle_quality: Vec<u64>,
+    cycle_bases: Vec<u64>,
+    cycle_n: Vec<u64>,
+    pub(crate) lengths: BTreeMap<usize, usize>,
+    // Number of reads for each GC content percentage
+    gc_content: BTreeMap<usize, usize>,
+}
+
+impl ReadStats {
+    pub(crate) fn add(&mut self, seq: &[u8], qual: &[u8]) {
+        let len = seq.len();
+        self.reads += 1;
+        self.bases += len;
+        *self.lengths.entry(len).or_insert(0) += 1;
+        if self.cycle_bases.len() < len {
+            self.cycle_quality.resize(len, 0);
+            self.cycle_bases.resize(len, 0);
+            self.cycle_n.resize(len, 0);
+        }
+        let mut gc = 0;
+        for (cycle, (base, q)) in seq.iter().zip(qual).enumerate() {
+            let q = q.saturating_sub(QUALITY_OFFSET) as u64;
+            self.quality += q;
+            self.cycle_quality[cycle] += q;
+            self.cycle_bases[cycle] += 1;
+            match base {
+                b'G' | b'C' | b'g' | b'c' => gc += 1,
+                b'N' | b'n' => {
+                    self.n += 1;
+                    self.cycle_n[cycle] += 1;
+                }
+                _ => {}
+            }
+        }
+        self.gc += gc;
+        if let Some(percent) = (gc * 100).checked_div(len) {
+            *self.gc_content.entry(percent).or_insert(0) += 1;
+        }
+    }
+
+    pub(crate) fn merge(&mut self, other: Self) {
+        self.reads += other.reads;
+        self.bases += other.bases;
+        self.quality += other.quality;
+        self.gc += other.gc;
+        self.n += other.n;
+        if self.cycle_bases.len() < other.cycle_bases.len() {
+            let len = other.cycle_bases.len();
+            self.cycle_quality.resize(len, 0);
+            self.cycle_bases.resize(len, 0);
+            self.cycle_n.resize(len, 0);
+        }
+        for (cycle, bases) in other.cycle_bases.into_iter().enumerate() {
+            self.cycle_quality[cycle] += other.cycle_quality[cycle];
+            self.cycle_bases[cycle] += bases;
+            self.cycle_n[cycle] += other.cycle_n[cycle];
+        }
+        for (len, count) in other.lengths {
+            *self.lengths.entry(len).or_insert(0) += count;
+        }
+        for (gc, count) in other.gc_content {
+            *self.gc_content.entry(gc).or_insert(0) += count;
+        }
+    }
+
+    /// Minimum, mean, median, N50 and maximum read length, which summarise
+    /// the yield of long reads better than read counts do
+    fn length_summary(&self) -> [f64; 5] {
+        let (Some(min), Some(max)) = (self.lengths.keys().next(), self.lengths.keys().last())
+        else {
+            return [f64::NAN; 5];
+        };
+        let mut median = 0;
+        let mut reads = 0;
+        for (len, count) in &self.lengths {
+            reads += count;
+            if reads * 2 >= self.reads {
+                median = *len;
+                break;
+            }
+        }
+        let mut n50 = 0;
+        let mut bases = 0;
+        for (len, count) in self.lengths.iter().rev() {
+            bases += len * count;
+            if bases * 2 >= self.bases {
+                n50 = *len;
+                break;
+            }
+        }
+        [
+            *min as f64,
+            Self::ratio(self.bases as f64, self.reads),
+            median as f64,
+            n50 as f64,
+            *max as f64,
+        ]
+    }
+
+    fn ratio(x: impl Into<f64>, total: usize) -> f64 {
+        if total == 0 {
+            f64::NAN
+        } else {
+            x.into() / total as f64
+        }
+    }
+}
+
+/// Statistics of several sets of reads, each named by its input and group
+/// (e.g., matched or unmatched reads), converted into R tables. The yield of
+/// the successive stages of filtering is reported as well.
+#[derive(Default)]
+pub(crate) struct ReadStatsTables(
+    Vec<(String, String, ReadStats)>,
+    Vec<(String, String, ReadStats)>,
+);
+
+impl ReadStatsTables {
+    pub(crate) fn push(&mut self, input: &str, group: &str, stats: ReadStats) {
+        self.0.push((input.to_string(), group.to_string(), stats));
+    }
+
+    /// Add the reads remaining after a stage of filtering
+    pub(crate) fn push_stage(&mut self, input: &str, stage: &str, stats: ReadStats) {
+        self.1.push((input.to_string(), stage.to_string(), stats));
+    }
+
+    /// Convert to an R list of `summary`, `cycles`, `lengths`, `gc` and
+    /// `yield` tables
+    pub(crate) fn into_list(self) -> List {
+        let labels = |n: fn(&ReadStats) -> usize| {
+            let mut inputs = Vec::new();
+            let mut groups = Vec::new();
+            for (input, group, stats) in &self.0 {
+                inputs.extend(std::iter::repeat_n(input.as_str(), n(stats)));
+                groups.extend(std::iter::repeat_n(group.as_str(), n(stats)));
+            }
+            (inputs, groups)
+        };
+        let summary = {
+            let (input, group) = labels(|_| 1);
+            let column =
+                |f: fn(&ReadStats) -> f64| self.0.iter().map(|x| f(&x.2)).collect::<Vec<_>>();
+            list!(
+                input = input,
+                group = group,
+                reads = column(|x| x.reads as f64),
+                bases = column(|x| x.bases as f64),
+                mean_quality = column(|x| ReadStats::ratio(x.quality as f64, x.bases)),
+                gc_content = column(|x| ReadStats::ratio(x.gc as f64, x.bases)),
+                n_rate = column(|x| ReadStats::ratio(x.n as f64, x.bases))
+            )
+        };
+        let cycles = {
+            let (input, group) = labels(|x| x.cycle_bases.len());
+            let mut cycle = Vec::new();
+            let mut quality = Vec::new();
+            let mut n = Vec::new();
+            for (_, _, stats) in &self.0 {
+                for (i, bases) in stats.cycle_bases.iter().enumerate() {
+                    cycle.push((i + 1) as f64);
+                    quality.push(ReadStats::ratio(
+                        stats.cycle_quality[i] as f64,
+                        *bases as usize,
+                    ));
+                    n.push(ReadStats::ratio(stats.cycle_n[i] as f64, *bases as usize));
+                }
+            }
+            list!(
+                input = input,
+                group = group,
+                cycle = cycle,
+                mean_quality = quality,
+                n_rate = n
+            )
+        };
+        let histogram = |n: fn(&ReadStats) -> usize,
+                         f: fn(&ReadStats) -> &BTreeMap<usize, usize>| {
+            let (input, group) = labels(n);
+            let (values, reads): (Vec<_>, Vec<_>) = self
+                .0
+                .iter()
+                .flat_map(|x| f(&x.2).iter().map(|(k, v)| (*k as f64, *v as f64)))
+                .unzip();
+            (input, group, values, reads)
+        };
+        let (input, group, length, reads) = histogram(|x| x.lengths.len(), |x| &x.lengths);
+        let lengths = list!(input = input, group = group, length = length, reads = reads);
+        let (input, group, gc, reads) = histogram(|x| x.gc_content.len(), |x| &x.gc_content);
+        let gc = list!(input = input, group = group, gc = gc, reads = reads);
+        let yields = {
+            let (input, stage): (Vec<_>, Vec<_>) =
+                self.1.iter().map(|x| (x.0.as_str(), x.1.as_str())).unzip();
+            let lengths = self
+                .1
+                .iter()
+                .map(|x| x.2.length_summary())
+                .collect::<Vec<_>>();
+            let column = |i: usize| lengths.iter().map(|x| x[i]).collect::<Vec<_>>();
+            list!(
+                input = input,
+                stage = stage,
+                reads = self.1.iter().map(|x| x.2.reads as f64).collect::<Vec<_>>(),
+                bases = self.1.iter().map(|x| x.2.bases as f64).collect::<Vec<_>>(),
+                min_length = column(0),
+                mean_length = column(1),
+                median_length = column(2),
+                n50 = column(3),
+                max_length = column(4)
+            )
+        };
+        // `yield` is a keyword, the list cannot be built with `list!`
+        List::from_names_and_values(
+            ["summary", "cycles", "lengths", "gc", "yield"],
+            [summary, cycles, lengths, gc, yields],
+        )
+        .expect("names and values have the same length")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_stats() {
+        let mut left = ReadStats::default();
+        left.add(b"ACGN", b"IIII");
+        let mut right = ReadStats::default();
+        right.add(b"GGCCAA", b"!!!!!!");
+        left.merge(right);
+        assert_eq!(left.reads, 2);
+        assert_eq!(left.bases, 10);
+        assert_eq!(left.gc, 6);
+        assert_eq!(left.n, 1);
+        // 'I' is quality 40 and '!' quality 0
+        assert_eq!(left.quality, 160);
+        assert_eq!(left.cycle_quality, vec![40, 40, 40, 40, 0, 0]);
+        assert_eq!(left.cycle_bases, vec![2, 2, 2, 2, 1, 1]);
+        assert_eq!(left.cycle_n, vec![0, 0, 0, 1, 0, 0]);
+        assert_eq!(
+            left.lengths.into_iter().collect::<Vec<_>>(),
+            vec![(4, 1), (6, 1)]
+        );
+        assert_eq!(
+            left.gc_content.into_iter().collect::<Vec<_>>(),
+            vec![(50, 1), (66, 1)]
+        );
+
+        let mut stats = ReadStats::default();
+        for len in [2, 2, 3, 10] {
+            stats.add(&vec![b'A'; len], &vec![b'I'; len]);
+        }
+        assert_eq!(stats.length_summary(), [2.0, 4.25, 2.0, 10.0, 10.0]);
+        assert!(ReadStats::default().length_summary()[0].is_nan());
+    }
+}
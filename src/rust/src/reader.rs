@@ -1,9 +1,14 @@
+use std::cell::Cell;
 use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
 
 use bytes::BytesMut;
 use indicatif::ProgressBar;
 use memchr::memchr;
 
+use crate::utils::CompleteWrite;
+
 pub(crate) struct ProgressBarReader<R> {
     bar: ProgressBar,
     reader: R,
@@ -45,6 +50,70 @@ impl<W: Write> Write for ProgressBarWriter<W> {
     }
 }
 
+impl<W: CompleteWrite> CompleteWrite for ProgressBarWriter<W> {
+    fn complete(&mut self) -> std::io::Result<()> {
+        self.writer.complete()
+    }
+}
+
+/// Counts the bytes read from a compressed input into a shared offset, for
+/// [`GzipInput`] to report where decoding failed
+pub(crate) struct OffsetReader<R> {
+    reader: R,
+    offset: Rc<Cell<u64>>,
+}
+
+impl<R> OffsetReader<R> {
+    pub(crate) fn new(reader: R, offset: Rc<Cell<u64>>) -> Self {
+        Self { reader, offset }
+    }
+}
+
+impl<R: Read> Read for OffsetReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let nbytes = self.reader.read(buf)?;
+        self.offset.set(self.offset.get() + nbytes as u64);
+        Ok(nbytes)
+    }
+}
+
+/// The decompressed stream of a gzip input. Decoders fail on corrupt data, on
+/// a CRC or length mismatch in a member trailer, and on a truncated last
+/// member; their error is reported with the input and the number of its
+/// compressed bytes read so far, rather than as a bare decoder error, so that
+/// an incomplete input never passes for a shorter one.
+pub(crate) struct GzipInput<R> {
+    decoder: R,
+    path: PathBuf,
+    offset: Rc<Cell<u64>>,
+}
+
+impl<R> GzipInput<R> {
+    pub(crate) fn new(decoder: R, path: PathBuf, offset: Rc<Cell<u64>>) -> Self {
+        Self {
+            decoder,
+            path,
+            offset,
+        }
+    }
+}
+
+impl<R: Read> Read for GzipInput<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.decoder.read(buf).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!(
+                    "Corrupt or truncated gzip input {} (after {} compressed bytes): {}",
+                    self.path.display(),
+                    self.offset.get(),
+                    e
+                ),
+            )
+        })
+    }
+}
+
 /// LineReader: Efficient zero-copy line-based reader using BytesMut.
 ///
 /// This reader avoids unnecessary heap allocations and copying by:
@@ -89,21 +158,21 @@ impl<R: Read> LineReader<R> {
         loop {
             self.fill_buf()?;
             if let Some(buffer) = self.buffer.as_mut() {
-                if let Some(pos) = memchr(b'\n', &buffer) {
+                if let Some(pos) = memchr(b'\n', buffer) {
                     // Fast path: newline found
                     let mut buf = buffer.split_to(pos + 1);
-                    let end = if pos > 0 && buf[pos - 1] == b'\r' {
-                        pos - 1
-                    } else {
-                        pos
-                    };
-                    let line = if let Some(mut leftover) = self.leftover.take() {
-                        leftover.extend_from_slice(&buf[.. end]);
+                    let mut line = if let Some(mut leftover) = self.leftover.take() {
+                        leftover.extend_from_slice(&buf[.. pos]);
                         leftover
                     } else {
                         // Directly build from slice without heap copying if possible
-                        buf.split_to(end)
+                        buf.truncate(pos);
+                        buf
                     };
+                    // The CR of a CRLF ending may be left in the previous buffer
+                    if line.last() == Some(&b'\r') {
+                        line.truncate(line.len() - 1);
+                    }
                     self.offset += 1;
                     return Ok(Some(line));
                 }
@@ -116,7 +185,9 @@ impl<R: Read> LineReader<R> {
                     std::mem::swap(&mut self.buffer, &mut self.leftover);
                 }
             } else {
-                let left = std::mem::take(&mut self.leftover);
+                // A fully consumed buffer leaves an empty leftover behind,
+                // which must not be reported as a trailing empty line
+                let left = std::mem::take(&mut self.leftover).filter(|x| !x.is_empty());
                 if left.is_some() {
                     self.offset += 1;
                 }
@@ -147,7 +218,7 @@ mod tests {
 
     use indicatif::ProgressBar;
 
-    use super::ProgressBarReader;
+    use super::{LineReader, ProgressBarReader};
 
     // Mock input for testing
     fn get_test_data() -> Vec<u8> {
@@ -175,4 +246,35 @@ mod tests {
         // The progress bar should have updated correctly
         assert_eq!(pb.position(), data.len() as u64);
     }
+
+    fn read_lines(data: &[u8], capacity: usize) -> Vec<Vec<u8>> {
+        let mut reader = LineReader::with_capacity(capacity, Cursor::new(data));
+        let mut lines = Vec::new();
+        while let Some(line) = reader.read_line().unwrap() {
+            lines.push(line.to_vec());
+        }
+        assert_eq!(reader.offset(), lines.len());
+        lines
+    }
+
+    #[test]
+    fn test_line_reader_crlf() {
+        // The CR and LF of a line ending may be read into different buffers
+        for capacity in [1, 2, 3, 64] {
+            assert_eq!(
+                read_lines(b"a\r\nbc\nd", capacity),
+                vec![b"a".to_vec(), b"bc".to_vec(), b"d".to_vec()]
+            );
+        }
+    }
+
+    #[test]
+    fn test_line_reader_buffer_end() {
+        // Each line ends exactly at the end of the buffer, leaving it fully
+        // consumed before the end of the input
+        assert_eq!(
+            read_lines(b"ab\ncd\n", 3),
+            vec![b"ab".to_vec(), b"cd".to_vec()]
+        );
+    }
 }
@@ -0,0 +1,312 @@
+use bytes::Bytes;
+use rustc_hash::FxHashMap as HashMap;
+use rustc_hash::FxHashSet as HashSet;
+
+use crate::fastq_record::FastqRecord;
+
+// Phred quality scores are encoded with an offset of 33
+const QUALITY_OFFSET: u8 = 33;
+
+/// A filter of reads, given the records of all their mates (one record for
+/// single-end reads, two for paired-end reads).
+///
+/// Filters are composed with a [`FilterChain`], which keeps the reads
+/// accepted by every filter and transforms them with each filter in turn.
+///
+/// ```
+/// use bytes::Bytes;
+/// use mire::fastq_record::FastqRecord;
+/// use mire::record_filter::{FilterChain, LengthFilter, RecordFilter};
+///
+/// /// Drop reads whose first mate starts with a poly-G run
+/// struct PolyG;
+///
+/// impl RecordFilter for PolyG {
+///     fn accept(&self, records: &[&FastqRecord<Bytes>]) -> bool {
+///         !records[0].seq.starts_with(b"GGGGGGGGGG")
+///     }
+/// }
+///
+/// let filters = FilterChain::new()
+///     .with(LengthFilter::new(Some(4), None))
+///     .with(PolyG);
+/// let record = FastqRecord::new(
+///     Bytes::from("r1"),
+///     None,
+///     Bytes::from("ACGT"),
+///     Bytes::from("+"),
+///     Bytes::from("IIII"),
+/// );
+/// assert!(filters.accept(&[&record]));
+/// ```
+pub trait RecordFilter: Send + Sync {
+    /// Whether to keep a read
+    fn accept(&self, records: &[&FastqRecord<Bytes>]) -> bool;
+
+    /// Transform each record of a kept read, records are kept as is by default
+    fn transform(&self, record: FastqRecord<Bytes>) -> FastqRecord<Bytes> {
+        record
+    }
+
+    /// Transform both records of a kept paired-end read, each with
+    /// [`RecordFilter::transform`] by default
+    fn transform_pair(
+        &self,
+        record1: FastqRecord<Bytes>,
+        record2: FastqRecord<Bytes>,
+    ) -> (FastqRecord<Bytes>, FastqRecord<Bytes>) {
+        (self.transform(record1), self.transform(record2))
+    }
+}
+
+/// A chain of filters, applied in the order they were added. A read is kept
+/// only if every filter accepts it, later filters are not run once a filter
+/// rejects the read.
+#[derive(Default)]
+pub struct FilterChain<'a> {
+    filters: Vec<Box<dyn RecordFilter + 'a>>,
+}
+
+impl<'a> FilterChain<'a> {
+    pub fn new() -> Self {
+        Self {
+            filters: Vec::new(),
+        }
+    }
+
+    pub fn with<F: RecordFilter + 'a>(mut self, filter: F) -> Self {
+        self.push(filter);
+        self
+    }
+
+    pub fn push<F: RecordFilter + 'a>(&mut self, filter: F) {
+        self.filters.push(Box::new(filter));
+    }
+
+    /// Whether the chain has no filter, all reads are kept
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+}
+
+impl RecordFilter for FilterChain<'_> {
+    fn accept(&self, records: &[&FastqRecord<Bytes>]) -> bool {
+        self.filters.iter().all(|filter| filter.accept(records))
+    }
+
+    fn transform(&self, record: FastqRecord<Bytes>) -> FastqRecord<Bytes> {
+        self.filters
+            .iter()
+            .fold(record, |record, filter| filter.transform(record))
+    }
+
+    fn transform_pair(
+        &self,
+        record1: FastqRecord<Bytes>,
+        record2: FastqRecord<Bytes>,
+    ) -> (FastqRecord<Bytes>, FastqRecord<Bytes>) {
+        self.filters
+            .iter()
+            .fold((record1, record2), |(record1, record2), filter| {
+                filter.transform_pair(record1, record2)
+            })
+    }
+}
+
+/// Keep reads whose sequence ID is in a set
+pub struct IdFilter {
+    ids: HashSet<Vec<u8>>,
+}
+
+impl IdFilter {
+    pub fn new<I: IntoIterator<Item = Vec<u8>>>(ids: I) -> Self {
+        Self {
+            ids: ids.into_iter().collect(),
+        }
+    }
+}
+
+impl RecordFilter for IdFilter {
+    fn accept(&self, records: &[&FastqRecord<Bytes>]) -> bool {
+        self.ids.contains(records[0].id.as_ref())
+    }
+}
+
+/// Keep reads whose mates are all within a range of lengths, both bounds are
+/// inclusive
+pub struct LengthFilter {
+    min: Option<usize>,
+    max: Option<usize>,
+}
+
+impl LengthFilter {
+    pub fn new(min: Option<usize>, max: Option<usize>) -> Self {
+        Self { min, max }
+    }
+}
+
+impl RecordFilter for LengthFilter {
+    fn accept(&self, records: &[&FastqRecord<Bytes>]) -> bool {
+        records.iter().all(|record| {
+            let len = record.seq.len();
+            self.min.is_none_or(|min| len >= min) && self.max.is_none_or(|max| len <= max)
+        })
+    }
+}
+
+/// Keep reads whose mean Phred quality (Phred+33), over all their mates, is
+/// at least `min_quality`. Reads without any base are rejected.
+pub struct QualityFilter {
+    min_quality: f64,
+}
+
+impl QualityFilter {
+    pub fn new(min_quality: f64) -> Self {
+        Self { min_quality }
+    }
+}
+
+impl RecordFilter for QualityFilter {
+    fn accept(&self, records: &[&FastqRecord<Bytes>]) -> bool {
+        let (quality, bases) = records.iter().fold((0u64, 0), |(quality, bases), record| {
+            let record_quality = record
+                .qual
+                .iter()
+                .map(|q| q.saturating_sub(QUALITY_OFFSET) as u64)
+                .sum::<u64>();
+            (quality + record_quality, bases + record.qual.len())
+        });
+        bases > 0 && quality as f64 / bases as f64 >= self.min_quality
+    }
+}
+
+/// Keep reads whose mates all have at least `threshold` bases differing from
+/// their most frequent base, which drops homopolymer-like reads
+pub struct ComplexityFilter {
+    threshold: usize,
+}
+
+impl ComplexityFilter {
+    pub fn new(threshold: usize) -> Self {
+        Self { threshold }
+    }
+}
+
+impl RecordFilter for ComplexityFilter {
+    fn accept(&self, records: &[&FastqRecord<Bytes>]) -> bool {
+        records
+            .iter()
+            .all(|record| pass_complexity_filter(&record.seq, self.threshold))
+    }
+}
+
+/// Return `true` if all base counts are ≤ `seq.len() - threshold`, otherwise
+/// `false`.
+pub(crate) fn pass_complexity_filter(seq: &[u8], threshold: usize) -> bool {
+    // remove low complexity reads (<20 non-sequentially repeated nucleotides)
+    let Some(threshold) = seq.len().checked_sub(threshold) else {
+        return false;
+    };
+    let mut counts = HashMap::with_capacity_and_hasher(4, rustc_hash::FxBuildHasher); // ATGC
+    for &b in seq {
+        let count = counts.entry(b).or_insert(0);
+        *count += 1;
+        if *count > threshold {
+            return false; // Early exit: too many repeats
+        }
+    }
+    true
+}
+
+/// Keep reads whose GC content, over all their mates, is within a range.
+/// Reads without any base have no GC content and are rejected.
+pub struct GcFilter {
+    min: f64,
+    max: f64,
+}
+
+impl GcFilter {
+    pub fn new(min: f64, max: f64) -> Self {
+        Self { min, max }
+    }
+}
+
+impl RecordFilter for GcFilter {
+    fn accept(&self, records: &[&FastqRecord<Bytes>]) -> bool {
+        let (gc, bases) = records.iter().fold((0, 0), |(gc, bases), record| {
+            let record_gc = record
+                .seq
+                .iter()
+                .filter(|b| matches!(b, b'G' | b'C' | b'g' | b'c'))
+                .count();
+            (gc + record_gc, bases + record.seq.len())
+        });
+        if bases == 0 {
+            return false;
+        }
+        let gc = gc as f64 / bases as f64;
+        gc >= self.min && gc <= self.max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: &'static str, seq: &'static str, qual: u8) -> FastqRecord<Bytes> {
+        FastqRecord::new(
+            Bytes::from(id),
+            None,
+            Bytes::from(seq),
+            Bytes::from("+"),
+            Bytes::from(vec![qual; seq.len()]),
+        )
+    }
+
+    struct Rename;
+
+    impl RecordFilter for Rename {
+        fn accept(&self, _records: &[&FastqRecord<Bytes>]) -> bool {
+            true
+        }
+
+        fn transform(&self, mut record: FastqRecord<Bytes>) -> FastqRecord<Bytes> {
+            record.id = Bytes::from([b"x".as_slice(), &record.id].concat());
+            record
+        }
+    }
+
+    #[test]
+    fn test_filters() {
+        let (gc50, gc100) = (record("r1", "ACGT", b'I'), record("r2", "GGCC", b'!'));
+        assert!(GcFilter::new(0.3, 0.6).accept(&[&gc50]));
+        assert!(!GcFilter::new(0.3, 0.6).accept(&[&gc100]));
+        // GC content is computed over both mates
+        assert!(GcFilter::new(0.7, 0.8).accept(&[&gc50, &gc100]));
+        assert!(!GcFilter::new(0.0, 1.0).accept(&[&record("r3", "", b'I')]));
+
+        assert!(IdFilter::new([b"r1".to_vec()]).accept(&[&gc50, &gc100]));
+        assert!(!IdFilter::new([b"r1".to_vec()]).accept(&[&gc100]));
+        assert!(LengthFilter::new(Some(4), Some(4)).accept(&[&gc50]));
+        assert!(!LengthFilter::new(None, Some(3)).accept(&[&gc50]));
+        // 'I' is quality 40 and '!' quality 0
+        assert!(QualityFilter::new(20.0).accept(&[&gc50, &gc100]));
+        assert!(!QualityFilter::new(20.1).accept(&[&gc50, &gc100]));
+        assert!(ComplexityFilter::new(2).accept(&[&gc50]));
+        assert!(!ComplexityFilter::new(2).accept(&[&record("r4", "AAAAAC", b'I')]));
+        assert!(!ComplexityFilter::new(20).accept(&[&gc50]));
+    }
+
+    #[test]
+    fn test_filter_chain() {
+        let chain = FilterChain::new();
+        assert!(chain.is_empty());
+        assert!(chain.accept(&[&record("r1", "", b'I')]));
+
+        let chain = chain.with(Rename).with(LengthFilter::new(Some(4), None));
+        assert!(chain.accept(&[&record("r1", "ACGT", b'I')]));
+        assert!(!chain.accept(&[&record("r1", "ACG", b'I')]));
+        let record = chain.with(Rename).transform(record("r1", "ACGT", b'I'));
+        assert_eq!(record.id.as_ref(), b"xxr1");
+    }
+}
@@ -0,0 +1,599 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use extendr_api::prelude::*;
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::error::{ErrorKind, RError};
+use crate::fastq_reader::FastqReader;
+use crate::fastq_record::FastqRecord;
+use crate::part_writer::PartWriter;
+use crate::remote::is_remote;
+use crate::seekable::zstd_frame_reader;
+use crate::utils::*;
+use crate::warnings::warn;
+
+/// The ID of each record of an output chunk, with the offset of the record
+/// in the uncompressed chunk. Empty when the output is not indexed.
+pub(crate) type ChunkRecords = Vec<(Bytes, usize)>;
+
+/// A chunk of whole records sent to a writer thread, with its records
+pub(crate) type IndexedChunk = (Vec<u8>, ChunkRecords);
+
+const INDEX_HEADER: &[u8] = b"id\tfile\tmember_offset\trecord_offset\n";
+
+// Gzip members and zstd frames of indexed inputs are decompressed whole to
+// read a record, so inputs made of larger ones are not indexed
+const MAX_INDEXED_CHUNK: usize = 64 << 20;
+
+/// The sidecar index of an output, named `<output>.idx`
+pub(crate) fn index_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".idx");
+    PathBuf::from(path)
+}
+
+/// Writes the sidecar index of an output: a table of the ID of each record,
+/// the name of the (part) file holding it, the offset of its chunk (a gzip
+/// member or zstd frame for compressed outputs) in this file, and the offset
+/// of the record in the uncompressed chunk. Files are named relative to the
+/// index, which lies next to them.
+pub(crate) struct IndexWriter {
+    path: PathBuf,
+    writer: BufWriter<Box<dyn CompleteWrite>>,
+}
+
+impl IndexWriter {
+    pub(crate) fn new(output: &Path) -> Result<Self> {
+        let path = index_path(output);
+        let mut writer = BufWriter::with_capacity(BUFFER_SIZE, new_writer(&path, None)?);
+        writer
+            .write_all(INDEX_HEADER)
+            .with_context(|| format!("Failed to write index {}", path.display()))?;
+        Ok(Self { path, writer })
+    }
+
+    /// Add the records of a chunk written at `offset` of `file`
+    pub(crate) fn add(&mut self, file: &Path, offset: u64, records: &ChunkRecords) -> Result<()> {
+        for (id, record_offset) in records {
+            self.add_record(file, offset, id, *record_offset)?;
+        }
+        Ok(())
+    }
+
+    fn add_record(
+        &mut self,
+        file: &Path,
+        offset: u64,
+        id: &[u8],
+        record_offset: usize,
+    ) -> Result<()> {
+        let name = file
+            .file_name()
+            .map(|x| x.as_encoded_bytes())
+            .unwrap_or_default();
+        self.writer
+            .write_all(id)
+            .and_then(|_| self.writer.write_all(b"\t"))
+            .and_then(|_| self.writer.write_all(name))
+            .and_then(|_| writeln!(self.writer, "\t{}\t{}", offset, record_offset))
+            .with_context(|| format!("Failed to write index {}", self.path.display()))
+    }
+
+    pub(crate) fn finish(mut self) -> Result<()> {
+        self.writer
+            .complete()
+            .with_context(|| format!("Failed to flush index {}", self.path.display()))
+    }
+}
+
+/// Index the records of a local FASTQ input into `<input>.idx`, like an
+/// indexed output, so that reads never extracted from it can be retrieved
+/// later without streaming it again. Compressed inputs must be made of gzip
+/// members or zstd frames of at most 64 MiB each (e.g. compressed by bgzip,
+/// or written by mire), records may span several of them. Returns the number
+/// of records indexed.
+pub(crate) fn index_reads(input: &Path) -> Result<usize> {
+    if is_remote(input) {
+        return Err(ErrorKind::Config.error(format!(
+            "Cannot index the remote input {}, only local files are read at random",
+            input.display()
+        )));
+    }
+    let format = ChunkFormat::of(input);
+    let mut reader = BufReader::with_capacity(BUFFER_SIZE, open_at(input, 0)?);
+    let mut index = IndexWriter::new(input)?;
+    let mut scanner = RecordScanner::default();
+    let mut records = 0;
+    loop {
+        let offset = reader
+            .stream_position()
+            .with_context(|| format!("Failed to read file: {}", input.display()))?;
+        let chunk = if format == ChunkFormat::Plain {
+            let chunk = reader
+                .fill_buf()
+                .with_context(|| format!("Failed to read file: {}", input.display()))?
+                .to_vec();
+            reader.consume(chunk.len());
+            chunk
+        } else {
+            if reader.fill_buf()?.is_empty() {
+                break;
+            }
+            let mut chunk = Vec::new();
+            decode_chunk(&mut reader, format)?
+                .take(MAX_INDEXED_CHUNK as u64 + 1)
+                .read_to_end(&mut chunk)
+                .with_context(|| format!("Failed to decompress chunk of {}", input.display()))?;
+            if chunk.len() > MAX_INDEXED_CHUNK {
+                return Err(ErrorKind::Config.error(format!(
+                    "Cannot index {}: it holds compressed blocks over {} MiB, \
+                     recompress it with bgzip",
+                    input.display(),
+                    MAX_INDEXED_CHUNK >> 20
+                )));
+            }
+            chunk
+        };
+        if format == ChunkFormat::Plain && chunk.is_empty() {
+            break;
+        }
+        scanner.scan(
+            offset,
+            &chunk,
+            format == ChunkFormat::Plain,
+            |id, offset, record_offset| {
+                records += 1;
+                index.add_record(input, offset, id, record_offset)
+            },
+        )?;
+    }
+    if !scanner.finished() {
+        return Err(ErrorKind::Parse.error(format!(
+            "(Reader) Truncated FASTQ record at the end of {}",
+            input.display()
+        )));
+    }
+    index.finish()?;
+    Ok(records)
+}
+
+/// Finds the records of a FASTQ file read chunk by chunk, and where each one
+/// starts
+#[derive(Default)]
+struct RecordScanner {
+    // Line of the current record, from 0 for the header
+    line: usize,
+    // Whether the last chunk ended within a line
+    within_line: bool,
+    // Where the header being read started, and its bytes read so far
+    header: Option<((u64, usize), Vec<u8>)>,
+}
+
+impl RecordScanner {
+    /// Scan the chunk starting at `offset` of the file, or at `offset` of the
+    /// decompressed file for plain files, and call `add` with the ID and the
+    /// location of each record
+    fn scan(
+        &mut self,
+        offset: u64,
+        chunk: &[u8],
+        plain: bool,
+        mut add: impl FnMut(&[u8], u64, usize) -> Result<()>,
+    ) -> Result<()> {
+        let mut pos = 0;
+        while pos < chunk.len() {
+            if self.line == 0 && !self.within_line {
+                let location = if plain {
+                    (offset + pos as u64, 0)
+                } else {
+                    (offset, pos)
+                };
+                self.header = Some((location, Vec::new()));
+            }
+            let end = memchr::memchr(b'\n', &chunk[pos ..]).map(|i| pos + i);
+            if let Some((_, header)) = &mut self.header {
+                header.extend_from_slice(&chunk[pos .. end.unwrap_or(chunk.len())]);
+            }
+            let Some(end) = end else {
+                self.within_line = true;
+                break;
+            };
+            self.within_line = false;
+            pos = end + 1;
+            let Some((location, header)) = self.header.take() else {
+                self.line = (self.line + 1) % 4;
+                continue;
+            };
+            let header = header.trim_ascii_end();
+            // Blank lines between records are skipped, as by `FastqReader`
+            if header.is_empty() {
+                continue;
+            }
+            let id = header
+                .strip_prefix(b"@")
+                .ok_or_else(|| {
+                    ErrorKind::Parse.error(format!(
+                        "(Reader) Invalid FASTQ header: {}",
+                        String::from_utf8_lossy(header)
+                    ))
+                })?
+                .split(|b| *b == b' ' || *b == b'\t')
+                .next()
+                .unwrap_or_default();
+            add(id, location.0, location.1)?;
+            self.line = 1;
+        }
+        Ok(())
+    }
+
+    /// Whether the file ended after a whole record, the last line may miss
+    /// its line break
+    fn finished(&self) -> bool {
+        (self.line == 0 && !self.within_line) || (self.line == 3 && self.within_line)
+    }
+}
+
+/// Where a record lies: its file, the offset of its chunk and its offset in
+/// the uncompressed chunk
+type RecordLocation = (PathBuf, u64, usize);
+
+/// Retrieve the records of `ids` from the outputs indexed by `index`, in the
+/// order of `ids`. IDs missing from the index are skipped. Only the chunks
+/// holding the records are read, the outputs are not scanned.
+pub(crate) fn lookup_records(index: &Path, ids: &[&str]) -> Result<Vec<FastqRecord<Bytes>>> {
+    let mut fetcher = RecordFetcher::default();
+    let found = locate_records(index, ids)?
+        .into_iter()
+        .map(|(id, location)| Ok((id, fetcher.fetch(&location)?)))
+        .collect::<Result<HashMap<&str, FastqRecord<Bytes>>>>()?;
+    Ok(ids.iter().filter_map(|id| found.get(id).cloned()).collect())
+}
+
+/// Extract the records of `ids` from the outputs indexed by `index` into
+/// `output`, seeking to each record instead of streaming the inputs again.
+/// Records are written in the order of the indexed outputs, `output` is
+/// compressed by its extension and indexed as well if `write_index` is set.
+/// Returns the number of records extracted.
+pub(crate) fn extract_records(
+    index: &Path,
+    ids: &[&str],
+    output: &Path,
+    compression_level: Option<i32>,
+    chunk_bytes: usize,
+    write_index: bool,
+) -> Result<usize> {
+    let compression = Compression::new(output, compression_level)?;
+    let locations = locate_records(index, ids)?;
+    if let Some((file, ..)) = locations.iter().map(|x| &x.1).find(|x| x.0 == output) {
+        return Err(ErrorKind::Config.error(format!(
+            "Cannot extract records into the indexed file {}",
+            file.display()
+        )));
+    }
+    let mut packer = ChunkPacker::new(compression);
+    let mut writer = PartWriter::new(output, None, chunk_bytes, None).with_index(write_index)?;
+    let mut fetcher = RecordFetcher::default();
+    let mut pool = Vec::with_capacity(chunk_bytes);
+    let mut pool_records = ChunkRecords::new();
+    for (_, location) in &locations {
+        let record = fetcher.fetch(location)?;
+        if !pool.is_empty() && pool.len() + record.bytes_size() > chunk_bytes {
+            let chunk = (
+                packer.pack(std::mem::take(&mut pool))?,
+                std::mem::take(&mut pool_records),
+            );
+            writer.write_records(0, &chunk)?;
+        }
+        if write_index {
+            pool_records.push((record.id.clone(), pool.len()));
+        }
+        record.extend(&mut pool);
+    }
+    if !pool.is_empty() {
+        writer.write_records(0, &(packer.pack(pool)?, pool_records))?;
+    }
+    writer.finish()?;
+    Ok(locations.len())
+}
+
+/// Find the records of `ids` in the index, sorted by their location so that
+/// files are read forward. IDs missing from the index are skipped.
+fn locate_records<'a>(index: &Path, ids: &[&'a str]) -> Result<Vec<(&'a str, RecordLocation)>> {
+    let mut wanted = ids
+        .iter()
+        .map(|id| (id.as_bytes(), (*id, None)))
+        .collect::<HashMap<&[u8], (&str, Option<RecordLocation>)>>();
+    let dir = index.parent().unwrap_or(Path::new(""));
+    let reader = BufReader::new(
+        File::open(index).with_context(|| format!("Failed to open file: {}", index.display()))?,
+    );
+    for line in reader.split(b'\n').skip(1) {
+        let line = line.with_context(|| format!("Failed to read index {}", index.display()))?;
+        let fields = line.split(|b| *b == b'\t').collect::<Vec<_>>();
+        let [id, file, member_offset, record_offset] = fields[..] else {
+            return Err(anyhow!(
+                "Invalid index line: {}",
+                String::from_utf8_lossy(&line)
+            ));
+        };
+        if let Some((_, location)) = wanted.get_mut(id) {
+            let file = dir.join(String::from_utf8_lossy(file).as_ref());
+            *location = Some((
+                file,
+                parse_usize(member_offset)? as u64,
+                parse_usize(record_offset)?,
+            ));
+        }
+    }
+    let nwanted = wanted.len();
+    let mut locations = wanted
+        .into_values()
+        .filter_map(|(id, location)| Some((id, location?)))
+        .collect::<Vec<_>>();
+    if locations.len() < nwanted {
+        warn(format!(
+            "{} of {} IDs were not found in index {}",
+            nwanted - locations.len(),
+            nwanted,
+            index.display()
+        ));
+    }
+    locations.sort_unstable_by(|a, b| a.1.cmp(&b.1));
+    Ok(locations)
+}
+
+/// Reads indexed records, keeping the last compressed chunk decompressed so
+/// that records sorted by location decompress each chunk once
+#[derive(Default)]
+struct RecordFetcher {
+    chunk: Option<((PathBuf, u64), DecodedChunk)>,
+}
+
+/// A decompressed chunk, with the offset of the next one in its file
+type DecodedChunk = (Vec<u8>, u64);
+
+impl RecordFetcher {
+    fn fetch(&mut self, location: &RecordLocation) -> Result<FastqRecord<Bytes>> {
+        let (file, member_offset, record_offset) = location;
+        let format = ChunkFormat::of(file);
+        if format == ChunkFormat::Plain {
+            let mut reader = open_at(file, member_offset + *record_offset as u64)?;
+            return read_one_record(&mut reader, file);
+        }
+        let cached = self
+            .chunk
+            .as_ref()
+            .is_some_and(|((cached, offset), _)| cached == file && offset == member_offset);
+        if !cached {
+            let chunk = read_chunk(file, *member_offset, format)?;
+            self.chunk = Some(((file.clone(), *member_offset), chunk));
+        }
+        // Safety: chunk was cached above
+        let (chunk, next) = &self.chunk.as_ref().unwrap().1;
+        let mut reader = chunk.get(*record_offset ..).unwrap_or_default();
+        if whole_record(reader) {
+            return read_one_record(&mut reader, file);
+        }
+        // Records of indexed inputs may go on in the next chunks
+        let (mut record, mut next) = (reader.to_vec(), *next);
+        while !whole_record(&record) {
+            let (chunk, end) = read_chunk(file, next, format)?;
+            if end == next {
+                break;
+            }
+            record.extend_from_slice(&chunk);
+            next = end;
+        }
+        read_one_record(&record[..], file)
+    }
+}
+
+/// Whether `bytes` start with the four lines of a record
+fn whole_record(bytes: &[u8]) -> bool {
+    memchr::memchr_iter(b'\n', bytes).nth(3).is_some()
+}
+
+fn open_at(file: &Path, offset: u64) -> Result<File> {
+    let mut reader =
+        File::open(file).with_context(|| format!("Failed to open file: {}", file.display()))?;
+    reader
+        .seek(SeekFrom::Start(offset))
+        .with_context(|| format!("Failed to seek in file: {}", file.display()))?;
+    Ok(reader)
+}
+
+/// Decompress the gzip member or zstd frame starting at `offset` of `file`,
+/// returns it with the offset of the next one
+fn read_chunk(file: &Path, offset: u64, format: ChunkFormat) -> Result<(Vec<u8>, u64)> {
+    let mut reader = BufReader::new(open_at(file, offset)?);
+    let mut chunk = Vec::new();
+    if reader.fill_buf()?.is_empty() {
+        return Ok((chunk, offset));
+    }
+    decode_chunk(&mut reader, format)?
+        .read_to_end(&mut chunk)
+        .with_context(|| format!("Failed to decompress chunk of {}", file.display()))?;
+    Ok((chunk, reader.stream_position()?))
+}
+
+/// A reader decompressing the gzip member or zstd frame at the start of
+/// `reader`, which is left at the end of it
+fn decode_chunk<'a, R: BufRead + 'a>(reader: R, format: ChunkFormat) -> Result<Box<dyn Read + 'a>> {
+    Ok(match format {
+        ChunkFormat::Zstd => zstd_frame_reader(reader)?,
+        _ => Box::new(flate2::bufread::GzDecoder::new(reader)),
+    })
+}
+
+fn read_one_record<R: Read>(reader: R, file: &Path) -> Result<FastqRecord<Bytes>> {
+    FastqReader::new(reader)
+        .read_record()
+        .with_context(|| format!("Failed to read FASTQ record of {}", file.display()))?
+        .ok_or_else(|| anyhow!("Indexed record is missing from {}", file.display()))
+}
+
+#[extendr]
+fn kractor_lookup(index: &str, ids: Vec<String>) -> std::result::Result<List, RError> {
+    let ids = ids.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    let records = lookup_records(Path::new(index), &ids).map_err(RError::from)?;
+    let mut columns: [Vec<Vec<u8>>; 4] = Default::default();
+    for record in records {
+        columns[0].push(record.id.to_vec());
+        columns[1].push(record.desc.map_or(Vec::new(), |x| x.to_vec()));
+        columns[2].push(record.seq.to_vec());
+        columns[3].push(record.qual.to_vec());
+    }
+    let [id, desc, seq, qual] = columns.map(u8_to_list_rstr);
+    Ok(list!(id = id, desc = desc, seq = seq, qual = qual))
+}
+
+#[extendr]
+fn kractor_extract_indexed(
+    index: &str,
+    ids: Vec<String>,
+    ofile: &str,
+    compression_level: Option<i32>,
+    chunk_bytes: usize,
+) -> std::result::Result<f64, RError> {
+    let ids = ids.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    extract_records(
+        Path::new(index),
+        &ids,
+        Path::new(ofile),
+        compression_level,
+        chunk_bytes,
+        true,
+    )
+    .map(|records| records as f64)
+    .map_err(RError::from)
+}
+
+#[extendr]
+fn kractor_index_reads(input: &str) -> std::result::Result<f64, RError> {
+    index_reads(Path::new(input))
+        .map(|records| records as f64)
+        .map_err(RError::from)
+}
+
+extendr_module! {
+    mod record_index;
+    fn kractor_lookup;
+    fn kractor_extract_indexed;
+    fn kractor_index_reads;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_records() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let records = [b"@r1\nACGT\n+\nIIII\n".as_slice(), b"@r2 x\nGG\n+\nII\n"];
+        let mut names = vec!["out.fq", "out.fq.gz"];
+        if cfg!(feature = "zstd") {
+            names.push("out.fq.zst");
+        }
+        for name in names {
+            let output = temp.path().join(name);
+            let mut packer = ChunkPacker::new(Compression::new(&output, None)?);
+            let mut file = Vec::new();
+            let mut index = IndexWriter::new(&output)?;
+            // Each record in its own chunk, then both in the last chunk
+            for (i, chunk) in [records[0].to_vec(), records[1].to_vec(), records.concat()]
+                .into_iter()
+                .enumerate()
+            {
+                let ids = if i < 2 {
+                    vec![(Bytes::from(format!("r{}", i + 1)), 0)]
+                } else {
+                    vec![
+                        (Bytes::from("r3"), 0),
+                        (Bytes::from("r4"), records[0].len()),
+                    ]
+                };
+                index.add(&output, file.len() as u64, &ids)?;
+                file.extend_from_slice(&packer.pack(chunk)?);
+            }
+            index.finish()?;
+            std::fs::write(&output, file)?;
+
+            let found = lookup_records(&index_path(&output), &["r4", "r0", "r1", "r2"])?;
+            assert_eq!(found.len(), 3);
+            assert_eq!(found[0].id.as_ref(), b"r2");
+            assert_eq!(found[0].desc.as_deref(), Some(b"x".as_slice()));
+            assert_eq!(found[1].seq.as_ref(), b"ACGT");
+            assert_eq!(found[2].qual.as_ref(), b"II");
+
+            // Records are extracted in the order of the indexed output
+            let extracted = temp.path().join(format!("extracted.{}", name));
+            let ids = ["r2", "r0", "r1"];
+            let n = extract_records(&index_path(&output), &ids, &extracted, Some(4), 20, true)?;
+            assert_eq!(n, 2);
+            let index = std::fs::read_to_string(index_path(&extracted))?;
+            let found = index.lines().skip(1).map(|x| &x[.. 2]).collect::<Vec<_>>();
+            assert_eq!(found, ["r1", "r2"]);
+            let found = lookup_records(&index_path(&extracted), &["r2", "r1"])?;
+            assert_eq!(found[0].seq.as_ref(), b"GG");
+            assert_eq!(found[1].seq.as_ref(), b"ACGT");
+            assert!(
+                extract_records(&index_path(&output), &ids, &output, Some(4), 20, false).is_err()
+            );
+        }
+        Ok(())
+    }
+    #[test]
+    fn test_index_reads() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let records = [
+            "@r1 x\nACGT\n+\nIIII\n",
+            "\n@r2\nGG\n+\nII\n",
+            "@r3\nT\n+\nI",
+        ];
+        let text = records.concat();
+        let mut names = vec!["reads.fq", "reads.fq.gz"];
+        if cfg!(feature = "zstd") {
+            names.push("reads.fq.zst");
+        }
+        for name in names {
+            let input = temp.path().join(name);
+            // Compressed in blocks cutting through records and their headers
+            let mut packer = ChunkPacker::new(Compression::new(&input, None)?);
+            let mut file = Vec::new();
+            for block in [&text[.. 2], &text[2 .. 20], &text[20 ..]] {
+                file.extend_from_slice(&packer.pack(block.as_bytes().to_vec())?);
+            }
+            std::fs::write(&input, file)?;
+            assert_eq!(index_reads(&input)?, 3);
+
+            // Reads never extracted are retrieved from the input
+            let found = lookup_records(&index_path(&input), &["r3", "r1", "r2"])?;
+            let ids = found.iter().map(|x| x.id.as_ref()).collect::<Vec<_>>();
+            assert_eq!(ids, [b"r3".as_slice(), b"r1", b"r2"]);
+            assert_eq!(found[1].desc.as_deref(), Some(b"x".as_slice()));
+            assert_eq!(found[1].seq.as_ref(), b"ACGT");
+            assert_eq!(found[0].qual.as_ref(), b"I");
+            let extracted = temp.path().join(format!("extracted.{}", name));
+            let ids = ["r2", "r1"];
+            assert_eq!(
+                extract_records(&index_path(&input), &ids, &extracted, None, 20, true)?,
+                2
+            );
+            let index = std::fs::read_to_string(index_path(&extracted))?;
+            let found = index.lines().skip(1).map(|x| &x[.. 2]).collect::<Vec<_>>();
+            assert_eq!(found, ["r1", "r2"]);
+            let found = lookup_records(&index_path(&extracted), &ids)?;
+            assert_eq!(found[0].seq.as_ref(), b"GG");
+            assert_eq!(found[1].seq.as_ref(), b"ACGT");
+        }
+
+        let input = temp.path().join("truncated.fq");
+        std::fs::write(&input, "@r1\nACGT\n+\nIIII\n@r2\nGG\n")?;
+        assert!(index_reads(&input).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,474 @@
+use std::path::Path;
+
+#[cfg(feature = "remote")]
+use std::io::Read;
+#[cfg(feature = "remote")]
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+// Object storage URLs are translated into plain HTTPS requests:
+// - `s3://bucket/key`: `https://bucket.s3.<region>.amazonaws.com/key`, or
+//   `<AWS_ENDPOINT_URL>/bucket/key` for S3-compatible storage
+// - `gs://bucket/key`: `https://storage.googleapis.com/bucket/key`
+// S3 requests are signed with the AWS credentials of the environment when
+// built with the `s3` feature, and unsigned otherwise; GCS requests carry the
+// OAuth token in `GOOGLE_OAUTH_ACCESS_TOKEN` if set. Private objects can also
+// be read with a pre-signed HTTPS URL.
+const REMOTE_SCHEMES: [&str; 4] = ["http://", "https://", "s3://", "gs://"];
+
+#[cfg(feature = "remote")]
+const REMOTE_RETRIES: usize = 5;
+#[cfg(feature = "remote")]
+const REMOTE_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Whether the input refers to a remote object rather than a local file
+pub(crate) fn is_remote(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|s| REMOTE_SCHEMES.iter().any(|scheme| s.starts_with(scheme)))
+}
+
+/// Object path without the query string, so extensions of pre-signed URLs
+/// (e.g. `reads.fastq.gz?X-Amz-Signature=...`) can be detected
+pub(crate) fn remote_object_path(path: &Path) -> &Path {
+    match path.to_str().and_then(|s| s.split_once('?')) {
+        Some((object, _)) => Path::new(object),
+        None => path,
+    }
+}
+
+#[cfg(not(feature = "remote"))]
+pub(crate) fn remote_size(path: &Path) -> Result<u64> {
+    Err(remote_unsupported(path))
+}
+
+#[cfg(not(feature = "remote"))]
+pub(crate) fn open_remote(path: &Path) -> Result<Box<dyn std::io::Read>> {
+    Err(remote_unsupported(path))
+}
+
+#[cfg(not(feature = "remote"))]
+fn remote_unsupported(path: &Path) -> anyhow::Error {
+    anyhow!(
+        "Cannot read remote input '{}': mire was built without the 'remote' feature",
+        path.display()
+    )
+}
+
+/// Size in bytes of a remote object
+#[cfg(feature = "remote")]
+pub(crate) fn remote_size(path: &Path) -> Result<u64> {
+    RemoteReader::new(path)?.size()
+}
+
+/// Open a remote object for streaming
+#[cfg(feature = "remote")]
+pub(crate) fn open_remote(path: &Path) -> Result<Box<dyn Read>> {
+    Ok(Box::new(RemoteReader::new(path)?))
+}
+
+#[cfg(feature = "remote")]
+fn object_url(path: &str) -> Result<String> {
+    if let Some(object) = path.strip_prefix("s3://") {
+        let (bucket, key) = split_object(path, object)?;
+        if let Some(endpoint) = std::env::var("AWS_ENDPOINT_URL")
+            .ok()
+            .filter(|x| !x.is_empty())
+        {
+            Ok(format!(
+                "{}/{}/{}",
+                endpoint.trim_end_matches('/'),
+                bucket,
+                key
+            ))
+        } else {
+            let region = std::env::var("AWS_REGION")
+                .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+                .unwrap_or_else(|_| "us-east-1".to_string());
+            Ok(format!(
+                "https://{}.s3.{}.amazonaws.com/{}",
+                bucket, region, key
+            ))
+        }
+    } else if let Some(object) = path.strip_prefix("gs://") {
+        let (bucket, key) = split_object(path, object)?;
+        Ok(format!("https://storage.googleapis.com/{}/{}", bucket, key))
+    } else {
+        Ok(path.to_string())
+    }
+}
+
+#[cfg(feature = "remote")]
+fn split_object<'a>(path: &str, object: &'a str) -> Result<(&'a str, &'a str)> {
+    object
+        .split_once('/')
+        .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+        .ok_or_else(|| {
+            anyhow!(
+                "Invalid object URL '{}': expected <scheme>://bucket/key",
+                path
+            )
+        })
+}
+
+/// A reader streaming a remote object over HTTP.
+///
+/// Transient failures (connection errors, truncated bodies, 5xx responses)
+/// are retried with exponential backoff, resuming from the current offset
+/// with a range request, so a dropped connection late in a multi-hundred-GB
+/// object doesn't restart the whole download.
+#[cfg(feature = "remote")]
+struct RemoteReader {
+    agent: ureq::Agent,
+    url: String,
+    auth: RemoteAuth,
+    offset: u64,
+    size: Option<u64>,
+    body: Option<Box<dyn Read + Send>>,
+}
+
+#[cfg(feature = "remote")]
+impl RemoteReader {
+    fn new(path: &Path) -> Result<Self> {
+        let path = path
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid remote path: {}", path.display()))?;
+        let auth = RemoteAuth::from_env(path)?;
+        let agent = ureq::Agent::config_builder()
+            .timeout_connect(Some(Duration::from_secs(30)))
+            .timeout_recv_response(Some(Duration::from_secs(60)))
+            .build()
+            .into();
+        Ok(Self {
+            agent,
+            url: auth.url().map_or_else(|| object_url(path), Ok)?,
+            auth,
+            offset: 0,
+            size: None,
+            body: None,
+        })
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        let response = with_retries(|| {
+            let mut request = self.agent.head(&self.url);
+            for (name, value) in self.auth.headers("HEAD") {
+                request = request.header(name, value);
+            }
+            request.call()
+        })
+        .map_err(|e| {
+            anyhow!(
+                "Failed to open remote object {}: {}{}",
+                self.url,
+                e,
+                self.auth.denied_hint(&e)
+            )
+        })?;
+        content_length(&response)
+            .ok_or_else(|| anyhow!("Remote object {} reports no Content-Length", self.url))
+    }
+
+    /// (Re)start the download from the current offset
+    fn connect(&mut self) -> std::result::Result<(), ureq::Error> {
+        let mut request = self.agent.get(&self.url);
+        if self.offset > 0 {
+            request = request.header("Range", &format!("bytes={}-", self.offset));
+        }
+        for (name, value) in self.auth.headers("GET") {
+            request = request.header(name, value);
+        }
+        let response = request.call()?;
+        if self.offset > 0 && response.status().as_u16() != 206 {
+            return Err(ureq::Error::Io(std::io::Error::other(format!(
+                "server ignored the range request (status {})",
+                response.status()
+            ))));
+        }
+        if self.size.is_none() {
+            self.size = content_length(&response);
+        }
+        self.body = Some(Box::new(response.into_body().into_reader()));
+        Ok(())
+    }
+}
+
+/// How the requests of a remote object are authorized
+#[cfg(feature = "remote")]
+enum RemoteAuth {
+    // Public objects and pre-signed URLs
+    None,
+    // GCS OAuth token
+    Bearer(String),
+    // S3 object read without credentials
+    UnsignedS3,
+    #[cfg(feature = "s3")]
+    Signed(crate::s3::S3ReadSigner),
+}
+
+#[cfg(feature = "remote")]
+impl RemoteAuth {
+    fn from_env(path: &str) -> Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|x| !x.is_empty());
+        if path.starts_with("gs://") {
+            Ok(var("GOOGLE_OAUTH_ACCESS_TOKEN").map_or(Self::None, Self::Bearer))
+        } else if path.starts_with("s3://") {
+            #[cfg(feature = "s3")]
+            if let Some(signer) = crate::s3::S3ReadSigner::from_env(Path::new(path))? {
+                return Ok(Self::Signed(signer));
+            }
+            Ok(Self::UnsignedS3)
+        } else {
+            Ok(Self::None)
+        }
+    }
+
+    /// The URL of signed requests, which must match the signed path
+    fn url(&self) -> Option<String> {
+        match self {
+            #[cfg(feature = "s3")]
+            Self::Signed(signer) => Some(signer.url()),
+            _ => None,
+        }
+    }
+
+    /// The headers authorizing a `method` request
+    #[cfg_attr(not(feature = "s3"), allow(unused_variables))]
+    fn headers(&self, method: &str) -> Vec<(String, String)> {
+        match self {
+            Self::Bearer(token) => vec![("Authorization".to_string(), format!("Bearer {}", token))],
+            #[cfg(feature = "s3")]
+            Self::Signed(signer) => signer.headers(method),
+            _ => Vec::new(),
+        }
+    }
+
+    /// What to check when access to an S3 object is denied
+    fn denied_hint(&self, error: &ureq::Error) -> &'static str {
+        if !matches!(error, ureq::Error::StatusCode(403)) {
+            return "";
+        }
+        match self {
+            Self::UnsignedS3 if cfg!(feature = "s3") => {
+                " (private S3 objects are read with the credentials in AWS_ACCESS_KEY_ID and \
+                 AWS_SECRET_ACCESS_KEY, plus AWS_SESSION_TOKEN for temporary credentials, \
+                 none of which are set)"
+            }
+            Self::UnsignedS3 => {
+                " (private S3 objects need mire built with the 's3' feature to sign requests \
+                 with AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, or a pre-signed HTTPS URL)"
+            }
+            #[cfg(feature = "s3")]
+            Self::Signed(_) => {
+                " (access denied to the credentials in AWS_ACCESS_KEY_ID, \
+                 AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN)"
+            }
+            _ => "",
+        }
+    }
+}
+
+#[cfg(feature = "remote")]
+impl Read for RemoteReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut attempt = 0;
+        loop {
+            let result = match self.body.as_mut() {
+                Some(body) => body.read(buf).map(Some),
+                None => self.connect().map(|_| None).map_err(std::io::Error::other),
+            };
+            let error = match result {
+                Ok(None) => continue,
+                Ok(Some(n)) if n > 0 => {
+                    self.offset += n as u64;
+                    return Ok(n);
+                }
+                Ok(Some(_)) => match self.size {
+                    // The body ended before the whole object was received
+                    Some(size) if self.offset < size => std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        format!("connection closed at byte {} of {}", self.offset, size),
+                    ),
+                    _ => return Ok(0),
+                },
+                Err(e) => e,
+            };
+            self.body = None;
+            attempt += 1;
+            if attempt > REMOTE_RETRIES || !retryable(&error) {
+                let hint = error
+                    .get_ref()
+                    .and_then(|e| e.downcast_ref::<ureq::Error>())
+                    .map(|e| self.auth.denied_hint(e))
+                    .unwrap_or_default();
+                return Err(std::io::Error::new(
+                    error.kind(),
+                    format!("Failed to read {}: {}{}", self.url, error, hint),
+                ));
+            }
+            std::thread::sleep(REMOTE_BACKOFF * 2u32.pow(attempt as u32 - 1));
+        }
+    }
+}
+
+#[cfg(feature = "remote")]
+fn retryable(error: &std::io::Error) -> bool {
+    match error
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<ureq::Error>())
+    {
+        // Client errors (e.g. 403, 404) won't go away by retrying
+        Some(ureq::Error::StatusCode(status)) => *status >= 500 || *status == 429,
+        _ => true,
+    }
+}
+
+#[cfg(feature = "remote")]
+fn with_retries<T>(
+    mut call: impl FnMut() -> std::result::Result<T, ureq::Error>,
+) -> std::result::Result<T, ureq::Error> {
+    let mut attempt = 0;
+    loop {
+        match call() {
+            Ok(out) => return Ok(out),
+            Err(ureq::Error::StatusCode(status)) if status < 500 && status != 429 => {
+                return Err(ureq::Error::StatusCode(status))
+            }
+            Err(e) => {
+                attempt += 1;
+                if attempt > REMOTE_RETRIES {
+                    return Err(e);
+                }
+                std::thread::sleep(REMOTE_BACKOFF * 2u32.pow(attempt as u32 - 1));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "remote")]
+fn content_length(response: &ureq::http::Response<ureq::Body>) -> Option<u64> {
+    response
+        .headers()
+        .get("content-length")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<u64>().ok())
+}
+
+#[cfg(all(test, feature = "remote"))]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_object_url() -> Result<()> {
+        assert_eq!(
+            object_url("gs://bucket/dir/reads.fq.gz")?,
+            "https://storage.googleapis.com/bucket/dir/reads.fq.gz"
+        );
+        assert_eq!(
+            object_url("https://host/reads.fq?sig=1")?,
+            "https://host/reads.fq?sig=1"
+        );
+        assert!(object_url("gs://bucket").is_err());
+        assert!(is_remote(Path::new("s3://bucket/key")));
+        assert!(!is_remote(Path::new("reads.fq")));
+        assert_eq!(
+            remote_object_path(Path::new("https://host/reads.fq.gz?sig=1")),
+            Path::new("https://host/reads.fq.gz")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_remote_reader_resumes() -> Result<()> {
+        let data = (0 .. 2000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/object", listener.local_addr()?);
+        let server_data = data.clone();
+        let server = std::thread::spawn(move || -> std::io::Result<Vec<String>> {
+            let mut ranges = Vec::new();
+            for (i, stream) in listener.incoming().take(2).enumerate() {
+                let mut stream = stream?;
+                let mut reader = BufReader::new(stream.try_clone()?);
+                let mut start = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line)?;
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(range) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        ranges.push(range.to_string());
+                        start = range.trim_end_matches('-').parse().unwrap();
+                    }
+                }
+                let body = &server_data[start ..];
+                let status = if start > 0 {
+                    "206 Partial Content"
+                } else {
+                    "200 OK"
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                )?;
+                // Drop the first connection half-way through the body
+                let sent = if i == 0 { body.len() / 2 } else { body.len() };
+                stream.write_all(&body[.. sent])?;
+            }
+            Ok(ranges)
+        });
+        let mut reader = RemoteReader::new(Path::new(&url))?;
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received)?;
+        assert_eq!(received, data);
+        let ranges = server.join().unwrap()?;
+        assert_eq!(ranges, vec!["1000-".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_remote_reader_denied() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/bucket/key", listener.local_addr()?);
+        let server = std::thread::spawn(move || -> std::io::Result<()> {
+            let mut stream = listener.incoming().next().unwrap()?;
+            let mut reader = BufReader::new(stream.try_clone()?);
+            let mut line = String::new();
+            while reader.read_line(&mut line)? > 2 {
+                line.clear();
+            }
+            write!(
+                stream,
+                "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            )
+        });
+        // An S3 object read without credentials, as resolved by its endpoint
+        let mut reader = RemoteReader::new(Path::new(&url))?;
+        reader.auth = RemoteAuth::UnsignedS3;
+        let err = reader.size().unwrap_err().to_string();
+        server.join().unwrap()?;
+        assert!(
+            err.contains("403") && err.contains("AWS_ACCESS_KEY_ID"),
+            "{}",
+            err
+        );
+        assert_eq!(
+            RemoteAuth::UnsignedS3.denied_hint(&ureq::Error::StatusCode(404)),
+            ""
+        );
+        assert_eq!(
+            RemoteAuth::None.denied_hint(&ureq::Error::StatusCode(403)),
+            ""
+        );
+        Ok(())
+    }
+}
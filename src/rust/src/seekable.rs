@@ -0,0 +1,151 @@
+use std::io::{BufRead, Read, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+use crate::remote::remote_object_path;
+
+// Magic numbers of the seekable zstd format: the seek table is stored in a
+// skippable frame, which standard zstd decoders ignore
+const SKIPPABLE_MAGIC: u32 = 0x184D2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+
+pub(crate) fn zstd_compressed(path: &Path) -> bool {
+    remote_object_path(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|s| s.eq_ignore_ascii_case("zst"))
+}
+
+/// Compress a chunk of whole records into a single zstd frame, whose header
+/// carries the decompressed size of the chunk for the seek table
+#[cfg(feature = "zstd")]
+pub(crate) fn zstd_pack(bytes: &[u8], level: i32) -> Result<Vec<u8>> {
+    zstd::bulk::compress(bytes, level).map_err(|e| anyhow!("Failed to compress chunk: {}", e))
+}
+
+#[cfg(not(feature = "zstd"))]
+pub(crate) fn zstd_pack(_bytes: &[u8], _level: i32) -> Result<Vec<u8>> {
+    Err(zstd_unsupported())
+}
+
+/// A reader decompressing only the zstd frame at the start of `reader`
+#[cfg(feature = "zstd")]
+pub(crate) fn zstd_frame_reader<'a, R: BufRead + 'a>(reader: R) -> Result<Box<dyn Read + 'a>> {
+    let decoder = zstd::stream::read::Decoder::with_buffer(reader)
+        .map_err(|e| anyhow!("Failed to create zstd decoder: {}", e))?;
+    Ok(Box::new(decoder.single_frame()))
+}
+
+#[cfg(not(feature = "zstd"))]
+pub(crate) fn zstd_frame_reader<'a, R: BufRead + 'a>(_reader: R) -> Result<Box<dyn Read + 'a>> {
+    Err(zstd_unsupported())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_unsupported() -> anyhow::Error {
+    anyhow!("Cannot handle zstd files: mire was built without the 'zstd' feature")
+}
+
+/// The seek table of a zstd file made of independent frames, one per chunk
+/// sent to the writer, following the seekable format of the zstd contrib
+/// library. Appended to the file once all frames are written, it lets
+/// downstream tools decompress any region of the file without decompressing
+/// the frames before it.
+#[derive(Default)]
+pub(crate) struct SeekTable {
+    // Compressed and decompressed size of each frame
+    frames: Vec<(u32, u32)>,
+}
+
+impl SeekTable {
+    /// Add the next frame of the file
+    pub(crate) fn push_frame(&mut self, frame: &[u8]) -> Result<()> {
+        let size = frame_content_size(frame)?;
+        self.push(frame.len(), size)
+    }
+
+    fn push(&mut self, compressed: usize, decompressed: u64) -> Result<()> {
+        let compressed = u32::try_from(compressed)
+            .map_err(|_| anyhow!("zstd frame of {} bytes is too large", compressed))?;
+        let decompressed = u32::try_from(decompressed)
+            .map_err(|_| anyhow!("zstd frame of {} bytes is too large", decompressed))?;
+        self.frames.push((compressed, decompressed));
+        Ok(())
+    }
+
+    /// Write the seek table as a skippable frame, without checksums
+    pub(crate) fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> std::io::Result<()> {
+        let frames = self.frames.len() as u32;
+        let mut table = Vec::with_capacity(17 + self.frames.len() * 8);
+        table.extend_from_slice(&SKIPPABLE_MAGIC.to_le_bytes());
+        table.extend_from_slice(&(frames * 8 + 9).to_le_bytes());
+        for (compressed, decompressed) in &self.frames {
+            table.extend_from_slice(&compressed.to_le_bytes());
+            table.extend_from_slice(&decompressed.to_le_bytes());
+        }
+        table.extend_from_slice(&frames.to_le_bytes());
+        table.push(0); // Seek_Table_Descriptor: no checksum
+        table.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+        writer.write_all(&table)
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn frame_content_size(frame: &[u8]) -> Result<u64> {
+    zstd::zstd_safe::get_frame_content_size(frame)
+        .map_err(|_| anyhow!("Invalid zstd frame"))?
+        .ok_or_else(|| anyhow!("zstd frame does not record its decompressed size"))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn frame_content_size(_frame: &[u8]) -> Result<u64> {
+    Err(zstd_unsupported())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seek_table() -> Result<()> {
+        assert!(zstd_compressed(Path::new("reads.fq.ZST")));
+        assert!(!zstd_compressed(Path::new("reads.fq.gz")));
+
+        let mut table = SeekTable::default();
+        table.push(10, 100)?;
+        table.push(20, 200)?;
+        assert!(table.push(1, u64::MAX).is_err());
+        let mut out = Vec::new();
+        table.write_to(&mut out)?;
+        assert_eq!(out.len(), 8 + 2 * 8 + 9);
+        assert_eq!(&out[.. 4], &[0x5E, 0x2A, 0x4D, 0x18]);
+        assert_eq!(&out[4 .. 8], &25u32.to_le_bytes());
+        assert_eq!(&out[8 .. 16], &[10, 0, 0, 0, 100, 0, 0, 0]);
+        assert_eq!(&out[24 .. 29], &[2, 0, 0, 0, 0]);
+        assert_eq!(&out[29 ..], &[0xB1, 0xEA, 0x92, 0x8F]);
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_frames() -> Result<()> {
+        let (chunk1, chunk2) = (b"@r1\nACGT\n+\nIIII\n", b"@r2\nGG\n+\nII\n");
+        let mut table = SeekTable::default();
+        let mut out = Vec::new();
+        for chunk in [chunk1.as_slice(), chunk2] {
+            let frame = zstd_pack(chunk, 4)?;
+            table.push_frame(&frame)?;
+            out.extend_from_slice(&frame);
+        }
+        let frame1 = table.frames[0].0 as usize;
+        table.write_to(&mut out)?;
+        // Frames decompress on their own, and the seek table is skipped
+        assert_eq!(zstd::decode_all(&out[frame1 ..])?, chunk2);
+        assert_eq!(
+            zstd::decode_all(out.as_slice())?,
+            [chunk1.as_slice(), chunk2].concat()
+        );
+        Ok(())
+    }
+}
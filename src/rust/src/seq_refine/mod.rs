@@ -1,14 +1,14 @@
 use anyhow::{anyhow, Context, Result};
 use extendr_api::prelude::*;
-use indicatif::{MultiProgress, ProgressBar, ProgressFinish};
 
 mod paired;
 mod single;
 
-mod seq_action;
+pub(crate) mod seq_action;
 
 use seq_action::*;
 
+use crate::error::RError;
 use crate::utils::*;
 
 #[extendr]
@@ -19,18 +19,23 @@ fn seq_refine(
     ofile2: Option<&str>,
     actions1: Robj,
     actions2: Robj,
+    whitelist: Robj,
+    translation: Option<&str>,
+    barcode_tag: Option<&str>,
     batch_size: usize,
     chunk_bytes: usize,
-    compression_level: i32,
+    compression_level: Option<i32>,
     nqueue: Option<usize>,
     threads: usize,
-) -> std::result::Result<(), String> {
+) -> std::result::Result<(), RError> {
     let actions1 = robj_to_seq_actions(&actions1)
         .with_context(|| format!("Failed to parse actions1"))
-        .map_err(|e| format!("{:?}", e))?;
+        .map_err(RError::from)?;
     let actions2 = robj_to_seq_actions(&actions2)
         .with_context(|| format!("Failed to parse actions2"))
-        .map_err(|e| format!("{:?}", e))?;
+        .map_err(RError::from)?;
+    let correction =
+        robj_to_tag_correction(&whitelist, translation, barcode_tag).map_err(RError::from)?;
     let threads = threads.max(1); // always use at least one thread
     if let Some(fq2) = fq2 {
         seq_refine_paired_read(
@@ -40,25 +45,27 @@ fn seq_refine(
             ofile2,
             actions1,
             actions2,
+            correction,
             batch_size,
             chunk_bytes,
             compression_level,
             nqueue,
             threads,
         )
-        .map_err(|e| format!("{:?}", e))
+        .map_err(RError::from)
     } else {
         seq_refine_single_read(
             fq1,
             ofile1,
             actions1,
+            correction,
             batch_size,
             chunk_bytes,
             compression_level,
             nqueue,
             threads,
         )
-        .map_err(|e| format!("{:?}", e))
+        .map_err(RError::from)
     }
 }
 
@@ -71,18 +78,21 @@ fn pprof_seq_refine(
     ofile2: Option<&str>,
     actions1: Robj,
     actions2: Robj,
+    whitelist: Robj,
+    translation: Option<&str>,
+    barcode_tag: Option<&str>,
     batch_size: usize,
     chunk_bytes: usize,
-    compression_level: i32,
+    compression_level: Option<i32>,
     nqueue: Option<usize>,
     threads: usize,
     pprof_file: &str,
-) -> std::result::Result<(), String> {
+) -> std::result::Result<(), RError> {
     let guard = pprof::ProfilerGuardBuilder::default()
         .frequency(2000)
         .build()
         .with_context(|| format!("cannot create profile guard"))
-        .map_err(|e| format!("{:?}", e))?;
+        .map_err(RError::from)?;
     let out = seq_refine(
         fq1,
         ofile1,
@@ -90,6 +100,9 @@ fn pprof_seq_refine(
         ofile2,
         actions1,
         actions2,
+        whitelist,
+        translation,
+        barcode_tag,
         batch_size,
         chunk_bytes,
         compression_level,
@@ -99,13 +112,13 @@ fn pprof_seq_refine(
     if let Ok(report) = guard.report().build() {
         let file = std::fs::File::create(pprof_file)
             .with_context(|| format!("Failed to create file {}", pprof_file))
-            .map_err(|e| format!("{:?}", e))?;
+            .map_err(RError::from)?;
         let mut options = pprof::flamegraph::Options::default();
         options.image_width = Some(2500);
         report
             .flamegraph_with_options(file, &mut options)
             .with_context(|| format!("Failed to write flamegraph to {}", pprof_file))
-            .map_err(|e| format!("{:?}", e))?;
+            .map_err(RError::from)?;
     };
     out
 }
@@ -114,24 +127,25 @@ fn seq_refine_single_read(
     fq1: &str,
     ofile1: Option<&str>,
     actions: Option<SubseqActions>,
+    correction: Option<TagCorrection>,
     batch_size: usize,
     chunk_bytes: usize,
-    compression_level: i32,
+    compression_level: Option<i32>,
     nqueue: Option<usize>,
     threads: usize,
 ) -> Result<()> {
     let ofile1 = ofile1.ok_or_else(|| anyhow!("No output file specified."))?;
-    let actions = actions.ok_or_else(|| anyhow!("No sequence actions were specified."))?;
+    let actions = actions
+        .ok_or_else(|| anyhow!("No sequence actions were specified."))?
+        .with_correction(correction);
     let reader_style = progress_reader_style()?;
     let writer_style = progress_writer_style()?;
-    let progress = MultiProgress::new();
-    let pb1 = progress.add(
-        ProgressBar::new(std::fs::metadata(fq1)?.len() as u64).with_finish(ProgressFinish::Abandon),
-    );
+    let progress = new_progress();
+    let pb1 = progress.add(input_progress_bar(fq1)?);
     pb1.set_prefix("Reading fastq");
     pb1.set_style(reader_style);
 
-    let pb2 = progress.add(ProgressBar::no_length().with_finish(ProgressFinish::Abandon));
+    let pb2 = progress.add(output_progress_bar());
     pb2.set_prefix("Writing fastq");
     pb2.set_style(writer_style);
 
@@ -156,9 +170,10 @@ fn seq_refine_paired_read(
     ofile2: Option<&str>,
     actions1: Option<SubseqActions>,
     actions2: Option<SubseqActions>,
+    correction: Option<TagCorrection>,
     batch_size: usize,
     chunk_bytes: usize,
-    compression_level: i32,
+    compression_level: Option<i32>,
     nqueue: Option<usize>,
     threads: usize,
 ) -> Result<()> {
@@ -173,14 +188,12 @@ fn seq_refine_paired_read(
 
     let reader_style = progress_reader_style()?;
     let writer_style = progress_writer_style()?;
-    let progress = MultiProgress::new();
-    let pb1 = progress.add(
-        ProgressBar::new(std::fs::metadata(fq1)?.len() as u64).with_finish(ProgressFinish::Abandon),
-    );
+    let progress = new_progress();
+    let pb1 = progress.add(input_progress_bar(fq1)?);
     pb1.set_prefix("Reading fq1");
     pb1.set_style(reader_style.clone());
     let pb2 = if let Some(_) = ofile1 {
-        let pb2 = progress.add(ProgressBar::no_length().with_finish(ProgressFinish::Abandon));
+        let pb2 = progress.add(output_progress_bar());
         pb2.set_prefix("Writing fq1");
         pb2.set_style(writer_style.clone());
         Some(pb2)
@@ -188,13 +201,11 @@ fn seq_refine_paired_read(
         None
     };
 
-    let pb3 = progress.add(
-        ProgressBar::new(std::fs::metadata(fq2)?.len() as u64).with_finish(ProgressFinish::Abandon),
-    );
+    let pb3 = progress.add(input_progress_bar(fq2)?);
     pb3.set_prefix("Reading fq2");
     pb3.set_style(reader_style);
     let pb4 = if let Some(_) = ofile2 {
-        let pb4 = progress.add(ProgressBar::no_length().with_finish(ProgressFinish::Abandon));
+        let pb4 = progress.add(output_progress_bar());
         pb4.set_prefix("Writing fq2");
         pb4.set_style(writer_style);
         Some(pb4)
@@ -202,7 +213,7 @@ fn seq_refine_paired_read(
         None
     };
 
-    let actions = SubseqPairedActions::new(actions1, actions2);
+    let actions = SubseqPairedActions::new(actions1, actions2).with_correction(correction);
     paired::seq_refine_paired_read(
         fq1,
         Some(pb1),
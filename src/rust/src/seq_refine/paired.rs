@@ -7,10 +7,10 @@ use anyhow::{anyhow, Context, Result};
 use bytes::Bytes;
 use crossbeam_channel::{Receiver, Sender};
 use indicatif::ProgressBar;
-use libdeflater::{CompressionLvl, Compressor};
 
 use super::seq_action::*;
 use crate::batchsender::BatchSender;
+use crate::error::ErrorKind;
 use crate::fastq_reader::*;
 use crate::fastq_record::{FastqParseError, FastqRecord};
 use crate::utils::*;
@@ -25,14 +25,15 @@ pub(crate) fn seq_refine_paired_read<P: AsRef<Path> + ?Sized>(
     output2_path: Option<&P>,
     output2_bar: Option<ProgressBar>,
     actions: &SubseqPairedActions,
-    compression_level: i32,
+    compression_level: Option<i32>,
     batch_size: usize,
     chunk_bytes: usize,
     nqueue: Option<usize>,
     threads: usize,
 ) -> Result<()> {
-    let compression_level = CompressionLvl::new(compression_level)
-        .map_err(|e| anyhow!("Invalid 'compression_level': {:?}", e))?;
+    // Both outputs share one level, checked against the format of each
+    let compression1 = Compression::of(output1_path.map(|x| x.as_ref()), compression_level)?;
+    let compression2 = Compression::of(output2_path.map(|x| x.as_ref()), compression_level)?;
     std::thread::scope(|scope| -> Result<()> {
         // Create a channel between the parser and writer threads
         // The channel transmits batches (Vec<FastqRecord>)
@@ -68,7 +69,7 @@ pub(crate) fn seq_refine_paired_read<P: AsRef<Path> + ?Sized>(
                     })?;
                 }
                 writer
-                    .flush()
+                    .complete()
                     .with_context(|| format!("(Writer1) Failed to flush writer"))?;
                 Ok(())
             }));
@@ -89,7 +90,7 @@ pub(crate) fn seq_refine_paired_read<P: AsRef<Path> + ?Sized>(
                     })?;
                 }
                 writer
-                    .flush()
+                    .complete()
                     .with_context(|| format!("(Writer2) Failed to flush writer"))?;
                 Ok(())
             }));
@@ -127,13 +128,14 @@ pub(crate) fn seq_refine_paired_read<P: AsRef<Path> + ?Sized>(
             let handle = scope.spawn(move || -> Result<()> {
                 let mut records1_pool: Vec<u8> = Vec::with_capacity(chunk_bytes);
                 let mut records2_pool: Vec<u8> = Vec::with_capacity(chunk_bytes);
-                let mut compressor = Compressor::new(compression_level);
+                let mut compressor1 = compression1.compressor();
+                let mut compressor2 = compression2.compressor();
                 while let Ok((records1, records2)) = rx.recv() {
                     // Initialize a thread-local batch sender for matching records
                     for (mut record1, mut record2) in zip(records1, records2) {
                         if record1.id != record2.id {
                             return Err(
-                                anyhow!("{}", FastqParseError::FastqPairError { read1_id: String::from_utf8_lossy(&record1.id).to_string(), read2_id: String::from_utf8_lossy(&record2.id).to_string(), read1_pos: None, read2_pos: None }
+                                anyhow::Error::new(FastqParseError::FastqPairError { read1_id: String::from_utf8_lossy(&record1.id).to_string(), read2_id: String::from_utf8_lossy(&record2.id).to_string(), read1_pos: None, read2_pos: None }
                             ));
                         }
                         actions.transform_fastq(&mut record1, &mut record2)?;
@@ -143,7 +145,7 @@ pub(crate) fn seq_refine_paired_read<P: AsRef<Path> + ?Sized>(
                                 let mut pack = Vec::with_capacity(chunk_bytes);
                                 std::mem::swap(&mut records1_pool, &mut pack);
                                 if gzip1 {
-                                    pack = gzip_pack(&pack, &mut compressor)?
+                                    pack = gzip_pack(&pack, &mut compressor1)?
                                 }
                                 Some(pack)
                             } else {
@@ -153,7 +155,7 @@ pub(crate) fn seq_refine_paired_read<P: AsRef<Path> + ?Sized>(
                                 let mut pack = Vec::with_capacity(chunk_bytes);
                                 std::mem::swap(&mut records2_pool, &mut pack);
                                 if gzip2 {
-                                    pack = gzip_pack(&pack, &mut compressor)?
+                                    pack = gzip_pack(&pack, &mut compressor2)?
                                 }
                                 Some(pack)
                             } else {
@@ -172,7 +174,7 @@ pub(crate) fn seq_refine_paired_read<P: AsRef<Path> + ?Sized>(
                 if !records1_pool.is_empty() {
                     let pack1 = if has_writer1 {
                         let pack = if gzip1 {
-                            gzip_pack(&records1_pool, &mut compressor)?
+                            gzip_pack(&records1_pool, &mut compressor1)?
                         } else {
                             records1_pool
                         };
@@ -182,7 +184,7 @@ pub(crate) fn seq_refine_paired_read<P: AsRef<Path> + ?Sized>(
                     };
                     let pack2 = if has_writer2 {
                         let pack = if gzip2 {
-                            gzip_pack(&records2_pool, &mut compressor)?
+                            gzip_pack(&records2_pool, &mut compressor2)?
                         } else {
                             records2_pool
                         };
@@ -209,13 +211,13 @@ pub(crate) fn seq_refine_paired_read<P: AsRef<Path> + ?Sized>(
                 let (records1, records2) = match (reader1_rx.recv(), reader2_rx.recv()) {
                     (Ok(rec1), Ok(rec2)) => (rec1, rec2),
                     (Err(_), Ok(_)) => {
-                        return Err(anyhow!(
-                            "(Reader collect) FASTQ pairing error: read1 channel closed before read2"
+                        return Err(ErrorKind::Pairing.error(
+                            "(Reader collect) FASTQ pairing error: read1 channel closed before read2",
                         ));
                     }
                     (Ok(_), Err(_)) => {
-                        return Err(anyhow!(
-                            "(Reader collect) FASTQ pairing error: read2 channel closed before read1"
+                        return Err(ErrorKind::Pairing.error(
+                            "(Reader collect) FASTQ pairing error: read2 channel closed before read1",
                         ));
                     }
                     (Err(_), Err(_)) => {
@@ -223,7 +225,11 @@ pub(crate) fn seq_refine_paired_read<P: AsRef<Path> + ?Sized>(
                     }
                 };
                 if records1.len() != records2.len() {
-                    return Err(anyhow!("(Reader collect) FASTQ pairing error: record count mismatch (read1: {}, read2: {})", records1.len(), records2.len()));
+                    return Err(ErrorKind::Pairing.error(format!(
+                        "(Reader collect) FASTQ pairing error: record count mismatch (read1: {}, read2: {})",
+                        records1.len(),
+                        records2.len()
+                    )));
                 }
                 reader_tx.send((records1, records2)).with_context(|| {
                     format!(
@@ -362,7 +368,7 @@ mod tests {
             Some(&out2_path),
             None,
             &paired_actions,
-            4,         // compression
+            Some(4),   // compression
             1,         // chunk size
             64 * 1024, // buffer size
             Some(2),   // queue size
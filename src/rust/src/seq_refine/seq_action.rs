@@ -1,12 +1,19 @@
+use std::borrow::Cow;
+
 use anyhow::{anyhow, Error, Result};
 use bytes::{BufMut, Bytes, BytesMut};
 use extendr_api::prelude::*;
 use rustc_hash::FxHashMap as HashMap;
 
+use crate::error::ErrorKind;
 use crate::fastq_record::FastqRecord;
 use crate::seq_range::{check_overlap, SeqRange, SeqRanges};
 use crate::seq_tag::*;
 use crate::utils::*;
+use crate::whitelist::{Translation, Whitelist};
+
+/// The subsequences collected for each tag
+type TagMap<'a> = HashMap<Bytes, Vec<&'a [u8]>>;
 
 pub(in crate::seq_refine) struct SubseqEmbedActions {
     tags: TagRanges,
@@ -21,9 +28,21 @@ impl SubseqEmbedActions {
         self.tags.len() > 0
     }
 
-    fn embed(&self, record: &mut FastqRecord<Bytes>) -> Result<()> {
+    fn embed(
+        &self,
+        record: &mut FastqRecord<Bytes>,
+        correction: Option<&TagCorrection>,
+    ) -> Result<()> {
         if self.has_action() {
-            let tag_map = self.tags.map_sequences(&record.seq)?;
+            let corrected;
+            let mut tag_map = self.tags.map_sequences(&record.seq)?;
+            if let Some(correction) = correction {
+                let qual_map = self.tags.map_sequences(&record.qual)?;
+                corrected = correction.correct(&tag_map, &qual_map);
+                if let Some(barcode) = &corrected {
+                    tag_map.insert(correction.tag.clone(), vec![&barcode[..]]);
+                }
+            }
             record.desc = Some(make_description(
                 &tag_map,
                 &record.desc.as_ref().map(|d| d.as_ref()),
@@ -33,6 +52,80 @@ impl SubseqEmbedActions {
     }
 }
 
+/// Correction of the barcode embedded under `tag` to a barcode of the
+/// whitelist, and translation of the barcode to the barcode of another assay,
+/// applied to the sequence collected from all its ranges
+pub(in crate::seq_refine) struct TagCorrection {
+    tag: Bytes,
+    whitelist: Option<Whitelist>,
+    translation: Option<Translation>,
+}
+
+impl TagCorrection {
+    pub(in crate::seq_refine) fn new(
+        tag: Bytes,
+        whitelist: Option<Whitelist>,
+        translation: Option<Translation>,
+    ) -> Self {
+        Self {
+            tag,
+            whitelist,
+            translation,
+        }
+    }
+
+    /// Returns the barcode replacing the barcode of a read, `None` if the
+    /// barcode is kept as read: a whitelisted barcode, or a barcode that
+    /// cannot be corrected, without translation.
+    fn correct(&self, tag_map: &TagMap, qual_map: &TagMap) -> Option<Vec<u8>> {
+        let raw = tag_map.get(&self.tag)?.concat();
+        let barcode = match &self.whitelist {
+            Some(whitelist) => {
+                let qual = qual_map.get(&self.tag).map(|qual| qual.concat());
+                whitelist.correct(&raw, qual.as_deref())
+            }
+            None => None,
+        }
+        .unwrap_or(Cow::Borrowed(&raw));
+        // Barcodes missing from the translation table are kept
+        if let Some(translated) = self
+            .translation
+            .as_ref()
+            .and_then(|translation| translation.get(&barcode))
+        {
+            return Some(translated.to_vec());
+        }
+        match barcode {
+            Cow::Owned(corrected) => Some(corrected),
+            Cow::Borrowed(_) => None,
+        }
+    }
+}
+
+pub(in crate::seq_refine) fn robj_to_tag_correction(
+    whitelist: &Robj,
+    translation: Option<&str>,
+    tag: Option<&str>,
+) -> Result<Option<TagCorrection>> {
+    if whitelist.is_null() && translation.is_none() {
+        return Ok(None);
+    }
+    let tag = tag.ok_or_else(|| anyhow!("No barcode tag to correct or translate"))?;
+    let whitelist = if whitelist.is_null() {
+        None
+    } else {
+        let whitelist = <&Whitelist>::try_from(whitelist)
+            .map_err(|e| ErrorKind::Config.error(format!("Invalid whitelist: {:?}", e)))?;
+        Some(whitelist.clone())
+    };
+    let translation = translation.map(Translation::from_path).transpose()?;
+    Ok(Some(TagCorrection::new(
+        Bytes::copy_from_slice(tag.as_bytes()),
+        whitelist,
+        translation,
+    )))
+}
+
 struct SubseqTrimActions {
     ranges: SeqRanges,
 }
@@ -111,25 +204,31 @@ impl SubseqTrimActions {
     }
 }
 
-pub(in crate::seq_refine) struct SubseqActions {
+pub(crate) struct SubseqActions {
     embed: SubseqEmbedActions,
     trim: SubseqTrimActions,
+    correction: Option<TagCorrection>,
 }
 
 impl SubseqActions {
     #[allow(dead_code)]
-    pub(in crate::seq_refine) fn builder() -> SubseqActionsBuilder {
+    pub(crate) fn builder() -> SubseqActionsBuilder {
         SubseqActionsBuilder {
             embed_list: Vec::new(),
             trim_list: Vec::new(),
         }
     }
 
-    pub(in crate::seq_refine) fn transform_fastq(
-        &self,
-        record: &mut FastqRecord<Bytes>,
-    ) -> Result<()> {
-        self.embed.embed(record)?;
+    pub(in crate::seq_refine) fn with_correction(
+        mut self,
+        correction: Option<TagCorrection>,
+    ) -> Self {
+        self.correction = correction;
+        self
+    }
+
+    pub(crate) fn transform_fastq(&self, record: &mut FastqRecord<Bytes>) -> Result<()> {
+        self.embed.embed(record, self.correction.as_ref())?;
         self.trim.trim(record)?;
 
         Ok(())
@@ -138,20 +237,31 @@ impl SubseqActions {
 
 /// Paired-end FASTQ transformation logic using optional tag embedding and trimming.
 /// Each read end (read1 or read2) can have its own independent action configuration.
-pub(in crate::seq_refine) struct SubseqPairedActions {
+pub(crate) struct SubseqPairedActions {
     actions1: Option<SubseqActions>,
     actions2: Option<SubseqActions>,
+    correction: Option<TagCorrection>,
 }
 
 impl SubseqPairedActions {
-    pub(in crate::seq_refine) fn new(
-        actions1: Option<SubseqActions>,
-        actions2: Option<SubseqActions>,
+    pub(crate) fn new(actions1: Option<SubseqActions>, actions2: Option<SubseqActions>) -> Self {
+        Self {
+            actions1,
+            actions2,
+            correction: None,
+        }
+    }
+
+    /// Correct the barcode merged from both reads
+    pub(in crate::seq_refine) fn with_correction(
+        mut self,
+        correction: Option<TagCorrection>,
     ) -> Self {
-        Self { actions1, actions2 }
+        self.correction = correction;
+        self
     }
 
-    pub(in crate::seq_refine) fn transform_fastq(
+    pub(crate) fn transform_fastq(
         &self,
         record1: &mut FastqRecord<Bytes>,
         record2: &mut FastqRecord<Bytes>,
@@ -179,30 +289,26 @@ impl SubseqPairedActions {
     ///   the sequences are concatenated in read1-first, read2-second order.
     ///
     /// Tags are serialized using `make_description()` and applied to both reads' description fields.
+    /// If a barcode correction is set, the barcode is corrected after merging,
+    /// with the qualities collected from the same ranges.
     fn embedded_labels(
         &self,
         record1: &mut FastqRecord<Bytes>,
         record2: &mut FastqRecord<Bytes>,
     ) -> Result<()> {
-        let tag_map = match (&self.actions1, &self.actions2) {
-            (Some(actions), None) => actions.embed.tags.map_sequences(&record1.seq)?,
-            (None, Some(actions)) => actions.embed.tags.map_sequences(&record2.seq)?,
-            (Some(actions1), Some(actions2)) => {
-                let mut tag_map = actions1.embed.tags.map_sequences(&record1.seq)?;
-                let tag_map2 = actions2.embed.tags.map_sequences(&record2.seq)?;
-
-                // Merge tag→sequence entries
-                for (tag, sequences) in tag_map2 {
-                    if let Some(v) = tag_map.get_mut(&tag) {
-                        v.extend(sequences); // read1 first, read2 second
-                    } else {
-                        tag_map.insert(tag, sequences);
-                    }
-                }
-                tag_map
-            }
-            (None, None) => return Ok(()),
+        let corrected;
+        let Some(mut tag_map) = self.map_sequences(&record1.seq, &record2.seq)? else {
+            return Ok(());
         };
+        if let Some(correction) = &self.correction {
+            let qual_map = self
+                .map_sequences(&record1.qual, &record2.qual)?
+                .unwrap_or_default();
+            corrected = correction.correct(&tag_map, &qual_map);
+            if let Some(barcode) = &corrected {
+                tag_map.insert(correction.tag.clone(), vec![&barcode[..]]);
+            }
+        }
 
         // Only write to description fields if any tag was collected
         if tag_map.len() > 0 {
@@ -217,12 +323,37 @@ impl SubseqPairedActions {
         }
         Ok(())
     }
+
+    /// Collect the tagged subsequences of `bytes1` and `bytes2` (the sequences
+    /// or the qualities of both reads), `None` if no read has embedding.
+    fn map_sequences<'a>(&self, bytes1: &'a [u8], bytes2: &'a [u8]) -> Result<Option<TagMap<'a>>> {
+        let tag_map = match (&self.actions1, &self.actions2) {
+            (Some(actions), None) => actions.embed.tags.map_sequences(bytes1)?,
+            (None, Some(actions)) => actions.embed.tags.map_sequences(bytes2)?,
+            (Some(actions1), Some(actions2)) => {
+                let mut tag_map = actions1.embed.tags.map_sequences(bytes1)?;
+                let tag_map2 = actions2.embed.tags.map_sequences(bytes2)?;
+
+                // Merge tag→sequence entries
+                for (tag, sequences) in tag_map2 {
+                    if let Some(v) = tag_map.get_mut(&tag) {
+                        v.extend(sequences); // read1 first, read2 second
+                    } else {
+                        tag_map.insert(tag, sequences);
+                    }
+                }
+                tag_map
+            }
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(tag_map))
+    }
 }
 
 /// Builder pattern for constructing `SubseqActions` step-by-step.
 /// Allows the user to accumulate multiple `embed` and `trim` operations,
 /// validating them before finalizing into a concrete `SubseqActions` instance.
-pub(in crate::seq_refine) struct SubseqActionsBuilder {
+pub(crate) struct SubseqActionsBuilder {
     embed_list: Vec<(Bytes, SeqRanges)>,
     trim_list: Vec<SeqRanges>,
 }
@@ -252,11 +383,7 @@ impl SubseqActionsBuilder {
 
     /// Adds a compound action to the builder.
     /// Dispatches to `embed`, `trim`, or both depending on action variant.
-    pub(in crate::seq_refine) fn add_action(
-        &mut self,
-        action: SeqAction,
-        ranges: SeqRanges,
-    ) -> Result<()> {
+    pub(crate) fn add_action(&mut self, action: SeqAction, ranges: SeqRanges) -> Result<()> {
         match action {
             SeqAction::Embed(tag) => {
                 self.add_embed(tag, ranges)?;
@@ -278,7 +405,7 @@ impl SubseqActionsBuilder {
     /// Trimming ranges from multiple calls may arrive unsorted and overlapping across entries.
     /// Sorting at build time ensures that the full set of ranges is globally ordered and
     /// ready for efficient trimming operations.
-    pub(in crate::seq_refine) fn build(self) -> Result<SubseqActions> {
+    pub(crate) fn build(self) -> Result<SubseqActions> {
         let tag_ranges = self
             .embed_list
             .into_iter()
@@ -294,20 +421,19 @@ impl SubseqActionsBuilder {
         Ok(SubseqActions {
             embed: embed_actions,
             trim: SubseqTrimActions::new(full_ranges),
+            correction: None,
         })
     }
 }
 
-pub(in crate::seq_refine) enum SeqAction {
+pub(crate) enum SeqAction {
     Embed(Bytes),
     Trim,
     EmbedTrim(Bytes),
 }
 
 // Create object from R
-pub(in crate::seq_refine) fn robj_to_seq_actions<'r>(
-    ranges: &Robj,
-) -> Result<Option<SubseqActions>> {
+pub(crate) fn robj_to_seq_actions<'r>(ranges: &Robj) -> Result<Option<SubseqActions>> {
     if ranges.is_null() {
         return Ok(None);
     }
@@ -350,6 +476,15 @@ impl TryFrom<&Robj> for SeqAction {
 }
 
 fn make_description(tag_map: &HashMap<Bytes, Vec<&[u8]>>, desc: &Option<&[u8]>) -> Bytes {
+    // Reads refined again keep a single annotation, with the tags of earlier
+    // runs carried through
+    if let Some(desc) = desc.filter(|desc| TAG_PREFIX_FINDER.find(desc).is_some()) {
+        let tags: Vec<(&[u8], Vec<u8>)> = tag_map
+            .iter()
+            .map(|(tag, sequences)| (tag.as_ref(), sequences.concat()))
+            .collect();
+        return set_mire_tags(desc, &tags);
+    }
     // add prefix, tag and seprator
     let mut out = BytesMut::with_capacity(
         // original description length
@@ -5,7 +5,6 @@ use anyhow::{anyhow, Context, Result};
 use bytes::Bytes;
 use crossbeam_channel::{Receiver, Sender};
 use indicatif::ProgressBar;
-use libdeflater::{CompressionLvl, Compressor};
 
 use super::seq_action::*;
 use crate::batchsender::BatchSender;
@@ -19,7 +18,7 @@ pub(crate) fn seq_refine_single_read<P: AsRef<Path> + ?Sized>(
     output_path: &P,
     output_bar: Option<ProgressBar>,
     actions: &SubseqActions,
-    compression_level: i32,
+    compression_level: Option<i32>,
     batch_size: usize,
     chunk_bytes: usize,
     nqueue: Option<usize>,
@@ -28,10 +27,7 @@ pub(crate) fn seq_refine_single_read<P: AsRef<Path> + ?Sized>(
     let input: &Path = input_path.as_ref();
     let output: &Path = output_path.as_ref();
 
-    // Ensure compression level is validated and converted before entering thread scope.
-    // Doing this outside avoids redundant validation across parser threads.
-    let compression_level = CompressionLvl::new(compression_level)
-        .map_err(|e| anyhow!("Invalid 'compression_level': {:?}", e))?;
+    let compression = Compression::new(output, compression_level)?;
     std::thread::scope(|scope| -> Result<()> {
         // Two communication pipelines are set up to decouple IO and CPU-intensive work:
         // - reader_tx: transfers raw FASTQ records to parser threads
@@ -55,7 +51,7 @@ pub(crate) fn seq_refine_single_read<P: AsRef<Path> + ?Sized>(
                     .with_context(|| format!("(Writer) Failed to write FastqRecord to output"))?;
             }
             writer
-                .flush()
+                .complete()
                 .with_context(|| format!("(Writer) Failed to flush writer"))?;
             Ok(())
         });
@@ -72,7 +68,7 @@ pub(crate) fn seq_refine_single_read<P: AsRef<Path> + ?Sized>(
             let handle = scope.spawn(move || -> Result<()> {
                 // Temporary buffer for current output chunk
                 let mut records_pool: Vec<u8> = Vec::with_capacity(chunk_bytes);
-                let mut compressor = Compressor::new(compression_level);
+                let mut compressor = compression.compressor();
                 while let Ok(records) = rx.recv() {
                     for mut record in records {
                         // Apply trimming, tag embedding, and other sequence transformations
@@ -200,7 +196,7 @@ mod tests {
             &output_path,
             None, // No progress bar
             &actions,
-            1,       // No compression
+            Some(1), // No compression
             1,       // chunk size
             8192,    // buffer size
             Some(2), // queue size
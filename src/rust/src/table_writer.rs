@@ -0,0 +1,235 @@
+use std::fmt::Display;
+use std::io::Write;
+use std::path::Path;
+use std::thread::{Scope, ScopedJoinHandle};
+
+use anyhow::{anyhow, Context, Result};
+use crossbeam_channel::Sender;
+
+use crate::part_writer::PartWriter;
+use crate::utils::*;
+
+/// The delimiter of a table by the extension of its path, before any
+/// compression extension: comma for `.csv`, tab otherwise
+pub(crate) fn table_delimiter(path: &Path) -> u8 {
+    let name = path
+        .file_name()
+        .map(|x| x.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    let name = name
+        .strip_suffix(".gz")
+        .or_else(|| name.strip_suffix(".zst"))
+        .unwrap_or(&name);
+    if name.ends_with(".csv") {
+        b','
+    } else {
+        b'\t'
+    }
+}
+
+/// Where the packed chunks of a table go
+enum TableSink<'a> {
+    File(Box<PartWriter<'a>>),
+    // Chunks are written by a dedicated writer thread
+    Thread(Sender<Vec<u8>>),
+}
+
+/// Writes a delimited table row by row, compressed by the extension of its
+/// path.
+///
+/// Rows are buffered into chunks of about `chunk_bytes`, each chunk is packed
+/// (one gzip member or zstd frame) as a whole, so a chunk always holds whole
+/// rows. Fields holding the delimiter, a quote or a line break are quoted,
+/// with quotes doubled, as read by `read.csv()` or `read.delim()`.
+pub(crate) struct TableWriter<'a> {
+    sink: TableSink<'a>,
+    packer: ChunkPacker,
+    delimiter: u8,
+    pool: Vec<u8>,
+    chunk_bytes: usize,
+    // Fields of the current row
+    fields: usize,
+}
+
+impl<'a> TableWriter<'a> {
+    /// A table written in the current thread
+    pub(crate) fn new(path: &'a Path, compression: Compression, chunk_bytes: usize) -> Self {
+        let writer = Box::new(PartWriter::new(path, None, chunk_bytes, None));
+        Self::with_sink(TableSink::File(writer), path, compression, chunk_bytes)
+    }
+
+    /// A table packed in the current thread and written by a writer thread
+    /// spawned in `scope`. The handle of the writer thread must be joined after
+    /// [`TableWriter::finish`], it reports the errors of writing the file.
+    pub(crate) fn spawn<'scope>(
+        scope: &'scope Scope<'scope, 'a>,
+        path: &'a Path,
+        compression: Compression,
+        chunk_bytes: usize,
+        nqueue: Option<usize>,
+    ) -> (Self, ScopedJoinHandle<'scope, Result<()>>) {
+        let (writer_tx, writer_rx) = new_channel::<Vec<u8>>(nqueue);
+
+        // ─── Writer Thread ─────────────────────────────────────
+        let handle = scope.spawn(move || -> Result<()> {
+            let mut writer = PartWriter::new(path, None, chunk_bytes, None);
+            for chunk in writer_rx {
+                writer
+                    .write_part(0, &chunk)
+                    .context("(Writer) Failed to write table rows")?;
+            }
+            writer.finish().context("(Writer) Failed to flush writer")
+        });
+        let table = Self::with_sink(TableSink::Thread(writer_tx), path, compression, chunk_bytes);
+        (table, handle)
+    }
+
+    fn with_sink(
+        sink: TableSink<'a>,
+        path: &Path,
+        compression: Compression,
+        chunk_bytes: usize,
+    ) -> Self {
+        Self {
+            sink,
+            packer: ChunkPacker::new(compression),
+            delimiter: table_delimiter(path),
+            pool: Vec::with_capacity(chunk_bytes),
+            chunk_bytes,
+            fields: 0,
+        }
+    }
+
+    /// Add a field to the current row, quoted if needed
+    pub(crate) fn field(&mut self, value: &[u8]) {
+        self.separate();
+        let delimiter = self.delimiter;
+        if value
+            .iter()
+            .any(|&x| x == delimiter || matches!(x, b'"' | b'\n' | b'\r'))
+        {
+            self.pool.push(b'"');
+            for &x in value {
+                if x == b'"' {
+                    self.pool.push(b'"');
+                }
+                self.pool.push(x);
+            }
+            self.pool.push(b'"');
+        } else {
+            self.pool.extend_from_slice(value);
+        }
+    }
+
+    /// Add a number (or any value which never needs quoting) to the current
+    /// row
+    pub(crate) fn number<T: Display>(&mut self, value: T) {
+        self.separate();
+        // Writing into a `Vec` cannot fail
+        write!(self.pool, "{}", value).ok();
+    }
+
+    fn separate(&mut self) {
+        if self.fields > 0 {
+            self.pool.push(self.delimiter);
+        }
+        self.fields += 1;
+    }
+
+    /// Terminate the current row
+    pub(crate) fn end_row(&mut self) -> Result<()> {
+        self.pool.push(b'\n');
+        self.fields = 0;
+        if self.pool.len() >= self.chunk_bytes {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write a whole row of fields
+    pub(crate) fn row<I, T>(&mut self, fields: I) -> Result<()>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        for value in fields {
+            self.field(value.as_ref());
+        }
+        self.end_row()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let pool = std::mem::replace(&mut self.pool, Vec::with_capacity(self.chunk_bytes));
+        let pack = self.packer.pack(pool)?;
+        match &mut self.sink {
+            TableSink::File(writer) => writer
+                .write_part(0, &pack)
+                .context("(Writer) Failed to write table rows"),
+            TableSink::Thread(writer_tx) => writer_tx
+                .send(pack)
+                .map_err(|_| anyhow!("(Writer) Table writer thread stopped")),
+        }
+    }
+
+    /// Write the remaining rows. A row left without its end is terminated.
+    pub(crate) fn finish(mut self) -> Result<()> {
+        if self.fields > 0 {
+            self.end_row()?;
+        }
+        if !self.pool.is_empty() {
+            self.flush()?;
+        }
+        match &mut self.sink {
+            TableSink::File(writer) => writer.finish().context("(Writer) Failed to flush writer"),
+            // Dropping the sender ends the writer thread
+            TableSink::Thread(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::MultiGzDecoder;
+
+    use super::*;
+
+    #[test]
+    fn test_table_writer() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+
+        let path = temp.path().join("table.csv");
+        let mut writer = TableWriter::new(&path, Compression::new(&path, None)?, 8);
+        writer.row(["taxid", "name"])?;
+        writer.number(562);
+        writer.field(b"Escherichia coli, \"K-12\"");
+        writer.end_row()?;
+        writer.field(b"9606");
+        writer.finish()?;
+        assert_eq!(
+            std::fs::read_to_string(&path)?,
+            "taxid,name\n562,\"Escherichia coli, \"\"K-12\"\"\"\n9606\n"
+        );
+
+        let path = temp.path().join("table.tsv.gz");
+        std::thread::scope(|scope| -> Result<()> {
+            let (mut writer, handle) =
+                TableWriter::spawn(scope, &path, Compression::new(&path, None)?, 8, Some(1));
+            for i in 0 .. 100 {
+                writer.row([i.to_string().as_bytes(), b"a,b".as_slice()])?;
+            }
+            writer.finish()?;
+            handle
+                .join()
+                .map_err(|e| anyhow!("(Writer) thread panicked: {:?}", e))?
+        })?;
+        let mut table = String::new();
+        MultiGzDecoder::new(std::fs::File::open(&path)?).read_to_string(&mut table)?;
+        assert_eq!(table.lines().count(), 100);
+        assert_eq!(table.lines().nth(42), Some("42\ta,b"));
+        assert_eq!(table_delimiter(Path::new("x.CSV.zst")), b',');
+        assert_eq!(table_delimiter(Path::new("x.txt")), b'\t');
+        Ok(())
+    }
+}
@@ -0,0 +1,383 @@
+use std::io::{Read, Write};
+use std::path::Path;
+
+const BLOCK_SIZE: u64 = 512;
+
+/// Split an `archive.tar#pattern` input into the archive path and the glob
+/// pattern of the members to read. Returns `None` for any other input.
+pub(crate) fn split_tar_member(path: &Path) -> Option<(&Path, &str)> {
+    let (archive, pattern) = path.to_str()?.rsplit_once('#')?;
+    if pattern.is_empty() {
+        return None;
+    }
+    let name = crate::remote::remote_object_path(Path::new(archive))
+        .to_str()?
+        .to_ascii_lowercase();
+    if name.ends_with(".tar") || name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some((Path::new(archive), pattern))
+    } else {
+        None
+    }
+}
+
+/// Match a member name against a glob pattern: `*` matches any sequence of
+/// characters (including `/`), `?` matches any single character.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and of the name when it was reached
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((sp, sn)) = star {
+            // Let the last `*` absorb one more character
+            p = sp + 1;
+            n = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p ..].iter().all(|b| *b == b'*')
+}
+
+/// Reader over the concatenated contents of the tar archive members whose
+/// name matches a glob pattern, in archive order.
+///
+/// The archive is streamed: members which don't match are skipped without
+/// being buffered, so the FASTQs of a multi-terabyte delivery can be read
+/// without unpacking it. Both ustar and GNU/PAX extensions for long names
+/// and large (> 8 GiB) members are supported.
+pub(crate) struct TarMembers<R> {
+    reader: R,
+    pattern: String,
+    remaining: u64, // bytes of the current member left to read
+    skip: u64,      // bytes to discard before the next header
+    matched: usize,
+    done: bool,
+}
+
+impl<R: Read> TarMembers<R> {
+    pub(crate) fn new(reader: R, pattern: &str) -> Self {
+        Self {
+            reader,
+            pattern: pattern.to_string(),
+            remaining: 0,
+            skip: 0,
+            matched: 0,
+            done: false,
+        }
+    }
+
+    /// Advance to the next matching member, or to the end of the archive
+    fn next_member(&mut self) -> std::io::Result<()> {
+        let mut long_name: Option<Vec<u8>> = None;
+        let mut long_size: Option<u64> = None;
+        loop {
+            let skip = std::mem::take(&mut self.skip);
+            self.discard(skip)?;
+            let mut header = [0u8; BLOCK_SIZE as usize];
+            if !self.read_block(&mut header)? || header.iter().all(|b| *b == 0) {
+                self.done = true;
+                if self.matched == 0 {
+                    return Err(std::io::Error::other(format!(
+                        "No member of the tar archive matches '{}'",
+                        self.pattern
+                    )));
+                }
+                return Ok(());
+            }
+            let size = long_size.take().map_or_else(|| header_size(&header), Ok)?;
+            let padded = size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+            match header[156] {
+                // GNU long name of the next member
+                b'L' => {
+                    let mut name = self.read_data(size, padded)?;
+                    // The name is stored NUL-terminated
+                    while name.last() == Some(&0) {
+                        name.pop();
+                    }
+                    long_name = Some(name);
+                    continue;
+                }
+                // PAX extended header of the next member
+                b'x' => {
+                    let data = self.read_data(size, padded)?;
+                    for (key, value) in pax_records(&data) {
+                        match key {
+                            b"path" => long_name = Some(value.to_vec()),
+                            b"size" => {
+                                long_size =
+                                    std::str::from_utf8(value).ok().and_then(|x| x.parse().ok())
+                            }
+                            _ => {}
+                        }
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            let name = long_name.take().unwrap_or_else(|| header_name(&header));
+            let regular = header[156] == b'0' || header[156] == 0;
+            if regular && glob_match(self.pattern.as_bytes(), &name) {
+                self.matched += 1;
+                self.remaining = size;
+                self.skip = padded - size;
+                return Ok(());
+            }
+            self.skip = padded;
+        }
+    }
+
+    /// Read a full header block, `false` at a clean end of stream
+    fn read_block(&mut self, block: &mut [u8]) -> std::io::Result<bool> {
+        let mut filled = 0;
+        while filled < block.len() {
+            match self.reader.read(&mut block[filled ..])? {
+                0 if filled == 0 => return Ok(false),
+                0 => return Err(truncated()),
+                n => filled += n,
+            }
+        }
+        Ok(true)
+    }
+
+    fn read_data(&mut self, size: u64, padded: u64) -> std::io::Result<Vec<u8>> {
+        let mut data = vec![0u8; size as usize];
+        self.reader.read_exact(&mut data)?;
+        self.discard(padded - size)?;
+        Ok(data)
+    }
+
+    fn discard(&mut self, nbytes: u64) -> std::io::Result<()> {
+        let skipped = std::io::copy(&mut self.reader.by_ref().take(nbytes), &mut std::io::sink())?;
+        if skipped < nbytes {
+            return Err(truncated());
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for TarMembers<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.remaining == 0 {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_member()?;
+        }
+        let len = buf.len().min(self.remaining as usize);
+        let nbytes = self.reader.read(&mut buf[.. len])?;
+        if nbytes == 0 {
+            return Err(truncated());
+        }
+        self.remaining -= nbytes as u64;
+        Ok(nbytes)
+    }
+}
+
+/// Writer of a tar archive whose members are added whole, one at a time.
+///
+/// Member names longer than the 100 bytes of a ustar header are written with a
+/// GNU long name entry, and sizes above 8 GiB with the GNU base-256 encoding,
+/// both of which are understood by GNU tar, bsdtar and [`TarMembers`].
+/// Timestamps are left at zero so archives are reproducible.
+pub(crate) struct TarWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    pub(crate) fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Add a regular file member
+    pub(crate) fn append(&mut self, name: &str, data: &[u8]) -> std::io::Result<()> {
+        if name.len() > 100 {
+            let mut long_name = name.as_bytes().to_vec();
+            long_name.push(0);
+            self.append_entry(b"././@LongLink", b'L', &long_name)?;
+        }
+        self.append_entry(name.as_bytes(), b'0', data)
+    }
+
+    fn append_entry(&mut self, name: &[u8], typeflag: u8, data: &[u8]) -> std::io::Result<()> {
+        let mut header = [0u8; BLOCK_SIZE as usize];
+        let name = &name[.. name.len().min(100)];
+        header[.. name.len()].copy_from_slice(name);
+        header[100 .. 107].copy_from_slice(b"0000644");
+        header[108 .. 115].copy_from_slice(b"0000000");
+        header[116 .. 123].copy_from_slice(b"0000000");
+        let size = data.len() as u64;
+        if size < 1 << 33 {
+            header[124 .. 135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        } else {
+            header[124] = 0x80;
+            header[128 .. 136].copy_from_slice(&size.to_be_bytes());
+        }
+        header[136 .. 147].copy_from_slice(b"00000000000");
+        header[156] = typeflag;
+        header[257 .. 263].copy_from_slice(b"ustar\0");
+        header[263 .. 265].copy_from_slice(b"00");
+        // The checksum is computed with its own field filled with spaces
+        header[148 .. 156].fill(b' ');
+        let checksum: u32 = header.iter().map(|b| u32::from(*b)).sum();
+        header[148 .. 155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(data)?;
+        let padding = (BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE;
+        self.writer.write_all(&vec![0u8; padding as usize])
+    }
+
+    /// Write the end-of-archive marker, returning the underlying writer for
+    /// the caller to flush
+    pub(crate) fn finish(mut self) -> std::io::Result<W> {
+        self.writer.write_all(&[0u8; 2 * BLOCK_SIZE as usize])?;
+        Ok(self.writer)
+    }
+}
+
+fn truncated() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Truncated tar archive")
+}
+
+fn header_name(header: &[u8]) -> Vec<u8> {
+    let field = |bytes: &[u8]| -> Vec<u8> {
+        let end = memchr::memchr(0, bytes).unwrap_or(bytes.len());
+        bytes[.. end].to_vec()
+    };
+    let name = field(&header[0 .. 100]);
+    // POSIX ustar stores long paths split into a prefix and a name, the GNU
+    // format (magic `ustar  `) uses these bytes for other fields
+    let prefix = if &header[257 .. 263] == b"ustar\0" {
+        field(&header[345 .. 500])
+    } else {
+        Vec::new()
+    };
+    if prefix.is_empty() {
+        name
+    } else {
+        [prefix.as_slice(), b"/", name.as_slice()].concat()
+    }
+}
+
+fn header_size(header: &[u8]) -> std::io::Result<u64> {
+    let field = &header[124 .. 136];
+    // GNU base-256 encoding for members larger than 8 GiB
+    if field[0] & 0x80 != 0 {
+        let size = field[1 ..]
+            .iter()
+            .fold(u64::from(field[0] & 0x7f), |acc, b| {
+                (acc << 8) | u64::from(*b)
+            });
+        return Ok(size);
+    }
+    let digits = field
+        .iter()
+        .copied()
+        .filter(|b| *b != 0 && *b != b' ')
+        .collect::<Vec<u8>>();
+    std::str::from_utf8(&digits)
+        .ok()
+        .and_then(|x| u64::from_str_radix(x, 8).ok())
+        .ok_or_else(|| std::io::Error::other("Invalid size in tar header"))
+}
+
+/// Parse PAX records: `<length> <key>=<value>\n`
+fn pax_records(mut data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut records = Vec::new();
+    while let Some(space) = memchr::memchr(b' ', data) {
+        let Some(len) = std::str::from_utf8(&data[.. space])
+            .ok()
+            .and_then(|x| x.parse::<usize>().ok())
+            .filter(|len| *len > space + 1 && *len <= data.len())
+        else {
+            break;
+        };
+        let record = &data[space + 1 .. len - 1];
+        if let Some(eq) = memchr::memchr(b'=', record) {
+            records.push((&record[.. eq], &record[eq + 1 ..]));
+        }
+        data = &data[len ..];
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tar_entry(name: &str, typeflag: u8, data: &[u8]) -> Vec<u8> {
+        let mut header = [0u8; 512];
+        header[.. name.len()].copy_from_slice(name.as_bytes());
+        header[124 .. 135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+        header[156] = typeflag;
+        header[257 .. 263].copy_from_slice(b"ustar\0");
+        let mut entry = header.to_vec();
+        entry.extend_from_slice(data);
+        entry.resize(entry.len().div_ceil(512) * 512, 0);
+        entry
+    }
+
+    #[test]
+    fn test_tar_members() -> std::io::Result<()> {
+        let record = "path=run/sample_R1_002.fastq\n";
+        let pax = format!("{} {}", record.len() + 3, record);
+        let archive = [
+            tar_entry("run/", b'5', b""),
+            tar_entry("run/sample_R1_001.fastq", b'0', b"@r1\nACGT\n+\nIIII\n"),
+            tar_entry("run/sample_R2_001.fastq", b'0', b"@r1\nTTTT\n+\nIIII\n"),
+            tar_entry("PaxHeaders/long", b'x', pax.as_bytes()),
+            tar_entry("truncated-name", b'0', b"@r2\nGGGG\n+\nIIII\n"),
+            vec![0u8; 1024],
+        ]
+        .concat();
+
+        let mut out = String::new();
+        TarMembers::new(archive.as_slice(), "*_R1_*.fastq").read_to_string(&mut out)?;
+        assert_eq!(out, "@r1\nACGT\n+\nIIII\n@r2\nGGGG\n+\nIIII\n");
+
+        out.clear();
+        TarMembers::new(archive.as_slice(), "run/sample_R2_00?.fastq").read_to_string(&mut out)?;
+        assert_eq!(out, "@r1\nTTTT\n+\nIIII\n");
+
+        assert!(TarMembers::new(archive.as_slice(), "*.fq")
+            .read_to_string(&mut out)
+            .is_err());
+        assert!(TarMembers::new(&archive[.. 1000], "*_R1_*")
+            .read_to_end(&mut Vec::new())
+            .is_err());
+
+        assert_eq!(
+            split_tar_member(Path::new("delivery.tar.gz#*_R1_*.fastq.gz")),
+            Some((Path::new("delivery.tar.gz"), "*_R1_*.fastq.gz"))
+        );
+        assert_eq!(split_tar_member(Path::new("reads#1.fastq")), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tar_writer() -> std::io::Result<()> {
+        let long_name = format!("{}/AAACCCAAGTTCATCG.fastq", "cells".repeat(30));
+        let mut writer = TarWriter::new(Vec::new());
+        writer.append("AAACCCAAGAAACACT.fastq", b"@r1\nACGT\n+\nIIII\n")?;
+        writer.append(&long_name, b"@r2\nGGGG\n+\nIIII\n")?;
+        let archive = writer.finish()?;
+        assert_eq!(archive.len() % 512, 0);
+
+        let mut out = String::new();
+        TarMembers::new(archive.as_slice(), "*AAACCCAAGTTCATCG*").read_to_string(&mut out)?;
+        assert_eq!(out, "@r2\nGGGG\n+\nIIII\n");
+        out.clear();
+        TarMembers::new(archive.as_slice(), "*.fastq").read_to_string(&mut out)?;
+        assert_eq!(out, "@r1\nACGT\n+\nIIII\n@r2\nGGGG\n+\nIIII\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,360 @@
+use std::io::Read;
+use std::path::Path;
+use std::thread::{Scope, ScopedJoinHandle};
+
+use anyhow::{anyhow, Context, Result};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use extendr_api::prelude::*;
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+
+use crate::error::ErrorKind;
+use crate::numa::*;
+use crate::utils::*;
+
+// Blocks an input is read ahead by its decompressing thread
+const READ_AHEAD_BLOCKS: usize = 4;
+
+/// The threads of each stage of a pipeline, set in R with `mire_threads()`.
+/// Counts left unset are chosen from the inputs and outputs of the pipeline.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct StageThreads {
+    /// Threads reading each input: with 2, the input is decompressed in a
+    /// thread of its own, ahead of the thread splitting it into records
+    pub(crate) readers: Option<usize>,
+    /// Threads matching, filtering and transforming records
+    pub(crate) parsers: usize,
+    /// Threads compressing the chunks of the outputs: with 0, each parser
+    /// compresses its own chunks
+    pub(crate) compressors: Option<usize>,
+    /// Threads writing the outputs, at most one per output file
+    pub(crate) writers: Option<usize>,
+    /// Whether threads are placed on the NUMA nodes of the machine: readers
+    /// on the node of the storage of their input, parsers over all nodes
+    pub(crate) numa: bool,
+}
+
+impl StageThreads {
+    pub(crate) fn new(parsers: usize) -> Self {
+        Self {
+            readers: None,
+            parsers: parsers.max(1),
+            compressors: None,
+            writers: None,
+            numa: false,
+        }
+    }
+
+    /// A single number sets the parser threads, a list (see `mire_threads()`)
+    /// the threads of each stage it names
+    pub(crate) fn from_robj(robj: &Robj) -> Result<Self> {
+        if let Some(parsers) = robj_to_count(robj) {
+            return Ok(Self::new(parsers));
+        }
+        let list = robj
+            .as_list()
+            .ok_or_else(|| ErrorKind::Config.error("'threads' must be a number or a list"))?;
+        let mut threads = Self::new(1);
+        for (name, value) in list.iter() {
+            if value.is_null() {
+                continue;
+            }
+            if name == "numa" {
+                threads.numa = value
+                    .as_bool()
+                    .ok_or_else(|| ErrorKind::Config.error("'numa' must be a logical"))?;
+                continue;
+            }
+            let count = robj_to_count(&value).ok_or_else(|| {
+                ErrorKind::Config.error(format!("'{}' threads must be a number", name))
+            })?;
+            match name {
+                "readers" if (1 ..= 2).contains(&count) => threads.readers = Some(count),
+                "readers" => {
+                    return Err(ErrorKind::Config.error("'readers' threads must be 1 or 2"))
+                }
+                "parsers" => threads.parsers = count.max(1),
+                "compressors" => threads.compressors = Some(count),
+                "writers" => threads.writers = Some(count.max(1)),
+                _ => return Err(ErrorKind::Config.error(format!("Unknown stage '{}'", name))),
+            }
+        }
+        Ok(threads)
+    }
+
+    /// Threads reading `input`: compressed inputs are decompressed in a
+    /// thread of their own by default
+    pub(crate) fn readers(&self, input: &Path) -> usize {
+        self.readers
+            .unwrap_or(if gz_compressed(input) { 2 } else { 1 })
+    }
+
+    /// Threads compressing chunks apart from the parsers, none by default
+    pub(crate) fn compressors(&self) -> usize {
+        self.compressors.unwrap_or(0)
+    }
+
+    /// Threads writing `outputs` files, one per file by default
+    pub(crate) fn writers(&self, outputs: usize) -> usize {
+        self.writers.unwrap_or(outputs).min(outputs)
+    }
+
+    /// CPUs of the threads reading `input` with `numa`: those of the node of
+    /// its storage, or of the first node if unknown or without CPUs
+    pub(crate) fn reader_cpus(&self, input: &Path) -> Option<&'static [usize]> {
+        let nodes = numa_nodes();
+        if !self.numa || nodes.is_empty() {
+            return None;
+        }
+        storage_node(input)
+            .and_then(|node| nodes.get(&node))
+            .or_else(|| nodes.values().next())
+            .map(|x| x.as_slice())
+    }
+
+    /// CPUs of the `parser`-th parser thread with `numa`, parsers taking the
+    /// nodes in turn
+    pub(crate) fn parser_cpus(&self, parser: usize) -> Option<&'static [usize]> {
+        let nodes = numa_nodes();
+        if !self.numa || nodes.is_empty() {
+            return None;
+        }
+        nodes
+            .values()
+            .nth(parser % nodes.len())
+            .map(|x| x.as_slice())
+    }
+}
+
+fn robj_to_count(robj: &Robj) -> Option<usize> {
+    robj.as_real()
+        .filter(|x| x.is_finite() && *x >= 0.0)
+        .map(|x| x as usize)
+        .or_else(|| robj.as_integer().filter(|x| *x >= 0).map(|x| x as usize))
+}
+
+/// Open an input like [`new_reader()`]. With 2 readers (see
+/// [`StageThreads::readers()`]), the input is read and decompressed by a
+/// thread spawned in `scope`, ahead of the thread consuming it. With `numa`,
+/// both threads are pinned to the node of the storage of the input.
+pub(crate) fn spawn_reader<'scope, 'env>(
+    scope: &'scope Scope<'scope, 'env>,
+    input: &'env Path,
+    progress_bar: Option<ProgressBar>,
+    threads: StageThreads,
+) -> Result<Box<dyn Read + 'scope>> {
+    let cpus = threads.reader_cpus(input);
+    if let Some(cpus) = cpus {
+        pin_thread(cpus);
+    }
+    if threads.readers(input) < 2 {
+        return new_reader(input, BUFFER_SIZE, progress_bar);
+    }
+    let (block_tx, block_rx) = bounded(READ_AHEAD_BLOCKS);
+
+    // ─── Decompressor Thread ───────────────────────────────
+    // Errors are handed over to the consuming thread, which reports them
+    scope.spawn(move || {
+        if let Some(cpus) = cpus {
+            pin_thread(cpus);
+        }
+        let mut reader = match new_reader(input, BUFFER_SIZE, progress_bar) {
+            Ok(reader) => reader,
+            Err(e) => {
+                block_tx.send(Err(e)).ok();
+                return;
+            }
+        };
+        loop {
+            let block = read_block(&mut reader, BUFFER_SIZE)
+                .with_context(|| format!("(Decompressor) Failed to read {}", input.display()));
+            let end = block.as_ref().map_or(true, |x| x.is_empty());
+            // The consuming thread stops receiving on its own errors
+            if block_tx.send(block).is_err() || end {
+                return;
+            }
+        }
+    });
+    Ok(Box::new(PipedReader {
+        block_rx,
+        block: Vec::new(),
+        pos: 0,
+    }))
+}
+
+/// Read a block of up to `size` bytes, shorter only at the end of the input
+fn read_block<R: Read + ?Sized>(reader: &mut R, size: usize) -> std::io::Result<Vec<u8>> {
+    let mut block = Vec::with_capacity(size);
+    reader.take(size as u64).read_to_end(&mut block)?;
+    Ok(block)
+}
+
+/// The consuming end of [`spawn_reader()`]
+struct PipedReader {
+    block_rx: Receiver<Result<Vec<u8>>>,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for PipedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos == self.block.len() {
+            match self.block_rx.recv() {
+                Ok(Ok(block)) => {
+                    self.block = block;
+                    self.pos = 0;
+                }
+                Ok(Err(e)) => return Err(std::io::Error::other(format!("{:?}", e))),
+                // The end of the input was sent as an empty block
+                Err(_) => return Ok(0),
+            }
+        }
+        let nbytes = buf.len().min(self.block.len() - self.pos);
+        buf[.. nbytes].copy_from_slice(&self.block[self.pos .. self.pos + nbytes]);
+        self.pos += nbytes;
+        Ok(nbytes)
+    }
+}
+
+/// Spawn `threads` compressor threads in `scope`, each packing the chunks
+/// received from `chunk_rx` with a packer of `new_packer`, and forwarding
+/// them to `writer_tx`. Chunks may reach the writer out of order.
+pub(crate) fn spawn_compressors<'scope, 'env, T, C, F, P>(
+    scope: &'scope Scope<'scope, 'env>,
+    threads: usize,
+    chunk_rx: C,
+    writer_tx: Sender<T>,
+    new_packer: F,
+) -> Vec<ScopedJoinHandle<'scope, Result<()>>>
+where
+    T: Send + 'scope,
+    C: IntoIterator<Item = T> + Clone + Send + 'scope,
+    F: Fn() -> P,
+    P: FnMut(T) -> Result<T> + Send + 'scope,
+{
+    (0 .. threads)
+        .map(|_| {
+            let rx = chunk_rx.clone();
+            let tx = writer_tx.clone();
+            let mut pack = new_packer();
+            // ─── Compressor Thread ─────────────────────────────────
+            scope.spawn(move || -> Result<()> {
+                for chunk in rx {
+                    tx.send(pack(chunk)?)
+                        .map_err(|_| anyhow!("(Compressor) Writer thread stopped"))?;
+                }
+                Ok(())
+            })
+        })
+        .collect()
+}
+
+/// Spawn a pool of parser threads in `scope`, parsing the batches
+/// received from `batch_rx`. Batches are shared out by work stealing, each
+/// going to the first idle parser, so parsers slowed down by a costly batch
+/// (e.g. one flushing a compressed chunk) do not hold back the others.
+///
+/// Each parser folds the batches it takes into a state of `new_state` with
+/// `parse`, and the state is turned into its result by `finish` once no batch
+/// is left, e.g. to send the last records it holds.
+pub(crate) fn spawn_parsers<'scope, 'env, T, B, S, R, N, P, F>(
+    scope: &'scope Scope<'scope, 'env>,
+    threads: StageThreads,
+    batch_rx: B,
+    new_state: N,
+    parse: P,
+    finish: F,
+) -> Result<ScopedJoinHandle<'scope, Result<Vec<R>>>>
+where
+    T: Send + 'scope,
+    B: IntoIterator<Item = T> + Send + 'scope,
+    B::IntoIter: Send,
+    S: Send,
+    R: Send + 'scope,
+    N: Fn() -> S + Send + Sync + 'scope,
+    P: Fn(&mut S, T) -> Result<()> + Send + Sync + 'scope,
+    F: Fn(S) -> Result<R> + Send + Sync + 'scope,
+{
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.parsers.max(1))
+        .thread_name(|i| format!("mire-parser-{}", i))
+        .start_handler(move |i| {
+            if let Some(cpus) = threads.parser_cpus(i) {
+                pin_thread(cpus);
+            }
+        })
+        .build()
+        .context("(Parser) Failed to build the parser pool")?;
+
+    // ─── Parser Thread ─────────────────────────────────────
+    Ok(scope.spawn(move || -> Result<Vec<R>> {
+        pool.install(|| {
+            batch_rx
+                .into_iter()
+                .par_bridge()
+                .try_fold(&new_state, |mut state, batch| {
+                    parse(&mut state, batch)?;
+                    Ok(state)
+                })
+                .map(|state| state.and_then(&finish))
+                .collect()
+        })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn test_spawn_reader() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let path = temp.path().join("reads.fq.gz");
+        let records = b"@r1\nACGT\n+\nIIII\n".repeat(100_000);
+        let mut encoder = gzip_encoder(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&records)?;
+        std::fs::write(&path, encoder.finish()?)?;
+
+        let missing = temp.path().join("missing.fq.gz");
+        let threads = StageThreads::new(2);
+        assert_eq!(threads.readers(&path), 2);
+        assert_eq!(threads.writers(1), 1);
+        std::thread::scope(|scope| -> Result<()> {
+            let mut out = Vec::new();
+            spawn_reader(scope, &path, None, threads)?.read_to_end(&mut out)?;
+            assert_eq!(out, records);
+            assert!(spawn_reader(scope, &missing, None, threads)?
+                .read_to_end(&mut out)
+                .is_err());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_spawn_parsers() -> Result<()> {
+        let (batch_tx, batch_rx) = bounded(2);
+        let sums = std::thread::scope(|scope| -> Result<Vec<usize>> {
+            let handle = spawn_parsers(
+                scope,
+                StageThreads::new(3),
+                batch_rx,
+                || 0,
+                |sum, batch: Vec<usize>| {
+                    *sum += batch.iter().sum::<usize>();
+                    Ok(())
+                },
+                Ok,
+            )?;
+            for i in 0 .. 100 {
+                batch_tx.send(vec![i; 10])?;
+            }
+            drop(batch_tx);
+            handle.join().map_err(|e| anyhow!("{:?}", e))?
+        })?;
+        assert!(!sums.is_empty());
+        assert_eq!(sums.iter().sum::<usize>(), 49500);
+        Ok(())
+    }
+}
@@ -1,26 +1,45 @@
-use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader};
 use std::io::{Read, Write};
+use std::ops::RangeInclusive;
 use std::path::Path;
 
 use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
 use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
 use extendr_api::prelude::*;
-#[cfg(not(feature = "isal"))]
-use flate2::bufread::GzDecoder;
-use indicatif::style::TemplateError;
-use indicatif::ProgressBar;
-use indicatif::ProgressStyle;
+#[cfg(any(test, not(feature = "isal")))]
+use flate2::bufread::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::GzBuilder;
+use indicatif::{ProgressBar, ProgressFinish};
 #[cfg(feature = "isal")]
 use isal::read::GzipDecoder;
-use libdeflater::Compressor;
+use libdeflater::{CompressionLvl, Compressor};
 use memchr::memmem::Finder;
 
+use crate::async_io::{file_reader, file_writer};
+use crate::error::ErrorKind;
+use crate::io_retry::{create_retry, open_retry};
+pub(crate) use crate::progress::{
+    new_progress, progress_reader_style, progress_records_style, progress_target,
+    progress_writer_style,
+};
+use crate::progress::{record_bar, ProgressUnit};
 use crate::reader::*;
+use crate::remote::*;
+use crate::s3::{is_s3, new_s3_writer};
+use crate::seekable::{zstd_compressed, zstd_pack};
+use crate::tar::{split_tar_member, TarMembers};
 
 pub(crate) const BLOCK_SIZE: usize = 8 * 1024 * 1024;
 pub(crate) const BUFFER_SIZE: usize = 4 * 1024 * 1024;
 
+// Header fields of the gzip members we write (RFC 1952): no modification
+// time and an unknown OS, so identical records compress to identical bytes
+// across runs and machines
+const GZIP_MTIME: u32 = 0;
+const GZIP_OS: u8 = 255;
+
 pub(crate) const TAG_PREFIX: &'static [u8] = b"MIRE{";
 pub(crate) const TAG_SUFFIX: u8 = b'}';
 pub(crate) static TAG_PREFIX_FINDER: std::sync::LazyLock<Finder> =
@@ -30,6 +49,214 @@ pub(crate) const KOUTPUT_TAXID_SUFFIX: u8 = b')';
 pub(crate) static KOUTPUT_TAXID_PREFIX_FINDER: std::sync::LazyLock<Finder> =
     std::sync::LazyLock::new(|| Finder::new(KOUTPUT_TAXID_PREFIX));
 
+// Extract the taxid from the koutput taxid field, which is either the bare taxid
+// or, when kraken2 was run with `--use-names`, a "name (taxid N)" string
+pub(crate) fn koutput_taxid(field: &[u8]) -> Option<&[u8]> {
+    if let Some(start) = KOUTPUT_TAXID_PREFIX_FINDER.find(field) {
+        let start = start + KOUTPUT_TAXID_PREFIX.len();
+        memchr::memchr(KOUTPUT_TAXID_SUFFIX, &field[start ..])
+            .map(|end| &field[start .. start + end])
+    } else {
+        Some(field)
+    }
+}
+
+// Split a koutput line into its 5 fields: classification status, sequence ID,
+// taxid, sequence length and LCA mapping. Only the first four tabs separate
+// fields, the rest of the line is the LCA mapping, so stray tabs past the
+// taxid never shift it or drop the line.
+pub(crate) fn koutput_fields(line: &[u8]) -> Option<[&[u8]; 5]> {
+    let mut fields = line.splitn(5, |b| *b == b'\t');
+    Some([
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+    ])
+}
+
+/// The columns (0-based) of the 5 koutput fields, for the outputs of
+/// wrappers adding or reordering columns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct KoutputColumns([usize; 5]);
+
+impl Default for KoutputColumns {
+    fn default() -> Self {
+        Self([0, 1, 2, 3, 4])
+    }
+}
+
+impl KoutputColumns {
+    /// Columns of the status, sequence ID, taxid, length and LCA mapping
+    pub(crate) fn new(columns: [usize; 5]) -> Result<Self> {
+        for (i, column) in columns.iter().enumerate() {
+            if columns[.. i].contains(column) {
+                return Err(anyhow!(
+                    "koutput fields must be in distinct columns, column {} is repeated",
+                    column + 1
+                ));
+            }
+        }
+        Ok(Self(columns))
+    }
+
+    /// Split a koutput line into its 5 fields, as [`koutput_fields()`] for the
+    /// standard layout. Otherwise, tabs separate all columns.
+    pub(crate) fn fields<'a>(&self, line: &'a [u8]) -> Option<[&'a [u8]; 5]> {
+        if *self == Self::default() {
+            return koutput_fields(line);
+        }
+        let mut fields: [Option<&[u8]>; 5] = [None; 5];
+        for (i, field) in line.split(|b| *b == b'\t').enumerate() {
+            for (column, out) in self.0.iter().zip(fields.iter_mut()) {
+                if *column == i {
+                    *out = Some(field);
+                }
+            }
+        }
+        Some([fields[0]?, fields[1]?, fields[2]?, fields[3]?, fields[4]?])
+    }
+}
+
+pub(crate) const KRAKEN_TAXID_PREFIX: &'static [u8] = b"kraken:taxid|";
+pub(crate) static KRAKEN_TAXID_PREFIX_FINDER: std::sync::LazyLock<Finder> =
+    std::sync::LazyLock::new(|| Finder::new(KRAKEN_TAXID_PREFIX));
+
+// Extract the taxid from a `kraken:taxid|N` token in a sequence header, as in
+// the Kraken2 library FASTA files and the `--classified-out` reads
+pub(crate) fn kraken_header_taxid(header: &[u8]) -> Option<&[u8]> {
+    let start = KRAKEN_TAXID_PREFIX_FINDER.find(header)? + KRAKEN_TAXID_PREFIX.len();
+    let len = header[start ..]
+        .iter()
+        .position(|b| *b == b'|' || b.is_ascii_whitespace())
+        .unwrap_or(header.len() - start);
+    (len > 0).then(|| &header[start .. start + len])
+}
+
+// Extract the value of `tag` from the `MIRE{tag:value:tag:value}` annotation
+// embedded into a read description by `seq_refine()`
+pub(crate) fn mire_tag<'d>(desc: &'d [u8], tag: &[u8]) -> Option<&'d [u8]> {
+    let start = TAG_PREFIX_FINDER.find(desc)? + TAG_PREFIX.len();
+    let end = start + memchr::memchr(TAG_SUFFIX, &desc[start ..])?;
+    let mut fields = desc[start .. end].split(|b| *b == b':');
+    while let Some(name) = fields.next() {
+        let value = fields.next()?;
+        if name == tag {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// The positions of the `MIRE{` prefix and of the `}` suffix of the `MIRE{}`
+// annotation of a read description
+fn mire_annotation(desc: &[u8]) -> Option<(usize, usize)> {
+    let start = TAG_PREFIX_FINDER.find(desc)?;
+    let fields = start + TAG_PREFIX.len();
+    let end = fields + memchr::memchr(TAG_SUFFIX, &desc[fields ..])?;
+    Some((start, end))
+}
+
+// The `tag:value` fields of the `MIRE{}` annotation of a read description, in
+// order
+pub(crate) fn mire_tags(desc: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let Some((start, end)) = mire_annotation(desc) else {
+        return Vec::new();
+    };
+    let mut fields = desc[start + TAG_PREFIX.len() .. end].split(|b| *b == b':');
+    let mut tags = Vec::new();
+    while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+        tags.push((name, value));
+    }
+    tags
+}
+
+// Set `tag:value` fields in the `MIRE{}` annotation of a read description:
+// the fields of the same tags are replaced and the others kept in place, so
+// the tags embedded by earlier runs are carried through
+pub(crate) fn set_mire_tags(desc: &[u8], tags: &[(&[u8], Vec<u8>)]) -> Bytes {
+    let Some((start, end)) = mire_annotation(desc) else {
+        let mut out = desc.to_vec();
+        for (tag, value) in tags {
+            out = add_mire_tag((!out.is_empty()).then_some(&out[..]), tag, value).to_vec();
+        }
+        return Bytes::from(out);
+    };
+    let mut out = Vec::with_capacity(
+        desc.len()
+            + tags
+                .iter()
+                .map(|(t, v)| t.len() + v.len() + 2)
+                .sum::<usize>(),
+    );
+    out.extend_from_slice(&desc[.. start + TAG_PREFIX.len()]);
+    let kept = mire_tags(desc)
+        .into_iter()
+        .filter(|(name, _)| !tags.iter().any(|(tag, _)| tag == name));
+    let new = tags.iter().map(|(tag, value)| (*tag, value.as_slice()));
+    for (i, (tag, value)) in kept.chain(new).enumerate() {
+        if i > 0 {
+            out.push(b':');
+        }
+        out.extend_from_slice(tag);
+        out.push(b':');
+        out.extend_from_slice(value);
+    }
+    out.extend_from_slice(&desc[end ..]);
+    Bytes::from(out)
+}
+
+// Remove the `MIRE{}` annotation from a read description, for tools confused
+// by long header comments. Returns `None` if nothing else is left.
+pub(crate) fn strip_mire_tags(desc: &Bytes) -> Option<Bytes> {
+    let Some((start, end)) = mire_annotation(desc) else {
+        return Some(desc.clone());
+    };
+    let before = desc[.. start].trim_ascii_end();
+    let after = desc[end + 1 ..].trim_ascii_start();
+    match (before.is_empty(), after.is_empty()) {
+        (true, true) => None,
+        (false, true) => Some(desc.slice_ref(before)),
+        (true, false) => Some(desc.slice_ref(after)),
+        (false, false) => Some(Bytes::from([before, b" ", after].concat())),
+    }
+}
+
+// Add `tag:value` to the `MIRE{}` annotation of a read description, creating
+// the annotation if absent
+pub(crate) fn add_mire_tag(desc: Option<&[u8]>, tag: &[u8], value: &[u8]) -> Bytes {
+    let mut out = Vec::with_capacity(
+        desc.map_or(0, |d| d.len() + 1) + TAG_PREFIX.len() + tag.len() + value.len() + 3,
+    );
+    let annotation = desc.and_then(|desc| {
+        let start = TAG_PREFIX_FINDER.find(desc)? + TAG_PREFIX.len();
+        Some(start + memchr::memchr(TAG_SUFFIX, &desc[start ..])?)
+    });
+    match (desc, annotation) {
+        (Some(desc), Some(end)) => {
+            out.extend_from_slice(&desc[.. end]);
+            out.push(b':');
+            out.extend_from_slice(tag);
+            out.push(b':');
+            out.extend_from_slice(value);
+            out.extend_from_slice(&desc[end ..]);
+        }
+        _ => {
+            if let Some(desc) = desc {
+                out.extend_from_slice(desc);
+                out.push(b' ');
+            }
+            out.extend_from_slice(TAG_PREFIX);
+            out.extend_from_slice(tag);
+            out.push(b':');
+            out.extend_from_slice(value);
+            out.push(TAG_SUFFIX);
+        }
+    }
+    Bytes::from(out)
+}
+
 // Parse &[u8] slice to f64 assuming ASCII decimal representation
 pub(crate) fn parse_f64(bytes: &[u8]) -> Result<f64> {
     let s = str::from_utf8(bytes.trim_ascii())
@@ -46,18 +273,86 @@ pub(crate) fn parse_usize(bytes: &[u8]) -> Result<usize> {
         .with_context(|| format!("Failed to parse integer '{}'", s))
 }
 
-pub(crate) fn u8_to_list_rstr(vv: Vec<Vec<u8>>) -> Vec<Rstr> {
-    vv.into_iter().map(|v| u8_to_rstr(v)).collect()
+pub(crate) fn u8_to_list_rstr(vv: Vec<Vec<u8>>) -> Strings {
+    let mut strings = RStrings::with_capacity(vv.len());
+    for v in vv {
+        strings.push(&v);
+    }
+    strings.finish()
+}
+
+/// Fills an R character vector allocated up front.
+///
+/// Each R string is stored into the vector right after its creation, so it is
+/// protected by the vector alone: converting hundreds of millions of strings
+/// holds no protection (nor any `Rstr`) per string, and the R garbage
+/// collector is free to run in between.
+pub(crate) struct RStrings {
+    strings: Strings,
+    len: usize,
+}
+
+impl RStrings {
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        Self {
+            strings: Strings::new(capacity),
+            len: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, value: &[u8]) {
+        let value = String::from_utf8_lossy(value);
+        self.set(|| unsafe {
+            extendr_ffi::Rf_mkCharLenCE(
+                value.as_ptr() as *const std::ffi::c_char,
+                value.len() as std::ffi::c_int,
+                extendr_ffi::cetype_t::CE_UTF8,
+            )
+        });
+    }
+
+    pub(crate) fn push_na(&mut self) {
+        self.set(|| unsafe { extendr_ffi::R_NaString });
+    }
+
+    /// Push the string already at position `index` again, e.g. a value
+    /// repeated over many rows, without creating it anew
+    pub(crate) fn push_from(&mut self, index: usize) {
+        assert!(index < self.len, "RStrings has no string at {}", index);
+        let strings = unsafe { self.strings.get() };
+        self.set(|| unsafe { extendr_ffi::STRING_ELT(strings, index as extendr_ffi::R_xlen_t) });
+    }
+
+    fn set<F: FnOnce() -> extendr_api::SEXP>(&mut self, charsxp: F) {
+        assert!(self.len < self.strings.len(), "RStrings is full");
+        single_threaded(|| unsafe {
+            extendr_ffi::SET_STRING_ELT(
+                self.strings.get_mut(),
+                self.len as extendr_ffi::R_xlen_t,
+                charsxp(),
+            );
+        });
+        self.len += 1;
+    }
+
+    pub(crate) fn finish(self) -> Strings {
+        self.strings
+    }
 }
 
 pub(crate) fn u8_to_rstr(bytes: Vec<u8>) -> Rstr {
     Rstr::from_string(&unsafe { String::from_utf8_unchecked(bytes) })
 }
 
+/// Whether the file at `path` is gzip-compressed, by its `.gz` or `.tgz`
+/// extension
 pub(crate) fn gz_compressed(path: &Path) -> bool {
-    path.extension()
+    remote_object_path(path)
+        .extension()
         .and_then(|e| e.to_str())
-        .map_or(false, |s| s.eq_ignore_ascii_case("gz"))
+        .map_or(false, |s| {
+            s.eq_ignore_ascii_case("gz") || s.eq_ignore_ascii_case("tgz")
+        })
 }
 
 pub(crate) fn gzip_pack(bytes: &[u8], compressor: &mut Compressor) -> Result<Vec<u8>> {
@@ -66,17 +361,192 @@ pub(crate) fn gzip_pack(bytes: &[u8], compressor: &mut Compressor) -> Result<Vec
     pack.resize(pack_size, 0);
     let size = compressor.gzip_compress(bytes, &mut pack)?;
     pack.truncate(size);
+    // The header is not covered by the CRC of the member, it can be fixed
+    // whatever the compressor wrote
+    pack[4 .. 8].copy_from_slice(&GZIP_MTIME.to_le_bytes());
+    pack[9] = GZIP_OS;
     Ok(pack)
 }
 
+/// How the chunks of an output are compressed, by the extension of its path
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum ChunkFormat {
+    Plain,
+    // One gzip member per chunk
+    Gzip,
+    // One zstd frame per chunk, indexed by a seek table (see `SeekTable`)
+    Zstd,
+}
+
+impl ChunkFormat {
+    pub(crate) fn of(path: &Path) -> Self {
+        if gz_compressed(path) {
+            Self::Gzip
+        } else if zstd_compressed(path) {
+            Self::Zstd
+        } else {
+            Self::Plain
+        }
+    }
+}
+
+/// The compression of an output: its format, by the extension of its path,
+/// and a level within the range of that format. The level is checked when
+/// the compression is built, before any thread starts or any file is created.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Compression {
+    Plain,
+    Gzip(CompressionLvl),
+    Zstd(i32),
+}
+
+impl Compression {
+    // libdeflate levels, without level 0 which only stores the data
+    pub(crate) const GZIP_LEVELS: RangeInclusive<i32> = 1 ..= 12;
+    pub(crate) const GZIP_DEFAULT_LEVEL: i32 = 4;
+    // zstd levels, without the negative fast levels
+    pub(crate) const ZSTD_LEVELS: RangeInclusive<i32> = 1 ..= 22;
+    pub(crate) const ZSTD_DEFAULT_LEVEL: i32 = 3;
+
+    /// The compression of `path` at `level`, or at the default level of its
+    /// format without `level`. The level of a plain output is ignored.
+    pub(crate) fn new(path: &Path, level: Option<i32>) -> Result<Self> {
+        Self::with_format(ChunkFormat::of(path), level)
+            .with_context(|| format!("Invalid compression of output {}", path.display()))
+    }
+
+    /// The compression of outputs in `format` at `level`, or at the default
+    /// level of the format without `level`
+    pub(crate) fn with_format(format: ChunkFormat, level: Option<i32>) -> Result<Self> {
+        let check = |name: &str, levels: RangeInclusive<i32>, default: i32| {
+            let level = level.unwrap_or(default);
+            if levels.contains(&level) {
+                Ok(level)
+            } else {
+                Err(ErrorKind::Config.error(format!(
+                    "Invalid 'compression_level' {}: {} levels range from {} to {}",
+                    level,
+                    name,
+                    levels.start(),
+                    levels.end()
+                )))
+            }
+        };
+        match format {
+            ChunkFormat::Plain => Ok(Self::Plain),
+            ChunkFormat::Gzip => {
+                let level = check("gzip", Self::GZIP_LEVELS, Self::GZIP_DEFAULT_LEVEL)?;
+                CompressionLvl::new(level).map(Self::Gzip).map_err(|e| {
+                    ErrorKind::Config
+                        .error(format!("Invalid 'compression_level' {}: {:?}", level, e))
+                })
+            }
+            ChunkFormat::Zstd if !cfg!(feature = "zstd") => Err(ErrorKind::Config
+                .error("Cannot write zstd outputs: mire was built without the 'zstd' feature")),
+            ChunkFormat::Zstd => {
+                check("zstd", Self::ZSTD_LEVELS, Self::ZSTD_DEFAULT_LEVEL).map(Self::Zstd)
+            }
+        }
+    }
+
+    /// [`Compression::new()`] of an optional output, plain without output
+    pub(crate) fn of(path: Option<&Path>, level: Option<i32>) -> Result<Self> {
+        path.map_or(Ok(Self::Plain), |path| Self::new(path, level))
+    }
+
+    /// The level of a compressed output
+    pub(crate) fn level(&self) -> Option<i32> {
+        match self {
+            Self::Plain => None,
+            Self::Gzip(level) => Some(i32::from(*level)),
+            Self::Zstd(level) => Some(*level),
+        }
+    }
+
+    /// A gzip compressor at the level of a gzip output, at the default level
+    /// of libdeflate otherwise
+    pub(crate) fn compressor(&self) -> Compressor {
+        match self {
+            Self::Gzip(level) => Compressor::new(*level),
+            _ => Compressor::new(CompressionLvl::default()),
+        }
+    }
+}
+
+/// Compresses the chunks of an output in a parser thread, each chunk being
+/// compressed on its own so that chunks of many threads can be concatenated
+pub(crate) struct ChunkPacker {
+    compression: Compression,
+    compressor: Compressor,
+}
+
+impl ChunkPacker {
+    pub(crate) fn new(compression: Compression) -> Self {
+        Self {
+            compression,
+            compressor: compression.compressor(),
+        }
+    }
+
+    pub(crate) fn pack(&mut self, chunk: Vec<u8>) -> Result<Vec<u8>> {
+        match self.compression {
+            Compression::Plain => Ok(chunk),
+            Compression::Gzip(_) => gzip_pack(&chunk, &mut self.compressor),
+            Compression::Zstd(level) => zstd_pack(&chunk, level),
+        }
+    }
+}
+
+/// A gzip stream encoder with the same fixed header as [`gzip_pack()`]
+pub(crate) fn gzip_encoder<W: Write>(writer: W, level: flate2::Compression) -> GzEncoder<W> {
+    GzBuilder::new()
+        .mtime(GZIP_MTIME)
+        .operating_system(GZIP_OS)
+        .write(writer, level)
+}
+
+/// A writer of an output which must be completed once all of it has been
+/// written. Flushing is enough for local files, while an S3 upload is only
+/// assembled from its parts on completion, so flushing mid-stream (as gzip
+/// encoders do) leaves it open.
+pub(crate) trait CompleteWrite: Write {
+    /// Flush and complete the output: nothing may be written afterwards
+    fn complete(&mut self) -> std::io::Result<()> {
+        self.flush()
+    }
+}
+
+impl CompleteWrite for Box<dyn Write> {}
+
+impl CompleteWrite for Vec<u8> {}
+
+impl CompleteWrite for Box<dyn CompleteWrite> {
+    fn complete(&mut self) -> std::io::Result<()> {
+        (**self).complete()
+    }
+}
+
+impl<W: CompleteWrite> CompleteWrite for std::io::BufWriter<W> {
+    fn complete(&mut self) -> std::io::Result<()> {
+        self.flush()?;
+        self.get_mut().complete()
+    }
+}
+
 pub(crate) fn new_writer<P: AsRef<Path> + ?Sized>(
     file: &P,
     progress_bar: Option<ProgressBar>,
-) -> Result<Box<dyn Write>> {
+) -> Result<Box<dyn CompleteWrite>> {
     let path: &Path = file.as_ref();
-    let file = File::create(path)
-        .with_context(|| format!("Failed to create output file {}", path.display()))?;
-    let writer: Box<dyn Write>;
+    let file: Box<dyn CompleteWrite> = if is_s3(path) {
+        new_s3_writer(path)
+            .with_context(|| format!("Failed to create output file {}", path.display()))?
+    } else {
+        Box::new(file_writer(create_retry(path).with_context(|| {
+            format!("Failed to create output file {}", path.display())
+        })?))
+    };
+    let writer: Box<dyn CompleteWrite>;
     if let Some(bar) = progress_bar {
         writer = Box::new(ProgressBarWriter::new(file, bar));
     } else {
@@ -85,65 +555,139 @@ pub(crate) fn new_writer<P: AsRef<Path> + ?Sized>(
     Ok(writer)
 }
 
-#[cfg(feature = "isal")]
-pub(crate) fn new_reader<P: AsRef<Path> + ?Sized>(
-    file: &P,
+/// Open a local file, or a remote object when built with the `remote` feature.
+/// Returns the input along with whether it's gzip-compressed.
+fn open_input(path: &Path, buffer_size: usize) -> Result<(Box<dyn Read>, bool)> {
+    if is_remote(path) {
+        return Ok((open_remote(path)?, gz_compressed(path)));
+    }
+    let file =
+        open_retry(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
+    if gz_compressed(path) || file.file().metadata().is_ok_and(|m| m.is_file()) {
+        return Ok((file_reader(file), gz_compressed(path)));
+    }
+    // Named pipes and process substitutions (e.g. `/dev/fd/63`) have no
+    // meaningful extension: detect gzip from the magic bytes instead. Pipes
+    // can't be rewound, so the peeked bytes are kept in the buffer.
+    let mut reader = BufReader::with_capacity(buffer_size, file);
+    let gzip = reader
+        .fill_buf()
+        .with_context(|| format!("Failed to read file: {}", path.display()))?
+        .starts_with(&[0x1f, 0x8b]);
+    Ok((Box::new(reader), gzip))
+}
+
+/// Size of an input in bytes, used for progress bars. Returns `None` for
+/// inputs of unknown size, such as named pipes.
+pub(crate) fn input_size<P: AsRef<Path> + ?Sized>(file: &P) -> Result<Option<u64>> {
+    let path: &Path = file.as_ref();
+    if let Some((archive, _)) = split_tar_member(path) {
+        input_size(archive)
+    } else if is_remote(path) {
+        remote_size(path).map(Some)
+    } else {
+        let metadata = std::fs::metadata(path)
+            .with_context(|| format!("Failed to open file: {}", path.display()))?;
+        Ok(metadata.is_file().then_some(metadata.len()))
+    }
+}
+
+/// Progress bar over the bytes read from an input; inputs of unknown size
+/// only report the bytes read so far
+pub(crate) fn input_progress_bar<P: AsRef<Path> + ?Sized>(file: &P) -> Result<ProgressBar> {
+    let bar = match input_size(file)? {
+        Some(size) => ProgressBar::with_draw_target(Some(size), progress_target()),
+        None => ProgressBar::with_draw_target(None, progress_target()),
+    };
+    let bar = bar.with_finish(ProgressFinish::Abandon);
+    // Inputs of unknown size count their records, see `split_input_bar()`
+    let unit = if bar.length().is_some() {
+        ProgressUnit::Bytes
+    } else {
+        ProgressUnit::Records
+    };
+    record_bar(&bar, unit);
+    Ok(bar)
+}
+
+/// The progress bar of the bytes written to an output
+pub(crate) fn output_progress_bar() -> ProgressBar {
+    let bar = ProgressBar::no_length().with_finish(ProgressFinish::Abandon);
+    record_bar(&bar, ProgressUnit::Bytes);
+    bar
+}
+
+/// Split the progress bar of an input into a bar of the (compressed) bytes
+/// consumed from the file, and a bar of the records parsed. Inputs of unknown
+/// size, such as named pipes, fall back to a spinner of the records parsed per
+/// second, as their bytes have no total to progress towards.
+pub(crate) fn split_input_bar(
+    bar: Option<ProgressBar>,
+) -> Result<(Option<ProgressBar>, Option<ProgressBar>)> {
+    match bar {
+        Some(bar) if bar.length().is_none() => {
+            bar.set_style(progress_records_style()?);
+            Ok((None, Some(bar)))
+        }
+        bar => Ok((bar, None)),
+    }
+}
+
+/// Stream the members of a tar archive matching `pattern`, concatenated in
+/// archive order. Progress is reported on the bytes of the archive itself.
+fn open_tar_members(
+    archive: &Path,
+    pattern: &str,
     buffer_size: usize,
     progress_bar: Option<ProgressBar>,
 ) -> Result<Box<dyn Read>> {
-    let path: &Path = file.as_ref();
-    let file =
-        File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
-    let reader: Box<dyn Read>;
-    if gz_compressed(path) {
-        if let Some(bar) = progress_bar {
-            reader = Box::new(GzipDecoder::new(BufReader::with_capacity(
-                buffer_size,
-                ProgressBarReader::new(file, bar),
-            )));
-        } else {
-            reader = Box::new(GzipDecoder::new(BufReader::with_capacity(
-                buffer_size,
-                file,
-            )));
-        }
+    let members = TarMembers::new(new_reader(archive, buffer_size, progress_bar)?, pattern);
+    let mut reader = BufReader::with_capacity(buffer_size, members);
+    // Members are usually compressed individually (e.g. `*.fastq.gz`)
+    let gzip = reader
+        .fill_buf()
+        .with_context(|| format!("Failed to read archive: {}", archive.display()))?
+        .starts_with(&[0x1f, 0x8b]);
+    if gzip {
+        Ok(gzip_input(archive, reader, buffer_size))
     } else {
-        if let Some(bar) = progress_bar {
-            reader = Box::new(ProgressBarReader::new(file, bar));
-        } else {
-            reader = Box::new(file);
-        }
+        Ok(Box::new(reader))
     }
-    Ok(reader)
 }
 
-#[cfg(not(feature = "isal"))]
 pub(crate) fn new_reader<P: AsRef<Path> + ?Sized>(
     file: &P,
     buffer_size: usize,
     progress_bar: Option<ProgressBar>,
 ) -> Result<Box<dyn Read>> {
     let path: &Path = file.as_ref();
-    let file =
-        File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
+    if let Some((archive, pattern)) = split_tar_member(path) {
+        return open_tar_members(archive, pattern, buffer_size, progress_bar);
+    }
+    let (file, gzip) = open_input(path, buffer_size)?;
     let reader: Box<dyn Read>;
-    if gz_compressed(path) {
-        if let Some(bar) = progress_bar {
-            reader = Box::new(GzDecoder::new(BufReader::with_capacity(
-                buffer_size,
-                ProgressBarReader::new(file, bar),
-            )));
-        } else {
-            reader = Box::new(GzDecoder::new(BufReader::with_capacity(buffer_size, file)));
-        }
+    if let Some(bar) = progress_bar {
+        reader = Box::new(ProgressBarReader::new(file, bar));
     } else {
-        if let Some(bar) = progress_bar {
-            reader = Box::new(ProgressBarReader::new(file, bar));
-        } else {
-            reader = Box::new(file);
-        }
+        reader = file;
     }
-    Ok(reader)
+    if gzip {
+        Ok(gzip_input(path, reader, buffer_size))
+    } else {
+        Ok(reader)
+    }
+}
+
+/// Decompress a gzip input, reporting decoding errors with `path` and the
+/// offset reached in `reader`
+fn gzip_input<R: Read + 'static>(path: &Path, reader: R, buffer_size: usize) -> Box<dyn Read> {
+    let offset = std::rc::Rc::new(std::cell::Cell::new(0));
+    let reader = BufReader::with_capacity(buffer_size, OffsetReader::new(reader, offset.clone()));
+    #[cfg(feature = "isal")]
+    let decoder = GzipDecoder::new(reader);
+    #[cfg(not(feature = "isal"))]
+    let decoder = MultiGzDecoder::new(reader);
+    Box::new(GzipInput::new(decoder, path.to_path_buf(), offset))
 }
 
 pub(crate) fn robj_to_option_str(robj: &Robj) -> Result<Option<Vec<&str>>> {
@@ -156,6 +700,30 @@ pub(crate) fn robj_to_option_str(robj: &Robj) -> Result<Option<Vec<&str>>> {
     }
 }
 
+/// The counts of each column of a count table returned to R (e.g. the
+/// `counts` of `krcount()`), a named list of numeric columns whose absent
+/// entries are `NA`, counted as zero
+pub(crate) fn list_to_counts(counts: &List) -> Result<Vec<Vec<usize>>> {
+    let mut columns = Vec::with_capacity(counts.len());
+    for (name, column) in counts.iter() {
+        let column = if let Some(values) = column.as_integer_slice() {
+            values
+                .iter()
+                .map(|&x| if x.is_na() { 0 } else { x as usize })
+                .collect::<Vec<_>>()
+        } else if let Some(values) = column.as_real_slice() {
+            values
+                .iter()
+                .map(|&x| if x.is_nan() { 0 } else { x as usize })
+                .collect::<Vec<_>>()
+        } else {
+            return Err(anyhow!("Counts of barcode '{}' must be numeric", name));
+        };
+        columns.push(column);
+    }
+    Ok(columns)
+}
+
 pub(crate) fn new_channel<T>(nqueue: Option<usize>) -> (Sender<T>, Receiver<T>) {
     if let Some(queue) = nqueue {
         bounded(queue)
@@ -164,14 +732,164 @@ pub(crate) fn new_channel<T>(nqueue: Option<usize>) -> (Sender<T>, Receiver<T>)
     }
 }
 
-pub(crate) fn progress_reader_style() -> std::result::Result<ProgressStyle, TemplateError> {
-    ProgressStyle::with_template(
-        "{prefix:.bold.cyan/blue} {decimal_bytes}/{decimal_total_bytes} {spinner:.green} [{elapsed_precise}] {decimal_bytes_per_sec} (ETA {eta})",
-    )
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gzip_header() -> Result<()> {
+        let records = b"@r1\nACGT\n+\nIIII\n";
+        let mut compressor = Compressor::new(CompressionLvl::new(6).unwrap());
+        let pack = gzip_pack(records, &mut compressor)?;
+        assert_eq!(pack, gzip_pack(records, &mut compressor)?);
+        assert_eq!(&pack[4 .. 8], &[0, 0, 0, 0]);
+        assert_eq!(pack[9], GZIP_OS);
+
+        let mut encoder = gzip_encoder(Vec::new(), flate2::Compression::default());
+        encoder.write_all(records)?;
+        let stream = encoder.finish()?;
+        assert_eq!(&stream[4 .. 8], &[0, 0, 0, 0]);
+        assert_eq!(stream[9], GZIP_OS);
 
-pub(crate) fn progress_writer_style() -> std::result::Result<ProgressStyle, TemplateError> {
-    ProgressStyle::with_template(
-        "{prefix:.bold.cyan/blue} {decimal_bytes} {spinner:.green} {decimal_bytes_per_sec}",
-    )
+        let mut out = Vec::new();
+        MultiGzDecoder::new([pack, stream].concat().as_slice()).read_to_end(&mut out)?;
+        assert_eq!(out, [records.as_slice(), records].concat());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gzip_input_integrity() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let path = temp.path().join("reads.fq.gz");
+        let mut encoder = gzip_encoder(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&b"@r1\nACGT\n+\nIIII\n".repeat(1000))?;
+        let stream = encoder.finish()?;
+        let read = |bytes: &[u8]| -> std::io::Result<Vec<u8>> {
+            std::fs::write(&path, bytes).unwrap();
+            let mut out = Vec::new();
+            new_reader(&path, 64, None).unwrap().read_to_end(&mut out)?;
+            Ok(out)
+        };
+        assert_eq!(read(&stream)?.len(), 16000);
+
+        // A truncated trailer or member is an error, not a shorter input
+        for len in [stream.len() - 4, stream.len() / 2] {
+            let err = read(&stream[.. len]).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+            assert!(err.to_string().contains(&format!(
+                "{} (after {} compressed bytes)",
+                path.display(),
+                len
+            )));
+        }
+        // So is a CRC mismatch
+        let mut corrupt = stream.clone();
+        corrupt[stream.len() - 8] ^= 1;
+        assert!(read(&corrupt).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gzip_input_progress() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let path = temp.path().join("koutput.txt.gz");
+        let mut compressor = Compressor::new(CompressionLvl::new(6).unwrap());
+        let line = b"C\tread1\t562\t150\t562:116\n";
+        let members = [
+            gzip_pack(line, &mut compressor)?,
+            gzip_pack(line, &mut compressor)?,
+        ];
+        std::fs::write(&path, members.concat())?;
+
+        // The bar follows the compressed bytes of every member
+        let (bar, lines_bar) = split_input_bar(Some(input_progress_bar(&path)?))?;
+        let bar = bar.unwrap();
+        assert!(lines_bar.is_none());
+        let mut out = Vec::new();
+        new_reader(&path, BUFFER_SIZE, Some(bar.clone()))?.read_to_end(&mut out)?;
+        assert_eq!(out, [line.as_slice(), line].concat());
+        assert_eq!(bar.position(), bar.length().unwrap());
+
+        let (bar, lines_bar) = split_input_bar(Some(ProgressBar::no_length()))?;
+        assert!(bar.is_none() && lines_bar.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression() -> Result<()> {
+        let gzip = Path::new("reads.fq.gz");
+        let zstd = Path::new("reads.fq.zst");
+        assert_eq!(
+            Compression::new(Path::new("reads.fq"), Some(99))?,
+            Compression::Plain
+        );
+        assert_eq!(
+            Compression::new(gzip, None)?,
+            Compression::Gzip(CompressionLvl::new(4).unwrap())
+        );
+        assert!(Compression::new(gzip, Some(13)).is_err());
+        if cfg!(feature = "zstd") {
+            assert_eq!(Compression::new(zstd, None)?, Compression::Zstd(3));
+            assert_eq!(Compression::new(zstd, Some(19))?, Compression::Zstd(19));
+            let err = Compression::new(zstd, Some(0)).unwrap_err();
+            assert!(format!("{:#}", err).contains("zstd levels range from 1 to 22"));
+        } else {
+            let err = Compression::new(zstd, None).unwrap_err();
+            assert!(format!("{:#}", err).contains("without the 'zstd' feature"));
+        }
+        assert!(Compression::with_format(ChunkFormat::Gzip, Some(0)).is_err());
+        assert_eq!(Compression::of(None, Some(0))?, Compression::Plain);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mire_tags() {
+        let desc = Bytes::from("1:N:0 MIRE{BARCODE:ACGT:UMI:TTTT} x");
+        assert_eq!(
+            mire_tags(&desc),
+            [(&b"BARCODE"[..], &b"ACGT"[..]), (b"UMI", b"TTTT")]
+        );
+        assert!(mire_tags(b"1:N:0").is_empty());
+
+        let tags = [(&b"UMI"[..], b"GGGG".to_vec()), (b"RG", b"S1".to_vec())];
+        assert_eq!(
+            set_mire_tags(&desc, &tags),
+            "1:N:0 MIRE{BARCODE:ACGT:UMI:GGGG:RG:S1} x"
+        );
+        assert_eq!(set_mire_tags(b"1:N:0", &tags), "1:N:0 MIRE{UMI:GGGG:RG:S1}");
+
+        assert_eq!(strip_mire_tags(&desc).unwrap(), "1:N:0 x");
+        assert_eq!(
+            strip_mire_tags(&Bytes::from("1:N:0 MIRE{UMI:TTTT}")).unwrap(),
+            "1:N:0"
+        );
+        assert_eq!(strip_mire_tags(&Bytes::from("MIRE{UMI:TTTT}")), None);
+        assert_eq!(strip_mire_tags(&Bytes::from("1:N:0")).unwrap(), "1:N:0");
+    }
+
+    #[test]
+    fn test_koutput_fields() {
+        let line = b"C\tSRR1.1 lane=2@tile:7\tE. coli (taxid 562)\t150|150\t562:3 |:|\t562:4";
+        let [status, id, taxid, length, lca] = koutput_fields(line).unwrap();
+        assert_eq!(status, b"C");
+        assert_eq!(id, b"SRR1.1 lane=2@tile:7");
+        assert_eq!(koutput_taxid(taxid), Some(&b"562"[..]));
+        assert_eq!(length, b"150|150");
+        // A stray tab is kept in the LCA mapping
+        assert_eq!(lca, b"562:3 |:|\t562:4");
+        assert_eq!(koutput_fields(b"C\t@r1\t562\t150"), None);
+    }
+
+    #[test]
+    fn test_koutput_columns() -> Result<()> {
+        let line = b"sample1\tr1\tC\t562\t150\t562:4\tgood";
+        let columns = KoutputColumns::new([2, 1, 3, 4, 5])?;
+        assert_eq!(
+            columns.fields(line),
+            Some([&b"C"[..], b"r1", b"562", b"150", b"562:4"])
+        );
+        assert_eq!(KoutputColumns::new([2, 1, 3, 4, 8])?.fields(line), None);
+        assert!(KoutputColumns::new([0, 1, 2, 3, 1]).is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,87 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use extendr_api::prelude::*;
+
+/// A warning counted by worker threads over a run, and reported once with the
+/// total count, e.g. "12 barcodes had an ambiguous correction and were left
+/// uncorrected"
+pub(crate) struct CountedWarning {
+    message: &'static str,
+    count: AtomicUsize,
+}
+
+impl CountedWarning {
+    const fn new(message: &'static str) -> Self {
+        Self {
+            message,
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn add(&self, n: usize) {
+        if n > 0 {
+            self.count.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+}
+
+pub(crate) static AMBIGUOUS_BARCODES: CountedWarning =
+    CountedWarning::new("barcodes had an ambiguous correction and were left uncorrected");
+pub(crate) static UNKNOWN_TAXID_RECORDS: CountedWarning =
+    CountedWarning::new("records with a taxid missing from the Kraken2 report were skipped");
+
+static COUNTED_WARNINGS: [&CountedWarning; 2] = [&AMBIGUOUS_BARCODES, &UNKNOWN_TAXID_RECORDS];
+
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Raise a warning in R once the current call returns. Worker threads may
+/// warn too, R is never called from them.
+pub(crate) fn warn<M: Into<String>>(message: M) {
+    // The lock is only poisoned by a thread panicking while pushing
+    WARNINGS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(message.into());
+}
+
+/// Take the warnings raised since the last call, counted warnings last
+fn take_warnings() -> Vec<String> {
+    let mut warnings = std::mem::take(&mut *WARNINGS.lock().unwrap_or_else(|e| e.into_inner()));
+    for warning in COUNTED_WARNINGS {
+        let count = warning.count.swap(0, Ordering::Relaxed);
+        if count > 0 {
+            warnings.push(format!("{} {}", count, warning.message));
+        }
+    }
+    warnings
+}
+
+/// The warnings raised by the last calls, raised in R by `rust_call()`
+#[extendr]
+fn mire_warnings() -> Strings {
+    Strings::from_values(take_warnings())
+}
+
+extendr_module! {
+    mod warnings;
+    fn mire_warnings;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_warnings() {
+        warn("3 of 5 IDs were not found");
+        UNKNOWN_TAXID_RECORDS.add(2);
+        UNKNOWN_TAXID_RECORDS.add(0);
+        let warnings = take_warnings();
+        assert!(warnings.iter().any(|x| x == "3 of 5 IDs were not found"));
+        assert!(warnings
+            .iter()
+            .any(|x| x.ends_with(UNKNOWN_TAXID_RECORDS.message)));
+        assert!(!take_warnings().iter().any(|x| x.starts_with("3 of 5")));
+    }
+}
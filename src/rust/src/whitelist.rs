@@ -0,0 +1,290 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use extendr_api::prelude::*;
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::error::RError;
+use crate::reader::LineReader;
+use crate::utils::*;
+use crate::warnings::AMBIGUOUS_BARCODES;
+
+// Minimum posterior probability of the best whitelisted neighbour of a
+// barcode for the barcode to be corrected, as in Cell Ranger
+const MIN_POSTERIOR: f64 = 0.975;
+
+/// The cell barcodes expected in a sample, with the number of reads (or any
+/// frequency) of each barcode, used as prior when correcting barcodes with a
+/// sequencing error. Plain lists give all barcodes the same prior.
+///
+/// R only holds an external pointer to the whitelist, which is shared rather
+/// than copied by the functions using it, so a whitelist of millions of
+/// barcodes is loaded once for all samples.
+#[extendr]
+#[derive(Clone)]
+pub struct Whitelist {
+    barcodes: Arc<HashMap<Vec<u8>, u64>>,
+    // Whether counts were given
+    counted: bool,
+}
+
+impl Whitelist {
+    /// Read a list of barcodes, one per line, optionally followed by a tab and
+    /// the count of the barcode
+    pub(crate) fn from_path(path: &str) -> Result<Self> {
+        let mut reader =
+            LineReader::with_capacity(BUFFER_SIZE, new_reader(path, BUFFER_SIZE, None)?);
+        let mut barcodes = HashMap::default();
+        let mut counted = false;
+        while let Some(line) = reader
+            .read_line()
+            .with_context(|| format!("Failed to read whitelist {}", path))?
+        {
+            let line = line.trim_ascii();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.split(|b| *b == b'\t');
+            // Safety: split always yields a first field
+            let barcode = fields.next().unwrap().to_ascii_uppercase();
+            let count = match fields.next() {
+                Some(count) => {
+                    counted = true;
+                    parse_usize(count).with_context(|| {
+                        format!(
+                            "Invalid count of barcode {} in whitelist {}",
+                            String::from_utf8_lossy(&barcode),
+                            path
+                        )
+                    })? as u64
+                }
+                None => 1,
+            };
+            *barcodes.entry(barcode).or_insert(0) += count;
+        }
+        if barcodes.is_empty() {
+            return Err(anyhow!("No barcode in whitelist {}", path));
+        }
+        Ok(Self {
+            barcodes: Arc::new(barcodes),
+            counted,
+        })
+    }
+
+    /// A whitelist of the given barcodes, without counts
+    pub(crate) fn from_barcodes<I: IntoIterator<Item = Vec<u8>>>(barcodes: I) -> Self {
+        Self {
+            barcodes: Arc::new(
+                barcodes
+                    .into_iter()
+                    .map(|barcode| (barcode.to_ascii_uppercase(), 1))
+                    .collect(),
+            ),
+            counted: false,
+        }
+    }
+
+    /// Correct a barcode with at most one sequencing error. Whitelisted
+    /// barcodes are returned as they are. Otherwise, each whitelisted barcode
+    /// one substitution away is weighted by its prior and the probability of
+    /// an error at the substituted base, from its `qual` (Phred+33) if given,
+    /// and the most likely one is returned if its posterior probability is
+    /// high enough. Returns `None` if the barcode cannot be corrected.
+    pub(crate) fn correct<'b>(
+        &self,
+        barcode: &'b [u8],
+        qual: Option<&[u8]>,
+    ) -> Option<Cow<'b, [u8]>> {
+        if self.barcodes.contains_key(barcode) {
+            return Some(Cow::Borrowed(barcode));
+        }
+        let mut best: Option<(Vec<u8>, f64)> = None;
+        let mut total = 0.0;
+        let mut candidate = barcode.to_ascii_uppercase();
+        for pos in 0 .. barcode.len() {
+            let original = candidate[pos];
+            let error = qual
+                .and_then(|qual| qual.get(pos))
+                .map_or(1.0, |q| 10f64.powf(-(q.saturating_sub(33) as f64) / 10.0));
+            for base in b"ACGT" {
+                if *base == original {
+                    continue;
+                }
+                candidate[pos] = *base;
+                if let Some(count) = self.barcodes.get(&candidate) {
+                    // A pseudocount keeps barcodes never seen in the counts
+                    let likelihood = (*count as f64 + 1.0) * error;
+                    total += likelihood;
+                    if best.as_ref().is_none_or(|(_, best)| likelihood > *best) {
+                        best = Some((candidate.clone(), likelihood));
+                    }
+                }
+            }
+            candidate[pos] = original;
+        }
+        let (corrected, likelihood) = best?;
+        if likelihood / total < MIN_POSTERIOR {
+            AMBIGUOUS_BARCODES.add(1);
+            return None;
+        }
+        Some(Cow::Owned(corrected))
+    }
+}
+
+/// A table translating barcodes between assays sharing gel beads, such as the
+/// ATAC and gene expression barcodes of 10x multiome, so that both assays
+/// report the same barcode for a cell
+pub(crate) struct Translation {
+    map: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Translation {
+    /// Read a CSV file of two columns, the barcode and its translation. A
+    /// header, whose first field is not a barcode, is skipped.
+    pub(crate) fn from_path(path: &str) -> Result<Self> {
+        let mut reader =
+            LineReader::with_capacity(BUFFER_SIZE, new_reader(path, BUFFER_SIZE, None)?);
+        let mut map = HashMap::default();
+        let mut header = true;
+        while let Some(line) = reader
+            .read_line()
+            .with_context(|| format!("Failed to read translation table {}", path))?
+        {
+            let line = line.trim_ascii();
+            if line.is_empty() {
+                continue;
+            }
+            let fields = line.split(|b| *b == b',').collect::<Vec<_>>();
+            if std::mem::take(&mut header)
+                && !fields[0]
+                    .iter()
+                    .all(|b| b"ACGTN".contains(&b.to_ascii_uppercase()))
+            {
+                continue;
+            }
+            let [from, to] = fields[..] else {
+                return Err(anyhow!(
+                    "Invalid line in translation table {}: {}",
+                    path,
+                    String::from_utf8_lossy(line)
+                ));
+            };
+            map.insert(from.to_ascii_uppercase(), to.to_ascii_uppercase());
+        }
+        Ok(Self { map })
+    }
+
+    /// The translation of a barcode, `None` for barcodes missing from the
+    /// table
+    pub(crate) fn get(&self, barcode: &[u8]) -> Option<&[u8]> {
+        self.map.get(barcode).map(|x| x.as_slice())
+    }
+}
+
+#[extendr]
+impl Whitelist {
+    fn load(path: &str) -> std::result::Result<Self, RError> {
+        Self::from_path(path).map_err(RError::from)
+    }
+
+    /// Number of barcodes
+    fn len(&self) -> usize {
+        self.barcodes.len()
+    }
+
+    /// Whether barcodes were loaded with counts
+    fn counted(&self) -> bool {
+        self.counted
+    }
+
+    /// Correct barcodes, `NA` for barcodes that cannot be corrected
+    fn correct_barcodes(&self, barcodes: Strings, quals: Nullable<Strings>) -> Strings {
+        let quals = match quals {
+            Nullable::NotNull(quals) => Some(quals),
+            Nullable::Null => None,
+        };
+        barcodes
+            .iter()
+            .enumerate()
+            .map(|(i, barcode)| {
+                if barcode.is_na() {
+                    return Rstr::na();
+                }
+                let qual = quals
+                    .as_ref()
+                    .map(|quals| quals.elt(i))
+                    .filter(|qual| !qual.is_na());
+                self.correct(barcode.as_bytes(), qual.as_ref().map(|x| x.as_bytes()))
+                    .map_or_else(Rstr::na, |x| u8_to_rstr(x.into_owned()))
+            })
+            .collect()
+    }
+}
+
+extendr_module! {
+    mod whitelist;
+    impl Whitelist;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_whitelist() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let path = temp.path().join("whitelist.tsv");
+        std::fs::write(&path, "AAAA\t100\nAAAT\t1\ncccc\t5\n\n")?;
+        let whitelist = Whitelist::from_path(path.to_str().unwrap())?;
+        assert!(whitelist.counted);
+        assert_eq!(whitelist.barcodes.len(), 3);
+
+        assert_eq!(
+            whitelist.correct(b"AAAA", None).as_deref(),
+            Some(&b"AAAA"[..])
+        );
+        assert_eq!(
+            whitelist.correct(b"CCCG", None).as_deref(),
+            Some(&b"CCCC"[..])
+        );
+        // AAAA is a hundred times more frequent than AAAT
+        assert_eq!(
+            whitelist.correct(b"AAAC", None).as_deref(),
+            Some(&b"AAAA"[..])
+        );
+        assert_eq!(whitelist.correct(b"GGGG", None), None);
+
+        // A plain list gives the same prior to all barcodes, the qualities
+        // then tell which base is most likely wrong
+        std::fs::write(&path, "AAAA\nTAAC\n")?;
+        let whitelist = Whitelist::from_path(path.to_str().unwrap())?;
+        assert!(!whitelist.counted);
+        assert_eq!(whitelist.correct(b"TAAA", None), None);
+        assert_eq!(
+            whitelist.correct(b"TAAA", Some(b"!III")).as_deref(),
+            Some(&b"AAAA"[..])
+        );
+        assert_eq!(
+            whitelist.correct(b"TAAA", Some(b"III!")).as_deref(),
+            Some(&b"TAAC"[..])
+        );
+        std::fs::write(&path, "AAAA\tx\n")?;
+        assert!(Whitelist::from_path(path.to_str().unwrap()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_translation() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let path = temp.path().join("translation.csv");
+        std::fs::write(&path, "atac,gex\nAAAA,CCCC\nacgt,GGGG\n")?;
+        let translation = Translation::from_path(path.to_str().unwrap())?;
+        assert_eq!(translation.get(b"AAAA"), Some(&b"CCCC"[..]));
+        assert_eq!(translation.get(b"ACGT"), Some(&b"GGGG"[..]));
+        assert_eq!(translation.get(b"CCCC"), None);
+        std::fs::write(&path, "AAAA,CCCC,GGGG\n")?;
+        assert!(Translation::from_path(path.to_str().unwrap()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use flate2::write::ZlibEncoder;
+
+// Zarr (format 2) stores are directories of JSON metadata and of chunks
+// compressed apart, which cloud-native tools (zarr-python, xarray, dask,
+// anndata) read chunk by chunk, also from object storage. Only what mire
+// writes is supported: groups, and one-dimensional arrays of numbers or
+// strings compressed by zlib.
+
+/// Maximum number of elements in a chunk of an array
+pub(crate) const CHUNK_LEN: usize = 1 << 20;
+
+/// Compression level of the chunks, favouring speed as for other outputs
+const ZLIB_LEVEL: u32 = 1;
+
+/// A Zarr store being written
+pub(crate) struct ZarrStore {
+    root: PathBuf,
+}
+
+impl ZarrStore {
+    /// Create the store at `path` with the attributes `attrs` of its root
+    /// group, replacing any existing store
+    pub(crate) fn create(path: &Path, attrs: &str) -> Result<Self> {
+        if path.exists() {
+            std::fs::remove_dir_all(path)
+                .with_context(|| format!("Failed to remove existing store {}", path.display()))?;
+        }
+        let store = Self {
+            root: path.to_path_buf(),
+        };
+        store.group("", attrs)?;
+        Ok(store)
+    }
+
+    /// Open the existing store at `path` to add groups or arrays to it
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        if !path.join(".zgroup").is_file() {
+            return Err(anyhow!("{} is not a Zarr store", path.display()));
+        }
+        Ok(Self {
+            root: path.to_path_buf(),
+        })
+    }
+
+    /// Create the group `name` (e.g. `"uns/reads"`, `""` for the root), with
+    /// the JSON object `attrs` as its attributes if not empty
+    pub(crate) fn group(&self, name: &str, attrs: &str) -> Result<()> {
+        let dir = self.root.join(name);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+        write_json(&dir.join(".zgroup"), "{\"zarr_format\": 2}")?;
+        write_attrs(&dir, attrs)
+    }
+
+    /// Start the array `name`, of chunks of `chunk_len` elements
+    pub(crate) fn array<T: ZarrType>(
+        &self,
+        name: &str,
+        chunk_len: usize,
+        attrs: &str,
+    ) -> Result<ArrayWriter<T>> {
+        let dir = self.root.join(name);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+        Ok(ArrayWriter {
+            dir,
+            attrs: attrs.to_string(),
+            chunk_len: chunk_len.max(1),
+            chunk: Vec::with_capacity(chunk_len.max(1)),
+            chunks: 0,
+            len: 0,
+            encoded: Vec::new(),
+        })
+    }
+
+    /// Write all of `values` as the array `name`
+    pub(crate) fn write_array<T: ZarrType>(
+        &self,
+        name: &str,
+        values: impl ExactSizeIterator<Item = T>,
+        attrs: &str,
+    ) -> Result<usize> {
+        let mut array = self.array(name, values.len().min(CHUNK_LEN), attrs)?;
+        for value in values {
+            array.push(value)?;
+        }
+        array.finish()
+    }
+}
+
+/// The type of the elements of an array
+pub(crate) trait ZarrType: Default {
+    /// The data type of the array metadata
+    const DTYPE: &'static str;
+    /// Its fill value, as JSON
+    const FILL_VALUE: &'static str;
+    /// Its filters, as JSON
+    const FILTERS: &'static str = "null";
+
+    /// Encode a chunk of elements, before compression
+    fn encode(chunk: &[Self], out: &mut Vec<u8>);
+}
+
+macro_rules! zarr_number {
+    ($type:ty, $dtype:expr, $fill:expr) => {
+        impl ZarrType for $type {
+            const DTYPE: &'static str = $dtype;
+            const FILL_VALUE: &'static str = $fill;
+
+            fn encode(chunk: &[Self], out: &mut Vec<u8>) {
+                for x in chunk {
+                    out.extend_from_slice(&x.to_le_bytes());
+                }
+            }
+        }
+    };
+}
+
+zarr_number!(i32, "<i4", "0");
+zarr_number!(i64, "<i8", "0");
+zarr_number!(f64, "<f8", "\"NaN\"");
+
+/// Strings are encoded by the `vlen-utf8` filter of numcodecs: the number of
+/// strings, then the length and bytes of each, in little-endian 32 bits
+impl ZarrType for String {
+    const DTYPE: &'static str = "|O";
+    const FILL_VALUE: &'static str = "\"\"";
+    const FILTERS: &'static str = "[{\"id\": \"vlen-utf8\"}]";
+
+    fn encode(chunk: &[Self], out: &mut Vec<u8>) {
+        out.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        for x in chunk {
+            out.extend_from_slice(&(x.len() as u32).to_le_bytes());
+            out.extend_from_slice(x.as_bytes());
+        }
+    }
+}
+
+/// Writes the chunks of an array as they fill, and its metadata once all
+/// elements are known
+pub(crate) struct ArrayWriter<T: ZarrType> {
+    dir: PathBuf,
+    attrs: String,
+    chunk_len: usize,
+    chunk: Vec<T>,
+    chunks: usize,
+    len: usize,
+    encoded: Vec<u8>,
+}
+
+impl<T: ZarrType> ArrayWriter<T> {
+    pub(crate) fn push(&mut self, value: T) -> Result<()> {
+        self.chunk.push(value);
+        self.len += 1;
+        if self.chunk.len() == self.chunk_len {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        // Readers expect every chunk whole: the last one is padded
+        self.chunk.resize_with(self.chunk_len, T::default);
+        self.encoded.clear();
+        T::encode(&self.chunk, &mut self.encoded);
+        self.chunk.clear();
+        let path = self.dir.join(self.chunks.to_string());
+        let file = std::fs::File::create(&path)
+            .with_context(|| format!("Failed to create chunk {}", path.display()))?;
+        let mut encoder = ZlibEncoder::new(file, flate2::Compression::new(ZLIB_LEVEL));
+        encoder
+            .write_all(&self.encoded)
+            .and_then(|_| encoder.finish().map(|_| ()))
+            .with_context(|| format!("Failed to write chunk {}", path.display()))?;
+        self.chunks += 1;
+        Ok(())
+    }
+
+    /// Write the last chunk and the metadata of the array, returning its
+    /// length
+    pub(crate) fn finish(mut self) -> Result<usize> {
+        if !self.chunk.is_empty() {
+            self.flush()?;
+        }
+        write_json(
+            &self.dir.join(".zarray"),
+            &format!(
+                "{{\"zarr_format\": 2, \"shape\": [{}], \"chunks\": [{}], \"dtype\": \"{}\", \
+                 \"compressor\": {{\"id\": \"zlib\", \"level\": {}}}, \"fill_value\": {}, \
+                 \"order\": \"C\", \"filters\": {}}}",
+                self.len,
+                self.chunk_len,
+                T::DTYPE,
+                ZLIB_LEVEL,
+                T::FILL_VALUE,
+                T::FILTERS
+            ),
+        )?;
+        write_attrs(&self.dir, &self.attrs)?;
+        Ok(self.len)
+    }
+}
+
+fn write_attrs(dir: &Path, attrs: &str) -> Result<()> {
+    if attrs.is_empty() {
+        return Ok(());
+    }
+    write_json(&dir.join(".zattrs"), attrs)
+}
+
+fn write_json(path: &Path, json: &str) -> Result<()> {
+    std::fs::write(path, json).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+
+    use super::*;
+
+    fn read_chunk(path: &Path) -> Vec<u8> {
+        let mut out = Vec::new();
+        ZlibDecoder::new(std::fs::File::open(path).unwrap())
+            .read_to_end(&mut out)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_zarr_store() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let path = temp.path().join("counts.zarr");
+        let store = ZarrStore::create(&path, "{\"encoding-type\": \"anndata\"}")?;
+        store.group("obs", "")?;
+        let mut array = store.array::<i32>("obs/n", 2, "")?;
+        for x in [1, 2, 3] {
+            array.push(x)?;
+        }
+        assert_eq!(array.finish()?, 3);
+        assert_eq!(read_chunk(&path.join("obs/n/0")), [1, 0, 0, 0, 2, 0, 0, 0]);
+        // The last chunk is padded with the default value
+        assert_eq!(read_chunk(&path.join("obs/n/1")), [3, 0, 0, 0, 0, 0, 0, 0]);
+        let metadata = std::fs::read_to_string(path.join("obs/n/.zarray"))?;
+        assert!(metadata.contains("\"shape\": [3], \"chunks\": [2], \"dtype\": \"<i4\""));
+
+        let store = ZarrStore::open(&path)?;
+        store.write_array("obs/_index", ["AC".to_string()].into_iter(), "")?;
+        assert_eq!(
+            read_chunk(&path.join("obs/_index/0")),
+            [1, 0, 0, 0, 2, 0, 0, 0, b'A', b'C']
+        );
+        assert!(path.join(".zattrs").is_file() && !path.join("obs/.zattrs").exists());
+        Ok(())
+    }
+}